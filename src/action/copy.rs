@@ -0,0 +1,47 @@
+use crate::crypto;
+use crate::data::file::{FileMeta, RemoteFile, RemoteFileVersion};
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{LogCategory, ProgressHandler};
+use std::borrow::Borrow;
+
+/// Copies a remote file to `filename` on the rate limiter's backend, without downloading it (see `Backend::copy_file`)
+pub async fn copy(
+    rate_limiter: impl Borrow<RateLimiter>,
+    progress: ProgressHandler,
+    file: RemoteFile,
+    filename: String,
+) {
+    let rate_limiter = rate_limiter.borrow();
+    let _permit_guard = rate_limiter.borrow_upload_permit().await;
+    // Nothing new is started once a daily cap is reached, the files left are done by the next run
+    if cap::is_reached() {
+        return;
+    }
+    if progress.logs(LogCategory::Transfers) {
+        progress.println(format!("Copying {}", file.rel_path.display()));
+    }
+
+    let backend = rate_limiter.backend();
+    let version = RemoteFileVersion {
+        path: file.full_path_hash.clone(),
+        id: file.id.clone(),
+    };
+    let meta = FileMeta {
+        rel_path: file.rel_path.clone(),
+        last_modified: file.last_modified,
+        mode: file.mode,
+        is_symlink: file.is_symlink,
+        birthtime: file.birthtime,
+        content_hash: file.content_hash,
+        original_size: file.original_size,
+    };
+    let enc_meta = crypto::encode_meta(backend.key(), &meta);
+
+    if let Err(err) = backend.copy_file(&version, file.size, &filename, enc_meta).await {
+        let err = err.wrap_err(format!("Failed to copy \"{}\"", file.rel_path.display()));
+        progress.report_error(format!("{:#}", err));
+        return;
+    }
+    progress.report_success();
+}
@@ -1,33 +1,51 @@
 use crate::data::file::{RemoteFile, RemoteFileVersion};
+use crate::net::cap;
 use crate::net::rate_limiter::RateLimiter;
-use crate::progress::ProgressHandler;
-use eyre::WrapErr;
+use crate::net::retention::is_version_locked;
+use crate::progress::{LogCategory, ProgressHandler};
 use std::borrow::Borrow;
 
 pub async fn delete(rate_limiter: impl Borrow<RateLimiter>, progress: ProgressHandler, file: RemoteFile) {
     let rate_limiter = rate_limiter.borrow();
     let _permit_guard = rate_limiter.borrow_delete_permit().await;
-    if progress.verbose() {
+    // Nothing new is started once a daily cap is reached, the files left are done by the next run
+    if cap::is_reached() {
+        return;
+    }
+    if progress.logs(LogCategory::Transfers) {
         progress.println(format!("Deleting {}", file.rel_path.display()));
     }
 
-    let b2 = rate_limiter.b2_client();
+    let backend = rate_limiter.backend();
 
     let version = RemoteFileVersion {
         path: file.full_path_hash.clone(),
         id: file.id.clone(),
     };
 
-    let err = b2
-        .delete_file_version(&version)
-        .await
-        .wrap_err_with(|| format!("Failed to delete last version of \"{}\"", file.rel_path.display()));
-    if let Err(err) = err {
+    // Hide first: if the version was deleted first and hiding failed, an older version would reappear as the latest
+    if let Err(err) = backend.hide_file(&file.full_path_hash).await {
+        let err = err.wrap_err(format!("Failed to hide \"{}\"", file.rel_path.display()));
         progress.report_error(format!("{:#}", err));
         return;
     }
 
-    let _ = b2.hide_file(&file.full_path_hash).await;
+    let err = backend.delete_file_version(&version).await;
+    if let Err(err) = err {
+        if is_version_locked(&err) {
+            progress.report_error(format!(
+                "\"{}\" is locked by Object Lock retention, it can't be deleted until the retention expires",
+                file.rel_path.display()
+            ));
+        } else {
+            let err = err.wrap_err(format!(
+                "Failed to delete last version of \"{}\"",
+                file.rel_path.display()
+            ));
+            progress.report_error(format!("{:#}", err));
+        }
+        return;
+    }
 
     progress.report_success();
 }
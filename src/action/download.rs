@@ -1,32 +1,52 @@
+use crate::data::checkpoint::RestoreCheckpoint;
 use crate::data::file::RemoteFile;
+use crate::data::fsync::{FsyncPolicy, FsyncQueue};
+use crate::data::paths::{check_path_len, check_rel_path, create_dir_all_under};
+use crate::net::cap;
 use crate::net::rate_limiter::RateLimiter;
-use crate::progress::ProgressHandler;
-use crate::stream::{DecompressionStream, DecryptionStream};
+use crate::progress::{LogCategory, ProgressHandler};
+use crate::stream::{DecompressionStream, DecryptionStream, WriteBehind};
 use eyre::WrapErr;
-use fs_set_times::{SetTimes, SystemTimeSpec};
+use fs_set_times::{set_symlink_times, SetTimes, SystemTimeSpec};
 use futures::StreamExt;
 use std::borrow::Borrow;
-use std::fs::{self, Permissions};
+use std::ffi::OsString;
+use std::fs::{self, File, Permissions};
+use std::io::{self, ErrorKind};
 use std::ops::Add;
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::task::spawn_blocking;
 
 pub async fn download(
     rate_limiter: impl Borrow<RateLimiter>,
     progress: ProgressHandler,
     target_path: impl Borrow<PathBuf>,
+    checkpoint: impl Borrow<RestoreCheckpoint>,
+    fsync: impl Borrow<FsyncQueue>,
     file: RemoteFile,
 ) {
+    // A tampered bucket could send the file anywhere, like ../../.bashrc, so nothing is downloaded for it
+    if let Err(err) = check_rel_path(&file.rel_path) {
+        progress.report_error(format!("Refusing to restore a file of the backup: {}", err));
+        return;
+    }
     let rate_limiter = rate_limiter.borrow();
     let mut _permit_guard = rate_limiter.borrow_download_permit().await;
-    let b2 = rate_limiter.b2_client();
+    // Nothing new is started once a daily cap is reached, the files left are done by the next run
+    if cap::is_reached() {
+        return;
+    }
+    let backend = rate_limiter.backend();
 
-    if progress.verbose() {
+    if progress.logs(LogCategory::Transfers) {
         progress.println(format!("Downloading {}", file.rel_path.display()));
     }
 
-    let encrypted = b2
+    let encrypted = backend
         .download_file_stream(&file.full_path_hash)
         .await
         .wrap_err_with(|| format!("Failed to download file \"{}\"", file.rel_path.display()));
@@ -38,26 +58,51 @@ pub async fn download(
         Ok(data) => data,
     };
 
-    let decrypted_stream = DecryptionStream::new(encrypted, &b2.key);
+    let decrypted_stream = DecryptionStream::new(rate_limiter.throttle_download(encrypted), backend.key());
 
-    if save_file(&file, decrypted_stream, target_path.borrow(), &progress)
-        .await
-        .is_ok()
+    let fsync = fsync.borrow();
+    let target_path = target_path.borrow();
+    let save_path = target_path.join(&file.rel_path);
+    if save_file(
+        &file,
+        decrypted_stream,
+        target_path,
+        save_path.clone(),
+        fsync.policy(),
+        &progress,
+    )
+    .await
+    .is_err()
     {
-        progress.report_success();
+        return;
+    }
+    match fsync.saved(checkpoint.borrow(), &save_path, &file.id).await {
+        Ok(()) => progress.report_success(),
+        Err(err) => progress.report_error(format!("Failed to sync \"{}\": {}", file.rel_path.display(), err)),
     }
 }
 
 async fn save_file(
     file: &RemoteFile,
     mut decrypted_stream: DecryptionStream,
-    target: &Path,
+    target_path: &Path,
+    save_path: PathBuf,
+    fsync_policy: FsyncPolicy,
     progress: &ProgressHandler,
 ) -> Result<(), ()> {
-    let save_path = target.join(&file.rel_path);
+    if let Err(err) = check_path_len(&save_path) {
+        progress.report_error(format!("Failed to restore \"{}\": {}", file.rel_path.display(), err));
+        return Err(());
+    }
     let save_dir = Path::new(&save_path).parent().unwrap();
-    if fs::create_dir_all(save_dir).is_err() {
-        progress.report_error(format!("Failed to create path to file \"{}\"", file.rel_path.display()));
+    // The folders come from the backup, symlinks already in the target must not send the file elsewhere
+    let rel_dir = file.rel_path.parent().unwrap_or_else(|| Path::new(""));
+    if let Err(err) = create_dir_all_under(target_path, rel_dir) {
+        progress.report_error(format!(
+            "Failed to create path to file \"{}\": {}",
+            file.rel_path.display(),
+            err
+        ));
         return Err(());
     }
     let _ = fs::remove_file(&save_path);
@@ -80,13 +125,28 @@ async fn save_file(
             Ok(data) => data,
         };
 
-        let link_target = String::from_utf8(decompressed).unwrap();
-        if symlink(link_target, save_path).is_err() {
-            progress.report_error(format!("Failed to create symlink \"{}\"", file.rel_path.display()));
+        let link_target = OsString::from_vec(decompressed);
+        if let Err(err) = symlink(link_target, &save_path) {
+            progress.report_error(format!(
+                "Failed to create symlink \"{}\": {}",
+                file.rel_path.display(),
+                err
+            ));
+            return Err(());
+        }
+        // Sets the time of the link itself, like lutimes()
+        let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(file.last_modified));
+        if let Err(err) = set_symlink_times(&save_path, None, Some(SystemTimeSpec::Absolute(mtime))) {
+            progress.report_error(format!(
+                "Failed to set mtime of symlink \"{}\": {}",
+                file.rel_path.display(),
+                err
+            ));
+            let _ = fs::remove_file(&save_path);
             return Err(());
         }
     } else {
-        let tempfile = match tempfile::NamedTempFile::new_in(save_dir) {
+        let tempfile = match NamedTempFile::new_in(save_dir) {
             Err(err) => {
                 progress.report_error(format!(
                     "Failed to create temp file for \"{}\": {}",
@@ -108,7 +168,26 @@ async fn save_file(
                 return Err(());
             }
         };
-        let mut decompressed_stream = DecompressionStream::new(Box::new(decrypted_stream), fd);
+        // Finds out that the disk is full before the download rather than after, and avoids fragmenting the file
+        let preallocated = file.original_size.filter(|&size| size > 0);
+        if let Some(size) = preallocated {
+            let reserve = tempfile.as_file().try_clone();
+            let reserved = spawn_blocking(move || preallocate(&reserve?, size))
+                .await
+                .unwrap_or_else(|err| Err(err.into()));
+            match reserved {
+                Err(err) if matches!(err.kind(), ErrorKind::StorageFull | ErrorKind::FileTooLarge) => {
+                    progress.report_error(format!("Not enough space for \"{}\": {}", file.rel_path.display(), err));
+                    let _ = tempfile.close();
+                    return Err(());
+                }
+                // Other errors only mean that the filesystem can't preallocate
+                _ => (),
+            }
+        }
+        // Decompression goes on while the writer thread waits on the disk
+        let (write_behind, writer) = WriteBehind::new(fd);
+        let mut decompressed_stream = DecompressionStream::new(Box::new(decrypted_stream), write_behind);
         while let Some(result) = decompressed_stream.next().await {
             if let Err(err) = result {
                 progress.report_error(format!(
@@ -121,33 +200,79 @@ async fn save_file(
                 return Err(());
             }
         }
-        let final_file = match tempfile.persist(&save_path) {
+        let written = match writer.finish().await {
+            Ok(written) => written,
             Err(err) => {
-                progress.report_error(format!("Failed to save \"{}\": {}", file.rel_path.display(), err.error));
-                drop(decompressed_stream);
+                progress.report_error(format!("Failed to write \"{}\": {}", file.rel_path.display(), err));
+                let _ = tempfile.close();
                 return Err(());
             }
-            Ok(f) => f,
         };
-        if let Err(err) = final_file.set_permissions(Permissions::from_mode(file.mode)) {
-            progress.report_error(format!(
-                "Failed to set permissions of file \"{}\": {}",
-                file.rel_path.display(),
-                err
-            ));
-            let _ = fs::remove_file(&save_path);
-            return Err(());
-        }
-        let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(file.last_modified));
-        if let Err(err) = SetTimes::set_times(&final_file, None, Some(SystemTimeSpec::Absolute(mtime))) {
-            progress.report_error(format!(
-                "Failed to set mtime of file \"{}\": {}",
-                file.rel_path.display(),
-                err
-            ));
-            let _ = fs::remove_file(&save_path);
+        // The content may have changed size while it was backed up, the rest of the preallocation is cut off
+        let truncate_to = preallocated.map(|_| written);
+        // The file only appears under its name once complete, with its mtime, so an interrupted restore
+        // never leaves a partial file that looks up to date
+        let sync = fsync_policy == FsyncPolicy::PerFile;
+        let (mode, last_modified) = (file.mode, file.last_modified);
+        let finished =
+            spawn_blocking(move || finish_file(tempfile, truncate_to, mode, last_modified, sync, &save_path)).await;
+        if let Err(err) = finished.unwrap_or_else(|err| Err(err.to_string())) {
+            progress.report_error(format!("Failed to save \"{}\": {}", file.rel_path.display(), err));
             return Err(());
         }
     }
     Ok(())
 }
+
+/// Sets the metadata of a downloaded temp file and moves it in place
+fn finish_file(
+    tempfile: NamedTempFile,
+    truncate_to: Option<u64>,
+    mode: u32,
+    last_modified: u64,
+    sync: bool,
+    save_path: &Path,
+) -> Result<(), String> {
+    if let Some(len) = truncate_to {
+        tempfile
+            .as_file()
+            .set_len(len)
+            .map_err(|err| format!("failed to truncate: {}", err))?;
+    }
+    tempfile
+        .as_file()
+        .set_permissions(Permissions::from_mode(mode))
+        .map_err(|err| format!("failed to set permissions: {}", err))?;
+    // There's no way to set the birthtime on Linux, it's only kept in the backup for `frozen info`
+    let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(last_modified));
+    SetTimes::set_times(tempfile.as_file(), None, Some(SystemTimeSpec::Absolute(mtime)))
+        .map_err(|err| format!("failed to set mtime: {}", err))?;
+    if sync {
+        tempfile
+            .as_file()
+            .sync_all()
+            .map_err(|err| format!("failed to sync: {}", err))?;
+    }
+    tempfile.persist(save_path).map_err(|err| err.error.to_string())?;
+    Ok(())
+}
+
+/// Reserves the space of a file before it's written
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    let len = libc::off_t::try_from(size).map_err(|_| io::Error::from(ErrorKind::FileTooLarge))?;
+    // Returns the error instead of setting errno
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Elsewhere the file is only extended, which still lets the filesystem know how large it gets
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)
+}
@@ -6,3 +6,6 @@ pub use download::download;
 
 mod delete;
 pub use delete::delete;
+
+mod copy;
+pub use copy::copy;
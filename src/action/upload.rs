@@ -1,18 +1,24 @@
-use crate::crypto;
-use crate::data::file::LocalFile;
+use crate::crypto::{self, RunningSha1};
+use crate::data::file::{FileMeta, LocalFile, RemoteFileVersion, SkippedFile};
+use crate::net::backend::Backend;
+use crate::net::cap;
 use crate::net::rate_limiter::RateLimiter;
-use crate::progress::ProgressHandler;
-use crate::stream::{CompressionStream, EncryptionStream};
-use eyre::WrapErr;
+use crate::progress::{LogCategory, ProgressHandler};
+use crate::stream::{CompressionLevel, CompressionStream, EncryptionStream};
+use eyre::{bail, Result, WrapErr};
+use futures::StreamExt;
 use std::borrow::Borrow;
 use std::io::Cursor;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 pub async fn upload(
     rate_limiter: impl Borrow<RateLimiter>,
     progress: ProgressHandler,
-    compression_level: i32,
+    compression: impl Borrow<CompressionLevel>,
     root_path: impl Borrow<PathBuf>,
+    verify: bool,
     file: LocalFile,
 ) {
     let root_path = root_path.borrow();
@@ -20,14 +26,18 @@ pub async fn upload(
 
     let rate_limiter = rate_limiter.borrow();
     let mut permit = rate_limiter.borrow_upload_permit().await;
-    let b2 = rate_limiter.b2_client();
+    // Nothing new is started once a daily cap is reached, the files left are done by the next run
+    if cap::is_reached() {
+        return;
+    }
+    let backend = rate_limiter.backend();
 
-    if progress.verbose() {
+    if progress.logs(LogCategory::Transfers) {
         progress.println(format!("Uploading {}", file.rel_path.display()));
     }
 
     if permit.is_none() {
-        let upload_url = match b2.get_upload_url().await {
+        let upload_url = match backend.get_upload_url().await {
             Ok(upload_url) => upload_url,
             Err(err) => {
                 progress.report_error(format!(
@@ -42,42 +52,126 @@ pub async fn upload(
     }
     let upload_url = permit.as_ref().unwrap();
 
+    let compression = compression.borrow();
+    let compression_level = compression.get();
+    let start = Instant::now();
     let is_symlink = file.is_symlink_at(root_path).unwrap_or(false);
+    // Lets restores preallocate the file, it's only a hint if the file changes while it's read
+    let mut original_size = None;
     let compressed_stream = if is_symlink {
-        let link_data = file.readlink_at(root_path).ok();
-        match link_data {
-            Some(data) => Some(CompressionStream::new(Cursor::new(data), compression_level).await),
-            None => None,
+        match file.readlink_at(root_path) {
+            Ok(data) => {
+                original_size = Some(data.len() as u64);
+                Ok(CompressionStream::new(Cursor::new(data), compression_level).await)
+            }
+            Err(err) => Err(format!("Failed to read symlink: {}", err)),
         }
     } else {
         let path = file.full_path(root_path);
-        let std_file = std::fs::File::open(path).ok();
-        match std_file {
-            Some(file) => Some(CompressionStream::new(file, compression_level).await),
-            None => None,
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                original_size = file.metadata().ok().map(|meta| meta.len());
+                Ok(CompressionStream::new(file, compression_level).await)
+            }
+            Err(err) => Err(format!("Failed to open file: {}", err)),
         }
     };
     let compressed_stream = match compressed_stream {
-        Some(c) => Box::new(c),
-        None => {
-            progress.report_error(format!("Failed to read file: {}", rel_path.display()));
+        Ok(c) => c,
+        Err(reason) => {
+            progress.report_skipped(SkippedFile::new(rel_path, reason));
             return;
         }
     };
 
-    let encrypted_stream = EncryptionStream::new(compressed_stream, &b2.key);
+    let compress_time = compressed_stream.compress_time();
+    // Only known this early if the whole file fit in one chunk, larger files are still being read
+    let content_hash = compressed_stream.content_hash();
+    // What we send, to compare with what the remote says it received
+    let sent = Arc::new(Mutex::new((0u64, RunningSha1::default())));
+    let sent_inspect = sent.clone();
+    let encrypted_stream = rate_limiter.throttle_upload(Box::new(
+        EncryptionStream::new(Box::new(compressed_stream), backend.key()).inspect(move |chunk| {
+            if let (true, Ok(chunk)) = (verify, chunk) {
+                let (size, sha1) = &mut *sent_inspect.lock().unwrap();
+                *size += chunk.len() as u64;
+                sha1.update(chunk);
+            }
+        }),
+    ));
 
     let filehash = &file.full_path_hash;
-    let enc_meta = crypto::encode_meta(&b2.key, rel_path, file.last_modified, file.mode, is_symlink);
+    let meta = FileMeta {
+        rel_path: rel_path.clone(),
+        last_modified: file.last_modified,
+        mode: file.mode,
+        is_symlink,
+        birthtime: file.birthtime,
+        content_hash,
+        original_size,
+    };
+    let enc_meta = crypto::encode_meta(backend.key(), &meta);
 
-    let err = b2
+    let version = backend
         .upload_file_stream(upload_url, filehash, encrypted_stream, Some(enc_meta))
         .await
         .wrap_err_with(|| format!("Failed to upload file \"{}\"", rel_path.display()));
-    if let Err(err) = err {
-        progress.report_error(format!("{:#}", err));
-        permit.take(); // The upload_url might be invalid now, let's get a new one
-        return;
+    let version = match version {
+        Ok(version) => version,
+        Err(err) => {
+            progress.report_error(format!("{:#}", err));
+            permit.take(); // The upload_url might be invalid now, let's get a new one
+            return;
+        }
+    };
+    if verify {
+        let (size, sha1) = sent.lock().unwrap().clone();
+        let verified = verify_upload(backend, &version, size, sha1.finish())
+            .await
+            .wrap_err_with(|| format!("Failed to verify upload of file \"{}\"", rel_path.display()));
+        if let Err(err) = verified {
+            // Don't leave a bad version as the latest, the next backup would think the file is up to date
+            let _ = backend.delete_file_version(&version).await;
+            progress.report_error(format!("{:#}", err));
+            return;
+        }
     }
+    compression.report(compress_time.get(), start.elapsed());
     progress.report_success();
 }
+
+/// Checks that the remote holds exactly what we uploaded, for `backup --verify`
+async fn verify_upload(backend: &dyn Backend, version: &RemoteFileVersion, size: u64, sha1: String) -> Result<()> {
+    let info = backend.file_version_info(version).await?;
+    if info.size != size {
+        bail!("the remote has {} bytes, but {} were uploaded", info.size, size);
+    }
+    match info.sha1 {
+        Some(remote_sha1) if remote_sha1 != sha1 => bail!("the remote SHA1 is {}, expected {}", remote_sha1, sha1),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::sha1_string;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verifies_uploads() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let data = b"uploaded data".to_vec();
+        let version = backend.upload_file_simple("file", data.clone()).await?;
+
+        verify_upload(&backend, &version, data.len() as u64, sha1_string(&data)).await?;
+        assert!(verify_upload(&backend, &version, 1, sha1_string(&data)).await.is_err());
+        assert!(
+            verify_upload(&backend, &version, data.len() as u64, sha1_string(b"other"))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::crypto;
+use frozen_core::data::audit;
+use frozen_core::data::duration::format_duration;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::output::format_timestamp;
+use std::time::Duration;
+
+pub async fn audit(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "target")?;
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let bucket = args
+        .get_one::<String>("bucket")
+        .map(String::as_str)
+        .or_else(|| root::bucket_of(&roots, &path));
+    let b2 = backend::connect_bucket(config, &keys, &b2, bucket).await?;
+    // Deleted roots aren't in the list anymore, but their trail is kept
+    let path_hash = crypto::hash_path_root(&path, &keys.encryption_key);
+    let records = audit::fetch_records(b2.as_ref(), &path_hash).await?;
+
+    if records.is_empty() {
+        println!("No run on {} was recorded", path.display());
+        return Ok(());
+    }
+    println!("Started (UTC)\tOperation\tDuration\tHost\tUser\tTransferred\tDeleted\tErrors\tFailure");
+    for record in records {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            format_timestamp(record.started),
+            record.operation.name(),
+            format_duration(Duration::from_secs(record.duration_secs)),
+            record.host,
+            record.user,
+            record.transferred,
+            record.deleted,
+            record.errors,
+            record.failure.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
@@ -1,162 +1,262 @@
-use crate::action;
-use crate::config::Config;
-use crate::data::paths::path_from_arg;
-use crate::data::root::{self, BackupRoot};
-use crate::dirdb::{diff::DirDiff, diff::FileDiff, DirDB};
-use crate::net::b2;
-use crate::net::rate_limiter::RateLimiter;
-use crate::progress::{Progress, ProgressType};
+use crate::prompt::prompt_yes_no;
 use crate::signal::interruptible;
+use crate::systemd::SdNotify;
 use clap::ArgMatches;
 use eyre::{bail, Result};
-use futures::stream::{FuturesUnordered, StreamExt};
-use futures::task::SpawnExt;
-use std::path::PathBuf;
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::checkpoint::{RestoreCheckpoint, CHECKPOINT_FILE_NAME};
+use frozen_core::data::duration::{duration_from_arg, format_duration};
+use frozen_core::data::paths::path_from_arg;
+use frozen_core::data::paths::to_semi_canonical_path;
+use frozen_core::data::report::{self, RunSummary};
+use frozen_core::data::root::{self, RootSettings};
+use frozen_core::dirdb::diff::DiffStrategy;
+use frozen_core::net::backend;
+use frozen_core::notifications;
+use frozen_core::session::{AuditedSession, BackupOptions, BackupSession};
+use frozen_core::snapshot::{Snapshot, SnapshotKind};
+use frozen_core::stats::{self, Stage};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub async fn backup(config: &Config, args: &ArgMatches) -> Result<()> {
     let path = path_from_arg(args, "source")?;
     if !path.is_dir() {
         bail!("{} is not a folder!", &path.display());
     }
+    // Files missing from a restore in progress would be deleted from the backup
+    if let Some(restore_target) = RestoreCheckpoint::find_unfinished(&path) {
+        bail!(
+            "{} is inside {}, which has an unfinished restore. Finish the restore first, \
+             or remove its {} if it was abandoned.",
+            path.display(),
+            restore_target.display(),
+            CHECKPOINT_FILE_NAME
+        );
+    }
     let target = path_from_arg(args, "destination").unwrap_or_else(|_| path.clone());
+    let only = match args.get_one::<OsString>("only") {
+        Some(subdir) => Some(only_subdir(&path, Path::new(subdir))?),
+        None => None,
+    };
+    let flags = RootSettings {
+        keep_existing: args.get_flag("keep-existing"),
+        delete_after_secs: duration_from_arg(args, "delete-after")?.map(|delay| delay.as_secs()),
+        strict_scan: args.get_flag("strict-scan"),
+        verify: args.get_flag("verify"),
+    };
+    let min_age = duration_from_arg(args, "min-age")?;
+    let exclude = own_files_excluded(&path, args)?;
+    let snapshot_kind = match args.get_one::<String>("snapshot") {
+        Some(name) => Some(SnapshotKind::from_name(name)?),
+        None => None,
+    };
+    let diff_strategy = match args.get_one::<String>("diff-strategy") {
+        Some(name) => DiffStrategy::from_name(name)?,
+        None => DiffStrategy::Auto,
+    };
     let keys = config.get_app_keys()?;
-
-    println!("Connecting to Backblaze B2");
-    let b2 = b2::B2::authenticate(config, &keys).await?;
-
-    println!("Downloading backup metadata");
-    let mut roots = root::fetch_roots(&b2).await?;
-    let mut root = root::open_create_root(&b2, &mut roots, &target).await?;
-    let arc_root = Arc::new(root.clone());
-
-    let backup_fut = backup_one_root(config, args, path, b2, arc_root);
-    let result = interruptible(backup_fut).await;
-
-    root.unlock().await?;
-    result
-}
-
-pub async fn backup_one_root(
-    config: &Config,
-    args: &ArgMatches,
-    path: PathBuf,
-    mut b2: b2::B2,
-    root: Arc<BackupRoot>,
-) -> Result<()> {
-    println!("Starting diff");
-    let progress = Progress::new(config.verbose);
-    let diff_progress = progress.show_progress_bar(ProgressType::Diff, 4);
-    let cleanup_progress = progress.get_progress_handler(ProgressType::Cleanup);
-    let upload_progress = progress.get_progress_handler(ProgressType::Upload);
-    let delete_progress = progress.get_progress_handler(ProgressType::Delete);
-
-    b2.progress.replace(diff_progress.clone());
-    let b2 = Arc::new(b2);
-
-    // Lets us wait for all backup actions to complete
-    let action_futs = FuturesUnordered::new();
-
-    let unfinished_large_files_fut = {
-        let b2 = b2.clone();
-        let path_hash = root.path_hash.clone();
-        tokio::spawn(async move { b2.list_unfinished_large_files(&path_hash).await })
+    let sd_notify = if args.get_flag("sd-notify") {
+        SdNotify::from_env()
+    } else {
+        None
     };
-
-    let dirdb_path = "dirdb/".to_string() + &root.path_hash;
-    let remote_dirdb_fut = {
-        let b2 = b2.clone();
-        let dirdb_path = dirdb_path.clone();
-        tokio::spawn(async move { b2.download_file(&dirdb_path).await })
+    let status = |status: &str| {
+        println!("{}", status);
+        if let Some(sd_notify) = &sd_notify {
+            sd_notify.status(status);
+        }
     };
 
-    let local_dirdb = Arc::new(DirDB::new_from_local(&path, &b2.key)?);
-    diff_progress.report_success();
-
-    let remote_dirdb = remote_dirdb_fut
-        .await?
-        .ok()
-        .and_then(|data| DirDB::new_from_packed(&data, &b2.key).ok());
-
-    let mut dir_diff = DirDiff::new(root.clone(), b2.clone(), local_dirdb.clone(), &remote_dirdb)?;
-    let path = Arc::new(path);
-    diff_progress.report_success();
+    status("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
 
-    diff_progress.println("Uploading pessimistic DirDB");
-    let dirdb_data = dir_diff.get_pessimistic_dirdb_data(&b2.key)?;
-    b2.upload_file_simple(&dirdb_path, dirdb_data).await?;
-    diff_progress.report_success();
-
-    diff_progress.println("Starting backup");
-    let mut num_cleanup_actions = 0;
-    let mut num_upload_actions = 0;
-    let mut num_delete_actions = 0;
-    let rate_limiter = Arc::new(RateLimiter::new(config, &b2));
-    let keep_existing = args.get_flag("keep-existing");
-    while let Some(item) = dir_diff.next().await {
-        let item = item?;
-
-        match item {
-            FileDiff {
-                local: Some(lfile),
-                remote,
-            } => {
-                if let Some(rfile) = remote {
-                    if rfile.last_modified >= lfile.last_modified {
-                        continue;
-                    }
+    status("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    // A partial backup only makes sense on top of a full one
+    // Existing roots stay in the bucket they were created in
+    let bucket = match roots.iter().find(|root| root.path == target) {
+        Some(existing_root) => {
+            let bucket = existing_root.bucket.as_deref();
+            if let Some(requested) = args.get_one::<String>("bucket") {
+                if bucket.unwrap_or(&config.bucket_name) != requested {
+                    bail!(
+                        "The backup of {} is already stored in bucket {}",
+                        target.display(),
+                        bucket.unwrap_or(&config.bucket_name)
+                    );
                 }
-                num_upload_actions += 1;
-                action_futs.spawn(action::upload(
-                    rate_limiter.clone(),
-                    upload_progress.clone(),
-                    config.compression_level,
-                    path.clone(),
-                    lfile,
-                ))?;
             }
-            FileDiff {
-                local: None,
-                remote: Some(rfile),
-            } => {
-                if keep_existing {
-                    continue;
+            bucket.map(ToOwned::to_owned)
+        }
+        None => args
+            .get_one::<String>("bucket")
+            .map(String::as_str)
+            .or_else(|| config.bucket_for(&target))
+            .filter(|bucket| *bucket != config.bucket_name)
+            .map(ToOwned::to_owned),
+    };
+    let root_b2 = backend::connect_bucket(config, &keys, &b2, bucket.as_deref()).await?;
+    let mut root = if only.is_some() {
+        root::open_root(&root_b2, &mut roots, &target, &prompt_yes_no).await?
+    } else {
+        root::open_create_root_in_bucket(b2.as_ref(), &root_b2, &mut roots, &target, bucket, &prompt_yes_no).await?
+    };
+    if args.get_flag("save-options") {
+        root.settings = flags.clone();
+        for saved_root in roots.iter_mut().filter(|saved_root| saved_root.path == target) {
+            saved_root.settings = flags.clone();
+        }
+        if let Err(err) = root::save_roots(b2.as_ref(), &roots).await {
+            root.unlock().await?;
+            return Err(err);
+        }
+        println!(
+            "Saved the options of every future backup of {}: {}",
+            target.display(),
+            describe(&flags)
+        );
+    } else if root.settings != RootSettings::default() {
+        println!("Using the options saved for this backup: {}", describe(&root.settings));
+    }
+    let arc_root = Arc::new(root.clone());
+
+    if let Some(sd_notify) = &sd_notify {
+        sd_notify.notify("READY=1");
+        sd_notify.start_watchdog();
+        sd_notify.status(&format!("Backing up {}", path.display()));
+    }
+
+    let saved = &root.settings;
+    let options = BackupOptions {
+        keep_existing: flags.keep_existing || saved.keep_existing,
+        delete_after: flags
+            .delete_after_secs
+            .or(saved.delete_after_secs)
+            .map(Duration::from_secs),
+        strict_scan: flags.strict_scan || saved.strict_scan,
+        verify: flags.verify || saved.verify,
+        only,
+        diff_strategy,
+        min_age,
+        exclude,
+        scan_cache: args.get_flag("scan-cache"),
+    };
+    // The backup reads from the snapshot, but is saved under the path of the source
+    let snapshot = match snapshot_kind {
+        Some(kind) => {
+            status(&format!("Taking a snapshot of {}", path.display()));
+            match Snapshot::create(kind, &path, &config.snapshot) {
+                Ok(snapshot) => Some(snapshot),
+                Err(err) => {
+                    root.unlock().await?;
+                    return Err(err);
                 }
-                num_delete_actions += 1;
-                action_futs.spawn(action::delete(rate_limiter.clone(), delete_progress.clone(), rfile))?;
             }
-            FileDiff {
-                local: None,
-                remote: None,
-            } => unreachable!(),
+        }
+        None => None,
+    };
+    let source = snapshot
+        .as_ref()
+        .map_or_else(|| path.clone(), |snapshot| snapshot.path().to_owned());
+    let session = BackupSession::new(config, root_b2.clone(), arc_root, source, options);
+    let mut audit = AuditRecord::start(AuditOperation::Backup);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    if let Some(snapshot) = snapshot {
+        if let Err(err) = snapshot.remove() {
+            eprintln!("{:#}", err);
         }
     }
-
-    let unfinished_large_files = unfinished_large_files_fut.await??;
-    for garbage in unfinished_large_files {
-        num_cleanup_actions += 1;
-        action_futs.spawn(action::delete(rate_limiter.clone(), cleanup_progress.clone(), garbage))?;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(root_b2.as_ref(), &root.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+    let uploaded_bytes = stats::snapshot()
+        .iter()
+        .find(|stats| stats.stage == Stage::Upload)
+        .map_or(0, |stats| stats.bytes);
+    let summary = RunSummary::new(&target, &audit, uploaded_bytes);
+    if let Err(err) = report::record(&Config::get_run_summaries_path(), &summary) {
+        eprintln!("Failed to save the run summary: {:#}", err);
     }
+    notifications::send_report(config.email.as_ref(), &target, &audit).await;
 
-    let cleanup_progress = progress.show_progress_bar(ProgressType::Cleanup, num_cleanup_actions);
-    let delete_progress = progress.show_progress_bar(ProgressType::Delete, num_delete_actions);
-    let upload_progress = progress.show_progress_bar(ProgressType::Upload, num_upload_actions);
-    diff_progress.report_success();
-    diff_progress.finish();
+    if let Some(sd_notify) = &sd_notify {
+        sd_notify.notify("STOPPING=1\nSTATUS=Unlocking the backup");
+    }
+    root.unlock().await?;
+    result
+}
 
-    let packed_local_dirdb = local_dirdb.to_packed(&b2.key)?;
-    action_futs.for_each(|()| futures::future::ready(())).await;
-    cleanup_progress.finish();
-    upload_progress.finish();
-    delete_progress.finish();
-    let (complete, err_count) = (progress.is_complete(), progress.errors_count());
-    drop(progress);
+/// Resolves the folder of `backup --only` relative to the source, the path can also be absolute inside the source
+/// The files of frozen in the source that are left out: the keyfile, unless asked otherwise, and maybe the config
+fn own_files_excluded(source: &Path, args: &ArgMatches) -> Result<Vec<PathBuf>> {
+    let mut exclude = Vec::new();
+    let keyfile = to_semi_canonical_path(&Config::get_keyfile_path())?;
+    if let Ok(rel_path) = keyfile.strip_prefix(source) {
+        if !args.get_flag("include-keyfile") {
+            exclude.push(rel_path.to_owned());
+        } else if keyfile.exists() {
+            eprintln!(
+                "Warning: backing up frozen's keyfile {}, which holds the key the backup is encrypted with",
+                keyfile.display()
+            );
+        }
+    }
+    if args.get_flag("exclude-config") {
+        let config_file = to_semi_canonical_path(&Config::get_file_path())?;
+        if let Ok(rel_path) = config_file.strip_prefix(source) {
+            exclude.push(rel_path.to_owned());
+        }
+    }
+    Ok(exclude)
+}
 
-    if !complete {
-        bail!("Couldn't complete all operations, {} error(s)", err_count)
+fn only_subdir(source: &Path, subdir: &Path) -> Result<PathBuf> {
+    let full_path = to_semi_canonical_path(&source.join(subdir))?;
+    let rel_dir = match full_path.strip_prefix(source) {
+        Ok(rel_dir) if !rel_dir.as_os_str().is_empty() => rel_dir.to_owned(),
+        _ => bail!(
+            "--only must be a folder inside {}, not {}",
+            source.display(),
+            full_path.display()
+        ),
+    };
+    // Symlinks aren't followed by the backup, a symlinked folder is backed up as a link
+    if !fs::symlink_metadata(&full_path)
+        .map(|meta| meta.is_dir())
+        .unwrap_or(false)
+    {
+        bail!("{} is not a folder!", full_path.display());
     }
+    Ok(rel_dir)
+}
 
-    println!("Uploading new DirDB");
-    b2.upload_file_simple(&dirdb_path, packed_local_dirdb).await?;
-    Ok(())
+/// The command line flags that give these settings
+fn describe(settings: &RootSettings) -> String {
+    let mut flags = Vec::new();
+    if settings.keep_existing {
+        flags.push("--keep-existing".to_owned());
+    }
+    if let Some(delay) = settings.delete_after_secs {
+        flags.push(format!(
+            "--delete-after {}",
+            format_duration(Duration::from_secs(delay))
+        ));
+    }
+    if settings.strict_scan {
+        flags.push("--strict-scan".to_owned());
+    }
+    if settings.verify {
+        flags.push("--verify".to_owned());
+    }
+    if flags.is_empty() {
+        "none".to_owned()
+    } else {
+        flags.join(" ")
+    }
 }
@@ -0,0 +1,61 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::notifications;
+use frozen_core::session::{AuditedSession, ExecSession};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub async fn backup_exec(config: &Config, args: &ArgMatches) -> Result<()> {
+    let target = path_from_arg(args, "target")?;
+    let command: Vec<OsString> = args.get_many::<OsString>("command").unwrap().cloned().collect();
+    let rel_path = match args.get_one::<PathBuf>("name") {
+        Some(name) => name.clone(),
+        None => {
+            let program = Path::new(&command[0])
+                .file_name()
+                .ok_or_else(|| eyre!("Give the name of the output with --name"))?;
+            PathBuf::from(format!("{}.out", program.to_string_lossy()))
+        }
+    };
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let bucket = match roots.iter().find(|root| root.path == target) {
+        Some(existing_root) => existing_root.bucket.clone(),
+        None => args
+            .get_one::<String>("bucket")
+            .filter(|bucket| **bucket != config.bucket_name)
+            .cloned(),
+    };
+    let root_b2 = backend::connect_bucket(config, &keys, &b2, bucket.as_deref()).await?;
+    let mut root =
+        root::open_create_root_in_bucket(b2.as_ref(), &root_b2, &mut roots, &target, bucket, &prompt_yes_no).await?;
+
+    println!(
+        "Backing up the output of {} as \"{}\"",
+        command[0].to_string_lossy(),
+        rel_path.display()
+    );
+    let session = ExecSession::new(config, root_b2.clone(), Arc::new(root.clone()), rel_path, command);
+    let mut audit = AuditRecord::start(AuditOperation::Backup);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(root_b2.as_ref(), &root.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+    notifications::send_report(config.email.as_ref(), &target, &audit).await;
+
+    root.unlock().await?;
+    result
+}
@@ -0,0 +1,43 @@
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::data::bench::run_bench;
+use frozen_core::net::backend;
+use frozen_core::output::{format_size, Cell, Listing, OutputFormat};
+use std::env;
+
+pub async fn bench(config: &Config, args: &ArgMatches) -> Result<()> {
+    let format = OutputFormat::from_arg(args, "format")?;
+    let size = *args.get_one::<u32>("size").unwrap() as usize * 1024 * 1024;
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!(
+        "Benchmarking with a {} test object, compression level {}",
+        format_size(size as u64),
+        config.compression_level
+    );
+    let report = run_bench(config, &b2, size, &env::temp_dir()).await?;
+
+    let mut listing = Listing::new(&["stage", "threads", "bytes", "seconds", "throughput"]);
+    for stage in report.stages.iter() {
+        listing.push(vec![
+            Cell::text(stage.name),
+            Cell::number(stage.threads as u64),
+            Cell::size(stage.bytes),
+            Cell::text(format!("{:.2}", stage.duration.as_secs_f64())),
+            Cell::throughput(stage.bytes_per_sec()),
+        ]);
+    }
+    print!("{}", listing.render(format));
+    if format == OutputFormat::Table {
+        println!(
+            "Compressed to {} ({:.0}%)",
+            format_size(report.compressed_size),
+            report.compressed_size as f64 * 100.0 / report.size as f64
+        );
+    }
+    Ok(())
+}
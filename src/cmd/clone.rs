@@ -0,0 +1,73 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::session::{AuditedSession, SyncSession};
+use std::sync::Arc;
+
+pub async fn clone(config: &Config, args: &ArgMatches) -> Result<()> {
+    let source_path = path_from_arg(args, "backup")?;
+    let clone_path = path_from_arg(args, "new-path")?;
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    if roots.iter().any(|root| root.path == clone_path) {
+        bail!(
+            "A backup already exists for \"{}\", use frozen sync to replace its files",
+            clone_path.display()
+        );
+    }
+    // The clone lives next to the original, so every object is a server-side copy
+    let bucket = root::bucket_of(&roots, &source_path).map(str::to_owned);
+    let root_b2 = backend::connect_bucket(config, &keys, &b2, bucket.as_deref()).await?;
+
+    let mut source = root::open_root(&root_b2, &mut roots, &source_path, &prompt_yes_no).await?;
+    let mut clone =
+        match root::open_create_root_in_bucket(b2.as_ref(), &root_b2, &mut roots, &clone_path, bucket, &prompt_yes_no)
+            .await
+        {
+            Ok(clone) => clone,
+            Err(err) => {
+                source.unlock().await?;
+                return Err(err);
+            }
+        };
+
+    println!(
+        "Cloning backup \"{}\" as \"{}\"",
+        source_path.display(),
+        clone_path.display()
+    );
+    let session = SyncSession::new(
+        config,
+        root_b2.clone(),
+        Arc::new(source.clone()),
+        root_b2.clone(),
+        Arc::new(clone.clone()),
+    );
+    let mut audit = AuditRecord::start(AuditOperation::Clone);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(root_b2.as_ref(), &clone.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+
+    clone.unlock().await?;
+    source.unlock().await?;
+    if result.is_err() {
+        eprintln!(
+            "The clone is incomplete, finish it with: frozen sync \"{}\" \"{}\"",
+            source_path.display(),
+            clone_path.display()
+        );
+    }
+    result
+}
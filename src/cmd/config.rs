@@ -0,0 +1,37 @@
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::{Config, CONFIG_DIR_ENV};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub async fn config(config: &Config, args: &ArgMatches) -> Result<()> {
+    match args.subcommand().unwrap() {
+        ("migrate", sub_args) => migrate(config, sub_args).await,
+        _ => unreachable!(),
+    }
+}
+
+async fn migrate(config: &Config, args: &ArgMatches) -> Result<()> {
+    let to = args.get_one::<OsString>("to").map(PathBuf::from);
+    let to_keyring = args.get_flag("keyring");
+    if to.is_none() && !to_keyring {
+        bail!("Nothing to migrate, pass --to <dir> and/or --keyring");
+    }
+
+    if to_keyring {
+        let keys = config.get_app_keys()?;
+        let mut config = config.clone();
+        config.migrate_key_to_keyring(&keys)?;
+        println!("Moved the encryption key to the keyring");
+    }
+    if let Some(dir) = to {
+        Config::migrate_to_dir(&dir)?;
+        println!("Moved the configuration to {}", dir.display());
+        println!(
+            "Set {}={} in your environment, or frozen won't find it",
+            CONFIG_DIR_ENV,
+            dir.display()
+        );
+    }
+    Ok(())
+}
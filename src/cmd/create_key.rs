@@ -0,0 +1,48 @@
+use crate::prompt::{prompt, prompt_password};
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::crypto::{AppKeys, SecretString};
+use frozen_core::net::b2::{B2, BACKUP_KEY_CAPABILITIES};
+
+pub async fn create_key(config: &Config, args: &ArgMatches) -> Result<()> {
+    let name = args
+        .get_one::<String>("name")
+        .cloned()
+        .unwrap_or_else(|| format!("frozen-{}", config.bucket_name));
+    let keys = config.get_app_keys()?;
+
+    println!("The new key is created with a key that can manage the account, like the master application key.");
+    let master_keys = AppKeys {
+        b2_key_id: prompt("Enter its key ID (or account ID)"),
+        b2_key: prompt_password("Enter the key"),
+        encryption_key: keys.encryption_key.clone(),
+    };
+
+    println!("Connecting to Backblaze B2");
+    let b2 = B2::authenticate(config, &master_keys).await?;
+    let (key_id, key) = b2.create_key(&name, BACKUP_KEY_CAPABILITIES).await?;
+
+    println!();
+    println!("Created key {} for bucket {}", name, config.bucket_name);
+    println!("Key ID:\t{}", key_id);
+    println!("Key:\t{}", key);
+    println!("Capabilities: {}", BACKUP_KEY_CAPABILITIES.join(", "));
+    println!();
+    if !config.bucket_routes.is_empty() {
+        println!("Warning: this key can't access the other buckets that some backups are routed to");
+    }
+
+    if args.get_flag("save") {
+        let mut config = config.clone();
+        config.set_app_key(&AppKeys {
+            b2_key_id: key_id,
+            b2_key: SecretString::from(key),
+            encryption_key: keys.encryption_key,
+        })?;
+        println!("The configuration now uses the new key");
+    } else {
+        println!("B2 won't show this key again, keep it somewhere safe or run with --save to use it right away");
+    }
+    Ok(())
+}
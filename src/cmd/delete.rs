@@ -1,12 +1,17 @@
-use crate::action;
-use crate::config::Config;
-use crate::data::{paths::path_from_arg, root};
-use crate::net::b2::{FileListDepth, B2};
-use crate::net::rate_limiter::RateLimiter;
-use crate::progress::{Progress, ProgressType};
+use crate::prompt::prompt_yes_no;
 use crate::signal::interruptible;
 use clap::ArgMatches;
-use eyre::{bail, Result};
+use eyre::Result;
+use frozen_core::action;
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::dirdb::remote::RemoteDirDB;
+use frozen_core::net::backend::{self, Backend};
+use frozen_core::net::cap;
+use frozen_core::net::rate_limiter::RateLimiter;
+use frozen_core::notifications;
+use frozen_core::progress::{Progress, ProgressType};
 use futures::stream::{FuturesUnordered, StreamExt};
 use futures::task::SpawnExt;
 use std::path::Path;
@@ -17,70 +22,89 @@ pub async fn delete(config: &Config, args: &ArgMatches) -> Result<()> {
     let keys = config.get_app_keys()?;
 
     println!("Connecting to Backblaze B2");
-    let mut b2 = B2::authenticate(config, &keys).await?;
+    let b2 = backend::connect(config, &keys).await?;
 
     println!("Downloading backup metadata");
-    let mut roots = root::fetch_roots(&b2).await?;
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+
+    let root_b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
 
     println!("Deleting backup folder {}", path.display());
-    let mut root = root::open_root(&b2, &mut roots, &path).await?;
-    let result = interruptible(delete_one_root(config, &mut b2, &path, &root, &mut roots)).await;
+    let mut root = root::open_root(&root_b2, &mut roots, &path, &prompt_yes_no).await?;
+    let mut audit = AuditRecord::start(AuditOperation::Delete);
+    let result = interruptible(delete_one_root(
+        config,
+        b2.as_ref(),
+        &root_b2,
+        &path,
+        &root,
+        &mut roots,
+        &mut audit,
+    ))
+    .await;
+    // Audit records aren't under the DirDB, so they outlive the root
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(root_b2.as_ref(), &root.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+    notifications::send_report(config.email.as_ref(), &path, &audit).await;
 
     root.unlock().await?;
     result
 }
 
+/// The root is deleted from `b2` and removed from the list of roots in `roots_b2`
 async fn delete_one_root(
     config: &Config,
-    b2: &mut B2,
+    roots_b2: &dyn Backend,
+    b2: &Arc<dyn Backend>,
     path: &Path,
     root: &root::BackupRoot,
     roots: &mut Vec<root::BackupRoot>,
+    audit: &mut AuditRecord,
 ) -> Result<()> {
     // We can't start removing files without pessimizing the DirDB (or removing it entirely!)
-    let dirdb_path = "dirdb/".to_string() + &root.path_hash;
-    if let err @ Err(_) = b2.hide_file(&dirdb_path).await {
-        // If the dirdb doesn't actually exist (or is already hidden), we can continue safely
-        if !b2
-            .list_remote_files(&dirdb_path, FileListDepth::Shallow)
-            .await?
-            .is_empty()
-        {
-            return err;
-        }
-    }
+    RemoteDirDB::hide(b2.as_ref(), &root.path_hash).await?;
 
     println!("Listing remote files");
-    let rfiles = root.list_remote_files(b2).await?;
+    let rfiles = root.list_remote_files(b2.as_ref()).await?;
 
     // Give it some time to commit the hide before listing versions (best effort)
-    let dirdb_versions = b2.list_remote_file_versions(&dirdb_path).await?;
+    let names = b2.object_names();
+    let mut dirdb_versions = b2.list_remote_file_versions(&names.dirdb(&root.path_hash)).await?;
+    if let Some(legacy_dirdb) = names.legacy_dirdb(&root.path_hash) {
+        dirdb_versions.extend(b2.list_remote_file_versions(&legacy_dirdb).await?);
+    }
     println!("Deleting {} versions of the DirDB", dirdb_versions.len());
     for dirdb_version in dirdb_versions.iter().rev() {
         b2.delete_file_version(dirdb_version).await?;
     }
 
-    let progress = Progress::new(config.verbose);
-    let delete_progress = progress.show_progress_bar(ProgressType::Delete, rfiles.len());
-    b2.progress.replace(delete_progress.clone());
+    let progress = Progress::new(config.verbosity);
+    let rfiles_count = rfiles.len();
+    let delete_progress = progress.show_progress_bar(ProgressType::Delete, rfiles_count);
+    let b2 = b2.with_progress(delete_progress.clone());
 
     // Lets us wait for all backup actions to complete
     let action_futs = FuturesUnordered::new();
 
-    let rate_limiter = Arc::new(RateLimiter::new(config, b2));
+    let rate_limiter = Arc::new(RateLimiter::new(config, &b2)?);
     for rfile in rfiles {
         action_futs.spawn(action::delete(rate_limiter.clone(), delete_progress.clone(), rfile))?;
     }
     action_futs.for_each(|()| futures::future::ready(())).await;
     delete_progress.finish();
     let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+    audit.deleted = rfiles_count - err_count.min(rfiles_count);
+    audit.errors = err_count;
+    audit.error_messages = progress.errors();
     drop(progress);
 
     println!("Deleting backup root");
-    root::delete_root(b2, roots, path).await?;
+    root::delete_root(roots_b2, roots, path).await?;
 
     if !complete {
-        bail!("Couldn't complete all operations, {} error(s)", err_count)
+        return Err(cap::incomplete_failure(err_count).into());
     }
     Ok(())
 }
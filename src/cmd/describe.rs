@@ -0,0 +1,50 @@
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+
+pub async fn describe(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "target")?;
+    let description = args.get_one::<String>("description");
+    let tags = args
+        .get_many::<String>("tag")
+        .into_iter()
+        .flatten()
+        .map(|tag| root::parse_tag(tag))
+        .collect::<Result<Vec<_>>>()?;
+    let untags: Vec<&String> = args.get_many::<String>("untag").into_iter().flatten().collect();
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let root = match roots.iter_mut().find(|r| r.path == path) {
+        Some(root) => root,
+        None => bail!("Backup folder {} does not exist", path.display()),
+    };
+
+    let changed = description.is_some() || !tags.is_empty() || !untags.is_empty();
+    if let Some(description) = description {
+        root.info.description = Some(description.trim().to_owned()).filter(|description| !description.is_empty());
+    }
+    for key in untags {
+        root.info.tags.remove(key);
+    }
+    root.info.tags.extend(tags);
+
+    println!(
+        "{}: {}",
+        root.path.display(),
+        root.info.description.as_deref().unwrap_or("(no description)")
+    );
+    if !root.info.tags.is_empty() {
+        println!("Tags: {}", root.info.tags_list().join(", "));
+    }
+    if changed {
+        root::save_roots(b2.as_ref(), &roots).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::doctor::{self, Finding, Severity};
+use frozen_core::data::root;
+use frozen_core::net::b2::{B2, BACKUP_KEY_CAPABILITIES};
+use frozen_core::net::backend::{self, Backend};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub async fn doctor(config: &Config, _args: &ArgMatches) -> Result<()> {
+    let mut findings = doctor::check_config(config);
+    let keys = match config.get_app_keys() {
+        Ok(keys) => keys,
+        Err(err) => {
+            findings.push(Finding::error(
+                "Encryption key",
+                format!("Couldn't load the keys: {:#}", err),
+                "Check the password, or import the key with `frozen import-key`",
+            ));
+            return report(&findings);
+        }
+    };
+
+    println!("Connecting to Backblaze B2");
+    let b2 = match B2::authenticate(config, &keys).await {
+        Ok(b2) => b2,
+        Err(err) => {
+            findings.push(Finding::error(
+                "Bucket",
+                format!("Couldn't connect to bucket {}: {:#}", config.bucket_name, err),
+                "Check that the bucket exists and that the app key can list it, or create a key with `frozen create-key --save`",
+            ));
+            return report(&findings);
+        }
+    };
+    findings.push(Finding::ok(
+        "Bucket",
+        format!("Bucket {} exists and is visible", config.bucket_name),
+    ));
+    findings.push(doctor::check_key_capabilities(
+        &b2.capabilities,
+        &b2.key_buckets,
+        &config.bucket_name,
+        BACKUP_KEY_CAPABILITIES,
+    ));
+    for route in config.bucket_routes.iter() {
+        let mut route_config = config.clone();
+        route_config.bucket_name = route.bucket.clone();
+        if let Err(err) = B2::authenticate(&route_config, &keys).await {
+            findings.push(Finding::error(
+                "Bucket",
+                format!(
+                    "Couldn't connect to bucket {} of {}: {:#}",
+                    route.bucket,
+                    route.path.display(),
+                    err
+                ),
+                "Check that the bucket exists and that the app key can access every bucket of bucket_routes",
+            ));
+        }
+    }
+    match b2.server_date().await {
+        Ok(date) => findings.push(doctor::check_clock_skew(&date, SystemTime::now())),
+        Err(err) => findings.push(Finding::warning(
+            "Clock",
+            format!("Couldn't get the server time: {:#}", err),
+            "Check that the system clock is synchronized, e.g. with NTP",
+        )),
+    }
+
+    println!("Checking the backups");
+    let b2: Arc<dyn Backend> = Arc::new(b2);
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    for root in roots.iter() {
+        match backend::connect_bucket(config, &keys, &b2, root.bucket.as_deref()).await {
+            Ok(root_b2) => findings.extend(doctor::check_root(root_b2.as_ref(), root).await),
+            Err(err) => findings.push(Finding::error(
+                "Backup",
+                format!("Couldn't connect to the bucket of {}: {:#}", root.path.display(), err),
+                "Check that the app key can access this bucket",
+            )),
+        }
+    }
+    findings.extend(doctor::check_local_state(&Config::get_prune_cursors_path(), &roots));
+
+    report(&findings)
+}
+
+fn report(findings: &[Finding]) -> Result<()> {
+    println!();
+    for finding in findings {
+        let label = match finding.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "ERROR",
+        };
+        println!("{}\t{}: {}", label, finding.check, finding.message);
+        if let Some(fix) = &finding.fix {
+            println!("\t  -> {}", fix);
+        }
+    }
+
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    let warnings = findings.iter().filter(|f| f.severity == Severity::Warning).count();
+    println!();
+    if errors > 0 {
+        bail!("Found {} problems and {} warnings", errors, warnings);
+    }
+    if warnings > 0 {
+        println!("No problems, but {} warnings", warnings);
+    } else {
+        println!("Everything looks fine");
+    }
+    Ok(())
+}
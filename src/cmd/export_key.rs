@@ -0,0 +1,16 @@
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::mnemonic::key_to_phrase;
+
+pub async fn export_key(config: &Config, _args: &ArgMatches) -> Result<()> {
+    let keys = config.get_app_keys()?;
+
+    println!("Recovery phrase of your encryption key:");
+    println!();
+    println!("{}", key_to_phrase(&keys.encryption_key));
+    println!();
+    println!("Write it down and keep it somewhere safe. Anyone with this phrase can decrypt all your backups.");
+    println!("If you lose both your password and keyfile, `frozen import-key` recovers the key from this phrase.");
+    Ok(())
+}
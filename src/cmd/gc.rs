@@ -0,0 +1,82 @@
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::duration::{duration_from_arg, format_duration};
+use frozen_core::data::gc::{self, UNFINISHED_UPLOADS_MIN_AGE_DEFAULT};
+use frozen_core::data::{paths::path_from_arg, root, share};
+use frozen_core::net::backend;
+use frozen_core::net::cap;
+use frozen_core::progress::{LogCategory, Progress, ProgressType};
+use std::ffi::OsString;
+
+pub async fn gc(config: &Config, args: &ArgMatches) -> Result<()> {
+    let min_age = duration_from_arg(args, "older-than")?.unwrap_or(UNFINISHED_UPLOADS_MIN_AGE_DEFAULT);
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    if args.get_one::<OsString>("target").is_some() {
+        let path = path_from_arg(args, "target")?;
+        roots.retain(|root| root.path == path);
+        if roots.is_empty() {
+            return Err(eyre!("Backup does not exist for \"{}\"", path.display()));
+        }
+    }
+
+    // Share links don't belong to a backup
+    if args.get_one::<OsString>("target").is_none() {
+        let deleted = share::delete_expired_shares(b2.as_ref()).await?;
+        if deleted > 0 {
+            println!("Deleted {} expired shared files", deleted);
+        }
+    }
+
+    println!("Listing unfinished uploads older than {}", format_duration(min_age));
+    let mut stale = Vec::new();
+    for root in roots.iter() {
+        let root_b2 = backend::connect_bucket(config, &keys, &b2, root.bucket.as_deref()).await?;
+        let root_stale = gc::list_stale_unfinished_uploads(root_b2.as_ref(), &root.path_hash, min_age).await?;
+        stale.extend(root_stale.into_iter().map(|(file, age)| (root_b2.clone(), file, age)));
+    }
+    if stale.is_empty() {
+        println!("Nothing to clean up");
+        return Ok(());
+    }
+
+    let progress = Progress::new(config.verbosity);
+    let cleanup_progress = progress.show_progress_bar(ProgressType::Cleanup, stale.len());
+    interruptible(async {
+        for (root_b2, file, age) in stale {
+            if cleanup_progress.logs(LogCategory::Transfers) {
+                cleanup_progress.println(format!(
+                    "Cancelling upload of {} started {} ago",
+                    file.rel_path.display(),
+                    format_duration(age)
+                ));
+            }
+            let root_b2 = root_b2.with_progress(cleanup_progress.clone());
+            match gc::cancel_unfinished_upload(root_b2.as_ref(), &file).await {
+                Ok(()) => cleanup_progress.report_success(),
+                Err(err) => cleanup_progress.report_error(format!(
+                    "Failed to cancel upload of \"{}\": {:#}",
+                    file.rel_path.display(),
+                    err
+                )),
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    cleanup_progress.finish();
+    let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+    drop(progress);
+
+    if !complete {
+        return Err(cap::incomplete_failure(err_count).into());
+    }
+    Ok(())
+}
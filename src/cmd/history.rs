@@ -0,0 +1,47 @@
+use clap::ArgMatches;
+use eyre::{eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::duration::format_duration;
+use frozen_core::data::history;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::output::{format_size, format_timestamp};
+use std::time::Duration;
+
+pub async fn history(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "target")?;
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let root = roots
+        .iter()
+        .find(|r| r.path == path)
+        .ok_or_else(|| eyre!("Backup does not exist for \"{}\"", path.display()))?;
+    let runs = history::fetch_history(b2.as_ref(), &root.path_hash).await?;
+
+    if runs.is_empty() {
+        println!("No backup of {} was recorded", path.display());
+        return Ok(());
+    }
+    println!("Started (UTC)\tDuration\tHost\tFiles\tSize\tUploaded\tDeleted\tErrors");
+    for run in runs {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            format_timestamp(run.started),
+            format_duration(Duration::from_secs(run.duration_secs)),
+            run.host,
+            run.files_count,
+            format_size(run.total_size),
+            run.uploaded,
+            run.deleted,
+            run.errors
+        );
+    }
+
+    Ok(())
+}
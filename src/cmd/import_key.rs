@@ -0,0 +1,20 @@
+use crate::prompt::prompt_password;
+use clap::ArgMatches;
+use eyre::{ensure, Result};
+use frozen_core::config::Config;
+use frozen_core::mnemonic::key_from_phrase;
+
+pub async fn import_key(config: &Config, _args: &ArgMatches) -> Result<()> {
+    ensure!(
+        !Config::has_keyfile(),
+        "A keyfile already exists! If you want to replace it with the recovered key, please delete it first.",
+    );
+
+    let phrase = prompt_password("Enter your recovery phrase");
+    let key = key_from_phrase(phrase.expose())?;
+
+    let mut config = config.clone();
+    config.import_encryption_key(&key)?;
+    println!("Saved the recovered key as your keyfile");
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{bail, Result, WrapErr};
+use frozen_core::config::Config;
+use frozen_core::data::manifest::{self, Manifest};
+use frozen_core::data::paths::{path_from_arg, to_semi_canonical_path};
+use frozen_core::data::root;
+use frozen_core::net::backend;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub async fn import_manifest(config: &Config, args: &ArgMatches) -> Result<()> {
+    let manifest_path = args.get_one::<PathBuf>("manifest").unwrap();
+    let json = fs::read(manifest_path).wrap_err_with(|| format!("Failed to read {}", manifest_path.display()))?;
+    let manifest = Manifest::from_json(&json)?;
+    let target = match path_from_arg(args, "backup") {
+        Ok(target) => target,
+        Err(_) => to_semi_canonical_path(Path::new(&manifest.path))?,
+    };
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    // Existing roots stay in their bucket, new ones go where the objects are
+    let bucket = match roots.iter().find(|root| root.path == target) {
+        Some(existing_root) => existing_root.bucket.clone(),
+        None => Some(manifest.bucket.clone()).filter(|bucket| *bucket != config.bucket_name),
+    };
+    if bucket.as_deref().unwrap_or(&config.bucket_name) != manifest.bucket {
+        bail!(
+            "The backup of {} is stored in bucket {}, but the manifest is for bucket {}",
+            target.display(),
+            bucket.as_deref().unwrap_or(&config.bucket_name),
+            manifest.bucket
+        );
+    }
+    let root_b2 = backend::connect_bucket(config, &keys, &b2, bucket.as_deref()).await?;
+    let is_new_root = !roots.iter().any(|root| root.path == target);
+    let mut root =
+        root::open_create_root_in_bucket(b2.as_ref(), &root_b2, &mut roots, &target, bucket, &prompt_yes_no).await?;
+
+    let result = interruptible(async {
+        println!(
+            "Checking the {} files of the manifest against the backup of {}",
+            manifest.files.len(),
+            target.display()
+        );
+        let imported = manifest::import(root_b2.as_ref(), &root, &manifest).await?;
+        println!(
+            "Imported {} files, the next backup only uploads what changed since",
+            imported
+        );
+        Ok(())
+    })
+    .await;
+    root.unlock().await?;
+    // A root created for a failed import would stay empty
+    if result.is_err() && is_new_root {
+        root::delete_root(b2.as_ref(), &mut roots, &target).await?;
+    }
+    result
+}
@@ -0,0 +1,97 @@
+use clap::ArgMatches;
+use eyre::{eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::dirdb::remote::RemoteDirDB;
+use frozen_core::net::backend::{self, FileListDepth};
+use frozen_core::output::{format_size, format_timestamp};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::UNIX_EPOCH;
+
+pub async fn info(config: &Config, args: &ArgMatches) -> Result<()> {
+    let target = path_from_arg(args, "target")?;
+    let path = path_from_arg(args, "path")?;
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &target)).await?;
+    let root = roots
+        .iter()
+        .find(|r| r.path == target)
+        .ok_or_else(|| eyre!("Backup does not exist for \"{}\"", target.display()))?;
+    let rel_path = path
+        .strip_prefix(&root.path)
+        .map_err(|_| eyre!("\"{}\" is not inside \"{}\"", path.display(), root.path.display()))?;
+    let (dir_hashes, full_path_hash) = root.file_path_hashes(rel_path, b2.key())?;
+
+    let (files, versions, remote_dirdb) = futures::join!(
+        b2.list_remote_files(&full_path_hash, FileListDepth::Shallow),
+        b2.list_remote_file_versions(&full_path_hash),
+        RemoteDirDB::fetch(b2.as_ref(), &root.path_hash)
+    );
+    let remote_file = files?.into_iter().find(|file| file.full_path_hash == full_path_hash);
+    let versions_count = versions?
+        .iter()
+        .filter(|version| version.path == full_path_hash)
+        .count();
+
+    println!();
+    println!("Path:\t\t{}", path.display());
+    println!("Path hash:\t{}", full_path_hash);
+    match &remote_file {
+        Some(file) => {
+            println!("Modified:\t{} UTC", format_timestamp(file.last_modified));
+            if let Some(birthtime) = file.birthtime {
+                println!("Created:\t{} UTC", format_timestamp(birthtime));
+            }
+            println!("Mode:\t\t{:o}", file.mode);
+            println!("Symlink:\t{}", if file.is_symlink { "yes" } else { "no" });
+            println!("Stored size:\t{} ({} bytes)", format_size(file.size), file.size);
+        }
+        None => println!("Not in the latest backup"),
+    }
+    println!("Versions:\t{}", versions_count);
+
+    if let Ok(meta) = fs::symlink_metadata(&path) {
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+        let status = match &remote_file {
+            Some(file) if file.last_modified >= mtime => "up to date",
+            Some(_) => "newer than the backup, will be uploaded",
+            None => "not backed up yet",
+        };
+        println!(
+            "Local file:\tmodified {} UTC, mode {:o} ({})",
+            format_timestamp(mtime),
+            meta.permissions().mode(),
+            status
+        );
+    } else {
+        println!("Local file:\tmissing");
+    }
+
+    let mut remote_dirdb = remote_dirdb?;
+    remote_dirdb.load_shards_on_path(b2.as_ref(), &dir_hashes).await;
+    let folder = remote_dirdb.dirdb.as_ref().and_then(|dirdb| {
+        dir_hashes.iter().try_fold(&dirdb.root, |node, hash| {
+            node.subfolders.iter().find(|sub| &sub.dir_name_hash == hash)
+        })
+    });
+    match folder {
+        Some(folder) => println!(
+            "DirDB folder:\t{} files, content hash {}, stored in {}",
+            folder.total_files_count,
+            data_encoding::HEXLOWER.encode(&folder.content_hash),
+            remote_dirdb
+                .shard_name(&dir_hashes)
+                .unwrap_or_else(|| "the main DirDB".to_owned())
+        ),
+        None => println!("DirDB folder:\tnot in the DirDB"),
+    }
+
+    Ok(())
+}
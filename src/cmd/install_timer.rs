@@ -0,0 +1,131 @@
+use crate::systemd::{escape_path, quote_exec_arg};
+use clap::ArgMatches;
+use eyre::{bail, Result, WrapErr};
+use frozen_core::config::Config;
+use frozen_core::data::paths::path_from_arg;
+use frozen_core::net::schedule::parse_time_of_day;
+use std::env;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+pub async fn install_timer(_config: &Config, args: &ArgMatches) -> Result<()> {
+    let source = path_from_arg(args, "source")?;
+    if !source.is_dir() {
+        bail!("{} is not a folder!", source.display());
+    }
+    let destination = path_from_arg(args, "destination").ok();
+    let at = args.get_one::<String>("at").unwrap();
+    let minutes = parse_time_of_day(at)?;
+    let system = args.get_flag("system");
+
+    let exe = env::current_exe().wrap_err("Failed to find the frozen executable")?;
+    let mut exec_start = format!(
+        "{} backup --sd-notify {}",
+        quote_exec_arg(&exe.to_string_lossy()),
+        quote_exec_arg(&source.to_string_lossy())
+    );
+    if let Some(destination) = &destination {
+        exec_start += &format!(" {}", quote_exec_arg(&destination.to_string_lossy()));
+    }
+
+    let mut service = format!(
+        "[Unit]\n\
+         Description=Frozen backup of {path}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={exec_start}\n\
+         WatchdogSec=10min\n",
+        path = source.display(),
+        exec_start = exec_start,
+    );
+    // The backup needs the config and keyfile of the user it runs as. Not taken from the environment,
+    // which is root's under sudo.
+    let system_user = match args.get_one::<String>("user") {
+        Some(user) => Some((user, home_of(user)?)),
+        None => None,
+    };
+    if let Some((user, home)) = &system_user {
+        service += &format!("User={}\n", user);
+        service += &format!(
+            "Environment={}\n",
+            quote_exec_arg(&format!("HOME={}", home.to_string_lossy()))
+        );
+    }
+    let timer = format!(
+        "[Unit]\n\
+         Description=Nightly frozen backup of {path}\n\
+         \n\
+         [Timer]\n\
+         OnCalendar=*-*-* {hours:02}:{minutes:02}:00\n\
+         Persistent=true\n\
+         RandomizedDelaySec=10min\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        path = source.display(),
+        hours = minutes / 60,
+        minutes = minutes % 60,
+    );
+
+    let unit_name = "frozen-backup-".to_owned() + &escape_path(&source);
+    if args.get_flag("print") {
+        println!("# {}.service\n{}", unit_name, service);
+        println!("# {}.timer\n{}", unit_name, timer);
+        return Ok(());
+    }
+
+    match &system_user {
+        Some((user, _)) => println!(
+            "The backup runs as {}, which needs a keyfile: run \"frozen save-key\" as {} if it has none.",
+            user, user
+        ),
+        None if !Config::has_keyfile() => eprintln!(
+            "Warning: No keyfile saved, scheduled backups can't ask for your password. Run \"frozen save-key\" first."
+        ),
+        None => {}
+    }
+    let units_dir = if system {
+        PathBuf::from("/etc/systemd/system")
+    } else {
+        let home = env::var_os("HOME").unwrap();
+        PathBuf::from(home).join(".config/systemd/user")
+    };
+    fs::create_dir_all(&units_dir).wrap_err_with(|| format!("Failed to create {}", units_dir.display()))?;
+    for (extension, contents) in [("service", &service), ("timer", &timer)] {
+        let path = units_dir.join(format!("{}.{}", unit_name, extension));
+        fs::write(&path, contents).wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+
+    let systemctl = if system { "systemctl" } else { "systemctl --user" };
+    println!(
+        "Enable the timer with: {0} daemon-reload && {0} enable --now '{1}.timer'",
+        systemctl, unit_name
+    );
+    Ok(())
+}
+
+/// The home folder of a user, from the password database
+fn home_of(user: &str) -> Result<PathBuf> {
+    let name = CString::new(user).wrap_err_with(|| format!("Invalid user name {:?}", user))?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = ptr::null_mut();
+    let err = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err)).wrap_err_with(|| format!("Failed to look up the user {}", user));
+    }
+    if found.is_null() {
+        bail!("There is no user named {}", user);
+    }
+    let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    Ok(PathBuf::from(OsStr::from_bytes(home.to_bytes())))
+}
@@ -0,0 +1,48 @@
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::net::backend;
+use frozen_core::net::lifecycle::{set_rule, LifecycleRule};
+use frozen_core::output::{Cell, Listing, OutputFormat};
+
+pub async fn lifecycle(config: &Config, args: &ArgMatches) -> Result<()> {
+    let format = OutputFormat::from_arg(args, "format")?;
+    let prefix = args.get_one::<String>("prefix").cloned().unwrap_or_default();
+    let hide_after = args.get_one::<u32>("hide-after-days").copied();
+    let delete_after = args.get_one::<u32>("delete-after-days").copied();
+    let clear = args.get_flag("clear");
+    if clear && (hide_after.is_some() || delete_after.is_some()) {
+        bail!("--clear can't be used with --hide-after-days or --delete-after-days");
+    }
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    let mut rules = b2.lifecycle_rules().await?;
+    if clear || hide_after.is_some() || delete_after.is_some() {
+        set_rule(&mut rules, LifecycleRule {
+            file_name_prefix: prefix,
+            days_from_uploading_to_hiding: hide_after,
+            days_from_hiding_to_deleting: delete_after,
+        });
+        b2.set_lifecycle_rules(&rules).await?;
+        println!("Updated the lifecycle rules of the bucket");
+    }
+
+    if rules.is_empty() && format == OutputFormat::Table {
+        println!("No lifecycle rules, hidden file versions are kept until they're deleted explicitly");
+        return Ok(());
+    }
+    let mut listing = Listing::new(&["prefix", "hide_after_days", "delete_after_days"]);
+    for rule in rules {
+        let days = |days: Option<u32>| days.map_or_else(Cell::none, |days| Cell::number(days as u64));
+        listing.push(vec![
+            Cell::text(rule.file_name_prefix),
+            days(rule.days_from_uploading_to_hiding),
+            days(rule.days_from_hiding_to_deleting),
+        ]);
+    }
+    print!("{}", listing.render(format));
+    Ok(())
+}
@@ -1,23 +1,126 @@
-use crate::config::Config;
-use crate::data::root;
-use crate::net::b2::B2;
 use clap::ArgMatches;
 use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::data::history;
+use frozen_core::data::paths::glob_match;
+use frozen_core::data::root::{self, BackupRoot};
+use frozen_core::dirdb::cache::{DirDBCache, DIRDB_FETCH_CONCURRENCY};
+use frozen_core::dirdb::remote::RemoteDirDB;
+use frozen_core::net::backend::{self, Backend};
+use frozen_core::output::{Cell, Listing, OutputFormat};
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
 
-pub async fn list(config: &Config, _args: &ArgMatches) -> Result<()> {
+/// What we know about the state of a backup root, each part is None if it couldn't be downloaded
+struct RootStatus {
+    last_backup: Option<u64>,
+    last_backup_errors: Option<usize>,
+    files_count: Option<u64>,
+    total_size: Option<u64>,
+    locked: Option<bool>,
+}
+
+async fn root_status(backend: &dyn Backend, root: &BackupRoot, dirdb: Option<&RemoteDirDB>) -> RootStatus {
+    let (history, locked) = futures::join!(
+        history::fetch_history(backend, &root.path_hash),
+        root.is_locked(backend)
+    );
+    let last_run = history.ok().and_then(|mut runs| runs.pop());
+    let files_count = dirdb
+        .and_then(|remote| remote.dirdb.as_ref())
+        .map(|dirdb| dirdb.root.total_files_count)
+        .or_else(|| last_run.as_ref().map(|run| run.files_count));
+
+    RootStatus {
+        last_backup: last_run.as_ref().map(|run| run.started),
+        last_backup_errors: last_run.as_ref().map(|run| run.errors),
+        files_count,
+        total_size: last_run.map(|run| run.total_size),
+        locked: locked.ok(),
+    }
+}
+
+pub async fn list(config: &Config, args: &ArgMatches) -> Result<()> {
+    let format = OutputFormat::from_arg(args, "format")?;
+    let filter = args.get_one::<String>("filter");
+    let tag = args.get_one::<String>("tag");
     let keys = config.get_app_keys()?;
 
-    println!("Connecting to Backblaze B2");
-    let b2 = B2::authenticate(config, &keys).await?;
+    // Scripts read stdout, so progress messages go to stderr unless we're printing a table
+    let log = |msg: &str| match format {
+        OutputFormat::Table => println!("{}", msg),
+        _ => eprintln!("{}", msg),
+    };
+    log("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
 
-    println!("Downloading backup metadata");
-    let mut roots = root::fetch_roots(&b2).await?;
+    log("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    if let Some(filter) = filter {
+        roots.retain(|root| glob_match(filter, &root.path.to_string_lossy()));
+    }
+    if let Some(tag) = tag {
+        roots.retain(|root| root.info.has_tag(tag));
+    }
     roots.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut root_b2s = Vec::with_capacity(roots.len());
+    for root in roots.iter() {
+        root_b2s.push(backend::connect_bucket(config, &keys, &b2, root.bucket.as_deref()).await?);
+    }
+    let dirdb_requests: Vec<(&dyn Backend, &str)> = roots
+        .iter()
+        .zip(root_b2s.iter())
+        .map(|(root, root_b2)| (root_b2.as_ref(), root.path_hash.as_str()))
+        .collect();
+    let dirdbs: Vec<Option<Arc<RemoteDirDB>>> = DirDBCache::default()
+        .fetch_all(&dirdb_requests)
+        .await
+        .into_iter()
+        .map(Result::ok)
+        .collect();
+    let statuses: Vec<RootStatus> = stream::iter(roots.iter().zip(root_b2s.iter()).zip(dirdbs.iter()))
+        .map(|((root, root_b2), dirdb)| root_status(root_b2.as_ref(), root, dirdb.as_deref()))
+        .buffered(DIRDB_FETCH_CONCURRENCY)
+        .collect()
+        .await;
 
-    println!("Backed-up folders:");
-    for root in roots {
-        println!("{}\t{}", root.path_hash, root.path.display());
+    let mut listing = Listing::new(&[
+        "path",
+        "path_hash",
+        "last_backup",
+        "last_backup_errors",
+        "files",
+        "size",
+        "locked",
+        "bucket",
+        "description",
+        "tags",
+    ]);
+    for (root, status) in roots.iter().zip(statuses) {
+        listing.push(vec![
+            Cell::text(root.path.to_string_lossy()),
+            Cell::text(&root.path_hash),
+            status.last_backup.map(Cell::timestamp).into(),
+            status
+                .last_backup_errors
+                .map(|errors| Cell::number(errors as u64))
+                .into(),
+            status.files_count.map(Cell::number).into(),
+            status.total_size.map(Cell::size).into(),
+            status.locked.map(Cell::bool).into(),
+            Cell::text(root.bucket.as_deref().unwrap_or(&config.bucket_name)),
+            Cell::text(root.info.description.as_deref().unwrap_or("")),
+            Cell::text(root.info.tags_list().join(", ")),
+        ]);
     }
 
+    if format == OutputFormat::Table {
+        if listing.is_empty() {
+            println!("No backed-up folders");
+            return Ok(());
+        }
+        println!("Backed-up folders:");
+    }
+    print!("{}", listing.render(format));
     Ok(())
 }
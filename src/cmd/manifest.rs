@@ -0,0 +1,45 @@
+use clap::ArgMatches;
+use eyre::{eyre, Result, WrapErr};
+use frozen_core::config::Config;
+use frozen_core::data::manifest::Manifest;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend::{self, FileListDepth};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub async fn manifest(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "backup")?;
+    let output = args.get_one::<PathBuf>("output");
+    let keys = config.get_app_keys()?;
+
+    // The manifest may go to stdout, so progress messages go to stderr
+    eprintln!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    eprintln!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let bucket = root::bucket_of(&roots, &path);
+    let b2 = backend::connect_bucket(config, &keys, &b2, bucket).await?;
+    let root = roots
+        .iter()
+        .find(|r| r.path == path)
+        .ok_or_else(|| eyre!("Backup does not exist for \"{}\"", path.display()))?;
+
+    eprintln!("Listing backed up files");
+    let files = b2
+        .list_remote_files(&(root.path_hash.clone() + "/"), FileListDepth::Deep)
+        .await?;
+    let manifest = Manifest::new(root, bucket.unwrap_or(&config.bucket_name), &files);
+    let mut json = serde_json::to_vec_pretty(&manifest)?;
+    json.push(b'\n');
+
+    match output {
+        Some(output) => {
+            fs::write(output, json).wrap_err_with(|| format!("Failed to write {}", output.display()))?;
+            eprintln!("Wrote the manifest of {} files to {}", files.len(), output.display());
+        }
+        None => io::stdout().write_all(&json)?,
+    }
+    Ok(())
+}
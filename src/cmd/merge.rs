@@ -0,0 +1,66 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::session::{AuditedSession, MergeSession};
+use std::sync::Arc;
+
+pub async fn merge(config: &Config, args: &ArgMatches) -> Result<()> {
+    let source_path = path_from_arg(args, "src-root")?;
+    let target_path = path_from_arg(args, "dst-root")?;
+    if source_path == target_path {
+        bail!("A backup can't be merged into itself");
+    }
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let source_b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &source_path)).await?;
+    let target_b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &target_path)).await?;
+
+    let mut source = root::open_root(&source_b2, &mut roots, &source_path, &prompt_yes_no).await?;
+    let mut target = match root::open_root(&target_b2, &mut roots, &target_path, &prompt_yes_no).await {
+        Ok(target) => target,
+        Err(err) => {
+            source.unlock().await?;
+            return Err(err);
+        }
+    };
+
+    println!(
+        "Merging backup \"{}\" into \"{}\"",
+        source_path.display(),
+        target_path.display()
+    );
+    let session = MergeSession::new(
+        config,
+        source_b2.clone(),
+        Arc::new(source.clone()),
+        target_b2.clone(),
+        Arc::new(target.clone()),
+    );
+    let mut audit = AuditRecord::start(AuditOperation::Merge);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    // The deletions are in the source's trail too, which outlives the source root
+    for (root_b2, audited) in [(&target_b2, &target), (&source_b2, &source)] {
+        if let Err(err) = audit::record(root_b2.as_ref(), &audited.path_hash, &audit).await {
+            eprintln!("Failed to save the audit record: {:#}", err);
+        }
+    }
+
+    target.unlock().await?;
+    source.unlock().await?;
+    if result.is_ok() {
+        println!("Deleting backup root \"{}\"", source_path.display());
+        root::delete_root(b2.as_ref(), &mut roots, &source_path).await?;
+    }
+    result
+}
@@ -16,5 +16,80 @@ pub use unlock::unlock;
 mod rename;
 pub use rename::rename;
 
+mod history;
+pub use history::history;
+
+mod info;
+pub use info::info;
+
+mod gc;
+pub use gc::gc;
+
+mod prune;
+pub use prune::prune;
+
+mod lifecycle;
+pub use lifecycle::lifecycle;
+
+mod tree;
+pub use tree::tree;
+
+mod share;
+pub use share::share;
+
+mod install_timer;
+pub use install_timer::install_timer;
+
+mod bench;
+pub use bench::bench;
+
+mod self_test;
+pub use self_test::self_test;
+
 mod save_key;
 pub use save_key::save_key;
+
+mod config;
+pub use config::config;
+
+mod create_key;
+pub use create_key::create_key;
+
+mod export_key;
+pub use export_key::export_key;
+
+mod import_key;
+pub use import_key::import_key;
+
+mod doctor;
+pub use doctor::doctor;
+
+mod test_restore;
+pub use test_restore::test_restore;
+
+mod audit;
+pub use audit::audit;
+
+mod manifest;
+pub use manifest::manifest;
+
+mod import_manifest;
+pub use import_manifest::import_manifest;
+
+mod describe;
+pub use describe::describe;
+
+mod backup_exec;
+pub use backup_exec::backup_exec;
+
+mod sync;
+pub use sync::sync;
+
+mod clone;
+pub use clone::clone;
+
+mod merge;
+pub use merge::merge;
+
+mod report;
+pub use report::report;
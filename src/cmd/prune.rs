@@ -0,0 +1,68 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::data::duration::{duration_from_arg, format_duration};
+use frozen_core::data::prune::prune_versions;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::progress::LogCategory;
+use std::time::Duration;
+
+/// Versions replaced more recently than this might still be wanted back
+const PRUNE_MIN_AGE_DEFAULT: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+pub async fn prune(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "target")?;
+    let min_age = duration_from_arg(args, "older-than")?.unwrap_or(PRUNE_MIN_AGE_DEFAULT);
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let mut root = root::open_root(&b2, &mut roots, &path, &prompt_yes_no).await?;
+
+    println!("Deleting versions replaced more than {} ago", format_duration(min_age));
+    let result = interruptible(async {
+        // The files, then the DirDB and everything stored next to it
+        let prefixes = [
+            ("files", root.path_hash.clone() + "/"),
+            ("dirdb", b2.object_names().dirdb(&root.path_hash)),
+        ];
+        for (name, prefix) in prefixes.iter() {
+            let cursor_path = Config::get_prune_cursors_path().join(format!("{}.{}", root.path_hash, name));
+            let stats = prune_versions(
+                b2.as_ref(),
+                prefix,
+                min_age,
+                config.delete_threads as usize,
+                &cursor_path,
+                |stats| {
+                    if config.verbosity.logs(LogCategory::Transfers) {
+                        println!(
+                            "Listed {} versions of the {}, deleted {}",
+                            stats.listed, name, stats.deleted
+                        );
+                    }
+                },
+            )
+            .await?;
+            println!("Deleted {} old versions of the {}", stats.deleted, name);
+            if stats.locked > 0 {
+                println!(
+                    "Kept {} old versions of the {} that are locked by Object Lock retention",
+                    stats.locked, name
+                );
+            }
+        }
+        Ok(())
+    })
+    .await;
+
+    root.unlock().await?;
+    result
+}
@@ -1,8 +1,8 @@
-use crate::config::Config;
-use crate::data::{paths::path_from_arg, root};
-use crate::net::b2::B2;
 use clap::ArgMatches;
 use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
 
 pub async fn rename(config: &Config, args: &ArgMatches) -> Result<()> {
     let src_path = path_from_arg(args, "source")?;
@@ -11,10 +11,10 @@ pub async fn rename(config: &Config, args: &ArgMatches) -> Result<()> {
     let keys = config.get_app_keys()?;
 
     println!("Connecting to Backblaze B2");
-    let b2 = B2::authenticate(config, &keys).await?;
+    let b2 = backend::connect(config, &keys).await?;
 
     println!("Downloading backup metadata");
-    let mut roots = root::fetch_roots(&b2).await?;
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
 
     let root = match roots.iter_mut().find(|r| r.path == *src_path) {
         Some(root) => root,
@@ -25,5 +25,5 @@ pub async fn rename(config: &Config, args: &ArgMatches) -> Result<()> {
 
     println!("Renaming folder {} to {}", src_path.display(), target_path.display());
     root.rename(target_path);
-    root::save_roots(&b2, &roots).await
+    root::save_roots(b2.as_ref(), &roots).await
 }
@@ -0,0 +1,50 @@
+use clap::ArgMatches;
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::data::paths::path_from_arg;
+use frozen_core::data::report::{self, Trends};
+use frozen_core::output::{Cell, Listing, OutputFormat};
+
+pub async fn report(_config: &Config, args: &ArgMatches) -> Result<()> {
+    let format = OutputFormat::from_arg(args, "format")?;
+    let last = *args.get_one::<usize>("last").unwrap();
+    let backup = match args.contains_id("backup") {
+        true => Some(path_from_arg(args, "backup")?.display().to_string()),
+        false => None,
+    };
+
+    let mut runs = report::load(&Config::get_run_summaries_path())?;
+    if let Some(backup) = &backup {
+        runs.retain(|run| run.backup == *backup);
+    }
+    let runs = &runs[runs.len().saturating_sub(last)..];
+    if runs.is_empty() {
+        match &backup {
+            Some(backup) => println!("No backup of {} was recorded on this machine", backup),
+            None => println!("No backup was recorded on this machine"),
+        }
+        return Ok(());
+    }
+
+    let mut listing = Listing::new(&[
+        "started", "backup", "duration", "uploaded", "bytes", "deleted", "errors", "failed",
+    ]);
+    for run in runs {
+        listing.push(vec![
+            Cell::timestamp(run.started),
+            Cell::text(run.backup.clone()),
+            Cell::duration(run.duration_secs),
+            Cell::number(run.uploaded as u64),
+            Cell::size(run.uploaded_bytes),
+            Cell::number(run.deleted as u64),
+            Cell::number(run.errors as u64),
+            Cell::bool(run.failed),
+        ]);
+    }
+    print!("{}", listing.render(format));
+    // The trends are for people, scripts get the runs to compute their own
+    if format == OutputFormat::Table {
+        println!("\n{}", Trends::new(runs));
+    }
+    Ok(())
+}
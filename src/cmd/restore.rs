@@ -1,148 +1,176 @@
-use crate::action;
-use crate::config::Config;
-use crate::data::paths::path_from_bytes;
-use crate::data::{paths::path_from_arg, root};
-use crate::dirdb::dirstat::DirStat;
-use crate::dirdb::{
-    diff::{DirDiff, FileDiff},
-    DirDB,
-};
-use crate::net::b2::B2;
-use crate::net::rate_limiter::RateLimiter;
-use crate::progress::{Progress, ProgressType};
+use crate::prompt::prompt_yes_no;
 use crate::signal::interruptible;
 use clap::ArgMatches;
-use eyre::{bail, Result};
-use futures::stream::{FuturesUnordered, StreamExt};
-use futures::task::SpawnExt;
-use std::fs;
-use std::path::{Path, PathBuf};
+use eyre::{bail, Result, WrapErr};
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::checkpoint::CHECKPOINT_FILE_NAME;
+use frozen_core::data::relocation::Relocation;
+use frozen_core::data::staging::{check_staging, swap_into};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::notifications;
+use frozen_core::session::{AuditedSession, OverwritePolicy, RestoreOptions, RestoreSession, TarRestoreSession};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::task::spawn_blocking;
 
 pub async fn restore(config: &Config, args: &ArgMatches) -> Result<()> {
+    if args.get_flag("to-stdout-tar") {
+        return restore_to_stdout_tar(config, args).await;
+    }
     let path = path_from_arg(args, "source")?;
     let target = path_from_arg(args, "destination").unwrap_or_else(|_| path.clone());
-    fs::create_dir_all(&target)?;
+    let staging = path_from_arg(args, "staging").ok();
+    let overwrite = match args.get_one::<String>("overwrite") {
+        Some(name) => Some(OverwritePolicy::from_name(name)?),
+        None => None,
+    };
+    match &staging {
+        Some(staging) => check_staging(staging, &target)?,
+        None => {
+            // A staged restore keeps the previous contents, and resuming a restore is always fine
+            let resumed = target.join(CHECKPOINT_FILE_NAME).is_file();
+            if !args.get_flag("force") && overwrite.is_none() && !resumed && !is_empty_or_missing(&target)? {
+                bail!(
+                    "{} is not empty, restoring into it would replace its files. \
+                     Use --force, or --overwrite to choose which files are replaced.",
+                    target.display()
+                );
+            }
+            fs::create_dir_all(&target)?
+        }
+    }
 
     let keys = config.get_app_keys()?;
 
     println!("Connecting to Backblaze B2");
-    let b2 = B2::authenticate(config, &keys).await?;
+    let b2 = backend::connect(config, &keys).await?;
 
     println!("Downloading backup metadata");
-    let mut roots = root::fetch_roots(&b2).await?;
-    let mut root = root::open_root(&b2, &mut roots, &path).await?;
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let mut root = root::open_root(&b2, &mut roots, &path, &prompt_yes_no).await?;
     let arc_root = Arc::new(root.clone());
 
-    let restore_fut = restore_one_root(config, target, b2, arc_root);
-    let result = interruptible(restore_fut).await;
+    let relocation = match relocation_from_args(args, &root.path) {
+        Ok(relocation) => relocation,
+        Err(err) => {
+            root.unlock().await?;
+            return Err(err);
+        }
+    };
+    let options = RestoreOptions {
+        relocation,
+        first: args
+            .get_many::<String>("first")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+        overwrite: overwrite.unwrap_or_default(),
+    };
+    // A staged restore starts from scratch (or from its own checkpoint), the target is only replaced once it's done
+    let restore_dir = staging.clone().unwrap_or_else(|| target.clone());
+    let session = RestoreSession::new(config, b2.clone(), arc_root, restore_dir, options);
+    let mut audit = AuditRecord::start(AuditOperation::Restore);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(b2.as_ref(), &root.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+    notifications::send_report(config.email.as_ref(), &path, &audit).await;
 
     root.unlock().await?;
-    result
+    result?;
+    if let Some(staging) = staging {
+        swap_into(&staging, &target)?;
+        println!(
+            "Swapped the restore into {}, its previous contents are now in {}",
+            target.display(),
+            staging.display()
+        );
+    }
+    Ok(())
 }
 
-pub async fn restore_one_root(config: &Config, target: PathBuf, mut b2: B2, root: Arc<root::BackupRoot>) -> Result<()> {
-    println!("Starting diff");
-    let progress = Progress::new(config.verbose);
-    let diff_progress = progress.show_progress_bar(ProgressType::Diff, 3);
-    let download_progress = progress.get_progress_handler(ProgressType::Download);
-
-    b2.progress.replace(diff_progress.clone());
-    let b2 = Arc::new(b2);
-
-    let target_dirdb = Arc::new(DirDB::new_from_local(&target, &b2.key)?);
-    diff_progress.report_success();
-
-    let dirdb_path = "dirdb/".to_string() + &root.path_hash;
-    let remote_dirdb = b2
-        .download_file(&dirdb_path)
-        .await
-        .ok()
-        .and_then(|data| DirDB::new_from_packed(&data, &b2.key).ok());
-    diff_progress.report_success();
-
-    let mut dir_diff = DirDiff::new(root.clone(), b2.clone(), target_dirdb.clone(), &remote_dirdb)?;
-    let target = Arc::new(target);
-
-    diff_progress.println("Starting download");
-    // Lets us wait for all backup actions to complete
-    let action_futs = FuturesUnordered::new();
+/// Writes the backup to stdout as a tar archive, everything else printed goes to stderr
+async fn restore_to_stdout_tar(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "source")?;
+    if io::stdout().is_terminal() {
+        bail!("Refusing to write a tar archive to a terminal, pipe it into tar -x or a file");
+    }
+    let archive = take_stdout()?;
+    let keys = config.get_app_keys()?;
 
-    let mut num_download_actions = 0;
-    let rate_limiter = Arc::new(RateLimiter::new(config, &b2));
-    while let Some(item) = dir_diff.next().await {
-        let item = item?;
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
 
-        match item {
-            FileDiff {
-                local,
-                remote: Some(rfile),
-            } => {
-                if let Some(lfile) = local {
-                    if lfile.last_modified >= rfile.last_modified {
-                        continue;
-                    }
-                }
-                num_download_actions += 1;
-                action_futs.spawn(action::download(
-                    rate_limiter.clone(),
-                    download_progress.clone(),
-                    target.clone(),
-                    rfile,
-                ))?;
-            }
-            FileDiff {
-                local: Some(_),
-                remote: None,
-            } => (),
-            FileDiff {
-                local: None,
-                remote: None,
-            } => unreachable!(),
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let mut root = root::open_root(&b2, &mut roots, &path, &prompt_yes_no).await?;
+    let relocation = match relocation_from_args(args, &root.path) {
+        Ok(relocation) => relocation,
+        Err(err) => {
+            root.unlock().await?;
+            return Err(err);
         }
-    }
+    };
 
-    let download_progress = progress.show_progress_bar(ProgressType::Download, num_download_actions);
-    diff_progress.report_success();
-    diff_progress.finish();
+    let session = TarRestoreSession::new(
+        config,
+        b2.clone(),
+        Arc::new(root.clone()),
+        Box::new(archive),
+        relocation,
+    );
+    let mut audit = AuditRecord::start(AuditOperation::Restore);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(b2.as_ref(), &root.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+    notifications::send_report(config.email.as_ref(), &path, &audit).await;
 
-    let empty_folders_task = remote_dirdb.map(|dirdb| {
-        let target = target.clone();
-        spawn_blocking(move || {
-            // Note how the root folder doesn't have a folder name, it's just the relative root "/"
-            for subfolder in dirdb.root.subfolders {
-                restore_empty_folders(subfolder, &target);
-            }
-        })
-    });
+    root.unlock().await?;
+    result
+}
 
-    action_futs.for_each(|()| futures::future::ready(())).await;
-    download_progress.finish();
-    let (complete, err_count) = (progress.is_complete(), progress.errors_count());
-    drop(progress);
-    if let Some(task) = empty_folders_task {
-        task.await?;
+/// Takes the stdout of the process for the archive, and points stdout at stderr,
+/// so that nothing else printed (progress bars, warnings) can end up in the archive
+fn take_stdout() -> Result<File> {
+    io::stdout().flush()?;
+    let archive_fd = unsafe { libc::dup(io::stdout().as_raw_fd()) };
+    if archive_fd < 0 {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to take stdout for the archive");
     }
-
-    if !complete {
-        bail!("Couldn't complete all operations, {} error(s)", err_count)
+    if unsafe { libc::dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()) } < 0 {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to redirect stdout to stderr");
     }
-    Ok(())
+    // The fd was just duplicated, nothing else owns it
+    Ok(unsafe { File::from_raw_fd(archive_fd) })
 }
 
-fn restore_empty_folders(dir: DirStat, target: &Path) {
-    let dir_path = if let Some(dir_name) = dir.dir_name {
-        target.join(path_from_bytes(&dir_name).unwrap())
-    } else {
-        return;
-    };
-
-    if dir.total_files_count == 0 {
-        let _ = fs::create_dir(&dir_path);
-    }
+fn relocation_from_args(args: &ArgMatches, root_path: &Path) -> Result<Relocation> {
+    let moves = args
+        .get_many::<String>("relocate")
+        .into_iter()
+        .flatten()
+        .map(|arg| Relocation::parse_move(arg, root_path))
+        .collect::<Result<_>>()?;
+    Ok(Relocation {
+        strip_prefix: args.get_one::<usize>("strip-prefix").copied().unwrap_or(0),
+        moves,
+    })
+}
 
-    for subfolder in dir.subfolders {
-        restore_empty_folders(subfolder, &dir_path);
+fn is_empty_or_missing(dir: &Path) -> Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err.into()),
     }
 }
@@ -1,6 +1,6 @@
-use crate::config::Config;
 use clap::ArgMatches;
 use eyre::{ensure, Result};
+use frozen_core::config::Config;
 
 pub async fn save_key(config: &Config, _args: &ArgMatches) -> Result<()> {
     ensure!(
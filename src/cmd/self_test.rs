@@ -0,0 +1,41 @@
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::selftest::{check_remote, run_local_checks, Check};
+use frozen_core::net::backend;
+
+pub async fn self_test(config: &Config, args: &ArgMatches) -> Result<()> {
+    let keys = config.get_app_keys()?;
+
+    println!("Running local checks");
+    let mut checks = run_local_checks(&keys.encryption_key).await;
+    if !args.get_flag("offline") {
+        println!("Checking the connection to bucket {}", config.bucket_name);
+        let result = match backend::connect(config, &keys).await {
+            Ok(b2) => check_remote(b2.as_ref()).await,
+            Err(err) => Err(err),
+        };
+        checks.push(Check {
+            name: "Upload, download and delete in the bucket",
+            result,
+        });
+    }
+
+    println!();
+    let mut failed = 0;
+    for check in checks.iter() {
+        match &check.result {
+            Ok(()) => println!("ok\t{}", check.name),
+            Err(err) => {
+                failed += 1;
+                println!("FAILED\t{}: {:#}", check.name, err);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    println!();
+    println!("All {} checks passed", checks.len());
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use clap::ArgMatches;
+use eyre::{bail, eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::duration::{duration_from_arg, format_duration};
+use frozen_core::data::share::{share_file, SHARE_PREFIX};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend::{self, FileListDepth};
+use frozen_core::net::sse::SseMode;
+use std::time::Duration;
+
+pub async fn share(config: &Config, args: &ArgMatches) -> Result<()> {
+    let target = path_from_arg(args, "target")?;
+    let path = path_from_arg(args, "path")?;
+    let expires = duration_from_arg(args, "expires")?.unwrap_or(Duration::from_secs(24 * 60 * 60));
+    if config.server_side_encryption == Some(SseMode::SseC) {
+        bail!("Share links can't work with SSE-C, downloads would need the SSE-C key");
+    }
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &target)).await?;
+    let root = roots
+        .iter()
+        .find(|r| r.path == target)
+        .ok_or_else(|| eyre!("Backup does not exist for \"{}\"", target.display()))?;
+    let rel_path = path
+        .strip_prefix(&root.path)
+        .map_err(|_| eyre!("\"{}\" is not inside \"{}\"", path.display(), root.path.display()))?;
+    let (_, full_path_hash) = root.file_path_hashes(rel_path, b2.key())?;
+    let file = b2
+        .list_remote_files(&full_path_hash, FileListDepth::Shallow)
+        .await?
+        .into_iter()
+        .find(|file| file.full_path_hash == full_path_hash)
+        .ok_or_else(|| eyre!("\"{}\" is not in the latest backup", path.display()))?;
+
+    println!("Uploading a decrypted copy of {}", path.display());
+    let url = share_file(b2.as_ref(), &file, expires).await?;
+    println!();
+    println!(
+        "Anyone with this link can download the file for {}:",
+        format_duration(expires)
+    );
+    println!("{}", url);
+    println!();
+    println!(
+        "The decrypted copy is stored under {} in the bucket until `frozen gc` deletes it after the link expires",
+        SHARE_PREFIX
+    );
+    Ok(())
+}
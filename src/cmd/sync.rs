@@ -0,0 +1,74 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::audit::{self, AuditOperation, AuditRecord};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+use frozen_core::session::{AuditedSession, SyncSession};
+use std::sync::Arc;
+
+pub async fn sync(config: &Config, args: &ArgMatches) -> Result<()> {
+    let source_path = path_from_arg(args, "source")?;
+    let target_path = path_from_arg(args, "target")?;
+    if source_path == target_path {
+        bail!("A backup can't be synced into itself");
+    }
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let source_bucket = root::bucket_of(&roots, &source_path).map(str::to_owned);
+    // A new target is created next to the source, copies stay within the bucket
+    let target_bucket = match roots.iter().find(|root| root.path == target_path) {
+        Some(existing_root) => existing_root.bucket.clone(),
+        None => source_bucket.clone(),
+    };
+    let source_b2 = backend::connect_bucket(config, &keys, &b2, source_bucket.as_deref()).await?;
+    let target_b2 = backend::connect_bucket(config, &keys, &b2, target_bucket.as_deref()).await?;
+
+    let mut source = root::open_root(&source_b2, &mut roots, &source_path, &prompt_yes_no).await?;
+    let mut target = match root::open_create_root_in_bucket(
+        b2.as_ref(),
+        &target_b2,
+        &mut roots,
+        &target_path,
+        target_bucket,
+        &prompt_yes_no,
+    )
+    .await
+    {
+        Ok(target) => target,
+        Err(err) => {
+            source.unlock().await?;
+            return Err(err);
+        }
+    };
+
+    println!(
+        "Syncing backup \"{}\" into \"{}\"",
+        source_path.display(),
+        target_path.display()
+    );
+    let session = SyncSession::new(
+        config,
+        source_b2,
+        Arc::new(source.clone()),
+        target_b2.clone(),
+        Arc::new(target.clone()),
+    );
+    let mut audit = AuditRecord::start(AuditOperation::Sync);
+    let result = interruptible(session.run_audited(&mut audit)).await;
+    let audit = audit.finish(&result);
+    if let Err(err) = audit::record(target_b2.as_ref(), &target.path_hash, &audit).await {
+        eprintln!("Failed to save the audit record: {:#}", err);
+    }
+
+    target.unlock().await?;
+    source.unlock().await?;
+    result
+}
@@ -0,0 +1,71 @@
+use crate::prompt::prompt_yes_no;
+use crate::signal::interruptible;
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::test_restore::{pick_sample, restore_sample, SampleOutcome};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::net::backend;
+
+pub async fn test_restore(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "backup")?;
+    let local_base = path_from_arg(args, "local").unwrap_or_else(|_| path.clone());
+    let sample_size = *args.get_one::<usize>("sample").unwrap();
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let mut roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let mut root = root::open_root(&b2, &mut roots, &path, &prompt_yes_no).await?;
+
+    let mut results = Vec::new();
+    let result = interruptible(async {
+        println!("Listing backed up files");
+        let files = root.list_remote_files(b2.as_ref()).await?;
+        let sample = pick_sample(files, sample_size);
+        // The sample is deleted with the temporary folder, even if the test fails
+        let target = tempfile::tempdir()?;
+        println!(
+            "Restoring {} random files into {}",
+            sample.len(),
+            target.path().display()
+        );
+        results = restore_sample(config, b2.clone(), &root.path_hash, sample, target.path(), &local_base).await?;
+        Ok(())
+    })
+    .await;
+    root.unlock().await?;
+    result?;
+
+    println!();
+    let count = |outcome: fn(&SampleOutcome) -> bool| results.iter().filter(|result| outcome(&result.outcome)).count();
+    for result in results.iter() {
+        let status = match &result.outcome {
+            SampleOutcome::Identical => continue,
+            SampleOutcome::Different(reason) => format!("DIFFERENT, {}", reason),
+            SampleOutcome::ChangedLocally => "modified since the backup, not compared".to_owned(),
+            SampleOutcome::MissingLocally => "not found locally, not compared".to_owned(),
+            SampleOutcome::Failed => "FAILED to restore".to_owned(),
+        };
+        println!("{}\t{}", result.rel_path.display(), status);
+    }
+    let failures = count(SampleOutcome::is_failure);
+    println!(
+        "Restored {} files: {} identical to the local files, {} not compared, {} broken",
+        results.len(),
+        count(|outcome| *outcome == SampleOutcome::Identical),
+        count(|outcome| matches!(outcome, SampleOutcome::ChangedLocally | SampleOutcome::MissingLocally)),
+        failures
+    );
+    if failures > 0 {
+        bail!(
+            "{} of the {} sampled files couldn't be restored intact",
+            failures,
+            results.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use clap::ArgMatches;
+use eyre::{eyre, Result};
+use frozen_core::config::Config;
+use frozen_core::data::tree::{direct_sizes, tree_lines};
+use frozen_core::data::{paths::path_from_arg, root};
+use frozen_core::dirdb::remote::RemoteDirDB;
+use frozen_core::net::backend::{self, FileListDepth};
+use frozen_core::output::format_size;
+
+pub async fn tree(config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = path_from_arg(args, "target")?;
+    let max_depth = args.get_one::<usize>("depth").copied();
+    let keys = config.get_app_keys()?;
+
+    println!("Connecting to Backblaze B2");
+    let b2 = backend::connect(config, &keys).await?;
+
+    println!("Downloading backup metadata");
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let b2 = backend::connect_bucket(config, &keys, &b2, root::bucket_of(&roots, &path)).await?;
+    let root = roots
+        .iter()
+        .find(|r| r.path == path)
+        .ok_or_else(|| eyre!("Backup does not exist for \"{}\"", path.display()))?;
+    let mut remote_dirdb = RemoteDirDB::fetch(b2.as_ref(), &root.path_hash).await?;
+    remote_dirdb.load_shards_of_subtree(b2.as_ref(), &[]).await;
+    let dirdb = remote_dirdb
+        .dirdb
+        .ok_or_else(|| eyre!("The backup of \"{}\" has no usable DirDB", path.display()))?;
+
+    // The DirDB doesn't know file sizes, only listing every file does
+    let sizes = if args.get_flag("sizes") {
+        println!("Listing remote files");
+        let files = b2.list_remote_files(&root.path_hash, FileListDepth::Deep).await?;
+        Some(direct_sizes(&root.path_hash, &files))
+    } else {
+        None
+    };
+
+    println!();
+    println!("{}", root.path.display());
+    for line in tree_lines(&dirdb.root, max_depth, sizes.as_ref()).iter().skip(1) {
+        let size = line
+            .size
+            .map(|size| format!(", {}", format_size(size)))
+            .unwrap_or_default();
+        println!(
+            "{}{}/ ({} files{})",
+            "  ".repeat(line.depth),
+            line.name,
+            line.files_count,
+            size
+        );
+    }
+    println!(
+        "{} files{}",
+        dirdb.root.total_files_count,
+        sizes
+            .as_ref()
+            .map(|sizes| format!(", {} stored", format_size(sizes.values().sum())))
+            .unwrap_or_default()
+    );
+    Ok(())
+}
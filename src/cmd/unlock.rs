@@ -1,21 +1,61 @@
-use crate::config::Config;
-use crate::data::{paths::path_from_arg, root};
-use crate::net::b2::B2;
+use crate::prompt::prompt_yes_no;
 use clap::ArgMatches;
-use eyre::Result;
+use eyre::{bail, Result};
+use frozen_core::config::Config;
+use frozen_core::data::{duration::duration_from_arg, duration::format_duration, paths::path_from_arg, root};
+use frozen_core::net::backend;
 
 pub async fn unlock(config: &Config, args: &ArgMatches) -> Result<()> {
     let path = path_from_arg(args, "target")?;
+    let older_than = duration_from_arg(args, "older-than")?;
     let keys = config.get_app_keys()?;
 
     println!("Connecting to Backblaze B2");
-    let mut b2 = B2::authenticate(config, &keys).await?;
+    let b2 = backend::connect(config, &keys).await?;
 
     println!("Downloading backup metadata");
-    let roots = root::fetch_roots(&b2).await?;
+    let roots = root::fetch_roots(b2.as_ref()).await?;
+    let root = match roots.iter().find(|r| r.path == path) {
+        Some(root) => root,
+        None => bail!("Backup does not exist for \"{}\"", path.display()),
+    };
+    let b2 = backend::connect_bucket(config, &keys, &b2, root.bucket.as_deref()).await?;
 
     println!("Unlocking backup folder {}", path.display());
-    root::wipe_locks(&mut b2, &roots, &path).await?;
+    let locks = root::list_locks(b2.as_ref(), root).await?;
+    if locks.is_empty() {
+        println!("Backup folder is not locked");
+        return Ok(());
+    }
 
+    println!("Found {} lock(s):", locks.len());
+    let mut stale_locks = Vec::new();
+    for lock in locks {
+        let is_stale = older_than.map(|min_age| lock.age >= min_age).unwrap_or(true);
+        println!(
+            "\t{}\theld by {}\tlast refreshed {} ago{}",
+            lock.name,
+            lock.describe_holder(),
+            format_duration(lock.age),
+            if is_stale { "" } else { "\t(recent, kept)" }
+        );
+        if is_stale {
+            stale_locks.push(lock);
+        }
+    }
+
+    if stale_locks.is_empty() {
+        println!("No lock is old enough to be removed");
+        return Ok(());
+    }
+    if !prompt_yes_no(&format!(
+        "Remove {} lock(s)? Unlocking a folder while a backup is running is dangerous",
+        stale_locks.len()
+    )) {
+        bail!("Unlock cancelled");
+    }
+
+    root::wipe_locks(b2.as_ref(), &stale_locks).await?;
+    println!("{} lock(s) removed", stale_locks.len());
     Ok(())
 }
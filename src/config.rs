@@ -1,16 +1,42 @@
-use crate::crypto::{decrypt, derive_key, encrypt, AppKeys, Key};
-use crate::prompt::{prompt, prompt_password, prompt_yes_no};
-use eyre::{bail, Result};
+use crate::crypto::{decrypt, derive_key, encrypt, AppKeys, Key, SecretString};
+use crate::data::file_lock;
+use crate::data::fsync::FsyncPolicy;
+use crate::failure::Failure;
+use crate::keyring;
+use crate::net::chaos::ChaosOptions;
+use crate::net::endpoint::ConnectionOptions;
+use crate::net::power::PowerLimits;
+use crate::net::retention::Retention;
+use crate::net::retry::RetryLimits;
+use crate::net::s3::TransferApi;
+use crate::net::schedule::BandwidthProfile;
+use crate::net::sse::SseMode;
+use crate::notifications::EmailSettings;
+use crate::progress::{self, Verbosity};
+use crate::snapshot::SnapshotSettings;
+use eyre::{ensure, eyre, Result};
 use serde::{Deserialize, Serialize};
+use sodiumoxide::utils::memzero;
 use std::env;
 use std::error::Error;
 use std::ffi::OsString;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions, Permissions};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-static CONFIG_FILE_RELPATH: &str = ".config/frozen.json";
-static KEY_FILE_RELPATH: &str = ".config/frozen.key";
+/// Moves the config file and keyfile somewhere else than ~/.config, see `Config::migrate_to_dir`
+pub static CONFIG_DIR_ENV: &str = "FROZEN_CONFIG_DIR";
+static CONFIG_FILE_NAME: &str = "frozen.json";
+static KEY_FILE_NAME: &str = "frozen.key";
+static PRUNE_CURSORS_RELPATH: &str = ".config/frozen.prune";
+static RUN_SUMMARIES_RELPATH: &str = ".config/frozen.runs";
+/// In the folder shared by every user, two users backing up the same root must see each other
+static LOCAL_LOCKS_DIR_NAME: &str = "frozen-locks";
+/// In the folder shared by every user too, the budget is for the whole machine
+static TRANSFER_SLOTS_DIR_NAME: &str = "frozen-transfer-slots";
+static SCAN_CACHE_RELPATH: &str = ".config/frozen.scancache";
 pub static UPLOAD_THREADS_DEFAULT: u16 = 16;
 pub static DOWNLOAD_THREADS_DEFAULT: u16 = 8;
 pub static DELETE_THREADS_DEFAULT: u16 = 32;
@@ -25,7 +51,57 @@ pub struct Config {
     pub download_threads: u16,
     pub delete_threads: u16,
     pub compression_level: i32,
-    pub verbose: bool,
+    /// If set, the compression level adapts to the bottleneck, between this level and `compression_level`
+    pub min_compression_level: Option<i32>,
+    /// Transfers allowed at once across every frozen process on this machine, if limited
+    pub machine_threads: Option<u16>,
+    /// Compression, encryption and hashing stages run at once, the number of CPUs if unset (see `stream::cpu_pool`)
+    pub cpu_threads: Option<u16>,
+    /// Time-of-day limits, see `net::schedule`
+    pub bandwidth_schedule: Vec<BandwidthProfile>,
+    /// Limits while on battery or on a metered connection, see `net::power`
+    pub power_limits: PowerLimits,
+    /// Object Lock retention of uploaded files, if enabled
+    pub retention: Option<Retention>,
+    /// How long failed requests are retried, see `net::retry`
+    pub retry_limits: RetryLimits,
+    /// How connections to B2 are opened, see `net::endpoint`
+    pub connection: ConnectionOptions,
+    /// How `backup --snapshot` takes snapshots, see `snapshot`
+    pub snapshot: SnapshotSettings,
+    /// When restored files are synced to disk, see `data::fsync`
+    pub restore_fsync: FsyncPolicy,
+    /// B2 server-side encryption of uploaded files, on top of our own encryption
+    pub server_side_encryption: Option<SseMode>,
+    /// Derive the names of the list of roots and of the DirDBs from the key, see `ObjectNames`
+    pub obfuscate_names: bool,
+    /// Which B2 API uploads and downloads file data, see `net::s3`. With S3, only files uploaded in one request and
+    /// downloads go through it: large files, copies, listings and everything else stay on the native API.
+    pub transfer_api: TransferApi,
+    /// New backup roots under these paths are stored in other buckets of the account
+    pub bucket_routes: Vec<BucketRoute>,
+    /// Email a report after each backup, restore and delete, see `notifications`
+    pub email: Option<EmailSettings>,
+    /// The encryption key is in the desktop keyring instead of a keyfile, see `keyring`
+    key_in_keyring: bool,
+    /// What to log on top of the progress bars, never saved in the config file
+    pub verbosity: Verbosity,
+    /// Fault injection for resilience testing, never saved in the config file
+    pub chaos: Option<ChaosOptions>,
+    /// Asks for the password or app key when they're needed, None if nobody can answer (e.g. tests)
+    prompt: Option<Arc<dyn Prompt>>,
+}
+
+/// Asks the user for what the config can't find by itself, the binary asks on the terminal
+pub trait Prompt: Send + Sync {
+    fn text(&self, msg: &str) -> String;
+    /// Not shown while it's typed
+    fn password(&self, msg: &str) -> SecretString;
+    /// For secrets that are fine to show while they're typed (e.g. a pasted app key)
+    fn secret(&self, msg: &str) -> SecretString;
+    /// Asks for a new password twice, warning about weak ones
+    fn new_password(&self, msg: &str) -> SecretString;
+    fn yes_no(&self, msg: &str) -> bool;
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,17 +113,106 @@ struct ConfigFile {
     pub download_threads: u16,
     pub delete_threads: u16,
     pub compression_level: i32,
+    #[serde(default)]
+    pub min_compression_level: Option<i32>,
+    #[serde(default)]
+    pub machine_threads: Option<u16>,
+    #[serde(default)]
+    pub cpu_threads: Option<u16>,
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthProfile>,
+    #[serde(default)]
+    pub power_limits: PowerLimits,
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    #[serde(default)]
+    pub retry_limits: RetryLimits,
+    #[serde(default)]
+    pub connection: ConnectionOptions,
+    #[serde(default)]
+    pub snapshot: SnapshotSettings,
+    #[serde(default)]
+    pub restore_fsync: FsyncPolicy,
+    #[serde(default)]
+    pub server_side_encryption: Option<SseMode>,
+    #[serde(default)]
+    pub obfuscate_names: bool,
+    #[serde(default)]
+    pub transfer_api: TransferApi,
+    #[serde(default)]
+    pub bucket_routes: Vec<BucketRoute>,
+    #[serde(default)]
+    pub email: Option<EmailSettings>,
+    #[serde(default)]
+    pub key_in_keyring: bool,
+}
+
+/// Stores the backup roots inside `path` in `bucket`, instead of the bucket of the config
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BucketRoute {
+    pub path: PathBuf,
+    pub bucket: String,
+}
+
+impl Default for Config {
+    /// Default settings without any B2 credentials, for backends that don't need them (e.g. tests)
+    fn default() -> Self {
+        Config {
+            encrypted_app_key: Vec::new(),
+            app_key_id: String::new(),
+            bucket_name: String::new(),
+            upload_threads: UPLOAD_THREADS_DEFAULT,
+            download_threads: DOWNLOAD_THREADS_DEFAULT,
+            delete_threads: DELETE_THREADS_DEFAULT,
+            compression_level: COMPRESSION_LEVEL_DEFAULT,
+            min_compression_level: None,
+            machine_threads: None,
+            cpu_threads: None,
+            bandwidth_schedule: Vec::new(),
+            power_limits: PowerLimits::default(),
+            retention: None,
+            retry_limits: RetryLimits::default(),
+            connection: ConnectionOptions::default(),
+            snapshot: SnapshotSettings::default(),
+            restore_fsync: FsyncPolicy::default(),
+            server_side_encryption: None,
+            obfuscate_names: false,
+            transfer_api: TransferApi::Native,
+            bucket_routes: Vec::new(),
+            email: None,
+            key_in_keyring: false,
+            verbosity: Verbosity::default(),
+            chaos: None,
+            prompt: None,
+        }
+    }
 }
 
 impl Config {
-    pub fn get_or_create(verbose: bool) -> Self {
+    /// Loads the config file, or creates it with what `prompt` asks. The prompt is kept to ask for the password later.
+    pub fn get_or_create(verbosity: Verbosity, prompt: Arc<dyn Prompt>) -> Self {
+        for path in [Self::get_file_path(), Self::get_keyfile_path()] {
+            match restrict_permissions(&path) {
+                Ok(true) => progress::log(format!(
+                    "Warning: {} was readable by other users, it is now only readable by you",
+                    path.display()
+                )),
+                Ok(false) => {}
+                Err(err) => progress::log(format!(
+                    "Warning: failed to restrict permissions of {}: {}",
+                    path.display(),
+                    err
+                )),
+            }
+        }
         let mut config = Self::new_from_file().unwrap_or_else(|_| {
-            println!("No configuration found, creating it.");
-            let config = Self::new_interactive();
+            progress::log("No configuration found, creating it.");
+            let config = Self::new_interactive(prompt.as_ref());
             config.save().expect("Failed to save configuration!");
             config
         });
-        config.verbose = verbose;
+        config.verbosity = verbosity;
+        config.prompt = Some(prompt);
         config
     }
 
@@ -55,7 +220,7 @@ impl Config {
         if let Ok(app_key) = decrypt(&self.encrypted_app_key, key) {
             Some(AppKeys {
                 b2_key_id: self.app_key_id.clone(),
-                b2_key: String::from_utf8(app_key).unwrap(),
+                b2_key: SecretString::from(String::from_utf8(app_key).unwrap()),
                 encryption_key: key.to_owned(),
             })
         } else {
@@ -64,54 +229,166 @@ impl Config {
     }
 
     pub fn get_app_keys(&self) -> Result<AppKeys> {
-        if let Ok(key) = std::fs::read(Self::get_keyfile_path()) {
-            let key = Key::from_slice(key.as_slice()).expect("Invalid keyfile");
+        if let Ok(mut key_bytes) = std::fs::read(Self::get_keyfile_path()) {
+            let key = Key::from_slice(key_bytes.as_slice()).expect("Invalid keyfile");
+            memzero(&mut key_bytes);
             if let Some(app_key) = self.try_derive_app_keys(&key) {
                 return Ok(app_key);
             } else {
-                eprintln!("Found a keyfile, but failed to decrypt app keys. You may be using the wrong keyfile.");
+                progress::log("Found a keyfile, but failed to decrypt app keys. You may be using the wrong keyfile.");
+            }
+        } else if self.key_in_keyring {
+            match keyring::load() {
+                Ok(key) => match self.try_derive_app_keys(&key) {
+                    Some(app_key) => return Ok(app_key),
+                    None => progress::log("Failed to decrypt app keys with the key from the keyring."),
+                },
+                Err(err) => progress::log(format!("Failed to load the key from the keyring: {:#}", err)),
             }
         }
 
+        let prompt = self.prompt()?;
         loop {
-            let pwd = prompt_password("Enter your backup password");
-            let key = derive_key(&pwd, &self.bucket_name);
+            let pwd = prompt.password("Enter your backup password");
+            let key = derive_key(pwd.expose(), &self.bucket_name);
             if let Some(app_key) = self.try_derive_app_keys(&key) {
                 return Ok(app_key);
             }
-            if !prompt_yes_no("Invalid password, try again?") {
-                bail!("Couldn't decrypt config file");
+            if !prompt.yes_no("Invalid password, try again?") {
+                return Err(eyre!("Couldn't decrypt config file").wrap_err(Failure::Config));
             }
         }
     }
 
+    /// The bucket a new backup root at `root_path` goes to, if it's not the bucket of the config.
+    /// The most specific route wins.
+    pub fn bucket_for(&self, root_path: &Path) -> Option<&str> {
+        self.bucket_routes
+            .iter()
+            .filter(|route| root_path.starts_with(&route.path))
+            .max_by_key(|route| route.path.components().count())
+            .map(|route| route.bucket.as_str())
+            .filter(|bucket| *bucket != self.bucket_name)
+    }
+
     pub fn has_keyfile() -> bool {
         Self::get_keyfile_path().exists()
     }
 
     pub fn save_encryption_key(app_keys: &AppKeys) -> Result<()> {
         let key = app_keys.encryption_key.as_ref();
-        let mut file = File::create(Self::get_keyfile_path())?;
+        let mut file = create_private(&Self::get_keyfile_path())?;
         file.write_all(key)?;
         Ok(())
     }
 
-    fn new_interactive() -> Config {
-        let b2_key_id = prompt("Enter you app key ID (or account ID)");
-        let b2_key = prompt("Enter you app key");
-        let bucket_name = prompt("Enter your backup bucket name");
-        let passwd = prompt_password("Choose a backup password");
+    /// Moves the encryption key to the desktop keyring, and removes the keyfile if there's one
+    pub fn migrate_key_to_keyring(&mut self, app_keys: &AppKeys) -> Result<()> {
+        keyring::store(&app_keys.encryption_key)?;
+        ensure!(
+            keyring::load()? == app_keys.encryption_key,
+            "The keyring returned another key than the one stored"
+        );
+        self.key_in_keyring = true;
+        self.save()
+            .map_err(|err| eyre!("Failed to save configuration: {}", err))?;
+        let keyfile_path = Self::get_keyfile_path();
+        if keyfile_path.exists() {
+            fs::remove_file(&keyfile_path)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the config file and keyfile to `dir`. Frozen only finds them there if `CONFIG_DIR_ENV` is set to `dir`.
+    pub fn migrate_to_dir(dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let moves = [
+            (Self::get_file_path(), dir.join(CONFIG_FILE_NAME)),
+            (Self::get_keyfile_path(), dir.join(KEY_FILE_NAME)),
+        ];
+        for (from, to) in moves.iter() {
+            if from == to || !from.exists() {
+                continue;
+            }
+            ensure!(!to.exists(), "{} already exists", to.display());
+            let mut file = create_private(to)?;
+            file.write_all(&fs::read(from)?)?;
+            file.sync_all()?;
+        }
+        for (from, to) in moves.iter() {
+            if from != to && from.exists() {
+                fs::remove_file(from)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Uses a recovered encryption key from now on, and saves it as the keyfile.
+    /// If the config was created with another password since, the B2 app key is encrypted again with the recovered key.
+    pub fn import_encryption_key(&mut self, key: &Key) -> Result<()> {
+        let app_keys = match self.try_derive_app_keys(key) {
+            Some(app_keys) => app_keys,
+            None => {
+                progress::log("This config was created with another password, it needs your app key again.");
+                progress::log("Afterwards only the keyfile opens it, not that other password.");
+                let b2_key = self.prompt()?.secret("Enter you app key");
+                self.encrypted_app_key = encrypt(b2_key.expose().as_bytes(), key);
+                self.save()
+                    .map_err(|err| eyre!("Failed to save configuration: {}", err))?;
+                self.try_derive_app_keys(key).unwrap()
+            }
+        };
+        Self::save_encryption_key(&app_keys)
+    }
+
+    /// Uses another B2 app key from now on, encrypted with the encryption key like the first one
+    pub fn set_app_key(&mut self, app_keys: &AppKeys) -> Result<()> {
+        self.app_key_id = app_keys.b2_key_id.clone();
+        self.encrypted_app_key = encrypt(app_keys.b2_key.expose().as_bytes(), &app_keys.encryption_key);
+        self.save()
+            .map_err(|err| eyre!("Failed to save configuration: {}", err))
+    }
 
-        let encryption_key = derive_key(&passwd, &bucket_name);
+    fn prompt(&self) -> Result<&dyn Prompt> {
+        self.prompt
+            .as_deref()
+            .ok_or_else(|| eyre!("The key can't be found, and there is no one to ask").wrap_err(Failure::Config))
+    }
+
+    fn new_interactive(prompt: &dyn Prompt) -> Config {
+        let b2_key_id = prompt.text("Enter you app key ID (or account ID)");
+        let b2_key = prompt.secret("Enter you app key");
+        let bucket_name = prompt.text("Enter your backup bucket name");
+        let passwd = prompt.new_password("Choose a backup password");
+
+        let encryption_key = derive_key(passwd.expose(), &bucket_name);
         Config {
-            encrypted_app_key: encrypt(&Vec::from(b2_key.as_str()), &encryption_key),
+            encrypted_app_key: encrypt(b2_key.expose().as_bytes(), &encryption_key),
             app_key_id: b2_key_id,
             bucket_name,
             upload_threads: UPLOAD_THREADS_DEFAULT,
             download_threads: DOWNLOAD_THREADS_DEFAULT,
             delete_threads: DELETE_THREADS_DEFAULT,
             compression_level: COMPRESSION_LEVEL_DEFAULT,
-            verbose: false,
+            min_compression_level: None,
+            machine_threads: None,
+            cpu_threads: None,
+            bandwidth_schedule: Vec::new(),
+            power_limits: PowerLimits::default(),
+            retention: None,
+            retry_limits: RetryLimits::default(),
+            connection: ConnectionOptions::default(),
+            snapshot: SnapshotSettings::default(),
+            restore_fsync: FsyncPolicy::default(),
+            server_side_encryption: None,
+            obfuscate_names: false,
+            transfer_api: TransferApi::Native,
+            bucket_routes: Vec::new(),
+            email: None,
+            key_in_keyring: false,
+            verbosity: Verbosity::default(),
+            chaos: None,
+            prompt: None,
         }
     }
 
@@ -127,12 +404,30 @@ impl Config {
             download_threads: config_file.download_threads,
             delete_threads: config_file.delete_threads,
             compression_level: config_file.compression_level,
-            verbose: false,
+            min_compression_level: config_file.min_compression_level,
+            machine_threads: config_file.machine_threads,
+            cpu_threads: config_file.cpu_threads,
+            bandwidth_schedule: config_file.bandwidth_schedule,
+            power_limits: config_file.power_limits,
+            retention: config_file.retention,
+            retry_limits: config_file.retry_limits,
+            connection: config_file.connection,
+            snapshot: config_file.snapshot,
+            restore_fsync: config_file.restore_fsync,
+            server_side_encryption: config_file.server_side_encryption,
+            obfuscate_names: config_file.obfuscate_names,
+            transfer_api: config_file.transfer_api,
+            bucket_routes: config_file.bucket_routes,
+            email: config_file.email,
+            key_in_keyring: config_file.key_in_keyring,
+            verbosity: Verbosity::default(),
+            chaos: None,
+            prompt: None,
         })
     }
 
     fn save(&self) -> Result<(), Box<dyn Error>> {
-        let mut file = File::create(Self::get_file_path())?;
+        let mut file = create_private(&Self::get_file_path())?;
         let config_file = ConfigFile {
             encrypted_app_key: self.encrypted_app_key.clone(),
             app_key_id: self.app_key_id.clone(),
@@ -141,6 +436,22 @@ impl Config {
             download_threads: self.download_threads,
             delete_threads: self.delete_threads,
             compression_level: self.compression_level,
+            min_compression_level: self.min_compression_level,
+            machine_threads: self.machine_threads,
+            cpu_threads: self.cpu_threads,
+            bandwidth_schedule: self.bandwidth_schedule.clone(),
+            power_limits: self.power_limits.clone(),
+            retention: self.retention,
+            retry_limits: self.retry_limits,
+            connection: self.connection.clone(),
+            snapshot: self.snapshot.clone(),
+            restore_fsync: self.restore_fsync,
+            server_side_encryption: self.server_side_encryption,
+            obfuscate_names: self.obfuscate_names,
+            transfer_api: self.transfer_api,
+            bucket_routes: self.bucket_routes.clone(),
+            email: self.email.clone(),
+            key_in_keyring: self.key_in_keyring,
         };
         let encoded = serde_json::to_string(&config_file)?;
         file.set_len(0)?;
@@ -149,13 +460,120 @@ impl Config {
         Ok(())
     }
 
-    fn get_file_path() -> PathBuf {
+    /// Folder of the config file and keyfile
+    pub fn get_dir_path() -> PathBuf {
+        match env::var_os(CONFIG_DIR_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let home = env::var_os("HOME").unwrap();
+                [home, OsString::from(".config")].iter().collect()
+            }
+        }
+    }
+
+    pub fn get_file_path() -> PathBuf {
+        Self::get_dir_path().join(CONFIG_FILE_NAME)
+    }
+
+    pub fn get_keyfile_path() -> PathBuf {
+        Self::get_dir_path().join(KEY_FILE_NAME)
+    }
+
+    /// Folder of the transfer slots shared by every frozen process, see `net::transfer_slots`
+    pub fn get_transfer_slots_path() -> PathBuf {
+        file_lock::shared_dir(TRANSFER_SLOTS_DIR_NAME)
+    }
+
+    /// Folder of the cursors of interrupted prunes, see `data::prune`
+    pub fn get_prune_cursors_path() -> PathBuf {
         let home = env::var_os("HOME").unwrap();
-        [home, OsString::from(CONFIG_FILE_RELPATH)].iter().collect()
+        [home, OsString::from(PRUNE_CURSORS_RELPATH)].iter().collect()
     }
 
-    fn get_keyfile_path() -> PathBuf {
+    /// File of the summaries of the backups run on this machine, see `data::report`
+    pub fn get_run_summaries_path() -> PathBuf {
         let home = env::var_os("HOME").unwrap();
-        [home, OsString::from(KEY_FILE_RELPATH)].iter().collect()
+        [home, OsString::from(RUN_SUMMARIES_RELPATH)].iter().collect()
+    }
+
+    /// Folder of the locks of the roots in use by frozen processes of this machine, see `data::local_lock`
+    pub fn get_local_locks_path() -> PathBuf {
+        file_lock::shared_dir(LOCAL_LOCKS_DIR_NAME)
+    }
+
+    /// Folder of the scan caches of the backups run on this machine, one file per root, see `dirdb::scan_cache`
+    pub fn get_scan_cache_path() -> PathBuf {
+        let home = env::var_os("HOME").unwrap();
+        [home, OsString::from(SCAN_CACHE_RELPATH)].iter().collect()
+    }
+}
+
+/// Creates or truncates a file only readable by its owner
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// Makes an existing file only readable by its owner, returns whether it was readable by others
+fn restrict_permissions(path: &Path) -> std::io::Result<bool> {
+    let mode = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().mode(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if mode & 0o077 == 0 {
+        return Ok(false);
+    }
+    fs::set_permissions(path, Permissions::from_mode(mode & 0o700))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn roots_are_routed_to_buckets() {
+        let config = Config {
+            bucket_name: "main".to_owned(),
+            bucket_routes: serde_json::from_str(
+                r#"[{"path": "/home/me/photos", "bucket": "photos"},
+                    {"path": "/home/me/photos/raw", "bucket": "raw"},
+                    {"path": "/srv", "bucket": "main"}]"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(config.bucket_for(Path::new("/home/me/photos/2020")), Some("photos"));
+        assert_eq!(config.bucket_for(Path::new("/home/me/photos/raw/a")), Some("raw"));
+        assert_eq!(config.bucket_for(Path::new("/home/me/photoshop")), None);
+        assert_eq!(config.bucket_for(Path::new("/srv/www")), None);
+    }
+
+    #[test]
+    fn secrets_are_private() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("frozen.key");
+        assert!(!restrict_permissions(&path)?);
+
+        fs::write(&path, b"old")?;
+        fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+        assert!(restrict_permissions(&path)?);
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert!(!restrict_permissions(&path)?);
+
+        fs::set_permissions(&path, Permissions::from_mode(0o666))?;
+        create_private(&path)?.write_all(b"new")?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read(&path)?, b"new");
+        Ok(())
     }
 }
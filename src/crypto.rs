@@ -1,6 +1,7 @@
+use crate::data::file::FileMeta;
 use base64::Engine;
 use bincode::{deserialize, serialize};
-use blake2::{Blake2bMac, Digest};
+use blake2::{Blake2b, Blake2bMac, Digest};
 use data_encoding::{BASE64URL_NOPAD, HEXLOWER_PERMISSIVE};
 use digest::generic_array::GenericArray;
 use digest::{FixedOutput, Mac, Update};
@@ -8,7 +9,8 @@ use eyre::{bail, eyre, Result};
 use sha1::Sha1;
 use sodiumoxide::crypto::secretstream::{Header, Pull, Push, Stream as SecretStream};
 use sodiumoxide::crypto::{hash, pwhash, secretbox};
-use sodiumoxide::randombytes;
+use sodiumoxide::{randombytes, utils};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::vec::Vec;
 
@@ -21,12 +23,55 @@ const DIRNAME_PATH_HASH_LEN: usize = 8;
 type DirnamePathHashLenTypenum = digest::consts::U8;
 type FilenamePathHashLenTypenum = digest::consts::U12;
 
+/// BLAKE2b-256 of a file's plaintext content, recorded in its metadata
+pub type ContentHash = [u8; 32];
+
+/// The encryption key is zeroed on drop by sodiumoxide, like every `Key`
 pub struct AppKeys {
     pub b2_key_id: String,
-    pub b2_key: String,
+    pub b2_key: SecretString,
     pub encryption_key: Key,
 }
 
+/// A password, app key or recovery phrase, zeroed in memory once dropped.
+/// It's hidden from `Debug`, so that it can't end up in errors or logs.
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        // Constant time, like comparing keys
+        utils::memcmp(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for SecretString {}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(****)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // Safe because zeroes are valid UTF-8
+        utils::memzero(unsafe { self.0.as_bytes_mut() });
+    }
+}
+
 /// Derives a secret key from the user password and the salt
 pub fn derive_key(pwd: &str, salt: &str) -> Key {
     let mut key = Key([0; secretbox::KEYBYTES]);
@@ -100,48 +145,144 @@ pub fn hash_path_dir_into(
     key: &Key,
     out: &mut [u8; DIRNAME_PATH_HASH_LEN],
 ) {
-    let &Key(keydata) = key;
-    let mut hasher = Blake2bMac::<DirnamePathHashLenTypenum>::new_with_salt_and_personal(&keydata, &[], &[]).unwrap();
+    // Borrowed, a copy of the key on the stack wouldn't be zeroed
+    let Key(keydata) = key;
+    let mut hasher = Blake2bMac::<DirnamePathHashLenTypenum>::new_with_salt_and_personal(keydata, &[], &[]).unwrap();
     Mac::update(&mut hasher, dir_path_hash.as_bytes());
     Mac::update(&mut hasher, secret_dirname);
     hasher.finalize_into(GenericArray::from_mut_slice(out));
 }
 
 pub fn hash_path_filename_into(parent_hash: &[u8], secret_filename: &[u8], key: &Key, out: &mut String) {
-    let &Key(keydata) = key;
-    let mut hasher = Blake2bMac::<FilenamePathHashLenTypenum>::new_with_salt_and_personal(&keydata, &[], &[]).unwrap();
+    let Key(keydata) = key;
+    let mut hasher = Blake2bMac::<FilenamePathHashLenTypenum>::new_with_salt_and_personal(keydata, &[], &[]).unwrap();
     Mac::update(&mut hasher, parent_hash);
     Mac::update(&mut hasher, secret_filename);
     base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(hasher.finalize().into_bytes(), out);
 }
 
 pub fn hash_path_root(secret_root_path: &Path, key: &Key) -> String {
-    let &Key(keydata) = key;
-    let mut hasher = Blake2bMac::<DirnamePathHashLenTypenum>::new_with_salt_and_personal(&keydata, &[], &[]).unwrap();
+    let Key(keydata) = key;
+    let mut hasher = Blake2bMac::<DirnamePathHashLenTypenum>::new_with_salt_and_personal(keydata, &[], &[]).unwrap();
     Mac::update(&mut hasher, &serialize(secret_root_path).unwrap());
     base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize().into_bytes())
 }
 
+/// The SSE-C key we give B2, derived from the encryption key so that B2 never sees the encryption key itself
+pub fn derive_sse_customer_key(key: &Key) -> [u8; 32] {
+    let Key(keydata) = key;
+    let mut hasher =
+        Blake2bMac::<digest::consts::U32>::new_with_salt_and_personal(keydata, &[], b"frozen-sse-c").unwrap();
+    Mac::update(&mut hasher, b"server-side encryption");
+    hasher.finalize().into_bytes().into()
+}
+
+/// Obfuscated name of one of frozen's own objects, see `ObjectNames`
+pub fn hash_object_name(name: &str, key: &Key) -> String {
+    let Key(keydata) = key;
+    let mut hasher =
+        Blake2bMac::<DirnamePathHashLenTypenum>::new_with_salt_and_personal(keydata, &[], b"frozen-names").unwrap();
+    Mac::update(&mut hasher, name.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize().into_bytes())
+}
+
 pub fn sha1_string(data: &[u8]) -> String {
     let mut hash = Sha1::default();
     <Sha1 as Update>::update(&mut hash, data);
     HEXLOWER_PERMISSIVE.encode(&hash.finalize())
 }
 
+/// SHA1 of data that arrives in pieces, like an upload stream
+#[derive(Default, Clone)]
+pub struct RunningSha1(Sha1);
+
+impl RunningSha1 {
+    pub fn update(&mut self, data: &[u8]) {
+        <Sha1 as Update>::update(&mut self.0, data);
+    }
+
+    pub fn finish(self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.0.finalize())
+    }
+}
+
+/// Content hash of data that arrives in pieces, like a file being read for upload
+#[derive(Default, Clone)]
+pub struct RunningContentHash(Blake2b<digest::consts::U32>);
+
+impl RunningContentHash {
+    pub fn update(&mut self, data: &[u8]) {
+        <Blake2b<digest::consts::U32> as Update>::update(&mut self.0, data);
+    }
+
+    pub fn finish(self) -> ContentHash {
+        self.0.finalize_fixed().into()
+    }
+}
+
+pub fn content_hash(data: &[u8]) -> ContentHash {
+    let mut hash = RunningContentHash::default();
+    hash.update(data);
+    hash.finish()
+}
+
 pub fn randombytes(count: usize) -> Vec<u8> {
     randombytes::randombytes(count)
 }
 
-pub fn encode_meta(key: &Key, filename: &Path, time: u64, mode: u32, is_symlink: bool) -> String {
-    let data = (filename, time, mode, is_symlink);
-    let encoded = serialize(&data).unwrap();
+pub fn encode_meta(key: &Key, meta: &FileMeta) -> String {
+    let encoded = serialize(meta).unwrap();
     BASE64URL_NOPAD.encode(&encrypt(&encoded, key))
 }
 
-pub fn decode_meta(key: &Key, meta_enc: &str) -> Result<(PathBuf, u64, u32, bool)> {
+pub fn decode_meta(key: &Key, meta_enc: &str) -> Result<FileMeta> {
     let data = BASE64URL_NOPAD.decode(meta_enc.as_bytes())?;
     let plain = decrypt(&data, key)?;
-    Ok(deserialize(&plain[..])?)
+    if let Ok(meta) = deserialize(&plain[..]) {
+        return Ok(meta);
+    }
+
+    // Metadata saved before the original size was recorded is missing the last field
+    type WithoutOriginalSize = (PathBuf, u64, u32, bool, Option<u64>, Option<ContentHash>);
+    if let Ok((rel_path, last_modified, mode, is_symlink, birthtime, content_hash)) =
+        deserialize::<WithoutOriginalSize>(&plain[..])
+    {
+        return Ok(FileMeta {
+            rel_path,
+            last_modified,
+            mode,
+            is_symlink,
+            birthtime,
+            content_hash,
+            original_size: None,
+        });
+    }
+
+    // Before that, the content hash was also missing
+    type WithoutContentHash = (PathBuf, u64, u32, bool, Option<u64>);
+    if let Ok((rel_path, last_modified, mode, is_symlink, birthtime)) = deserialize::<WithoutContentHash>(&plain[..]) {
+        return Ok(FileMeta {
+            rel_path,
+            last_modified,
+            mode,
+            is_symlink,
+            birthtime,
+            content_hash: None,
+            original_size: None,
+        });
+    }
+
+    // And before that, the birthtime too
+    let (rel_path, last_modified, mode, is_symlink): (PathBuf, u64, u32, bool) = deserialize(&plain[..])?;
+    Ok(FileMeta {
+        rel_path,
+        last_modified,
+        mode,
+        is_symlink,
+        birthtime: None,
+        content_hash: None,
+        original_size: None,
+    })
 }
 
 #[cfg(test)]
@@ -170,6 +311,15 @@ mod tests {
         assert_eq!(a, b);
     }
 
+    #[test]
+    fn secrets_stay_hidden() {
+        let secret = SecretString::from("hunter2".to_owned());
+        assert_eq!(format!("{:?}", secret), "SecretString(****)");
+        assert_eq!(secret.expose(), "hunter2");
+        assert!(secret == secret.clone());
+        assert!(secret != SecretString::from("hunter3".to_owned()));
+    }
+
     #[test]
     fn metadata_roundtrip() {
         let key = derive_key("pass", "salt");
@@ -178,15 +328,39 @@ mod tests {
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let mode = 0o755;
-        let is_symlink = true;
-
-        let meta = encode_meta(&key, &filename, time, mode, is_symlink);
-        let (dec_filename, dec_time, dec_mode, dec_is_symlink) = decode_meta(&key, &meta).unwrap();
-        assert_eq!(filename, dec_filename);
-        assert_eq!(time, dec_time);
-        assert_eq!(mode, dec_mode);
-        assert_eq!(is_symlink, dec_is_symlink);
+        let meta = FileMeta {
+            rel_path: filename,
+            last_modified: time,
+            mode: 0o755,
+            is_symlink: true,
+            birthtime: Some(time - 10),
+            content_hash: Some(content_hash(b"content")),
+            original_size: Some(7),
+        };
+
+        let enc_meta = encode_meta(&key, &meta);
+        assert_eq!(decode_meta(&key, &enc_meta).unwrap(), meta);
+    }
+
+    #[test]
+    fn legacy_metadata_decodes() {
+        let key = derive_key("pass", "salt");
+        let legacy = serialize(&(PathBuf::from("a/b"), 42u64, 0o644u32, false)).unwrap();
+        let enc_meta = BASE64URL_NOPAD.encode(&encrypt(&legacy, &key));
+        let meta = decode_meta(&key, &enc_meta).unwrap();
+        assert_eq!((meta.rel_path.as_path(), meta.last_modified), (Path::new("a/b"), 42));
+        assert_eq!(meta.birthtime, None);
+
+        let without_hash = serialize(&(PathBuf::from("a/b"), 42u64, 0o644u32, false, Some(7u64))).unwrap();
+        let enc_meta = BASE64URL_NOPAD.encode(&encrypt(&without_hash, &key));
+        let meta = decode_meta(&key, &enc_meta).unwrap();
+        assert_eq!((meta.birthtime, meta.content_hash), (Some(7), None));
+
+        let hash = Some([3u8; 32]);
+        let without_size = serialize(&(PathBuf::from("a/b"), 42u64, 0o644u32, false, Some(7u64), hash)).unwrap();
+        let enc_meta = BASE64URL_NOPAD.encode(&encrypt(&without_size, &key));
+        let meta = decode_meta(&key, &enc_meta).unwrap();
+        assert_eq!((meta.content_hash, meta.original_size), (hash, None));
     }
 
     #[test]
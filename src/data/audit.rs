@@ -0,0 +1,146 @@
+//! An encrypted trail of the backups, restores and deletions of each root, see `frozen audit`
+//!
+//! Unlike the history (see `data::history`), every record is its own object, so machines sharing a bucket
+//! never overwrite each other's records, and the trail of a root is kept after the root is deleted.
+
+use crate::crypto;
+use crate::data::history::hostname;
+use crate::net::backend::{Backend, FileListDepth};
+use bincode::{deserialize, serialize};
+use data_encoding::HEXLOWER;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOperation {
+    Backup,
+    Restore,
+    Delete,
+    /// Recorded in the trail of the target, see `session::SyncSession`
+    Sync,
+    /// Recorded in the trail of the new root, see `frozen clone`
+    Clone,
+    /// Recorded in the trails of both roots, see `session::MergeSession`
+    Merge,
+}
+
+/// What a run did to a backup root, and who ran it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub operation: AuditOperation,
+    /// When the run started, in seconds since the Unix epoch
+    pub started: u64,
+    pub duration_secs: u64,
+    pub host: String,
+    pub user: String,
+    /// Files uploaded by a backup, downloaded by a restore, or copied by a sync, clone or merge
+    pub transferred: usize,
+    /// Files deleted from the root, or from the source of a merge
+    pub deleted: usize,
+    pub errors: usize,
+    /// Why the run failed, if it did
+    pub failure: Option<String>,
+    /// The exit code of the command whose output was backed up (see `ExecSession`), None if it was killed
+    pub exit_code: Option<i32>,
+    /// The first errors of the run, for its report (see `notifications`). Only the counts are kept in the trail.
+    #[serde(skip)]
+    pub error_messages: Vec<String>,
+}
+
+impl AuditOperation {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditOperation::Backup => "backup",
+            AuditOperation::Restore => "restore",
+            AuditOperation::Delete => "delete",
+            AuditOperation::Sync => "sync",
+            AuditOperation::Clone => "clone",
+            AuditOperation::Merge => "merge",
+        }
+    }
+}
+
+impl AuditRecord {
+    /// Starts recording a run, the counts are filled in as it goes
+    pub fn start(operation: AuditOperation) -> Self {
+        Self {
+            operation,
+            started: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            duration_secs: 0,
+            host: hostname(),
+            user: env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+            transferred: 0,
+            deleted: 0,
+            errors: 0,
+            failure: None,
+            exit_code: None,
+            error_messages: Vec::new(),
+        }
+    }
+
+    pub fn finish(mut self, result: &Result<()>) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.duration_secs = now.saturating_sub(self.started);
+        self.failure = result.as_ref().err().map(|err| format!("{:#}", err));
+        self
+    }
+}
+
+/// Adds a record to the trail of a backup root
+pub async fn record(backend: &dyn Backend, root_path_hash: &str, record: &AuditRecord) -> Result<()> {
+    // Each record is a new object with a random suffix, so concurrent runs never collide
+    let name = format!(
+        "{}{:020}-{}",
+        backend.object_names().audit(root_path_hash),
+        record.started,
+        HEXLOWER.encode(&crypto::randombytes(8))
+    );
+    let data = crypto::encrypt(&serialize(record)?, backend.key());
+    backend.upload_file_simple(&name, data).await?;
+    Ok(())
+}
+
+/// Downloads the trail of a backup root, oldest first
+pub async fn fetch_records(backend: &dyn Backend, root_path_hash: &str) -> Result<Vec<AuditRecord>> {
+    let prefix = backend.object_names().audit(root_path_hash);
+    let mut records = Vec::new();
+    for file in backend.list_remote_files(&prefix, FileListDepth::Shallow).await? {
+        let enc_data = backend.download_file(&file.full_path_hash).await?;
+        let data = crypto::decrypt(&enc_data, backend.key())?;
+        records.push(deserialize::<AuditRecord>(&data[..]).wrap_err("Failed to decode an audit record")?);
+    }
+    records.sort_by_key(|record| record.started);
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+    use eyre::eyre;
+
+    #[tokio::test]
+    async fn records_are_appended() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        assert!(fetch_records(&backend, "root").await?.is_empty());
+
+        let mut backup = AuditRecord::start(AuditOperation::Backup);
+        backup.transferred = 3;
+        let backup = backup.finish(&Ok(()));
+        record(&backend, "root", &backup).await?;
+        let mut delete = AuditRecord::start(AuditOperation::Delete);
+        delete.started += 1;
+        record(&backend, "root", &delete.finish(&Err(eyre!("lost connection")))).await?;
+        record(&backend, "other", &AuditRecord::start(AuditOperation::Restore)).await?;
+
+        let records = fetch_records(&backend, "root").await?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], backup);
+        assert_eq!(records[1].operation, AuditOperation::Delete);
+        assert_eq!(records[1].failure.as_deref(), Some("lost connection"));
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Measures the throughput of each stage of the backup pipeline, to help pick thread counts and compression levels
+
+use crate::config::Config;
+use crate::net::backend::{Backend, UploadStream};
+use crate::stream::{CompressionStream, DecompressionStream, DecryptionStream, EncryptionStream};
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use eyre::{ensure, Result, WrapErr};
+use futures::future::try_join_all;
+use futures::stream::{self, Stream, StreamExt};
+use sodiumoxide::randombytes::randombytes;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Test objects are uploaded under this prefix, outside of any backup root
+const BENCH_PREFIX: &str = "bench/";
+/// Every other block of the test data is zeroes, so that it compresses about 2:1 like typical files
+const BLOCK_SIZE: usize = 4096;
+
+/// How long a stage of the pipeline took
+pub struct StageTiming {
+    pub name: &'static str,
+    /// Transfers running at the same time, only the network stages run several
+    pub threads: u16,
+    /// Bytes going into the stage, for all threads
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl StageTiming {
+    pub fn bytes_per_sec(&self) -> u64 {
+        (self.bytes as f64 / self.duration.as_secs_f64().max(1e-9)) as u64
+    }
+}
+
+pub struct BenchReport {
+    pub stages: Vec<StageTiming>,
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+/// Sends `size` bytes of test data through the whole upload and restore pipeline, timing each stage separately.
+/// The network stages use as many concurrent transfers as the upload and download threads of the config.
+/// The test objects are deleted afterwards, even if the benchmark fails.
+pub async fn run_bench(
+    config: &Config,
+    backend: &Arc<dyn Backend>,
+    size: usize,
+    temp_dir: &Path,
+) -> Result<BenchReport> {
+    let names: Vec<String> = (0..config.upload_threads.max(1))
+        .map(|index| format!("{}{}.{}", BENCH_PREFIX, HEXLOWER.encode(&randombytes(8)), index))
+        .collect();
+    let result = run_stages(config, backend, size, temp_dir, &names).await;
+    for name in names.iter() {
+        // Best effort, the test objects are tiny next to a backup if anything is left behind
+        if let Ok(versions) = backend.list_remote_file_versions(name).await {
+            for version in versions.iter().filter(|version| &version.path == name) {
+                let _ = backend.delete_file_version(version).await;
+            }
+        }
+    }
+    result
+}
+
+async fn run_stages(
+    config: &Config,
+    backend: &Arc<dyn Backend>,
+    size: usize,
+    temp_dir: &Path,
+    names: &[String],
+) -> Result<BenchReport> {
+    let key = backend.key();
+    let mut stages = Vec::new();
+    let mut stage = |name, threads, bytes: u64, start: Instant| {
+        stages.push(StageTiming {
+            name,
+            threads,
+            bytes,
+            duration: start.elapsed(),
+        })
+    };
+
+    let data = test_data(size);
+    let path = temp_dir.join(format!("frozen-bench-{}", std::process::id()));
+    fs::write(&path, &data).wrap_err("Failed to write the test file")?;
+    let start = Instant::now();
+    let read = fs::read(&path);
+    stage("scan", 1, size as u64, start);
+    let _ = fs::remove_file(&path);
+    ensure!(read? == data, "The test file changed while we read it back");
+
+    let start = Instant::now();
+    let compressed = collect(CompressionStream::new(Cursor::new(data.clone()), config.compression_level).await).await?;
+    stage("compress", 1, size as u64, start);
+    let compressed_size = compressed.iter().map(Bytes::len).sum::<usize>() as u64;
+
+    let start = Instant::now();
+    let encrypted = collect(EncryptionStream::new(Box::new(chunks_stream(compressed)), key)).await?;
+    stage("encrypt", 1, compressed_size, start);
+    let encrypted_size = encrypted.iter().map(Bytes::len).sum::<usize>() as u64;
+
+    let start = Instant::now();
+    try_join_all(names.iter().map(|name| {
+        let encrypted = encrypted.clone();
+        async move {
+            let upload_url = backend.get_upload_url().await?;
+            let data_stream: UploadStream = Box::new(chunks_stream(encrypted));
+            backend.upload_file_stream(&upload_url, name, data_stream, None).await
+        }
+    }))
+    .await
+    .wrap_err("Failed to upload the test object")?;
+    stage("upload", names.len() as u16, encrypted_size * names.len() as u64, start);
+
+    let download_threads = config.download_threads.max(1);
+    let start = Instant::now();
+    let mut downloaded = try_join_all((0..download_threads).map(|index| async move {
+        let name = &names[index as usize % names.len()];
+        collect(backend.download_file_stream(name).await?).await
+    }))
+    .await
+    .wrap_err("Failed to download the test object")?;
+    stage(
+        "download",
+        download_threads,
+        encrypted_size * download_threads as u64,
+        start,
+    );
+
+    let start = Instant::now();
+    let decrypted = collect(DecryptionStream::new(chunks_stream(downloaded.remove(0)).boxed(), key)).await?;
+    stage("decrypt", 1, encrypted_size, start);
+
+    // Decompression writes its output to a file, like a restore does
+    let file = tempfile::tempfile_in(temp_dir).wrap_err("Failed to create the restored test file")?;
+    let start = Instant::now();
+    let mut decompressed_stream = DecompressionStream::new(Box::new(chunks_stream(decrypted)), file.try_clone()?);
+    while let Some(result) = decompressed_stream.next().await {
+        result?;
+    }
+    stage("decompress", 1, compressed_size, start);
+    drop(decompressed_stream);
+    ensure!(
+        file.metadata()?.len() == size as u64,
+        "The test object didn't round-trip through the pipeline"
+    );
+
+    Ok(BenchReport {
+        stages,
+        size: size as u64,
+        compressed_size,
+    })
+}
+
+/// Random data, with every other block zeroed
+fn test_data(size: usize) -> Vec<u8> {
+    let mut data = randombytes(size);
+    for block in data.chunks_mut(BLOCK_SIZE).skip(1).step_by(2) {
+        block.fill(0);
+    }
+    data
+}
+
+fn chunks_stream(chunks: Vec<Bytes>) -> impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync {
+    stream::iter(chunks.into_iter().map(Ok))
+}
+
+async fn collect(mut stream: impl Stream<Item = Result<Bytes>> + Unpin) -> Result<Vec<Bytes>> {
+    let mut chunks = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        chunks.push(chunk?);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bench_round_trips_and_cleans_up() -> Result<()> {
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new(test_key()));
+        let mut config = Config::default();
+        config.upload_threads = 2;
+        config.download_threads = 3;
+        let temp_dir = tempdir()?;
+
+        let report = run_bench(&config, &backend, 100_000, temp_dir.path()).await?;
+        let names: Vec<_> = report.stages.iter().map(|stage| stage.name).collect();
+        assert_eq!(names, [
+            "scan",
+            "compress",
+            "encrypt",
+            "upload",
+            "download",
+            "decrypt",
+            "decompress"
+        ]);
+        assert!(report.compressed_size < report.size * 3 / 4);
+        assert_eq!(report.stages[3].threads, 2);
+        assert_eq!(report.stages[4].threads, 3);
+        assert!(backend.list_remote_file_versions(BENCH_PREFIX).await?.is_empty());
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use eyre::{Result, WrapErr};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Written in the restore target, and removed once a restore completes
+pub const CHECKPOINT_FILE_NAME: &str = ".frozen-restore-checkpoint";
+const HEADER_PREFIX: &str = "frozen restore checkpoint ";
+
+/// Remembers which files an interrupted restore already wrote, so that rerunning it only downloads what's missing
+///
+/// The file has a header line naming the backup root, then the id of each restored file version.
+/// A checkpoint left by a restore of another root is started over.
+pub struct RestoreCheckpoint {
+    path: PathBuf,
+    done: HashSet<String>,
+    file: Mutex<File>,
+}
+
+impl RestoreCheckpoint {
+    pub fn open(target: &Path, root_path_hash: &str) -> Result<Self> {
+        let path = target.join(CHECKPOINT_FILE_NAME);
+        let header = HEADER_PREFIX.to_owned() + root_path_hash;
+        let mut done = HashSet::new();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            let mut lines = contents.lines();
+            if lines.next() == Some(header.as_str()) {
+                // The last line may be cut short if we were interrupted while writing it, that only costs a download
+                done.extend(lines.filter(|line| !line.is_empty()).map(str::to_owned));
+            }
+        }
+
+        fs::create_dir_all(target).wrap_err_with(|| format!("Failed to create {}", target.display()))?;
+        let mut file = if done.is_empty() {
+            let mut file = File::create(&path)?;
+            writeln!(file, "{}", header)?;
+            file
+        } else {
+            OpenOptions::new().append(true).open(&path)?
+        };
+        // Don't append to a truncated last line
+        file.write_all(b"\n")
+            .wrap_err_with(|| format!("Failed to write restore checkpoint {}", path.display()))?;
+        Ok(Self {
+            path,
+            done,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Number of files restored by previous attempts
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// Whether a previous attempt already restored this version of a file
+    pub fn is_done(&self, id: &str) -> bool {
+        self.done.contains(id)
+    }
+
+    /// Records a restored file, once it's complete on disk
+    pub fn mark_done(&self, id: &str) {
+        // Best effort, a restore shouldn't fail because we couldn't save progress
+        let _ = writeln!(self.file.lock().unwrap(), "{}", id);
+    }
+
+    /// The target of the unfinished restore that `path` is inside of, if any. Interrupted restores count,
+    /// their target is still missing files until they're resumed.
+    pub fn find_unfinished(path: &Path) -> Option<&Path> {
+        path.ancestors().find(|dir| dir.join(CHECKPOINT_FILE_NAME).is_file())
+    }
+
+    /// Removes the checkpoint after a complete restore
+    pub fn remove(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).wrap_err("Failed to remove the restore checkpoint")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn checkpoint_survives_reopen() -> Result<()> {
+        let target = tempdir()?;
+        let checkpoint = RestoreCheckpoint::open(target.path(), "root")?;
+        assert!(checkpoint.is_empty());
+        checkpoint.mark_done("id1");
+        checkpoint.mark_done("id2");
+        drop(checkpoint);
+
+        let checkpoint = RestoreCheckpoint::open(target.path(), "root")?;
+        assert_eq!(checkpoint.len(), 2);
+        assert!(checkpoint.is_done("id1") && checkpoint.is_done("id2"));
+        checkpoint.mark_done("id3");
+        drop(checkpoint);
+        assert_eq!(RestoreCheckpoint::open(target.path(), "root")?.len(), 3);
+
+        // Another root starts over
+        let checkpoint = RestoreCheckpoint::open(target.path(), "other")?;
+        assert!(!checkpoint.is_done("id1"));
+        let subdir = target.path().join("subdir");
+        assert_eq!(RestoreCheckpoint::find_unfinished(&subdir), Some(target.path()));
+        checkpoint.remove()?;
+        assert!(!target.path().join(CHECKPOINT_FILE_NAME).exists());
+        assert_eq!(RestoreCheckpoint::find_unfinished(&subdir), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+//! Diagnostics of the configuration, the bucket and the local state, see `frozen doctor`
+
+use crate::config::Config;
+use crate::data::duration::format_duration;
+use crate::data::gc::{list_stale_unfinished_uploads, UNFINISHED_UPLOADS_MIN_AGE_DEFAULT};
+use crate::data::root::{list_locks, BackupRoot};
+use crate::net::backend::Backend;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Locks older than this probably belong to an operation that crashed or lost its connection
+pub const STALE_LOCK_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Clocks further apart than this break B2 requests, and make lock and retention ages wrong
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+/// Clocks further apart than this are worth fixing before they get worse
+const WARN_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The result of one check, with what to do about it if it's not ok
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    pub fn ok(check: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity: Severity::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    pub fn warning(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity: Severity::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    pub fn error(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity: Severity::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Settings that can't work, or that are likely mistakes
+pub fn check_config(config: &Config) -> Vec<Finding> {
+    const CHECK: &str = "Configuration";
+    let mut findings = Vec::new();
+    if config.bucket_name.is_empty() {
+        findings.push(Finding::error(
+            CHECK,
+            "The bucket name is missing",
+            "Delete the config file and run frozen again to set it up",
+        ));
+    }
+    if config.upload_threads == 0 || config.download_threads == 0 || config.delete_threads == 0 {
+        findings.push(Finding::error(
+            CHECK,
+            "Some thread counts are 0, those operations would never make progress",
+            "Set upload_threads, download_threads and delete_threads to at least 1 in the config file",
+        ));
+    }
+    if !(1..=22).contains(&config.compression_level) {
+        findings.push(Finding::error(
+            CHECK,
+            format!("Compression level {} is not between 1 and 22", config.compression_level),
+            "Set compression_level between 1 and 22 in the config file",
+        ));
+    }
+    if let Some(route) = config.bucket_routes.iter().find(|route| route.bucket.is_empty()) {
+        findings.push(Finding::error(
+            CHECK,
+            format!("The bucket route of {} has no bucket", route.path.display()),
+            "Set a bucket for every entry of bucket_routes in the config file",
+        ));
+    }
+    if findings.is_empty() {
+        findings.push(Finding::ok(CHECK, "The config file is valid"));
+    }
+    findings
+}
+
+/// What the app key is missing out of `needed`, and whether it can access more than our bucket
+pub fn check_key_capabilities(
+    capabilities: &[String],
+    key_buckets: &[String],
+    bucket_name: &str,
+    needed: &[&str],
+) -> Finding {
+    const CHECK: &str = "App key";
+    let missing: Vec<&str> = needed
+        .iter()
+        .copied()
+        .filter(|needed| !capabilities.iter().any(|capability| capability == needed))
+        .collect();
+    if !missing.is_empty() {
+        return Finding::error(
+            CHECK,
+            format!("The app key can't {}", missing.join(", ")),
+            "Create a key with everything frozen needs with `frozen create-key --save`",
+        );
+    }
+    match key_buckets {
+        [] => Finding::warning(
+            CHECK,
+            "The app key can access every bucket of the account",
+            "Limit what a leaked key can do with `frozen create-key --save`",
+        ),
+        buckets if !buckets.iter().any(|bucket| bucket == bucket_name) => Finding::error(
+            CHECK,
+            format!("The app key can only access bucket {}", buckets.join(", ")),
+            "Create a key for the backup bucket with `frozen create-key --save`",
+        ),
+        [_] => Finding::ok(
+            CHECK,
+            "The app key has the capabilities frozen needs, and only for this bucket",
+        ),
+        buckets => Finding::ok(
+            CHECK,
+            format!(
+                "The app key has the capabilities frozen needs, for this bucket and {} others",
+                buckets.len() - 1
+            ),
+        ),
+    }
+}
+
+/// Compares our clock with the Date header of a server reply, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn check_clock_skew(server_date: &str, now: SystemTime) -> Finding {
+    const CHECK: &str = "Clock";
+    let server_time = match parse_http_date(server_date) {
+        Some(server_time) => server_time,
+        None => {
+            return Finding::warning(
+                CHECK,
+                format!("Couldn't read the server time \"{}\"", server_date),
+                "Check that the system clock is synchronized, e.g. with NTP",
+            )
+        }
+    };
+    let (skew, direction) = match now.duration_since(server_time) {
+        Ok(skew) => (skew, "ahead of"),
+        Err(err) => (err.duration(), "behind"),
+    };
+    let message = format!("The local clock is {} {} the server", format_duration(skew), direction);
+    let fix = "Synchronize the system clock, e.g. by enabling NTP";
+    if skew > MAX_CLOCK_SKEW {
+        Finding::error(CHECK, message, fix)
+    } else if skew > WARN_CLOCK_SKEW {
+        Finding::warning(CHECK, message, fix)
+    } else {
+        Finding::ok(CHECK, "The local clock matches the server")
+    }
+}
+
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = date.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT") || !(1970..10000).contains(&year) {
+        return None;
+    }
+
+    // Days since the epoch of a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hours * 3600 + minutes * 60 + seconds))
+}
+
+/// Old locks and abandoned uploads of a backup root
+pub async fn check_root(backend: &dyn Backend, root: &BackupRoot) -> Vec<Finding> {
+    const CHECK: &str = "Backup";
+    let path = root.path.display();
+    let mut findings = Vec::new();
+
+    match list_locks(backend, root).await {
+        Ok(locks) => {
+            let stale = locks.iter().filter(|lock| lock.age >= STALE_LOCK_AGE).count();
+            if stale > 0 {
+                findings.push(Finding::warning(
+                    CHECK,
+                    format!(
+                        "{} has a lock that wasn't refreshed for more than {}",
+                        path,
+                        format_duration(STALE_LOCK_AGE)
+                    ),
+                    format!(
+                        "If no backup of it is running, unlock it with `frozen unlock --older-than 1d {}`",
+                        path
+                    ),
+                ));
+            }
+        }
+        Err(err) => findings.push(Finding::error(
+            CHECK,
+            format!("Couldn't list the locks of {}: {:#}", path, err),
+            "Check that the app key can list files",
+        )),
+    }
+
+    match list_stale_unfinished_uploads(backend, &root.path_hash, UNFINISHED_UPLOADS_MIN_AGE_DEFAULT).await {
+        Ok(stale) if !stale.is_empty() => findings.push(Finding::warning(
+            CHECK,
+            format!("{} has {} abandoned uploads taking up space", path, stale.len()),
+            format!("Cancel them with `frozen gc {}`", path),
+        )),
+        Ok(_) => (),
+        Err(err) => findings.push(Finding::error(
+            CHECK,
+            format!("Couldn't list the unfinished uploads of {}: {:#}", path, err),
+            "Check that the app key can list files",
+        )),
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::ok(CHECK, format!("{} has no stale locks or uploads", path)));
+    }
+    findings
+}
+
+/// Leftovers in the local state folders, `prune_cursors_dir` is usually `Config::get_prune_cursors_path`
+pub fn check_local_state(prune_cursors_dir: &Path, roots: &[BackupRoot]) -> Vec<Finding> {
+    const CHECK: &str = "Local state";
+    let entries = match fs::read_dir(prune_cursors_dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![Finding::ok(CHECK, "No interrupted prunes")],
+    };
+
+    let (mut resumable, mut orphans) = (0, 0);
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Cursors are named after the root path hash, then what they prune
+        let root_path_hash = name.rsplit_once('.').map_or(name.as_str(), |(hash, _)| hash);
+        if roots.iter().any(|root| root.path_hash == root_path_hash) {
+            resumable += 1;
+        } else {
+            orphans += 1;
+        }
+    }
+
+    let mut findings = Vec::new();
+    if orphans > 0 {
+        findings.push(Finding::warning(
+            CHECK,
+            format!("{} prune cursors belong to backups that don't exist anymore", orphans),
+            format!("Delete the files that aren't needed in {}", prune_cursors_dir.display()),
+        ));
+    }
+    findings.push(Finding::ok(CHECK, match resumable {
+        0 => "No interrupted prunes".to_owned(),
+        resumable => format!("{} interrupted prunes will resume where they stopped", resumable),
+    }));
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::{test_backup_root, test_key};
+    use eyre::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn reads_server_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1709164800))
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(check_clock_skew(date, now).severity, Severity::Ok);
+        assert_eq!(
+            check_clock_skew(date, now + WARN_CLOCK_SKEW * 2).severity,
+            Severity::Warning
+        );
+        assert_eq!(
+            check_clock_skew(date, now - MAX_CLOCK_SKEW * 2).severity,
+            Severity::Error
+        );
+    }
+
+    #[test]
+    fn checks_key_capabilities() {
+        let capabilities = vec!["listFiles".to_owned(), "readFiles".to_owned()];
+        let needed = ["listFiles", "readFiles"];
+        let check = |key_buckets: &[&str], needed: &[&str]| {
+            let key_buckets: Vec<String> = key_buckets.iter().map(|bucket| bucket.to_string()).collect();
+            check_key_capabilities(&capabilities, &key_buckets, "b", needed)
+        };
+        assert_eq!(check(&["b"], &needed).severity, Severity::Ok);
+        assert_eq!(check(&["other", "b"], &needed).severity, Severity::Ok);
+        assert_eq!(check(&[], &needed).severity, Severity::Warning);
+        assert_eq!(check(&["other"], &needed).severity, Severity::Error);
+        assert_eq!(check(&["b"], &["writeFiles"]).severity, Severity::Error);
+    }
+
+    #[tokio::test]
+    async fn finds_stale_state() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let root = test_backup_root(&test_key());
+        assert_eq!(check_root(&backend, &root).await[0].severity, Severity::Ok);
+
+        let started = SystemTime::now() - UNFINISHED_UPLOADS_MIN_AGE_DEFAULT * 2;
+        backend.start_unfinished_upload(&(root.path_hash.clone() + "/file"), Path::new("file"), started);
+        let findings = check_root(&backend, &root).await;
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Warning);
+
+        let dir = tempdir()?;
+        assert_eq!(check_local_state(&dir.path().join("missing"), &[]).len(), 1);
+        fs::write(dir.path().join(root.path_hash.clone() + ".files"), "")?;
+        fs::write(dir.path().join("deleted.files"), "")?;
+        let findings = check_local_state(dir.path(), &[root]);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert!(findings[1].message.starts_with("1 interrupted"));
+        Ok(())
+    }
+}
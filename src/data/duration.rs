@@ -0,0 +1,110 @@
+use clap::ArgMatches;
+use eyre::{bail, eyre, Result};
+use std::time::Duration;
+
+/// Parses a human-friendly duration like "90s", "30m", "12h", "7d" or "1d12h"
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("Empty duration");
+    }
+
+    let mut total_secs = 0u64;
+    let mut num_start = 0;
+    for (pos, c) in text.char_indices() {
+        if c.is_ascii_digit() {
+            continue;
+        }
+        let num = &text[num_start..pos];
+        if num.is_empty() {
+            bail!("Invalid duration \"{}\", expected a number before '{}'", text, c);
+        }
+        let unit_secs = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => bail!("Invalid duration unit '{}' in \"{}\" (use s, m, h, d or w)", c, text),
+        };
+        let secs = num.parse::<u64>()?.checked_mul(unit_secs);
+        total_secs = secs
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(|| eyre!("Duration \"{}\" is too long", text))?;
+        num_start = pos + c.len_utf8();
+    }
+
+    // A trailing number without a unit is taken to be in seconds
+    if num_start < text.len() {
+        total_secs = total_secs
+            .checked_add(text[num_start..].parse::<u64>()?)
+            .ok_or_else(|| eyre!("Duration \"{}\" is too long", text))?;
+    }
+    Ok(Duration::from_secs(total_secs))
+}
+
+/// Formats a duration with its two most significant units, e.g. "3d 4h" or "12m 5s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Parses an optional duration command line argument
+pub fn duration_from_arg(args: &ArgMatches, name: &str) -> Result<Option<Duration>> {
+    match args.get_one::<String>(name) {
+        Some(text) => parse_duration(text)
+            .map(Some)
+            .map_err(|err| eyre!("Invalid value for \"{}\": {}", name, err)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_simple_durations() -> Result<()> {
+        assert_eq!(parse_duration("90s")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("30m")?, Duration::from_secs(30 * 60));
+        assert_eq!(parse_duration("12h")?, Duration::from_secs(12 * 3600));
+        assert_eq!(parse_duration("7d")?, Duration::from_secs(7 * 86400));
+        assert_eq!(parse_duration("2w")?, Duration::from_secs(14 * 86400));
+        assert_eq!(parse_duration("42")?, Duration::from_secs(42));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_compound_durations() -> Result<()> {
+        assert_eq!(parse_duration("1d12h")?, Duration::from_secs(36 * 3600));
+        assert_eq!(parse_duration("1h30m15")?, Duration::from_secs(5415));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("12y").is_err());
+        assert!(parse_duration("1hh").is_err());
+        assert!(parse_duration("999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1").is_err());
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m 5s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 60)), "3h 1m");
+        assert_eq!(format_duration(Duration::from_secs(2 * 86400 + 5 * 3600)), "2d 5h");
+    }
+}
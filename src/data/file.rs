@@ -1,7 +1,27 @@
+use crate::crypto::ContentHash;
 use eyre::Result;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::fs;
+use std::os::unix::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The metadata saved with each file, encrypted (see `crypto::encode_meta`)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileMeta {
+    pub rel_path: PathBuf,
+    pub last_modified: u64,
+    pub mode: u32,
+    pub is_symlink: bool,
+    /// Creation time, on platforms and filesystems that record it
+    pub birthtime: Option<u64>,
+    /// Hash of the plaintext content (or symlink target), missing for files uploaded by older versions,
+    /// and for files too large to be hashed before their upload starts
+    pub content_hash: Option<ContentHash>,
+    /// Size of the plaintext content, missing for files uploaded by older versions and for command outputs
+    pub original_size: Option<u64>,
+}
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct LocalFile {
@@ -9,6 +29,7 @@ pub struct LocalFile {
     pub full_path_hash: String,
     pub last_modified: u64,
     pub mode: u32,
+    pub birthtime: Option<u64>,
 }
 
 #[derive(Eq, Clone)]
@@ -19,14 +40,42 @@ pub struct RemoteFile {
     pub last_modified: u64,
     pub mode: u32,
     pub is_symlink: bool,
+    pub birthtime: Option<u64>,
+    pub content_hash: Option<ContentHash>,
+    /// Size of the stored (compressed and encrypted) object
+    pub size: u64,
+    /// Size of the content once restored, if known (see `FileMeta::original_size`)
+    pub original_size: Option<u64>,
 }
 
-#[derive(Clone, PartialEq, Eq)]
+/// A local file or folder that was left out of a backup because it couldn't be read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub rel_path: PathBuf,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct RemoteFileVersion {
     pub path: String,
     pub id: String,
 }
 
+impl FileMeta {
+    /// Metadata for frozen's own files (DirDBs, locks, ...), which don't come from a local file
+    pub fn new_internal(filename: &Path) -> Self {
+        Self {
+            rel_path: filename.to_owned(),
+            last_modified: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            mode: 0o644,
+            is_symlink: false,
+            birthtime: None,
+            content_hash: None,
+            original_size: None,
+        }
+    }
+}
+
 impl LocalFile {
     pub fn full_path(&self, root_path: &Path) -> PathBuf {
         root_path.join(&self.rel_path)
@@ -38,29 +87,34 @@ impl LocalFile {
             .is_symlink())
     }
 
+    /// Reads the raw target of a symlink, which doesn't have to be valid UTF-8
     pub fn readlink_at(&self, root_path: &Path) -> Result<Vec<u8>> {
-        Ok(Vec::from(
-            fs::read_link(self.full_path(root_path))?.to_str().unwrap().as_bytes(),
-        ))
+        Ok(fs::read_link(self.full_path(root_path))?.into_os_string().into_vec())
+    }
+}
+
+impl SkippedFile {
+    pub fn new(rel_path: &Path, reason: impl Into<String>) -> Self {
+        Self {
+            rel_path: rel_path.to_owned(),
+            reason: reason.into(),
+        }
     }
 }
 
 impl RemoteFile {
-    pub fn new(
-        filename: &Path,
-        fullname: &str,
-        id: &str,
-        last_modified: u64,
-        mode: u32,
-        is_symlink: bool,
-    ) -> RemoteFile {
+    pub fn new(meta: FileMeta, fullname: &str, id: &str, size: u64) -> RemoteFile {
         Self {
-            rel_path: filename.to_owned(),
+            rel_path: meta.rel_path,
             full_path_hash: fullname.to_owned(),
             id: id.to_string(),
-            last_modified,
-            mode,
-            is_symlink,
+            last_modified: meta.last_modified,
+            mode: meta.mode,
+            is_symlink: meta.is_symlink,
+            birthtime: meta.birthtime,
+            content_hash: meta.content_hash,
+            size,
+            original_size: meta.original_size,
         }
     }
 }
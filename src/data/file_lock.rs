@@ -0,0 +1,140 @@
+//! Locks that the kernel releases when their process exits, see `local_lock` and `net::transfer_slots`
+//!
+//! A lock is an `flock` on a file in a folder that every user of the machine shares. The files are never deleted
+//! and nothing is decided from what they hold, so there's no lock left behind by a dead process to take back.
+//! A lock is held by an open file, so the same process opening the file twice is refused like any other.
+
+use std::fs::{self, DirBuilder, File, OpenOptions, Permissions};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Where the folders of locks shared by every user of this machine go. Not `env::temp_dir`, which can differ per user.
+const SHARED_DIR: &str = "/tmp";
+
+/// A folder of locks shared by every user of this machine
+pub fn shared_dir(name: &str) -> PathBuf {
+    Path::new(SHARED_DIR).join(name)
+}
+
+/// An exclusive lock on a file, released when dropped
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Takes the lock of the file at `path`, creating it. None if it's held, even by another file of this process.
+    pub fn try_lock(path: &Path) -> io::Result<Option<Self>> {
+        let file = open(path)?;
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Ok(Some(Self { file })),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Waits until the lock of the file at `path` is free and takes it, blocking the thread meanwhile
+    pub fn lock(path: &Path) -> io::Result<Self> {
+        let file = open(path)?;
+        flock(&file, libc::LOCK_EX)?;
+        Ok(Self { file })
+    }
+
+    /// Says who holds the lock, for `read_holder`. The file of another user may not be writable, then it says nothing.
+    pub fn write_holder(&self, holder: &str) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.write_all_at(holder.as_bytes(), 0)
+    }
+}
+
+/// What the holder of the lock at `path` wrote about itself, empty if it didn't (yet)
+pub fn read_holder(path: &Path) -> io::Result<String> {
+    let mut holder = String::new();
+    File::open(path)?.read_to_string(&mut holder)?;
+    Ok(holder)
+}
+
+/// Opens a lock file, creating it and its folder writable by every user
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        create_shared_dir(dir)?;
+    }
+    // A symlink would be planted by another user, to make us write elsewhere
+    let created = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o666)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(path);
+    match created {
+        Ok(file) => {
+            // The mode of a new file went through the umask, only its owner can change it back
+            let _ = file.set_permissions(Permissions::from_mode(0o666));
+            Ok(file)
+        }
+        // Reading is enough to lock the file of another user
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+            .open(path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Creates a folder that every user can add files to, but not remove those of others from (like /tmp)
+fn create_shared_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o1777).create(dir) {
+        Ok(()) => fs::set_permissions(dir, Permissions::from_mode(0o1777))?,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    if !fs::symlink_metadata(dir)?.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} is in the way of the lock folder", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn locks_are_exclusive_until_dropped() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("locks/a");
+
+        let lock = FileLock::try_lock(&path)?.unwrap();
+        lock.write_holder("first")?;
+        assert!(FileLock::try_lock(&path)?.is_none());
+        assert_eq!(read_holder(&path)?, "first");
+        assert_eq!(
+            fs::metadata(dir.path().join("locks"))?.permissions().mode() & 0o7777,
+            0o1777
+        );
+
+        // The file stays, what it holds doesn't matter once its holder is gone
+        drop(lock);
+        assert!(path.exists());
+        let _again = FileLock::lock(&path)?;
+        assert!(FileLock::try_lock(&path)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! When restored files are flushed to disk
+//!
+//! A restored file is only recorded in the restore checkpoint once it's durable, otherwise a crash could leave
+//! an empty file that the next run believes is done.
+
+use crate::data::checkpoint::RestoreCheckpoint;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::task::spawn_blocking;
+
+/// Restored files are synced in batches of this many files
+const BATCH_SIZE: usize = 256;
+
+/// How restored files are synced, as saved in the config
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// Each file and its folder are synced before the next step of its download, the slowest but the most careful
+    PerFile,
+    /// Files are synced a batch at a time in the background, and at the end of the restore
+    #[default]
+    Batched,
+    /// Leaves it to the OS, a crash can lose files that the checkpoint says are restored
+    None,
+}
+
+/// Restored files waiting to be synced, before they're recorded in the checkpoint
+pub struct FsyncQueue {
+    policy: FsyncPolicy,
+    /// The paths and file ids of the restored files that aren't synced yet
+    pending: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl FsyncQueue {
+    pub fn new(policy: FsyncPolicy) -> Self {
+        Self {
+            policy,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+
+    /// Records a restored file in the checkpoint once it's synced, or queues it for the next batch
+    pub async fn saved(&self, checkpoint: &RestoreCheckpoint, path: &Path, id: &str) -> io::Result<()> {
+        match self.policy {
+            FsyncPolicy::PerFile => {
+                // The content was synced before the file was renamed, the rename itself is in the folder
+                if let Some(dir) = path.parent() {
+                    let dir = dir.to_owned();
+                    spawn_blocking(move || File::open(dir)?.sync_all()).await??;
+                }
+                checkpoint.mark_done(id);
+            }
+            FsyncPolicy::Batched => {
+                let batch = {
+                    let mut pending = self.pending.lock().unwrap();
+                    pending.push((path.to_owned(), id.to_owned()));
+                    if pending.len() < BATCH_SIZE {
+                        return Ok(());
+                    }
+                    mem::take(&mut *pending)
+                };
+                sync_batch(checkpoint, batch).await?;
+            }
+            FsyncPolicy::None => checkpoint.mark_done(id),
+        }
+        Ok(())
+    }
+
+    /// Syncs the files left in the queue, once every download is done
+    pub async fn flush(&self, checkpoint: &RestoreCheckpoint) -> io::Result<()> {
+        let batch = mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        sync_batch(checkpoint, batch).await
+    }
+}
+
+/// Syncs the files of a batch and their folders, then records them in the checkpoint
+async fn sync_batch(checkpoint: &RestoreCheckpoint, batch: Vec<(PathBuf, String)>) -> io::Result<()> {
+    let batch = spawn_blocking(move || -> io::Result<_> {
+        let mut dirs = HashSet::new();
+        for (path, _) in batch.iter() {
+            // Symlinks have nothing to sync but their folder
+            if !path.symlink_metadata()?.file_type().is_symlink() {
+                File::open(path)?.sync_all()?;
+            }
+            if let Some(dir) = path.parent() {
+                dirs.insert(dir.to_owned());
+            }
+        }
+        for dir in dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(batch)
+    })
+    .await??;
+    for (_, id) in batch {
+        checkpoint.mark_done(&id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::checkpoint::CHECKPOINT_FILE_NAME;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn batched_files_are_checkpointed_once_synced() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let checkpoint = RestoreCheckpoint::open(dir.path(), "root")?;
+        let queue = FsyncQueue::new(FsyncPolicy::Batched);
+        let path = dir.path().join("file");
+        fs::write(&path, b"content")?;
+
+        let checkpointed = || fs::read_to_string(dir.path().join(CHECKPOINT_FILE_NAME)).unwrap();
+
+        queue.saved(&checkpoint, &path, "id").await?;
+        assert!(!checkpointed().lines().any(|line| line == "id"));
+        queue.flush(&checkpoint).await?;
+        assert!(checkpointed().lines().any(|line| line == "id"));
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use crate::data::file::{RemoteFile, RemoteFileVersion};
+use crate::net::backend::Backend;
+use eyre::Result;
+use std::time::{Duration, SystemTime};
+
+/// Unfinished uploads more recent than this may belong to a backup still running on another machine
+pub const UNFINISHED_UPLOADS_MIN_AGE_DEFAULT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Lists the unfinished large file uploads under `prefix` that were started at least `min_age` ago
+pub async fn list_stale_unfinished_uploads(
+    backend: &dyn Backend,
+    prefix: &str,
+    min_age: Duration,
+) -> Result<Vec<(RemoteFile, Duration)>> {
+    let now = SystemTime::now();
+    let unfinished = backend.list_unfinished_large_files(prefix).await?;
+    Ok(unfinished
+        .into_iter()
+        .map(|(file, started)| (file, now.duration_since(started).unwrap_or_default()))
+        .filter(|(_, age)| *age >= min_age)
+        .collect())
+}
+
+/// Cancels an unfinished upload, the parts already uploaded are deleted with it
+pub async fn cancel_unfinished_upload(backend: &dyn Backend, file: &RemoteFile) -> Result<()> {
+    let version = RemoteFileVersion {
+        path: file.full_path_hash.clone(),
+        id: file.id.clone(),
+    };
+    backend.delete_file_version(&version).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn only_stale_uploads_are_cleaned() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        backend.start_unfinished_upload("root/old", Path::new("old"), now - 2 * day);
+        backend.start_unfinished_upload("root/recent", Path::new("recent"), now);
+        backend.start_unfinished_upload("other/old", Path::new("other"), now - 2 * day);
+
+        let stale = list_stale_unfinished_uploads(&backend, "root/", day).await?;
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].0.rel_path, Path::new("old"));
+        cancel_unfinished_upload(&backend, &stale[0].0).await?;
+        assert_eq!(backend.unfinished_count(), 2);
+        assert!(list_stale_unfinished_uploads(&backend, "root/", day).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use crate::crypto;
+use crate::data::names::ObjectNames;
+use crate::net::backend::Backend;
+use bincode::{deserialize, serialize};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Only the most recent runs are kept in the history
+const MAX_HISTORY_RUNS: usize = 1000;
+
+/// A summary of one backup run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BackupRun {
+    /// When the backup started, in seconds since the Unix epoch
+    pub started: u64,
+    pub duration_secs: u64,
+    /// The machine the backup ran on
+    pub host: String,
+    /// Number of files in the backed up folder
+    pub files_count: u64,
+    /// Total size of the files in the backed up folder
+    pub total_size: u64,
+    pub uploaded: usize,
+    pub deleted: usize,
+    pub errors: usize,
+}
+
+impl BackupRun {
+    /// Starts recording a run, the counts are filled in as the backup goes
+    pub fn start() -> Self {
+        Self {
+            started: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            duration_secs: 0,
+            host: hostname(),
+            files_count: 0,
+            total_size: 0,
+            uploaded: 0,
+            deleted: 0,
+            errors: 0,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.duration_secs = now.saturating_sub(self.started);
+    }
+}
+
+/// The history is stored next to the DirDB, so deleting a backup root also deletes its history
+fn history_path(names: &ObjectNames, root_path_hash: &str) -> String {
+    names.dirdb(root_path_hash) + ".history"
+}
+
+/// Downloads the runs of a backup root, oldest first
+pub async fn fetch_history(backend: &dyn Backend, root_path_hash: &str) -> Result<Vec<BackupRun>> {
+    let names = backend.object_names();
+    let mut enc_data = backend.download_file(&history_path(&names, root_path_hash)).await;
+    // Kept when the names are obfuscated, the next run is recorded under the new name
+    if let (Err(_), Some(legacy_dirdb)) = (&enc_data, names.legacy_dirdb(root_path_hash)) {
+        enc_data = backend.download_file(&(legacy_dirdb + ".history")).await;
+    }
+    let enc_data = match enc_data {
+        Ok(enc_data) => enc_data,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let data = crypto::decrypt(&enc_data, backend.key())?;
+    deserialize(&data[..]).wrap_err("Failed to decode the backup history")
+}
+
+/// Appends a run to the history of a backup root
+pub async fn record_run(backend: &dyn Backend, root_path_hash: &str, run: BackupRun) -> Result<()> {
+    // A history we can't read isn't worth failing backups over, we start a new one
+    let mut runs = fetch_history(backend, root_path_hash).await.unwrap_or_default();
+    runs.push(run);
+    if runs.len() > MAX_HISTORY_RUNS {
+        runs.drain(..runs.len() - MAX_HISTORY_RUNS);
+    }
+
+    let data = crypto::encrypt(&serialize(&runs)?, backend.key());
+    let path = history_path(&backend.object_names(), root_path_hash);
+    backend.upload_file_simple(&path, data).await?;
+    Ok(())
+}
+
+pub(crate) fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test]
+    async fn record_and_fetch() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        assert!(fetch_history(&backend, "root").await?.is_empty());
+
+        let mut run = BackupRun::start();
+        run.uploaded = 3;
+        record_run(&backend, "root", run.clone()).await?;
+        record_run(&backend, "root", BackupRun::start()).await?;
+        let history = fetch_history(&backend, "root").await?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0], run);
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Keeps two frozen processes of this machine from working on the same backup root at once
+//!
+//! The lock is a `FileLock` named after the root's path hash, in a folder shared by every user of the machine.
+//! Its holder writes its PID and command line in it, to be named to the others. The kernel releases it when its
+//! holder exits, however it exits. Unlike the remote lock (see `BackupRoot::lock`), it's seen immediately and
+//! names the other process.
+
+use crate::data::file_lock::{read_holder, FileLock};
+use crate::failure::Failure;
+use eyre::{eyre, Result, WrapErr};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+/// The locks this process holds, another session of this process can work on the same root
+static HELD: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// A root locked by this process, unlocked when dropped
+pub struct LocalLock {
+    /// None if this process already held the lock, then the first holder releases it
+    lock: Option<(FileLock, PathBuf)>,
+}
+
+impl LocalLock {
+    /// Locks the root with this path hash, `root_path` is only for the error if another process has it
+    pub fn acquire(dir: &Path, root_path_hash: &str, root_path: &Path) -> Result<Self> {
+        let path = dir.join(root_path_hash.to_owned() + ".lock");
+        let mut held = HELD.lock().unwrap();
+        // Several sessions of this process may work on the same root, e.g. a sync into itself fails later
+        if held.contains(&path) {
+            return Ok(Self { lock: None });
+        }
+        let lock =
+            FileLock::try_lock(&path).wrap_err_with(|| format!("Failed to take the local lock {}", path.display()))?;
+        let lock = match lock {
+            Some(lock) => lock,
+            None => {
+                // The holder may not have written who it is yet
+                let holder = read_holder(&path).unwrap_or_default();
+                let named = match holder.split_once('\n') {
+                    Some((pid, command)) => format!(" (PID {}: {})", pid, command),
+                    None => String::new(),
+                };
+                return Err(
+                    eyre!("{} is in use by another frozen process{}", root_path.display(), named)
+                        .wrap_err(Failure::LockConflict),
+                );
+            }
+        };
+        let command = env::args().collect::<Vec<_>>().join(" ");
+        // Only the other processes' errors are less clear without it
+        let _ = lock.write_holder(&format!("{}\n{}", process::id(), command));
+        held.push(path.clone());
+        Ok(Self {
+            lock: Some((lock, path)),
+        })
+    }
+}
+
+impl Drop for LocalLock {
+    fn drop(&mut self) {
+        if let Some((lock, path)) = self.lock.take() {
+            HELD.lock().unwrap().retain(|held| *held != path);
+            drop(lock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn other_processes_are_refused_until_unlocked() -> Result<()> {
+        let dir = tempdir()?;
+        let root_path = Path::new("/home");
+        let lock_path = dir.path().join("hash.lock");
+
+        // Another open file of the lock is refused like another process
+        let other = FileLock::try_lock(&lock_path)?.unwrap();
+        other.write_holder("1\nfrozen restore /home")?;
+        let err = LocalLock::acquire(dir.path(), "hash", root_path).err().unwrap();
+        assert_eq!(err.downcast_ref::<Failure>(), Some(&Failure::LockConflict));
+        assert!(format!("{:#}", err).contains("PID 1: frozen restore /home"));
+
+        // Whatever its holder left in the file, a released lock can be taken
+        drop(other);
+        let lock = LocalLock::acquire(dir.path(), "hash", root_path)?;
+        let again = LocalLock::acquire(dir.path(), "hash", root_path)?;
+        drop(again);
+        assert!(FileLock::try_lock(&lock_path)?.is_none());
+        drop(lock);
+        assert!(FileLock::try_lock(&lock_path)?.is_some());
+        Ok(())
+    }
+}
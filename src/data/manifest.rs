@@ -0,0 +1,256 @@
+//! An inventory of the files of a backup, decrypted, for tools that audit or restore backups on their own
+//!
+//! The manifest is a JSON object (schema version 1):
+//! - `version`: 1, bumped when fields change meaning or go away. New fields may be added without a bump.
+//! - `path`, `path_hash`, `bucket`: the backed up folder, the hash its objects are named after, and their bucket
+//! - `created`: when the manifest was made, in seconds since the epoch
+//! - `files`: one object per backed up file, sorted by object name:
+//!   - `path`: the path relative to the backed up folder, with invalid UTF-8 replaced
+//!   - `path_base64`: only for paths that aren't valid UTF-8, the raw bytes of the path in base64
+//!   - `object_name`, `file_id`: the object holding the file, for `b2_download_file_by_name` or `_by_id`
+//!   - `stored_size`: the size of the object, compressed and encrypted
+//!   - `last_modified`, `birthtime`: in seconds since the epoch, the birthtime may be missing
+//!   - `mode`: the Unix permissions
+//!   - `is_symlink`: the object holds the target of a symlink instead of file content
+//!   - `content_hash`: the BLAKE2b-256 of the plaintext content in hex, missing for files uploaded without one
+//!   - `original_size`: the size of the plaintext content, missing for files uploaded without one
+//!
+//! A manifest can also register objects that are already uploaded into a backup root (see `import`),
+//! as long as they're named and encrypted like frozen would have uploaded them.
+
+use crate::data::file::{FileMeta, RemoteFile};
+use crate::data::paths::{path_from_bytes, path_to_bytes};
+use crate::data::root::BackupRoot;
+use crate::dirdb::remote::RemoteDirDB;
+use crate::dirdb::DirDB;
+use crate::net::backend::Backend;
+use data_encoding::{BASE64, HEXLOWER};
+use eyre::{bail, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST_VERSION: u32 = 1;
+/// The rest of the problems are only counted, an incompatible manifest has one per file
+const MAX_SHOWN_PROBLEMS: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    pub path: String,
+    pub path_hash: String,
+    pub bucket: String,
+    pub created: u64,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestFile {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+    pub object_name: String,
+    pub file_id: String,
+    pub stored_size: u64,
+    pub last_modified: u64,
+    #[serde(default)]
+    pub birthtime: Option<u64>,
+    pub mode: u32,
+    pub is_symlink: bool,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub original_size: Option<u64>,
+}
+
+impl Manifest {
+    pub fn new(root: &BackupRoot, bucket: &str, files: &[RemoteFile]) -> Self {
+        let mut files: Vec<ManifestFile> = files.iter().map(ManifestFile::from_remote).collect();
+        files.sort_by(|a, b| a.object_name.cmp(&b.object_name));
+        Self {
+            version: MANIFEST_VERSION,
+            path: root.path.to_string_lossy().into_owned(),
+            path_hash: root.path_hash.clone(),
+            bucket: bucket.to_owned(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            files,
+        }
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let manifest: Manifest = serde_json::from_slice(json).wrap_err("Invalid manifest")?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "Unsupported manifest version {}, expected {}",
+                manifest.version,
+                MANIFEST_VERSION
+            );
+        }
+        Ok(manifest)
+    }
+}
+
+impl ManifestFile {
+    pub fn from_remote(file: &RemoteFile) -> Self {
+        let raw_path = path_to_bytes(&file.rel_path).unwrap_or_default();
+        let path_base64 = match std::str::from_utf8(raw_path) {
+            Ok(_) => None,
+            Err(_) => Some(BASE64.encode(raw_path)),
+        };
+        Self {
+            path: file.rel_path.to_string_lossy().into_owned(),
+            path_base64,
+            object_name: file.full_path_hash.clone(),
+            file_id: file.id.clone(),
+            stored_size: file.size,
+            last_modified: file.last_modified,
+            birthtime: file.birthtime,
+            mode: file.mode,
+            is_symlink: file.is_symlink,
+            content_hash: file.content_hash.map(|hash| HEXLOWER.encode(&hash)),
+            original_size: file.original_size,
+        }
+    }
+
+    /// The exact path relative to the backed up folder, even if it isn't valid UTF-8
+    pub fn rel_path(&self) -> Result<PathBuf> {
+        match &self.path_base64 {
+            Some(encoded) => {
+                let raw_path = BASE64
+                    .decode(encoded.as_bytes())
+                    .wrap_err_with(|| format!("Invalid path_base64 for \"{}\"", self.path))?;
+                Ok(path_from_bytes(&raw_path)?.to_owned())
+            }
+            None => Ok(PathBuf::from(&self.path)),
+        }
+    }
+
+    /// The metadata stored with the file, as it was uploaded
+    pub fn meta(&self) -> Result<FileMeta> {
+        let content_hash = match &self.content_hash {
+            Some(hex) => {
+                let hash = HEXLOWER
+                    .decode(hex.as_bytes())
+                    .ok()
+                    .and_then(|hash| hash.try_into().ok());
+                match hash {
+                    Some(hash) => Some(hash),
+                    None => bail!("Invalid content_hash for \"{}\"", self.path),
+                }
+            }
+            None => None,
+        };
+        Ok(FileMeta {
+            rel_path: self.rel_path()?,
+            last_modified: self.last_modified,
+            mode: self.mode,
+            is_symlink: self.is_symlink,
+            birthtime: self.birthtime,
+            content_hash,
+            original_size: self.original_size,
+        })
+    }
+}
+
+/// Registers the files of a manifest into a locked backup root, and saves a DirDB covering every file of the root.
+/// Each file must already be uploaded under the name frozen gives it, with the manifest's metadata encrypted with our
+/// key. Nothing is saved if any file doesn't match. Returns the number of files of the manifest.
+///
+/// The DirDB holds the content hashes of the folders, from the sizes and mtimes of their files. The next backup skips
+/// the folders whose local files still match, like after a backup that uploaded them.
+pub async fn import(backend: &dyn Backend, root: &BackupRoot, manifest: &Manifest) -> Result<usize> {
+    let key = backend.key();
+    let remote_files = root.list_remote_files(backend).await?;
+    let by_name: HashMap<&str, &RemoteFile> = remote_files
+        .iter()
+        .map(|file| (file.full_path_hash.as_str(), file))
+        .collect();
+
+    let mut problems = Vec::new();
+    for file in manifest.files.iter() {
+        let meta = file.meta()?;
+        let (_, expected_name) = root.file_path_hashes(&meta.rel_path, key)?;
+        let problem = if expected_name != file.object_name {
+            format!(
+                "\"{}\" is stored as {}, not as {}",
+                file.path, file.object_name, expected_name
+            )
+        } else {
+            match by_name.get(file.object_name.as_str()) {
+                None => format!("\"{}\" is not uploaded as {}", file.path, file.object_name),
+                Some(remote) if remote.rel_path != meta.rel_path => format!(
+                    "The metadata of {} is for \"{}\", not \"{}\"",
+                    file.object_name,
+                    remote.rel_path.display(),
+                    file.path
+                ),
+                Some(remote) if !same_meta(remote, &meta) => format!(
+                    "The metadata of {} doesn't match the manifest for \"{}\"",
+                    file.object_name, file.path
+                ),
+                Some(_) => continue,
+            }
+        };
+        problems.push(problem);
+    }
+    if !problems.is_empty() {
+        let mut msg = format!("{} files of the manifest can't be imported:", problems.len());
+        for problem in problems.iter().take(MAX_SHOWN_PROBLEMS) {
+            msg += &format!("\n\t{}", problem);
+        }
+        if problems.len() > MAX_SHOWN_PROBLEMS {
+            msg += &format!("\n\tand {} more", problems.len() - MAX_SHOWN_PROBLEMS);
+        }
+        bail!(msg);
+    }
+
+    // Files of the root that aren't in the manifest are kept
+    let dirdb = DirDB::new_from_remote_files(&remote_files, key)?;
+    let mut remote_dirdb = RemoteDirDB::fetch(backend, &root.path_hash).await?;
+    remote_dirdb.save(backend, &dirdb).await?;
+    Ok(manifest.files.len())
+}
+
+/// Whether an uploaded file has the metadata the manifest gives it
+fn same_meta(remote: &RemoteFile, meta: &FileMeta) -> bool {
+    remote.last_modified == meta.last_modified
+        && remote.mode == meta.mode
+        && remote.is_symlink == meta.is_symlink
+        && remote.content_hash == meta.content_hash
+        && remote.original_size == meta.original_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn manifest_files_round_trip() -> Result<()> {
+        let meta = FileMeta {
+            rel_path: PathBuf::from(OsStr::from_bytes(b"dir/not\xffutf8")),
+            last_modified: 1_600_000_000,
+            mode: 0o640,
+            is_symlink: false,
+            birthtime: Some(1_500_000_000),
+            content_hash: Some([7; 32]),
+            original_size: Some(4096),
+        };
+        let file = RemoteFile::new(meta.clone(), "root/a/b", "file_id", 123);
+        let entry = ManifestFile::from_remote(&file);
+        assert_eq!(entry.path, "dir/not\u{fffd}utf8");
+        assert!(entry.path_base64.is_some());
+
+        let json = serde_json::to_vec(&entry)?;
+        let parsed: ManifestFile = serde_json::from_slice(&json)?;
+        assert_eq!(parsed.meta()?, meta);
+        assert_eq!(parsed.object_name, "root/a/b");
+        assert_eq!(parsed.stored_size, 123);
+
+        assert!(Manifest::from_json(br#"{"version": 2}"#).is_err());
+        Ok(())
+    }
+}
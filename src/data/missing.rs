@@ -0,0 +1,105 @@
+//! Files deleted locally that `backup --delete-after` keeps on the remote until they've been missing long enough
+
+use crate::crypto;
+use crate::net::backend::Backend;
+use bincode::{deserialize, serialize};
+use eyre::{Result, WrapErr};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Stored next to the DirDB, so deleting a backup root also deletes it
+fn missing_path(backend: &dyn Backend, root_path_hash: &str) -> String {
+    backend.object_names().dirdb(root_path_hash) + ".missing"
+}
+
+/// When each remote file was first found missing locally, by full path hash
+pub struct MissingFiles {
+    previous: HashMap<String, u64>,
+    current: HashMap<String, u64>,
+    delete_after: Duration,
+}
+
+impl MissingFiles {
+    /// Downloads the files found missing by the previous backups of a root
+    pub async fn fetch(backend: &dyn Backend, root_path_hash: &str, delete_after: Duration) -> Result<Self> {
+        let previous = match backend.download_file(&missing_path(backend, root_path_hash)).await {
+            Ok(enc_data) => {
+                let data = crypto::decrypt(&enc_data, backend.key())?;
+                deserialize(&data[..]).wrap_err("Failed to decode the locally deleted files")?
+            }
+            Err(_) => HashMap::new(),
+        };
+        Ok(Self {
+            previous,
+            current: HashMap::new(),
+            delete_after,
+        })
+    }
+
+    /// Whether a remote file missing locally has been missing for long enough to delete it.
+    /// If not, it's kept on the remote and recorded as still missing. `now` is in seconds since the Unix epoch.
+    pub fn is_expired(&mut self, full_path_hash: &str, now: u64) -> bool {
+        let first_missed = self.previous.get(full_path_hash).copied().unwrap_or(now);
+        if now.saturating_sub(first_missed) >= self.delete_after.as_secs() {
+            return true;
+        }
+        self.current.insert(full_path_hash.to_owned(), first_missed);
+        false
+    }
+
+    /// Keeps the files outside of `prefix` as they were, for backups that only diffed it (see `backup --only`)
+    pub fn keep_previous_outside(&mut self, prefix: &str) {
+        for (full_path_hash, &first_missed) in self.previous.iter() {
+            if !full_path_hash.starts_with(prefix) {
+                self.current.entry(full_path_hash.clone()).or_insert(first_missed);
+            }
+        }
+    }
+
+    /// Number of files kept on the remote by this backup
+    pub fn kept_count(&self) -> usize {
+        self.current.len()
+    }
+
+    /// Saves the files still missing after this backup, the files that came back or were deleted are forgotten
+    pub async fn save(&self, backend: &dyn Backend, root_path_hash: &str) -> Result<()> {
+        let data = crypto::encrypt(&serialize(&self.current)?, backend.key());
+        backend
+            .upload_file_simple(&missing_path(backend, root_path_hash), data)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test]
+    async fn files_expire_after_delay() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut missing = MissingFiles::fetch(&backend, "root", day).await?;
+        assert!(!missing.is_expired("root/a", 1000));
+        assert!(!missing.is_expired("root/b", 1000));
+        assert_eq!(missing.kept_count(), 2);
+        missing.save(&backend, "root").await?;
+
+        // b came back, so it's forgotten
+        let mut missing = MissingFiles::fetch(&backend, "root", day).await?;
+        assert!(!missing.is_expired("root/a", 1000 + day.as_secs() - 1));
+        missing.save(&backend, "root").await?;
+
+        let mut missing = MissingFiles::fetch(&backend, "root", day).await?;
+        assert!(!missing.is_expired("root/b", 1000 + day.as_secs()));
+        assert!(missing.is_expired("root/a", 1000 + day.as_secs()));
+        assert_eq!(missing.kept_count(), 1);
+
+        let mut missing = MissingFiles::fetch(&backend, "root", day).await?;
+        missing.keep_previous_outside("root/dir/");
+        assert_eq!(missing.kept_count(), 1);
+        Ok(())
+    }
+}
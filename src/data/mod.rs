@@ -1,3 +1,26 @@
+pub mod audit;
+pub mod bench;
+pub mod checkpoint;
+pub mod doctor;
+pub mod duration;
 pub mod file;
+pub mod file_lock;
+pub mod fsync;
+pub mod gc;
+pub mod history;
+pub mod local_lock;
+pub mod manifest;
+pub mod missing;
+pub mod names;
 pub mod paths;
+pub mod prune;
+pub mod relocation;
+pub mod remote_lock;
+pub mod report;
 pub mod root;
+pub mod selftest;
+pub mod share;
+pub mod staging;
+pub mod tar;
+pub mod test_restore;
+pub mod tree;
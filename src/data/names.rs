@@ -0,0 +1,101 @@
+//! Names of frozen's own objects on the remote: the list of backup roots, and the DirDBs stored next to each root
+
+use crate::crypto::{hash_object_name, Key};
+
+const PLAIN_ROOTS_NAME: &str = "backup_root";
+const PLAIN_DIRDB_PREFIX: &str = "dirdb/";
+const PLAIN_AUDIT_PREFIX: &str = "audit/";
+/// Obfuscated lists of roots are padded to a multiple of this, so their size doesn't tell how many roots there are
+const ROOTS_PADDING: usize = 4096;
+
+/// With plain names anyone with access to the bucket can tell which object is the list of roots, and which are DirDBs.
+/// Obfuscated names are derived from the key instead, so they look like the hashed names of the backed up files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectNames {
+    roots: String,
+    dirdb_prefix: String,
+    audit_prefix: String,
+    obfuscated: bool,
+}
+
+impl ObjectNames {
+    pub fn plain() -> Self {
+        Self {
+            roots: PLAIN_ROOTS_NAME.to_owned(),
+            dirdb_prefix: PLAIN_DIRDB_PREFIX.to_owned(),
+            audit_prefix: PLAIN_AUDIT_PREFIX.to_owned(),
+            obfuscated: false,
+        }
+    }
+
+    pub fn obfuscated(key: &Key) -> Self {
+        Self {
+            roots: hash_object_name(PLAIN_ROOTS_NAME, key),
+            dirdb_prefix: hash_object_name(PLAIN_DIRDB_PREFIX, key) + "/",
+            audit_prefix: hash_object_name(PLAIN_AUDIT_PREFIX, key) + "/",
+            obfuscated: true,
+        }
+    }
+
+    /// The list of backup roots
+    pub fn roots(&self) -> &str {
+        &self.roots
+    }
+
+    /// Where the list of roots was before the names were obfuscated, if they are
+    pub fn legacy_roots(&self) -> Option<&'static str> {
+        Some(PLAIN_ROOTS_NAME).filter(|_| self.obfuscated)
+    }
+
+    /// The DirDB of a backup root, its index, delta, shards and history are named after it
+    pub fn dirdb(&self, root_path_hash: &str) -> String {
+        self.dirdb_prefix.clone() + root_path_hash
+    }
+
+    /// The audit records of a backup root, one object each.
+    /// They're not named after the DirDB, so they outlive the deletion of the root.
+    pub fn audit(&self, root_path_hash: &str) -> String {
+        self.audit_prefix.clone() + root_path_hash + "/"
+    }
+
+    /// Where the DirDB of a backup root was before the names were obfuscated, if they are
+    pub fn legacy_dirdb(&self, root_path_hash: &str) -> Option<String> {
+        Some(PLAIN_DIRDB_PREFIX.to_owned() + root_path_hash).filter(|_| self.obfuscated)
+    }
+
+    /// Pads the serialized list of roots, if the names are obfuscated. Trailing bytes are ignored when deserializing.
+    pub fn pad_roots(&self, mut roots: Vec<u8>) -> Vec<u8> {
+        if self.obfuscated {
+            let padded_len = (roots.len() / ROOTS_PADDING + 1) * ROOTS_PADDING;
+            roots.resize(padded_len, 0);
+        }
+        roots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::derive_key;
+
+    #[test]
+    fn obfuscated_names_depend_on_key() {
+        let plain = ObjectNames::plain();
+        assert_eq!(plain.roots(), "backup_root");
+        assert_eq!(plain.dirdb("hash"), "dirdb/hash");
+        assert_eq!(plain.audit("hash"), "audit/hash/");
+        assert_eq!(plain.legacy_dirdb("hash"), None);
+        assert_eq!(plain.pad_roots(vec![1, 2]), [1, 2]);
+
+        let names = ObjectNames::obfuscated(&derive_key("pass", "a"));
+        assert_ne!(names.roots(), "backup_root");
+        assert!(!names.dirdb("hash").starts_with("dirdb/"));
+        assert!(names.dirdb("hash").ends_with("/hash"));
+        assert_eq!(names.legacy_roots(), Some("backup_root"));
+        assert_ne!(names, ObjectNames::obfuscated(&derive_key("pass", "b")));
+        assert_eq!(names, ObjectNames::obfuscated(&derive_key("pass", "a")));
+
+        assert_eq!(names.pad_roots(vec![1; 10]).len(), ROOTS_PADDING);
+        assert_eq!(names.pad_roots(vec![1; ROOTS_PADDING]).len(), 2 * ROOTS_PADDING);
+    }
+}
@@ -1,10 +1,24 @@
 use clap::ArgMatches;
-use eyre::{eyre, Result};
+use eyre::{bail, eyre, Result};
 use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path, PathBuf};
 
+/// Longest path the OS accepts in a single call, including the terminating NUL (PATH_MAX on Linux)
+pub const PATH_MAX: usize = 4096;
+
+/// Fails with a clear error for paths the OS would refuse with a cryptic ENAMETOOLONG
+pub fn check_path_len(path: &Path) -> Result<()> {
+    let len = path.as_os_str().len();
+    if len >= PATH_MAX {
+        bail!("Path is too long ({} bytes, the limit is {})", len, PATH_MAX - 1);
+    }
+    Ok(())
+}
+
 fn remove_relative_components(path: &Path) -> PathBuf {
     let mut components = Vec::new();
     let mut skip = 0;
@@ -66,6 +80,90 @@ pub fn path_from_bytes(bytes: &[u8]) -> Result<&Path> {
     Ok(Path::new(os_str))
 }
 
+/// Checks that a path from the backup stays inside the folder it's joined to: not empty, not absolute, and without '..'.
+/// The paths in the remote metadata are only as trustworthy as the bucket holding them.
+pub fn check_rel_path(rel_path: &Path) -> Result<()> {
+    let mut has_name = false;
+    for component in rel_path.components() {
+        match component {
+            Component::Normal(_) => has_name = true,
+            Component::CurDir => (),
+            _ => bail!("\"{}\" is not a relative path inside the backup", rel_path.display()),
+        }
+    }
+    if !has_name {
+        bail!("\"{}\" is not a relative path inside the backup", rel_path.display());
+    }
+    Ok(())
+}
+
+/// Creates the folders of `rel_dir` under `root` like `create_dir_all`, but only as real folders inside `root`.
+///
+/// Existing symlinks on the way are refused rather than followed: a stale or planted symlink inside a restore target
+/// would otherwise redirect the restored files outside of it. Only `root` itself may be (or go through) a symlink.
+pub fn create_dir_all_under(root: &Path, rel_dir: &Path) -> Result<()> {
+    walk_dirs_under(root, rel_dir, true)
+}
+
+/// Checks that the folders of `rel_dir` under `root` are all real folders inside `root`, without creating them
+pub fn check_dirs_under(root: &Path, rel_dir: &Path) -> Result<()> {
+    walk_dirs_under(root, rel_dir, false)
+}
+
+fn walk_dirs_under(root: &Path, rel_dir: &Path, create: bool) -> Result<()> {
+    let mut path = root.to_owned();
+    for component in rel_dir.components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => continue,
+            _ => bail!("\"{}\" leads outside of {}", rel_dir.display(), root.display()),
+        }
+        let meta = match fs::symlink_metadata(&path) {
+            Err(err) if err.kind() == ErrorKind::NotFound && create => match fs::create_dir(&path) {
+                Ok(()) => continue,
+                // Created at the same time for another file, it still has to be checked
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => fs::symlink_metadata(&path)?,
+                Err(err) => return Err(err.into()),
+            },
+            meta => meta?,
+        };
+        if meta.file_type().is_symlink() {
+            bail!("{} is a symlink, nothing is restored through it", path.display());
+        }
+        if !meta.is_dir() {
+            bail!("{} is in the way, it's not a folder", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Matches a path against a glob pattern, where `*` matches any run of characters (including '/') and `?` any one character
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last star if the rest doesn't match: (pattern pos after the star, path pos)
+    let mut backtrack = None;
+
+    while t < path.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == path[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the star swallow one more character
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,4 +199,51 @@ mod tests {
         assert_eq!(to_bytes, path_to_bytes(from_bytes)?);
         Ok(())
     }
+
+    #[test]
+    fn path_length_limit() {
+        let longest = "/".to_owned() + &"a".repeat(PATH_MAX - 2);
+        assert!(check_path_len(Path::new(&longest)).is_ok());
+        assert!(check_path_len(Path::new(&(longest + "a"))).is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/home/*", "/home/user/photos"));
+        assert!(glob_match("*photos", "/home/user/photos"));
+        assert!(glob_match("/home/?ser/*", "/home/user/photos"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("/home/*/music", "/home/user/photos"));
+        assert!(!glob_match("/home", "/home/user"));
+        assert!(glob_match("*a*b*c", "xxaxxbxxbxc"));
+    }
+
+    #[test]
+    fn rel_paths_stay_inside() {
+        assert!(check_rel_path(Path::new("a/b")).is_ok());
+        assert!(check_rel_path(Path::new("./a")).is_ok());
+        assert!(check_rel_path(Path::new("")).is_err());
+        assert!(check_rel_path(Path::new(".")).is_err());
+        assert!(check_rel_path(Path::new("/etc/passwd")).is_err());
+        assert!(check_rel_path(Path::new("a/../../b")).is_err());
+        assert!(check_rel_path(Path::new("..")).is_err());
+    }
+
+    #[test]
+    fn folders_are_only_created_inside_the_root() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (root, outside) = (dir.path().join("root"), dir.path().join("outside"));
+        fs::create_dir_all(&root)?;
+        fs::create_dir_all(&outside)?;
+        std::os::unix::fs::symlink(&outside, root.join("link"))?;
+
+        create_dir_all_under(&root, Path::new("a/b"))?;
+        assert!(root.join("a/b").is_dir());
+        check_dirs_under(&root, Path::new("a/b"))?;
+        assert!(create_dir_all_under(&root, Path::new("link/c")).is_err());
+        assert!(!outside.join("c").exists());
+        assert!(create_dir_all_under(&root, Path::new("a/../../escaped")).is_err());
+        assert!(check_dirs_under(&root, Path::new("link")).is_err());
+        Ok(())
+    }
 }
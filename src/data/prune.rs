@@ -0,0 +1,215 @@
+use crate::data::file::RemoteFileVersion;
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::retention::is_version_locked;
+use eyre::{Result, WrapErr};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where an interrupted prune resumes from, saved locally after each page of versions is done
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneCursor {
+    /// The listing prefix this cursor is for
+    pub prefix: String,
+    /// First version of the next page to list
+    pub start: Option<RemoteFileVersion>,
+    /// The file of the last version of the previous page, and when that version was uploaded (in seconds since the epoch).
+    /// A page can start in the middle of the versions of a file, this tells whether its first version is the latest.
+    pub previous: Option<(String, u64)>,
+}
+
+impl PruneCursor {
+    /// Returns the saved cursor for `prefix`, or a cursor starting from the beginning
+    pub fn load(path: &Path, prefix: &str) -> Self {
+        let saved = fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<PruneCursor>(&data).ok());
+        match saved {
+            Some(cursor) if cursor.prefix == prefix => cursor,
+            _ => Self {
+                prefix: prefix.to_owned(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write then rename, so an interruption never leaves a truncated cursor
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&temp_path, path).wrap_err("Failed to save the prune cursor")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub listed: usize,
+    pub deleted: usize,
+    /// Versions that should have been deleted, but are still under Object Lock retention
+    pub locked: usize,
+}
+
+/// Deletes the old versions under `prefix` that were replaced by a newer upload at least `min_age` ago.
+/// The latest upload of each file is always kept, even if the file was hidden since.
+///
+/// Versions are listed one page at a time, and each page is deleted with `concurrency` requests in flight.
+/// The cursor at `cursor_path` is saved after each page, so an interrupted prune resumes where it stopped.
+/// Versions under Object Lock retention are counted and skipped, the next prune after their retention expires deletes them.
+/// If other deletions of a page fail, we stop before saving past it and return `Failure::Incomplete`.
+pub async fn prune_versions(
+    backend: &dyn Backend,
+    prefix: &str,
+    min_age: Duration,
+    concurrency: usize,
+    cursor_path: &Path,
+    mut on_page: impl FnMut(&PruneStats),
+) -> Result<PruneStats> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut cursor = PruneCursor::load(cursor_path, prefix);
+    let mut stats = PruneStats::default();
+    loop {
+        let page = backend.list_file_versions_page(prefix, cursor.start.as_ref()).await?;
+        stats.listed += page.versions.len();
+
+        let mut to_delete = Vec::new();
+        for (version, uploaded) in page.versions {
+            let uploaded = uploaded.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            if let Some((previous_path, replaced_at)) = &cursor.previous {
+                if previous_path == &version.path && now.saturating_sub(*replaced_at) >= min_age.as_secs() {
+                    to_delete.push(version.clone());
+                }
+            }
+            cursor.previous = Some((version.path, uploaded));
+        }
+
+        let results: Vec<_> = stream::iter(to_delete.iter())
+            .map(|version| backend.delete_file_version(version))
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        let locked = results
+            .iter()
+            .filter(|result| matches!(result, Err(err) if is_version_locked(err)))
+            .count();
+        let errors = results.iter().filter(|result| result.is_err()).count() - locked;
+        stats.deleted += to_delete.len() - errors - locked;
+        stats.locked += locked;
+        on_page(&stats);
+        if errors > 0 {
+            return Err(cap::incomplete_failure(errors).into());
+        }
+
+        cursor.start = page.next;
+        if cursor.start.is_none() {
+            if cursor_path.exists() {
+                fs::remove_file(cursor_path)?;
+            }
+            return Ok(stats);
+        }
+        cursor.save(cursor_path)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::net::retention::{Retention, RetentionMode};
+    use crate::test_helpers::test_key;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn prunes_replaced_versions() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        // Spans several pages of the memory backend, with files split across pages
+        for round in 0..4u8 {
+            for file in 0..7 {
+                backend
+                    .upload_file_simple(&format!("root/file{}", file), vec![round])
+                    .await?;
+            }
+        }
+        backend.upload_file_simple("other/file", vec![0]).await?;
+        backend.upload_file_simple("other/file", vec![1]).await?;
+
+        let dir = tempdir()?;
+        let cursor_path = dir.path().join("cursor");
+        let mut pages = 0;
+        let stats = prune_versions(&backend, "root/", Duration::ZERO, 4, &cursor_path, |_| pages += 1).await?;
+        assert!(pages > 1);
+        assert_eq!(stats, PruneStats {
+            listed: 28,
+            deleted: 21,
+            locked: 0,
+        });
+        assert!(!cursor_path.exists());
+        assert_eq!(backend.list_remote_file_versions("root/").await?.len(), 7);
+        assert_eq!(&backend.download_file("root/file3").await?[..], &[3]);
+        assert_eq!(backend.list_remote_file_versions("other/").await?.len(), 2);
+
+        // Recent replacements are kept
+        backend.upload_file_simple("root/file0", vec![4]).await?;
+        let stats = prune_versions(&backend, "root/", Duration::from_secs(3600), 4, &cursor_path, |_| ()).await?;
+        assert_eq!(stats.deleted, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_from_cursor() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        for round in 0..2u8 {
+            for file in 0..10 {
+                backend
+                    .upload_file_simple(&format!("root/file{}", file), vec![round])
+                    .await?;
+            }
+        }
+        let dir = tempdir()?;
+        let cursor_path = dir.path().join("cursor");
+        let versions = backend.list_remote_file_versions_timed("root/").await?;
+        // As if a previous run was interrupted after the first 12 versions
+        let cursor = PruneCursor {
+            prefix: "root/".to_owned(),
+            start: Some(versions[12].0.clone()),
+            previous: Some((
+                versions[11].0.path.clone(),
+                versions[11].1.duration_since(UNIX_EPOCH)?.as_secs(),
+            )),
+        };
+        cursor.save(&cursor_path)?;
+        assert_eq!(PruneCursor::load(&cursor_path, "root/"), cursor);
+        assert_eq!(PruneCursor::load(&cursor_path, "other/").start, None);
+
+        let stats = prune_versions(&backend, "root/", Duration::ZERO, 2, &cursor_path, |_| ()).await?;
+        assert_eq!(stats.listed, 8);
+        assert_eq!(stats.deleted, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn locked_versions_are_skipped() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        backend.set_retention(Some(Retention {
+            mode: RetentionMode::Compliance,
+            days: 1,
+        }));
+        let upload_url = backend.get_upload_url().await?;
+        for round in 0..3u8 {
+            backend
+                .upload_file(&upload_url, "root/file", vec![round], Some(String::new()))
+                .await?;
+        }
+        let dir = tempdir()?;
+        let stats = prune_versions(&backend, "root/", Duration::ZERO, 2, &dir.path().join("cursor"), |_| ()).await?;
+        assert_eq!(stats.deleted, 0);
+        assert_eq!(stats.locked, 2);
+        assert_eq!(backend.list_remote_file_versions("root/").await?.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use eyre::{bail, eyre, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Changes where restored files are written, for `restore --strip-prefix` and `--relocate`
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Number of leading folders removed from the paths in the backup
+    pub strip_prefix: usize,
+    /// Folders of the backup restored somewhere else, relative to the restore target unless absolute.
+    /// The first match wins, and relocated paths aren't stripped.
+    pub moves: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Relocation {
+    pub fn is_identity(&self) -> bool {
+        self.strip_prefix == 0 && self.moves.is_empty()
+    }
+
+    /// Parses a `<from>=<to>` move, where `from` is either relative to the backup root, or absolute inside it
+    pub fn parse_move(arg: &str, root_path: &Path) -> Result<(PathBuf, PathBuf)> {
+        let (from, to) = arg
+            .split_once('=')
+            .ok_or_else(|| eyre!("Invalid relocation \"{}\", expected <from>=<to>", arg))?;
+        let from = Path::new(from);
+        let from = if from.is_absolute() {
+            from.strip_prefix(root_path).map_err(|_| {
+                eyre!(
+                    "\"{}\" is not inside the backup \"{}\"",
+                    from.display(),
+                    root_path.display()
+                )
+            })?
+        } else {
+            from
+        };
+        if from.components().any(|comp| !matches!(comp, Component::Normal(_))) || to.is_empty() {
+            bail!("Invalid relocation \"{}\"", arg);
+        }
+        Ok((from.to_owned(), PathBuf::from(to)))
+    }
+
+    /// Where a path of the backup is restored, relative to the restore target unless absolute.
+    /// Returns None if nothing is left of the path after stripping its prefix.
+    pub fn apply(&self, rel_path: &Path) -> Option<PathBuf> {
+        self.split(rel_path).map(|(base, rest)| base.join(rest))
+    }
+
+    /// Like `apply`, but keeps apart the folder chosen with `--relocate` (empty otherwise) and the rest of the path,
+    /// which comes from the backup. Only the rest has to be checked for symlinks when restoring.
+    pub fn split(&self, rel_path: &Path) -> Option<(PathBuf, PathBuf)> {
+        for (from, to) in self.moves.iter() {
+            if let Ok(rest) = rel_path.strip_prefix(from) {
+                return Some((to.clone(), rest.to_owned()));
+            }
+        }
+        let stripped: PathBuf = rel_path.components().skip(self.strip_prefix).collect();
+        if stripped.as_os_str().is_empty() {
+            None
+        } else {
+            Some((PathBuf::new(), stripped))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relocates_paths() -> Result<()> {
+        let root = Path::new("/var/lib/app");
+        let relocation = Relocation {
+            strip_prefix: 1,
+            moves: vec![
+                Relocation::parse_move("/var/lib/app/db=/srv/db-recovery", root)?,
+                Relocation::parse_move("logs/old=archive", root)?,
+            ],
+        };
+        assert_eq!(
+            relocation.apply(Path::new("db/x/y")),
+            Some(PathBuf::from("/srv/db-recovery/x/y"))
+        );
+        assert_eq!(
+            relocation.apply(Path::new("logs/old/1")),
+            Some(PathBuf::from("archive/1"))
+        );
+        assert_eq!(relocation.apply(Path::new("logs/new/1")), Some(PathBuf::from("new/1")));
+        assert_eq!(relocation.apply(Path::new("top")), None);
+        assert_eq!(
+            relocation.split(Path::new("logs/old/1")),
+            Some((PathBuf::from("archive"), PathBuf::from("1")))
+        );
+        assert_eq!(
+            relocation.split(Path::new("logs/new/1")),
+            Some((PathBuf::new(), PathBuf::from("new/1")))
+        );
+        assert!(Relocation::default().is_identity());
+
+        assert!(Relocation::parse_move("db", root).is_err());
+        assert!(Relocation::parse_move("/etc/db=/srv", root).is_err());
+        assert!(Relocation::parse_move("../db=/srv", root).is_err());
+        assert!(Relocation::parse_move("db=", root).is_err());
+        Ok(())
+    }
+}
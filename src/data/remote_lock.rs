@@ -0,0 +1,193 @@
+//! The lock of a backup root on the remote, see `BackupRoot::lock`
+//!
+//! A lock is a file named after the root's path hash, holding who took it. Its holder uploads it again every
+//! `REFRESH_INTERVAL` while it works, so that a lock left behind by a process that died stops being refreshed,
+//! and `frozen unlock --older-than` can tell it apart from the lock of a long backup that is still running.
+
+use crate::crypto;
+use crate::data::file::RemoteFileVersion;
+use crate::data::history::hostname;
+use crate::net::backend::Backend;
+use crate::output::format_timestamp;
+use bincode::{deserialize, serialize};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How often a held lock is uploaded again
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Who holds a lock, saved encrypted in the lock file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockHolder {
+    pub host: String,
+    pub pid: u32,
+    /// When the lock was taken, in seconds since the Unix epoch
+    pub started: u64,
+    /// When the lock was last uploaded again, in seconds since the Unix epoch
+    pub refreshed: u64,
+}
+
+impl LockHolder {
+    fn new() -> Self {
+        let now = now_secs();
+        Self {
+            host: hostname(),
+            pid: process::id(),
+            started: now,
+            refreshed: now,
+        }
+    }
+
+    /// Locks taken before they said who holds them are empty, they have no holder
+    pub fn decode(data: &[u8], key: &crypto::Key) -> Option<Self> {
+        let data = crypto::decrypt(data, key).ok()?;
+        deserialize(&data).ok()
+    }
+
+    fn encode(&self, key: &crypto::Key) -> Result<Vec<u8>> {
+        Ok(crypto::encrypt(&serialize(self)?, key))
+    }
+
+    /// The holder as shown to the user, e.g. "laptop, PID 1234, since 2021-03-04 15:06:07"
+    pub fn describe(&self) -> String {
+        format!(
+            "{}, PID {}, since {}",
+            self.host,
+            self.pid,
+            format_timestamp(self.started)
+        )
+    }
+
+    /// When the holder last said it was still working
+    pub fn refreshed_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.refreshed)
+    }
+}
+
+/// A lock held by this process, uploaded again in the background until it's released
+pub struct RemoteLock {
+    backend: Arc<dyn Backend>,
+    /// The current version of the lock file, the refresher replaces it
+    version: Arc<Mutex<RemoteFileVersion>>,
+    stop: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl RemoteLock {
+    /// Uploads the lock file at `path` and starts refreshing it
+    pub async fn take(backend: &Arc<dyn Backend>, path: String) -> Result<Self> {
+        Self::take_refreshed_every(backend, path, REFRESH_INTERVAL).await
+    }
+
+    async fn take_refreshed_every(backend: &Arc<dyn Backend>, path: String, interval: Duration) -> Result<Self> {
+        let holder = LockHolder::new();
+        let version = backend.upload_file_simple(&path, holder.encode(backend.key())?).await?;
+        let version = Arc::new(Mutex::new(version));
+        let (stop, stopped) = oneshot::channel();
+        let refresher = tokio::spawn(refresh(
+            backend.clone(),
+            path,
+            version.clone(),
+            holder,
+            interval,
+            stopped,
+        ));
+        Ok(Self {
+            backend: backend.clone(),
+            version,
+            stop: Mutex::new(Some((stop, refresher))),
+        })
+    }
+
+    /// The current version of the lock file
+    pub fn version(&self) -> RemoteFileVersion {
+        self.version.lock().unwrap().clone()
+    }
+
+    /// Stops refreshing the lock and deletes it
+    pub async fn release(&self) -> Result<()> {
+        let stop = self.stop.lock().unwrap().take();
+        if let Some((stop, refresher)) = stop {
+            // A refresh in progress finishes first, or the version it uploads would be left behind
+            let _ = stop.send(());
+            let _ = refresher.await;
+        }
+        let version = self.version();
+        self.backend.delete_file_version(&version).await
+    }
+}
+
+impl Drop for RemoteLock {
+    /// A lock that wasn't released stops being refreshed, so that it ages like that of a process that died
+    fn drop(&mut self) {
+        if let Some((stop, _)) = self.stop.get_mut().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+async fn refresh(
+    backend: Arc<dyn Backend>,
+    path: String,
+    version: Arc<Mutex<RemoteFileVersion>>,
+    mut holder: LockHolder,
+    interval: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = &mut stopped => return,
+        }
+        holder.refreshed = now_secs();
+        // A failed refresh is tried again at the next one, the lock only looks older meanwhile
+        let data = match holder.encode(backend.key()) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        if let Ok(new_version) = backend.upload_file_simple(&path, data).await {
+            let old_version = mem::replace(&mut *version.lock().unwrap(), new_version);
+            let _ = backend.delete_file_version(&old_version).await;
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test]
+    async fn locks_say_who_holds_them_and_are_refreshed() -> Result<()> {
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new(test_key()));
+        let lock =
+            RemoteLock::take_refreshed_every(&backend, "hash.lock.1".to_owned(), Duration::from_millis(20)).await?;
+        let first_version = lock.version();
+        let holder = LockHolder::decode(&backend.download_file("hash.lock.1").await?, backend.key()).unwrap();
+        assert_eq!(holder.pid, process::id());
+        assert_eq!(holder.host, hostname());
+
+        // Each refresh replaces the previous version
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let versions = backend.list_remote_file_versions("hash.lock.").await?;
+        assert_eq!(versions.len(), 1);
+        assert_ne!(versions[0], first_version);
+
+        lock.release().await?;
+        assert!(backend.list_remote_file_versions("hash.lock.").await?.is_empty());
+
+        // Locks of older versions are empty
+        assert_eq!(LockHolder::decode(&[], backend.key()), None);
+        Ok(())
+    }
+}
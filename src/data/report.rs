@@ -0,0 +1,208 @@
+//! Summaries of the backups run on this machine, kept locally to show their trends with `frozen report`
+//!
+//! Unlike the history of each root (see `data::history`), nothing is downloaded, and the bytes uploaded are known.
+
+use crate::data::audit::AuditRecord;
+use crate::data::duration::format_duration;
+use crate::output::format_size;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Only the most recent runs are kept, older ones are dropped when the file gets twice as long
+const MAX_SUMMARIES: usize = 1000;
+
+/// What one backup run did, one JSON line of the summaries file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RunSummary {
+    /// When the backup started, in seconds since the Unix epoch
+    pub started: u64,
+    pub duration_secs: u64,
+    /// The path of the backup root
+    pub backup: String,
+    pub uploaded: usize,
+    /// Bytes sent to the remote, compressed and encrypted
+    pub uploaded_bytes: u64,
+    pub deleted: usize,
+    pub errors: usize,
+    pub failed: bool,
+}
+
+impl RunSummary {
+    pub fn new(backup: &Path, audit: &AuditRecord, uploaded_bytes: u64) -> Self {
+        Self {
+            started: audit.started,
+            duration_secs: audit.duration_secs,
+            backup: backup.display().to_string(),
+            uploaded: audit.transferred,
+            uploaded_bytes,
+            deleted: audit.deleted,
+            errors: audit.errors,
+            failed: audit.failure.is_some(),
+        }
+    }
+}
+
+/// Appends a run to the summaries file
+pub fn record(summaries_path: &Path, summary: &RunSummary) -> Result<()> {
+    let mut line = serde_json::to_string(summary)?;
+    line.push('\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(summaries_path)
+        .wrap_err_with(|| format!("Failed to open {}", summaries_path.display()))?;
+    file.write_all(line.as_bytes())?;
+    drop(file);
+
+    let runs = load(summaries_path)?;
+    if runs.len() > 2 * MAX_SUMMARIES {
+        let mut kept = String::new();
+        for run in runs[runs.len() - MAX_SUMMARIES..].iter() {
+            kept += &serde_json::to_string(run)?;
+            kept.push('\n');
+        }
+        fs::write(summaries_path, kept)?;
+    }
+    Ok(())
+}
+
+/// Reads the recorded runs, oldest first. Lines that can't be read (e.g. cut short by a crash) are skipped.
+pub fn load(summaries_path: &Path) -> Result<Vec<RunSummary>> {
+    let data = match fs::read_to_string(summaries_path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Failed to read {}", summaries_path.display())),
+    };
+    Ok(data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// How the last run compares to the runs before it
+pub struct Trends<'a> {
+    runs: &'a [RunSummary],
+}
+
+impl<'a> Trends<'a> {
+    /// `runs` are oldest first, and can't be empty
+    pub fn new(runs: &'a [RunSummary]) -> Self {
+        debug_assert!(!runs.is_empty());
+        Self { runs }
+    }
+
+    fn median(&self, value: impl Fn(&RunSummary) -> u64) -> u64 {
+        let mut values: Vec<u64> = self.runs.iter().map(value).collect();
+        values.sort_unstable();
+        values[values.len() / 2]
+    }
+
+    /// How many times the median the last run is, if it's worth saying
+    fn ratio_to_median(&self, value: impl Fn(&RunSummary) -> u64) -> Option<f64> {
+        let median = self.median(&value);
+        let last = value(self.runs.last().unwrap());
+        if self.runs.len() < 3 || median == 0 {
+            return None;
+        }
+        Some(last as f64 / median as f64).filter(|ratio| *ratio >= 2.0)
+    }
+
+    /// Runs with errors or that failed, among `runs`
+    fn troubled(runs: &[RunSummary]) -> usize {
+        runs.iter().filter(|run| run.errors > 0 || run.failed).count()
+    }
+}
+
+impl<'a> fmt::Display for Trends<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.runs.last().unwrap();
+        write!(
+            f,
+            "Uploaded per run: {} median, {} last",
+            format_size(self.median(|run| run.uploaded_bytes)),
+            format_size(last.uploaded_bytes)
+        )?;
+        if let Some(ratio) = self.ratio_to_median(|run| run.uploaded_bytes) {
+            write!(f, " ({:.1}\u{d7} the median)", ratio)?;
+        }
+        write!(
+            f,
+            "\nDuration: {} median, {} last",
+            format_duration(Duration::from_secs(self.median(|run| run.duration_secs))),
+            format_duration(Duration::from_secs(last.duration_secs))
+        )?;
+        if let Some(ratio) = self.ratio_to_median(|run| run.duration_secs) {
+            write!(f, " ({:.1}\u{d7} the median)", ratio)?;
+        }
+
+        let (older, newer) = self.runs.split_at(self.runs.len() / 2);
+        write!(
+            f,
+            "\nRuns with errors: {} of {}",
+            Self::troubled(self.runs),
+            self.runs.len()
+        )?;
+        // Errors that keep coming back in the recent runs are worth a look, even if each run mostly works
+        if !older.is_empty() && Self::troubled(newer) * older.len() > Self::troubled(older) * newer.len() {
+            write!(
+                f,
+                ", getting worse ({} of the last {})",
+                Self::troubled(newer),
+                newer.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn run(uploaded_bytes: u64, errors: usize) -> RunSummary {
+        RunSummary {
+            started: 1_000_000,
+            duration_secs: 60,
+            backup: "/home".to_owned(),
+            uploaded: 1,
+            uploaded_bytes,
+            deleted: 0,
+            errors,
+            failed: false,
+        }
+    }
+
+    #[test]
+    fn summaries_are_appended() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("frozen.runs");
+        assert!(load(&path)?.is_empty());
+        record(&path, &run(10, 0))?;
+        record(&path, &run(20, 1))?;
+        assert_eq!(load(&path)?, vec![run(10, 0), run(20, 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn trends_point_out_growth_and_errors() {
+        let runs = vec![run(100, 0), run(120, 0), run(90, 0), run(110, 1), run(1000, 2)];
+        let report = Trends::new(&runs).to_string();
+        assert!(report.contains("(9.1\u{d7} the median)"), "{}", report);
+        assert!(
+            report.contains("Runs with errors: 2 of 5, getting worse (2 of the last 3)"),
+            "{}",
+            report
+        );
+
+        let steady = vec![run(100, 0), run(110, 0), run(100, 0)];
+        let report = Trends::new(&steady).to_string();
+        assert!(!report.contains("median)"), "{}", report);
+        assert!(report.ends_with("Runs with errors: 0 of 3"), "{}", report);
+    }
+}
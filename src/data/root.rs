@@ -1,30 +1,110 @@
+use crate::config::Config;
 use crate::crypto;
 use crate::data::file::{RemoteFile, RemoteFileVersion};
-use crate::net::b2;
-use crate::prompt::prompt_yes_no;
-use bincode::{deserialize, serialize};
+use crate::data::local_lock::LocalLock;
+use crate::data::paths::path_to_bytes;
+use crate::data::remote_lock::{LockHolder, RemoteLock};
+use crate::failure::Failure;
+use crate::net::backend::{Backend, FileListDepth};
+use base64::Engine;
+use bincode::{deserialize_from, serialize};
 use data_encoding::HEXLOWER_PERMISSIVE;
 use eyre::{bail, ensure, eyre, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::iter::Iterator;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
+/// Between the path hash of a root and the name of a lock, in the lock's file name
+const LOCK_INFIX: &str = ".lock.";
+
+/// Behavior flags of `backup` remembered for a root, so they can't be forgotten on the command line
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootSettings {
+    /// Keep remote files that have been deleted locally
+    pub keep_existing: bool,
+    /// Only delete remote files once they've been missing locally for this many seconds
+    pub delete_after_secs: Option<u64>,
+    /// Refuse to back up if some files can't be read
+    pub strict_scan: bool,
+    /// Check every upload with the remote
+    pub verify: bool,
+}
+
+/// What a root is for, to tell roots apart in `list`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootInfo {
+    pub description: Option<String>,
+    /// Tags as key/value pairs, plain tags (e.g. "offsite") have an empty value
+    pub tags: BTreeMap<String, String>,
+}
+
+impl RootInfo {
+    /// Whether the root has a tag, given as "key" (any value) or "key=value"
+    pub fn has_tag(&self, tag: &str) -> bool {
+        match tag.split_once('=') {
+            Some((key, value)) => self.tags.get(key).is_some_and(|tag_value| tag_value == value),
+            None => self.tags.contains_key(tag),
+        }
+    }
+
+    /// The tags as "key" or "key=value", sorted by key
+    pub fn tags_list(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}={}", key, value)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parses a tag given as "key" or "key=value"
+pub fn parse_tag(tag: &str) -> Result<(String, String)> {
+    let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+    ensure!(!key.is_empty(), "Invalid tag \"{}\", the key is empty", tag);
+    Ok((key.to_owned(), value.to_owned()))
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BackupRoot {
     pub path: PathBuf,
     pub path_hash: String,
+    /// The bucket holding the files of this root, if it's not the bucket of the config.
+    /// Saved after the list of roots, so that older versions can still read the list.
+    #[serde(skip)]
+    pub bucket: Option<String>,
+    /// Backup options saved with the root, that every backup of it uses. Saved after the buckets.
+    #[serde(skip)]
+    pub settings: RootSettings,
+    /// Description and tags of the root. Saved after the settings.
+    #[serde(skip)]
+    pub info: RootInfo,
 
     #[serde(skip)]
-    lock: Option<(RemoteFileVersion, b2::B2)>,
+    lock: Option<Arc<RemoteLock>>,
+    /// Keeps other frozen processes of this machine away while the root is locked, shared by the clones of the root
+    #[serde(skip)]
+    local_lock: Option<Arc<LocalLock>>,
 }
 
 impl BackupRoot {
-    fn new(path: &Path, key: &crypto::Key) -> BackupRoot {
+    pub(crate) fn new(path: &Path, key: &crypto::Key) -> BackupRoot {
         BackupRoot {
             path: path.to_owned(),
             path_hash: crypto::hash_path_root(path, key),
+            bucket: None,
+            settings: RootSettings::default(),
+            info: RootInfo::default(),
             lock: None,
+            local_lock: None,
         }
     }
 
@@ -32,15 +112,15 @@ impl BackupRoot {
         self.path = new_path;
     }
 
-    pub async fn list_remote_files(&self, b2: &b2::B2) -> Result<Vec<RemoteFile>> {
-        self.list_remote_files_at(b2, "/", b2::FileListDepth::Deep).await
+    pub async fn list_remote_files(&self, backend: &dyn Backend) -> Result<Vec<RemoteFile>> {
+        self.list_remote_files_at(backend, "/", FileListDepth::Deep).await
     }
 
     pub async fn list_remote_files_at(
         &self,
-        b2: &b2::B2,
+        backend: &dyn Backend,
         prefix: &str,
-        depth: b2::FileListDepth,
+        depth: FileListDepth,
     ) -> Result<Vec<RemoteFile>> {
         ensure!(
             self.lock.is_some(),
@@ -52,31 +132,70 @@ impl BackupRoot {
         debug_assert!(prefix.ends_with('/'));
 
         let path = self.path_hash.clone() + prefix;
-        let mut files = b2.list_remote_files(&path, depth).await?;
+        let mut files = backend.list_remote_files(&path, depth).await?;
         files.sort();
         Ok(files)
     }
 
-    pub async fn lock(&mut self, b2: &b2::B2) -> Result<()> {
+    /// Computes where a file is stored on the remote.
+    /// Returns the dir name hashes of the folders leading to the file (as in the DirDB) and the file's full path hash.
+    pub fn file_path_hashes(&self, rel_path: &Path, key: &crypto::Key) -> Result<(Vec<[u8; 8]>, String)> {
+        let filename = match rel_path.components().next_back() {
+            Some(Component::Normal(filename)) => filename,
+            _ => bail!("Invalid file path \"{}\"", rel_path.display()),
+        };
+        let (dir_hashes, dir_path_hash) = dir_path_hashes(rel_path.parent().unwrap(), key)?;
+
+        let parent_path_hash = self.path_hash.clone() + &dir_path_hash;
+        let mut full_path_hash = parent_path_hash.clone();
+        crypto::hash_path_filename_into(
+            parent_path_hash.as_bytes(),
+            path_to_bytes(Path::new(filename))?,
+            key,
+            &mut full_path_hash,
+        );
+        Ok((dir_hashes, full_path_hash))
+    }
+
+    /// Whether another operation currently holds a lock on this root
+    pub async fn is_locked(&self, backend: &dyn Backend) -> Result<bool> {
+        let lock_path_prefix = self.path_hash.to_owned() + LOCK_INFIX;
+        Ok(!backend.list_remote_file_versions(&lock_path_prefix).await?.is_empty())
+    }
+
+    /// Locks the root, asking `confirm` whether to go on if another lock already exists.
+    /// The lock says who holds it, and is refreshed until the root is unlocked.
+    pub async fn lock(&mut self, backend: &Arc<dyn Backend>, confirm: &dyn Fn(&str) -> bool) -> Result<()> {
+        // Another process of this machine is caught before asking about the remote lock, and can be named
+        let local_lock = LocalLock::acquire(&Config::get_local_locks_path(), &self.path_hash, &self.path)?;
         let rand_str = HEXLOWER_PERMISSIVE.encode(&crypto::randombytes(4));
-        let lock_path_prefix = self.path_hash.to_owned() + ".lock.";
-        let lock_path = lock_path_prefix.to_owned() + &rand_str;
+        let lock_path = self.path_hash.to_owned() + LOCK_INFIX + &rand_str;
 
-        let lock_version = b2.upload_file_simple(&lock_path, Vec::new()).await?;
-        let locks = b2.list_remote_file_versions(&lock_path_prefix).await;
-        self.lock = Some((lock_version, b2.clone()));
+        let lock = RemoteLock::take(backend, lock_path).await?;
+        self.lock = Some(Arc::new(lock));
+        self.local_lock = Some(Arc::new(local_lock));
+        let locks = match list_locks(backend.as_ref(), self).await {
+            Ok(locks) => locks,
+            Err(err) => {
+                let _ = self.unlock().await;
+                return Err(err.wrap_err("Failed to lock backup root"));
+            }
+        };
 
-        if let Err(err) = locks {
-            let _ = self.unlock().await;
-            return Err(err.wrap_err("Failed to lock backup root"));
+        let others: Vec<_> = locks.iter().filter(|lock| lock.name != rand_str).collect();
+        if others.is_empty() {
+            return Ok(());
         }
-        let locks = locks.unwrap();
-
-        if locks.len() > 1 && !prompt_yes_no("Backup root already locked, continue anyways?") {
+        let holders = others
+            .iter()
+            .map(|lock| lock.describe_holder())
+            .collect::<Vec<_>>()
+            .join("; ");
+        if !confirm(&format!("Backup root already locked by {}, continue anyways?", holders)) {
             let _ = self.unlock().await;
-            bail!(
-                "Failed to lock the backup root, {} lock already exists",
-                locks.len() - 1
+            return Err(
+                eyre!("Failed to lock the backup root, {} lock already exists", others.len())
+                    .wrap_err(Failure::LockConflict),
             );
         }
 
@@ -84,46 +203,139 @@ impl BackupRoot {
     }
 
     pub async fn unlock(&mut self) -> Result<()> {
-        if self.lock.is_none() {
-            return Ok(());
+        self.local_lock = None;
+        match self.lock.take() {
+            Some(lock) => lock.release().await,
+            None => Ok(()),
         }
-        let (version, b2) = self.lock.take().unwrap();
-        b2.delete_file_version(&version).await
     }
 }
 
-pub async fn fetch_roots(b2: &b2::B2) -> Result<Vec<BackupRoot>> {
-    let enc_data = match b2.download_file("backup_root").await {
+/// Computes the dir name hashes of the folders of a relative path (as in the DirDB),
+/// and the path hash prefix of the files directly inside it, relative to the backup root (e.g. "/<hash>/<hash>/")
+pub fn dir_path_hashes(rel_dir: &Path, key: &crypto::Key) -> Result<(Vec<[u8; 8]>, String)> {
+    let mut dir_hashes = Vec::new();
+    let mut dir_path_hash = "/".to_owned();
+    for component in rel_dir.components() {
+        let dir_name = match component {
+            Component::Normal(dir_name) => dir_name,
+            _ => bail!("Invalid relative path \"{}\"", rel_dir.display()),
+        };
+        let mut dir_hash = [0; 8];
+        crypto::hash_path_dir_into(&dir_path_hash, path_to_bytes(Path::new(dir_name))?, key, &mut dir_hash);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(dir_hash, &mut dir_path_hash);
+        dir_path_hash.push('/');
+        dir_hashes.push(dir_hash);
+    }
+    Ok((dir_hashes, dir_path_hash))
+}
+
+pub async fn fetch_roots(backend: &dyn Backend) -> Result<Vec<BackupRoot>> {
+    let names = backend.object_names();
+    let mut enc_data = backend.download_file(names.roots()).await;
+    // The list is moved to its obfuscated name the next time it's saved
+    if let (Err(_), Some(legacy_roots)) = (&enc_data, names.legacy_roots()) {
+        enc_data = backend.download_file(legacy_roots).await;
+    }
+    let enc_data = match enc_data {
         Ok(enc_data) => enc_data,
         Err(_) => return Ok(Vec::new()),
     };
-    let data = crypto::decrypt(&enc_data, &b2.key)?;
-    Ok(deserialize(&data[..]).unwrap())
+    let data = crypto::decrypt(&enc_data, backend.key())?;
+    let mut reader = &data[..];
+    let mut roots: Vec<BackupRoot> = deserialize_from(&mut reader)?;
+    // Lists saved before roots could be in other buckets end here, or with padding that reads as an empty list
+    let buckets: Vec<Option<String>> = deserialize_from(&mut reader).unwrap_or_default();
+    if buckets.len() == roots.len() {
+        for (root, bucket) in roots.iter_mut().zip(buckets) {
+            root.bucket = bucket;
+        }
+    }
+    // Same for lists saved before roots had settings
+    let settings: Vec<RootSettings> = deserialize_from(&mut reader).unwrap_or_default();
+    if settings.len() == roots.len() {
+        for (root, settings) in roots.iter_mut().zip(settings) {
+            root.settings = settings;
+        }
+    }
+    // And lists saved before roots had descriptions
+    let infos: Vec<RootInfo> = deserialize_from(&mut reader).unwrap_or_default();
+    if infos.len() == roots.len() {
+        for (root, info) in roots.iter_mut().zip(infos) {
+            root.info = info;
+        }
+    }
+    Ok(roots)
 }
 
-pub async fn save_roots(b2: &b2::B2, roots: &[BackupRoot]) -> Result<()> {
-    let plain_data = serialize(roots)?;
-    let data = crypto::encrypt(&plain_data, &b2.key);
-    b2.upload_file_simple("backup_root", data).await?;
+pub async fn save_roots(backend: &dyn Backend, roots: &[BackupRoot]) -> Result<()> {
+    let names = backend.object_names();
+    let mut plain_data = serialize(roots)?;
+    let buckets: Vec<&Option<String>> = roots.iter().map(|root| &root.bucket).collect();
+    plain_data.extend(serialize(&buckets)?);
+    let settings: Vec<&RootSettings> = roots.iter().map(|root| &root.settings).collect();
+    plain_data.extend(serialize(&settings)?);
+    let infos: Vec<&RootInfo> = roots.iter().map(|root| &root.info).collect();
+    plain_data.extend(serialize(&infos)?);
+    let plain_data = names.pad_roots(plain_data);
+    let data = crypto::encrypt(&plain_data, backend.key());
+    backend.upload_file_simple(names.roots(), data).await?;
+    if let Some(legacy_roots) = names.legacy_roots() {
+        if backend
+            .list_remote_files(legacy_roots, FileListDepth::Shallow)
+            .await?
+            .iter()
+            .any(|file| file.full_path_hash == legacy_roots)
+        {
+            backend.hide_file(legacy_roots).await?;
+        }
+    }
     Ok(())
 }
 
 /// Opens an existing backup root, or creates one if necessary
-pub async fn open_create_root(b2: &b2::B2, roots: &mut Vec<BackupRoot>, path: &Path) -> Result<BackupRoot> {
+pub async fn open_create_root(
+    backend: &Arc<dyn Backend>,
+    roots: &mut Vec<BackupRoot>,
+    path: &Path,
+    confirm: &dyn Fn(&str) -> bool,
+) -> Result<BackupRoot> {
+    open_create_root_in_bucket(backend.as_ref(), backend, roots, path, None, confirm).await
+}
+
+/// Opens an existing backup root, or creates one whose files are stored in `bucket`.
+/// The list of roots is saved with `roots_backend`, and the root is locked with `backend`, which must be its bucket.
+pub async fn open_create_root_in_bucket(
+    roots_backend: &dyn Backend,
+    backend: &Arc<dyn Backend>,
+    roots: &mut Vec<BackupRoot>,
+    path: &Path,
+    bucket: Option<String>,
+    confirm: &dyn Fn(&str) -> bool,
+) -> Result<BackupRoot> {
     let mut root: BackupRoot;
     if let Some(existing_root) = roots.iter_mut().find(|r| r.path == *path) {
         root = existing_root.clone();
     } else {
-        root = BackupRoot::new(path, &b2.key);
+        root = BackupRoot::new(path, backend.key());
+        root.bucket = bucket;
         roots.push(root.clone());
-        save_roots(b2, roots).await?;
+        save_roots(roots_backend, roots).await?;
     }
 
-    root.lock(b2).await?;
+    root.lock(backend, confirm).await?;
     Ok(root)
 }
 
-pub async fn delete_root(b2: &mut b2::B2, roots: &mut Vec<BackupRoot>, path: &Path) -> Result<()> {
+/// The bucket of the root at `path`, if it exists and isn't in the bucket of the config
+pub fn bucket_of<'a>(roots: &'a [BackupRoot], path: &Path) -> Option<&'a str> {
+    roots
+        .iter()
+        .find(|r| r.path == path)
+        .and_then(|root| root.bucket.as_deref())
+}
+
+pub async fn delete_root(backend: &dyn Backend, roots: &mut Vec<BackupRoot>, path: &Path) -> Result<()> {
     if roots
         .iter()
         .position(|r| r.path == path)
@@ -135,36 +347,92 @@ pub async fn delete_root(b2: &mut b2::B2, roots: &mut Vec<BackupRoot>, path: &Pa
             path.display()
         ))
     } else {
-        save_roots(b2, roots).await
+        save_roots(backend, roots).await
     }
 }
 
-/// Opens an existing backup root
-pub async fn open_root(b2: &b2::B2, roots: &mut [BackupRoot], path: &Path) -> Result<BackupRoot> {
+/// Opens an existing backup root, `confirm` is asked whether to go on if it's already locked
+pub async fn open_root(
+    backend: &Arc<dyn Backend>,
+    roots: &mut [BackupRoot],
+    path: &Path,
+    confirm: &dyn Fn(&str) -> bool,
+) -> Result<BackupRoot> {
     match roots.iter().find(|r| r.path == path) {
         Some(root) => {
             let mut root = root.clone();
-            root.lock(b2).await?;
+            root.lock(backend, confirm).await?;
             Ok(root)
         }
         None => Err(eyre!("Backup does not exist for \"{}\"", path.display())),
     }
 }
 
-/// Forcibly unlocks a backup root
-pub async fn wipe_locks(b2: &mut b2::B2, roots: &[BackupRoot], path: &Path) -> Result<()> {
-    if let Some(root) = roots.iter().find(|r| r.path == *path) {
-        let lock_path_prefix = root.path_hash.to_owned() + ".lock.";
-        let locks = b2.list_remote_file_versions(&lock_path_prefix).await?;
+/// A lock of a backup root on the remote
+pub struct RootLock {
+    /// What tells the locks of a root apart
+    pub name: String,
+    /// The versions of the lock file, a refresh briefly leaves two
+    pub versions: Vec<RemoteFileVersion>,
+    /// Who holds the lock, None for the empty locks of older versions of frozen
+    pub holder: Option<LockHolder>,
+    /// For how long the lock wasn't refreshed (or since it was taken, for the locks that aren't)
+    pub age: Duration,
+}
 
-        println!("{} lock files to remove", locks.len());
-        for lock_version in &locks {
-            b2.delete_file_version(lock_version).await?;
+impl RootLock {
+    pub fn describe_holder(&self) -> String {
+        match &self.holder {
+            Some(holder) => holder.describe(),
+            None => "an unknown holder".to_owned(),
+        }
+    }
+}
+
+/// Lists the locks of a backup root on the remote, with who holds them
+pub async fn list_locks(backend: &dyn Backend, root: &BackupRoot) -> Result<Vec<RootLock>> {
+    let lock_path_prefix = root.path_hash.to_owned() + LOCK_INFIX;
+    let now = SystemTime::now();
+    let mut locks: Vec<RootLock> = Vec::new();
+    for (version, uploaded) in backend.list_remote_file_versions_timed(&lock_path_prefix).await? {
+        let name = version.path.trim_start_matches(&lock_path_prefix).to_owned();
+        let age = now.duration_since(uploaded).unwrap_or_default();
+        match locks.iter_mut().find(|lock| lock.name == name) {
+            Some(lock) => {
+                lock.versions.push(version);
+                lock.age = lock.age.min(age);
+            }
+            None => locks.push(RootLock {
+                name,
+                versions: vec![version],
+                holder: None,
+                age,
+            }),
         }
-        Ok(())
-    } else {
-        Err(eyre!("Backup does not exist for \"{}\"", path.display()))
     }
+
+    for lock in &mut locks {
+        // The lock may be released as we look, then it has no holder to show
+        let data = match backend.download_file(&lock.versions[0].path).await {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        lock.holder = LockHolder::decode(&data, backend.key());
+        if let Some(holder) = &lock.holder {
+            lock.age = now.duration_since(holder.refreshed_at()).unwrap_or_default();
+        }
+    }
+    Ok(locks)
+}
+
+/// Forcibly removes locks of a backup root, see `list_locks`
+pub async fn wipe_locks(backend: &dyn Backend, locks: &[RootLock]) -> Result<()> {
+    for lock in locks {
+        for version in &lock.versions {
+            backend.delete_file_version(version).await?;
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -177,3 +445,118 @@ pub mod test_helpers {
         BackupRoot::new(Path::new("/tmp/test/path"), key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test]
+    async fn roots_move_to_obfuscated_name() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let roots = vec![BackupRoot::new(Path::new("/a"), backend.key())];
+        save_roots(&backend, &roots).await?;
+        assert_eq!(backend.file_names(), ["backup_root"]);
+
+        backend.set_obfuscated_names();
+        let mut roots = fetch_roots(&backend).await?;
+        assert_eq!(roots[0].path, Path::new("/a"));
+        roots.push(BackupRoot::new(Path::new("/b"), backend.key()));
+        save_roots(&backend, &roots).await?;
+        let names = backend.object_names();
+        assert_eq!(backend.file_names(), [names.roots()]);
+        assert_eq!(fetch_roots(&backend).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roots_remember_their_bucket() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let mut roots = vec![
+            BackupRoot::new(Path::new("/a"), backend.key()),
+            BackupRoot::new(Path::new("/b"), backend.key()),
+        ];
+        roots[1].bucket = Some("photos".to_owned());
+        save_roots(&backend, &roots).await?;
+        let roots = fetch_roots(&backend).await?;
+        assert_eq!(bucket_of(&roots, Path::new("/a")), None);
+        assert_eq!(bucket_of(&roots, Path::new("/b")), Some("photos"));
+
+        // Lists saved by older versions have no buckets
+        let old_list = crypto::encrypt(&serialize(&roots)?, backend.key());
+        backend.upload_file_simple("backup_root", old_list).await?;
+        assert_eq!(fetch_roots(&backend).await?.len(), 2);
+        assert_eq!(bucket_of(&fetch_roots(&backend).await?, Path::new("/b")), None);
+
+        // As well as padded lists
+        backend.set_obfuscated_names();
+        save_roots(&backend, &roots).await?;
+        assert_eq!(
+            bucket_of(&fetch_roots(&backend).await?, Path::new("/b")),
+            Some("photos")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roots_remember_their_settings() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let mut roots = vec![
+            BackupRoot::new(Path::new("/a"), backend.key()),
+            BackupRoot::new(Path::new("/b"), backend.key()),
+        ];
+        roots[0].bucket = Some("photos".to_owned());
+        roots[1].settings = RootSettings {
+            keep_existing: true,
+            delete_after_secs: Some(60),
+            ..Default::default()
+        };
+        save_roots(&backend, &roots).await?;
+        let fetched = fetch_roots(&backend).await?;
+        assert_eq!(fetched[0].bucket.as_deref(), Some("photos"));
+        assert_eq!(fetched[0].settings, RootSettings::default());
+        assert_eq!(fetched[1].settings, roots[1].settings);
+
+        // Lists saved before the settings only have buckets
+        let mut old_list = serialize(&roots)?;
+        old_list.extend(serialize(&vec![None::<String>; 2])?);
+        let old_list = crypto::encrypt(&old_list, backend.key());
+        backend.upload_file_simple("backup_root", old_list).await?;
+        assert_eq!(fetch_roots(&backend).await?[1].settings, RootSettings::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roots_remember_their_info() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let mut roots = vec![
+            BackupRoot::new(Path::new("/a"), backend.key()),
+            BackupRoot::new(Path::new("/b"), backend.key()),
+        ];
+        roots[1].info.description = Some("Photos from the NAS".to_owned());
+        for tag in ["offsite", "site=paris"] {
+            let (key, value) = parse_tag(tag)?;
+            roots[1].info.tags.insert(key, value);
+        }
+        save_roots(&backend, &roots).await?;
+        let fetched = fetch_roots(&backend).await?;
+        assert_eq!(fetched[0].info, RootInfo::default());
+        assert_eq!(fetched[1].info, roots[1].info);
+        assert_eq!(fetched[1].info.tags_list(), ["offsite", "site=paris"]);
+        assert!(fetched[1].info.has_tag("offsite"));
+        assert!(fetched[1].info.has_tag("site"));
+        assert!(fetched[1].info.has_tag("site=paris"));
+        assert!(!fetched[1].info.has_tag("site=lyon"));
+        assert!(parse_tag("=value").is_err());
+
+        // Lists saved before the descriptions only have settings
+        let mut old_list = serialize(&roots)?;
+        old_list.extend(serialize(&vec![None::<String>; 2])?);
+        old_list.extend(serialize(&vec![RootSettings::default(); 2])?);
+        let old_list = crypto::encrypt(&old_list, backend.key());
+        backend.upload_file_simple("backup_root", old_list).await?;
+        assert_eq!(fetch_roots(&backend).await?[1].info, RootInfo::default());
+        Ok(())
+    }
+}
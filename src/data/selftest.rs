@@ -0,0 +1,224 @@
+//! Round trips of the encryption, compression, DirDB and path hashing code on generated data, see `frozen self-test`
+
+use crate::crypto::{self, randombytes, Key};
+use crate::data::root::{dir_path_hashes, BackupRoot};
+use crate::dirdb::DirDB;
+use crate::net::backend::Backend;
+use crate::stream::{CompressionStream, DecryptionStream, EncryptionStream, STREAMS_CHUNK_SIZE};
+use bytes::Bytes;
+use eyre::{ensure, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use tempfile::tempdir;
+
+/// Where the remote check uploads its test object
+const REMOTE_TEST_PREFIX: &str = "self-test/";
+
+/// The outcome of one check
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<()>,
+}
+
+/// Runs every local check with `key`, they don't need a connection
+pub async fn run_local_checks(key: &Key) -> Vec<Check> {
+    vec![
+        Check {
+            name: "Encryption streams",
+            result: check_encryption(key).await,
+        },
+        Check {
+            name: "Compression",
+            result: check_compression().await,
+        },
+        Check {
+            name: "Upload and download pipeline",
+            result: check_pipeline(key).await,
+        },
+        Check {
+            name: "DirDB pack and unpack",
+            result: check_dirdb(key),
+        },
+        Check {
+            name: "Path hashing",
+            result: check_path_hashing(key),
+        },
+    ]
+}
+
+/// Uploads a small object through `backend`, downloads it back and deletes it
+pub async fn check_remote(backend: &dyn Backend) -> Result<()> {
+    let name = REMOTE_TEST_PREFIX.to_owned() + &data_encoding::HEXLOWER.encode(&randombytes(8));
+    let data = randombytes(1024);
+    let version = backend.upload_file_simple(&name, data.clone()).await?;
+    let downloaded = backend.download_file(&name).await;
+    backend.delete_file_version(&version).await?;
+    ensure!(
+        downloaded?[..] == data[..],
+        "The downloaded test object differs from the upload"
+    );
+    Ok(())
+}
+
+async fn encrypt_all(data: Vec<u8>, key: &Key) -> Result<Vec<Bytes>> {
+    let input = stream::iter(vec![Ok(Bytes::from(data))]);
+    EncryptionStream::new(Box::new(input), key).try_collect().await
+}
+
+async fn decrypt_all(encrypted: Vec<Bytes>, key: &Key) -> Result<Vec<u8>> {
+    let input = stream::iter(encrypted.into_iter().map(Ok)).boxed();
+    DecryptionStream::new(input, key)
+        .map_ok(|chunk| chunk.to_vec())
+        .try_concat()
+        .await
+}
+
+async fn check_encryption(key: &Key) -> Result<()> {
+    // More than one chunk, so that chunks are chained
+    let data = randombytes(STREAMS_CHUNK_SIZE + 4321);
+    let encrypted = encrypt_all(data.clone(), key).await?;
+    ensure!(
+        decrypt_all(encrypted.clone(), key).await? == data,
+        "Decrypted data differs from the original"
+    );
+
+    let mut tampered = encrypted.clone();
+    let mut chunk = tampered[0].to_vec();
+    let last = chunk.len() - 1;
+    chunk[last] ^= 1;
+    tampered[0] = Bytes::from(chunk);
+    ensure!(
+        decrypt_all(tampered, key).await.is_err(),
+        "Tampered data was decrypted without an error"
+    );
+    let other_key = crypto::derive_key("not the key", "self-test");
+    ensure!(
+        decrypt_all(encrypted, &other_key).await.is_err(),
+        "Data was decrypted with the wrong key"
+    );
+
+    let small = randombytes(100);
+    ensure!(
+        crypto::decrypt(&crypto::encrypt(&small, key), key)? == small,
+        "Decrypted object differs from the original"
+    );
+    Ok(())
+}
+
+/// Compressible data, random bytes wouldn't exercise much of zstd
+fn generated_text(len: usize) -> Vec<u8> {
+    let words = ["frozen ", "backup ", "encrypted ", "compressed ", "folder ", "file\n"];
+    let seed = randombytes(len / 8 + 1);
+    seed.iter()
+        .flat_map(|byte| words[*byte as usize % words.len()].bytes())
+        .take(len)
+        .collect()
+}
+
+async fn check_compression() -> Result<()> {
+    let data = generated_text(1024 * 1024);
+    let compressed: Vec<Bytes> = CompressionStream::new(Cursor::new(data.clone()), 3)
+        .await
+        .try_collect()
+        .await?;
+    let compressed = compressed.concat();
+    ensure!(
+        compressed.len() < data.len(),
+        "Compression didn't make the data any smaller"
+    );
+    ensure!(
+        zstd::decode_all(compressed.as_slice())? == data,
+        "Decompressed data differs from the original"
+    );
+    Ok(())
+}
+
+/// Compression then encryption like an upload, and the other way around like a download
+async fn check_pipeline(key: &Key) -> Result<()> {
+    let data = generated_text(3 * 1024 * 1024);
+    let compressed = CompressionStream::new(Cursor::new(data.clone()), 3).await;
+    let encrypted: Vec<Bytes> = EncryptionStream::new(Box::new(compressed), key).try_collect().await?;
+    let compressed = decrypt_all(encrypted, key).await?;
+    ensure!(
+        zstd::decode_all(compressed.as_slice())? == data,
+        "Downloaded data differs from the uploaded data"
+    );
+    Ok(())
+}
+
+fn check_dirdb(key: &Key) -> Result<()> {
+    let dir = tempdir()?;
+    for i in 0..200 {
+        let path = dir.path().join(format!("dir{}/sub{}/file{}", i % 7, i % 3, i));
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, i.to_string())?;
+    }
+    fs::create_dir_all(dir.path().join("empty/nested"))?;
+
+    let dirdb = DirDB::new_from_local(dir.path(), key)?;
+    ensure!(dirdb.root.total_files_count == 200, "The scan didn't count every file");
+    let unpacked = DirDB::new_from_packed(&dirdb.to_packed(key)?, key)?;
+    ensure!(
+        unpacked.root == dirdb.root,
+        "The unpacked DirDB differs from the original"
+    );
+    ensure!(
+        DirDB::new_from_packed(&dirdb.to_packed(key)?, &crypto::derive_key("not the key", "self-test")).is_err(),
+        "The DirDB was unpacked with the wrong key"
+    );
+    Ok(())
+}
+
+fn check_path_hashing(key: &Key) -> Result<()> {
+    let root = BackupRoot::new(Path::new("/self-test/root"), key);
+    ensure!(
+        root.path_hash == BackupRoot::new(Path::new("/self-test/root"), key).path_hash,
+        "Root path hashes aren't deterministic"
+    );
+    ensure!(
+        root.path_hash != BackupRoot::new(Path::new("/self-test/other"), key).path_hash,
+        "Different roots have the same path hash"
+    );
+    let other_key = crypto::derive_key("not the key", "self-test");
+    ensure!(
+        root.path_hash != BackupRoot::new(Path::new("/self-test/root"), &other_key).path_hash,
+        "Path hashes don't depend on the key"
+    );
+
+    let (dir_hashes, dir_path_hash) = dir_path_hashes(Path::new("a/b"), key)?;
+    let (file_dir_hashes, full_path_hash) = root.file_path_hashes(Path::new("a/b/c"), key)?;
+    ensure!(
+        dir_hashes == file_dir_hashes,
+        "Files and folders have different folder hashes"
+    );
+    ensure!(
+        full_path_hash.starts_with(&(root.path_hash.clone() + &dir_path_hash)),
+        "File path hashes don't start with their folder's"
+    );
+    let (_, sibling_hash) = root.file_path_hashes(Path::new("a/b/d"), key)?;
+    ensure!(
+        sibling_hash != full_path_hash,
+        "Different files have the same path hash"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn self_test_passes() -> Result<()> {
+        for Check { name, result } in run_local_checks(&test_key()).await {
+            result.map_err(|err| err.wrap_err(name))?;
+        }
+        let backend = MemoryBackend::new(test_key());
+        check_remote(&backend).await?;
+        assert!(backend.list_remote_file_versions(REMOTE_TEST_PREFIX).await?.is_empty());
+        Ok(())
+    }
+}
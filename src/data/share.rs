@@ -0,0 +1,137 @@
+//! Share links for a single backed up file, for handing it to someone who doesn't have the backup key
+
+use crate::crypto::randombytes;
+use crate::data::file::RemoteFile;
+use crate::net::backend::Backend;
+use crate::stream::{DecompressedStream, DecryptionStream};
+use data_encoding::HEXLOWER;
+use eyre::{bail, eyre, Result, WrapErr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Shared copies are uploaded under this prefix, outside of any backup root
+pub const SHARE_PREFIX: &str = "share/";
+/// B2 download authorizations are valid for a week at most
+pub const SHARE_MAX_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Uploads a decrypted copy of a backed up file, and returns a URL that downloads it until `valid_for` elapses.
+///
+/// Our files are all encrypted with the backup key, which can't be handed out without giving access to everything,
+/// so the link points to a plain copy under `SHARE_PREFIX` instead. Its name starts with its expiry date,
+/// and the copy is deleted by `delete_expired_shares`.
+pub async fn share_file(backend: &dyn Backend, file: &RemoteFile, valid_for: Duration) -> Result<String> {
+    if file.is_symlink {
+        bail!("\"{}\" is a symlink, only files can be shared", file.rel_path.display());
+    }
+    if valid_for > SHARE_MAX_DURATION {
+        bail!("Share links can't be valid for more than 7 days");
+    }
+
+    let encrypted = backend.download_file_stream(&file.full_path_hash).await?;
+    let decrypted = DecryptionStream::new(encrypted, backend.key());
+    let data_stream = DecompressedStream::new(Box::new(decrypted)).await;
+
+    let expires = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + valid_for.as_secs();
+    let file_name = file
+        .rel_path
+        .file_name()
+        .map(|name| share_file_name(&name.to_string_lossy()))
+        .unwrap_or_default();
+    let name = format!(
+        "{}{}/{}/{}",
+        SHARE_PREFIX,
+        expires,
+        HEXLOWER.encode(&randombytes(16)),
+        file_name
+    );
+    let upload_url = backend.get_upload_url().await?;
+    backend
+        .upload_file_stream(&upload_url, &name, Box::new(data_stream), None)
+        .await
+        .wrap_err_with(|| format!("Failed to copy \"{}\"", file.rel_path.display()))?;
+    backend.share_url(&name, valid_for).await
+}
+
+/// Keeps the name readable in the URL, without anything that would need escaping
+fn share_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        "file".to_owned() + &name
+    } else {
+        name
+    }
+}
+
+/// Deletes the shared copies whose link expired, returns how many were deleted
+pub async fn delete_expired_shares(backend: &dyn Backend) -> Result<usize> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut deleted = 0;
+    for version in backend.list_remote_file_versions(SHARE_PREFIX).await? {
+        let expires = version.path[SHARE_PREFIX.len()..]
+            .split('/')
+            .next()
+            .and_then(|expires| expires.parse::<u64>().ok())
+            .ok_or_else(|| eyre!("Unexpected shared file name {}", version.path))?;
+        if expires <= now {
+            backend.delete_file_version(&version).await?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::stream::{CompressionStream, EncryptionStream};
+    use crate::test_helpers::test_key;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shares_decrypted_copy() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let compressed = CompressionStream::new(Cursor::new(b"shared data".to_vec()), 3).await;
+        let encrypted: Vec<Bytes> = EncryptionStream::new(Box::new(compressed), backend.key())
+            .try_collect()
+            .await?;
+        let version = backend.upload_file_simple("root/hash", encrypted.concat()).await?;
+        let file = RemoteFile {
+            rel_path: PathBuf::from("dir/My report.pdf"),
+            full_path_hash: version.path,
+            id: version.id,
+            last_modified: 0,
+            mode: 0o644,
+            is_symlink: false,
+            birthtime: None,
+            content_hash: None,
+            size: 0,
+            original_size: None,
+        };
+
+        let url = share_file(&backend, &file, Duration::from_secs(3600)).await?;
+        let name = url
+            .strip_prefix("memory:///")
+            .and_then(|url| url.split('?').next())
+            .unwrap();
+        assert!(name.starts_with(SHARE_PREFIX) && name.ends_with("/My_report.pdf"));
+        assert_eq!(&backend.download_file(name).await?[..], b"shared data");
+        assert!(share_file(&backend, &file, SHARE_MAX_DURATION * 2).await.is_err());
+
+        // Only expired copies are cleaned up
+        assert_eq!(delete_expired_shares(&backend).await?, 0);
+        backend.upload_file_simple("share/1/old/file", vec![0]).await?;
+        assert_eq!(delete_expired_shares(&backend).await?, 1);
+        assert_eq!(backend.list_remote_file_versions(SHARE_PREFIX).await?.len(), 1);
+        assert_eq!(share_file_name(".bashrc"), "file.bashrc");
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use crate::data::checkpoint::CHECKPOINT_FILE_NAME;
+use eyre::{bail, Result, WrapErr};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Checks that a restore can be staged in `staging` and then swapped into `target`, before downloading anything.
+/// Both folders are created if they don't exist yet.
+/// The staging folder must be empty, unless it holds an interrupted restore to resume.
+pub fn check_staging(staging: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(staging).wrap_err_with(|| format!("Failed to create {}", staging.display()))?;
+    fs::create_dir_all(target).wrap_err_with(|| format!("Failed to create {}", target.display()))?;
+    let staging = staging.canonicalize()?;
+    let target = target.canonicalize()?;
+    if staging.starts_with(&target) || target.starts_with(&staging) {
+        bail!(
+            "The staging folder {} can't be inside the restore target {}, or the other way around",
+            staging.display(),
+            target.display()
+        );
+    }
+    let is_empty = fs::read_dir(&staging)?.next().is_none();
+    if !is_empty && !staging.join(CHECKPOINT_FILE_NAME).exists() {
+        bail!(
+            "The staging folder {} must be empty, or hold an interrupted restore",
+            staging.display()
+        );
+    }
+    // Renames don't cross filesystems
+    if fs::metadata(&staging)?.dev() != fs::metadata(&target)?.dev() {
+        bail!(
+            "The staging folder {} must be on the same filesystem as {}",
+            staging.display(),
+            target.display()
+        );
+    }
+    Ok(())
+}
+
+/// Swaps a completely restored staging folder with the target.
+/// What was in the target before ends up in the staging folder, nothing is deleted.
+pub fn swap_into(staging: &Path, target: &Path) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    match exchange(staging, target) {
+        Ok(()) => return Ok(()),
+        // Older kernels and some filesystems can't exchange, they get the three renames
+        Err(err) if matches!(err.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)) => {}
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Failed to swap {} with {}", staging.display(), target.display()))
+        }
+    }
+    swap_by_renames(staging, target)
+}
+
+/// Swaps both paths in one step, the target is never missing even if we're killed halfway
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the target aside, the staging folder in its place, and the previous target into the staging folder
+fn swap_by_renames(staging: &Path, target: &Path) -> Result<()> {
+    let mut previous = OsString::from(staging.as_os_str());
+    previous.push(".previous");
+    let previous = PathBuf::from(previous);
+    if previous.exists() {
+        bail!("{} is in the way of swapping in the restore", previous.display());
+    }
+
+    fs::rename(target, &previous).wrap_err_with(|| format!("Failed to move {} aside", target.display()))?;
+    if let Err(err) = fs::rename(staging, target) {
+        let _ = fs::rename(&previous, target);
+        return Err(err).wrap_err_with(|| format!("Failed to move {} to {}", staging.display(), target.display()));
+    }
+    fs::rename(&previous, staging).wrap_err_with(|| {
+        format!(
+            "The restore is in {}, but failed to move the previous contents to {}",
+            target.display(),
+            staging.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn swaps_staged_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let staging = dir.path().join("staging");
+        let target = dir.path().join("target");
+        check_staging(&staging, &target)?;
+        fs::write(staging.join("restored"), b"new")?;
+        fs::write(target.join("old"), b"old")?;
+
+        swap_into(&staging, &target)?;
+        assert_eq!(fs::read(target.join("restored"))?, b"new");
+        assert!(!target.join("old").exists());
+        assert_eq!(fs::read(staging.join("old"))?, b"old");
+
+        // The previous contents must be moved out of the way before staging again
+        assert!(check_staging(&staging, &target).is_err());
+        assert!(check_staging(&target.join("sub"), &target).is_err());
+        assert!(check_staging(&staging, &staging.join("sub")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn swaps_by_renames() -> Result<()> {
+        let dir = tempdir()?;
+        let staging = dir.path().join("staging");
+        let target = dir.path().join("target");
+        check_staging(&staging, &target)?;
+        fs::write(staging.join("restored"), b"new")?;
+        fs::write(target.join("old"), b"old")?;
+
+        swap_by_renames(&staging, &target)?;
+        assert_eq!(fs::read(target.join("restored"))?, b"new");
+        assert_eq!(fs::read(staging.join("old"))?, b"old");
+        assert!(!dir.path().join("staging.previous").exists());
+        Ok(())
+    }
+}
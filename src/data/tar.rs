@@ -0,0 +1,154 @@
+//! Writes restored files as a tar archive (POSIX ustar, with pax headers for what doesn't fit), see `restore --to-stdout-tar`
+
+use std::io::{self, Write};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Largest value of the 12 bytes numeric fields (11 octal digits), bigger sizes go in a pax header
+const MAX_OCTAL_11: u64 = 0o77777777777;
+
+/// What a tar entry is
+pub enum EntryKind<'a> {
+    File,
+    /// A symlink to this raw target, symlinks have no content
+    Symlink(&'a [u8]),
+}
+
+/// The header of a tar entry, followed by `size` bytes of content
+pub struct TarEntry<'a> {
+    /// Relative path in the archive, raw bytes since paths don't have to be valid UTF-8
+    pub path: &'a [u8],
+    pub mode: u32,
+    /// Seconds since the Unix epoch
+    pub mtime: u64,
+    pub size: u64,
+    pub kind: EntryKind<'a>,
+}
+
+/// Writes the header of an entry, the caller then writes exactly `entry.size` bytes and `write_padding`
+pub fn write_header(out: &mut impl Write, entry: &TarEntry) -> io::Result<()> {
+    let link = match entry.kind {
+        EntryKind::File => &[][..],
+        EntryKind::Symlink(target) => target,
+    };
+    let mut pax = Vec::new();
+    if entry.path.len() > 100 {
+        pax_record(&mut pax, "path", entry.path);
+    }
+    if link.len() > 100 {
+        pax_record(&mut pax, "linkpath", link);
+    }
+    if entry.size > MAX_OCTAL_11 {
+        pax_record(&mut pax, "size", entry.size.to_string().as_bytes());
+    }
+    if !pax.is_empty() {
+        let pax_header = ustar_header(b"././@PaxHeader", 0o644, entry.mtime, pax.len() as u64, b'x', b"");
+        out.write_all(&pax_header)?;
+        out.write_all(&pax)?;
+        write_padding(out, pax.len() as u64)?;
+    }
+
+    let typeflag = match entry.kind {
+        EntryKind::File => b'0',
+        EntryKind::Symlink(_) => b'2',
+    };
+    // The pax header has the whole values, these are only for readers that ignore it
+    let header = ustar_header(
+        &entry.path[..entry.path.len().min(100)],
+        entry.mode,
+        entry.mtime,
+        entry.size.min(MAX_OCTAL_11),
+        typeflag,
+        &link[..link.len().min(100)],
+    );
+    out.write_all(&header)
+}
+
+/// Pads the content of an entry of `size` bytes to a whole block
+pub fn write_padding(out: &mut impl Write, size: u64) -> io::Result<()> {
+    let rest = (size % BLOCK_SIZE as u64) as usize;
+    if rest != 0 {
+        out.write_all(&[0; BLOCK_SIZE][rest..])?;
+    }
+    Ok(())
+}
+
+/// Ends the archive with two empty blocks
+pub fn write_end(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(&[0; 2 * BLOCK_SIZE])
+}
+
+fn ustar_header(name: &[u8], mode: u32, mtime: u64, size: u64, typeflag: u8, link: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name);
+    octal_field(&mut header[100..108], (mode & 0o7777) as u64);
+    octal_field(&mut header[108..116], 0);
+    octal_field(&mut header[116..124], 0);
+    octal_field(&mut header[124..136], size);
+    octal_field(&mut header[136..148], mtime.min(MAX_OCTAL_11));
+    header[156] = typeflag;
+    header[157..157 + link.len()].copy_from_slice(link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// Zero-padded octal, ending with a NUL
+fn octal_field(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// A "<length> <key>=<value>\n" record, whose length counts its own digits
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    pax.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_record_length_counts_itself() {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", &[b'a'; 95]);
+        assert_eq!(pax.len(), 105);
+        assert!(pax.starts_with(b"105 path=aaa"));
+
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", b"x");
+        assert_eq!(pax, b"9 path=x\n");
+    }
+
+    #[test]
+    fn header_checksum_matches() {
+        let entry = TarEntry {
+            path: b"dir/file",
+            mode: 0o640,
+            mtime: 1_000_000,
+            size: 1234,
+            kind: EntryKind::File,
+        };
+        let mut out = Vec::new();
+        write_header(&mut out, &entry).unwrap();
+        assert_eq!(out.len(), BLOCK_SIZE);
+        assert_eq!(&out[124..136], b"00000002322\0");
+        let mut blanked = out.clone();
+        blanked[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = blanked.iter().map(|&byte| byte as u32).sum();
+        assert_eq!(&out[148..156], format!("{:06o}\0 ", checksum).as_bytes());
+    }
+}
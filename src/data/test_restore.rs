@@ -0,0 +1,208 @@
+//! Restores a random sample of a backup and compares it with the live files, see `frozen test-restore`
+
+use crate::action;
+use crate::config::Config;
+use crate::data::checkpoint::RestoreCheckpoint;
+use crate::data::file::RemoteFile;
+use crate::data::fsync::FsyncQueue;
+use crate::net::backend::Backend;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{Progress, ProgressType};
+use blake2::{Blake2b512, Digest};
+use eyre::Result;
+use futures::future::join_all;
+use sodiumoxide::randombytes::randombytes_uniform;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SampleOutcome {
+    /// Same content as the live file, which wasn't modified since the backup
+    Identical,
+    /// Restored, but different from a live file that has the same mtime as in the backup
+    Different(String),
+    /// Restored, but the live file was modified since, so there's nothing to compare with
+    ChangedLocally,
+    /// Restored, but there's no live file to compare with
+    MissingLocally,
+    /// Couldn't be restored, the error was already reported
+    Failed,
+}
+
+impl SampleOutcome {
+    /// Whether this shows a broken backup
+    pub fn is_failure(&self) -> bool {
+        matches!(self, SampleOutcome::Different(_) | SampleOutcome::Failed)
+    }
+}
+
+pub struct SampleResult {
+    pub rel_path: PathBuf,
+    pub outcome: SampleOutcome,
+}
+
+/// Picks up to `count` files at random
+pub fn pick_sample(mut files: Vec<RemoteFile>, count: usize) -> Vec<RemoteFile> {
+    let count = count.min(files.len());
+    for i in 0..count {
+        let j = i + randombytes_uniform((files.len() - i) as u32) as usize;
+        files.swap(i, j);
+    }
+    files.truncate(count);
+    files
+}
+
+/// Downloads `files` into `target`, then compares each with the live file of the same path under `local_base`
+pub async fn restore_sample(
+    config: &Config,
+    backend: Arc<dyn Backend>,
+    root_path_hash: &str,
+    files: Vec<RemoteFile>,
+    target: &Path,
+    local_base: &Path,
+) -> Result<Vec<SampleResult>> {
+    let rate_limiter = RateLimiter::new(config, &backend)?;
+    let checkpoint = RestoreCheckpoint::open(target, root_path_hash)?;
+    let fsync = FsyncQueue::new(config.restore_fsync);
+    let progress = Progress::new(config.verbosity);
+    let download_progress = progress.show_progress_bar(ProgressType::Download, files.len());
+    let target_buf = target.to_owned();
+    join_all(files.iter().map(|file| {
+        action::download(
+            &rate_limiter,
+            download_progress.clone(),
+            &target_buf,
+            &checkpoint,
+            &fsync,
+            file.clone(),
+        )
+    }))
+    .await;
+    if let Err(err) = fsync.flush(&checkpoint).await {
+        download_progress.report_error(format!("Failed to sync the restored files: {}", err));
+    }
+    download_progress.finish();
+    drop(progress);
+
+    Ok(files
+        .iter()
+        .map(|file| SampleResult {
+            rel_path: file.rel_path.clone(),
+            outcome: compare(file, &target.join(&file.rel_path), &local_base.join(&file.rel_path)),
+        })
+        .collect())
+}
+
+fn compare(file: &RemoteFile, restored: &Path, local: &Path) -> SampleOutcome {
+    if fs::symlink_metadata(restored).is_err() {
+        return SampleOutcome::Failed;
+    }
+    let local_meta = match fs::symlink_metadata(local) {
+        Ok(meta) => meta,
+        Err(_) => return SampleOutcome::MissingLocally,
+    };
+    let local_mtime = local_meta
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs());
+    if local_mtime != Some(file.last_modified) {
+        return SampleOutcome::ChangedLocally;
+    }
+
+    let restored_mtime = fs::symlink_metadata(restored)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs());
+    if restored_mtime != local_mtime {
+        return SampleOutcome::Different("the restored file has another mtime".to_owned());
+    }
+    let same = if file.is_symlink {
+        match (fs::read_link(restored), fs::read_link(local)) {
+            (Ok(restored), Ok(local)) => Ok(restored == local),
+            (Err(err), _) | (_, Err(err)) => Err(err),
+        }
+    } else {
+        hash_file(restored).and_then(|restored| Ok(restored == hash_file(local)?))
+    };
+    match same {
+        Ok(true) => SampleOutcome::Identical,
+        Ok(false) if file.is_symlink => SampleOutcome::Different("the link points elsewhere".to_owned()),
+        Ok(false) => SampleOutcome::Different("the content is different".to_owned()),
+        Err(err) => SampleOutcome::Different(format!("couldn't compare: {}", err)),
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Blake2b512::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs_set_times::{set_mtime, SystemTimeSpec};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn remote_file(rel_path: &str, last_modified: u64) -> RemoteFile {
+        RemoteFile {
+            rel_path: PathBuf::from(rel_path),
+            full_path_hash: rel_path.to_owned(),
+            id: String::new(),
+            last_modified,
+            mode: 0o644,
+            is_symlink: false,
+            birthtime: None,
+            content_hash: None,
+            size: 0,
+            original_size: None,
+        }
+    }
+
+    #[test]
+    fn samples_are_compared_with_live_files() -> Result<()> {
+        let files: Vec<_> = (0..10).map(|i| remote_file(&i.to_string(), 0)).collect();
+        let sample = pick_sample(files.clone(), 3);
+        assert_eq!(sample.len(), 3);
+        assert!(sample.iter().all(|file| files.contains(file)));
+        assert_eq!(pick_sample(files, 20).len(), 10);
+
+        let (restored, local) = (tempdir()?, tempdir()?);
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        for (dir, name, content) in [
+            (&restored, "same", "a"),
+            (&local, "same", "a"),
+            (&restored, "different", "a"),
+            (&local, "different", "b"),
+            (&restored, "missing", "a"),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, content)?;
+            set_mtime(&path, SystemTimeSpec::Absolute(mtime))?;
+        }
+        let compare_file = |name| {
+            compare(
+                &remote_file(name, 1_000_000),
+                &restored.path().join(name),
+                &local.path().join(name),
+            )
+        };
+        assert_eq!(compare_file("same"), SampleOutcome::Identical);
+        assert!(compare_file("different").is_failure());
+        assert_eq!(compare_file("missing"), SampleOutcome::MissingLocally);
+        assert_eq!(compare_file("not-restored"), SampleOutcome::Failed);
+
+        set_mtime(
+            local.path().join("same"),
+            SystemTimeSpec::Absolute(mtime + Duration::from_secs(1)),
+        )?;
+        assert_eq!(compare_file("same"), SampleOutcome::ChangedLocally);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! The folder hierarchy of a backup, as recorded in its DirDB
+
+use crate::data::file::RemoteFile;
+use crate::dirdb::dirstat::DirStat;
+use base64::Engine;
+use std::collections::HashMap;
+
+/// A folder of `tree_lines`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeLine {
+    /// 0 for the backup root
+    pub depth: usize,
+    /// The folder name, or its hash if the DirDB doesn't store the name
+    pub name: String,
+    /// Number of files in the folder and all its subfolders
+    pub files_count: u64,
+    /// Stored size of the files in the folder and all its subfolders, if the sizes were listed
+    pub size: Option<u64>,
+}
+
+/// Sums the stored size of the files directly in each folder, by folder path hash relative to the root (e.g. "/<hash>/")
+pub fn direct_sizes(root_path_hash: &str, files: &[RemoteFile]) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    for file in files {
+        let rel_hash = match file.full_path_hash.strip_prefix(root_path_hash) {
+            Some(rel_hash) => rel_hash,
+            None => continue,
+        };
+        let dir_path_hash = &rel_hash[..rel_hash.rfind('/').map_or(0, |pos| pos + 1)];
+        *sizes.entry(dir_path_hash.to_owned()).or_default() += file.size;
+    }
+    sizes
+}
+
+/// Flattens the folders of a DirDB into the lines of a tree, parents first and subfolders sorted by name.
+/// Folders deeper than `max_depth` are left out, but still counted in their parent.
+pub fn tree_lines(root: &DirStat, max_depth: Option<usize>, sizes: Option<&HashMap<String, u64>>) -> Vec<TreeLine> {
+    let mut lines = Vec::new();
+    push_lines(root, 0, max_depth, &mut "/".to_owned(), sizes, &mut lines);
+    lines
+}
+
+/// Returns the size of the subtree, if the sizes are known
+fn push_lines(
+    stat: &DirStat,
+    depth: usize,
+    max_depth: Option<usize>,
+    path_hash: &mut String,
+    sizes: Option<&HashMap<String, u64>>,
+    lines: &mut Vec<TreeLine>,
+) -> Option<u64> {
+    let visible = max_depth.is_none_or(|max_depth| depth <= max_depth);
+    let line_index = lines.len();
+    if visible {
+        let name = match &stat.dir_name {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None if depth == 0 => "/".to_owned(),
+            None => format!(
+                "<{}>",
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(stat.dir_name_hash)
+            ),
+        };
+        lines.push(TreeLine {
+            depth,
+            name,
+            files_count: stat.total_files_count,
+            size: None,
+        });
+    }
+
+    let mut size = sizes.map(|sizes| sizes.get(path_hash.as_str()).copied().unwrap_or(0));
+    let mut subfolders: Vec<&DirStat> = stat.subfolders.iter().collect();
+    subfolders.sort_by(|a, b| a.dir_name.cmp(&b.dir_name));
+    for subfolder in subfolders {
+        let path_hash_len = path_hash.len();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(subfolder.dir_name_hash, path_hash);
+        path_hash.push('/');
+        let sub_size = push_lines(subfolder, depth + 1, max_depth, path_hash, sizes, lines);
+        path_hash.truncate(path_hash_len);
+        size = size.zip(sub_size).map(|(size, sub_size)| size + sub_size);
+    }
+
+    if visible {
+        lines[line_index].size = size;
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{test_backup_root, test_dirstat, test_key};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn tree_of_dirdb() {
+        let key = test_key();
+        let root = test_backup_root(&key);
+        let mut stat = test_dirstat();
+        stat.recompute_dir_name_hashes(&mut "/".to_owned(), &key);
+
+        let file = |rel_path: &str, size| RemoteFile {
+            rel_path: PathBuf::from(rel_path),
+            full_path_hash: root.file_path_hashes(Path::new(rel_path), &key).unwrap().1,
+            id: String::new(),
+            last_modified: 0,
+            mode: 0,
+            is_symlink: false,
+            birthtime: None,
+            content_hash: None,
+            size,
+            original_size: None,
+        };
+        let files = [file("a", 10), file("dir/c", 5), file("dir/d", 1)];
+        let sizes = direct_sizes(&root.path_hash, &files);
+        assert_eq!(sizes.len(), 2);
+
+        let lines = tree_lines(&stat, None, Some(&sizes));
+        assert_eq!(lines, [
+            TreeLine {
+                depth: 0,
+                name: "/".to_owned(),
+                files_count: 15,
+                size: Some(16),
+            },
+            TreeLine {
+                depth: 1,
+                name: "dir".to_owned(),
+                files_count: 5,
+                size: Some(6),
+            },
+        ]);
+
+        let lines = tree_lines(&stat, Some(0), None);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].size, None);
+    }
+}
@@ -1,15 +1,76 @@
 use crate::crypto::{decrypt, encrypt, Key};
-use eyre::Result;
-use std::path::Path;
+use crate::data::file::{RemoteFile, SkippedFile};
+use crate::data::paths::{path_from_bytes, path_to_bytes};
+use crate::data::root::dir_path_hashes;
+use blake2::{Blake2b, Digest};
+use digest::consts::U8;
+use digest::generic_array::GenericArray;
+use eyre::{bail, ensure, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 mod bitstream;
+pub mod cache;
+pub mod delta;
 pub mod diff;
 pub mod dirstat;
 pub mod filestat;
 pub mod pack;
+pub mod remote;
+pub mod scan_cache;
+mod shard;
+pub mod spill;
 
 use self::dirstat::DirStat;
 use self::filestat::FileStat;
+use self::scan_cache::ScanCache;
+use self::spill::{FileSpill, SPILL_AFTER_FILES};
+
+/// Every DirDB object starts with these magic bytes and the format version (before encryption).
+/// Objects written before the header existed don't have it, and are treated as version 0.
+const FORMAT_MAGIC: &[u8; 4] = b"FZDB";
+/// Bump this when the format of any DirDB object changes. Older DirDBs are re-uploaded when next saved.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// A decrypted DirDB object, without its header
+struct Unsealed {
+    version: u8,
+    data: Vec<u8>,
+}
+
+impl Unsealed {
+    /// We can read older formats, but a newer format could be silently mis-parsed
+    fn check_version(&self) -> Result<()> {
+        ensure!(
+            self.version <= FORMAT_VERSION,
+            "The DirDB was saved in format version {}, but this version of frozen only supports up to version {}. \
+             Please upgrade frozen.",
+            self.version,
+            FORMAT_VERSION
+        );
+        Ok(())
+    }
+}
+
+/// Adds the format header and encrypts a DirDB object
+fn seal(data: &[u8], key: &Key) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(FORMAT_MAGIC.len() + 1 + data.len());
+    plain.extend_from_slice(FORMAT_MAGIC);
+    plain.push(FORMAT_VERSION);
+    plain.extend_from_slice(data);
+    encrypt(&plain, key)
+}
+
+/// Decrypts a DirDB object and reads its header, the version still needs to be checked before parsing
+fn unseal(packed: &[u8], key: &Key) -> Result<Unsealed> {
+    let mut data = decrypt(packed, key)?;
+    if data.len() <= FORMAT_MAGIC.len() || !data.starts_with(FORMAT_MAGIC) {
+        return Ok(Unsealed { version: 0, data });
+    }
+    let version = data[FORMAT_MAGIC.len()];
+    data.drain(..FORMAT_MAGIC.len() + 1);
+    Ok(Unsealed { version, data })
+}
 
 pub struct DirDB {
     pub root: DirStat,
@@ -30,7 +91,21 @@ impl DirDB {
     }
 
     pub fn new_from_local(path: &Path, key: &Key) -> Result<Self> {
-        let mut root = DirStat::new(path, path)?;
+        Self::new_from_local_skipping(path, key, &[], &mut Vec::new(), None)
+    }
+
+    /// Scans a local folder, leaving out the files and folders that can't be read and adding them to `skipped`.
+    /// The `exclude` paths, relative to `path`, are left out as if they didn't exist.
+    /// Folders that didn't change since they were put in `cache` aren't statted again.
+    pub fn new_from_local_skipping(
+        path: &Path,
+        key: &Key,
+        exclude: &[PathBuf],
+        skipped: &mut Vec<SkippedFile>,
+        cache: Option<&mut ScanCache>,
+    ) -> Result<Self> {
+        let spill = &mut FileSpill::new(SPILL_AFTER_FILES);
+        let mut root = DirStat::new_skipping(path, path, exclude, skipped, spill, cache)?;
 
         // It'd be meaningless for the root dir to have a name relative to itself!
         root.dir_name = None;
@@ -38,20 +113,192 @@ impl DirDB {
 
         let mut path_hash_str = "/".to_string();
         root.recompute_dir_name_hashes(&mut path_hash_str, key);
+        root.check_hash_collisions(Path::new(""))?;
+
+        Ok(Self { root })
+    }
+
+    /// Scans a single folder of a backup, whose path relative to the backup root is `rel_dir`.
+    /// The root of the returned DirDB is that folder, with its name and rel_paths still relative to `base_path`.
+    pub fn new_from_local_subtree(
+        base_path: &Path,
+        rel_dir: &Path,
+        key: &Key,
+        exclude: &[PathBuf],
+        skipped: &mut Vec<SkippedFile>,
+        cache: Option<&mut ScanCache>,
+    ) -> Result<Self> {
+        let (dir_hashes, mut path_hash_str) = dir_path_hashes(rel_dir, key)?;
+        let dir_name_hash = match dir_hashes.last() {
+            Some(&hash) => hash,
+            None => bail!("Can't scan the backup root as a subtree of itself"),
+        };
+        let spill = &mut FileSpill::new(SPILL_AFTER_FILES);
+        let mut root = DirStat::new_skipping(base_path, &base_path.join(rel_dir), exclude, skipped, spill, cache)?;
+        root.dir_name_hash = dir_name_hash;
+        root.recompute_dir_name_hashes(&mut path_hash_str, key);
+        root.check_hash_collisions(rel_dir)?;
+
+        Ok(Self { root })
+    }
+
+    /// Builds a DirDB from the relative paths of the files of a backup, without scanning a local folder.
+    /// The content hashes are unknown and left cleared, so the next backup diffs every folder.
+    pub fn new_from_rel_paths<'a>(rel_paths: impl IntoIterator<Item = &'a Path>, key: &Key) -> Result<Self> {
+        let mut root = Self::new_empty().root;
+        for rel_path in rel_paths {
+            let mut node = &mut root;
+            node.total_files_count += 1;
+            for component in rel_path.parent().unwrap_or_else(|| Path::new("")).components() {
+                let dir_name = path_to_bytes(Path::new(&component))?.to_owned();
+                let index = match node
+                    .subfolders
+                    .iter()
+                    .position(|sub| sub.dir_name.as_ref() == Some(&dir_name))
+                {
+                    Some(index) => index,
+                    None => {
+                        node.subfolders.push(DirStat {
+                            dir_name: Some(dir_name),
+                            ..Default::default()
+                        });
+                        node.subfolders.len() - 1
+                    }
+                };
+                node = &mut node.subfolders[index];
+                node.total_files_count += 1;
+            }
+        }
+
+        let mut path_hash_str = "/".to_string();
+        root.recompute_dir_name_hashes(&mut path_hash_str, key);
+        root.check_hash_collisions(Path::new(""))?;
+        Ok(Self { root })
+    }
 
+    /// Builds a DirDB from the files of a backup and the metadata they were uploaded with, without scanning a local folder.
+    /// The content hashes are those a scan of the same files finds, if their mtimes are whole seconds (we only keep
+    /// the seconds). Folders with a file whose size isn't known, and their parents, get a cleared content hash.
+    pub fn new_from_remote_files(files: &[RemoteFile], key: &Key) -> Result<Self> {
+        let mut dirdb = Self::new_from_rel_paths(files.iter().map(|file| file.rel_path.as_path()), key)?;
+        let mut files_by_dir: HashMap<&Path, Vec<&RemoteFile>> = HashMap::new();
+        for file in files {
+            let dir = file.rel_path.parent().unwrap_or_else(|| Path::new(""));
+            files_by_dir.entry(dir).or_default().push(file);
+        }
+        hash_remote_content(&mut dirdb.root, Path::new(""), &files_by_dir);
+        Ok(dirdb)
+    }
+
+    /// Finds a folder from the dir name hashes leading to it
+    pub fn find(&self, dir_hashes: &[[u8; 8]]) -> Option<&DirStat> {
+        shard::find_node(&self.root, dir_hashes)
+    }
+
+    /// Returns a copy of this DirDB with the folder at `rel_dir` replaced by `subtree`, creating missing parent folders.
+    /// We don't know the content of the parents' other files, so their content hash is cleared,
+    /// which makes the next full backup diff them again.
+    pub fn grafted(&self, rel_dir: &Path, key: &Key, subtree: DirStat) -> Result<Self> {
+        let (dir_hashes, _) = dir_path_hashes(rel_dir, key)?;
+        let dir_names = rel_dir
+            .components()
+            .map(|name| Ok(path_to_bytes(Path::new(&name))?.to_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        if dir_hashes.is_empty() {
+            bail!("Can't graft a subtree over the backup root");
+        }
+        let mut root = self.root.clone();
+        graft_into(&mut root, &dir_hashes, &dir_names, subtree);
         Ok(Self { root })
     }
 
     pub fn new_from_packed(packed: &[u8], key: &Key) -> Result<Self> {
-        let decrypted = decrypt(packed, key)?;
+        let unsealed = unseal(packed, key)?;
+        unsealed.check_version()?;
+        Self::new_from_unsealed(&unsealed, key)
+    }
+
+    fn new_from_unsealed(unsealed: &Unsealed, key: &Key) -> Result<Self> {
+        // Versions 0 and 1 only differ by the header
         Ok(Self {
-            root: DirStat::new_from_bytes(&mut decrypted.as_slice(), key)?,
+            root: DirStat::new_from_bytes(&mut unsealed.data.as_slice(), key)?,
         })
     }
 
     pub fn to_packed(&self, key: &Key) -> Result<Vec<u8>> {
         let mut packed_plain = Vec::new();
         self.root.serialize_into(&mut packed_plain)?;
-        Ok(encrypt(&packed_plain, key))
+        Ok(seal(&packed_plain, key))
     }
 }
+
+/// Hashes the content of a folder like `DirStat::new_skipping`, which goes through files and subfolders by name
+fn hash_remote_content(node: &mut DirStat, rel_dir: &Path, files_by_dir: &HashMap<&Path, Vec<&RemoteFile>>) {
+    enum Entry<'a> {
+        File(&'a RemoteFile),
+        Folder(usize),
+    }
+    let mut entries = Vec::new();
+    for file in files_by_dir.get(rel_dir).into_iter().flatten() {
+        entries.push((file.rel_path.clone(), Entry::File(file)));
+    }
+    for (index, subfolder) in node.subfolders.iter().enumerate() {
+        let name = path_from_bytes(subfolder.dir_name.as_deref().unwrap_or_default()).unwrap_or(Path::new(""));
+        entries.push((rel_dir.join(name), Entry::Folder(index)));
+    }
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = Blake2b::<U8>::new();
+    let mut known = true;
+    for (rel_path, entry) in entries {
+        hasher.update(path_to_bytes(&rel_path).unwrap());
+        match entry {
+            Entry::File(file) => {
+                hasher.update(file.last_modified.to_le_bytes());
+                hasher.update(0u32.to_le_bytes());
+                match file.original_size {
+                    Some(size) => hasher.update(size.to_le_bytes()),
+                    None => known = false,
+                }
+            }
+            Entry::Folder(index) => {
+                let subfolder = &mut node.subfolders[index];
+                hash_remote_content(subfolder, &rel_path, files_by_dir);
+                known &= subfolder.content_hash != [0; 8];
+                hasher.update(subfolder.content_hash);
+            }
+        }
+    }
+    node.content_hash = [0; 8];
+    if known {
+        hasher.finalize_into(GenericArray::from_mut_slice(&mut node.content_hash));
+    }
+}
+
+fn graft_into(node: &mut DirStat, dir_hashes: &[[u8; 8]], dir_names: &[Vec<u8>], subtree: DirStat) {
+    let direct_files_count = node.compute_direct_files_count();
+    let index = match node
+        .subfolders
+        .iter()
+        .position(|sub| sub.dir_name_hash == dir_hashes[0])
+    {
+        Some(index) => index,
+        None => {
+            node.subfolders.push(DirStat {
+                dir_name: Some(dir_names[0].clone()),
+                dir_name_hash: dir_hashes[0],
+                ..Default::default()
+            });
+            node.subfolders.len() - 1
+        }
+    };
+    if dir_hashes.len() == 1 {
+        node.subfolders[index] = subtree;
+    } else {
+        graft_into(&mut node.subfolders[index], &dir_hashes[1..], &dir_names[1..], subtree);
+    }
+
+    let subfolders_files_count: u64 = node.subfolders.iter().map(|sub| sub.total_files_count).sum();
+    node.total_files_count = direct_files_count + subfolders_files_count;
+    node.content_hash = [0; 8];
+}
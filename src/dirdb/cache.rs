@@ -0,0 +1,84 @@
+//! Fetches the DirDBs of many backup roots at once, for commands that look at every root
+
+use super::remote::RemoteDirDB;
+use crate::net::backend::Backend;
+use eyre::Result;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// How many DirDBs we download at the same time by default
+pub const DIRDB_FETCH_CONCURRENCY: usize = 8;
+
+/// Keeps the DirDBs fetched during a run, by root path hash, so each one is only downloaded and decoded once.
+/// The DirDBs are fetched without their shards, see `RemoteDirDB::fetch`.
+pub struct DirDBCache {
+    concurrency: usize,
+    fetched: Mutex<HashMap<String, Arc<RemoteDirDB>>>,
+}
+
+impl Default for DirDBCache {
+    fn default() -> Self {
+        Self::new(DIRDB_FETCH_CONCURRENCY)
+    }
+}
+
+impl DirDBCache {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the DirDB of a root, fetching it unless it was already fetched during this run
+    pub async fn fetch(&self, backend: &dyn Backend, root_path_hash: &str) -> Result<Arc<RemoteDirDB>> {
+        if let Some(remote) = self.fetched.lock().unwrap().get(root_path_hash) {
+            return Ok(remote.clone());
+        }
+        let remote = Arc::new(RemoteDirDB::fetch(backend, root_path_hash).await?);
+        let mut fetched = self.fetched.lock().unwrap();
+        // Another fetch of the same root may have finished first, everyone gets the same DirDB
+        Ok(fetched.entry(root_path_hash.to_owned()).or_insert(remote).clone())
+    }
+
+    /// Fetches the DirDBs of many roots, each from its own backend, with at most `concurrency` downloads at a time.
+    /// The results are in the same order as `roots`. Failed fetches aren't cached, they're tried again next time.
+    pub async fn fetch_all(&self, roots: &[(&dyn Backend, &str)]) -> Vec<Result<Arc<RemoteDirDB>>> {
+        stream::iter(roots.iter())
+            .map(|&(backend, root_path_hash)| self.fetch(backend, root_path_hash))
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirdb::DirDB;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::{test_dirstat, test_key};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetches_each_root_once() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dirdb = DirDB { root: test_dirstat() };
+        RemoteDirDB::fetch(&backend, "a").await?.save(&backend, &dirdb).await?;
+
+        let cache = DirDBCache::new(2);
+        let roots: Vec<(&dyn Backend, &str)> = vec![(&backend, "a"), (&backend, "b"), (&backend, "a")];
+        let fetched = cache.fetch_all(&roots).await;
+        assert_eq!(fetched.len(), 3);
+        let (a, b) = (fetched[0].as_ref().unwrap(), fetched[1].as_ref().unwrap());
+        assert_eq!(a.dirdb.as_ref().unwrap().root.total_files_count, 15);
+        assert!(b.dirdb.is_none());
+        assert!(Arc::ptr_eq(a, fetched[2].as_ref().unwrap()));
+
+        // Later changes aren't seen during the same run
+        RemoteDirDB::hide(&backend, "a").await?;
+        assert!(cache.fetch(&backend, "a").await?.dirdb.is_some());
+        assert!(DirDBCache::default().fetch(&backend, "a").await?.dirdb.is_none());
+        Ok(())
+    }
+}
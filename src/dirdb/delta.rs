@@ -0,0 +1,179 @@
+//! Incremental DirDB updates
+//! A delta with only the folders that changed since the last full DirDB is stored next to it with a ".delta" suffix.
+//! Each delta replaces the previous one and is relative to the full DirDB, until it grows too big or too old and
+//! we upload a new full DirDB instead (compaction, see `remote`).
+
+use super::{seal, DirStat, Unsealed};
+use crate::crypto::Key;
+use eyre::{ensure, eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct DeltaNode {
+    dir_name_hash: [u8; 8],
+    content_hash: [u8; 8],
+    /// None when the folder is unchanged from the base DirDB, and should be copied from it
+    changed: Option<ChangedDir>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChangedDir {
+    dir_name: Option<Vec<u8>>,
+    total_files_count: u64,
+    subfolders: Vec<DeltaNode>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct DirDBDelta {
+    /// Hash of the packed full DirDB that this delta applies to
+    pub base_hash: [u8; 8],
+    pub updates_since_full: u32,
+    pub root: DeltaNode,
+}
+
+impl DeltaNode {
+    pub fn new(stat: &DirStat, base: Option<&DirStat>) -> Self {
+        if let Some(base) = base {
+            // Pessimized folders have a null hash, they never match anything
+            if base.content_hash == stat.content_hash && stat.content_hash != [0; 8] {
+                return DeltaNode {
+                    dir_name_hash: stat.dir_name_hash,
+                    content_hash: stat.content_hash,
+                    changed: None,
+                };
+            }
+        }
+
+        let base_subfolders: HashMap<&[u8; 8], &DirStat> = base
+            .map(|base| base.subfolders.iter().map(|sub| (&sub.dir_name_hash, sub)).collect())
+            .unwrap_or_default();
+        let subfolders = stat
+            .subfolders
+            .iter()
+            .map(|sub| DeltaNode::new(sub, base_subfolders.get(&sub.dir_name_hash).copied()))
+            .collect();
+
+        DeltaNode {
+            dir_name_hash: stat.dir_name_hash,
+            content_hash: stat.content_hash,
+            changed: Some(ChangedDir {
+                dir_name: stat.dir_name.clone(),
+                total_files_count: stat.total_files_count,
+                subfolders,
+            }),
+        }
+    }
+
+    pub fn apply(self, base: Option<DirStat>) -> Result<DirStat> {
+        let changed = match self.changed {
+            Some(changed) => changed,
+            None => {
+                let base = base.ok_or_else(|| eyre!("DirDB delta refers to a folder missing from its base"))?;
+                ensure!(
+                    base.content_hash == self.content_hash,
+                    "DirDB delta refers to a folder that doesn't match its base"
+                );
+                return Ok(base);
+            }
+        };
+
+        let (base_dir_name, mut base_subfolders) = match base {
+            Some(base) => {
+                let subfolders: HashMap<[u8; 8], DirStat> = base
+                    .subfolders
+                    .into_iter()
+                    .map(|sub| (sub.dir_name_hash, sub))
+                    .collect();
+                (base.dir_name, subfolders)
+            }
+            None => (None, HashMap::new()),
+        };
+        let subfolders = changed
+            .subfolders
+            .into_iter()
+            .map(|sub| {
+                let base_sub = base_subfolders.remove(&sub.dir_name_hash);
+                sub.apply(base_sub)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(DirStat {
+            total_files_count: changed.total_files_count,
+            direct_files: None,
+            subfolders,
+            dir_name: changed.dir_name.or(base_dir_name),
+            dir_name_hash: self.dir_name_hash,
+            content_hash: self.content_hash,
+        })
+    }
+}
+
+impl DirDBDelta {
+    pub fn to_packed(&self, key: &Key) -> Result<Vec<u8>> {
+        let serialized = bincode::serialize(self)?;
+        let compressed = zstd::encode_all(serialized.as_slice(), 19)?;
+        Ok(seal(&compressed, key))
+    }
+
+    pub fn new_from_unsealed(unsealed: &Unsealed) -> Result<Self> {
+        let serialized = zstd::decode_all(unsealed.data.as_slice())?;
+        Ok(bincode::deserialize(&serialized)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirdb::DirDB;
+    use crate::test_helpers::test_key;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_tree(base: &Path, files: &[&str]) {
+        for file in files {
+            let path = base.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn delta_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let files = ["a/1", "a/b/2", "c/3", "c/d/4", "e/5"];
+        write_tree(dir.path(), &files);
+        let base = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        fs::write(dir.path().join("c/d/4"), "changed content")?;
+        fs::remove_dir_all(dir.path().join("e"))?;
+        write_tree(dir.path(), &["f/g/6"]);
+        let new = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let delta = DeltaNode::new(&new.root, Some(&base.root));
+        let changed = delta.changed.as_ref().unwrap();
+        let unchanged_a = changed
+            .subfolders
+            .iter()
+            .find(|sub| sub.dir_name_hash == base.root.subfolders[0].dir_name_hash);
+        assert!(unchanged_a.unwrap().changed.is_none());
+
+        let applied = delta.apply(Some(base.root.clone()))?;
+        assert!(applied == new.root);
+        Ok(())
+    }
+
+    #[test]
+    fn delta_needs_matching_base() -> Result<()> {
+        let dir = tempdir()?;
+        write_tree(dir.path(), &["a/1", "b/2"]);
+        let base = DirDB::new_from_local(dir.path(), &test_key())?;
+        fs::write(dir.path().join("b/2"), "changed content")?;
+        let new = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let delta = DeltaNode::new(&new.root, Some(&base.root));
+        assert!(delta.apply(None).is_err());
+        Ok(())
+    }
+}
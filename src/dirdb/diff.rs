@@ -1,13 +1,15 @@
 use self::files::FileDiffStream;
 use super::{DirDB, DirStat};
-use crate::crypto::Key;
 use crate::data::root::BackupRoot;
-use crate::net::b2::B2;
-use eyre::Result;
+use crate::net::rate_limiter::RateLimiter;
+use eyre::{bail, Result};
 use futures::stream::{SelectAll, Stream, StreamExt};
 use futures::task::Poll;
 use owning_ref::ArcRef;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::Context;
 
@@ -15,31 +17,183 @@ mod dirs;
 mod files;
 pub use files::FileDiff;
 
+/// Which folders are listed in a single deep request, rather than one shallow request each
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiffStrategy {
+    /// Picks the fewest requests, estimated from the file counts of the DirDBs
+    #[default]
+    Auto,
+    /// Lists everything in one deep request
+    AlwaysDeep,
+    /// Lists each changed folder on its own
+    AlwaysShallow,
+}
+
+impl DiffStrategy {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(DiffStrategy::Auto),
+            "always-deep" => Ok(DiffStrategy::AlwaysDeep),
+            "always-shallow" => Ok(DiffStrategy::AlwaysShallow),
+            other => bail!(
+                "Invalid diff strategy \"{}\" (use auto, always-deep or always-shallow)",
+                other
+            ),
+        }
+    }
+}
+
+/// What a diff cost, to compare with what the DirDBs let us expect.
+/// Requests are counted like B2 bills them, one per 1000 listed files.
+#[derive(Default, Debug)]
+pub struct DiffStats {
+    /// List requests the diff was expected to take
+    pub planned_requests: AtomicU64,
+    /// List requests it would have taken without merging folders into deep listings
+    pub unmerged_requests: AtomicU64,
+    /// List requests actually made, from the number of files each listing returned
+    pub list_requests: AtomicU64,
+    /// Folders that were listed on the remote
+    pub listings: AtomicU64,
+    pub remote_files_compared: AtomicU64,
+    pub local_files_compared: AtomicU64,
+}
+
+impl DiffStats {
+    pub(super) fn add(counter: &AtomicU64, count: u64) {
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+impl Display for DiffStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (planned, unmerged) = (get(&self.planned_requests), get(&self.unmerged_requests));
+        write!(
+            f,
+            "Diffed {} remote and {} local files, with {} list requests in {} listings (expected {}, {} saved by merging folders)",
+            get(&self.remote_files_compared),
+            get(&self.local_files_compared),
+            get(&self.list_requests),
+            get(&self.listings),
+            planned,
+            unmerged.saturating_sub(planned),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingKind {
+    /// The folder and all of its subfolders in one request
+    Deep,
+    /// Only the files directly in the folder, its changed subfolders have their own listings
+    Shallow,
+    /// Nothing to list, the folder isn't on the remote
+    LocalOnly,
+}
+
+/// How the diff lists one folder, decided from the DirDBs (see `DiffStrategy`)
+#[derive(Clone, Debug)]
+pub struct DiffListing {
+    /// The folder, relative to the diffed folder. Folders only on the remote are named by the hash of their name.
+    pub rel_path: PathBuf,
+    pub kind: ListingKind,
+    /// The files the DirDBs say the listing compares
+    pub remote_files: u64,
+    pub local_files: u64,
+}
+
+impl Display for DiffListing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let path = Path::new("/").join(&self.rel_path);
+        match self.kind {
+            ListingKind::Deep => write!(f, "Listing {} and its subfolders", path.display())?,
+            ListingKind::Shallow => write!(f, "Listing the files directly in {}", path.display())?,
+            ListingKind::LocalOnly => {
+                return write!(
+                    f,
+                    "Not listing {}, it's new ({} local files)",
+                    path.display(),
+                    self.local_files
+                )
+            }
+        }
+        write!(
+            f,
+            " ({} remote and {} local files)",
+            self.remote_files, self.local_files
+        )
+    }
+}
+
 /// Use this struct to start diffing folders and to receive `FileDiff`s
 pub struct DirDiff {
     diff_stream: SelectAll<FileDiffStream>,
+    stats: Arc<DiffStats>,
+    listings: Vec<DiffListing>,
     pessimistic_dirdb: DirDB,
 }
 
 impl DirDiff {
-    pub fn new(root: Arc<BackupRoot>, b2: Arc<B2>, local: Arc<DirDB>, remote: &Option<DirDB>) -> Result<DirDiff> {
+    pub fn new(
+        root: Arc<BackupRoot>,
+        rate_limiter: Arc<RateLimiter>,
+        local: Arc<DirDB>,
+        remote: &Option<DirDB>,
+        strategy: DiffStrategy,
+    ) -> Result<DirDiff> {
         let empty_remote = DirDB::new_empty();
         let remote = remote.as_ref().unwrap_or(&empty_remote);
+        Self::new_at(root, rate_limiter, local, &remote.root, "/".to_owned(), strategy)
+    }
+
+    /// Diffs a single folder of the backup, whose files are under `prefix_path_hash` (e.g. "/<hash>/<hash>/").
+    /// The pessimistic DirDB then only has that folder as root.
+    pub fn new_at(
+        root: Arc<BackupRoot>,
+        rate_limiter: Arc<RateLimiter>,
+        local: Arc<DirDB>,
+        remote: &DirStat,
+        prefix_path_hash: String,
+        strategy: DiffStrategy,
+    ) -> Result<DirDiff> {
         let pessimistic_dirdb = DirDB {
-            root: dirs::merge_dirstats_pessimistic(&local.root, &remote.root),
+            root: dirs::merge_dirstats_pessimistic(&local.root, remote),
         };
 
         let local = ArcRef::new(local).map(|db| &db.root);
-        let diff_stream = dirs::diff_dirs(root, b2, local, &remote.root);
+        let stats = Arc::new(DiffStats::default());
+        let (diff_stream, listings) = dirs::diff_dirs(
+            root,
+            rate_limiter,
+            local,
+            remote,
+            prefix_path_hash,
+            strategy,
+            stats.clone(),
+        );
 
         Ok(DirDiff {
             diff_stream,
+            stats,
+            listings,
             pessimistic_dirdb,
         })
     }
 
-    pub fn get_pessimistic_dirdb_data(&self, key: &Key) -> Result<Vec<u8>> {
-        self.pessimistic_dirdb.to_packed(key)
+    /// Counters of the diff so far, complete once the stream ends
+    pub fn stats(&self) -> &DiffStats {
+        &self.stats
+    }
+
+    /// The listings the diff makes, in the order they're started
+    pub fn listings(&self) -> &[DiffListing] {
+        &self.listings
+    }
+
+    /// A DirDB that is safe to save on the remote while the backup runs, see `merge_dirstats_pessimistic`
+    pub fn pessimistic_dirdb(&self) -> &DirDB {
+        &self.pessimistic_dirdb
     }
 }
 
@@ -1,37 +1,51 @@
-use super::{DirStat, FileDiffStream};
+use super::{DiffListing, DiffStats, DiffStrategy, DirStat, FileDiffStream, ListingKind};
 use crate::data::root::BackupRoot;
 use crate::dirdb::DirDB;
-use crate::net::b2::B2;
+use crate::net::rate_limiter::RateLimiter;
 use base64::Engine;
 use futures::stream::SelectAll;
 use owning_ref::ArcRef;
 use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::sync::Arc;
 
-struct DiffTree {
+pub(super) struct DiffTree {
     children: Vec<DiffTree>,
     local: Option<ArcRef<DirDB, DirStat>>,
     prefix_path_hash: String,
     total_files_count: u64,  // How many (remote) files we can expect a deep list request to return
     direct_files_count: u64, // How many (remote) files a shallow list request is expected to return
+    local_total_files_count: u64, // How many local files a deep diff compares, and may upload
+    local_direct_files_count: u64, // How many local files a shallow diff compares, and may upload
     deep_diff: bool,         // If false, we do a shallow list request of just the folder's direct files
     local_only: bool,        // If true, the folder doesn't exist on the remote
 }
 
-fn optimized_diff_tree(local: ArcRef<DirDB, DirStat>, remote: &DirStat) -> Option<DiffTree> {
-    let mut prefix_path_hash = "/".to_owned();
-
+fn optimized_diff_tree(
+    local: ArcRef<DirDB, DirStat>,
+    remote: &DirStat,
+    mut prefix_path_hash: String,
+    strategy: DiffStrategy,
+    stats: &DiffStats,
+) -> Option<DiffTree> {
     // When the remote DB is empty/missing, or pessimized and with no folders, deep-diff everything
-    // Normally we deep-diff when a remote folder is missing locally, but this is the root folder,
+    // Normally we deep-diff when a remote folder is missing locally, but this is the top folder of the diff,
     // and, a root with no subdirs is just as fast to deep-diff as shallow-diff, so we don't lose
     // any performance by sharing the same encoding for "no subdirs at all" and "no dirdb at all"
     if remote.content_hash == [0u8; 8] && remote.subfolders.is_empty() {
+        DiffStats::add(&stats.planned_requests, 1);
+        DiffStats::add(&stats.unmerged_requests, 1);
+        let local_total_files_count = local.total_files_count;
         return Some(DiffTree {
             children: vec![],
             local: Some(local),
             prefix_path_hash,
             direct_files_count: 0,
             total_files_count: 0,
+            local_total_files_count,
+            local_direct_files_count: 0,
             deep_diff: true,
             local_only: false,
         });
@@ -39,25 +53,46 @@ fn optimized_diff_tree(local: ArcRef<DirDB, DirStat>, remote: &DirStat) -> Optio
 
     let tree = DiffTree::new(&mut prefix_path_hash, &local, remote);
     tree.map(|mut tree| {
-        tree.optimize();
+        DiffStats::add(&stats.unmerged_requests, tree.cost());
+        match strategy {
+            DiffStrategy::Auto => tree.optimize(),
+            DiffStrategy::AlwaysDeep => {
+                tree.deep_diff = true;
+                tree.children.clear();
+            }
+            // Folders missing locally are still deep-diffed, their subfolders aren't in the tree
+            DiffStrategy::AlwaysShallow => (),
+        }
+        DiffStats::add(&stats.planned_requests, tree.cost());
         tree
     })
 }
 
 pub fn diff_dirs(
     root: Arc<BackupRoot>,
-    b2: Arc<B2>,
+    rate_limiter: Arc<RateLimiter>,
     local: ArcRef<DirDB, DirStat>,
     remote: &DirStat,
-) -> SelectAll<FileDiffStream> {
+    prefix_path_hash: String,
+    strategy: DiffStrategy,
+    stats: Arc<DiffStats>,
+) -> (SelectAll<FileDiffStream>, Vec<DiffListing>) {
     let mut diff_streams = SelectAll::new();
-    let diff_tree = match optimized_diff_tree(local, remote) {
-        None => return diff_streams, // If nothing changed, we can take the fast way out
+    let mut listings = Vec::new();
+    let diff_tree = match optimized_diff_tree(local, remote, prefix_path_hash, strategy, &stats) {
+        None => return (diff_streams, listings), // If nothing changed, we can take the fast way out
         Some(t) => t,
     };
 
-    diff_tree.into_diff_streams(root, b2, &mut diff_streams);
-    diff_streams
+    diff_tree.into_diff_streams(
+        root,
+        rate_limiter,
+        &stats,
+        Path::new(""),
+        &mut diff_streams,
+        &mut listings,
+    );
+    (diff_streams, listings)
 }
 
 impl DiffTree {
@@ -75,6 +110,8 @@ impl DiffTree {
             prefix_path_hash: prefix_path_hash.clone(),
             total_files_count: remote.total_files_count,
             direct_files_count: remote.total_files_count, // Updated in loop below
+            local_total_files_count: local.total_files_count,
+            local_direct_files_count: local.compute_direct_files_count(),
             deep_diff: false,
             local_only: false,
         };
@@ -111,6 +148,8 @@ impl DiffTree {
                         prefix_path_hash: prefix_path_hash.clone(),
                         total_files_count: remote_subdir.total_files_count,
                         direct_files_count: remote_subdir.total_files_count, // Largely meaningless!
+                        local_total_files_count: 0,
+                        local_direct_files_count: 0,
                         deep_diff: true, // Could have subfolders, but they're not gonna be in the tree
                         local_only: false,
                     });
@@ -124,12 +163,15 @@ impl DiffTree {
                 .encode_string(local_only_subdir.dir_name_hash, prefix_path_hash);
             prefix_path_hash.push('/');
 
+            let local_files_count = local_only_subdir.total_files_count;
             tree.children.push(DiffTree {
                 children: Vec::new(),
                 local: Some(local_only_subdir),
                 prefix_path_hash: prefix_path_hash.clone(),
                 total_files_count: 0, // Nothing to list if the remote folder doesn't exist
                 direct_files_count: 0,
+                local_total_files_count: local_files_count,
+                local_direct_files_count: local_files_count,
                 deep_diff: false,
                 local_only: true,
             });
@@ -139,14 +181,25 @@ impl DiffTree {
     }
 
     /// How many requests it costs to list this many files
-    fn files_count_to_request_cost(files_count: u64) -> u64 {
+    pub(super) fn files_count_to_request_cost(files_count: u64) -> u64 {
         const MAX_FILES_PER_REQUEST: u64 = 1000;
 
         // Division rounding up (with x86 in mind, which has fast division+remainder)
         let div = files_count / MAX_FILES_PER_REQUEST;
         let rem = files_count % MAX_FILES_PER_REQUEST;
         // NOTE: We don't eliminate requests with 0 expected remote files at the moment
-        if rem != 0 || files_count == 0 { div + 1 } else { div }
+        if rem != 0 || files_count == 0 {
+            div + 1
+        } else {
+            div
+        }
+    }
+
+    /// The cost of diffing these files in a single stream.
+    /// New local files cost like remote files: a stream has to compare and queue them all at once,
+    /// and once uploaded they're listed by the next backups.
+    fn diff_cost(remote_files_count: u64, local_files_count: u64) -> u64 {
+        Self::files_count_to_request_cost(remote_files_count.max(local_files_count))
     }
 
     /// Optimizes how many requests are needed to diff this tree, and returns the number
@@ -157,7 +210,7 @@ impl DiffTree {
             return 0;
         }
 
-        let merged_diff_cost = Self::files_count_to_request_cost(self.total_files_count);
+        let merged_diff_cost = Self::diff_cost(self.total_files_count, self.local_total_files_count);
         let mut separate_diff_cost = 0;
         for subtree in self.children.iter_mut() {
             separate_diff_cost += subtree.optimize_with_costs();
@@ -168,7 +221,7 @@ impl DiffTree {
             debug_assert_eq!(separate_diff_cost, 0);
             return merged_diff_cost; // A smart compiler would move that before the loop...
         }
-        separate_diff_cost += Self::files_count_to_request_cost(self.direct_files_count);
+        separate_diff_cost += Self::diff_cost(self.direct_files_count, self.local_direct_files_count);
 
         if merged_diff_cost < separate_diff_cost {
             self.deep_diff = true;
@@ -183,24 +236,89 @@ impl DiffTree {
         self.optimize_with_costs();
     }
 
-    pub fn into_diff_streams(self, root: Arc<BackupRoot>, b2: Arc<B2>, diff_streams: &mut SelectAll<FileDiffStream>) {
+    /// How many requests it costs to diff this tree as it is
+    fn cost(&self) -> u64 {
+        if self.local_only {
+            0
+        } else if self.deep_diff {
+            Self::diff_cost(self.total_files_count, self.local_total_files_count)
+        } else {
+            let direct_cost = Self::diff_cost(self.direct_files_count, self.local_direct_files_count);
+            direct_cost + self.children.iter().map(DiffTree::cost).sum::<u64>()
+        }
+    }
+
+    /// The name of the folder, or the hash of its name if it's only on the remote
+    fn dir_name(&self) -> OsString {
+        match self.local.as_ref().and_then(|local| local.dir_name.as_ref()) {
+            Some(name) => OsStr::from_bytes(name).to_owned(),
+            None => {
+                let name_hash = self.prefix_path_hash.trim_end_matches('/').rsplit('/').next();
+                OsString::from(name_hash.unwrap_or_default())
+            }
+        }
+    }
+
+    /// Starts a stream per listing of the tree, and describes each listing in `listings`
+    pub fn into_diff_streams(
+        self,
+        root: Arc<BackupRoot>,
+        rate_limiter: Arc<RateLimiter>,
+        stats: &Arc<DiffStats>,
+        rel_path: &Path,
+        diff_streams: &mut SelectAll<FileDiffStream>,
+        listings: &mut Vec<DiffListing>,
+    ) {
+        listings.push(match (self.local_only, self.deep_diff) {
+            (true, _) => DiffListing {
+                rel_path: rel_path.to_owned(),
+                kind: ListingKind::LocalOnly,
+                remote_files: 0,
+                local_files: self.local_total_files_count,
+            },
+            (false, true) => DiffListing {
+                rel_path: rel_path.to_owned(),
+                kind: ListingKind::Deep,
+                remote_files: self.total_files_count,
+                local_files: self.local_total_files_count,
+            },
+            (false, false) => DiffListing {
+                rel_path: rel_path.to_owned(),
+                kind: ListingKind::Shallow,
+                remote_files: self.direct_files_count,
+                local_files: self.local_direct_files_count,
+            },
+        });
         let stream = match (self.local, self.local_only) {
             (local, false) => FileDiffStream::new(
                 root.clone(),
-                b2.clone(),
+                rate_limiter.clone(),
                 self.prefix_path_hash.clone(),
                 local,
                 self.deep_diff,
+                stats.clone(),
+            ),
+            (Some(local), true) => FileDiffStream::new_local(
+                root.clone(),
+                self.prefix_path_hash.clone(),
+                local,
+                rate_limiter.backend().key(),
+                stats,
             ),
-            (Some(local), true) => {
-                FileDiffStream::new_local(root.clone(), self.prefix_path_hash.clone(), local, &b2.key)
-            }
             (None, true) => unreachable!("We can't have a local-only folder without a local DirStat!"),
         };
         diff_streams.push(stream);
 
         for child in self.children.into_iter() {
-            child.into_diff_streams(root.clone(), b2.clone(), diff_streams);
+            let child_path = rel_path.join(child.dir_name());
+            child.into_diff_streams(
+                root.clone(),
+                rate_limiter.clone(),
+                stats,
+                &child_path,
+                diff_streams,
+                listings,
+            );
         }
     }
 }
@@ -273,10 +391,15 @@ fn pessimize_dirstat(dirstat: &DirStat) -> DirStat {
 
 #[cfg(test)]
 mod test {
+    use crate::config::Config;
     use crate::dirdb::diff::dirs::{diff_dirs, optimized_diff_tree, DiffTree};
+    use crate::dirdb::diff::{DiffStats, DiffStrategy, ListingKind};
     use crate::dirdb::DirDB;
+    use crate::net::backend::Backend;
+    use crate::net::rate_limiter::RateLimiter;
     use crate::test_helpers::*;
     use owning_ref::ArcRef;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
 
     impl DiffTree {
@@ -289,6 +412,8 @@ mod test {
                 prefix_path_hash: "/".to_string(),
                 total_files_count: indirect_files + direct_files,
                 direct_files_count: direct_files,
+                local_total_files_count: 0,
+                local_direct_files_count: 0,
                 deep_diff: false,
                 local_only: false,
             }
@@ -302,6 +427,8 @@ mod test {
                 prefix_path_hash: "/".to_string(),
                 total_files_count: total_files,
                 direct_files_count: total_files,
+                local_total_files_count: 0,
+                local_direct_files_count: 0,
                 deep_diff: true,
                 local_only: false,
             }
@@ -318,6 +445,8 @@ mod test {
                     prefix_path_hash: "".to_string(),
                     total_files_count: subfolder_files_count,
                     direct_files_count: subfolder_files_count,
+                    local_total_files_count: 0,
+                    local_direct_files_count: 0,
                     deep_diff: false,
                     local_only: false,
                 };
@@ -327,6 +456,7 @@ mod test {
 
         fn move_to_parent(self, parent: &mut DiffTree) {
             parent.total_files_count += self.total_files_count;
+            parent.local_total_files_count += self.local_total_files_count;
             parent.children.push(self);
         }
 
@@ -342,15 +472,35 @@ mod test {
     fn empty_remote_dirdb() {
         // If there's no remote DirDB (or invalid/empty), we must diff everything
         let key = test_key();
-        let b2 = Arc::new(test_b2(key.clone()));
+        let b2: Arc<dyn Backend> = Arc::new(test_b2(key.clone()));
+        let rate_limiter = Arc::new(RateLimiter::new(&Config::default(), &b2).unwrap());
         let root = Arc::new(test_backup_root(&key));
         let local = ArcRef::new(Arc::new(test_dirdb())).map(|d| &d.root);
         let remote = DirDB::new_empty();
 
-        let streams = diff_dirs(root, b2, local.clone(), &remote.root);
+        let stats = Arc::new(DiffStats::default());
+        let (streams, listings) = diff_dirs(
+            root,
+            rate_limiter,
+            local.clone(),
+            &remote.root,
+            "/".to_owned(),
+            DiffStrategy::Auto,
+            stats.clone(),
+        );
         assert_eq!(streams.len(), 1); // Exactly one diff stream: everything
-
-        let tree = optimized_diff_tree(local, &remote.root).unwrap();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].kind, ListingKind::Deep);
+        assert_eq!(
+            listings[0].to_string(),
+            format!(
+                "Listing / and its subfolders (0 remote and {} local files)",
+                local.total_files_count
+            )
+        );
+        assert_eq!(stats.planned_requests.load(Ordering::Relaxed), 1);
+
+        let tree = optimized_diff_tree(local, &remote.root, "/".to_owned(), DiffStrategy::Auto, &stats).unwrap();
         assert!(tree.children.is_empty());
         assert!(tree.prefix_path_hash == "/");
         assert!(tree.deep_diff);
@@ -365,7 +515,10 @@ mod test {
         let mut tree = tree.wrap_in_new_parent(50);
         tree.add_subfolders(3, || 150);
         let mut root = tree.wrap_in_new_parent(0);
+        let unmerged_cost = root.cost();
         let cost = root.optimize_with_costs();
+        assert!(unmerged_cost > cost);
+        assert_eq!(root.cost(), cost);
 
         // We expect a single deep-diff request at the root
         let expected_cost = DiffTree::files_count_to_request_cost(root.total_files_count);
@@ -466,6 +619,23 @@ mod test {
         assert!(root.children.is_empty());
     }
 
+    #[test]
+    fn large_local_only_folder_prevents_merge() {
+        // Merging would be a single request if we only counted the remote files,
+        // but the new local folder makes the merged diff compare (and later list) all of its files
+        let mut root = DiffTree::new_without_subdirs(0, 10);
+        DiffTree::new_without_subdirs(0, 10).move_to_parent(&mut root);
+        let mut local_only = DiffTree::new_without_subdirs(0, 0);
+        local_only.local_only = true;
+        local_only.local_total_files_count = 50_000;
+        local_only.move_to_parent(&mut root);
+        let cost = root.optimize_with_costs();
+
+        assert_eq!(cost, 2);
+        assert!(!root.deep_diff);
+        assert_eq!(root.children.len(), 2);
+    }
+
     #[test]
     fn very_large_shallow_diff_doesnt_merge_up() {
         let tree = DiffTree::new_without_subdirs(99999, 1);
@@ -1,11 +1,14 @@
-use super::{DirDB, DirStat};
+use super::dirs::DiffTree;
+use super::{DiffStats, DirDB, DirStat};
 use crate::crypto;
 use crate::data::file::{LocalFile, RemoteFile};
 use crate::data::paths::filename_to_bytes;
 use crate::data::root::BackupRoot;
-use crate::net::b2::{FileListDepth, B2};
+use crate::dirdb::dirstat::collision_message;
+use crate::net::backend::FileListDepth;
+use crate::net::rate_limiter::RateLimiter;
 use base64::Engine;
-use eyre::Result;
+use eyre::{bail, Result};
 use futures::future::{FutureExt, LocalBoxFuture};
 use futures::stream::{LocalBoxStream, Stream, StreamExt};
 use futures::task::{Context, Poll};
@@ -35,16 +38,18 @@ pub struct FileDiffStream {
     state: FileDiffStreamState,
     dir_stat: Option<ArcRef<DirDB, DirStat>>,
     dir_path_hash: Option<String>,
+    stats: Arc<DiffStats>,
 }
 
 impl FileDiffStream {
     /// Creates a stream that will list and diff remote files
     pub fn new(
         root: Arc<BackupRoot>,
-        b2: Arc<B2>,
+        rate_limiter: Arc<RateLimiter>,
         prefix: String,
         dir_stat: Option<ArcRef<DirDB, DirStat>>,
         deep_diff: bool,
+        stats: Arc<DiffStats>,
     ) -> Self {
         let dir_path_hash = root.path_hash.clone() + &prefix;
 
@@ -53,43 +58,54 @@ impl FileDiffStream {
         } else {
             FileListDepth::Shallow
         };
-        let b2_clone = b2.clone();
-        let list_fut = async move { root.list_remote_files_at(&b2_clone, &prefix, depth).await }.boxed_local();
+        let key = rate_limiter.backend().key().clone();
+        let list_stats = stats.clone();
+        let list_fut = async move {
+            let _permit = rate_limiter.borrow_control_permit().await;
+            let files = root
+                .list_remote_files_at(rate_limiter.backend(), &prefix, depth)
+                .await?;
+            DiffStats::add(&list_stats.listings, 1);
+            DiffStats::add(
+                &list_stats.list_requests,
+                DiffTree::files_count_to_request_cost(files.len() as u64),
+            );
+            Ok(files)
+        }
+        .boxed_local();
 
         Self {
-            state: FileDiffStreamState::DownloadFileList {
-                list_fut,
-                key: b2.key.clone(),
-                depth,
-            },
+            state: FileDiffStreamState::DownloadFileList { list_fut, key, depth },
             dir_stat,
             dir_path_hash: Some(dir_path_hash),
+            stats,
         }
     }
 
-    /// Creates a stream that returns the files in a local directory not present on the remote
+    /// Creates a stream that returns the files in a local directory not present on the remote.
+    /// Folders are read one at a time as the stream is consumed, so a large new tree isn't loaded all at once.
     pub fn new_local(
         root: Arc<BackupRoot>,
         prefix: String,
         dir_stat: ArcRef<DirDB, DirStat>,
         key: &crypto::Key,
+        stats: &Arc<DiffStats>,
     ) -> Self {
-        let mut local_files = HashMap::new();
-        let mut dir_path_hash = root.path_hash.clone() + &prefix;
-        Self::flatten_dirstat_files(&mut local_files, &dir_stat, &mut dir_path_hash, key);
-
-        let diff_iter = local_files.into_iter().map(|(_, lfile)| {
-            Ok(FileDiff {
-                local: Some(lfile),
-                remote: None,
-            })
-        });
-        let diff_stream = futures::stream::iter(diff_iter).boxed_local();
+        let dir_path_hash = root.path_hash.clone() + &prefix;
+        let local_files = LocalTreeFiles {
+            folders: vec![(dir_stat, dir_path_hash)],
+            files: Vec::new().into_iter(),
+            key: key.clone(),
+            stats: stats.clone(),
+        };
 
         Self {
-            state: FileDiffStreamState::DiffFiles { diff_stream },
+            state: FileDiffStreamState::DiffFiles {
+                diff_stream: futures::stream::iter(local_files).boxed_local(),
+            },
             dir_stat: None,
             dir_path_hash: None,
+            stats: stats.clone(),
         }
     }
 
@@ -143,8 +159,8 @@ impl FileDiffStream {
         dirstat: &DirStat,
         dir_path_hash: &str,
         key: &crypto::Key,
-    ) {
-        for filestat in dirstat.direct_files.as_ref().unwrap() {
+    ) -> Result<()> {
+        for filestat in dirstat.direct_files.as_ref().unwrap().load()?.iter() {
             let mut full_path_hash = dir_path_hash.to_owned();
             crypto::hash_path_filename_into(
                 dir_path_hash.as_bytes(),
@@ -158,9 +174,15 @@ impl FileDiffStream {
                 full_path_hash,
                 last_modified: filestat.last_modified,
                 mode: filestat.mode,
+                birthtime: filestat.birthtime,
             };
-            files.insert(lfile.full_path_hash.clone(), lfile);
+            // Name hashes are truncated, two files with the same hash would be stored as one
+            if let Some(other) = files.insert(lfile.full_path_hash.clone(), lfile) {
+                let lfile = &files[&other.full_path_hash];
+                bail!(collision_message(&other.rel_path, &lfile.rel_path));
+            }
         }
+        Ok(())
     }
 
     fn flatten_dirstat_files(
@@ -168,16 +190,17 @@ impl FileDiffStream {
         dirstat: &DirStat,
         dir_path_hash: &mut String,
         key: &crypto::Key,
-    ) {
-        Self::flatten_dirstat_files_shallow(files, dirstat, dir_path_hash, key);
+    ) -> Result<()> {
+        Self::flatten_dirstat_files_shallow(files, dirstat, dir_path_hash, key)?;
 
         let cur_dir_path_hash_len = dir_path_hash.len();
         for subdir in dirstat.subfolders.iter() {
             dir_path_hash.truncate(cur_dir_path_hash_len);
             base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(subdir.dir_name_hash, dir_path_hash);
             dir_path_hash.push('/');
-            Self::flatten_dirstat_files(files, subdir, dir_path_hash, key);
+            Self::flatten_dirstat_files(files, subdir, dir_path_hash, key)?;
         }
+        Ok(())
     }
 
     fn poll_download_fut(
@@ -199,13 +222,19 @@ impl FileDiffStream {
                 // For remote-only diffs the local dir stat is None
                 if let Some(ref local_dir_stat) = self.dir_stat.take() {
                     let mut dir_path_hash = self.dir_path_hash.take().unwrap();
-                    if let FileListDepth::Deep = depth {
-                        Self::flatten_dirstat_files(&mut local_files, local_dir_stat, &mut dir_path_hash, &key);
+                    let flattened = if let FileListDepth::Deep = depth {
+                        Self::flatten_dirstat_files(&mut local_files, local_dir_stat, &mut dir_path_hash, &key)
                     } else {
-                        Self::flatten_dirstat_files_shallow(&mut local_files, local_dir_stat, &dir_path_hash, &key);
+                        Self::flatten_dirstat_files_shallow(&mut local_files, local_dir_stat, &dir_path_hash, &key)
+                    };
+                    if let Err(err) = flattened {
+                        self.state = FileDiffStreamState::Failed;
+                        return Poll::Ready(Some(Err(err)));
                     }
                 }
 
+                DiffStats::add(&self.stats.remote_files_compared, remote_files.len() as u64);
+                DiffStats::add(&self.stats.local_files_compared, local_files.len() as u64);
                 let mut diff_stream = Self::make_diff_stream(local_files, remote_files);
                 let next = diff_stream.poll_next_unpin(cx);
 
@@ -219,6 +248,54 @@ impl FileDiffStream {
     }
 }
 
+/// The files of a local-only tree, read one folder at a time
+struct LocalTreeFiles {
+    /// Folders left to read, with the path hash prefix of their files
+    folders: Vec<(ArcRef<DirDB, DirStat>, String)>,
+    /// Files of the last folder read, left to return
+    files: std::vec::IntoIter<LocalFile>,
+    key: crypto::Key,
+    stats: Arc<DiffStats>,
+}
+
+impl Iterator for LocalTreeFiles {
+    type Item = Result<FileDiff>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(lfile) = self.files.next() {
+                return Some(Ok(FileDiff {
+                    local: Some(lfile),
+                    remote: None,
+                }));
+            }
+            let (dir_stat, dir_path_hash) = self.folders.pop()?;
+            for (index, subdir) in dir_stat.subfolders.iter().enumerate() {
+                let mut subdir_path_hash = dir_path_hash.clone();
+                base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .encode_string(subdir.dir_name_hash, &mut subdir_path_hash);
+                subdir_path_hash.push('/');
+                let subdir_stat = dir_stat.clone().map(move |stat| &stat.subfolders[index]);
+                self.folders.push((subdir_stat, subdir_path_hash));
+            }
+
+            let mut files = HashMap::new();
+            let flattened =
+                FileDiffStream::flatten_dirstat_files_shallow(&mut files, &dir_stat, &dir_path_hash, &self.key);
+            if let Err(err) = flattened {
+                self.folders.clear();
+                return Some(Err(err));
+            }
+            DiffStats::add(&self.stats.local_files_compared, files.len() as u64);
+            self.files = files
+                .into_iter()
+                .map(|(_, lfile)| lfile)
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
 impl Stream for FileDiffStream {
     type Item = Result<FileDiff>;
 
@@ -239,9 +316,11 @@ impl Stream for FileDiffStream {
 #[cfg(test)]
 mod test {
     use crate::dirdb::diff::files::FileDiffStream;
+    use crate::dirdb::diff::DiffStats;
     use crate::test_helpers::*;
     use futures::{executor::block_on, StreamExt};
     use owning_ref::ArcRef;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
 
     #[test]
@@ -252,7 +331,8 @@ mod test {
         let dirdb = ArcRef::new(Arc::new(test_dirdb()));
         let dirstat = dirdb.map(|d| &d.root);
 
-        let mut stream = FileDiffStream::new_local(root, prefix, dirstat, &key);
+        let stats = Arc::new(DiffStats::default());
+        let mut stream = FileDiffStream::new_local(root, prefix, dirstat, &key, &stats);
 
         let mut filenames = vec![];
         while let Some(item) = block_on(stream.next()) {
@@ -265,5 +345,6 @@ mod test {
 
         // Yup. We're gleefuly hardcoding the contents for this test!
         assert_eq!(filenames, vec!["a", "b", "dir/c"]);
+        assert_eq!(stats.local_files_compared.load(Ordering::Relaxed), 3);
     }
 }
@@ -1,19 +1,24 @@
+use super::scan_cache::{CachedDir, CachedEntry, CachedFile, ScanCache};
+use super::spill::{DirectFiles, FileSpill};
 use super::FileStat;
 use crate::crypto::{self, Key};
-use crate::data::paths::path_to_bytes;
+use crate::data::file::SkippedFile;
+use crate::data::paths::{check_path_len, path_from_bytes, path_to_bytes};
 use base64::Engine;
 use blake2::{Blake2b, Digest};
 use digest::generic_array::GenericArray;
-use eyre::Result;
+use eyre::{bail, Result};
+use std::collections::HashMap;
+use std::fs::{self, DirEntry};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct DirStat {
     /// This is the total number of files in the tree under this directory
     pub total_files_count: u64,
     /// The files directly in this folder
-    pub direct_files: Option<Vec<FileStat>>,
+    pub direct_files: Option<DirectFiles>,
     /// The immediate subfolders of this directory
     pub subfolders: Vec<DirStat>,
     /// This directory's clear name
@@ -26,47 +31,86 @@ pub struct DirStat {
 
 impl DirStat {
     /// Creates a DirStat, but does not compute dir_name_hash
+    #[cfg(test)]
     pub(super) fn new(base_path: &Path, dir_path: &Path) -> Result<Self> {
-        let mut hasher = Blake2b::<digest::consts::U8>::new();
-        let mut total_files_count = 0;
-        let mut direct_files = Vec::new();
-        let mut subfolders = Vec::new();
+        let spill = &mut FileSpill::new(super::spill::SPILL_AFTER_FILES);
+        Self::new_skipping(base_path, dir_path, &[], &mut Vec::new(), spill, None)
+    }
 
-        let mut entries = std::fs::read_dir(dir_path)?.filter_map(|e| e.ok()).collect::<Vec<_>>();
-        entries.sort_by_key(|a| a.path());
+    /// Like `new`, but files and subfolders that can't be read are left out and added to `skipped`.
+    /// Only failing to read `dir_path` itself is an error. The `exclude` paths (relative to `base_path`) are left out.
+    ///
+    /// The files of finished folders go to `spill`, which moves them to disk once there are too many.
+    /// Folders that didn't change since they were put in `cache` aren't statted again, and the cache is updated.
+    ///
+    /// The tree is walked with an explicit stack, since pathological trees can be deep enough to overflow the real one.
+    pub(super) fn new_skipping(
+        base_path: &Path,
+        dir_path: &Path,
+        exclude: &[PathBuf],
+        skipped: &mut Vec<SkippedFile>,
+        spill: &mut FileSpill,
+        mut cache: Option<&mut ScanCache>,
+    ) -> Result<Self> {
+        let mut stack = vec![ScanFrame::open(base_path, dir_path, skipped, cache.as_deref_mut())?];
+        loop {
+            let frame = stack.last_mut().unwrap();
+            let entry = match frame.entries.next() {
+                Some(entry) => entry,
+                None => {
+                    let (rel_path, done) = stack.pop().unwrap().finish(spill, cache.as_deref_mut())?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.add_subfolder(&rel_path, done),
+                        None => return Ok(done),
+                    }
+                    continue;
+                }
+            };
 
-        for entry in entries {
             let path = entry.path();
             let rel_path = PathBuf::from(path.strip_prefix(base_path)?);
-            hasher.update(path_to_bytes(&rel_path).unwrap());
-            let is_symlink = entry.file_type().map(|ft| ft.is_symlink()).unwrap_or(false);
-            if path.is_dir() && !is_symlink {
-                let subfolder = DirStat::new(base_path, &path)?;
-                total_files_count += subfolder.total_files_count;
-                hasher.update(subfolder.content_hash);
-                subfolders.push(subfolder);
+            if exclude.contains(&rel_path) {
+                continue;
+            }
+            let name = entry.file_name();
+            let cached = frame.cached.as_ref().and_then(|dir| dir.get(name.as_bytes())).copied();
+            let is_folder = match cached {
+                Some(CachedEntry::Folder) => true,
+                Some(CachedEntry::File(_)) => false,
+                None => path.is_dir() && !entry.file_type().map(|ft| ft.is_symlink()).unwrap_or(false),
+            };
+            if is_folder {
+                match ScanFrame::open(base_path, &path, skipped, cache.as_deref_mut()) {
+                    Ok(subframe) => {
+                        frame.record(name.as_bytes(), CachedEntry::Folder);
+                        stack.push(subframe);
+                    }
+                    Err(err) => skipped.push(SkippedFile::new(&rel_path, format!("Failed to read folder: {}", err))),
+                }
             } else {
-                total_files_count += 1;
-                let meta = entry.metadata()?;
-                let mtime = meta.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
-                hasher.update(mtime.as_secs().to_le_bytes());
-                hasher.update(mtime.subsec_nanos().to_le_bytes());
-                hasher.update(meta.len().to_le_bytes());
-
-                direct_files.push(FileStat::new(rel_path, meta)?);
+                let file = match cached {
+                    Some(CachedEntry::File(file)) => Ok(file),
+                    _ => check_path_len(&path)
+                        .and_then(|()| Ok(entry.metadata()?))
+                        .and_then(|meta| CachedFile::from_metadata(&meta)),
+                };
+                let file = match file {
+                    Ok(file) => file,
+                    Err(err) => {
+                        skipped.push(SkippedFile::new(&rel_path, format!("Failed to read metadata: {}", err)));
+                        continue;
+                    }
+                };
+                frame.record(name.as_bytes(), CachedEntry::File(file));
+                let hasher = &mut frame.hasher;
+                hasher.update(path_to_bytes(&rel_path).unwrap());
+                hasher.update(file.mtime.0.to_le_bytes());
+                hasher.update(file.mtime.1.to_le_bytes());
+                hasher.update(file.size.to_le_bytes());
+                frame.stat.total_files_count += 1;
+                frame.direct_files.push(file.to_stat(rel_path));
             }
         }
-
-        let dir_name = path_to_bytes(Path::new(dir_path.file_name().unwrap()))?;
-        let mut result = Self {
-            total_files_count,
-            subfolders,
-            direct_files: Some(direct_files),
-            dir_name: Some(dir_name.to_owned()),
-            ..Default::default()
-        };
-        hasher.finalize_into(GenericArray::from_mut_slice(&mut result.content_hash));
-        Ok(result)
     }
 
     pub fn recompute_dir_name_hashes(&mut self, path_hash_str: &mut String, key: &Key) {
@@ -85,6 +129,32 @@ impl DirStat {
         }
     }
 
+    /// Checks that no two subfolders of the same folder have the same name hash, anywhere in the tree.
+    /// Name hashes are truncated, and two paths with the same hash would be stored as one object on the remote,
+    /// the last one uploaded silently replacing the other. `rel_dir` is the path of this folder.
+    /// File name hashes also depend on the backup root, so they're checked when diffing (see `FileDiffStream`).
+    pub fn check_hash_collisions(&self, rel_dir: &Path) -> Result<()> {
+        let mut stack = vec![(self, rel_dir.to_owned())];
+        while let Some((stat, rel_dir)) = stack.pop() {
+            let mut dir_hashes = HashMap::new();
+            for subfolder in stat.subfolders.iter() {
+                let name = path_from_bytes(subfolder.dir_name.as_deref().unwrap_or_default())?;
+                let sub_rel_dir = rel_dir.join(name);
+                if let Some(other) = dir_hashes.insert(subfolder.dir_name_hash, sub_rel_dir.clone()) {
+                    bail!(collision_message(&other, &sub_rel_dir));
+                }
+                stack.push((subfolder, sub_rel_dir));
+            }
+        }
+        Ok(())
+    }
+
+    /// Total size of the files in the tree, only known for DirStats of local folders
+    pub fn total_size(&self) -> u64 {
+        let direct_size: u64 = self.direct_files.iter().map(DirectFiles::total_size).sum();
+        direct_size + self.subfolders.iter().map(DirStat::total_size).sum::<u64>()
+    }
+
     pub fn compute_direct_files_count(&self) -> u64 {
         let subfolder_files_count = self.subfolders.iter().fold(0, |sum, e| sum + e.total_files_count);
         // File counts may be inaccurate due to pessimistic DirDBs or TOCTOU, could underflow
@@ -92,6 +162,105 @@ impl DirStat {
     }
 }
 
+pub(super) fn collision_message(a: &Path, b: &Path) -> String {
+    format!(
+        "\"{}\" and \"{}\" have the same name hash, so they can't both be backed up. \
+         This is astronomically unlikely to happen by chance, but renaming either of them fixes it.",
+        a.display(),
+        b.display()
+    )
+}
+
+/// A folder that `DirStat::new_skipping` is in the middle of scanning
+struct ScanFrame {
+    path: PathBuf,
+    rel_path: PathBuf,
+    /// The entries left to scan, in order
+    entries: std::vec::IntoIter<DirEntry>,
+    hasher: Blake2b<digest::consts::U8>,
+    direct_files: Vec<FileStat>,
+    stat: DirStat,
+    /// What the scan cache knows of this folder, and records for the next scans
+    cached: Option<CachedDir>,
+}
+
+impl ScanFrame {
+    fn open(
+        base_path: &Path,
+        dir_path: &Path,
+        skipped: &mut Vec<SkippedFile>,
+        cache: Option<&mut ScanCache>,
+    ) -> Result<Self> {
+        check_path_len(dir_path)?;
+        let rel_path = dir_path.strip_prefix(base_path)?.to_owned();
+        // Read before the entries, a change while they're listed then invalidates what we cache
+        let mtime = match cache {
+            Some(_) => fs::metadata(dir_path).and_then(|meta| meta.modified()).ok(),
+            None => None,
+        };
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(dir_path)? {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(err) => skipped.push(SkippedFile::new(&rel_path, format!("Failed to list folder: {}", err))),
+            }
+        }
+        entries.sort_by_key(|a| a.path());
+        let cached = match (cache, mtime) {
+            (Some(cache), Some(mtime)) => cache
+                .take(&rel_path, mtime, entries.len())
+                .or_else(|| CachedDir::start(mtime, entries.len())),
+            _ => None,
+        };
+
+        Ok(Self {
+            path: dir_path.to_owned(),
+            rel_path,
+            entries: entries.into_iter(),
+            hasher: Blake2b::new(),
+            direct_files: Vec::new(),
+            stat: DirStat::default(),
+            cached,
+        })
+    }
+
+    fn record(&mut self, name: &[u8], entry: CachedEntry) {
+        if let Some(cached) = &mut self.cached {
+            cached.insert(name, entry);
+        }
+    }
+
+    fn add_subfolder(&mut self, rel_path: &Path, subfolder: DirStat) {
+        self.hasher.update(path_to_bytes(rel_path).unwrap());
+        self.hasher.update(subfolder.content_hash);
+        self.stat.total_files_count += subfolder.total_files_count;
+        self.stat.subfolders.push(subfolder);
+    }
+
+    /// Returns the finished DirStat with the folder's relative path
+    fn finish(self, spill: &mut FileSpill, cache: Option<&mut ScanCache>) -> Result<(PathBuf, DirStat)> {
+        let Self {
+            path,
+            rel_path,
+            hasher,
+            direct_files,
+            mut stat,
+            cached,
+            ..
+        } = self;
+        if let (Some(cache), Some(cached)) = (cache, cached) {
+            cache.insert(&rel_path, cached);
+        }
+        stat.direct_files = Some(spill.store(direct_files)?);
+        stat.dir_name = match path.file_name() {
+            Some(name) => Some(path_to_bytes(Path::new(name))?.to_owned()),
+            None => None,
+        };
+        hasher.finalize_into(GenericArray::from_mut_slice(&mut stat.content_hash));
+        Ok((rel_path, stat))
+    }
+}
+
 impl PartialEq for DirStat {
     fn eq(&self, other: &Self) -> bool {
         self.total_files_count == other.total_files_count
@@ -107,9 +276,28 @@ impl Eq for DirStat {}
 #[cfg(test)]
 mod tests {
     use self::super::DirStat;
+    use crate::dirdb::spill::FileSpill;
     use eyre::Result;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
     use std::path::Path;
 
+    #[test]
+    fn detects_hash_collisions() -> Result<()> {
+        let key = crate::test_helpers::test_key();
+        let path = Path::new("test_data");
+        let mut stat = DirStat::new(path, path)?;
+        stat.recompute_dir_name_hashes(&mut "/".to_owned(), &key);
+        stat.check_hash_collisions(Path::new(""))?;
+
+        let mut twin = stat.subfolders[0].clone();
+        twin.dir_name = Some(b"twin".to_vec());
+        stat.subfolders.push(twin);
+        let err = stat.check_hash_collisions(Path::new("")).unwrap_err();
+        assert!(err.to_string().contains("\"twin\""));
+        Ok(())
+    }
+
     #[test]
     fn count_subfolders() -> Result<()> {
         let path = Path::new("test_data/Folder A/ac");
@@ -147,4 +335,47 @@ mod tests {
         assert_eq!(DirStat::new(path, path)?.total_files_count, 8);
         Ok(())
     }
+
+    #[test]
+    fn scans_deep_trees() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let deepest = dir.path().join("d/".repeat(1500));
+        fs::create_dir_all(&deepest)?;
+        fs::write(deepest.join("file"), "")?;
+
+        // Scanning recursively would need a lot more stack than this.
+        // The tree is returned to be dropped here, since dropping it still recurses (at most PATH_MAX / 2 levels).
+        let base = dir.path().to_owned();
+        let stat = std::thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(move || DirStat::new(&base, &base))?
+            .join()
+            .unwrap()?;
+        assert_eq!(stat.total_files_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn skips_unreadable_folders() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("locked"))?;
+        fs::write(dir.path().join("locked/file"), "")?;
+        fs::write(dir.path().join("file"), "")?;
+        fs::set_permissions(dir.path().join("locked"), fs::Permissions::from_mode(0o000))?;
+        let readable = fs::read_dir(dir.path().join("locked")).is_ok();
+
+        let mut skipped = Vec::new();
+        let spill = &mut FileSpill::new(0);
+        let stat = DirStat::new_skipping(dir.path(), dir.path(), &[], &mut skipped, spill, None);
+        fs::set_permissions(dir.path().join("locked"), fs::Permissions::from_mode(0o755))?;
+        if readable {
+            // Permissions don't apply to root
+            return Ok(());
+        }
+        let stat = stat?;
+        assert_eq!(stat.total_files_count, 1);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].rel_path, Path::new("locked"));
+        Ok(())
+    }
 }
@@ -9,6 +9,9 @@ pub struct FileStat {
     pub rel_path: PathBuf,
     pub last_modified: u64,
     pub mode: u32,
+    pub size: u64,
+    /// Creation time, if the platform and filesystem record it
+    pub birthtime: Option<u64>,
 }
 
 impl FileStat {
@@ -17,6 +20,12 @@ impl FileStat {
             rel_path,
             last_modified: meta.modified()?.duration_since(UNIX_EPOCH).unwrap().as_secs(),
             mode: meta.permissions().mode(),
+            size: meta.len(),
+            birthtime: meta
+                .created()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs()),
         })
     }
 }
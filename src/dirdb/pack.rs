@@ -9,7 +9,7 @@ use std::path::{Path, PathBuf};
 use zstd::stream::{read::Decoder, write::Encoder};
 
 ///! Very dense custom bitstream format for DirStat objects
-///! We need a dense format because DirStats are uploaded in full after every change,
+///! We need a dense format because DirStats are regularly uploaded in full (see `remote` for how they are stored),
 ///! and need to be downloaded before we can start diffing folders.
 
 #[derive(Default)]
@@ -237,6 +237,12 @@ impl DirStat {
 
     /// Load directory stats from a buffer produced by `serialize_into`
     pub fn new_from_bytes(reader: &mut &[u8], key: &Key) -> Result<Self> {
+        Self::new_from_bytes_at(reader, key, "")
+    }
+
+    /// Load a subtree serialized on its own, `parent_path_hash` being the path hash of its parent folder
+    /// (e.g. "/" for a subfolder of the root), which we need to recompute the dir name hashes
+    pub fn new_from_bytes_at(reader: &mut &[u8], key: &Key, parent_path_hash: &str) -> Result<Self> {
         let mut files_count_stream = BitstreamReader::new(reader);
         let mut subdirs_count_stream = BitstreamReader::new(files_count_stream.slice_after());
         let mut dirname_count_stream = BitstreamReader::new(subdirs_count_stream.slice_after());
@@ -246,9 +252,14 @@ impl DirStat {
         let mut dirnames_reader = Decoder::new(dirnames_data)?;
 
         let mut subdirs_data = &dirnames_data[dirnames_data_size..];
-        let mut path_hash_str = String::new();
+        let mut path_hash_str = parent_path_hash.to_owned();
+        let rel_path = if parent_path_hash.is_empty() {
+            Some(PathBuf::new())
+        } else {
+            None
+        };
         Self::subdirs_from_bytes(
-            Some(&PathBuf::new()),
+            rel_path.as_ref(),
             &mut path_hash_str,
             key,
             &mut subdirs_data,
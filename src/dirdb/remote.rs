@@ -0,0 +1,616 @@
+//! Storage of the DirDB of a backup root on the remote
+//! The full DirDB is either a single object (see `pack`) or a sharded index (see `shard`), with deltas on top (see `delta`).
+
+use super::delta::{DeltaNode, DirDBDelta};
+use super::shard::{find_node, splice_shard, split_shards, ShardIndex, ShardRef, ShardSettings};
+use super::{unseal, DirDB, DirStat, Unsealed, FORMAT_VERSION};
+use crate::crypto::Key;
+use crate::net::backend::{is_not_found, Backend, FileListDepth};
+use crate::progress::ProgressHandler;
+use blake2::{Blake2b, Digest};
+use bytes::Bytes;
+use digest::generic_array::GenericArray;
+use eyre::Result;
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+/// After this many delta updates, the next update uploads a full DirDB
+const MAX_UPDATES_SINCE_FULL: u32 = 32;
+/// A delta bigger than this fraction of the full DirDB is replaced by a new full DirDB
+const MAX_DELTA_SIZE_RATIO: usize = 4;
+/// How many shards we download at the same time
+const SHARD_DOWNLOADS_CONCURRENCY: usize = 8;
+
+fn packed_hash(packed: &[u8]) -> [u8; 8] {
+    let mut hash = [0u8; 8];
+    let mut hasher = Blake2b::<digest::consts::U8>::new();
+    hasher.update(packed);
+    hasher.finalize_into(GenericArray::from_mut_slice(&mut hash));
+    hash
+}
+
+/// The full DirDB that deltas are currently relative to
+struct DeltaBase {
+    /// For a sharded DirDB, the index with the stubs of the shards
+    root: DirStat,
+    hash: [u8; 8],
+    /// Size of the full DirDB, including all of its shards
+    packed_len: usize,
+    updates_since_full: u32,
+    shards: Vec<ShardRef>,
+    /// Format version of the full DirDB
+    version: u8,
+}
+
+/// The DirDB of a backup root, as stored on the remote
+pub struct RemoteDirDB {
+    /// The latest DirDB (the full DirDB with its delta applied), or None if the remote has no usable DirDB
+    /// Shards that haven't been loaded yet are stubs, see `load_shards`.
+    pub dirdb: Option<DirDB>,
+    full_path: String,
+    index_path: String,
+    delta_path: String,
+    base: Option<DeltaBase>,
+    has_delta: bool,
+    /// Shards whose stub is still in `dirdb`
+    unloaded_shards: Vec<ShardRef>,
+    sharding: ShardSettings,
+    /// Where the bytes of the transfers are reported
+    progress: Option<ProgressHandler>,
+}
+
+impl RemoteDirDB {
+    /// Downloads the DirDB of a backup root, without its shards. Missing or unreadable DirDBs are treated as empty,
+    /// but a DirDB saved by a newer version of frozen is an error, since we can't update it without losing data.
+    pub async fn fetch(backend: &dyn Backend, root_path_hash: &str) -> Result<Self> {
+        Self::fetch_with_progress(backend, root_path_hash, None).await
+    }
+
+    /// Same as `fetch`, reporting the bytes of this DirDB's transfers on a bytes bar (see `Progress::show_bytes_bar`),
+    /// since big DirDBs take a while at the start and end of a run
+    pub async fn fetch_with_progress(
+        backend: &dyn Backend,
+        root_path_hash: &str,
+        progress: Option<ProgressHandler>,
+    ) -> Result<Self> {
+        let full_path = backend.object_names().dirdb(root_path_hash);
+        let index_path = full_path.clone() + ".index";
+        let delta_path = full_path.clone() + ".delta";
+        let mut remote = RemoteDirDB {
+            dirdb: None,
+            full_path,
+            index_path,
+            delta_path,
+            base: None,
+            has_delta: false,
+            unloaded_shards: Vec::new(),
+            sharding: ShardSettings::default(),
+            progress,
+        };
+
+        let progress = remote.progress.as_ref();
+        let (full, index, delta) = futures::join!(
+            download(backend, progress, &remote.full_path),
+            download(backend, progress, &remote.index_path),
+            download(backend, progress, &remote.delta_path)
+        );
+        // Only one of them is visible at a time, the other is hidden when a full DirDB is uploaded
+        let key = backend.key();
+        let base = match (full, index) {
+            (Ok(full), Err(_)) => read_base(&full, key, |unsealed| {
+                Ok((DirDB::new_from_unsealed(unsealed, key)?.root, Vec::new()))
+            })?,
+            (Err(_), Ok(index)) => read_base(&index, key, |unsealed| {
+                let (shard_index, root) = ShardIndex::new_from_unsealed(unsealed, key)?;
+                Ok((root, shard_index.shards))
+            })?,
+            _ => return Ok(remote),
+        };
+        let mut base = match base {
+            Some(base) => base,
+            None => return Ok(remote),
+        };
+
+        // Only a missing delta means there's none, a delta that failed to download may still be the latest DirDB
+        let delta = match delta.map(|delta| unseal(&delta, key)) {
+            Ok(Ok(unsealed)) => {
+                unsealed.check_version()?;
+                Some(DirDBDelta::new_from_unsealed(&unsealed))
+            }
+            Ok(Err(err)) => Some(Err(err)),
+            Err(err) if is_not_found(&err) => None,
+            Err(err) => Some(Err(err)),
+        };
+        remote.has_delta = delta.is_some();
+        remote.dirdb = match delta {
+            None => Some(DirDB {
+                root: base.root.clone(),
+            }),
+            // A delta for another base is left over from before the last compaction, the full DirDB is newer
+            Some(Ok(delta)) if delta.base_hash != base.hash => Some(DirDB {
+                root: base.root.clone(),
+            }),
+            Some(Ok(delta)) => {
+                base.updates_since_full = delta.updates_since_full;
+                // If we can't apply the latest delta, the full DirDB alone would be outdated, which isn't safe
+                delta
+                    .root
+                    .apply(Some(base.root.clone()))
+                    .ok()
+                    .map(|root| DirDB { root })
+            }
+            Some(Err(_)) => None,
+        };
+
+        // Shards replaced by the delta don't need to be loaded anymore
+        if let Some(dirdb) = remote.dirdb.as_ref() {
+            remote.unloaded_shards = base
+                .shards
+                .iter()
+                .filter(|shard| {
+                    find_node(&dirdb.root, &shard.path).map(|node| node.content_hash) == Some(shard.content_hash)
+                })
+                .cloned()
+                .collect();
+        }
+        remote.base = Some(base);
+        Ok(remote)
+    }
+
+    /// Downloads the shards we need to diff against a local tree, those with the same content locally are left out.
+    /// If a shard can't be loaded the DirDB is dropped, since we can't trust the stubs left in it,
+    /// and the next save uploads a new full DirDB instead of referring to the broken one.
+    pub async fn load_shards(&mut self, backend: &dyn Backend, local: &DirStat) {
+        self.load_shards_where(backend, |shard| {
+            find_node(local, &shard.path).map(|node| node.content_hash) != Some(shard.content_hash)
+        })
+        .await
+    }
+
+    /// Downloads the shards containing the folder at `dir_hashes`, so that the DirDB has its subtree
+    pub async fn load_shards_on_path(&mut self, backend: &dyn Backend, dir_hashes: &[[u8; 8]]) {
+        self.load_shards_where(backend, |shard| dir_hashes.starts_with(&shard.path))
+            .await
+    }
+
+    /// Downloads the shards containing the folder at `dir_hashes` or inside of it, so that the DirDB has its whole subtree
+    pub async fn load_shards_of_subtree(&mut self, backend: &dyn Backend, dir_hashes: &[[u8; 8]]) {
+        self.load_shards_where(backend, |shard| {
+            dir_hashes.starts_with(&shard.path) || shard.path.starts_with(dir_hashes)
+        })
+        .await
+    }
+
+    /// The name of the shard storing the folder at `dir_hashes`, or None if it's in the main DirDB object
+    pub fn shard_name(&self, dir_hashes: &[[u8; 8]]) -> Option<String> {
+        let shards = &self.base.as_ref()?.shards;
+        let shard = shards
+            .iter()
+            .filter(|shard| dir_hashes.starts_with(&shard.path))
+            .max_by_key(|shard| shard.path.len())?;
+        Some(self.full_path.clone() + &shard.file_suffix())
+    }
+
+    async fn load_shards_where<F>(&mut self, backend: &dyn Backend, is_needed: F)
+    where
+        F: Fn(&ShardRef) -> bool,
+    {
+        let dirdb = match self.dirdb.as_mut() {
+            Some(dirdb) => dirdb,
+            None => return,
+        };
+        let (needed, unneeded) = std::mem::take(&mut self.unloaded_shards)
+            .into_iter()
+            .partition::<Vec<_>, _>(|shard| is_needed(shard));
+        self.unloaded_shards = unneeded;
+
+        let full_path = &self.full_path;
+        let progress = self.progress.as_ref();
+        let loaded = stream::iter(needed)
+            .map(|shard| async move {
+                let packed = download(backend, progress, &(full_path.clone() + &shard.file_suffix())).await?;
+                let stat = shard.unpack(&packed, backend.key())?;
+                Ok::<_, eyre::Report>((shard, stat))
+            })
+            .buffer_unordered(SHARD_DOWNLOADS_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await;
+        match loaded {
+            Ok(loaded) => {
+                for (shard, stat) in loaded {
+                    splice_shard(&mut dirdb.root, &shard, stat);
+                }
+            }
+            Err(_) => {
+                self.dirdb = None;
+                self.base = None;
+            }
+        }
+    }
+
+    /// Replaces the remote DirDB, uploading only a delta when possible
+    pub async fn save(&mut self, backend: &dyn Backend, dirdb: &DirDB) -> Result<()> {
+        let key = backend.key();
+        if let Some(base) = self.base.as_mut() {
+            // DirDBs in an older format are migrated by uploading a new full DirDB
+            if base.updates_since_full < MAX_UPDATES_SINCE_FULL && base.version == FORMAT_VERSION {
+                let delta = DirDBDelta {
+                    base_hash: base.hash,
+                    updates_since_full: base.updates_since_full + 1,
+                    root: DeltaNode::new(&dirdb.root, Some(&base.root)),
+                };
+                let packed = delta.to_packed(key)?;
+                if packed.len() * MAX_DELTA_SIZE_RATIO <= base.packed_len {
+                    upload(backend, self.progress.as_ref(), &self.delta_path, packed).await?;
+                    base.updates_since_full += 1;
+                    self.has_delta = true;
+                    return Ok(());
+                }
+            }
+        }
+
+        let old_shards = self.base.take().map(|base| base.shards).unwrap_or_default();
+        let base = match split_shards(dirdb, key, self.sharding, &old_shards)? {
+            Some((index, root, new_shards)) => {
+                for new_shard in new_shards {
+                    let shard_path = self.full_path.clone() + &new_shard.shard_ref.file_suffix();
+                    upload(backend, self.progress.as_ref(), &shard_path, new_shard.packed).await?;
+                }
+                let shards_len: usize = index.shards.iter().map(|shard| shard.packed_len).sum();
+                let packed = index.to_packed(key)?;
+                let base = DeltaBase {
+                    root,
+                    hash: packed_hash(&packed),
+                    packed_len: packed.len() + shards_len,
+                    updates_since_full: 0,
+                    shards: index.shards,
+                    version: FORMAT_VERSION,
+                };
+                upload(backend, self.progress.as_ref(), &self.index_path, packed).await?;
+                hide_if_exists(backend, &self.full_path).await?;
+                base
+            }
+            None => {
+                let packed = dirdb.to_packed(key)?;
+                let base = DeltaBase {
+                    root: dirdb.root.clone(),
+                    hash: packed_hash(&packed),
+                    packed_len: packed.len(),
+                    updates_since_full: 0,
+                    shards: Vec::new(),
+                    version: FORMAT_VERSION,
+                };
+                upload(backend, self.progress.as_ref(), &self.full_path, packed).await?;
+                hide_if_exists(backend, &self.index_path).await?;
+                base
+            }
+        };
+
+        // Shards that aren't referenced anymore would just be ignored
+        for old_shard in old_shards {
+            if !base.shards.iter().any(|shard| shard.path == old_shard.path) {
+                let _ = backend
+                    .hide_file(&(self.full_path.clone() + &old_shard.file_suffix()))
+                    .await;
+            }
+        }
+        self.unloaded_shards.clear();
+        self.base = Some(base);
+
+        // A leftover delta would be ignored since its base is gone, but there's no point downloading it
+        if self.has_delta && backend.hide_file(&self.delta_path).await.is_ok() {
+            self.has_delta = false;
+        }
+        Ok(())
+    }
+
+    /// Hides the DirDB of a backup root, so that the next backup has to compare against the remote files
+    pub async fn hide(backend: &dyn Backend, root_path_hash: &str) -> Result<()> {
+        let names = backend.object_names();
+        for full_path in std::iter::once(names.dirdb(root_path_hash)).chain(names.legacy_dirdb(root_path_hash)) {
+            hide_if_exists(backend, &full_path).await?;
+            hide_if_exists(backend, &(full_path + ".index")).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a full DirDB or a shard index. Unreadable objects are treated as missing, except for newer formats.
+fn read_base<F>(packed: &[u8], key: &Key, decode: F) -> Result<Option<DeltaBase>>
+where
+    F: FnOnce(&Unsealed) -> Result<(DirStat, Vec<ShardRef>)>,
+{
+    let unsealed = match unseal(packed, key) {
+        Ok(unsealed) => unsealed,
+        Err(_) => return Ok(None),
+    };
+    unsealed.check_version()?;
+    Ok(decode(&unsealed).ok().map(|(root, shards)| DeltaBase {
+        root,
+        hash: packed_hash(packed),
+        packed_len: packed.len() + shards.iter().map(|shard| shard.packed_len).sum::<usize>(),
+        updates_since_full: 0,
+        shards,
+        version: unsealed.version,
+    }))
+}
+
+/// Downloads a DirDB object, reporting its bytes as they arrive
+async fn download(backend: &dyn Backend, progress: Option<&ProgressHandler>, path: &str) -> Result<Bytes> {
+    let progress = match progress {
+        Some(progress) => progress,
+        None => return backend.download_file(path).await,
+    };
+    let (size, mut stream) = backend.download_file_stream_sized(path).await?;
+    if let Some(size) = size {
+        progress.add_bytes_to_do(size);
+    }
+    let mut data = Vec::with_capacity(size.unwrap_or(0) as usize);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if size.is_none() {
+            progress.add_bytes_to_do(chunk.len() as u64);
+        }
+        progress.report_bytes(chunk.len() as u64);
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.into())
+}
+
+/// Uploads a DirDB object, its bytes are reported once it's uploaded
+async fn upload(backend: &dyn Backend, progress: Option<&ProgressHandler>, path: &str, packed: Vec<u8>) -> Result<()> {
+    let len = packed.len() as u64;
+    if let Some(progress) = progress {
+        progress.add_bytes_to_do(len);
+    }
+    backend.upload_file_simple(path, packed).await?;
+    if let Some(progress) = progress {
+        progress.report_bytes(len);
+    }
+    Ok(())
+}
+
+/// Hides a file, unless it's already hidden or doesn't exist
+async fn hide_if_exists(backend: &dyn Backend, path: &str) -> Result<()> {
+    if let err @ Err(_) = backend.hide_file(path).await {
+        let files = backend.list_remote_files(path, FileListDepth::Shallow).await?;
+        if files.iter().any(|file| file.full_path_hash == path) {
+            return err;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::encrypt;
+    use crate::dirdb::FORMAT_MAGIC;
+    use crate::net::memory::MemoryBackend;
+    use crate::output::format_size;
+    use crate::progress::{status_report, Progress, ProgressType, Verbosity};
+    use crate::test_helpers::test_key;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_tree(base: &Path, files: &[String]) {
+        for file in files {
+            let path = base.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file.as_bytes()).unwrap();
+        }
+    }
+
+    fn small_shards() -> ShardSettings {
+        ShardSettings {
+            min_files: 50,
+            max_files: 200,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn save_and_fetch() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..1000)
+            .map(|i| format!("dir{}/sub{}/file{}", i % 20, i / 20, i))
+            .collect();
+        write_tree(dir.path(), &files);
+        let first = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert!(remote.dirdb.is_none());
+        remote.save(&backend, &first).await?;
+        assert_eq!(backend.file_names(), vec!["dirdb/root".to_owned()]);
+
+        fs::write(dir.path().join("dir3/sub3/file3"), "changed content")?;
+        let second = DirDB::new_from_local(dir.path(), &test_key())?;
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert!(remote.dirdb.as_ref().unwrap().root == first.root);
+        remote.save(&backend, &second).await?;
+        assert_eq!(backend.file_names(), vec![
+            "dirdb/root".to_owned(),
+            "dirdb/root.delta".to_owned()
+        ]);
+
+        let remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert!(remote.dirdb.as_ref().unwrap().root == second.root);
+        assert_eq!(remote.base.as_ref().unwrap().updates_since_full, 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transfers_are_reported_in_bytes() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..100).map(|i| format!("dir{}/file{}", i % 5, i)).collect();
+        write_tree(dir.path(), &files);
+        let dirdb = DirDB::new_from_local(dir.path(), &test_key())?;
+        let progress = Progress::new(Verbosity::default());
+        let bar = progress.show_bytes_bar(ProgressType::DirDB);
+        let mut remote = RemoteDirDB::fetch_with_progress(&backend, "root", Some(bar.clone())).await?;
+        remote.save(&backend, &dirdb).await?;
+        let packed_len = backend.download_file("dirdb/root").await?.len();
+        assert!(bar.is_complete());
+
+        let remote = RemoteDirDB::fetch_with_progress(&backend, "root", Some(bar.clone())).await?;
+        assert!(remote.dirdb.is_some());
+        assert!(bar.is_complete());
+        let line = format!("DirDB: {0}/{0} in ", format_size(2 * packed_len as u64));
+        assert!(status_report().iter().any(|report| report.starts_with(&line)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sharded_lazy_loading() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..500).map(|i| format!("dir{}/file{}", i % 5, i)).collect();
+        write_tree(dir.path(), &files);
+        fs::create_dir_all(dir.path().join("dir4/empty"))?;
+        let first = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        remote.sharding = small_shards();
+        remote.save(&backend, &first).await?;
+        assert!(!backend.file_names().contains(&"dirdb/root".to_owned()));
+        assert_eq!(backend.file_names().len(), 6);
+
+        // Nothing to load when the local tree has the same content
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert_eq!(remote.unloaded_shards.len(), 5);
+        remote.load_shards(&backend, &first.root).await;
+        assert_eq!(remote.unloaded_shards.len(), 5);
+        assert!(remote.dirdb.as_ref().unwrap().root.subfolders[0].subfolders.is_empty());
+
+        // Looking up a single path only loads the shard it's in
+        let mut lookup = RemoteDirDB::fetch(&backend, "root").await?;
+        let dir4 = first
+            .root
+            .subfolders
+            .iter()
+            .find(|sub| !sub.subfolders.is_empty())
+            .unwrap();
+        let path = [dir4.dir_name_hash, dir4.subfolders[0].dir_name_hash];
+        lookup.load_shards_on_path(&backend, &path).await;
+        assert_eq!(lookup.unloaded_shards.len(), 4);
+        assert!(find_node(&lookup.dirdb.as_ref().unwrap().root, &path).is_some());
+        assert!(lookup.shard_name(&path).unwrap().starts_with("dirdb/root.shards/"));
+        assert_eq!(lookup.shard_name(&[]), None);
+
+        // Only the shard that changed is loaded
+        fs::write(dir.path().join("dir3/file3"), "changed content")?;
+        let second = DirDB::new_from_local(dir.path(), &test_key())?;
+        remote.load_shards(&backend, &second.root).await;
+        assert_eq!(remote.unloaded_shards.len(), 4);
+
+        // A delta works on top of the index
+        remote.save(&backend, &second).await?;
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert_eq!(remote.base.as_ref().unwrap().updates_since_full, 1);
+        remote.load_shards(&backend, &DirDB::new_empty().root).await;
+        assert!(remote.dirdb.as_ref().unwrap().root == second.root);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_shard_drops_dirdb() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..200).map(|i| format!("dir{}/file{}", i % 2, i)).collect();
+        write_tree(dir.path(), &files);
+        let dirdb = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        remote.sharding = small_shards();
+        remote.save(&backend, &dirdb).await?;
+        let shard_name = backend
+            .file_names()
+            .into_iter()
+            .find(|name| name.starts_with("dirdb/root.shards/"))
+            .unwrap();
+        backend.hide_file(&shard_name).await?;
+
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert!(remote.dirdb.is_some());
+        remote.load_shards(&backend, &DirDB::new_empty().root).await;
+        assert!(remote.dirdb.is_none());
+
+        // Going back to a single full DirDB hides the index
+        remote.save(&backend, &dirdb).await?;
+        assert!(backend.file_names().contains(&"dirdb/root".to_owned()));
+        assert!(!backend.file_names().contains(&"dirdb/root.index".to_owned()));
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        remote.load_shards(&backend, &DirDB::new_empty().root).await;
+        assert!(remote.dirdb.unwrap().root == dirdb.root);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_delta_download_is_not_a_missing_delta() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..1000)
+            .map(|i| format!("dir{}/sub{}/file{}", i % 20, i / 20, i))
+            .collect();
+        write_tree(dir.path(), &files);
+        let first = DirDB::new_from_local(dir.path(), &test_key())?;
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        remote.save(&backend, &first).await?;
+        fs::write(dir.path().join("dir3/sub3/file3"), "changed content")?;
+        let second = DirDB::new_from_local(dir.path(), &test_key())?;
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        remote.save(&backend, &second).await?;
+        assert!(backend.file_names().contains(&"dirdb/root.delta".to_owned()));
+        assert!(RemoteDirDB::fetch(&backend, "root").await?.dirdb.unwrap().root == second.root);
+
+        // The full DirDB alone is outdated, the diff has to compare against the remote files instead
+        backend.fail_downloads_of("dirdb/root.delta");
+        let remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert!(remote.dirdb.is_none());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn legacy_format_is_migrated() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let dir = tempdir()?;
+        let files: Vec<String> = (0..1000).map(|i| format!("dir{}/file{}", i % 20, i)).collect();
+        write_tree(dir.path(), &files);
+        let dirdb = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        // Before the header, the full DirDB was just the encrypted bitstream
+        let mut legacy = Vec::new();
+        dirdb.root.serialize_into(&mut legacy)?;
+        backend
+            .upload_file_simple("dirdb/root", encrypt(&legacy, &test_key()))
+            .await?;
+
+        let mut remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert_eq!(remote.base.as_ref().unwrap().version, 0);
+        assert!(remote.dirdb.as_ref().unwrap().root == dirdb.root);
+
+        // Even an unchanged DirDB is uploaded in full instead of as a delta
+        remote.save(&backend, &dirdb).await?;
+        assert_eq!(backend.file_names(), vec!["dirdb/root".to_owned()]);
+        let remote = RemoteDirDB::fetch(&backend, "root").await?;
+        assert_eq!(remote.base.as_ref().unwrap().version, FORMAT_VERSION);
+        assert!(remote.dirdb.unwrap().root == dirdb.root);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn newer_format_is_refused() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let mut future = FORMAT_MAGIC.to_vec();
+        future.push(FORMAT_VERSION + 1);
+        future.extend_from_slice(b"something we can't read");
+        backend
+            .upload_file_simple("dirdb/root", encrypt(&future, &test_key()))
+            .await?;
+
+        let err = RemoteDirDB::fetch(&backend, "root").await.err().unwrap();
+        assert!(format!("{}", err).contains("upgrade frozen"));
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Remembers what the last scans of a backup found in each folder, so unchanged folders aren't statted file by file
+//!
+//! A folder is reused when its mtime and number of entries are the same as when it was cached. Adding, removing or
+//! renaming an entry changes the folder's mtime, but writing into an existing file doesn't, so cached folders expire
+//! after `MAX_AGE` and are scanned again. Until then such edits are missed, so the cache is only used when asked for
+//! with `backup --scan-cache`.
+
+use super::FileStat;
+use crate::data::paths::{path_from_bytes, path_to_bytes};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cached folders are scanned again after this long, to pick up the files modified in place
+pub const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Folders modified this recently aren't cached, they could still change within the same mtime
+const SETTLE_SECS: u64 = 2;
+
+/// What a scan found in a folder
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CachedDir {
+    mtime: (u64, u32),
+    entry_count: u64,
+    /// When the folder was last actually scanned, in seconds since the Unix epoch
+    cached_at: u64,
+    /// By raw file name
+    entries: HashMap<Vec<u8>, CachedEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachedEntry {
+    Folder,
+    File(CachedFile),
+}
+
+/// The metadata of a file that the scan uses
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CachedFile {
+    /// Seconds and nanoseconds since the Unix epoch
+    pub mtime: (u64, u32),
+    pub mode: u32,
+    pub size: u64,
+    pub birthtime: Option<u64>,
+}
+
+impl CachedFile {
+    pub fn from_metadata(meta: &Metadata) -> Result<Self> {
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            mode: meta.permissions().mode(),
+            size: meta.len(),
+            birthtime: meta
+                .created()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs()),
+        })
+    }
+
+    pub fn to_stat(self, rel_path: PathBuf) -> FileStat {
+        FileStat {
+            rel_path,
+            last_modified: self.mtime.0,
+            mode: self.mode,
+            size: self.size,
+            birthtime: self.birthtime,
+        }
+    }
+}
+
+impl CachedDir {
+    /// Starts recording a folder being scanned, None if it's too recent to be cached
+    pub fn start(mtime: SystemTime, entry_count: usize) -> Option<Self> {
+        let mtime = mtime.duration_since(UNIX_EPOCH).ok()?;
+        let now = now_secs();
+        if mtime.as_secs() + SETTLE_SECS > now {
+            return None;
+        }
+        Some(Self {
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            entry_count: entry_count as u64,
+            cached_at: now,
+            entries: HashMap::new(),
+        })
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&CachedEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn insert(&mut self, name: &[u8], entry: CachedEntry) {
+        self.entries.insert(name.to_owned(), entry);
+    }
+}
+
+/// The cached folders of one backup, by path relative to the source
+pub struct ScanCache {
+    path: PathBuf,
+    /// Folders from the last scans, taken out as this scan reaches them
+    previous: HashMap<Vec<u8>, CachedDir>,
+    /// Folders found by this scan
+    current: HashMap<Vec<u8>, CachedDir>,
+}
+
+impl ScanCache {
+    /// Loads the cache saved at `path`, a missing or unreadable cache is just empty
+    pub fn load(path: &Path) -> Self {
+        let previous = fs::read(path)
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.to_owned(),
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    /// The cached content of a folder, if it didn't change since and didn't expire
+    pub fn take(&mut self, rel_dir: &Path, mtime: SystemTime, entry_count: usize) -> Option<CachedDir> {
+        let cached = self.previous.remove(path_to_bytes(rel_dir).ok()?)?;
+        let mtime = mtime.duration_since(UNIX_EPOCH).ok()?;
+        let unchanged = cached.mtime == (mtime.as_secs(), mtime.subsec_nanos())
+            && cached.entry_count == entry_count as u64
+            && cached.cached_at + MAX_AGE.as_secs() > now_secs();
+        Some(cached).filter(|_| unchanged)
+    }
+
+    /// Records what this scan found in a folder, a reused folder keeps its `cached_at`
+    pub fn insert(&mut self, rel_dir: &Path, dir: CachedDir) {
+        if let Ok(rel_dir) = path_to_bytes(rel_dir) {
+            self.current.insert(rel_dir.to_owned(), dir);
+        }
+    }
+
+    /// Saves the folders of this scan, whose root was `scanned_dir`. Folders outside it are kept as they were.
+    pub fn save(self, scanned_dir: &Path) -> Result<()> {
+        let Self {
+            path,
+            previous,
+            mut current,
+        } = self;
+        for (rel_dir, dir) in previous {
+            if !path_from_bytes(&rel_dir)?.starts_with(scanned_dir) {
+                current.entry(rel_dir).or_insert(dir);
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bincode::serialize(&current)?)
+            .wrap_err_with(|| format!("Failed to save the scan cache {}", path.display()))?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirdb::dirstat::DirStat;
+    use crate::dirdb::spill::FileSpill;
+    use std::fs::File;
+
+    fn scan(dir: &Path, cache_path: &Path) -> Result<DirStat> {
+        let mut cache = ScanCache::load(cache_path);
+        let spill = &mut FileSpill::new(u64::MAX);
+        let stat = DirStat::new_skipping(dir, dir, &[], &mut Vec::new(), spill, Some(&mut cache))?;
+        cache.save(Path::new(""))?;
+        Ok(stat)
+    }
+
+    fn set_old_mtime(path: &Path) -> Result<()> {
+        File::open(path)?.set_modified(SystemTime::now() - Duration::from_secs(3600))?;
+        Ok(())
+    }
+
+    #[test]
+    fn unchanged_folders_are_reused() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache_path = dir.path().join("cache");
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub"))?;
+        fs::write(source.join("sub/file"), "one")?;
+        for path in [source.join("sub/file"), source.join("sub"), source.clone()].iter() {
+            set_old_mtime(path)?;
+        }
+        let first = scan(&source, &cache_path)?;
+
+        // Writing into a file doesn't change its folder, the cached metadata is used
+        fs::write(source.join("sub/file"), "three")?;
+        let second = scan(&source, &cache_path)?;
+        assert_eq!(second, first);
+        assert_eq!(second.total_size(), 3);
+
+        // A new file changes the folder, which is scanned again
+        fs::write(source.join("sub/new"), "")?;
+        set_old_mtime(&source.join("sub"))?;
+        let third = scan(&source, &cache_path)?;
+        assert_eq!(third.total_files_count, 2);
+        assert_eq!(third.total_size(), 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! Sharded DirDBs
+//! The DirDB of a huge tree is stored as an index at "dirdb/<root path hash>.index", where big subtrees are
+//! replaced by stubs that only keep their content hash and file count. Each of those subtrees is stored as a
+//! separate shard under "dirdb/<root path hash>.shards/", and only downloaded when we actually need to look inside.
+
+use super::{seal, unseal, DirDB, DirStat, Unsealed};
+use crate::crypto::Key;
+use base64::Engine;
+use eyre::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Subtrees with fewer files than this are kept in the index
+const SHARD_MIN_FILES: u64 = 10_000;
+/// Subtrees with more files than this are split into smaller shards, if they have subfolders
+const SHARD_MAX_FILES: u64 = 100_000;
+
+#[derive(Clone, Copy)]
+pub(super) struct ShardSettings {
+    pub min_files: u64,
+    pub max_files: u64,
+}
+
+impl Default for ShardSettings {
+    fn default() -> Self {
+        Self {
+            min_files: SHARD_MIN_FILES,
+            max_files: SHARD_MAX_FILES,
+        }
+    }
+}
+
+/// Where to find a shard, and what it should contain
+#[derive(Serialize, Deserialize, Clone)]
+pub(super) struct ShardRef {
+    /// The dir name hashes from the root to the shard's folder
+    pub path: Vec<[u8; 8]>,
+    /// The clear names of the folders on the path, which the index may not have kept
+    pub dir_names: Vec<Option<Vec<u8>>>,
+    pub content_hash: [u8; 8],
+    /// Size of the encrypted shard
+    pub packed_len: usize,
+}
+
+impl ShardRef {
+    /// The shard's file name, relative to the full DirDB's
+    pub fn file_suffix(&self) -> String {
+        let mut suffix = ".shards".to_owned();
+        for hash in &self.path {
+            suffix.push('/');
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(hash, &mut suffix);
+        }
+        suffix
+    }
+
+    /// The path hash of the shard's parent folder, in the same format as `DirStat::recompute_dir_name_hashes`
+    fn parent_path_hash(&self) -> String {
+        let mut path_hash = "/".to_owned();
+        for hash in &self.path[..self.path.len() - 1] {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode_string(hash, &mut path_hash);
+            path_hash.push('/');
+        }
+        path_hash
+    }
+
+    pub fn unpack(&self, packed: &[u8], key: &Key) -> Result<DirStat> {
+        let unsealed = unseal(packed, key)?;
+        unsealed.check_version()?;
+        let stat = DirStat::new_from_bytes_at(&mut unsealed.data.as_slice(), key, &self.parent_path_hash())?;
+        ensure!(
+            stat.content_hash == self.content_hash && Some(&stat.dir_name_hash) == self.path.last(),
+            "DirDB shard doesn't match its index"
+        );
+        Ok(stat)
+    }
+}
+
+/// The root of a sharded DirDB
+#[derive(Serialize, Deserialize)]
+pub(super) struct ShardIndex {
+    /// The DirStat tree with stubs in place of the shards, in the `pack` format
+    pub root: Vec<u8>,
+    pub shards: Vec<ShardRef>,
+}
+
+impl ShardIndex {
+    pub fn to_packed(&self, key: &Key) -> Result<Vec<u8>> {
+        Ok(seal(&bincode::serialize(self)?, key))
+    }
+
+    pub fn new_from_unsealed(unsealed: &Unsealed, key: &Key) -> Result<(Self, DirStat)> {
+        let index: Self = bincode::deserialize(&unsealed.data)?;
+        let root = DirStat::new_from_bytes(&mut index.root.as_slice(), key)?;
+        Ok((index, root))
+    }
+}
+
+/// A shard that needs to be uploaded
+pub(super) struct NewShard {
+    pub shard_ref: ShardRef,
+    pub packed: Vec<u8>,
+}
+
+/// Turns a folder into a stub, which keeps its content hash as a promise of what the shard contains
+fn stub(stat: &DirStat) -> DirStat {
+    DirStat {
+        total_files_count: stat.total_files_count,
+        direct_files: None,
+        subfolders: Vec::new(),
+        dir_name: stat.dir_name.clone(),
+        dir_name_hash: stat.dir_name_hash,
+        content_hash: stat.content_hash,
+    }
+}
+
+struct Splitter<'a> {
+    key: &'a Key,
+    settings: ShardSettings,
+    /// Shards already on the remote, which don't need to be uploaded again if their content is the same
+    existing: HashMap<&'a [[u8; 8]], &'a ShardRef>,
+    /// Folders that have existing shards under them
+    existing_parents: HashSet<&'a [[u8; 8]]>,
+    shards: Vec<ShardRef>,
+    new_shards: Vec<NewShard>,
+}
+
+impl<'a> Splitter<'a> {
+    fn split(
+        &mut self,
+        stat: &DirStat,
+        path: &mut Vec<[u8; 8]>,
+        dir_names: &mut Vec<Option<Vec<u8>>>,
+    ) -> Result<DirStat> {
+        let mut index_stat = stub(stat);
+        for sub in stat.subfolders.iter() {
+            path.push(sub.dir_name_hash);
+            dir_names.push(sub.dir_name.clone());
+
+            // The subtree of a shard we never downloaded isn't in the DirDB, but its content can't have changed
+            let existing = self
+                .existing
+                .get(path.as_slice())
+                .filter(|shard| shard.content_hash == sub.content_hash && sub.content_hash != [0; 8]);
+            if let Some(&existing) = existing {
+                self.shards.push(existing.clone());
+                index_stat.subfolders.push(stub(sub));
+            } else if (sub.total_files_count > self.settings.max_files && !sub.subfolders.is_empty())
+                || self.existing_parents.contains(path.as_slice())
+            {
+                // If we haven't downloaded a shard below, the DirDB only has its stub, so we must keep it separate
+                index_stat.subfolders.push(self.split(sub, path, dir_names)?);
+            } else if sub.total_files_count >= self.settings.min_files {
+                let mut plain = Vec::new();
+                sub.serialize_into(&mut plain)?;
+                let packed = seal(&plain, self.key);
+                let shard_ref = ShardRef {
+                    path: path.clone(),
+                    dir_names: dir_names.clone(),
+                    content_hash: sub.content_hash,
+                    packed_len: packed.len(),
+                };
+                self.shards.push(shard_ref.clone());
+                self.new_shards.push(NewShard { shard_ref, packed });
+                index_stat.subfolders.push(stub(sub));
+            } else {
+                index_stat.subfolders.push(sub.clone());
+            }
+
+            path.pop();
+            dir_names.pop();
+        }
+        Ok(index_stat)
+    }
+}
+
+/// Splits the big subtrees of a DirDB into shards.
+/// Returns the index and the shards that need to be uploaded, or None if the DirDB is small enough to stay whole.
+pub(super) fn split_shards(
+    dirdb: &DirDB,
+    key: &Key,
+    settings: ShardSettings,
+    existing: &[ShardRef],
+) -> Result<Option<(ShardIndex, DirStat, Vec<NewShard>)>> {
+    let mut splitter = Splitter {
+        key,
+        settings,
+        existing: existing.iter().map(|shard| (shard.path.as_slice(), shard)).collect(),
+        existing_parents: existing
+            .iter()
+            .flat_map(|shard| (1..shard.path.len()).map(move |len| &shard.path[..len]))
+            .collect(),
+        shards: Vec::new(),
+        new_shards: Vec::new(),
+    };
+    let root = splitter.split(&dirdb.root, &mut Vec::new(), &mut Vec::new())?;
+    if splitter.shards.is_empty() {
+        return Ok(None);
+    }
+
+    let mut packed_root = Vec::new();
+    root.serialize_into(&mut packed_root)?;
+    let index = ShardIndex {
+        root: packed_root,
+        shards: splitter.shards,
+    };
+    Ok(Some((index, root, splitter.new_shards)))
+}
+
+pub(super) fn find_node<'a>(root: &'a DirStat, path: &[[u8; 8]]) -> Option<&'a DirStat> {
+    path.iter().try_fold(root, |node, hash| {
+        node.subfolders.iter().find(|sub| &sub.dir_name_hash == hash)
+    })
+}
+
+/// Replaces the stub of a shard by its content
+pub(super) fn splice_shard(root: &mut DirStat, shard_ref: &ShardRef, shard: DirStat) {
+    let mut node = root;
+    for (hash, dir_name) in shard_ref.path.iter().zip(shard_ref.dir_names.iter()) {
+        node = match node.subfolders.iter_mut().find(|sub| &sub.dir_name_hash == hash) {
+            Some(sub) => sub,
+            None => return,
+        };
+        if node.dir_name.is_none() {
+            node.dir_name = dir_name.clone();
+        }
+    }
+    node.subfolders = shard.subfolders;
+    node.total_files_count = shard.total_files_count;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_key;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn split_and_splice() -> Result<()> {
+        let dir = tempdir()?;
+        for i in 0..60 {
+            let path = dir
+                .path()
+                .join(format!("big/sub{}/a-rather-long-folder-name/file{}", i % 3, i));
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "")?;
+        }
+        fs::create_dir_all(dir.path().join("big/sub0/a-rather-long-folder-name/empty"))?;
+        fs::create_dir_all(dir.path().join("small"))?;
+        fs::write(dir.path().join("small/file"), "")?;
+        let dirdb = DirDB::new_from_local(dir.path(), &test_key())?;
+
+        let settings = ShardSettings {
+            min_files: 10,
+            max_files: 30,
+        };
+        let (index, root, new_shards) = split_shards(&dirdb, &test_key(), settings, &[])?.unwrap();
+        // big/ is split into its three subfolders, small/ stays in the index
+        assert_eq!(new_shards.len(), 3);
+        assert_eq!(index.shards.len(), 3);
+        assert!(root.subfolders.iter().all(|sub| sub.content_hash != [0; 8]));
+
+        let (_, mut unpacked_root) =
+            ShardIndex::new_from_unsealed(&unseal(&index.to_packed(&test_key())?, &test_key())?, &test_key())?;
+        for shard in new_shards {
+            let stat = shard.shard_ref.unpack(&shard.packed, &test_key())?;
+            splice_shard(&mut unpacked_root, &shard.shard_ref, stat);
+        }
+        assert!(unpacked_root == dirdb.root);
+
+        // Unchanged shards are reused as-is
+        let (_, _, new_shards) = split_shards(&dirdb, &test_key(), settings, &index.shards)?.unwrap();
+        assert!(new_shards.is_empty());
+        Ok(())
+    }
+}
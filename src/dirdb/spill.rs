@@ -0,0 +1,197 @@
+//! Keeps the files of a scanned tree on disk instead of in memory, for trees too large to hold (tens of millions of files)
+//!
+//! A scan keeps the files of the first folders it finishes in memory. Past `SPILL_AFTER_FILES`, the files of each
+//! further folder are written to an unnamed temporary file (in `TMPDIR`), and only their place in it is kept.
+//! The folders themselves stay in memory, there are far fewer of them. The diff reads the files back one folder at a time.
+
+use super::FileStat;
+use eyre::{ensure, Result, WrapErr};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Files a scan keeps in memory before spilling the others to disk, a few hundred MB of `FileStat`s
+pub const SPILL_AFTER_FILES: u64 = 1_000_000;
+
+/// The files directly in a scanned folder
+#[derive(Debug, Clone)]
+pub enum DirectFiles {
+    Loaded(Vec<FileStat>),
+    Spilled(SpilledFiles),
+}
+
+/// Where the files of a folder are in the spill file
+#[derive(Debug, Clone)]
+pub struct SpilledFiles {
+    file: Arc<File>,
+    offset: u64,
+    len: usize,
+    count: usize,
+    total_size: u64,
+}
+
+impl DirectFiles {
+    /// The files, read back from the disk if they were spilled
+    pub fn load(&self) -> Result<Cow<'_, [FileStat]>> {
+        match self {
+            DirectFiles::Loaded(files) => Ok(Cow::Borrowed(files)),
+            DirectFiles::Spilled(spilled) => Ok(Cow::Owned(
+                spilled.load().wrap_err("Failed to read back the scanned files")?,
+            )),
+        }
+    }
+
+    /// Total size of the files, without reading them back
+    pub fn total_size(&self) -> u64 {
+        match self {
+            DirectFiles::Loaded(files) => files.iter().map(|file| file.size).sum(),
+            DirectFiles::Spilled(spilled) => spilled.total_size,
+        }
+    }
+}
+
+impl SpilledFiles {
+    fn load(&self) -> Result<Vec<FileStat>> {
+        let mut data = vec![0; self.len];
+        self.file.read_exact_at(&mut data, self.offset)?;
+        let mut data = data.as_slice();
+        let mut files = Vec::with_capacity(self.count);
+        for _ in 0..self.count {
+            files.push(decode(&mut data)?);
+        }
+        Ok(files)
+    }
+}
+
+/// Where a scan puts the files of the folders it finishes
+pub struct FileSpill {
+    in_memory_limit: u64,
+    in_memory: u64,
+    /// Created on the first spill, most scans never need it
+    file: Option<Arc<File>>,
+    end: u64,
+}
+
+impl FileSpill {
+    /// Keeps up to `in_memory_limit` files in memory, then spills
+    pub fn new(in_memory_limit: u64) -> Self {
+        Self {
+            in_memory_limit,
+            in_memory: 0,
+            file: None,
+            end: 0,
+        }
+    }
+
+    /// Keeps the files of a finished folder, in memory while they fit under the limit
+    pub fn store(&mut self, files: Vec<FileStat>) -> Result<DirectFiles> {
+        if self.in_memory + files.len() as u64 <= self.in_memory_limit {
+            self.in_memory += files.len() as u64;
+            return Ok(DirectFiles::Loaded(files));
+        }
+        let file = match &self.file {
+            Some(file) => file.clone(),
+            None => {
+                let file = tempfile::tempfile().wrap_err("Failed to create a temporary file to spill the scan to")?;
+                self.file.insert(Arc::new(file)).clone()
+            }
+        };
+        let mut data = Vec::new();
+        for stat in files.iter() {
+            encode(&mut data, stat);
+        }
+        file.write_all_at(&data, self.end)
+            .wrap_err("Failed to spill the scanned files to disk")?;
+        let spilled = SpilledFiles {
+            file,
+            offset: self.end,
+            len: data.len(),
+            count: files.len(),
+            total_size: files.iter().map(|file| file.size).sum(),
+        };
+        self.end += data.len() as u64;
+        Ok(DirectFiles::Spilled(spilled))
+    }
+}
+
+fn encode(data: &mut Vec<u8>, stat: &FileStat) {
+    let path = stat.rel_path.as_os_str().as_bytes();
+    data.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    data.extend_from_slice(path);
+    data.extend_from_slice(&stat.last_modified.to_le_bytes());
+    data.extend_from_slice(&stat.mode.to_le_bytes());
+    data.extend_from_slice(&stat.size.to_le_bytes());
+    match stat.birthtime {
+        Some(birthtime) => {
+            data.push(1);
+            data.extend_from_slice(&birthtime.to_le_bytes());
+        }
+        None => data.push(0),
+    }
+}
+
+fn decode(data: &mut &[u8]) -> Result<FileStat> {
+    let path_len = u32::from_le_bytes(take(data)?) as usize;
+    ensure!(data.len() >= path_len, "Spilled file list is truncated");
+    let (path, rest) = data.split_at(path_len);
+    *data = rest;
+    let rel_path = PathBuf::from(OsStr::from_bytes(path));
+    let last_modified = u64::from_le_bytes(take(data)?);
+    let mode = u32::from_le_bytes(take(data)?);
+    let size = u64::from_le_bytes(take(data)?);
+    let birthtime = match take::<1>(data)? {
+        [0] => None,
+        _ => Some(u64::from_le_bytes(take(data)?)),
+    };
+    Ok(FileStat {
+        rel_path,
+        last_modified,
+        mode,
+        size,
+        birthtime,
+    })
+}
+
+fn take<const N: usize>(data: &mut &[u8]) -> Result<[u8; N]> {
+    ensure!(data.len() >= N, "Spilled file list is truncated");
+    let (bytes, rest) = data.split_at(N);
+    *data = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirdb::dirstat::DirStat;
+    use std::path::Path;
+
+    fn all_files(stat: &DirStat, files: &mut Vec<FileStat>) -> Result<()> {
+        files.extend_from_slice(&stat.direct_files.as_ref().unwrap().load()?);
+        for subfolder in stat.subfolders.iter() {
+            all_files(subfolder, files)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn spilled_scan_reads_back_the_same_files() -> Result<()> {
+        let path = Path::new("test_data");
+        let in_memory = DirStat::new(path, path)?;
+        let spill = &mut FileSpill::new(2);
+        let spilled = DirStat::new_skipping(path, path, &[], &mut Vec::new(), spill, None)?;
+        assert!(spill.end > 0);
+        assert_eq!(spilled, in_memory);
+        assert_eq!(spilled.total_size(), in_memory.total_size());
+
+        let (mut expected, mut actual) = (Vec::new(), Vec::new());
+        all_files(&in_memory, &mut expected)?;
+        all_files(&spilled, &mut actual)?;
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+}
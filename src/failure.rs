@@ -0,0 +1,99 @@
+use eyre::Report;
+use std::fmt::{self, Display, Formatter};
+
+/// Exit code when an error doesn't fall in any of the more specific `Failure` classes
+pub const EXIT_CODE_OTHER_FAILURE: i32 = 5;
+
+/// Broad classes of failures, each with their own exit code so wrapper scripts can react to them
+///
+/// These are attached to an eyre report either as the root error or as context (with `wrap_err`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The command ran to completion, but some individual operations failed
+    Incomplete { errors: usize },
+    /// Invalid configuration, wrong password, or Backblaze B2 refusing our credentials
+    Config,
+    /// The backup folder is locked by another operation
+    LockConflict,
+    /// The user pressed Ctrl+C
+    Interrupted,
+    /// A daily cap of the Backblaze B2 account was reached, see `net::cap`
+    CapReached,
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Incomplete { .. } => 1,
+            Failure::Config => 2,
+            Failure::LockConflict => 3,
+            Failure::Interrupted => 4,
+            Failure::CapReached => 6,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Incomplete { errors } => write!(f, "Couldn't complete all operations, {} error(s)", errors),
+            Failure::Config => write!(f, "Configuration or authentication error"),
+            Failure::LockConflict => write!(f, "Backup folder is locked"),
+            Failure::Interrupted => write!(f, "Interrupted by Ctrl+C"),
+            Failure::CapReached => write!(f, "Daily cap of the Backblaze B2 account reached, resume tomorrow"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Picks the process exit code for an error report
+pub fn exit_code_for(err: &Report) -> i32 {
+    match err.downcast_ref::<Failure>() {
+        Some(failure) => failure.exit_code(),
+        None => EXIT_CODE_OTHER_FAILURE,
+    }
+}
+
+/// Describes the exit codes, for the command line help
+pub fn exit_codes_help() -> String {
+    format!(
+        "Exit codes:\n  0  Success\n  1  Completed, but some files failed\n  2  Configuration or authentication \
+         error\n  3  Backup folder is locked\n  4  Interrupted\n  {}  Other failure\n  6  Daily B2 cap reached",
+        EXIT_CODE_OTHER_FAILURE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::{eyre, WrapErr};
+
+    #[test]
+    fn root_failure_exit_code() {
+        let err: Report = Failure::Interrupted.into();
+        assert_eq!(exit_code_for(&err), 4);
+        let err = err.wrap_err("restore failed");
+        assert_eq!(exit_code_for(&err), 4);
+    }
+
+    #[test]
+    fn cap_failure_exit_code() {
+        let err = eyre!("Transaction cap exceeded").wrap_err(Failure::CapReached);
+        assert_eq!(exit_code_for(&err), 6);
+        assert!(exit_codes_help().contains("6  Daily B2 cap reached"));
+    }
+
+    #[test]
+    fn context_failure_exit_code() {
+        let err: Result<(), Report> = Err(eyre!("Backblaze B2 login failure"));
+        let err = err.wrap_err(Failure::Config).wrap_err("backup failed").unwrap_err();
+        assert_eq!(exit_code_for(&err), 2);
+    }
+
+    #[test]
+    fn unclassified_exit_code() {
+        let err = eyre!("Download failed").wrap_err("restore failed");
+        assert_eq!(exit_code_for(&err), EXIT_CODE_OTHER_FAILURE);
+    }
+}
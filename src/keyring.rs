@@ -0,0 +1,47 @@
+//! Keeps the encryption key in the desktop keyring (through libsecret's `secret-tool`) instead of a keyfile
+
+use crate::crypto::{Key, SecretString};
+use data_encoding::HEXLOWER;
+use eyre::{bail, eyre, Result, WrapErr};
+use sodiumoxide::utils::memzero;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const ATTRIBUTES: [&str; 4] = ["service", "frozen", "key", "encryption"];
+
+pub fn store(key: &Key) -> Result<()> {
+    let mut child = Command::new("secret-tool")
+        .arg("store")
+        .arg("--label=Frozen backup encryption key")
+        .args(ATTRIBUTES)
+        .stdin(Stdio::piped())
+        .spawn()
+        .wrap_err("Failed to run secret-tool, is libsecret installed?")?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(SecretString::from(HEXLOWER.encode(&key.0)).expose().as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("secret-tool failed to store the key in the keyring");
+    }
+    Ok(())
+}
+
+pub fn load() -> Result<Key> {
+    let mut output = Command::new("secret-tool")
+        .arg("lookup")
+        .args(ATTRIBUTES)
+        .stderr(Stdio::inherit())
+        .output()
+        .wrap_err("Failed to run secret-tool, is libsecret installed?")?;
+    if !output.status.success() {
+        bail!("The key is not in the keyring");
+    }
+    let decoded = HEXLOWER.decode(output.stdout.trim_ascii());
+    memzero(&mut output.stdout);
+    let mut key_bytes = decoded.map_err(|_| eyre!("The key in the keyring is invalid"))?;
+    let key = Key::from_slice(&key_bytes);
+    memzero(&mut key_bytes);
+    key.ok_or_else(|| eyre!("The key in the keyring is invalid"))
+}
@@ -0,0 +1,34 @@
+//! Core of the frozen backup tool: encrypted and compressed backups to Backblaze B2
+//!
+//! The main entry points are `session::BackupSession` and `session::RestoreSession`, which run
+//! against any storage implementing `net::backend::Backend` (`net::b2::B2` for Backblaze B2, or
+//! `net::memory::MemoryBackend` for tests).
+//!
+//! A typical backup looks like this:
+//! 1. Load a `config::Config` and derive the `crypto::AppKeys` from it
+//! 2. Authenticate with `net::b2::B2::authenticate`
+//! 3. Fetch the backup roots with `data::root::fetch_roots`, then open and lock one
+//! 4. Run a `BackupSession`, then unlock the root
+//!
+//! The library doesn't print anything, its messages go to the handler given to `progress::set_log_handler`.
+
+pub mod action;
+pub mod config;
+pub mod crypto;
+pub mod data;
+pub mod dirdb;
+pub mod failure;
+pub mod keyring;
+pub mod mnemonic;
+pub mod net;
+pub mod notifications;
+pub mod output;
+pub mod password;
+pub mod progress;
+pub mod session;
+pub mod snapshot;
+pub mod stats;
+pub mod stream;
+
+#[cfg(test)]
+mod test_helpers;
@@ -1,44 +1,160 @@
-use crate::config::Config;
+use crate::prompt::TerminalPrompt;
 use clap::{arg, Command};
 use eyre::{Result, WrapErr};
+use frozen_core::config::Config;
+use frozen_core::failure::{exit_code_for, exit_codes_help};
+use frozen_core::net::chaos::ChaosOptions;
+use frozen_core::output::{Cell, Listing, OutputFormat};
+use frozen_core::progress::{self, LogCategory, Verbosity};
+use frozen_core::stats;
+use frozen_core::stream::cpu_pool;
 use std::ffi::OsString;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::Arc;
 
-mod action;
 mod cmd;
-mod config;
-mod crypto;
-mod data;
-mod dirdb;
-mod net;
-mod progress;
 mod prompt;
 mod signal;
-mod stream;
-
-#[cfg(test)]
-mod test_helpers;
+mod systemd;
 
 #[tokio::main]
 async fn async_main() -> Result<()> {
     let args = Command::new("Frozen Backup")
         .about("Encrypted and compressed backups to Backblaze B2")
-        .arg(arg!(-v --verbose "Log every file transferred"))
+        .after_help(exit_codes_help())
+        .arg(
+            arg!(-v --verbose "Log every file transferred, -vv also every retry, -vvv also the diff decisions")
+                .action(clap::ArgAction::Count),
+        )
+        .arg(
+            arg!(--log <categories> "Log only these, comma-separated: transfers, retries, diff (instead of -v levels)")
+                .value_delimiter(','),
+        )
+        .arg(
+            arg!(--chaos <probability> "Randomly disrupt this fraction of network operations, for testing")
+                .value_parser(clap::value_parser!(f64))
+                .hide(true),
+        )
+        .arg(
+            arg!(--"chaos-seed" <seed> "Seed for --chaos, to replay the same faults")
+                .value_parser(clap::value_parser!(u64))
+                .hide(true),
+        )
+        .arg(
+            arg!(--"upload-threads" <count> "Upload this many files at once, instead of the configured number")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .global(true),
+        )
+        .arg(
+            arg!(--"download-threads" <count> "Download this many files at once, instead of the configured number")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .global(true),
+        )
+        .arg(
+            arg!(--"delete-threads" <count> "Delete this many files at once, instead of the configured number")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .global(true),
+        )
+        .arg(
+            arg!(--"compression-level" <level> "Compress uploads with this zstd level (1-22), instead of the configured one")
+                .value_parser(clap::value_parser!(i32).range(1..=22))
+                .global(true),
+        )
+        .arg(
+            arg!(--"min-compression-level" <level> "Lower the compression level down to this one when compressing is the bottleneck")
+                .value_parser(clap::value_parser!(i32).range(1..=22))
+                .global(true),
+        )
+        .arg(
+            arg!(--"cpu-threads" <count> "Compress, encrypt and hash this many chunks at once, instead of the configured number")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .global(true),
+        )
+        .arg(arg!(--stats "Show the time spent compressing, encrypting, and transferring data at the end").global(true))
+        .arg(
+            arg!(--"machine-threads" <count> "Share this many transfers between every frozen process running on this machine")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .global(true),
+        )
         .subcommand_required(true)
-        .subcommand(Command::new("list").about("List the currently backup up folders"))
+        .subcommand(
+            Command::new("list")
+                .about("List the currently backup up folders")
+                .arg(arg!(--format <format> "Output as a table, or as json or csv for scripts").default_value("table"))
+                .arg(arg!(--filter <glob> "Only list folders whose path matches this pattern (e.g. \"/home/*\")"))
+                .arg(arg!(--tag <tag> "Only list folders with this tag, as key or key=value")),
+        )
         .subcommand(
             Command::new("backup")
                 .about("Backup a folder, encrypted and compressed, to the cloud")
                 .arg(arg!(-k --"keep-existing" "Keep remote files that have been deleted locally"))
+                .arg(
+                    arg!(--"delete-after" <duration> "Only delete remote files once they've been deleted locally for this long (e.g. 7d)")
+                        .conflicts_with("keep-existing"),
+                )
+                .arg(arg!(--"strict-scan" "Fail without changing anything if some files can't be read, instead of skipping them"))
+                .arg(arg!(--"scan-cache" "Reuse what the last backups found in folders whose mtime didn't change, instead of statting every file. Files modified in place in those folders are missed for up to a week"))
+                .arg(arg!(--snapshot <kind> "Back up from a snapshot of the source taken with btrfs, zfs, lvm or the configured command"))
+                .arg(arg!(--"min-age" <duration> "Leave files modified less than this long ago for the next backup, they may still be written (e.g. 10m)"))
+                .arg(arg!(--verify "Check with the remote that every upload was stored intact"))
+                .arg(arg!(--"include-keyfile" "Back up frozen's keyfile if it's in the source, it's left out by default"))
+                .arg(arg!(--"exclude-config" "Also leave out frozen's config file if it's in the source"))
+                .arg(arg!(--"save-options" "Remember the options of this backup (or their absence) for every future backup of this folder"))
+                .arg(arg!(--"diff-strategy" <strategy> "How to list the remote folders: auto, always-deep or always-shallow (for debugging)"))
+                .arg(arg!(--"sd-notify" "Report readiness and progress to systemd, for Type=notify services"))
+                .arg(arg!(--bucket <name> "Store a new backup in this bucket of the account, instead of the configured one"))
+                .arg(
+                    arg!(--only <subdir> "Only back up this folder of an existing backup, relative to the source")
+                        .value_parser(clap::value_parser!(OsString)),
+                )
                 .arg(arg!(<source> "The source folder to backup").value_parser(clap::value_parser!(OsString)))
                 .arg(
                     arg!([destination] "Save the back up under a different path")
                         .value_parser(clap::value_parser!(OsString)),
                 ),
         )
+        .subcommand(
+            Command::new("backup-exec")
+                .about("Back up the output of a command (e.g. pg_dump) as a file of a backup, without storing it locally")
+                .arg(
+                    arg!(--name <file> "The path of the output in the backup (default: <program>.out)")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(arg!(--bucket <name> "Store a new backup in this bucket of the account, instead of the configured one"))
+                .arg(arg!(<target> "The backup to save the output in, it only holds outputs of commands").value_parser(clap::value_parser!(OsString)))
+                .arg(
+                    arg!(<command> "The command and its arguments, after --")
+                        .num_args(1..)
+                        .last(true)
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
         .subcommand(
             Command::new("restore")
                 .about("Restore a backed up folder")
+                .arg(
+                    arg!(--"strip-prefix" <count> "Remove this many leading folders from the restored paths")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--relocate <move> "Restore a folder of the backup somewhere else, as <from>=<to> (repeatable)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    arg!(--first <glob> "Download the files matching this glob, or in a folder that matches, before the rest (repeatable)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    arg!(--staging <dir> "Restore into this folder first, and only swap it with the destination once complete")
+                        .value_parser(clap::value_parser!(OsString)),
+                )
+                .arg(
+                    arg!(--"to-stdout-tar" "Write the files to stdout as a tar archive, e.g. to pipe into tar -x over ssh, instead of restoring them")
+                        .conflicts_with_all(["destination", "staging", "force", "overwrite", "first"]),
+                )
+                .arg(arg!(--force "Restore into a folder that isn't empty, replacing files older than their backup"))
+                .arg(arg!(--overwrite <policy> "Which local files that differ from the backup are replaced: never, older (the default) or always. Allows restoring into a folder that isn't empty"))
                 .arg(arg!(<source> "The backed up folder to restore").value_parser(clap::value_parser!(OsString)))
                 .arg(
                     arg!([destination] "Path to save the downloaded folder")
@@ -53,15 +169,217 @@ async fn async_main() -> Result<()> {
         .subcommand(
             Command::new("unlock")
                 .about("Force unlocking a folder after an interrupted backup. Dangerous.")
+                .arg(arg!(--"older-than" <duration> "Only remove locks not refreshed for this long (e.g. 30m, 12h, 7d)"))
                 .arg(
                     arg!(<target> "The backed up folder to forcibly unlock")
                         .value_parser(clap::value_parser!(OsString)),
                 ),
         )
+        .subcommand(
+            Command::new("history")
+                .about("Show when a folder was backed up, and what changed each time")
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Show everything the backup knows about a file, to debug why it keeps being uploaded")
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString)))
+                .arg(arg!(<path> "The file in the backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("tree")
+                .about("Show the folders of a backup with their number of files, from the DirDB")
+                .arg(
+                    arg!(--depth <depth> "Only show folders down to this depth")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(arg!(--sizes "Also show the stored size of each folder, which needs to list every file"))
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("share")
+                .about("Print a link that downloads one backed up file without the backup key, until it expires")
+                .arg(arg!(--expires <duration> "How long the link works, at most 7d (default 24h)"))
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString)))
+                .arg(arg!(<path> "The file in the backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Clean up uploads left unfinished by interrupted backups, and expired share links")
+                .arg(arg!(--"older-than" <duration> "Only cancel uploads started longer ago than this (default 1d)"))
+                .arg(
+                    arg!([target] "Only clean up this backed up folder")
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Delete the old versions of backed up files, keeping the latest one of each file")
+                .arg(arg!(--"older-than" <duration> "Only delete versions replaced longer ago than this (default 30d)"))
+                .arg(arg!(<target> "The backed up folder to prune").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("lifecycle")
+                .about("Show or change the lifecycle rules that make B2 delete hidden file versions by itself")
+                .arg(arg!(--prefix <prefix> "The file name prefix of the rule to change (default: the whole bucket)"))
+                .arg(
+                    arg!(--"hide-after-days" <days> "Hide files this many days after they're uploaded")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    arg!(--"delete-after-days" <days> "Delete hidden files this many days after they're hidden")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(arg!(--clear "Remove the rule for the prefix"))
+                .arg(arg!(--format <format> "Output as a table, or as json or csv for scripts").default_value("table")),
+        )
+        .subcommand(
+            Command::new("install-timer")
+                .about("Install a systemd timer backing up a folder every night")
+                .arg(arg!(--at <time> "Time of day of the backup, in local time").default_value("03:00"))
+                .arg(
+                    arg!(--system "Install a system unit in /etc/systemd/system, instead of a user unit")
+                        .requires("user"),
+                )
+                .arg(
+                    arg!(--user <name> "The user a system unit runs as, whose config and keyfile it uses")
+                        .requires("system"),
+                )
+                .arg(arg!(--print "Only print the units instead of installing them"))
+                .arg(arg!(<source> "The folder to backup").value_parser(clap::value_parser!(OsString)))
+                .arg(
+                    arg!([destination] "Save the back up under a different path")
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
         .subcommand(
             Command::new("save-key")
                 .about("Saves a keyfile on this computer that will be used instead of your backup password."),
         )
+        .subcommand(
+            Command::new("config")
+                .about("Manage where the configuration and encryption key are stored")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("migrate")
+                        .about("Move the configuration and keyfile to another folder, or the key to the keyring")
+                        .arg(
+                            arg!(--to <dir> "Move the config file and keyfile to this folder")
+                                .value_parser(clap::value_parser!(OsString)),
+                        )
+                        .arg(arg!(--keyring "Move the encryption key to the desktop keyring, instead of a keyfile")),
+                ),
+        )
+        .subcommand(
+            Command::new("create-key")
+                .about("Create a B2 app key that can only access the backup bucket, from a key that can manage the account")
+                .arg(arg!(--name <name> "Name of the new key (default: frozen-<bucket>)"))
+                .arg(arg!(--save "Use the new key in the configuration from now on")),
+        )
+        .subcommand(
+            Command::new("export-key").about("Print your encryption key as a BIP39 recovery phrase to write down"),
+        )
+        .subcommand(
+            Command::new("import-key")
+                .about("Recover your encryption key from its recovery phrase, and save it as the keyfile"),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure the speed of compression, encryption, uploads and downloads with a test object")
+                .arg(
+                    arg!(--size <mib> "Size of the test object in MiB")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("64"),
+                )
+                .arg(arg!(--format <format> "Output as a table, or as json or csv for scripts").default_value("table")),
+        )
+        .subcommand(
+            Command::new("self-test")
+                .about("Check that encryption, compression and the DirDB work on this machine, and that the bucket is usable")
+                .arg(arg!(--offline "Only run the local checks, without connecting to the bucket")),
+        )
+        .subcommand(
+            Command::new("test-restore")
+                .about("Restore a random sample of a backup into a temporary folder, and compare it with the local files")
+                .arg(
+                    arg!(--sample <count> "How many files to restore")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(arg!(<backup> "The backed up folder to test").value_parser(clap::value_parser!(OsString)))
+                .arg(
+                    arg!([local] "Where the live files are, if not at the backed up path")
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("audit")
+                .about("Show every backup, restore and delete of a folder, from any machine, even after it was deleted")
+                .arg(arg!(--bucket <name> "Look in this bucket of the account, for a deleted backup stored elsewhere"))
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("manifest")
+                .about("Export the decrypted list of files of a backup as JSON, with their metadata and object names")
+                .arg(
+                    arg!(-o --output <file> "Write the manifest to this file instead of stdout")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(arg!(<backup> "The backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("import-manifest")
+                .about("Register files already uploaded with a compatible key into a backup, from a manifest")
+                .arg(arg!(<manifest> "The manifest listing the files").value_parser(clap::value_parser!(PathBuf)))
+                .arg(
+                    arg!([backup] "The backed up folder, if not the one in the manifest")
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Diagnose the configuration, app key, bucket, clock and leftover state, and suggest fixes"),
+        )
+        .subcommand(
+            Command::new("describe")
+                .about("Set the description and tags of a backed up folder, shown by list, or show them")
+                .arg(arg!(--description <text> "Describe what the folder is, an empty text removes the description"))
+                .arg(arg!(--tag <tag> "Add a tag, as key or key=value (repeatable)").action(clap::ArgAction::Append))
+                .arg(arg!(--untag <key> "Remove the tag with this key (repeatable)").action(clap::ArgAction::Append))
+                .arg(arg!(<target> "The backed up folder").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Make a backup mirror another one, copying files on the server without downloading them")
+                .arg(arg!(<source> "The backup to copy from").value_parser(clap::value_parser!(OsString)))
+                .arg(
+                    arg!(<target> "The backup that becomes a copy of the source, created next to it if needed")
+                        .value_parser(clap::value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("clone")
+                .about("Copy a backup under a new path on the server, e.g. to keep it restorable before restructuring")
+                .arg(arg!(<backup> "The backup to clone").value_parser(clap::value_parser!(OsString)))
+                .arg(arg!(<"new-path"> "The path of the clone, which must not be backed up yet").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Move the files of a backup into another on the server, keeping the newest of each file")
+                .arg(arg!(<"src-root"> "The backup to merge, deleted once its files are moved").value_parser(clap::value_parser!(OsString)))
+                .arg(arg!(<"dst-root"> "The backup that receives the files").value_parser(clap::value_parser!(OsString))),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Show the last backups run on this machine, and how their uploads, durations and errors trend")
+                .arg(
+                    arg!(--last <count> "How many runs to show")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(arg!(--format <format> "Output as a table, or as json or csv for scripts").default_value("table"))
+                .arg(arg!([backup] "Only show the backups of this folder").value_parser(clap::value_parser!(OsString))),
+        )
         .subcommand(
             Command::new("rename")
                 .about("Rename a backed-up folder on the server.")
@@ -70,27 +388,112 @@ async fn async_main() -> Result<()> {
         )
         .get_matches();
 
-    let config = Config::get_or_create(args.get_flag("verbose"));
-    match args.subcommand().unwrap() {
+    let verbosity = match args.get_many::<String>("log") {
+        Some(names) => {
+            let categories = names
+                .map(|name| LogCategory::from_name(name))
+                .collect::<Result<Vec<_>>>()?;
+            Verbosity::only(&categories)
+        }
+        None => Verbosity::from_level(args.get_count("verbose")),
+    };
+    let mut config = Config::get_or_create(verbosity, Arc::new(TerminalPrompt));
+    if let Some(&probability) = args.get_one::<f64>("chaos") {
+        config.chaos = Some(ChaosOptions::new(
+            probability,
+            args.get_one::<u64>("chaos-seed").copied(),
+        )?);
+    }
+    // Overrides only apply to this run, they are never saved in the config file
+    let sub_args = args.subcommand().unwrap().1;
+    if let Some(&threads) = sub_args.get_one::<u16>("upload-threads") {
+        config.upload_threads = threads;
+    }
+    if let Some(&threads) = sub_args.get_one::<u16>("download-threads") {
+        config.download_threads = threads;
+    }
+    if let Some(&threads) = sub_args.get_one::<u16>("delete-threads") {
+        config.delete_threads = threads;
+    }
+    if let Some(&level) = sub_args.get_one::<i32>("compression-level") {
+        config.compression_level = level;
+    }
+    if let Some(&level) = sub_args.get_one::<i32>("min-compression-level") {
+        config.min_compression_level = Some(level);
+    }
+    if let Some(&threads) = sub_args.get_one::<u16>("machine-threads") {
+        config.machine_threads = Some(threads);
+    }
+    if let Some(&threads) = sub_args.get_one::<u16>("cpu-threads") {
+        config.cpu_threads = Some(threads);
+    }
+    if let Some(threads) = config.cpu_threads {
+        cpu_pool::set_threads(threads as usize);
+    }
+    let result = match args.subcommand().unwrap() {
         ("backup", sub_args) => cmd::backup(&config, sub_args).await,
+        ("backup-exec", sub_args) => cmd::backup_exec(&config, sub_args).await,
         ("restore", sub_args) => cmd::restore(&config, sub_args).await,
         ("delete", sub_args) => cmd::delete(&config, sub_args).await,
         ("unlock", sub_args) => cmd::unlock(&config, sub_args).await,
         ("list", sub_args) => cmd::list(&config, sub_args).await,
         ("rename", sub_args) => cmd::rename(&config, sub_args).await,
+        ("sync", sub_args) => cmd::sync(&config, sub_args).await,
+        ("clone", sub_args) => cmd::clone(&config, sub_args).await,
+        ("merge", sub_args) => cmd::merge(&config, sub_args).await,
+        ("history", sub_args) => cmd::history(&config, sub_args).await,
+        ("report", sub_args) => cmd::report(&config, sub_args).await,
+        ("info", sub_args) => cmd::info(&config, sub_args).await,
+        ("tree", sub_args) => cmd::tree(&config, sub_args).await,
+        ("share", sub_args) => cmd::share(&config, sub_args).await,
+        ("gc", sub_args) => cmd::gc(&config, sub_args).await,
+        ("prune", sub_args) => cmd::prune(&config, sub_args).await,
+        ("lifecycle", sub_args) => cmd::lifecycle(&config, sub_args).await,
+        ("install-timer", sub_args) => cmd::install_timer(&config, sub_args).await,
         ("save-key", sub_args) => cmd::save_key(&config, sub_args).await,
+        ("config", sub_args) => cmd::config(&config, sub_args).await,
+        ("create-key", sub_args) => cmd::create_key(&config, sub_args).await,
+        ("export-key", sub_args) => cmd::export_key(&config, sub_args).await,
+        ("import-key", sub_args) => cmd::import_key(&config, sub_args).await,
+        ("bench", sub_args) => cmd::bench(&config, sub_args).await,
+        ("self-test", sub_args) => cmd::self_test(&config, sub_args).await,
+        ("doctor", sub_args) => cmd::doctor(&config, sub_args).await,
+        ("audit", sub_args) => cmd::audit(&config, sub_args).await,
+        ("manifest", sub_args) => cmd::manifest(&config, sub_args).await,
+        ("import-manifest", sub_args) => cmd::import_manifest(&config, sub_args).await,
+        ("describe", sub_args) => cmd::describe(&config, sub_args).await,
+        ("test-restore", sub_args) => cmd::test_restore(&config, sub_args).await,
         _ => unreachable!(),
+    };
+    if sub_args.get_flag("stats") {
+        print_stats();
+    }
+    result.wrap_err_with(|| format!("\r{} failed", args.subcommand_name().unwrap()))
+}
+
+fn print_stats() {
+    let mut listing = Listing::new(&["stage", "calls", "bytes", "seconds", "throughput"]);
+    for stage in stats::snapshot() {
+        listing.push(vec![
+            Cell::text(stage.stage.name()),
+            Cell::number(stage.calls),
+            Cell::size(stage.bytes),
+            Cell::text(format!("{:.2}", stage.time.as_secs_f64())),
+            stage.bytes_per_sec().map(Cell::throughput).into(),
+        ]);
     }
-    .wrap_err_with(|| format!("\r{} failed", args.subcommand_name().unwrap()))
+    println!("\nTime spent in each stage, summed over all threads:");
+    print!("{}", listing.render(OutputFormat::Table));
 }
 
 fn main() {
     sodiumoxide::init().expect("Failed to initialize the crypto library");
+    progress::set_log_handler(|msg| println!("{}", msg));
     let return_code = match async_main() {
         Ok(()) => 0,
         Err(err) => {
             eprintln!("{:#}", err);
-            1
+            exit_code_for(&err)
         }
     };
     exit(return_code);
@@ -0,0 +1,115 @@
+//! Recovery phrases for the encryption key, so it can be written down on paper
+//!
+//! A phrase is the standard BIP39 mnemonic of the key: 24 words of the English list, whose last bits are the first
+//! byte of the key's SHA-256, so that most typos are caught. Any BIP39 tool can check it.
+
+use crate::crypto::Key;
+use bip39::{Language, Mnemonic};
+use eyre::{bail, eyre, Result};
+use sodiumoxide::crypto::secretbox::KEYBYTES;
+use sodiumoxide::utils::memzero;
+
+/// 11 bits per word, for the 256 bits of the key and 8 bits of checksum
+const WORD_COUNT: usize = (KEYBYTES * 8 + 8) / 11;
+/// The first 4 letters of each word of the list are unique, so typing only those is enough
+const PREFIX_LEN: usize = 4;
+
+/// The key as a BIP39 phrase
+pub fn key_to_phrase(key: &Key) -> String {
+    let Key(keydata) = key;
+    Mnemonic::from_entropy_in(Language::English, keydata)
+        .expect("Keys have a valid BIP39 entropy size")
+        .to_string()
+}
+
+/// Decodes a phrase from `key_to_phrase`, ignoring case and extra whitespace
+pub fn key_from_phrase(phrase: &str) -> Result<Key> {
+    let list = Language::English.word_list();
+    let words = phrase
+        .split_whitespace()
+        .enumerate()
+        .map(|(index, word)| {
+            let word = word.to_lowercase();
+            let prefix = &word[..word.char_indices().nth(PREFIX_LEN).map_or(word.len(), |(pos, _)| pos)];
+            list.iter()
+                .find(|candidate| **candidate == word || (prefix.len() == PREFIX_LEN && candidate.starts_with(prefix)))
+                .copied()
+                // The word itself isn't shown, it's part of the key
+                .ok_or_else(|| eyre!("Word {} is not a word of recovery phrases", index + 1))
+        })
+        .collect::<Result<Vec<&str>>>()?;
+    if words.len() != WORD_COUNT {
+        bail!(
+            "A recovery phrase has {} words, but this one has {}",
+            WORD_COUNT,
+            words.len()
+        );
+    }
+
+    let mut words = words.join(" ").into_bytes();
+    let mnemonic = Mnemonic::parse_in_normalized(Language::English, std::str::from_utf8(&words)?);
+    memzero(&mut words);
+    let mnemonic = mnemonic.map_err(|err| match err {
+        bip39::Error::InvalidChecksum => {
+            eyre!("Wrong checksum, some words of the recovery phrase are wrong or in the wrong order")
+        }
+        err => eyre!("Invalid recovery phrase: {}", err),
+    })?;
+    let (mut entropy, len) = mnemonic.to_entropy_array();
+    let key = Key::from_slice(&entropy[..len]);
+    memzero(&mut entropy);
+    key.ok_or_else(|| eyre!("A recovery phrase has {} words", WORD_COUNT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::derive_key;
+    use data_encoding::HEXLOWER;
+    use std::collections::HashSet;
+
+    #[test]
+    fn phrase_roundtrip() -> Result<()> {
+        let list = Language::English.word_list();
+        let prefixes: HashSet<&str> = list.iter().map(|word| &word[..word.len().min(PREFIX_LEN)]).collect();
+        assert_eq!(prefixes.len(), list.len());
+
+        let key = derive_key("pass", "bucket");
+        let phrase = key_to_phrase(&key);
+        assert_eq!(phrase.split(' ').count(), 24);
+        assert_eq!(key_from_phrase(&phrase)?, key);
+
+        // Only the first 4 letters count
+        let short: Vec<String> = phrase
+            .split(' ')
+            .map(|word| word[..word.len().min(PREFIX_LEN)].to_uppercase())
+            .collect();
+        assert_eq!(key_from_phrase(&format!("  {}\n", short.join("  ")))?, key);
+
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        if words[0] != words[1] {
+            assert!(key_from_phrase(&words.join(" ")).is_err());
+        }
+        assert!(key_from_phrase(&phrase.replacen(' ', " nonsense ", 1)).is_err());
+        assert!(key_from_phrase(words[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn phrases_are_standard_bip39() -> Result<()> {
+        // Test vectors of the BIP39 reference implementation
+        let zeros = Key::from_slice(&[0; KEYBYTES]).unwrap();
+        assert_eq!(key_to_phrase(&zeros), format!("{}art", "abandon ".repeat(23)));
+        let ones = Key::from_slice(&[0xff; KEYBYTES]).unwrap();
+        assert_eq!(key_to_phrase(&ones), format!("{}vote", "zoo ".repeat(23)));
+        let phrase = "void come effort suffer camp survey warrior heavy shoot primary clutch crush open amazing \
+                      screen patrol group space point ten exist slush involve unfold";
+        let key = key_from_phrase(phrase)?;
+        assert_eq!(
+            HEXLOWER.encode(&key.0),
+            "f585c11aec520db57dd353c69554b21a89b20fb0650966fa0a9d6f74fd989d8f"
+        );
+        Ok(())
+    }
+}
@@ -1,54 +1,258 @@
 use crate::config::Config;
-use crate::crypto::{self, decode_meta, encode_meta, sha1_string, AppKeys};
-use crate::data::file::{RemoteFile, RemoteFileVersion};
-use crate::progress::ProgressHandler;
-use crate::stream::{HashedStream, SimpleBytesStream};
+use crate::crypto::{self, decode_meta, encode_meta, sha1_string, AppKeys, SecretString};
+use crate::data::file::{FileMeta, RemoteFile, RemoteFileVersion};
+use crate::data::names::ObjectNames;
+use crate::failure::Failure;
+use crate::net::backend::{
+    Backend, FileListDepth, FileVersionInfo, NotFound, SizedDownload, UploadStream, UploadTarget, VersionsPage,
+};
+use crate::net::cap;
+use crate::net::chaos::RequestChaos;
+use crate::net::endpoint::ConnectionOptions;
+use crate::net::health::{self, Connectivity};
+use crate::net::lifecycle::LifecycleRule;
+use crate::net::replayable_body::ReplayableBody;
+use crate::net::retention::{Retention, VersionLocked};
+use crate::net::retry::{self, parse_retry_after, ErrorClass, RetryAction, RetryBudget, RetryLimits};
+use crate::net::s3::{self, S3Endpoint, TransferApi};
+use crate::net::sse::ServerSideEncryption;
+use crate::progress::{self, LogCategory, ProgressHandler};
+use crate::stats::{self, Stage, TimedStream};
+use crate::stream::HashedStream;
+use async_stream::try_stream;
 use bytes::Bytes;
 use data_encoding::BASE64_NOPAD;
-use eyre::{bail, ensure, eyre, Result};
+use eyre::{bail, eyre, Result, WrapErr};
+use futures::future::{BoxFuture, FutureExt};
 use futures::stream::BoxStream;
-use futures::{Stream, StreamExt};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
-use reqwest::{tls, Body, Client, ClientBuilder, Response, StatusCode, Url};
-use serde_json::{self, json, Value};
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{tls, Client, ClientBuilder, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
 use std::future::Future;
 use std::iter::FromIterator;
 use std::path::Path;
-use std::str::{from_utf8, FromStr};
-use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-#[derive(Copy, Clone)]
-pub enum FileListDepth {
-    Shallow,
-    // List only files in the current "folder"
-    Deep, // List every file recursively
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::{spawn_blocking, JoinHandle};
+
+mod reply;
+mod request;
+
+/// Where accounts are authorized, which tells the API URL of the account
+const AUTHORIZE_ENDPOINT: &str = "api.backblazeb2.com";
+
+/// How many listed files are decoded together by one blocking task
+const DECODE_BATCH_SIZE: usize = 500;
+
+/// How many listing pages can be received ahead of the one being consumed
+const LIST_PREFETCH_PAGES: usize = 1;
+
+/// Files larger than this can't be copied by b2_copy_file, they're copied part by part
+const COPY_FILE_MAX_SIZE: u64 = 5_000_000_000;
+
+/// The size of each part copied with b2_copy_part
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// A file from a listing reply, with its metadata still encrypted
+struct ListedFile {
+    full_name: String,
+    id: String,
+    enc_meta: String,
+    size: u64,
+}
+
+/// What a listed file version is, the "action" of B2's listings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileAction {
+    /// Data that was uploaded
+    Upload,
+    /// A tombstone: the file is deleted as far as backups go, while its older versions wait to be pruned
+    Hide,
+    /// A large file that was started but never finished
+    Start,
+    /// A virtual folder, in shallow listings
+    Folder,
+}
+
+impl FileAction {
+    /// Fails on actions we don't know about, rather than guessing whether the file still exists
+    fn of(file: &reply::ListedFile) -> Result<Self> {
+        match file.action.as_str() {
+            "upload" => Ok(FileAction::Upload),
+            "hide" => Ok(FileAction::Hide),
+            "start" => Ok(FileAction::Start),
+            "folder" => Ok(FileAction::Folder),
+            action => bail!(
+                "Unknown action {} for listed file {}, this version of frozen may be too old for this bucket",
+                action,
+                file.file_name
+            ),
+        }
+    }
+}
+
+/// The files of a `b2_list_file_names` reply whose latest version has data.
+/// Hidden files are deleted files, B2 doesn't list them, but a hide marker must not be mistaken for a file either.
+fn listed_files(reply: &reply::ListFiles) -> Result<Vec<ListedFile>> {
+    let mut listed = Vec::new();
+    for file in reply.files.iter() {
+        match FileAction::of(file)? {
+            FileAction::Upload => listed.push(ListedFile {
+                full_name: file.file_name.clone(),
+                id: file.id()?.to_owned(),
+                enc_meta: file.enc_meta()?.to_owned(),
+                size: file.content_length,
+            }),
+            FileAction::Hide | FileAction::Start | FileAction::Folder => continue,
+        }
+    }
+    Ok(listed)
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub struct B2Upload {
-    pub upload_url: String,
-    pub auth_token: String,
+/// Files of a listing whose metadata is being decoded in the background
+type DecodingBatch = JoinHandle<Result<Vec<RemoteFile>>>;
+
+/// Starts decoding the metadata of `listed` in batches on the blocking thread pool, the batches are in listing order
+fn decode_listed_files(key: &crypto::Key, mut listed: Vec<ListedFile>) -> Vec<DecodingBatch> {
+    let mut batches = Vec::new();
+    while !listed.is_empty() {
+        let rest = listed.split_off(listed.len().min(DECODE_BATCH_SIZE));
+        let batch = std::mem::replace(&mut listed, rest);
+        let key = key.clone();
+        batches.push(spawn_blocking(move || {
+            batch
+                .into_iter()
+                .map(|file| {
+                    let meta = decode_meta(&key, &file.enc_meta)?;
+                    Ok(RemoteFile::new(meta, &file.full_name, &file.id, file.size))
+                })
+                .collect()
+        }));
+    }
+    batches
+}
+
+/// Cancels a background task when dropped
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
+/// What frozen needs to back up, restore, prune and share files in its bucket.
+/// Changing the bucket's lifecycle rules with `frozen lifecycle` needs a key that can write the bucket settings.
+pub const BACKUP_KEY_CAPABILITIES: &[&str] = &[
+    "listBuckets",
+    "listFiles",
+    "readFiles",
+    "shareFiles",
+    "writeFiles",
+    "deleteFiles",
+    "readFileRetentions",
+    "writeFileRetentions",
+];
+
 #[derive(Clone)]
 pub struct B2 {
     pub key: crypto::Key,
     pub bucket_id: String,
     pub acc_id: String,
-    pub auth_token: String,
     pub api_url: Url,
     pub bucket_download_url: Url,
-    pub client: Client,
+    auth: Arc<Authorization>,
+    retry_limits: RetryLimits,
+    retry_budget: Arc<RetryBudget>,
+    connectivity: Arc<Connectivity>,
     pub progress: Option<ProgressHandler>,
+    pub retention: Option<Retention>,
+    pub sse: Option<ServerSideEncryption>,
+    pub names: ObjectNames,
+    /// What the app key is allowed to do, as given by B2 when authenticating
+    pub capabilities: Vec<String>,
+    /// The buckets the app key is restricted to, empty if it can access every bucket
+    pub key_buckets: Vec<String>,
+    /// Uploads and downloads file data through the S3-compatible endpoint when set, see `TransferApi`
+    s3: Option<Arc<S3Endpoint>>,
+    /// Fails some requests on purpose, with the hidden `--chaos` flag
+    chaos: Option<Arc<RequestChaos>>,
+}
+
+/// The account authorization token, renewed when B2 says it expired.
+/// Shared by the clones of a `B2`, so they all switch to the renewed token.
+struct Authorization {
+    basic_auth: SecretString,
+    options: ConnectionOptions,
+    /// Sends the current token with each request
+    client: RwLock<Client>,
+    /// Bumped by each renewal, so the requests that failed with the same token only renew it once
+    generation: AtomicU64,
+    renewal: tokio::sync::Mutex<()>,
+}
+
+impl Authorization {
+    fn new(basic_auth: SecretString, auth_token: &str, options: ConnectionOptions) -> Result<Self> {
+        Ok(Self {
+            basic_auth,
+            client: RwLock::new(authorized_client(auth_token, &options)?),
+            options,
+            generation: AtomicU64::new(0),
+            renewal: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Authorizes the account again, unless another request already did since `failed_generation`
+    async fn renew(&self, failed_generation: u64) -> Result<()> {
+        let _renewal = self.renewal.lock().await;
+        if self.generation() != failed_generation {
+            return Ok(());
+        }
+        let authorized = authorize_account(&self.basic_auth, &self.options).await?;
+        *self.client.write().unwrap() = authorized_client(&authorized.authorization_token, &self.options)?;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+/// A reply to a request that went through the retries. Failed replies were read to find out why they failed.
+enum Reply {
+    Success(Response),
+    Failure(StatusCode, Bytes),
+}
+
+impl Reply {
+    async fn into_bytes(self) -> Result<(StatusCode, Bytes)> {
+        match self {
+            Reply::Success(response) => Ok((response.status(), response.bytes().await?)),
+            Reply::Failure(status, body) => Ok((status, body)),
+        }
+    }
 }
 
 async fn warning(maybe_progress: &Option<ProgressHandler>, msg: &str) {
     match maybe_progress {
-        Some(progress) => {
-            progress.println(format!("Warning: {}", msg));
+        Some(progress) => progress.report_warning(msg),
+        None => progress::log(format!("Warning: {}", msg)),
+    }
+}
+
+/// Warns about a request that is retried, once per reason unless the retries are logged
+async fn retry_warning(maybe_progress: &Option<ProgressHandler>, reason: &str, attempts: u32) {
+    match maybe_progress {
+        Some(progress) if progress.logs(LogCategory::Retries) => {
+            progress.println(format!("Warning: {}, retrying (attempt {})", reason, attempts))
         }
-        None => println!("Warning: {}", msg),
+        _ => warning(maybe_progress, reason).await,
     }
 }
 
@@ -58,129 +262,285 @@ fn make_basic_auth(
         b2_key: password,
         ..
     }: &AppKeys,
-) -> String {
-    let val = username.to_owned() + ":" + password;
-    let encoded = BASE64_NOPAD.encode(val.as_bytes());
-    "Basic ".to_owned() + &encoded
+) -> SecretString {
+    let val = SecretString::from(username.to_owned() + ":" + password.expose());
+    let encoded = SecretString::from(BASE64_NOPAD.encode(val.expose().as_bytes()));
+    SecretString::from("Basic ".to_owned() + encoded.expose())
 }
 
-fn base_client() -> ClientBuilder {
-    Client::builder()
+fn base_client(options: &ConnectionOptions) -> Result<ClientBuilder> {
+    let builder = Client::builder()
         .https_only(true)
-        .min_tls_version(tls::Version::TLS_1_2)
+        .min_tls_version(tls::Version::TLS_1_2);
+    options.apply(builder)
+}
+
+fn authorized_client(auth_token: &str, options: &ConnectionOptions) -> Result<Client> {
+    let mut auth_token = HeaderValue::from_str(auth_token)?;
+    auth_token.set_sensitive(true);
+    let headers = HeaderMap::from_iter([(AUTHORIZATION, auth_token)]);
+    Ok(base_client(options)?
+        .default_headers(headers)
+        .build()
+        .expect("Failed to build HTTP client"))
+}
+
+async fn authorize_account(basic_auth: &SecretString, options: &ConnectionOptions) -> Result<reply::AuthorizeAccount> {
+    let client = base_client(options)?.build().expect("Failed to build HTTP client");
+    // Kept out of logs and debug output of the request
+    let mut basic_auth = HeaderValue::from_str(basic_auth.expose())?;
+    basic_auth.set_sensitive(true);
+    let res = client
+        .get(format!("https://{}/b2api/v3/b2_authorize_account", AUTHORIZE_ENDPOINT))
+        .header(AUTHORIZATION, basic_auth)
+        .send()
+        .await?;
+    let status = res.status();
+    let body = res.bytes().await?;
+
+    if !status.is_success() {
+        let reason = reply::failure_reason(status, &body);
+        return Err(eyre!("Backblaze B2 login failure, {}", reason).wrap_err(Failure::Config));
+    }
+    reply::parse("authorize_account", status, &body)
 }
 
 impl B2 {
+    /// Sends requests with the current account authorization token
+    fn client(&self) -> Client {
+        self.auth.client.read().unwrap().clone()
+    }
+
     async fn request_with_backoff<Fn, Fut>(&self, req_fn: Fn) -> Result<(StatusCode, Bytes)>
     where
         Fn: FnMut() -> Fut,
         Fut: Future<Output = Result<Response, reqwest::Error>>,
     {
-        let (status, response) = self.request_response_with_backoff(req_fn).await?;
-        Ok((status, response.bytes().await?))
+        self.timed_request_with_backoff(Stage::Request, 0, false, req_fn)
+            .await?
+            .into_bytes()
+            .await
+    }
+
+    /// Like `request_with_backoff`, but the time is recorded as sending `bytes` of file data.
+    /// Uploads are authorized by the token of their upload URL, not by the account's.
+    async fn upload_with_backoff<Fn, Fut>(&self, bytes: u64, req_fn: Fn) -> Result<(StatusCode, Bytes)>
+    where
+        Fn: FnMut() -> Fut,
+        Fut: Future<Output = Result<Response, reqwest::Error>>,
+    {
+        self.timed_request_with_backoff(Stage::Upload, bytes, true, req_fn)
+            .await?
+            .into_bytes()
+            .await
+    }
+
+    async fn request_response_with_backoff<Fn, Fut>(&self, req_fn: Fn) -> Result<Reply>
+    where
+        Fn: FnMut() -> Fut,
+        Fut: Future<Output = Result<Response, reqwest::Error>>,
+    {
+        self.timed_request_with_backoff(Stage::Request, 0, false, req_fn).await
+    }
+
+    async fn timed_request_with_backoff<Fn, Fut>(
+        &self,
+        stage: Stage,
+        bytes: u64,
+        upload_url_auth: bool,
+        req_fn: Fn,
+    ) -> Result<Reply>
+    where
+        Fn: FnMut() -> Fut,
+        Fut: Future<Output = Result<Response, reqwest::Error>>,
+    {
+        let start = Instant::now();
+        let result = self.retry_request(upload_url_auth, req_fn).await;
+        stats::record(stage, bytes, start.elapsed());
+        result
     }
 
-    async fn request_response_with_backoff<Fn, Fut>(&self, mut req_fn: Fn) -> Result<(StatusCode, Response)>
+    /// Sends a request until it succeeds, or until its failure isn't worth retrying (see `net::retry`)
+    async fn retry_request<Fn, Fut>(&self, upload_url_auth: bool, mut req_fn: Fn) -> Result<Reply>
     where
         Fn: FnMut() -> Fut,
         Fut: Future<Output = Result<Response, reqwest::Error>>,
     {
-        let mut hard_fails = 0u32;
+        let mut retries = HashMap::<ErrorClass, u32>::new();
         let mut attempts = 0u32;
+        let mut wait = Duration::ZERO;
         loop {
             attempts += 1;
-            if attempts > 1 {
-                let cooldown = (1 << attempts.min(5)) * 100; // Up to 3.2 seconds
-                sleep(Duration::from_millis(cooldown));
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
             }
 
-            let res = match req_fn().await {
-                Ok(res) => res,
-                Err(e) => {
-                    let err_str = format!("Unexpected request failure: {}", e);
-                    warning(&self.progress, &err_str).await;
-                    continue;
+            let generation = self.auth.generation();
+            // The failed reply with its Retry-After, or the error if there was no reply
+            let failed = match self.chaos.as_ref().and_then(|chaos| chaos.injected_reply()) {
+                Some((status, body)) => Ok((status, None, body)),
+                None => match req_fn().await {
+                    Ok(res) => {
+                        if res.status().is_success() {
+                            return Ok(Reply::Success(res));
+                        }
+                        let status = res.status();
+                        let retry_after = res
+                            .headers()
+                            .get(RETRY_AFTER)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(parse_retry_after);
+                        Ok((status, retry_after, res.bytes().await?))
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            let (class, failure, reason) = match failed {
+                Ok((status, retry_after, body)) => {
+                    // Proxies may answer with an HTML page instead, it's classified by its status alone
+                    let error = reply::ErrorReply::parse(&body).unwrap_or_default();
+                    let class = retry::classify_reply(status.as_u16(), &error.code);
+                    if class == ErrorClass::CapExceeded {
+                        cap::set_reached();
+                        let message = if error.message.is_empty() {
+                            &error.code
+                        } else {
+                            &error.message
+                        };
+                        return Err(eyre!("{}", message).wrap_err(Failure::CapReached));
+                    }
+                    // The status comes first, so that repeated warnings are counted together (see `Progress::warnings`)
+                    let reason = match error.message.as_str() {
+                        "" => format!(
+                            "{} {}",
+                            status.as_u16(),
+                            status.canonical_reason().unwrap_or("Request failure")
+                        ),
+                        message => format!("{} {}", status.as_u16(), message),
+                    };
+                    wait = retry_after.unwrap_or_default().min(retry::MAX_RETRY_WAIT);
+                    (class, Ok((status, body)), reason)
+                }
+                Err(err) => {
+                    let reason = format!("Unexpected request failure: {}", err);
+                    wait = Duration::ZERO;
+                    (retry::classify_error(&err), Err(err), reason)
                 }
             };
-            let status = res.status();
 
-            // Temporary failure is not an error, just asking for an exponential backoff
-            if status.as_u16() == 503 || status.as_u16() == 408 {
-                warning(
-                    &self.progress,
-                    status.canonical_reason().unwrap_or("Temporary request failure"),
-                )
-                .await;
-                continue;
+            let retried = retries.entry(class).or_insert(0);
+            let mut action = class.action(*retried, upload_url_auth, &self.retry_limits);
+            *retried += 1;
+            let budget_spent = action != RetryAction::Fail && !self.retry_budget.spend();
+            if budget_spent {
+                action = RetryAction::Fail;
             }
-
-            // Treat internal server errors as temporary failures, for a few attempts
-            if status.as_u16() == 500 && hard_fails < 5 {
-                hard_fails += 1;
-                warning(
-                    &self.progress,
-                    status.canonical_reason().unwrap_or("Internal server error"),
-                )
-                .await;
-                continue;
+            match action {
+                RetryAction::Fail => {
+                    return match failure {
+                        Ok((status, body)) => Ok(Reply::Failure(status, body)),
+                        Err(err) if budget_spent => Err(eyre!(err).wrap_err(format!(
+                            "Gave up, the retries of this run are spent ({}), the network or B2 may be down",
+                            self.retry_limits.run_budget.unwrap_or_default()
+                        ))),
+                        Err(err) if attempts > 1 => Err(eyre!(err).wrap_err(format!(
+                            "Gave up after {} attempts, the network or B2 may be down",
+                            attempts
+                        ))),
+                        Err(err) => Err(err.into()),
+                    }
+                }
+                RetryAction::Reauthorize => {
+                    warning(&self.progress, "Authorization expired, authorizing again").await;
+                    self.auth.renew(generation).await?;
+                    wait = Duration::ZERO;
+                }
+                RetryAction::Backoff => {
+                    // Without any reply, B2 may be unreachable altogether: one request checks and the others wait
+                    let reachable =
+                        class != ErrorClass::Connection || self.connectivity.wait_online(&self.progress).await?;
+                    if reachable {
+                        retry_warning(&self.progress, &reason, attempts).await;
+                    }
+                    let cooldown = Duration::from_millis((1 << attempts.min(5)) * 100); // Up to 3.2 seconds
+                    wait = wait.max(cooldown);
+                }
             }
-
-            return Ok((status, res));
         }
     }
 
     pub async fn authenticate(config: &Config, keys: &AppKeys) -> Result<B2> {
-        let client = base_client().build().expect("Failed to build HTTP client");
+        // Fails quickly with a clear message when offline, instead of a request error
+        let options = config.connection.clone();
+        health::check_reachable(&format!("{}:443", AUTHORIZE_ENDPOINT), &options).await?;
         let basic_auth = make_basic_auth(keys);
         let bucket_name = config.bucket_name.to_owned();
-
-        let res = client
-            .get("https://api.backblazeb2.com/b2api/v2/b2_authorize_account")
-            .header(AUTHORIZATION, basic_auth)
-            .send()
-            .await?;
-        let status = res.status();
-        let body = res.bytes().await?;
-
-        let reply_json: Value = match serde_json::from_slice(&body) {
-            Err(_) => bail!(
-                "authenticate failed to parse json: {}",
-                std::str::from_utf8(&body).unwrap()
-            ),
-            Ok(json) => json,
+        let authorized = authorize_account(&basic_auth, &options).await?;
+        let storage = authorized.api_info.storage_api;
+
+        let bucket_download_url = Url::from_str(&format!("{}/file/{}/", storage.download_url, &config.bucket_name))?;
+
+        let api_url = Url::from_str(&storage.api_url)?.join("b2api/v3/")?;
+        let s3 = match config.transfer_api {
+            TransferApi::Native => None,
+            TransferApi::S3 => {
+                let url = storage.s3_api_url.as_deref().ok_or_else(|| {
+                    eyre!("B2 gave no S3-compatible endpoint for this account, set transfer_api to native")
+                        .wrap_err(Failure::Config)
+                })?;
+                let secret_key = SecretString::from(keys.b2_key.expose().to_owned());
+                Some(Arc::new(S3Endpoint::new(
+                    url,
+                    &bucket_name,
+                    &keys.b2_key_id,
+                    secret_key,
+                )?))
+            }
         };
-
-        if !status.is_success() {
-            let mut err_msg = "Backblaze B2 login failure: ".to_string() + from_utf8(&body).unwrap();
-            if let Value::String(ref reply_err_msg) = reply_json["message"] {
-                err_msg += &(String::from(": ") + reply_err_msg);
+        let api_endpoint = format!(
+            "{}:{}",
+            api_url.host_str().unwrap_or_default(),
+            api_url.port_or_known_default().unwrap_or(443)
+        );
+        if config.verbosity.logs(LogCategory::Retries) {
+            // Tells which address a stalled connection may be stuck on
+            match options.resolve(&api_endpoint) {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.iter().map(ToString::to_string).collect();
+                    progress::log(format!("B2 API at {} resolves to {}", api_endpoint, addrs.join(", ")));
+                }
+                Err(err) => progress::log(format!("Failed to resolve the B2 API at {}: {:#}", api_endpoint, err)),
             }
-            bail!(err_msg);
         }
 
-        let auth_token = reply_json["authorizationToken"].as_str().unwrap().to_string();
-        let bucket_download_url = Url::from_str(&format!(
-            "{}/file/{}/",
-            reply_json["downloadUrl"].as_str().unwrap(),
-            &config.bucket_name
-        ))?;
-
-        let headers = HeaderMap::from_iter([(AUTHORIZATION, HeaderValue::from_str(&auth_token)?)]);
-        let client = base_client()
-            .default_headers(headers)
-            .build()
-            .expect("Failed to build HTTP client");
-        let api_url = Url::from_str(reply_json["apiUrl"].as_str().unwrap())?.join("b2api/v2/")?;
-
         let mut b2 = B2 {
             key: keys.encryption_key.clone(),
-            acc_id: reply_json["accountId"].as_str().unwrap().to_string(),
-            auth_token,
+            acc_id: authorized.account_id,
             bucket_id: String::new(),
             api_url,
             bucket_download_url,
             progress: None,
-            client,
+            auth: Arc::new(Authorization::new(
+                basic_auth,
+                &authorized.authorization_token,
+                options.clone(),
+            )?),
+            retry_limits: config.retry_limits,
+            retry_budget: Arc::new(RetryBudget::new(&config.retry_limits)),
+            connectivity: Arc::new(Connectivity::new(api_endpoint, options)),
+            retention: config.retention,
+            sse: config
+                .server_side_encryption
+                .map(|mode| ServerSideEncryption::new(mode, &keys.encryption_key)),
+            names: if config.obfuscate_names {
+                ObjectNames::obfuscated(&keys.encryption_key)
+            } else {
+                ObjectNames::plain()
+            },
+            key_buckets: storage.allowed.bucket_names(),
+            chaos: config.chaos.map(|options| Arc::new(RequestChaos::new(options))),
+            capabilities: storage.allowed.capabilities,
+            s3,
         };
 
         let bucket_id = b2.get_bucket_id(&bucket_name).await?;
@@ -194,160 +554,280 @@ impl B2 {
 
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_list_buckets").unwrap())
-                    .json(&json!({
-                         "bucketName": bucket_name,
-                         "accountId": self.acc_id
-                    }))
+                    .json(&request::ListBuckets {
+                        account_id: &self.acc_id,
+                        bucket_id: None,
+                        bucket_name: Some(&bucket_name),
+                    })
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json = Self::get_json_reply("get_bucket_id", status, body).await?;
-
-        let buckets = reply_json["buckets"].as_array().unwrap();
-        for bucket in buckets {
-            if bucket["bucketName"] == bucket_name {
-                return Ok(bucket["bucketId"].as_str().unwrap().to_string());
+        let reply: reply::ListBuckets = Self::get_json_reply("get_bucket_id", status, body).await?;
+        for bucket in reply.buckets {
+            if bucket.bucket_name == bucket_name {
+                return Ok(bucket.bucket_id);
             }
         }
-        Err(eyre!("Bucket '{}' not found", bucket_name))
+        Err(eyre!("Bucket '{}' not found", bucket_name).wrap_err(Failure::Config))
+    }
+
+    /// The Date header of a B2 reply, to compare our clock with the server's
+    pub async fn server_date(&self) -> Result<String> {
+        let reply = self
+            .request_response_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_list_buckets").unwrap())
+                    .json(&request::ListBuckets {
+                        account_id: &self.acc_id,
+                        bucket_id: Some(&self.bucket_id),
+                        bucket_name: None,
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
+        let response = match reply {
+            Reply::Success(response) => response,
+            Reply::Failure(status, _) => bail!("list_buckets failed with error {}", status.as_u16()),
+        };
+        let date = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .ok_or_else(|| eyre!("B2 replied without a Date header"))?;
+        Ok(date.to_str()?.to_owned())
+    }
+
+    /// Creates an application key that can only use the bucket of this connection, with `capabilities`.
+    /// Returns the ID of the new key and the key itself, which B2 won't show again.
+    pub async fn create_key(&self, name: &str, capabilities: &[&str]) -> Result<(String, String)> {
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_create_key").unwrap())
+                    .json(&request::CreateKey {
+                        account_id: &self.acc_id,
+                        capabilities,
+                        key_name: name,
+                        bucket_id: &self.bucket_id,
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
+
+        let reply: reply::CreateKey = Self::get_json_reply("create_key", status, body).await?;
+        Ok((reply.application_key_id, reply.application_key))
+    }
+
+    async fn lifecycle_rules(&self) -> Result<Vec<LifecycleRule>> {
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_list_buckets").unwrap())
+                    .json(&request::ListBuckets {
+                        account_id: &self.acc_id,
+                        bucket_id: Some(&self.bucket_id),
+                        bucket_name: None,
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
+
+        let reply: reply::ListBuckets = Self::get_json_reply("list_buckets", status, body).await?;
+        let bucket = reply
+            .buckets
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("Bucket not found"))?;
+        Ok(bucket.lifecycle_rules)
     }
 
-    pub async fn list_remote_files(&self, prefix: &str, depth: FileListDepth) -> Result<Vec<RemoteFile>> {
+    async fn set_lifecycle_rules(&self, rules: &[LifecycleRule]) -> Result<()> {
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_update_bucket").unwrap())
+                    .json(&request::UpdateBucket {
+                        account_id: &self.acc_id,
+                        bucket_id: &self.bucket_id,
+                        lifecycle_rules: rules,
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
+
+        Self::get_json_reply::<serde::de::IgnoredAny>("update_bucket", status, body).await?;
+        Ok(())
+    }
+
+    async fn share_url(&self, filename: &str, valid_for: Duration) -> Result<String> {
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_get_download_authorization").unwrap())
+                    .json(&request::GetDownloadAuthorization {
+                        bucket_id: &self.bucket_id,
+                        file_name_prefix: filename,
+                        valid_duration_in_seconds: valid_for.as_secs(),
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
+
+        let reply: reply::DownloadAuthorization =
+            Self::get_json_reply("get_download_authorization", status, body).await?;
+        let mut url = self.bucket_download_url.join(filename)?;
+        url.query_pairs_mut()
+            .append_pair("Authorization", &reply.authorization_token);
+        Ok(url.into())
+    }
+
+    async fn list_remote_files(&self, prefix: &str, depth: FileListDepth) -> Result<Vec<RemoteFile>> {
+        self.list_remote_files_stream(prefix, depth).try_collect().await
+    }
+
+    /// Lists the files page by page, their metadata is decoded on the blocking thread pool.
+    /// The next pages are requested in the background while the current one is decoded and consumed.
+    fn list_remote_files_stream<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxStream<'a, Result<RemoteFile>> {
+        let (send, mut recv) = mpsc::channel(LIST_PREFETCH_PAGES);
+        let pages = tokio::spawn(self.clone().list_file_name_pages(prefix.to_owned(), depth, send));
+
+        Box::pin(try_stream! {
+            // The page requests stop with the stream, once they can't send their page
+            let _pages = AbortOnDrop(pages);
+            while let Some(page) = recv.recv().await {
+                for decoding in page? {
+                    for file in decoding.await?? {
+                        yield file;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Sends each page of the listing as soon as it's received, along with the tasks decoding its files
+    async fn list_file_name_pages(
+        self,
+        prefix: String,
+        depth: FileListDepth,
+        pages: mpsc::Sender<Result<Vec<DecodingBatch>>>,
+    ) {
         let delimiter = match depth {
             FileListDepth::Shallow => Some("/"),
             FileListDepth::Deep => None,
         };
-        let body = json!({
-            "bucketId": self.bucket_id,
-            "maxFileCount": 10000,
-            "delimiter": delimiter,
-            "prefix": prefix,
-        });
         let mut start_filename: Option<String> = None;
-        let mut files: Vec<RemoteFile> = Vec::new();
 
         loop {
-            let (status, body) = self
+            let body = request::ListFileNames {
+                bucket_id: &self.bucket_id,
+                max_file_count: 10000,
+                delimiter,
+                prefix: &prefix,
+                start_file_name: start_filename.as_deref(),
+            };
+            let reply = self
                 .request_with_backoff(|| async {
-                    let mut body = body.clone();
-                    if start_filename.is_some() {
-                        body.as_object_mut()
-                            .unwrap()
-                            .insert("startFileName".into(), start_filename.clone().unwrap().into());
-                    }
-
-                    self.client
+                    self.client()
                         .post(self.api_url.join("b2_list_file_names").unwrap())
                         .json(&body)
                         .send()
                         .await
                 })
-                .await?;
-
-            let reply_json = Self::get_json_reply("list_remote_files", status, body).await?;
-
-            for file in reply_json["files"].as_array().unwrap() {
-                // Ignore non-files (folders, large file starts) entirely
-                if file["action"] != "upload" {
-                    continue;
+                .await;
+            let reply = match reply {
+                Ok((status, body)) => Self::get_json_reply::<reply::ListFiles>("list_remote_files", status, body).await,
+                Err(err) => Err(err),
+            };
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(err) => {
+                    let _ = pages.send(Err(err)).await;
+                    return;
                 }
-                let full_name = file["fileName"].as_str().unwrap();
-                let id = file["fileId"].as_str().unwrap();
-                let enc_meta = file["fileInfo"]["enc_meta"].as_str().unwrap();
-                let (filename, mtime, mode, is_symlink) = decode_meta(&self.key, enc_meta)?;
-                files.push(RemoteFile::new(&filename, full_name, id, mtime, mode, is_symlink))
+            };
+
+            let decoding = listed_files(&reply).map(|listed| decode_listed_files(&self.key, listed));
+            let failed = decoding.is_err();
+            if pages.send(decoding).await.is_err() || failed {
+                return;
             }
 
-            if let Some(next) = reply_json["nextFileName"].as_str() {
-                start_filename = Some(next.to_string());
-            } else {
-                break;
+            match reply.next_file_name {
+                Some(next) => start_filename = Some(next),
+                None => break,
             }
         }
-
-        Ok(files)
     }
 
-    pub async fn list_remote_file_versions(&self, prefix: &str) -> Result<Vec<RemoteFileVersion>> {
-        let body = json!({
-            "bucketId": self.bucket_id,
-            "maxFileCount": 10000,
-            "prefix": prefix,
-        });
-        let mut start_file_version: Option<RemoteFileVersion> = None;
-        let mut files: Vec<RemoteFileVersion> = Vec::new();
-
-        loop {
-            let (status, body) = self
-                .request_with_backoff(|| async {
-                    let mut body = body.clone();
-                    if start_file_version.is_some() {
-                        let ver = start_file_version.as_ref().unwrap();
-                        let body_mut = body.as_object_mut().unwrap();
-                        body_mut.insert("startFileName".into(), ver.path.clone().into());
-                        body_mut.insert("startFileId".into(), ver.id.clone().into());
-                    }
-
-                    self.client
-                        .post(self.api_url.join("b2_list_file_versions").unwrap())
-                        .json(&body)
-                        .send()
-                        .await
-                })
-                .await?;
+    async fn list_file_versions_page(&self, prefix: &str, start: Option<&RemoteFileVersion>) -> Result<VersionsPage> {
+        let body = request::ListFileVersions {
+            bucket_id: &self.bucket_id,
+            max_file_count: 10000,
+            prefix,
+            start_file_name: start.map(|start| start.path.as_str()),
+            start_file_id: start.map(|start| start.id.as_str()),
+        };
 
-            let reply_json = Self::get_json_reply("list_remote_files_versions", status, body).await?;
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_list_file_versions").unwrap())
+                    .json(&body)
+                    .send()
+                    .await
+            })
+            .await?;
 
-            for file in reply_json["files"].as_array().unwrap() {
-                // Ignore non-files (folders, hidden files, large file starts) entirely
-                if file["action"] != "upload" {
-                    continue;
-                }
-                let file_id = file["fileId"].as_str().unwrap().to_string();
-                let file_name = file["fileName"].as_str().unwrap().to_string();
-                files.push(RemoteFileVersion {
-                    path: file_name,
-                    id: file_id,
-                });
-            }
+        let reply: reply::ListFiles = Self::get_json_reply("list_remote_files_versions", status, body).await?;
 
-            let maybe_next_name = reply_json["nextFileName"].as_str();
-            let maybe_next_id = reply_json["nextFileId"].as_str();
-            if let (Some(name), Some(id)) = (maybe_next_name, maybe_next_id) {
-                start_file_version = Some(RemoteFileVersion {
-                    path: name.to_string(),
-                    id: id.to_string(),
-                });
-            } else {
-                break;
+        let mut versions = Vec::new();
+        for file in reply.files.iter() {
+            // Hide markers aren't versions with data, they're only the reason older versions aren't listed anymore
+            if FileAction::of(file)? != FileAction::Upload {
+                continue;
             }
+            let uploaded = UNIX_EPOCH + Duration::from_millis(file.upload_timestamp);
+            versions.push((
+                RemoteFileVersion {
+                    path: file.file_name.clone(),
+                    id: file.id()?.to_owned(),
+                },
+                uploaded,
+            ));
         }
 
-        Ok(files)
+        let next = match (reply.next_file_name, reply.next_file_id) {
+            (Some(path), Some(id)) => Some(RemoteFileVersion { path, id }),
+            _ => None,
+        };
+        Ok(VersionsPage { versions, next })
     }
 
-    pub async fn list_unfinished_large_files(&self, prefix: &str) -> Result<Vec<RemoteFile>> {
-        let body = json!({
-            "bucketId": self.bucket_id,
-            "namePrefix": prefix,
-        });
+    async fn list_unfinished_large_files(&self, prefix: &str) -> Result<Vec<(RemoteFile, SystemTime)>> {
         let mut start_file_version: Option<String> = None;
-        let mut unfinished_files: Vec<RemoteFile> = Vec::new();
+        let mut unfinished_files: Vec<(RemoteFile, SystemTime)> = Vec::new();
 
         loop {
+            let body = request::ListUnfinishedLargeFiles {
+                bucket_id: &self.bucket_id,
+                name_prefix: prefix,
+                start_file_id: start_file_version.as_deref(),
+            };
             let (status, body) = self
                 .request_with_backoff(|| async {
-                    let mut body = body.clone();
-                    if let Some(ver) = start_file_version.as_deref() {
-                        let body_mut = body.as_object_mut().unwrap();
-                        body_mut.insert("startFileId".into(), ver.into());
-                    };
-
-                    self.client
+                    self.client()
                         .post(self.api_url.join("b2_list_unfinished_large_files").unwrap())
                         .json(&body)
                         .send()
@@ -355,188 +835,437 @@ impl B2 {
                 })
                 .await?;
 
-            let reply_json = Self::get_json_reply("list_unfinished_large_files", status, body).await?;
+            let reply: reply::ListFiles = Self::get_json_reply("list_unfinished_large_files", status, body).await?;
 
-            for file in reply_json["files"].as_array().unwrap() {
+            for file in reply.files.iter() {
                 // Ignore non-large files (regular uploads, folders, hidden files) entirely
-                if file["action"] != "start" {
+                if FileAction::of(file)? != FileAction::Start {
                     continue;
                 }
-                let full_name = file["fileName"].as_str().unwrap();
-                let id = file["fileId"].as_str().unwrap();
-                let enc_meta = file["fileInfo"]["enc_meta"].as_str().unwrap();
-                let (filename, mtime, mode, is_symlink) = decode_meta(&self.key, enc_meta)?;
-                unfinished_files.push(RemoteFile::new(&filename, full_name, id, mtime, mode, is_symlink))
+                let started = UNIX_EPOCH + Duration::from_millis(file.upload_timestamp);
+                let meta = decode_meta(&self.key, file.enc_meta()?)?;
+                let file = RemoteFile::new(meta, &file.file_name, file.id()?, 0);
+                unfinished_files.push((file, started))
             }
 
-            let maybe_next_id = reply_json["nextFileId"].as_str();
-            if let Some(id) = maybe_next_id {
-                start_file_version = Some(id.to_string());
-            } else {
-                break;
+            match reply.next_file_id {
+                Some(id) => start_file_version = Some(id),
+                None => break,
             }
         }
 
         Ok(unfinished_files)
     }
 
-    pub async fn get_upload_url(&self) -> Result<B2Upload> {
+    async fn get_upload_url(&self) -> Result<UploadTarget> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_get_upload_url").unwrap())
-                    .json(&json!({"bucketId": self.bucket_id}))
+                    .json(&request::BucketId {
+                        bucket_id: &self.bucket_id,
+                    })
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json = Self::get_json_reply("get_upload_url", status, body).await?;
-        Ok(B2Upload {
-            upload_url: reply_json["uploadUrl"].as_str().unwrap().to_string(),
-            auth_token: reply_json["authorizationToken"].as_str().unwrap().to_string(),
+        let reply: reply::UploadUrl = Self::get_json_reply("get_upload_url", status, body).await?;
+        Ok(UploadTarget {
+            url: reply.upload_url,
+            auth_token: reply.authorization_token,
         })
     }
 
-    /// The returned B2Upload struct is only valid for the one large file being uploaded
-    pub async fn get_upload_part_url(&self, file_id: &str) -> Result<B2Upload> {
+    /// The returned UploadTarget is only valid for the one large file being uploaded
+    pub async fn get_upload_part_url(&self, file_id: &str) -> Result<UploadTarget> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_get_upload_part_url").unwrap())
-                    .json(&json!({ "fileId": file_id }))
+                    .json(&request::FileId { file_id })
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json: Value = serde_json::from_slice(&body)?;
-        ensure!(
-            status.is_success(),
-            "get_upload_part_url failed with error {}: {}",
-            status.as_u16(),
-            reply_json["message"]
-        );
+        let reply: reply::UploadUrl = Self::get_json_reply("get_upload_part_url", status, body).await?;
+        Ok(UploadTarget {
+            url: reply.upload_url,
+            auth_token: reply.authorization_token,
+        })
+    }
+
+    async fn file_version_info(&self, file_version: &RemoteFileVersion) -> Result<FileVersionInfo> {
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_get_file_info").unwrap())
+                    .json(&request::FileId {
+                        file_id: &file_version.id,
+                    })
+                    .send()
+                    .await
+            })
+            .await?;
 
-        Ok(B2Upload {
-            upload_url: reply_json["uploadUrl"].as_str().unwrap().to_string(),
-            auth_token: reply_json["authorizationToken"].as_str().unwrap().to_string(),
+        let reply: reply::FileDetails = Self::get_json_reply("get_file_info", status, body)
+            .await
+            .wrap_err_with(|| format!("Invalid file info for {}", file_version.path))?;
+        // Large files have no SHA1 of their own, only their parts do
+        let sha1 = reply
+            .content_sha1
+            .as_deref()
+            .map(|sha1| sha1.trim_start_matches("unverified:"))
+            .filter(|&sha1| sha1 != "none")
+            .map(str::to_owned);
+        Ok(FileVersionInfo {
+            size: reply.content_length,
+            sha1,
         })
     }
 
-    pub async fn delete_file_version(&self, file_version: &RemoteFileVersion) -> Result<()> {
+    async fn delete_file_version(&self, file_version: &RemoteFileVersion) -> Result<()> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_delete_file_version").unwrap())
-                    .json(&json!({
-                        "fileId": file_version.id,
-                         "fileName": file_version.path,
-                    }))
+                    .json(&request::DeleteFileVersion {
+                        file_id: &file_version.id,
+                        file_name: &file_version.path,
+                    })
                     .send()
                     .await
             })
             .await?;
 
         if !status.is_success() {
-            let reply_json: Value = serde_json::from_slice(&body)?;
+            let message = reply::ErrorReply::parse(&body)
+                .unwrap_or_default()
+                .message
+                .to_lowercase();
+            if message.contains("retention") || message.contains("legal hold") {
+                return Err(VersionLocked {
+                    path: file_version.path.clone(),
+                }
+                .into());
+            }
             bail!(
-                "Removal of {} failed with error {}: {}",
+                "Removal of {} failed with {}",
                 file_version.path,
-                status.as_u16(),
-                reply_json["message"]
+                reply::failure_reason(status, &body)
             );
         }
         Ok(())
     }
 
-    pub async fn upload_file_simple(&self, filename: &str, data: Vec<u8>) -> Result<RemoteFileVersion> {
-        let upload_url = self.get_upload_url().await?;
-        self.upload_file(&upload_url, filename, data, None).await
-    }
-
-    pub async fn upload_file(
+    async fn copy_file(
         &self,
-        b2upload: &B2Upload,
+        file_version: &RemoteFileVersion,
+        size: u64,
         filename: &str,
-        data: Vec<u8>,
-        enc_meta: Option<String>,
+        enc_meta: &str,
     ) -> Result<RemoteFileVersion> {
-        let data_stream = Box::new(SimpleBytesStream::new(data.into()));
-        self.upload_file_stream(b2upload, filename, data_stream, enc_meta).await
+        // Copies are file data, which is locked like uploads are (see `Retention`)
+        let retention = self.retention;
+        if size > COPY_FILE_MAX_SIZE {
+            let file_id = self.start_large_file(filename, enc_meta, retention).await?;
+            return match self.copy_large_file_parts(file_version, size, &file_id).await {
+                Ok(part_hashes) => self.finish_large_file(&file_id, &part_hashes).await,
+                Err(err) => {
+                    let _ = self.cancel_large_file(&file_id).await;
+                    Err(err)
+                }
+            };
+        }
+
+        let request_body = request::CopyFile {
+            source_file_id: &file_version.id,
+            destination_bucket_id: &self.bucket_id,
+            file_name: filename,
+            metadata_directive: "REPLACE",
+            content_type: "application/octet-stream",
+            file_info: request::FileInfo { enc_meta },
+            sse: self.copy_sse(),
+            file_retention: retention.map(From::from),
+        };
+        let (status, body) = self
+            .request_with_backoff(|| async {
+                self.client()
+                    .post(self.api_url.join("b2_copy_file").unwrap())
+                    .json(&request_body)
+                    .send()
+                    .await
+            })
+            .await?;
+
+        let reply: reply::FileVersion = Self::get_json_reply("copy_file", status, body).await?;
+        Ok(RemoteFileVersion {
+            path: reply.file_name,
+            id: reply.file_id,
+        })
+    }
+
+    /// Copies a file into a large file started with `start_large_file`, returns the SHA1 of each part
+    async fn copy_large_file_parts(
+        &self,
+        file_version: &RemoteFileVersion,
+        size: u64,
+        large_file_id: &str,
+    ) -> Result<Vec<String>> {
+        let mut part_hashes = Vec::new();
+        for (part_index, start) in (0..size).step_by(COPY_PART_SIZE as usize).enumerate() {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let request_body = request::CopyPart {
+                source_file_id: &file_version.id,
+                large_file_id,
+                part_number: part_index + 1,
+                range: format!("bytes={}-{}", start, end),
+                sse: self.copy_sse(),
+            };
+            let (status, body) = self
+                .request_with_backoff(|| async {
+                    self.client()
+                        .post(self.api_url.join("b2_copy_part").unwrap())
+                        .json(&request_body)
+                        .send()
+                        .await
+                })
+                .await?;
+            let reply: reply::CopyPart = Self::get_json_reply("copy_part", status, body)
+                .await
+                .wrap_err_with(|| format!("copy_part didn't return the SHA1 of part {}", part_index + 1))?;
+            part_hashes.push(reply.content_sha1);
+        }
+        Ok(part_hashes)
+    }
+
+    /// Copies need the SSE-C key to read the source, and to encrypt the copy like an upload
+    fn copy_sse(&self) -> request::CopySse {
+        match &self.sse {
+            Some(sse) => request::CopySse {
+                source_server_side_encryption: sse.copy_source_settings(),
+                destination_server_side_encryption: Some(sse.settings()),
+            },
+            None => request::CopySse::default(),
+        }
     }
 
-    pub async fn upload_file_stream(
+    async fn upload_file_stream(
         &self,
-        b2upload: &B2Upload,
+        target: &UploadTarget,
         filename: &str,
         data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
         enc_meta: Option<String>,
     ) -> Result<RemoteFileVersion> {
-        let enc_meta = if enc_meta.is_some() {
-            enc_meta.as_ref().unwrap().to_owned()
-        } else {
-            let last_modified = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            let mode = 0o644;
-            encode_meta(&self.key, Path::new(filename), last_modified, mode, false)
+        // Only file data is locked, see `Retention`
+        let retention = self.retention.filter(|_| enc_meta.is_some());
+        let enc_meta = match enc_meta {
+            Some(enc_meta) => enc_meta,
+            None => encode_meta(&self.key, &FileMeta::new_internal(Path::new(filename))),
         };
 
         let lower_bound_size = data_stream.size_hint().0;
         if lower_bound_size >= 2 {
-            self.upload_large_file_stream(filename, data_stream, &enc_meta).await
+            self.upload_large_file_stream(filename, data_stream, &enc_meta, retention)
+                .await
         } else {
-            self.upload_small_file_stream(b2upload, filename, data_stream, &enc_meta)
+            self.upload_small_file_stream(target, filename, data_stream, &enc_meta, retention)
                 .await
         }
     }
 
-    /// Uploads a stream in one shot using b2_upload_file
+    /// Uploads a stream in one shot using b2_upload_file, or S3 PutObject
     async fn upload_small_file_stream(
         &self,
-        b2upload: &B2Upload,
+        target: &UploadTarget,
         filename: &str,
         mut data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
         enc_meta: &str,
+        retention: Option<Retention>,
     ) -> Result<RemoteFileVersion> {
-        let data = data_stream.next().await;
-        let data = data.expect("Data stream to upload must not be empty")?;
+        // An empty stream is uploaded as an empty file
+        let data = data_stream.next().await.unwrap_or_else(|| Ok(Bytes::new()))?;
         // Small files here means files that have only one chunk
         assert!(data_stream.next().await.is_none());
 
+        if let Some(s3) = &self.s3 {
+            let sha256 = s3::sha256_hex(&data);
+            let data = ReplayableBody::new(data)?;
+            return self.put_object(s3, filename, data, &sha256, enc_meta, retention).await;
+        }
+
         let sha1 = sha1_string(&data);
+        let data = ReplayableBody::new(data)?;
+        let retain_until = retention.map(|retention| (retention.mode, retention.retain_until_millis()));
 
         let (status, body) = self
-            .request_with_backoff(|| async {
-                self.client
-                    .post(&b2upload.upload_url)
-                    .header(AUTHORIZATION, &b2upload.auth_token as &str)
+            .upload_with_backoff(data.len(), || async {
+                let mut request = self.client().post(&target.url);
+                for (name, value) in self.sse.iter().flat_map(ServerSideEncryption::upload_headers) {
+                    request = request.header(name, value);
+                }
+                if let Some((mode, retain_until)) = retain_until {
+                    request = request
+                        .header("X-Bz-File-Retention-Mode", mode.name())
+                        .header("X-Bz-File-Retention-Retain-Until-Timestamp", retain_until);
+                }
+                request
+                    .header(AUTHORIZATION, &target.auth_token as &str)
                     .header(CONTENT_TYPE, "application/octet-stream")
                     .header(CONTENT_LENGTH, data.len())
                     .header("X-Bz-File-Name", filename.to_string())
                     .header("X-Bz-Content-Sha1", sha1.clone())
                     .header("X-Bz-Info-enc_meta", enc_meta.to_owned())
-                    .body(Body::from(data.clone()))
+                    .body(data.to_body())
+                    .send()
+                    .await
+            })
+            .await?;
+
+        let reply: reply::FileVersion = Self::get_json_reply("upload_file", status, body).await?;
+        Ok(RemoteFileVersion {
+            path: reply.file_name,
+            id: reply.file_id,
+        })
+    }
+
+    /// Uploads a small file through the S3-compatible endpoint, which checks the SHA-256 of the data
+    async fn put_object(
+        &self,
+        s3: &S3Endpoint,
+        filename: &str,
+        data: ReplayableBody,
+        sha256: &str,
+        enc_meta: &str,
+        retention: Option<Retention>,
+    ) -> Result<RemoteFileVersion> {
+        let url = s3.object_url(filename);
+        // B2 keeps x-amz-meta-* as the file info, where listings find the metadata
+        let mut headers = vec![
+            ("content-type", "application/octet-stream".to_owned()),
+            ("content-length", data.len().to_string()),
+            ("x-amz-meta-enc_meta", enc_meta.to_owned()),
+        ];
+        headers.extend(self.sse.iter().flat_map(ServerSideEncryption::s3_upload_headers));
+        if let Some(retention) = retention {
+            headers.push(("x-amz-object-lock-mode", retention.mode.name().to_uppercase()));
+            let retain_until = s3::iso8601(retention.retain_until_millis() / 1000);
+            headers.push(("x-amz-object-lock-retain-until-date", retain_until));
+        }
+
+        let reply = self
+            .timed_request_with_backoff(Stage::Upload, data.len(), false, || async {
+                self.s3_request(s3, Method::PUT, url.clone(), &headers, sha256)
+                    .body(data.to_body())
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json = Self::get_json_reply("upload_file", status, body).await?;
+        let response = match reply {
+            Reply::Success(response) => response,
+            Reply::Failure(status, body) => bail!("put_object failed with {}", reply::failure_reason(status, &body)),
+        };
+        // The S3 version ID of a B2 file is its file ID
+        let file_id = response
+            .headers()
+            .get("x-amz-version-id")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| eyre!("put_object of {} returned no version ID", filename))?;
         Ok(RemoteFileVersion {
-            path: reply_json["fileName"].as_str().unwrap().to_string(),
-            id: reply_json["fileId"].as_str().unwrap().to_string(),
+            path: filename.to_owned(),
+            id: file_id.to_owned(),
         })
     }
 
+    /// A request to the S3-compatible endpoint, signed when it is built so that each retry has a fresh signature
+    fn s3_request(
+        &self,
+        s3: &S3Endpoint,
+        method: Method,
+        url: Url,
+        headers: &[(&str, String)],
+        payload_sha256: &str,
+    ) -> RequestBuilder {
+        let signature = s3.sign(method.as_str(), &url, headers, payload_sha256, SystemTime::now());
+        // The signature's authorization replaces the account token the client sends by default
+        let mut request = self.client().request(method, url);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        for (name, value) in signature {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Uploads file data that must not become visible before `confirm` succeeds.
+    /// A large file is only visible once finished, but it needs at least two parts: shorter streams are kept in
+    /// memory until confirmed, then uploaded in one request.
+    async fn upload_file_stream_confirmed(
+        &self,
+        filename: &str,
+        mut data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
+        enc_meta: &str,
+        confirm: impl Future<Output = Result<()>>,
+    ) -> Result<RemoteFileVersion> {
+        let first = data_stream.next().await.transpose()?;
+        let second = match first {
+            Some(_) => data_stream.next().await.transpose()?,
+            None => None,
+        };
+        match second {
+            None => {
+                confirm.await?;
+                let target = self.get_upload_url().await?;
+                let data_stream = futures::stream::iter(first.map(Ok));
+                self.upload_small_file_stream(&target, filename, data_stream, enc_meta, self.retention)
+                    .await
+            }
+            Some(second) => {
+                let head = futures::stream::iter(first.into_iter().chain(Some(second)).map(Ok));
+                self.upload_large_file_stream_confirmed(
+                    filename,
+                    head.chain(data_stream),
+                    enc_meta,
+                    self.retention,
+                    confirm,
+                )
+                .await
+            }
+        }
+    }
+
     /// Uploads a stream as a large file
     async fn upload_large_file_stream(
         &self,
         filename: &str,
         data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
         enc_meta: &str,
+        retention: Option<Retention>,
+    ) -> Result<RemoteFileVersion> {
+        self.upload_large_file_stream_confirmed(filename, data_stream, enc_meta, retention, async { Ok(()) })
+            .await
+    }
+
+    /// Uploads a stream as a large file, which is only finished if `confirm` succeeds after the last part
+    async fn upload_large_file_stream_confirmed(
+        &self,
+        filename: &str,
+        data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
+        enc_meta: &str,
+        retention: Option<Retention>,
+        confirm: impl Future<Output = Result<()>>,
     ) -> Result<RemoteFileVersion> {
-        let file_id = self.start_large_file(filename, enc_meta).await?;
-        let result = self.upload_large_file_stream_parts(&file_id, data_stream).await;
+        let file_id = self.start_large_file(filename, enc_meta, retention).await?;
+        let result = match self.upload_large_file_stream_parts(&file_id, data_stream).await {
+            Ok(part_hashes) => match confirm.await {
+                Ok(()) => self.finish_large_file(&file_id, &part_hashes).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
 
         if result.is_err() {
             let _ = self.cancel_large_file(&file_id).await;
@@ -544,12 +1273,13 @@ impl B2 {
         result
     }
 
+    /// Uploads the parts of a large file, returns the SHA1 of each part
     async fn upload_large_file_stream_parts(
         &self,
         file_id: &str,
         data_stream: impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync + 'static,
-    ) -> Result<RemoteFileVersion> {
-        let b2upload = self.get_upload_part_url(file_id).await?;
+    ) -> Result<Vec<String>> {
+        let target = self.get_upload_part_url(file_id).await?;
         let mut part_hashes = Vec::<String>::new();
 
         let hashed_stream = HashedStream::new(Box::new(data_stream));
@@ -559,199 +1289,418 @@ impl B2 {
             let (part_data, part_hash) = result?;
 
             let part_num = idx + 1; // Parts are indexed from 1
-            self.upload_part(&b2upload, part_num, &part_hash, part_data).await?;
+            let part_data = ReplayableBody::new(part_data)?;
+            self.upload_part(&target, part_num, &part_hash, &part_data).await?;
             part_hashes.push(part_hash);
         }
-
-        self.finish_large_file(file_id, &part_hashes).await
+        Ok(part_hashes)
     }
 
     async fn upload_part(
         &self,
-        B2Upload {
-            ref upload_url,
+        UploadTarget {
+            ref url,
             ref auth_token,
-        }: &B2Upload,
+        }: &UploadTarget,
         part_index: usize,
         sha1: &str,
-        data: Bytes,
+        data: &ReplayableBody,
     ) -> Result<()> {
         let (status, body) = self
-            .request_with_backoff(|| async {
-                self.client
-                    .post(upload_url)
+            .upload_with_backoff(data.len(), || async {
+                let mut request = self.client().post(url);
+                for (name, value) in self.sse.iter().flat_map(ServerSideEncryption::customer_headers) {
+                    request = request.header(name, value);
+                }
+                request
                     .header(AUTHORIZATION, auth_token)
                     .header(CONTENT_TYPE, "application/octet-stream")
                     .header(CONTENT_LENGTH, data.len())
                     .header("X-Bz-Part-Number", part_index.to_string())
                     .header("X-Bz-Content-Sha1", sha1)
-                    .body(Body::from(data.clone()))
+                    .body(data.to_body())
                     .send()
                     .await
             })
             .await?;
 
-        Self::get_json_reply("upload_file", status, body).await?;
+        Self::get_json_reply::<serde::de::IgnoredAny>("upload_part", status, body).await?;
         Ok(())
     }
 
     async fn finish_large_file(&self, file_id: &str, part_hashes: &[String]) -> Result<RemoteFileVersion> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_finish_large_file").unwrap())
-                    .json(&json!({
-                        "fileId": file_id,
-                        "partSha1Array": part_hashes,
-                    }))
+                    .json(&request::FinishLargeFile {
+                        file_id,
+                        part_sha1_array: part_hashes,
+                    })
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json = Self::get_json_reply("finish_large_file", status, body).await?;
+        let reply: reply::FileVersion = Self::get_json_reply("finish_large_file", status, body).await?;
         Ok(RemoteFileVersion {
-            path: reply_json["fileName"].as_str().unwrap().to_string(),
-            id: reply_json["fileId"].as_str().unwrap().to_string(),
+            path: reply.file_name,
+            id: reply.file_id,
         })
     }
 
     async fn cancel_large_file(&self, file_id: &str) -> Result<()> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_cancel_large_file").unwrap())
-                    .json(&json!({ "fileId": file_id }))
+                    .json(&request::FileId { file_id })
                     .send()
                     .await
             })
             .await?;
 
-        Self::get_json_reply("finish_large_file", status, body).await?;
+        Self::get_json_reply::<serde::de::IgnoredAny>("cancel_large_file", status, body).await?;
         Ok(())
     }
 
-    async fn start_large_file(&self, filename: &str, enc_meta: &str) -> Result<String> {
+    async fn start_large_file(&self, filename: &str, enc_meta: &str, retention: Option<Retention>) -> Result<String> {
+        let request_body = request::StartLargeFile {
+            bucket_id: &self.bucket_id,
+            file_name: filename,
+            content_type: "application/octet-stream",
+            file_info: request::FileInfo { enc_meta },
+            server_side_encryption: self.sse.as_ref().map(ServerSideEncryption::settings),
+            file_retention: retention.map(From::from),
+        };
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_start_large_file").unwrap())
-                    .json(&json!({
-                        "bucketId": self.bucket_id,
-                        "fileName": filename,
-                        "contentType": "application/octet-stream",
-                        "fileInfo": {
-                            "enc_meta": enc_meta
-                        }
-                    }))
+                    .json(&request_body)
                     .send()
                     .await
             })
             .await?;
 
-        let reply_json = Self::get_json_reply("start_large_file", status, body).await?;
-        Ok(reply_json["fileId"].as_str().unwrap().to_string())
+        let reply: reply::StartLargeFile = Self::get_json_reply("start_large_file", status, body).await?;
+        Ok(reply.file_id)
     }
 
-    async fn get_json_reply(api_name: &str, status: StatusCode, body: Bytes) -> Result<Value> {
-        let reply_json: Value = match serde_json::from_slice(&body) {
-            Err(_) => {
-                bail!(
-                    "{} failed to parse json: {}",
-                    api_name,
-                    std::str::from_utf8(&body).unwrap()
-                );
-            }
-            Ok(json) => json,
-        };
-
-        ensure!(
-            status.is_success(),
-            "{} failed with error {}: {}, {}",
-            api_name,
-            status.as_u16(),
-            reply_json["code"],
-            reply_json["message"]
-        );
-        Ok(reply_json)
+    /// The reply of a request, or why it failed (see `reply::parse`)
+    async fn get_json_reply<T: DeserializeOwned>(api_name: &str, status: StatusCode, body: Bytes) -> Result<T> {
+        reply::parse(api_name, status, &body)
     }
 
-    pub async fn download_file(&self, filename: &str) -> Result<Bytes> {
+    async fn download_file(&self, filename: &str) -> Result<Bytes> {
         let res = self.download_file_response(filename).await?;
-        Ok(res.bytes().await?)
+        let start = Instant::now();
+        let data = res.bytes().await?;
+        stats::record(Stage::Download, data.len() as u64, start.elapsed());
+        Ok(data)
     }
 
-    pub async fn download_file_stream(
-        &self,
-        filename: &str,
-    ) -> Result<BoxStream<'static, Result<Bytes, reqwest::Error>>> {
+    async fn download_file_stream(&self, filename: &str) -> Result<BoxStream<'static, Result<Bytes>>> {
+        Ok(self.download_file_stream_sized(filename).await?.1)
+    }
+
+    async fn download_file_stream_sized(&self, filename: &str) -> Result<SizedDownload> {
         let res = self.download_file_response(filename).await?;
-        Ok(res.bytes_stream().boxed())
+        let size = res.content_length();
+        let stream = TimedStream::new(Box::pin(res.bytes_stream()), Stage::Download);
+        Ok((size, stream.map_err(From::from).boxed()))
     }
 
     async fn download_file_response(&self, filename: &str) -> Result<Response> {
-        let (status, body) = self
+        let reply = self
             .request_response_with_backoff(|| async {
-                self.client
-                    .get(self.bucket_download_url.join(filename).unwrap())
-                    .send()
-                    .await
+                if let Some(s3) = &self.s3 {
+                    let headers: Vec<_> = self
+                        .sse
+                        .iter()
+                        .flat_map(ServerSideEncryption::s3_customer_headers)
+                        .collect();
+                    let url = s3.object_url(filename);
+                    return self
+                        .s3_request(s3, Method::GET, url, &headers, s3::EMPTY_SHA256)
+                        .send()
+                        .await;
+                }
+                let mut request = self.client().get(self.bucket_download_url.join(filename).unwrap());
+                for (name, value) in self.sse.iter().flat_map(ServerSideEncryption::customer_headers) {
+                    request = request.header(name, value);
+                }
+                request.send().await
             })
             .await?;
 
-        ensure!(
-            status.is_success(),
-            "Download of {} failed with error {}",
-            filename,
-            status.as_u16()
-        );
-        Ok(body)
+        match reply {
+            Reply::Success(response) => Ok(response),
+            Reply::Failure(StatusCode::NOT_FOUND, _) => Err(NotFound(filename.to_owned()).into()),
+            Reply::Failure(status, _) => bail!("Download of {} failed with error {}", filename, status.as_u16()),
+        }
     }
 
-    pub async fn hide_file(&self, file_path_hash: &str) -> Result<()> {
+    async fn hide_file(&self, file_path_hash: &str) -> Result<()> {
         let (status, body) = self
             .request_with_backoff(|| async {
-                self.client
+                self.client()
                     .post(self.api_url.join("b2_hide_file").unwrap())
-                    .json(&json!({
-                        "bucketId": self.bucket_id,
-                        "fileName": file_path_hash
-                    }))
+                    .json(&request::HideFile {
+                        bucket_id: &self.bucket_id,
+                        file_name: file_path_hash,
+                    })
                     .send()
                     .await
             })
             .await?;
 
         if !status.is_success() {
-            let reply_json: Value = serde_json::from_slice(&body)?;
             bail!(
-                "Hiding of {} failed with error {}: {}",
+                "Hiding of {} failed with {}",
                 file_path_hash,
-                status.as_u16(),
-                reply_json["message"]
+                reply::failure_reason(status, &body)
             );
         }
         Ok(())
     }
 }
 
+impl Backend for B2 {
+    fn key(&self) -> &crypto::Key {
+        &self.key
+    }
+
+    fn object_names(&self) -> ObjectNames {
+        self.names.clone()
+    }
+
+    fn with_progress(&self, progress: ProgressHandler) -> Arc<dyn Backend> {
+        let mut b2 = self.clone();
+        b2.progress.replace(progress);
+        Arc::new(b2)
+    }
+
+    fn list_remote_files<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxFuture<'a, Result<Vec<RemoteFile>>> {
+        B2::list_remote_files(self, prefix, depth).boxed()
+    }
+
+    fn list_remote_files_stream<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxStream<'a, Result<RemoteFile>> {
+        B2::list_remote_files_stream(self, prefix, depth)
+    }
+
+    fn list_file_versions_page<'a>(
+        &'a self,
+        prefix: &'a str,
+        start: Option<&'a RemoteFileVersion>,
+    ) -> BoxFuture<'a, Result<VersionsPage>> {
+        B2::list_file_versions_page(self, prefix, start).boxed()
+    }
+
+    fn list_unfinished_large_files<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(RemoteFile, SystemTime)>>> {
+        B2::list_unfinished_large_files(self, prefix).boxed()
+    }
+
+    fn get_upload_url(&self) -> BoxFuture<'_, Result<UploadTarget>> {
+        B2::get_upload_url(self).boxed()
+    }
+
+    fn upload_file_stream<'a>(
+        &'a self,
+        target: &'a UploadTarget,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: Option<String>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        B2::upload_file_stream(self, target, filename, data_stream, enc_meta).boxed()
+    }
+
+    fn upload_file_stream_confirmed<'a>(
+        &'a self,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: String,
+        confirm: BoxFuture<'a, Result<()>>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move { B2::upload_file_stream_confirmed(self, filename, data_stream, &enc_meta, confirm).await }.boxed()
+    }
+
+    fn download_file_stream<'a>(
+        &'a self,
+        filename: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, Result<Bytes>>>> {
+        B2::download_file_stream(self, filename).boxed()
+    }
+
+    fn download_file_stream_sized<'a>(&'a self, filename: &'a str) -> BoxFuture<'a, Result<SizedDownload>> {
+        B2::download_file_stream_sized(self, filename).boxed()
+    }
+
+    fn file_version_info<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<FileVersionInfo>> {
+        B2::file_version_info(self, file_version).boxed()
+    }
+
+    fn delete_file_version<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<()>> {
+        B2::delete_file_version(self, file_version).boxed()
+    }
+
+    fn copy_file<'a>(
+        &'a self,
+        file_version: &'a RemoteFileVersion,
+        size: u64,
+        filename: &'a str,
+        enc_meta: String,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move { B2::copy_file(self, file_version, size, filename, &enc_meta).await }.boxed()
+    }
+
+    fn hide_file<'a>(&'a self, file_path_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        B2::hide_file(self, file_path_hash).boxed()
+    }
+
+    fn download_file<'a>(&'a self, filename: &'a str) -> BoxFuture<'a, Result<Bytes>> {
+        B2::download_file(self, filename).boxed()
+    }
+
+    fn lifecycle_rules(&self) -> BoxFuture<'_, Result<Vec<LifecycleRule>>> {
+        B2::lifecycle_rules(self).boxed()
+    }
+
+    fn set_lifecycle_rules<'a>(&'a self, rules: &'a [LifecycleRule]) -> BoxFuture<'a, Result<()>> {
+        B2::set_lifecycle_rules(self, rules).boxed()
+    }
+
+    fn share_url<'a>(&'a self, filename: &'a str, valid_for: Duration) -> BoxFuture<'a, Result<String>> {
+        B2::share_url(self, filename, valid_for).boxed()
+    }
+}
+
 #[cfg(test)]
 pub mod test_helpers {
-    use super::{base_client, B2};
-    use crate::crypto::Key;
+    use super::{Authorization, ObjectNames, B2};
+    use crate::crypto::{Key, SecretString};
+    use crate::net::endpoint::ConnectionOptions;
+    use crate::net::health::Connectivity;
+    use crate::net::retry::{RetryBudget, RetryLimits};
     use reqwest::Url;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     pub fn test_b2(key: Key) -> B2 {
         B2 {
             key,
             bucket_id: "bucket_id".to_string(),
             acc_id: "acc_id".to_string(),
-            auth_token: "auth_token".to_string(),
             api_url: Url::from_str("https://example.org/api/").unwrap(),
             bucket_download_url: Url::from_str("https://example.org/download_url/").unwrap(),
-            client: base_client().build().unwrap(),
+            auth: Arc::new(
+                Authorization::new(
+                    SecretString::from(String::new()),
+                    "auth_token",
+                    ConnectionOptions::default(),
+                )
+                .unwrap(),
+            ),
+            retry_limits: RetryLimits::default(),
+            retry_budget: Arc::new(RetryBudget::new(&RetryLimits::default())),
+            connectivity: Arc::new(Connectivity::new(
+                "example.org:443".to_owned(),
+                ConnectionOptions::default(),
+            )),
             progress: None,
+            retention: None,
+            sse: None,
+            names: ObjectNames::plain(),
+            capabilities: Vec::new(),
+            key_buckets: Vec::new(),
+            s3: None,
+            chaos: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_key;
+    use serde_json::json;
+
+    #[test]
+    fn listings_skip_tombstones() -> Result<()> {
+        let key = test_key();
+        let enc_meta = encode_meta(&key, &FileMeta::new_internal(Path::new("a")));
+        let reply = json!({"files": [
+            {"action": "upload", "fileName": "root/a", "fileId": "1", "fileInfo": {"enc_meta": enc_meta}, "contentLength": 3},
+            {"action": "hide", "fileName": "root/b", "fileId": "2", "fileInfo": {}},
+            {"action": "folder", "fileName": "root/c/", "fileId": null, "fileInfo": {}},
+        ]});
+        let listed = listed_files(&serde_json::from_value(reply)?)?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].full_name.as_str(), listed[0].size), ("root/a", 3));
+
+        let future = json!({"files": [{"action": "trash", "fileName": "root/d", "fileInfo": {}}]});
+        assert!(listed_files(&serde_json::from_value(future)?).is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn listed_files_decode_in_order() -> Result<()> {
+        let key = test_key();
+        let listed = (0..DECODE_BATCH_SIZE * 2 + 1)
+            .map(|i| ListedFile {
+                full_name: format!("root/{}", i),
+                id: i.to_string(),
+                enc_meta: encode_meta(&key, &FileMeta::new_internal(Path::new(&i.to_string()))),
+                size: i as u64,
+            })
+            .collect();
+        let batches = decode_listed_files(&key, listed);
+        assert_eq!(batches.len(), 3);
+        let mut files = Vec::new();
+        for batch in batches {
+            files.extend(batch.await??);
+        }
+        assert_eq!(files.len(), DECODE_BATCH_SIZE * 2 + 1);
+        for (i, file) in files.iter().enumerate() {
+            assert_eq!(
+                (file.id.as_str(), file.rel_path.as_path()),
+                (i.to_string().as_str(), Path::new(&i.to_string()))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn injected_faults_are_retried() -> Result<()> {
+        let mut b2 = test_helpers::test_b2(test_key());
+        b2.retry_limits = RetryLimits {
+            max_retries: 1,
+            run_budget: None,
+        };
+        let chaos = Arc::new(RequestChaos::new(crate::net::chaos::ChaosOptions {
+            probability: 1.0,
+            seed: 1,
+        }));
+        b2.chaos = Some(chaos.clone());
+
+        // Every attempt fails before the request is sent, until the retries run out
+        let (status, body) = b2
+            .request_with_backoff(|| async { unreachable!("the request is never sent") })
+            .await?;
+        assert!(status == StatusCode::INTERNAL_SERVER_ERROR || status == StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply::ErrorReply::parse(&body).unwrap().message, "injected fault");
+        assert!(chaos.injected_count() >= 2);
+        Ok(())
+    }
+}
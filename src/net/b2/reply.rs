@@ -0,0 +1,310 @@
+//! The replies of the B2 API that frozen reads
+//!
+//! Fields frozen doesn't use are ignored, so B2 can add new ones. A reply missing a field frozen needs is an error
+//! naming the API call, never a panic. Proxies and load balancers can also answer in B2's place with an HTML page,
+//! only an excerpt of such bodies is shown in errors.
+
+use crate::net::lifecycle::LifecycleRule;
+use crate::net::s3;
+use eyre::{bail, eyre, Result};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// How much of a body that isn't a B2 reply is shown in errors
+const EXCERPT_LEN: usize = 200;
+
+/// The body of a failed request, when it comes from B2: JSON from the native API, XML from the S3-compatible one
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ErrorReply {
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl ErrorReply {
+    /// None if the body isn't a B2 error, e.g. a proxy's error page
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        serde_json::from_slice(body)
+            .ok()
+            .or_else(|| s3::parse_error(body).map(|(code, message)| ErrorReply { code, message }))
+    }
+}
+
+/// The start of a body on one line, as text even if it isn't UTF-8
+pub fn body_excerpt(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(body);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return "(empty body)".to_owned();
+    }
+    match text.char_indices().nth(EXCERPT_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Why a request failed, from B2's error reply or from whatever else answered
+pub fn failure_reason(status: StatusCode, body: &[u8]) -> String {
+    match ErrorReply::parse(body) {
+        Some(ErrorReply { code, message }) if !code.is_empty() || !message.is_empty() => {
+            format!("error {}: {}, {}", status.as_u16(), code, message)
+        }
+        _ => format!(
+            "error {}, the reply isn't from B2: {}",
+            status.as_u16(),
+            body_excerpt(body)
+        ),
+    }
+}
+
+/// Parses the reply of a successful request, or fails with the reason the request failed
+pub fn parse<T: DeserializeOwned>(api_name: &str, status: StatusCode, body: &[u8]) -> Result<T> {
+    if !status.is_success() {
+        bail!("{} failed with {}", api_name, failure_reason(status, body));
+    }
+    serde_json::from_slice(body).map_err(|err| {
+        eyre!(
+            "{} returned an unexpected reply ({}): {}",
+            api_name,
+            err,
+            body_excerpt(body)
+        )
+    })
+}
+
+/// The reply of b2_authorize_account in API v3, where the URLs and permissions are grouped by API
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthorizeAccount {
+    pub account_id: String,
+    pub authorization_token: String,
+    pub api_info: ApiInfo,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiInfo {
+    pub storage_api: StorageApi,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageApi {
+    pub api_url: String,
+    pub download_url: String,
+    /// The S3-compatible endpoint of the account's region
+    pub s3_api_url: Option<String>,
+    #[serde(default)]
+    pub allowed: Allowed,
+}
+
+/// What the app key may do
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Allowed {
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Empty unless the key is restricted to some buckets
+    #[serde(default)]
+    buckets: Vec<AllowedBucket>,
+    /// How older replies name the only bucket of a restricted key
+    bucket_name: Option<String>,
+}
+
+impl Allowed {
+    /// The names of the buckets the key is restricted to, empty if it can access every bucket
+    pub fn bucket_names(&self) -> Vec<String> {
+        let names = self.buckets.iter().filter_map(|bucket| bucket.name.clone());
+        names.chain(self.bucket_name.clone()).collect()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct AllowedBucket {
+    /// None once the bucket is deleted
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListBuckets {
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Bucket {
+    pub bucket_id: String,
+    pub bucket_name: String,
+    #[serde(default)]
+    pub lifecycle_rules: Vec<LifecycleRule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateKey {
+    pub application_key_id: String,
+    pub application_key: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadAuthorization {
+    pub authorization_token: String,
+}
+
+/// A page of `b2_list_file_names`, `b2_list_file_versions` or `b2_list_unfinished_large_files`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListFiles {
+    pub files: Vec<ListedFile>,
+    pub next_file_name: Option<String>,
+    pub next_file_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedFile {
+    pub action: String,
+    pub file_name: String,
+    /// Virtual folders of shallow listings have no ID
+    pub file_id: Option<String>,
+    #[serde(default)]
+    pub file_info: FileInfo,
+    #[serde(default)]
+    pub content_length: u64,
+    #[serde(default)]
+    pub upload_timestamp: u64,
+}
+
+impl ListedFile {
+    pub fn id(&self) -> Result<&str> {
+        self.file_id
+            .as_deref()
+            .ok_or_else(|| eyre!("Listed file {} has no file ID", self.file_name))
+    }
+
+    pub fn enc_meta(&self) -> Result<&str> {
+        self.file_info.enc_meta.as_deref().ok_or_else(|| {
+            eyre!(
+                "Listed file {} has no metadata, it wasn't uploaded by frozen",
+                self.file_name
+            )
+        })
+    }
+}
+
+/// The custom file info of a file, frozen only sets its encrypted metadata
+#[derive(Deserialize, Debug, Default)]
+pub struct FileInfo {
+    pub enc_meta: Option<String>,
+}
+
+/// The file version of an upload, a copy or a finished large file
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersion {
+    pub file_id: String,
+    pub file_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartLargeFile {
+    pub file_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadUrl {
+    pub upload_url: String,
+    pub authorization_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileDetails {
+    pub content_length: u64,
+    /// "none" for large files, which only have the SHA1 of their parts
+    pub content_sha1: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyPart {
+    pub content_sha1: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_that_are_not_from_b2() {
+        let html = b"<html>\n  <head><title>502 Bad Gateway</title></head>\n</html>";
+        let err = parse::<UploadUrl>("get_upload_url", StatusCode::BAD_GATEWAY, html).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "get_upload_url failed with error 502, the reply isn't from B2: <html> <head><title>502 Bad Gateway</title></head> </html>"
+        );
+
+        let b2_error = br#"{"status": 400, "code": "bad_request", "message": "Invalid bucketId"}"#;
+        let err = parse::<UploadUrl>("get_upload_url", StatusCode::BAD_REQUEST, b2_error).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "get_upload_url failed with error 400: bad_request, Invalid bucketId"
+        );
+
+        // A successful reply that isn't the expected JSON, not even UTF-8
+        let err = parse::<UploadUrl>("get_upload_url", StatusCode::OK, b"\xff{\"uploadUrl\"").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("get_upload_url returned an unexpected reply"));
+        assert!(parse::<UploadUrl>("get_upload_url", StatusCode::OK, b"{}").is_err());
+
+        let s3_error = b"<Error><Code>InvalidAccessKeyId</Code><Message>Malformed Access Key Id</Message></Error>";
+        let err = parse::<UploadUrl>("put_object", StatusCode::FORBIDDEN, s3_error).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "put_object failed with error 403: InvalidAccessKeyId, Malformed Access Key Id"
+        );
+
+        let long = "x".repeat(1000);
+        assert_eq!(body_excerpt(long.as_bytes()).len(), EXCERPT_LEN + 3);
+        assert_eq!(body_excerpt(b" \n"), "(empty body)");
+    }
+
+    #[test]
+    fn authorize_reply_of_api_v3() {
+        let body = br#"{
+            "accountId": "account",
+            "authorizationToken": "token",
+            "apiInfo": {
+                "storageApi": {
+                    "absoluteMinimumPartSize": 5000000,
+                    "allowed": {
+                        "buckets": [{"id": "bucket-id", "name": "bucket"}, {"id": "deleted-id", "name": null}],
+                        "capabilities": ["listFiles", "readFiles"],
+                        "namePrefix": null
+                    },
+                    "apiUrl": "https://api004.backblazeb2.com",
+                    "downloadUrl": "https://f004.backblazeb2.com",
+                    "infoType": "storageApi",
+                    "recommendedPartSize": 100000000,
+                    "s3ApiUrl": "https://s3.us-west-004.backblazeb2.com"
+                }
+            },
+            "applicationKeyExpirationTimestamp": null
+        }"#;
+        let reply: AuthorizeAccount = parse("authorize_account", StatusCode::OK, body).unwrap();
+        let storage = reply.api_info.storage_api;
+        assert_eq!(storage.api_url, "https://api004.backblazeb2.com");
+        assert_eq!(
+            storage.s3_api_url.as_deref(),
+            Some("https://s3.us-west-004.backblazeb2.com")
+        );
+        assert_eq!(storage.allowed.bucket_names(), ["bucket"]);
+        assert_eq!(storage.allowed.capabilities, ["listFiles", "readFiles"]);
+    }
+}
@@ -0,0 +1,216 @@
+//! The request bodies of the B2 API calls that frozen makes
+//!
+//! Optional fields are left out of the JSON when they're None, B2 treats a missing field as unset.
+
+use crate::net::lifecycle::LifecycleRule;
+use crate::net::retention::Retention;
+use crate::net::sse::SseSettings;
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListBuckets<'a> {
+    pub account_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_name: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateKey<'a> {
+    pub account_id: &'a str,
+    pub capabilities: &'a [&'a str],
+    pub key_name: &'a str,
+    pub bucket_id: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateBucket<'a> {
+    pub account_id: &'a str,
+    pub bucket_id: &'a str,
+    pub lifecycle_rules: &'a [LifecycleRule],
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GetDownloadAuthorization<'a> {
+    pub bucket_id: &'a str,
+    pub file_name_prefix: &'a str,
+    pub valid_duration_in_seconds: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListFileNames<'a> {
+    pub bucket_id: &'a str,
+    pub max_file_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<&'a str>,
+    pub prefix: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_file_name: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListFileVersions<'a> {
+    pub bucket_id: &'a str,
+    pub max_file_count: u32,
+    pub prefix: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_file_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_file_id: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUnfinishedLargeFiles<'a> {
+    pub bucket_id: &'a str,
+    pub name_prefix: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_file_id: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BucketId<'a> {
+    pub bucket_id: &'a str,
+}
+
+/// The body of the calls about one file version or large file, like b2_get_file_info or b2_cancel_large_file
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileId<'a> {
+    pub file_id: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteFileVersion<'a> {
+    pub file_id: &'a str,
+    pub file_name: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HideFile<'a> {
+    pub bucket_id: &'a str,
+    pub file_name: &'a str,
+}
+
+/// The custom file info of uploads, only the encrypted metadata
+#[derive(Serialize, Debug)]
+pub struct FileInfo<'a> {
+    pub enc_meta: &'a str,
+}
+
+/// Locks the uploaded data, see `Retention`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileRetention {
+    pub mode: &'static str,
+    pub retain_until_timestamp: u64,
+}
+
+impl From<Retention> for FileRetention {
+    fn from(retention: Retention) -> Self {
+        Self {
+            mode: retention.mode.name(),
+            retain_until_timestamp: retention.retain_until_millis(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartLargeFile<'a> {
+    pub bucket_id: &'a str,
+    pub file_name: &'a str,
+    pub content_type: &'static str,
+    pub file_info: FileInfo<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_side_encryption: Option<SseSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_retention: Option<FileRetention>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FinishLargeFile<'a> {
+    pub file_id: &'a str,
+    pub part_sha1_array: &'a [String],
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyFile<'a> {
+    pub source_file_id: &'a str,
+    pub destination_bucket_id: &'a str,
+    pub file_name: &'a str,
+    /// Always "REPLACE", the copy gets its own metadata
+    pub metadata_directive: &'static str,
+    pub content_type: &'static str,
+    pub file_info: FileInfo<'a>,
+    #[serde(flatten)]
+    pub sse: CopySse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_retention: Option<FileRetention>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyPart<'a> {
+    pub source_file_id: &'a str,
+    pub large_file_id: &'a str,
+    pub part_number: usize,
+    pub range: String,
+    #[serde(flatten)]
+    pub sse: CopySse,
+}
+
+/// Copies need the SSE-C key to read the source, and encrypt the copy like an upload
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CopySse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_server_side_encryption: Option<SseSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_server_side_encryption: Option<SseSettings>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn requests_match_the_b2_api() {
+        let copy = CopyPart {
+            source_file_id: "source",
+            large_file_id: "large",
+            part_number: 2,
+            range: "bytes=10-19".to_owned(),
+            sse: CopySse::default(),
+        };
+        assert_eq!(
+            serde_json::to_value(&copy).unwrap(),
+            json!({"sourceFileId": "source", "largeFileId": "large", "partNumber": 2, "range": "bytes=10-19"})
+        );
+
+        let list = ListFileNames {
+            bucket_id: "bucket",
+            max_file_count: 10000,
+            delimiter: None,
+            prefix: "root/",
+            start_file_name: Some("root/b"),
+        };
+        assert_eq!(
+            serde_json::to_value(&list).unwrap(),
+            json!({"bucketId": "bucket", "maxFileCount": 10000, "prefix": "root/", "startFileName": "root/b"})
+        );
+    }
+}
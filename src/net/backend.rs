@@ -0,0 +1,283 @@
+use crate::config::Config;
+use crate::crypto::{AppKeys, Key};
+use crate::data::file::{RemoteFile, RemoteFileVersion};
+use crate::data::names::ObjectNames;
+use crate::net::b2::B2;
+use crate::net::chaos::ChaosBackend;
+use crate::net::lifecycle::LifecycleRule;
+use crate::progress::{self, ProgressHandler};
+use crate::stream::SimpleBytesStream;
+use bytes::Bytes;
+use eyre::{Report, Result};
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// A stream of data to upload, `size_hint` is used to decide whether it needs to be a large file
+pub type UploadStream = Box<dyn Stream<Item = Result<Bytes>> + Unpin + Send + Sync>;
+
+/// Where to send uploads, from `Backend::get_upload_url`. Only the backend that returned it knows what it holds.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UploadTarget {
+    pub(crate) url: String,
+    pub(crate) auth_token: String,
+}
+
+impl UploadTarget {
+    /// For backends outside of this crate, which choose what the URL and token mean
+    pub fn new(url: String, auth_token: String) -> Self {
+        Self { url, auth_token }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn auth_token(&self) -> &str {
+        &self.auth_token
+    }
+}
+
+/// A download along with the size of the file, if the remote tells it before sending it
+pub type SizedDownload = (Option<u64>, BoxStream<'static, Result<Bytes>>);
+
+#[derive(Copy, Clone)]
+pub enum FileListDepth {
+    Shallow,
+    // List only files in the current "folder"
+    Deep, // List every file recursively
+}
+
+/// The error of downloading a file that has no visible version on the remote.
+/// Other download errors don't tell whether the file exists, e.g. a timeout.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to download file \"{}\": not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Whether an error is (or was caused by) a `NotFound`
+pub fn is_not_found(err: &Report) -> bool {
+    err.downcast_ref::<NotFound>().is_some()
+}
+
+/// What the remote holds for an uploaded file version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileVersionInfo {
+    pub size: u64,
+    /// Hex SHA1 of the data, when the remote knows it (B2 doesn't for large files)
+    pub sha1: Option<String>,
+}
+
+/// A page of `Backend::list_file_versions_page`
+pub struct VersionsPage {
+    /// Versions of the same file are listed together, newest first
+    pub versions: Vec<(RemoteFileVersion, SystemTime)>,
+    /// Where the next page starts, if there's one
+    pub next: Option<RemoteFileVersion>,
+}
+
+/// The remote storage that backups are saved to
+///
+/// File names are opaque hashes (see `crypto`), and all data passed through a backend is already
+/// encrypted. Backends are expected to handle their own retries for temporary failures.
+pub trait Backend: Send + Sync {
+    /// The key used to encrypt file data and metadata
+    fn key(&self) -> &Key;
+
+    /// The names of the list of roots and of the DirDBs
+    fn object_names(&self) -> ObjectNames;
+
+    /// Returns a copy of this backend that reports warnings through a progress bar
+    fn with_progress(&self, progress: ProgressHandler) -> Arc<dyn Backend>;
+
+    /// Lists the latest version of the files under `prefix`, decoding their metadata
+    fn list_remote_files<'a>(&'a self, prefix: &'a str, depth: FileListDepth)
+        -> BoxFuture<'a, Result<Vec<RemoteFile>>>;
+
+    /// Same as `list_remote_files`, but returns the files as soon as they're decoded, in the same order
+    fn list_remote_files_stream<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxStream<'a, Result<RemoteFile>> {
+        self.list_remote_files(prefix, depth)
+            .map_ok(|files| futures::stream::iter(files.into_iter().map(Ok)))
+            .try_flatten_stream()
+            .boxed()
+    }
+
+    /// Lists one page of the versions under `prefix` along with the time they were uploaded at,
+    /// starting at `start` (included) or at the beginning
+    fn list_file_versions_page<'a>(
+        &'a self,
+        prefix: &'a str,
+        start: Option<&'a RemoteFileVersion>,
+    ) -> BoxFuture<'a, Result<VersionsPage>>;
+
+    /// Lists uploads that were started but never finished under `prefix`, along with the time they were started at
+    fn list_unfinished_large_files<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(RemoteFile, SystemTime)>>>;
+
+    /// Returns an upload target, which may be reused for several successive uploads
+    fn get_upload_url(&self) -> BoxFuture<'_, Result<UploadTarget>>;
+
+    /// Uploads a stream of data as a new version of `filename`
+    fn upload_file_stream<'a>(
+        &'a self,
+        target: &'a UploadTarget,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: Option<String>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>>;
+
+    /// Same as `upload_file_stream`, but the new version only becomes visible once the whole stream is uploaded
+    /// and `confirm` succeeds. If either fails, nothing is left on the remote and the error is returned.
+    fn upload_file_stream_confirmed<'a>(
+        &'a self,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: String,
+        confirm: BoxFuture<'a, Result<()>>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>>;
+
+    /// Downloads the latest version of a file, fails with `NotFound` if it has none
+    fn download_file_stream<'a>(
+        &'a self,
+        filename: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, Result<Bytes>>>>;
+
+    /// Same as `download_file_stream`, along with the size of the file if the remote knows it
+    fn download_file_stream_sized<'a>(&'a self, filename: &'a str) -> BoxFuture<'a, Result<SizedDownload>> {
+        async move { Ok((None, self.download_file_stream(filename).await?)) }.boxed()
+    }
+
+    /// Asks the remote what it stored for a file version, without downloading it
+    fn file_version_info<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<FileVersionInfo>>;
+
+    fn delete_file_version<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<()>>;
+
+    /// Copies a file version of this account to `filename` in this bucket, without downloading it.
+    /// The copy gets new metadata, `size` is the size of the stored version.
+    fn copy_file<'a>(
+        &'a self,
+        file_version: &'a RemoteFileVersion,
+        size: u64,
+        filename: &'a str,
+        enc_meta: String,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>>;
+
+    /// Hides a file, so that it isn't listed anymore but its versions are kept
+    fn hide_file<'a>(&'a self, file_path_hash: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// The lifecycle rules of the bucket
+    fn lifecycle_rules(&self) -> BoxFuture<'_, Result<Vec<LifecycleRule>>>;
+
+    /// Replaces all the lifecycle rules of the bucket
+    fn set_lifecycle_rules<'a>(&'a self, rules: &'a [LifecycleRule]) -> BoxFuture<'a, Result<()>>;
+
+    /// A URL that downloads the latest version of `filename` without credentials, until it expires
+    fn share_url<'a>(&'a self, filename: &'a str, valid_for: Duration) -> BoxFuture<'a, Result<String>>;
+
+    /// Lists every version of every file under `prefix`, along with the time it was uploaded at
+    fn list_remote_file_versions_timed<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(RemoteFileVersion, SystemTime)>>> {
+        async move {
+            let mut versions = Vec::new();
+            let mut start = None;
+            loop {
+                let page = self.list_file_versions_page(prefix, start.as_ref()).await?;
+                versions.extend(page.versions);
+                match page.next {
+                    Some(next) => start = Some(next),
+                    None => return Ok(versions),
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn list_remote_file_versions<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<RemoteFileVersion>>> {
+        async move {
+            let versions = self.list_remote_file_versions_timed(prefix).await?;
+            Ok(versions.into_iter().map(|(version, _)| version).collect())
+        }
+        .boxed()
+    }
+
+    fn download_file<'a>(&'a self, filename: &'a str) -> BoxFuture<'a, Result<Bytes>> {
+        async move {
+            let mut stream = self.download_file_stream(filename).await?;
+            let mut data = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            Ok(data.into())
+        }
+        .boxed()
+    }
+
+    fn upload_file<'a>(
+        &'a self,
+        target: &'a UploadTarget,
+        filename: &'a str,
+        data: Vec<u8>,
+        enc_meta: Option<String>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        let data_stream = Box::new(SimpleBytesStream::new(data.into()));
+        self.upload_file_stream(target, filename, data_stream, enc_meta)
+    }
+
+    fn upload_file_simple<'a>(&'a self, filename: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            let upload_url = self.get_upload_url().await?;
+            self.upload_file(&upload_url, filename, data, None).await
+        }
+        .boxed()
+    }
+}
+
+/// Connects to another bucket of the account, or returns `default` if `bucket` is None or the bucket of the config
+pub async fn connect_bucket(
+    config: &Config,
+    keys: &AppKeys,
+    default: &Arc<dyn Backend>,
+    bucket: Option<&str>,
+) -> Result<Arc<dyn Backend>> {
+    match bucket {
+        Some(bucket) if bucket != config.bucket_name => {
+            let mut config = config.clone();
+            config.bucket_name = bucket.to_owned();
+            connect(&config, keys).await
+        }
+        _ => Ok(default.clone()),
+    }
+}
+
+/// Connects to Backblaze B2, wrapped in a `ChaosBackend` if fault injection is enabled.
+/// B2 then also fails some of its requests itself, which are retried (see `RequestChaos`).
+pub async fn connect(config: &Config, keys: &AppKeys) -> Result<Arc<dyn Backend>> {
+    let b2: Arc<dyn Backend> = Arc::new(B2::authenticate(config, keys).await?);
+    Ok(match config.chaos {
+        Some(options) => {
+            progress::log(format!(
+                "Chaos mode: disrupting {}% of operations (seed {})",
+                options.probability * 100.0,
+                options.seed
+            ));
+            Arc::new(ChaosBackend::new(b2, options))
+        }
+        None => b2,
+    })
+}
@@ -0,0 +1,32 @@
+//! Daily caps of the Backblaze B2 account: once one is reached, every request of that kind fails until the next day
+//!
+//! The first refused request marks the cap as reached, then transfers stop being started (see `action`), and the
+//! run ends with `Failure::CapReached`. What was done is kept: the next backup only diffs what's left, and restores
+//! resume from their checkpoint.
+
+use crate::failure::Failure;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CAP_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a B2 error code means a daily cap was reached (storage, download, or class B/C transactions)
+pub fn is_cap_error(code: &str) -> bool {
+    code.ends_with("cap_exceeded")
+}
+
+pub fn set_reached() {
+    CAP_REACHED.store(true, Ordering::Release);
+}
+
+pub fn is_reached() -> bool {
+    CAP_REACHED.load(Ordering::Acquire)
+}
+
+/// The failure of a run that didn't complete, because of the cap if it was reached
+pub fn incomplete_failure(errors: usize) -> Failure {
+    if is_reached() {
+        Failure::CapReached
+    } else {
+        Failure::Incomplete { errors }
+    }
+}
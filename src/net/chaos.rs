@@ -0,0 +1,379 @@
+use crate::crypto::Key;
+use crate::data::file::{RemoteFile, RemoteFileVersion};
+use crate::data::names::ObjectNames;
+use crate::net::backend::{Backend, FileListDepth, FileVersionInfo, UploadStream, UploadTarget, VersionsPage};
+use crate::net::lifecycle::LifecycleRule;
+use crate::progress::ProgressHandler;
+use async_stream::stream;
+use bytes::Bytes;
+use eyre::{bail, Result};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Settings of the fault injection mode (the hidden `--chaos` flag)
+#[derive(Clone, Copy, Debug)]
+pub struct ChaosOptions {
+    /// Probability that any given operation is disrupted, between 0 and 1
+    pub probability: f64,
+    /// The same seed injects the same sequence of faults, as long as operations run in the same order
+    pub seed: u64,
+}
+
+impl ChaosOptions {
+    pub fn new(probability: f64, seed: Option<u64>) -> Result<Self> {
+        if !(0.0..=1.0).contains(&probability) {
+            bail!("Chaos probability must be between 0 and 1, got {}", probability);
+        }
+        let seed = seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+        Ok(Self { probability, seed })
+    }
+}
+
+enum Fault {
+    ServerError,
+    Slow(Duration),
+}
+
+/// The seeded random sequence that decides which operations are disrupted
+#[derive(Clone)]
+struct Dice {
+    probability: f64,
+    /// Shared by the copies of a backend, so they draw from the same sequence
+    rng_state: Arc<Mutex<u64>>,
+}
+
+impl Dice {
+    fn new(probability: f64, seed: u64) -> Self {
+        Self {
+            probability,
+            // Xorshift gets stuck on a zero state
+            rng_state: Arc::new(Mutex::new(seed.max(1))),
+        }
+    }
+
+    /// Xorshift64*, good enough to pick faults and small enough to not need a dependency
+    fn next_random(&self) -> u64 {
+        let mut state = self.rng_state.lock().unwrap();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a random float in [0, 1)
+    fn next_unit(&self) -> f64 {
+        (self.next_random() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn roll(&self) -> bool {
+        self.next_unit() < self.probability
+    }
+}
+
+/// Fails B2 requests before they're sent, with a 500 or 503 reply that goes through the retries like a real one
+/// (see `B2::retry_request`). `ChaosBackend` faults end the operation instead, as if its retries were exhausted.
+pub struct RequestChaos {
+    dice: Dice,
+    injected: AtomicU32,
+}
+
+impl RequestChaos {
+    pub fn new(options: ChaosOptions) -> Self {
+        Self {
+            // Not the sequence of the `ChaosBackend` wrapping the same B2
+            dice: Dice::new(options.probability, options.seed.rotate_left(32)),
+            injected: AtomicU32::new(0),
+        }
+    }
+
+    /// The failed reply to handle instead of sending the request, if this attempt is disrupted
+    pub fn injected_reply(&self) -> Option<(StatusCode, Bytes)> {
+        if !self.dice.roll() {
+            return None;
+        }
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        let (status, code) = if self.dice.next_unit() < 0.5 {
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "service_unavailable")
+        };
+        let body = format!(
+            r#"{{"status": {}, "code": "{}", "message": "injected fault"}}"#,
+            status.as_u16(),
+            code
+        );
+        Some((status, Bytes::from(body)))
+    }
+
+    /// How many requests were failed so far
+    pub fn injected_count(&self) -> u32 {
+        self.injected.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a backend and randomly disrupts its operations, to exercise error handling and resuming
+///
+/// Disrupted operations either fail as if the server returned a 500 too many times, or are delayed. Downloads
+/// can also be cut short without any error, like a connection closed too early.
+pub struct ChaosBackend {
+    inner: Arc<dyn Backend>,
+    dice: Dice,
+}
+
+impl ChaosBackend {
+    pub fn new(inner: Arc<dyn Backend>, options: ChaosOptions) -> Self {
+        Self {
+            inner,
+            dice: Dice::new(options.probability, options.seed),
+        }
+    }
+
+    fn pick_fault(&self) -> Option<Fault> {
+        if !self.dice.roll() {
+            return None;
+        }
+        if self.dice.next_unit() < 0.5 {
+            Some(Fault::ServerError)
+        } else {
+            Some(Fault::Slow(Duration::from_millis(100 + self.dice.next_random() % 900)))
+        }
+    }
+
+    async fn disrupt(&self, operation: &str) -> Result<()> {
+        match self.pick_fault() {
+            Some(Fault::ServerError) => bail!("{} failed: injected fault, 500 Internal Server Error", operation),
+            Some(Fault::Slow(delay)) => tokio::time::sleep(delay).await,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+impl Backend for ChaosBackend {
+    fn key(&self) -> &Key {
+        self.inner.key()
+    }
+
+    fn object_names(&self) -> ObjectNames {
+        self.inner.object_names()
+    }
+
+    fn with_progress(&self, progress: ProgressHandler) -> Arc<dyn Backend> {
+        Arc::new(ChaosBackend {
+            inner: self.inner.with_progress(progress),
+            dice: self.dice.clone(),
+        })
+    }
+
+    fn list_remote_files<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxFuture<'a, Result<Vec<RemoteFile>>> {
+        async move {
+            self.disrupt("list_remote_files").await?;
+            self.inner.list_remote_files(prefix, depth).await
+        }
+        .boxed()
+    }
+
+    fn list_file_versions_page<'a>(
+        &'a self,
+        prefix: &'a str,
+        start: Option<&'a RemoteFileVersion>,
+    ) -> BoxFuture<'a, Result<VersionsPage>> {
+        async move {
+            self.disrupt("list_file_versions").await?;
+            self.inner.list_file_versions_page(prefix, start).await
+        }
+        .boxed()
+    }
+
+    fn list_unfinished_large_files<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(RemoteFile, SystemTime)>>> {
+        async move {
+            self.disrupt("list_unfinished_large_files").await?;
+            self.inner.list_unfinished_large_files(prefix).await
+        }
+        .boxed()
+    }
+
+    fn get_upload_url(&self) -> BoxFuture<'_, Result<UploadTarget>> {
+        async move {
+            self.disrupt("get_upload_url").await?;
+            self.inner.get_upload_url().await
+        }
+        .boxed()
+    }
+
+    fn upload_file_stream<'a>(
+        &'a self,
+        target: &'a UploadTarget,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: Option<String>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            self.disrupt("upload_file").await?;
+            self.inner
+                .upload_file_stream(target, filename, data_stream, enc_meta)
+                .await
+        }
+        .boxed()
+    }
+
+    fn upload_file_stream_confirmed<'a>(
+        &'a self,
+        filename: &'a str,
+        data_stream: UploadStream,
+        enc_meta: String,
+        confirm: BoxFuture<'a, Result<()>>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            self.disrupt("upload_file").await?;
+            self.inner
+                .upload_file_stream_confirmed(filename, data_stream, enc_meta, confirm)
+                .await
+        }
+        .boxed()
+    }
+
+    fn download_file_stream<'a>(
+        &'a self,
+        filename: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, Result<Bytes>>>> {
+        async move {
+            self.disrupt("download_file").await?;
+            let mut data_stream = self.inner.download_file_stream(filename).await?;
+            if !self.dice.roll() {
+                return Ok(data_stream);
+            }
+
+            // Silently drop the end of the download, starting somewhere in the first chunk
+            let cut_fraction = self.dice.next_unit();
+            Ok(stream! {
+                if let Some(chunk) = data_stream.next().await {
+                    yield chunk.map(|chunk| chunk.slice(..(chunk.len() as f64 * cut_fraction) as usize));
+                }
+            }
+            .boxed())
+        }
+        .boxed()
+    }
+
+    fn file_version_info<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<FileVersionInfo>> {
+        async move {
+            self.disrupt("file_version_info").await?;
+            self.inner.file_version_info(file_version).await
+        }
+        .boxed()
+    }
+
+    fn delete_file_version<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.disrupt("delete_file_version").await?;
+            self.inner.delete_file_version(file_version).await
+        }
+        .boxed()
+    }
+
+    fn copy_file<'a>(
+        &'a self,
+        file_version: &'a RemoteFileVersion,
+        size: u64,
+        filename: &'a str,
+        enc_meta: String,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            self.disrupt("copy_file").await?;
+            self.inner.copy_file(file_version, size, filename, enc_meta).await
+        }
+        .boxed()
+    }
+
+    fn hide_file<'a>(&'a self, file_path_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.disrupt("hide_file").await?;
+            self.inner.hide_file(file_path_hash).await
+        }
+        .boxed()
+    }
+
+    fn lifecycle_rules(&self) -> BoxFuture<'_, Result<Vec<LifecycleRule>>> {
+        async move {
+            self.disrupt("lifecycle_rules").await?;
+            self.inner.lifecycle_rules().await
+        }
+        .boxed()
+    }
+
+    fn set_lifecycle_rules<'a>(&'a self, rules: &'a [LifecycleRule]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.disrupt("set_lifecycle_rules").await?;
+            self.inner.set_lifecycle_rules(rules).await
+        }
+        .boxed()
+    }
+
+    fn share_url<'a>(&'a self, filename: &'a str, valid_for: Duration) -> BoxFuture<'a, Result<String>> {
+        async move {
+            self.disrupt("share_url").await?;
+            self.inner.share_url(filename, valid_for).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::test_helpers::test_key;
+
+    fn chaos_memory(probability: f64, seed: u64) -> (MemoryBackend, ChaosBackend) {
+        let memory = MemoryBackend::new(test_key());
+        let options = ChaosOptions::new(probability, Some(seed)).unwrap();
+        let chaos = ChaosBackend::new(Arc::new(memory.clone()), options);
+        (memory, chaos)
+    }
+
+    #[tokio::test]
+    async fn no_chaos_passes_through() -> Result<()> {
+        let (memory, chaos) = chaos_memory(0.0, 1);
+        for _ in 0..20 {
+            chaos.upload_file_simple("file", vec![1, 2, 3]).await?;
+            assert_eq!(&chaos.download_file("file").await?[..], &[1, 2, 3]);
+        }
+        assert_eq!(memory.versions_count(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_chaos_always_disrupts() {
+        let (memory, chaos) = chaos_memory(1.0, 1);
+        memory.upload_file_simple("file", vec![7; 100]).await.unwrap();
+        for _ in 0..20 {
+            match chaos.download_file("file").await {
+                Ok(data) => assert!(data.len() < 100),
+                Err(err) => assert!(format!("{}", err).contains("injected fault")),
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let (_, first) = chaos_memory(0.5, 42);
+        let (_, second) = chaos_memory(0.5, 42);
+        let first_rolls: Vec<bool> = (0..64).map(|_| first.dice.roll()).collect();
+        let second_rolls: Vec<bool> = (0..64).map(|_| second.dice.roll()).collect();
+        assert_eq!(first_rolls, second_rolls);
+        assert!(first_rolls.contains(&true) && first_rolls.contains(&false));
+        assert!(ChaosOptions::new(1.5, None).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! How connections to B2 are opened, for networks where B2 is only reachable one way,
+//! or where TLS goes through a corporate proxy or must match known keys
+
+use data_encoding::BASE64;
+use eyre::{bail, eyre, Result, WrapErr};
+use reqwest::{Certificate, ClientBuilder};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{CertificateError, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Only connect over this IP version, e.g. when IPv6 to B2 is broken. Both are tried if missing.
+    #[serde(default)]
+    pub ip_version: Option<IpVersion>,
+    /// Give up on a connection attempt after this long, instead of waiting for the system's timeout
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// A PEM bundle of extra CAs to trust, e.g. the CA of a proxy that intercepts TLS
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// If not empty, B2 servers must present a certificate whose key is one of these,
+    /// as the base64 SHA-256 of its SubjectPublicKeyInfo (like `pin-sha256` in HPKP).
+    /// Checked during the TLS handshake, so nothing is sent to a server whose key isn't pinned.
+    #[serde(default)]
+    pub pinned_keys: Vec<String>,
+}
+
+impl ConnectionOptions {
+    pub fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        // Binding to the unspecified address of a version only lets the connections use that version
+        let builder = match self.ip_version {
+            Some(IpVersion::Ipv4) => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            Some(IpVersion::Ipv6) => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            None => builder,
+        };
+        let mut builder = match self.connect_timeout_secs {
+            Some(secs) => builder.connect_timeout(Duration::from_secs(secs)),
+            None => builder,
+        };
+        // The pins are checked by our own TLS config, which then has to trust the extra CAs itself
+        if !self.pinned_keys.is_empty() {
+            return Ok(builder.use_preconfigured_tls(self.pinned_tls_config()?));
+        }
+        if let Some(ca_file) = &self.ca_file {
+            let certs = Certificate::from_pem_bundle(&self.read_ca_file()?)
+                .wrap_err_with(|| format!("Invalid CA file {}", ca_file.display()))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(builder)
+    }
+
+    fn read_ca_file(&self) -> Result<Vec<u8>> {
+        let ca_file = self.ca_file.as_ref().expect("No CA file");
+        fs::read(ca_file).wrap_err_with(|| format!("Failed to read CA file {}", ca_file.display()))
+    }
+
+    /// Trusts the same CAs as the default config, and checks the pinned keys once a certificate is trusted
+    fn pinned_tls_config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        }));
+        if let Some(ca_file) = &self.ca_file {
+            let certs = rustls_pemfile::certs(&mut self.read_ca_file()?.as_slice())
+                .wrap_err_with(|| format!("Invalid CA file {}", ca_file.display()))?;
+            for cert in certs {
+                roots
+                    .add(&rustls::Certificate(cert))
+                    .wrap_err_with(|| format!("Invalid CA file {}", ca_file.display()))?;
+            }
+        }
+        let verifier = PinnedKeyVerifier {
+            trusted: WebPkiVerifier::new(roots, None),
+            pinned_keys: self.pinned_keys.iter().map(|pin| pin.trim().to_owned()).collect(),
+        };
+        Ok(ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth())
+    }
+
+    /// The addresses of `endpoint` (host:port) we may connect to
+    pub fn resolve(&self, endpoint: &str) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<_> = endpoint.to_socket_addrs()?.filter(|addr| self.accepts(addr)).collect();
+        if addrs.is_empty() {
+            match self.ip_version {
+                Some(version) => bail!("{} has no {:?} address", endpoint, version),
+                None => bail!("{} has no address", endpoint),
+            }
+        }
+        Ok(addrs)
+    }
+
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        match self.ip_version {
+            Some(IpVersion::Ipv4) => addr.is_ipv4(),
+            Some(IpVersion::Ipv6) => addr.is_ipv6(),
+            None => true,
+        }
+    }
+}
+
+/// Refuses the servers whose certificate isn't trusted, or whose key isn't pinned
+struct PinnedKeyVerifier {
+    trusted: WebPkiVerifier,
+    pinned_keys: Vec<String>,
+}
+
+/// The error of a certificate whose key isn't pinned, which retrying won't change (see `is_certificate_error`)
+struct UnpinnedKey(String);
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.trusted
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        check_pinned_key(&self.pinned_keys, &end_entity.0).map_err(|err| {
+            let host = match server_name {
+                ServerName::DnsName(name) => name.as_ref().to_owned(),
+                ServerName::IpAddress(addr) => addr.to_string(),
+                _ => "The server".to_owned(),
+            };
+            let err = UnpinnedKey(format!("{} {}", host, err));
+            rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(err)))
+        })?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Checks that the key of a DER certificate is one of the pinned keys
+fn check_pinned_key(pinned_keys: &[String], cert: &[u8]) -> Result<()> {
+    let spki = subject_public_key_info(cert).ok_or_else(|| eyre!("sent a certificate that failed to parse"))?;
+    let pin = BASE64.encode(&sha256::hash(spki).0);
+    if !pinned_keys.contains(&pin) {
+        bail!("has a certificate key ({}) that is not one of the pinned keys", pin);
+    }
+    Ok(())
+}
+
+/// Whether a request failed because the server's certificate was refused, e.g. its key isn't pinned
+pub fn is_certificate_error(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        // The TLS error is wrapped in an I/O error, whose own source skips it
+        let wrapped = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|err| err.get_ref())
+            .map(|err| err as &(dyn StdError + 'static));
+        for err in std::iter::once(err).chain(wrapped) {
+            if let Some(rustls::Error::InvalidCertificate(_)) = err.downcast_ref::<rustls::Error>() {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl fmt::Display for UnpinnedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// rustls shows the `Debug` of certificate errors
+impl fmt::Debug for UnpinnedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl StdError for UnpinnedKey {}
+
+/// The encoded SubjectPublicKeyInfo of a DER certificate
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // tbsCertificate ::= SEQUENCE { [0] version OPTIONAL, serial, signature, issuer, validity, subject, spki, .. }
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs)?.2;
+    }
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (header_len, content, _) = der_element(tbs)?;
+    Some(&tbs[..header_len + content.len()])
+}
+
+/// Splits the first DER element of `input` into its header length, its content, and the rest of the input
+fn der_element(input: &[u8]) -> Option<(usize, &[u8], &[u8])> {
+    let first_len = *input.get(1)?;
+    let (header_len, len) = if first_len < 0x80 {
+        (2, first_len as usize)
+    } else {
+        let len_bytes = (first_len & 0x7F) as usize;
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0usize, |len, &byte| len << 8 | byte as usize);
+        (2 + len_bytes, len)
+    };
+    let content = input.get(header_len..header_len + len)?;
+    Some((header_len, content, &input[header_len + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_one_ip_version() -> Result<()> {
+        let options: ConnectionOptions = serde_json::from_str(r#"{"ip_version": "ipv4"}"#)?;
+        assert_eq!(options.ip_version, Some(IpVersion::Ipv4));
+        assert_eq!(options.resolve("127.0.0.1:443")?.len(), 1);
+        assert!(options.resolve("[::1]:443").is_err());
+        assert_eq!(ConnectionOptions::default().resolve("[::1]:443")?.len(), 1);
+        Ok(())
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        if content.len() < 0x80 {
+            element.push(content.len() as u8);
+        } else {
+            element.extend_from_slice(&[0x82, (content.len() >> 8) as u8, content.len() as u8]);
+        }
+        element.extend_from_slice(content);
+        element
+    }
+
+    #[test]
+    fn finds_certificate_key() {
+        let spki = der(0x30, &[0x42; 200]);
+        let tbs = [
+            der(0xA0, &der(0x02, &[2])),
+            der(0x02, &[1, 2, 3]),
+            der(0x30, &[]),
+            der(0x30, &[0x31; 3]),
+            der(0x30, &[0x17; 4]),
+            der(0x30, &[0x31; 5]),
+            spki.clone(),
+            der(0xA3, &[0; 10]),
+        ]
+        .concat();
+        let cert = der(0x30, &[der(0x30, &tbs), der(0x30, &[]), der(0x03, &[0; 8])].concat());
+        assert_eq!(subject_public_key_info(&cert), Some(&spki[..]));
+        assert_eq!(subject_public_key_info(&cert[..cert.len() / 2]), None);
+
+        let pin = BASE64.encode(&sha256::hash(&spki).0);
+        assert!(check_pinned_key(&[pin], &cert).is_ok());
+        assert!(check_pinned_key(&["AAAA".to_owned()], &cert).is_err());
+        assert!(check_pinned_key(&["AAAA".to_owned()], &cert[..cert.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn refused_certificates_are_found_in_the_error() {
+        let unpinned = rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(UnpinnedKey(
+            "b2 has a certificate key".to_owned(),
+        ))));
+        assert!(unpinned.to_string().contains("b2 has a certificate key"));
+        let io_err = std::io::Error::new(std::io::ErrorKind::InvalidData, unpinned);
+        assert!(is_certificate_error(&io_err));
+        let other = std::io::Error::new(std::io::ErrorKind::InvalidData, rustls::Error::DecryptError);
+        assert!(!is_certificate_error(&other));
+    }
+}
@@ -0,0 +1,133 @@
+//! Detects when the network or B2 is unreachable as a whole, instead of letting every request retry on its own
+//!
+//! When a request gets no reply, one request checks whether B2 can be reached at all. If it can't, every request
+//! waits for the connection to come back, and the run fails with a clear message if it stays down for too long.
+
+use crate::data::duration::format_duration;
+use crate::net::endpoint::ConnectionOptions;
+use crate::progress::{self, ProgressHandler};
+use eyre::{bail, eyre, Result, WrapErr};
+use std::env;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// How long a connection attempt may take before B2 is considered unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the connection is checked while B2 is unreachable
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long we wait for the connection to come back before the run fails
+const OFFLINE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Whether B2 can be reached, shared by the clones of a `B2`
+pub struct Connectivity {
+    /// The host:port of the B2 API
+    endpoint: String,
+    options: ConnectionOptions,
+    /// Only one request checks the connection at a time, the others wait for its answer
+    probing: tokio::sync::Mutex<()>,
+}
+
+impl Connectivity {
+    pub fn new(endpoint: String, options: ConnectionOptions) -> Self {
+        Self {
+            endpoint,
+            options,
+            probing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Called when a request got no reply. Returns right away if B2 is reachable, which means the failure only
+    /// concerned that request, or waits for the connection to come back. Returns whether B2 was reachable right away.
+    pub async fn wait_online(&self, progress: &Option<ProgressHandler>) -> Result<bool> {
+        let _probing = self.probing.lock().await;
+        if probe(&self.endpoint, &self.options).await.is_ok() {
+            return Ok(true);
+        }
+
+        let offline_since = Instant::now();
+        print(
+            progress,
+            &format!(
+                "{} is unreachable, waiting for the connection to come back",
+                self.endpoint
+            ),
+        );
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            match probe(&self.endpoint, &self.options).await {
+                Ok(()) => {
+                    print(progress, "The connection is back");
+                    return Ok(false);
+                }
+                Err(err) if offline_since.elapsed() >= OFFLINE_TIMEOUT => {
+                    return Err(err.wrap_err(format!(
+                        "Lost the connection to B2 for {}, check the network",
+                        format_duration(OFFLINE_TIMEOUT)
+                    )));
+                }
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Checks that a TCP connection to `endpoint` (host:port) can be opened, before starting a command
+pub async fn check_reachable(endpoint: &str, options: &ConnectionOptions) -> Result<()> {
+    probe(endpoint, options)
+        .await
+        .wrap_err_with(|| format!("Can't reach {}, check the network connection", endpoint))
+}
+
+async fn probe(endpoint: &str, options: &ConnectionOptions) -> Result<()> {
+    // Requests go through the proxy, which may be the only way out, so there's nothing to check directly
+    if behind_proxy() {
+        return Ok(());
+    }
+    let (endpoint, options) = (endpoint.to_owned(), options.clone());
+    tokio::task::spawn_blocking(move || {
+        let mut last_err = None;
+        for addr in options.resolve(&endpoint)? {
+            match TcpStream::connect_timeout(&addr, PROBE_TIMEOUT) {
+                Ok(_) => return Ok(()),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match last_err {
+            Some(err) => Err(eyre!(err)),
+            None => bail!("{} has no address", endpoint),
+        }
+    })
+    .await?
+}
+
+/// Whether requests are sent through a proxy, which reqwest picks from the environment
+fn behind_proxy() -> bool {
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .any(|name| env::var_os(name).is_some_and(|value| !value.is_empty()))
+}
+
+fn print(progress: &Option<ProgressHandler>, msg: &str) {
+    match progress {
+        Some(progress) => progress.println(format!("Warning: {}", msg)),
+        None => progress::log(format!("Warning: {}", msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn detects_reachable_endpoints() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = listener.local_addr()?.to_string();
+        let options = ConnectionOptions::default();
+        check_reachable(&endpoint, &options).await?;
+        assert!(Connectivity::new(endpoint, options.clone()).wait_online(&None).await?);
+
+        assert!(check_reachable("127.0.0.1:1", &options).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// A lifecycle rule of the bucket, which makes B2 hide or delete old file versions by itself
+///
+/// Frozen hides the files deleted from a backup, so `days_from_hiding_to_deleting` is what eventually frees their space.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleRule {
+    /// The rule applies to the files whose name starts with this, an empty prefix is the whole bucket
+    pub file_name_prefix: String,
+    pub days_from_uploading_to_hiding: Option<u32>,
+    pub days_from_hiding_to_deleting: Option<u32>,
+}
+
+/// Replaces the rule for `rule.file_name_prefix`, or removes it if the rule has no days set.
+/// B2 only allows one rule per prefix.
+pub fn set_rule(rules: &mut Vec<LifecycleRule>, rule: LifecycleRule) {
+    rules.retain(|existing| existing.file_name_prefix != rule.file_name_prefix);
+    if rule.days_from_uploading_to_hiding.is_some() || rule.days_from_hiding_to_deleting.is_some() {
+        rules.push(rule);
+        rules.sort_by(|a, b| a.file_name_prefix.cmp(&b.file_name_prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rules_match_the_b2_api() {
+        let rules: Vec<LifecycleRule> = serde_json::from_value(json!([{
+            "fileNamePrefix": "",
+            "daysFromUploadingToHiding": null,
+            "daysFromHidingToDeleting": 30
+        }]))
+        .unwrap();
+        assert_eq!(rules, [LifecycleRule {
+            file_name_prefix: String::new(),
+            days_from_uploading_to_hiding: None,
+            days_from_hiding_to_deleting: Some(30),
+        }]);
+
+        let mut rules = rules;
+        set_rule(&mut rules, LifecycleRule {
+            file_name_prefix: "dirdb/".to_owned(),
+            days_from_hiding_to_deleting: Some(1),
+            ..Default::default()
+        });
+        set_rule(&mut rules, LifecycleRule {
+            days_from_hiding_to_deleting: Some(7),
+            ..Default::default()
+        });
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].days_from_hiding_to_deleting, Some(7));
+        set_rule(&mut rules, LifecycleRule::default());
+        assert_eq!(rules.len(), 1);
+        assert_eq!(serde_json::to_value(&rules[0]).unwrap()["fileNamePrefix"], "dirdb/");
+    }
+}
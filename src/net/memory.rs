@@ -0,0 +1,508 @@
+use crate::crypto::{decode_meta, encode_meta, sha1_string, Key};
+use crate::data::file::{FileMeta, RemoteFile, RemoteFileVersion};
+use crate::data::names::ObjectNames;
+use crate::net::backend::{
+    Backend, FileListDepth, FileVersionInfo, NotFound, SizedDownload, UploadStream, UploadTarget, VersionsPage,
+};
+use crate::net::lifecycle::LifecycleRule;
+use crate::net::retention::{Retention, VersionLocked};
+use crate::progress::ProgressHandler;
+use bytes::Bytes;
+use eyre::{eyre, Result};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Versions are listed in much smaller pages than B2's, so that tests go through several of them
+const VERSIONS_PAGE_SIZE: usize = 10;
+
+struct StoredVersion {
+    id: String,
+    /// None for hide markers
+    data: Option<Bytes>,
+    enc_meta: String,
+    uploaded: SystemTime,
+    /// Object Lock retention, the version can't be deleted before this
+    retain_until: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Storage {
+    /// Versions of each file, newest first (the same order B2 lists them in)
+    files: BTreeMap<String, Vec<StoredVersion>>,
+    /// Large file uploads that were started but never finished, with their encrypted metadata and start time
+    unfinished: Vec<(RemoteFileVersion, String, SystemTime)>,
+    next_id: AtomicU64,
+    /// Only stored, the memory backend never expires anything by itself
+    lifecycle_rules: Vec<LifecycleRule>,
+    /// Applied to uploads of file data, like B2 does
+    retention: Option<Retention>,
+    /// Plain names if None
+    object_names: Option<ObjectNames>,
+    /// Downloads of these files fail like a server error
+    failing_downloads: Vec<String>,
+}
+
+impl Storage {
+    fn new_id(&self) -> String {
+        format!("mem_{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn latest_upload(&self, filename: &str) -> Option<&StoredVersion> {
+        self.files
+            .get(filename)?
+            .first()
+            .filter(|version| version.data.is_some())
+    }
+}
+
+/// A backend that keeps everything in memory, so backups can be tested without network access
+///
+/// Clones (and copies returned by `with_progress`) share the same storage.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    key: Key,
+    storage: Arc<Mutex<Storage>>,
+}
+
+impl MemoryBackend {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            storage: Default::default(),
+        }
+    }
+
+    /// Names of the files that currently have a visible (not hidden) version
+    pub fn file_names(&self) -> Vec<String> {
+        let storage = self.storage.lock().unwrap();
+        storage
+            .files
+            .keys()
+            .filter(|name| storage.latest_upload(name).is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// Simulates a large file upload that was interrupted, e.g. by a crash or another machine still uploading it
+    pub fn start_unfinished_upload(&self, filename: &str, rel_path: &Path, started: SystemTime) -> RemoteFileVersion {
+        let mut storage = self.storage.lock().unwrap();
+        let version = RemoteFileVersion {
+            path: filename.to_owned(),
+            id: storage.new_id(),
+        };
+        let enc_meta = encode_meta(&self.key, &FileMeta::new_internal(rel_path));
+        storage.unfinished.push((version.clone(), enc_meta, started));
+        version
+    }
+
+    /// Number of uploads that were started but never finished
+    /// Locks the file data uploaded from now on, like `Config::retention` does on B2
+    pub fn set_retention(&self, retention: Option<Retention>) {
+        self.storage.lock().unwrap().retention = retention;
+    }
+
+    /// Uses obfuscated names from now on, like `Config::obfuscate_names` does on B2
+    pub fn set_obfuscated_names(&self) {
+        self.storage.lock().unwrap().object_names = Some(ObjectNames::obfuscated(&self.key));
+    }
+
+    /// Makes the downloads of `filename` fail from now on, without telling whether it exists
+    pub fn fail_downloads_of(&self, filename: &str) {
+        self.storage.lock().unwrap().failing_downloads.push(filename.to_owned());
+    }
+
+    pub fn unfinished_count(&self) -> usize {
+        self.storage.lock().unwrap().unfinished.len()
+    }
+
+    /// Total number of stored versions, including hide markers
+    pub fn versions_count(&self) -> usize {
+        self.storage.lock().unwrap().files.values().map(Vec::len).sum()
+    }
+
+    fn list_remote_files_sync(&self, prefix: &str, depth: FileListDepth) -> Result<Vec<RemoteFile>> {
+        let storage = self.storage.lock().unwrap();
+        let mut files = Vec::new();
+        for (full_name, _) in storage.files.range(prefix.to_owned()..) {
+            if !full_name.starts_with(prefix) {
+                break;
+            }
+            if let FileListDepth::Shallow = depth {
+                if full_name[prefix.len()..].contains('/') {
+                    continue;
+                }
+            }
+            if let Some(version) = storage.latest_upload(full_name) {
+                let meta = decode_meta(&self.key, &version.enc_meta)?;
+                let size = version.data.as_ref().map_or(0, |data| data.len() as u64);
+                files.push(RemoteFile::new(meta, full_name, &version.id, size));
+            }
+        }
+        Ok(files)
+    }
+
+    fn list_remote_file_versions_sync(&self, prefix: &str) -> Vec<(RemoteFileVersion, SystemTime)> {
+        let storage = self.storage.lock().unwrap();
+        storage
+            .files
+            .range(prefix.to_owned()..)
+            .take_while(|(name, _)| name.starts_with(prefix))
+            .flat_map(|(name, versions)| {
+                versions
+                    .iter()
+                    .filter(|version| version.data.is_some())
+                    .map(move |version| {
+                        let file_version = RemoteFileVersion {
+                            path: name.clone(),
+                            id: version.id.clone(),
+                        };
+                        (file_version, version.uploaded)
+                    })
+            })
+            .collect()
+    }
+
+    fn push_version(
+        &self,
+        filename: &str,
+        data: Option<Bytes>,
+        enc_meta: String,
+        retain_until: Option<SystemTime>,
+    ) -> RemoteFileVersion {
+        let mut storage = self.storage.lock().unwrap();
+        let id = storage.new_id();
+        let version = StoredVersion {
+            id: id.clone(),
+            data,
+            enc_meta,
+            uploaded: SystemTime::now(),
+            retain_until,
+        };
+        storage.files.entry(filename.to_owned()).or_default().insert(0, version);
+        RemoteFileVersion {
+            path: filename.to_owned(),
+            id,
+        }
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn key(&self) -> &Key {
+        &self.key
+    }
+
+    fn object_names(&self) -> ObjectNames {
+        let names = self.storage.lock().unwrap().object_names.clone();
+        names.unwrap_or_else(ObjectNames::plain)
+    }
+
+    fn with_progress(&self, _progress: ProgressHandler) -> Arc<dyn Backend> {
+        Arc::new(self.clone())
+    }
+
+    fn list_remote_files<'a>(
+        &'a self,
+        prefix: &'a str,
+        depth: FileListDepth,
+    ) -> BoxFuture<'a, Result<Vec<RemoteFile>>> {
+        async move { self.list_remote_files_sync(prefix, depth) }.boxed()
+    }
+
+    fn list_file_versions_page<'a>(
+        &'a self,
+        prefix: &'a str,
+        start: Option<&'a RemoteFileVersion>,
+    ) -> BoxFuture<'a, Result<VersionsPage>> {
+        async move {
+            let mut versions = self.list_remote_file_versions_sync(prefix);
+            if let Some(start) = start {
+                let start_pos = versions
+                    .iter()
+                    .position(|(version, _)| version == start)
+                    .ok_or_else(|| eyre!("Start version {} of \"{}\" not found", start.id, start.path))?;
+                versions.drain(..start_pos);
+            }
+            let next = versions.get(VERSIONS_PAGE_SIZE).map(|(version, _)| version.clone());
+            versions.truncate(VERSIONS_PAGE_SIZE);
+            Ok(VersionsPage { versions, next })
+        }
+        .boxed()
+    }
+
+    fn list_unfinished_large_files<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(RemoteFile, SystemTime)>>> {
+        // Uploads complete atomically, only the ones simulated by `start_unfinished_upload` are left unfinished
+        async move {
+            let storage = self.storage.lock().unwrap();
+            let mut files = Vec::new();
+            for (version, enc_meta, started) in storage.unfinished.iter() {
+                if !version.path.starts_with(prefix) {
+                    continue;
+                }
+                let file = RemoteFile::new(decode_meta(&self.key, enc_meta)?, &version.path, &version.id, 0);
+                files.push((file, *started));
+            }
+            Ok(files)
+        }
+        .boxed()
+    }
+
+    fn get_upload_url(&self) -> BoxFuture<'_, Result<UploadTarget>> {
+        async {
+            Ok(UploadTarget {
+                url: "memory://".to_owned(),
+                auth_token: String::new(),
+            })
+        }
+        .boxed()
+    }
+
+    fn upload_file_stream<'a>(
+        &'a self,
+        _target: &'a UploadTarget,
+        filename: &'a str,
+        mut data_stream: UploadStream,
+        enc_meta: Option<String>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            let retention = self.storage.lock().unwrap().retention.filter(|_| enc_meta.is_some());
+            let enc_meta =
+                enc_meta.unwrap_or_else(|| encode_meta(&self.key, &FileMeta::new_internal(Path::new(filename))));
+
+            let mut data = Vec::new();
+            while let Some(chunk) = data_stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            let retain_until =
+                retention.map(|retention| UNIX_EPOCH + Duration::from_millis(retention.retain_until_millis()));
+            Ok(self.push_version(filename, Some(data.into()), enc_meta, retain_until))
+        }
+        .boxed()
+    }
+
+    fn upload_file_stream_confirmed<'a>(
+        &'a self,
+        filename: &'a str,
+        mut data_stream: UploadStream,
+        enc_meta: String,
+        confirm: BoxFuture<'a, Result<()>>,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            let retention = self.storage.lock().unwrap().retention;
+            let mut data = Vec::new();
+            while let Some(chunk) = data_stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            confirm.await?;
+            let retain_until =
+                retention.map(|retention| UNIX_EPOCH + Duration::from_millis(retention.retain_until_millis()));
+            Ok(self.push_version(filename, Some(data.into()), enc_meta, retain_until))
+        }
+        .boxed()
+    }
+
+    fn download_file_stream<'a>(
+        &'a self,
+        filename: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, Result<Bytes>>>> {
+        async move {
+            let storage = self.storage.lock().unwrap();
+            if storage.failing_downloads.iter().any(|failing| failing == filename) {
+                return Err(eyre!(
+                    "Failed to download file \"{}\": 503 Service Unavailable",
+                    filename
+                ));
+            }
+            let data = storage
+                .latest_upload(filename)
+                .and_then(|version| version.data.clone())
+                .ok_or_else(|| NotFound(filename.to_owned()))?;
+            Ok(stream::once(async { Ok(data) }).boxed())
+        }
+        .boxed()
+    }
+
+    fn download_file_stream_sized<'a>(&'a self, filename: &'a str) -> BoxFuture<'a, Result<SizedDownload>> {
+        async move {
+            let size = {
+                let storage = self.storage.lock().unwrap();
+                let data = storage
+                    .latest_upload(filename)
+                    .and_then(|version| version.data.as_ref());
+                data.map(|data| data.len() as u64)
+            };
+            Ok((size, self.download_file_stream(filename).await?))
+        }
+        .boxed()
+    }
+
+    fn file_version_info<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<FileVersionInfo>> {
+        async move {
+            let storage = self.storage.lock().unwrap();
+            let data = storage
+                .files
+                .get(&file_version.path)
+                .and_then(|versions| versions.iter().find(|version| version.id == file_version.id))
+                .and_then(|version| version.data.as_ref())
+                .ok_or_else(|| {
+                    eyre!(
+                        "File version {} of \"{}\" not found",
+                        file_version.id,
+                        file_version.path
+                    )
+                })?;
+            Ok(FileVersionInfo {
+                size: data.len() as u64,
+                sha1: Some(sha1_string(data)),
+            })
+        }
+        .boxed()
+    }
+
+    fn copy_file<'a>(
+        &'a self,
+        file_version: &'a RemoteFileVersion,
+        _size: u64,
+        filename: &'a str,
+        enc_meta: String,
+    ) -> BoxFuture<'a, Result<RemoteFileVersion>> {
+        async move {
+            let (data, retention) = {
+                let storage = self.storage.lock().unwrap();
+                let data = storage
+                    .files
+                    .get(&file_version.path)
+                    .and_then(|versions| versions.iter().find(|version| version.id == file_version.id))
+                    .and_then(|version| version.data.clone())
+                    .ok_or_else(|| eyre!("Failed to copy file \"{}\": not found", file_version.path))?;
+                (data, storage.retention)
+            };
+            let retain_until =
+                retention.map(|retention| UNIX_EPOCH + Duration::from_millis(retention.retain_until_millis()));
+            Ok(self.push_version(filename, Some(data), enc_meta, retain_until))
+        }
+        .boxed()
+    }
+
+    fn delete_file_version<'a>(&'a self, file_version: &'a RemoteFileVersion) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let mut storage = self.storage.lock().unwrap();
+            if let Some(pos) = storage
+                .unfinished
+                .iter()
+                .position(|(version, ..)| version == file_version)
+            {
+                storage.unfinished.remove(pos);
+                return Ok(());
+            }
+            let versions = storage
+                .files
+                .get_mut(&file_version.path)
+                .ok_or_else(|| eyre!("Failed to delete file \"{}\": not found", file_version.path))?;
+            let pos = versions
+                .iter()
+                .position(|version| version.id == file_version.id)
+                .ok_or_else(|| eyre!("Failed to delete file \"{}\": no such version", file_version.path))?;
+            if versions[pos]
+                .retain_until
+                .is_some_and(|until| until > SystemTime::now())
+            {
+                return Err(VersionLocked {
+                    path: file_version.path.clone(),
+                }
+                .into());
+            }
+            versions.remove(pos);
+            if versions.is_empty() {
+                storage.files.remove(&file_version.path);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn hide_file<'a>(&'a self, file_path_hash: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            if self.storage.lock().unwrap().latest_upload(file_path_hash).is_none() {
+                return Err(eyre!("Failed to hide file \"{}\": not found", file_path_hash));
+            }
+            self.push_version(file_path_hash, None, String::new(), None);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn lifecycle_rules(&self) -> BoxFuture<'_, Result<Vec<LifecycleRule>>> {
+        async move { Ok(self.storage.lock().unwrap().lifecycle_rules.clone()) }.boxed()
+    }
+
+    fn set_lifecycle_rules<'a>(&'a self, rules: &'a [LifecycleRule]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.storage.lock().unwrap().lifecycle_rules = rules.to_vec();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn share_url<'a>(&'a self, filename: &'a str, valid_for: Duration) -> BoxFuture<'a, Result<String>> {
+        async move {
+            if self.storage.lock().unwrap().latest_upload(filename).is_none() {
+                return Err(eyre!("Failed to share file \"{}\": not found", filename));
+            }
+            Ok(format!("memory:///{}?Authorization={}", filename, valid_for.as_secs()))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::backend::is_not_found;
+    use crate::test_helpers::test_key;
+
+    #[tokio::test]
+    async fn upload_list_hide() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let mut meta = FileMeta::new_internal(Path::new("dir/a"));
+        meta.last_modified = 42;
+        let meta = encode_meta(backend.key(), &meta);
+        let url = backend.get_upload_url().await?;
+        backend.upload_file(&url, "root/x/a", vec![1, 2, 3], Some(meta)).await?;
+        backend.upload_file_simple("root/x/y/b", vec![4]).await?;
+        backend.upload_file_simple("root/x/y/b", vec![5]).await?;
+
+        let shallow = backend.list_remote_files("root/x/", FileListDepth::Shallow).await?;
+        assert_eq!(shallow.len(), 1);
+        assert_eq!(shallow[0].rel_path, Path::new("dir/a"));
+        assert_eq!(shallow[0].last_modified, 42);
+        assert_eq!(backend.list_remote_files("root/", FileListDepth::Deep).await?.len(), 2);
+        assert_eq!(backend.list_remote_file_versions("root/x/y/").await?.len(), 2);
+        assert_eq!(&backend.download_file("root/x/y/b").await?[..], &[5]);
+
+        backend.hide_file("root/x/a").await?;
+        assert_eq!(backend.file_names(), vec!["root/x/y/b".to_owned()]);
+        assert!(is_not_found(&backend.download_file("root/x/a").await.unwrap_err()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_versions() -> Result<()> {
+        let backend = MemoryBackend::new(test_key());
+        let old = backend.upload_file_simple("lock", Vec::new()).await?;
+        let new = backend.upload_file_simple("lock", Vec::new()).await?;
+        backend.delete_file_version(&new).await?;
+        assert_eq!(backend.list_remote_file_versions("lock").await?.len(), 1);
+        backend.delete_file_version(&old).await?;
+        assert!(backend.delete_file_version(&old).await.is_err());
+        assert_eq!(backend.versions_count(), 0);
+        Ok(())
+    }
+}
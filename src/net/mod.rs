@@ -1,2 +1,18 @@
 pub mod b2;
+pub mod backend;
+pub mod cap;
+pub mod chaos;
+pub mod endpoint;
+pub mod health;
+pub mod lifecycle;
+pub mod memory;
+pub mod pause;
+pub mod power;
 pub mod rate_limiter;
+pub mod replayable_body;
+pub mod retention;
+pub mod retry;
+pub mod s3;
+pub mod schedule;
+pub mod sse;
+pub mod transfer_slots;
@@ -0,0 +1,31 @@
+//! Pausing the transfers of a running command without aborting it, e.g. on Ctrl+Z (see the `signal` module of the binary)
+
+use crate::progress;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often paused transfers check whether they can go on
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// New transfers wait, and the running ones stop between two chunks until `resume`
+pub fn pause() {
+    PAUSED.store(true, Ordering::Release);
+    progress::show_paused(true);
+}
+
+pub fn resume() {
+    PAUSED.store(false, Ordering::Release);
+    progress::show_paused(false);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Acquire)
+}
+
+pub async fn wait_while_paused() {
+    while is_paused() {
+        tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+    }
+}
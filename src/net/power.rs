@@ -0,0 +1,146 @@
+//! Battery and metered network detection, so scheduled backups on laptops go easy on the battery and the data plan
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Limits that apply while a power or network condition holds
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PowerProfile {
+    /// Total transfer speed of the uploads and downloads, unlimited if missing
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Caps the upload, download and delete threads
+    #[serde(default)]
+    pub max_threads: Option<u16>,
+    /// Don't start new transfers at all, the ones already running still finish
+    #[serde(default)]
+    pub pause: bool,
+}
+
+/// What to do on battery power or on a metered connection, nothing if both are missing
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PowerLimits {
+    #[serde(default)]
+    pub on_battery: Option<PowerProfile>,
+    #[serde(default)]
+    pub on_metered: Option<PowerProfile>,
+}
+
+/// The conditions of the machine right now
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PowerState {
+    pub on_battery: bool,
+    pub metered: bool,
+}
+
+impl PowerLimits {
+    pub fn is_empty(&self) -> bool {
+        self.on_battery.is_none() && self.on_metered.is_none()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for profile in self.on_battery.iter().chain(self.on_metered.iter()) {
+            if profile.bytes_per_sec == Some(0) || profile.max_threads == Some(0) {
+                bail!("Battery and metered limits can't be zero, use \"pause\" to stop transfers");
+            }
+        }
+        Ok(())
+    }
+
+    /// Only looks for the conditions that have limits, checking for a metered network runs a command
+    pub fn detect(&self) -> PowerState {
+        PowerState {
+            on_battery: self.on_battery.is_some() && on_battery(Path::new(POWER_SUPPLY_DIR)),
+            metered: self.on_metered.is_some() && is_metered(),
+        }
+    }
+
+    /// The profiles in effect in `state`
+    pub fn active(&self, state: PowerState) -> impl Iterator<Item = &PowerProfile> {
+        let battery = self.on_battery.as_ref().filter(|_| state.on_battery);
+        let metered = self.on_metered.as_ref().filter(|_| state.metered);
+        battery.into_iter().chain(metered)
+    }
+}
+
+/// A laptop is on battery when one of its batteries is discharging. Machines without batteries never are.
+fn on_battery(power_supply_dir: &Path) -> bool {
+    let supplies = match fs::read_dir(power_supply_dir) {
+        Ok(supplies) => supplies,
+        Err(_) => return false,
+    };
+    supplies.flatten().any(|supply| {
+        let read = |name| fs::read_to_string(supply.path().join(name)).unwrap_or_default();
+        read("type").trim() == "Battery" && read("status").trim() == "Discharging"
+    })
+}
+
+/// Asks NetworkManager whether the primary connection is metered, or guessed to be (e.g. a phone hotspot).
+/// Without NetworkManager, connections are assumed not to be metered.
+fn is_metered() -> bool {
+    let output = Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => parse_nm_metered(&String::from_utf8_lossy(&output.stdout)),
+        _ => false,
+    }
+}
+
+/// Parses busctl's "u <value>", NetworkManager's metered values are unknown, yes, no, guess-yes and guess-no
+fn parse_nm_metered(reply: &str) -> bool {
+    matches!(reply.trim().strip_prefix("u "), Some("1") | Some("3"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn detects_battery_and_metered() -> Result<()> {
+        let dir = tempdir()?;
+        assert!(!on_battery(&dir.path().join("missing")));
+        for (name, kind, status) in [("AC", "Mains", None), ("BAT0", "Battery", Some("Charging"))] {
+            fs::create_dir(dir.path().join(name))?;
+            fs::write(dir.path().join(name).join("type"), format!("{}\n", kind))?;
+            if let Some(status) = status {
+                fs::write(dir.path().join(name).join("status"), format!("{}\n", status))?;
+            }
+        }
+        assert!(!on_battery(dir.path()));
+        fs::write(dir.path().join("BAT0").join("status"), "Discharging\n")?;
+        assert!(on_battery(dir.path()));
+
+        assert!(parse_nm_metered("u 1\n"));
+        assert!(parse_nm_metered("u 3"));
+        assert!(!parse_nm_metered("u 4"));
+        assert!(!parse_nm_metered(""));
+
+        let limits = PowerLimits {
+            on_battery: Some(PowerProfile {
+                pause: true,
+                ..Default::default()
+            }),
+            on_metered: None,
+        };
+        let on_battery = PowerState {
+            on_battery: true,
+            metered: true,
+        };
+        assert_eq!(limits.active(on_battery).count(), 1);
+        assert_eq!(limits.active(PowerState::default()).count(), 0);
+        Ok(())
+    }
+}
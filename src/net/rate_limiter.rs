@@ -1,51 +1,219 @@
 pub use self::data_permit::RateLimitPermit;
 use crate::config::Config;
-use crate::net::b2::{B2Upload, B2};
+use crate::net::backend::{Backend, UploadStream, UploadTarget};
+use crate::net::pause;
+use crate::net::schedule::Schedule;
+use crate::net::transfer_slots::{TransferSlot, TransferSlots};
+use bytes::Bytes;
 use crossbeam::queue::ArrayQueue;
+use eyre::Result;
+use futures::stream::{BoxStream, Stream, StreamExt};
 use futures_intrusive::sync::{Semaphore, SemaphoreReleaser};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 mod data_permit;
 
+/// Permits reserved for control-plane requests (listings, DirDB uploads, ...)
+const CONTROL_THREADS: usize = 4;
+/// How long transfers wait before checking again when the bandwidth schedule caps the threads
+const SCHEDULE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits how many requests of each kind run at once.
+///
+/// Control-plane requests have their own lane with reserved permits, so they are never
+/// queued behind bulk data transfers, even when every upload or download permit is taken.
+/// Bulk transfers may also need a slot in the budget shared with other frozen processes (see `net::transfer_slots`),
+/// and follow the bandwidth schedule and power limits of the config (see `net::schedule`).
+/// No transfer makes progress while they're paused (see `net::pause`).
 pub struct RateLimiter {
-    b2: B2,
+    backend: Arc<dyn Backend>,
 
+    control_sem: Semaphore,
     download_sem: Semaphore,
     delete_sem: Semaphore,
     upload_sem: Semaphore,
 
-    upload_urls: ArrayQueue<Option<B2Upload>>,
+    download_running: AtomicUsize,
+    delete_running: AtomicUsize,
+    upload_running: AtomicUsize,
+
+    upload_urls: ArrayQueue<Option<UploadTarget>>,
+    machine_slots: Option<TransferSlots>,
+    schedule: Arc<Schedule>,
+}
+
+/// Held for the duration of a bulk transfer
+pub struct TransferPermit<'rate_limiter> {
+    _releaser: SemaphoreReleaser<'rate_limiter>,
+    _running: RunningTransfer<'rate_limiter>,
+    _slot: Option<TransferSlot>,
+}
+
+/// Counts a running transfer against the thread cap of the schedule
+struct RunningTransfer<'rate_limiter> {
+    running: &'rate_limiter AtomicUsize,
+}
+
+impl Drop for RunningTransfer<'_> {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl RateLimiter {
-    pub fn new(config: &Config, b2: &B2) -> Self {
+    pub fn new(config: &Config, backend: &Arc<dyn Backend>) -> Result<Self> {
         let upload_urls = ArrayQueue::new(config.upload_threads as usize);
         for _ in 0..config.upload_threads {
             upload_urls.push(None).unwrap();
         }
 
-        Self {
-            b2: b2.clone(),
+        Ok(Self {
+            backend: backend.clone(),
+            control_sem: Semaphore::new(false, CONTROL_THREADS),
             upload_sem: Semaphore::new(false, config.upload_threads as usize),
             download_sem: Semaphore::new(false, config.download_threads as usize),
             delete_sem: Semaphore::new(false, config.delete_threads as usize),
+            download_running: AtomicUsize::new(0),
+            delete_running: AtomicUsize::new(0),
+            upload_running: AtomicUsize::new(0),
             upload_urls,
+            machine_slots: config
+                .machine_threads
+                .map(|count| TransferSlots::new(Config::get_transfer_slots_path(), count)),
+            schedule: Arc::new(Schedule::new(&config.bandwidth_schedule, &config.power_limits)?),
+        })
+    }
+
+    pub fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    /// Waits for a permit in the control-plane lane, which bulk transfers never take
+    pub async fn borrow_control_permit(&self) -> SemaphoreReleaser<'_> {
+        self.control_sem.acquire(1).await
+    }
+
+    pub async fn borrow_upload_permit(&self) -> RateLimitPermit<'_, UploadTarget> {
+        let permit = self
+            .borrow_transfer_permit(&self.upload_sem, &self.upload_running)
+            .await;
+        RateLimitPermit::new(permit, &self.upload_urls)
+    }
+
+    pub async fn borrow_download_permit(&self) -> TransferPermit<'_> {
+        self.borrow_transfer_permit(&self.download_sem, &self.download_running)
+            .await
+    }
+
+    pub async fn borrow_delete_permit(&self) -> TransferPermit<'_> {
+        self.borrow_transfer_permit(&self.delete_sem, &self.delete_running)
+            .await
+    }
+
+    /// The local permits are taken first, so we don't hold machine-wide slots while waiting on our own limits
+    async fn borrow_transfer_permit<'a>(&'a self, sem: &'a Semaphore, running: &'a AtomicUsize) -> TransferPermit<'a> {
+        let releaser = sem.acquire(1).await;
+        pause::wait_while_paused().await;
+        let running = loop {
+            let max_threads = self.schedule.limits().max_threads.map_or(usize::MAX, usize::from);
+            let started = running.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count < max_threads {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            });
+            if started.is_ok() {
+                break RunningTransfer { running };
+            }
+            tokio::time::sleep(SCHEDULE_RETRY_INTERVAL).await;
+        };
+        let slot = match &self.machine_slots {
+            Some(slots) => slots.acquire().await,
+            None => None,
+        };
+        TransferPermit {
+            _releaser: releaser,
+            _running: running,
+            _slot: slot,
         }
     }
 
-    pub fn b2_client(&self) -> &B2 {
-        &self.b2
+    /// Slows down an upload to follow the bandwidth schedule, and holds it while paused
+    pub fn throttle_upload(&self, stream: UploadStream) -> UploadStream {
+        Box::new(Box::pin(throttle(self.schedule.clone(), stream)))
     }
 
-    pub async fn borrow_upload_permit(&self) -> RateLimitPermit<'_, B2Upload> {
-        let releaser = self.upload_sem.acquire(1).await;
-        RateLimitPermit::new(releaser, &self.upload_urls)
+    /// Slows down a download to follow the bandwidth schedule, and holds it while paused
+    pub fn throttle_download(&self, stream: BoxStream<'static, Result<Bytes>>) -> BoxStream<'static, Result<Bytes>> {
+        throttle(self.schedule.clone(), stream).boxed()
     }
+}
+
+fn throttle<S>(schedule: Arc<Schedule>, stream: S) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    stream.then(move |chunk| {
+        let wait = match &chunk {
+            Ok(data) => schedule.reserve(data.len()),
+            Err(_) => Duration::ZERO,
+        };
+        async move {
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            pause::wait_while_paused().await;
+            chunk
+        }
+    })
+}
 
-    pub async fn borrow_download_permit(&self) -> SemaphoreReleaser<'_> {
-        self.download_sem.acquire(1).await
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemoryBackend;
+    use crate::net::schedule::BandwidthProfile;
+    use crate::test_helpers::test_key;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn control_lane_is_not_starved_by_uploads() {
+        let mut config = Config::default();
+        config.upload_threads = 2;
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new(test_key()));
+        let rate_limiter = RateLimiter::new(&config, &backend).unwrap();
+
+        let _uploads = (
+            rate_limiter.borrow_upload_permit().await,
+            rate_limiter.borrow_upload_permit().await,
+        );
+        assert!(rate_limiter.borrow_upload_permit().now_or_never().is_none());
+        let controls: Vec<_> = (0..CONTROL_THREADS)
+            .map(|_| rate_limiter.borrow_control_permit().now_or_never())
+            .collect();
+        assert!(controls.iter().all(Option::is_some));
+        assert!(rate_limiter.borrow_control_permit().now_or_never().is_none());
     }
 
-    pub async fn borrow_delete_permit(&self) -> SemaphoreReleaser<'_> {
-        self.delete_sem.acquire(1).await
+    #[tokio::test]
+    async fn schedule_caps_threads() {
+        let mut config = Config::default();
+        config.bandwidth_schedule = vec![BandwidthProfile {
+            from: "00:00".to_owned(),
+            to: "00:00".to_owned(),
+            bytes_per_sec: None,
+            max_threads: Some(1),
+        }];
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new(test_key()));
+        let rate_limiter = RateLimiter::new(&config, &backend).unwrap();
+
+        let download = rate_limiter.borrow_download_permit().await;
+        assert!(rate_limiter.borrow_download_permit().now_or_never().is_none());
+        let _upload = rate_limiter.borrow_upload_permit().await;
+        drop(download);
+        assert!(rate_limiter.borrow_download_permit().now_or_never().is_some());
     }
 }
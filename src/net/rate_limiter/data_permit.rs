@@ -1,21 +1,21 @@
+use super::TransferPermit;
 use crossbeam::queue::ArrayQueue;
-use futures_intrusive::sync::SemaphoreReleaser;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
 
 pub struct RateLimitPermit<'rate_limiter, T: Debug> {
-    _releaser: SemaphoreReleaser<'rate_limiter>,
+    _permit: TransferPermit<'rate_limiter>,
     data_queue: &'rate_limiter ArrayQueue<Option<T>>,
     data: Option<T>,
 }
 
 impl<'r, T: Debug> RateLimitPermit<'r, T> {
-    pub fn new(releaser: SemaphoreReleaser<'r>, data_queue: &'r ArrayQueue<Option<T>>) -> Self {
+    pub fn new(permit: TransferPermit<'r>, data_queue: &'r ArrayQueue<Option<T>>) -> Self {
         let data = data_queue
             .pop()
             .expect("The data queue should be behind a semaphore and never underflow");
         Self {
-            _releaser: releaser,
+            _permit: permit,
             data_queue,
             data,
         }
@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use eyre::Result;
+use futures::stream::{self, Stream, StreamExt};
+use reqwest::Body;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use tokio::task::block_in_place;
+
+/// Chunks at least this big are spooled to disk instead of staying in memory during the upload
+const SPOOL_THRESHOLD: usize = 1024 * 1024;
+/// Size of the reads when sending a spooled chunk
+const SPOOL_READ_SIZE: usize = 256 * 1024;
+
+/// Data to upload that can be sent again from the start each time a request is retried
+///
+/// Big chunks are written to an anonymous temp file and re-read for every attempt, so a worker
+/// waiting out a backoff or a slow upload doesn't keep a whole chunk alive in memory.
+pub enum ReplayableBody {
+    Memory(Bytes),
+    Spooled { file: Arc<File>, len: u64 },
+}
+
+impl ReplayableBody {
+    /// Takes ownership of a chunk, spooling it to disk if it's big
+    pub fn new(data: Bytes) -> Result<Self> {
+        if data.len() < SPOOL_THRESHOLD {
+            return Ok(ReplayableBody::Memory(data));
+        }
+        let file = block_in_place(|| -> io::Result<File> {
+            let mut file = tempfile::tempfile()?;
+            file.write_all(&data)?;
+            Ok(file)
+        })?;
+        Ok(ReplayableBody::Spooled {
+            file: Arc::new(file),
+            len: data.len() as u64,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            ReplayableBody::Memory(data) => data.len() as u64,
+            ReplayableBody::Spooled { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Creates a request body that sends the data from the start
+    pub fn to_body(&self) -> Body {
+        match self {
+            // Cloning Bytes only bumps a refcount
+            ReplayableBody::Memory(data) => Body::from(data.clone()),
+            ReplayableBody::Spooled { file, len } => Body::wrap_stream(spooled_stream(file.clone(), *len)),
+        }
+    }
+}
+
+/// Reads a spooled file from the start, without touching the file's shared cursor
+fn spooled_stream(file: Arc<File>, len: u64) -> impl Stream<Item = io::Result<Bytes>> + Send + Sync + 'static {
+    stream::iter((0..len).step_by(SPOOL_READ_SIZE)).then(move |offset| {
+        let file = file.clone();
+        async move {
+            let size = (len - offset).min(SPOOL_READ_SIZE as u64) as usize;
+            let mut buf = vec![0u8; size];
+            block_in_place(|| file.read_exact_at(&mut buf, offset))?;
+            Ok(Bytes::from(buf))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spooled_body_replays() -> Result<()> {
+        let data: Bytes = (0..SPOOL_THRESHOLD * 2 + 12345)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>()
+            .into();
+        let body = ReplayableBody::new(data.clone())?;
+        let (file, len) = match &body {
+            ReplayableBody::Spooled { file, len } => (file.clone(), *len),
+            ReplayableBody::Memory(_) => panic!("Big chunks should be spooled"),
+        };
+        assert_eq!(body.len(), data.len() as u64);
+
+        for _ in 0..2 {
+            let chunks: Vec<Bytes> = spooled_stream(file.clone(), len).try_collect().await?;
+            assert_eq!(chunks.concat(), data);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_body_stays_in_memory() -> Result<()> {
+        let body = ReplayableBody::new(Bytes::from_static(b"small"))?;
+        assert!(matches!(body, ReplayableBody::Memory(_)));
+        assert_eq!(body.to_body().as_bytes(), Some(&b"small"[..]));
+        Ok(())
+    }
+}
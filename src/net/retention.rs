@@ -0,0 +1,88 @@
+//! Object Lock retention of uploaded files, so that a compromised client can't delete recent backups
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionMode {
+    /// Keys with the bypassGovernance capability can still delete locked files
+    Governance,
+    /// Nobody can delete locked files before their retention expires, not even the account owner
+    Compliance,
+}
+
+impl RetentionMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            RetentionMode::Governance => "governance",
+            RetentionMode::Compliance => "compliance",
+        }
+    }
+}
+
+/// Retention applied to the backed up files when they're uploaded (the bucket must have Object Lock enabled)
+///
+/// Only file data is locked. Frozen's own files (locks, DirDBs, the list of roots) are replaced or deleted
+/// as part of normal operation, and can be rebuilt from the files.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    pub mode: RetentionMode,
+    pub days: u32,
+}
+
+impl Retention {
+    /// Until when a file uploaded now is retained, in milliseconds since the epoch like B2 wants it
+    pub fn retain_until_millis(&self) -> u64 {
+        let until = SystemTime::now() + Duration::from_secs(self.days as u64 * 24 * 60 * 60);
+        until.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+}
+
+/// Returned when deleting a file version that is still under retention
+#[derive(Debug)]
+pub struct VersionLocked {
+    pub path: String,
+}
+
+impl Display for VersionLocked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is locked by Object Lock retention, it can't be deleted until the retention expires",
+            self.path
+        )
+    }
+}
+
+impl Error for VersionLocked {}
+
+/// Whether an error (or one of its causes) is a `VersionLocked`
+pub fn is_version_locked(err: &eyre::Report) -> bool {
+    err.chain().any(|cause| cause.downcast_ref::<VersionLocked>().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::WrapErr;
+
+    #[test]
+    fn locked_versions_are_recognized() {
+        let retention: Retention = serde_json::from_str(r#"{"mode": "compliance", "days": 30}"#).unwrap();
+        assert_eq!(retention.mode, RetentionMode::Compliance);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let until = retention.retain_until_millis();
+        assert!(until >= now + 30 * 24 * 60 * 60 * 1000 && until < now + 31 * 24 * 60 * 60 * 1000);
+
+        let locked: eyre::Result<()> = Err(VersionLocked {
+            path: "file".to_owned(),
+        }
+        .into());
+        let locked = locked.wrap_err("Failed to delete").unwrap_err();
+        assert!(is_version_locked(&locked));
+        assert!(!is_version_locked(&eyre::eyre!("other error")));
+    }
+}
@@ -0,0 +1,202 @@
+//! Which failed B2 requests are worth trying again, and how many times
+//!
+//! B2 tells why a request failed with a status and an error code in the JSON reply. Some failures only need a
+//! backoff, some need a new authorization token first, and retrying the others can't help.
+
+use crate::net::{cap, endpoint};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// With the backoff capped at a few seconds, that's a couple minutes without connectivity before a request fails
+const MAX_RETRIES_DEFAULT: u32 = 40;
+
+/// Longer waits asked for by a server are ignored for the usual backoff, a broken server can't stall a run for a day
+pub const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// How long requests keep being retried, in the config
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryLimits {
+    /// Retries of one request for temporary failures (no connection, busy servers), before it fails
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Retries of all the requests of a run, once they're spent every failure is final
+    #[serde(default)]
+    pub run_budget: Option<u32>,
+}
+
+fn default_max_retries() -> u32 {
+    MAX_RETRIES_DEFAULT
+}
+
+impl Default for RetryLimits {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES_DEFAULT,
+            run_budget: None,
+        }
+    }
+}
+
+/// The retries left for the whole run, shared by every request
+pub struct RetryBudget {
+    limit: Option<u32>,
+    spent: AtomicU32,
+}
+
+impl RetryBudget {
+    pub fn new(limits: &RetryLimits) -> Self {
+        Self {
+            limit: limits.run_budget,
+            spent: AtomicU32::new(0),
+        }
+    }
+
+    /// Takes one retry from the budget, false if it's spent
+    pub fn spend(&self) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        self.spent
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |spent| {
+                if spent < limit {
+                    Some(spent + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// Why a request failed, each class has its own retry policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// No reply at all: the connection failed, was reset, or timed out
+    Connection,
+    /// The server is busy or timed out (503, 408)
+    Unavailable,
+    /// We're sending too many requests (429), the reply may say how long to wait
+    Throttled,
+    /// Internal server error (500), often temporary but sometimes not
+    ServerError,
+    /// The authorization token expired or was revoked (401), authorizing again fixes it
+    AuthExpired,
+    /// A daily cap of the account was reached (403), see `net::cap`
+    CapExceeded,
+    /// Anything else, the caller gets the reply
+    Fatal,
+}
+
+/// What the retry layer does about a failed request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Wait, then send the request again
+    Backoff,
+    /// Authorize the account again, then send the request again right away
+    Reauthorize,
+    /// Give up and hand the failure to the caller
+    Fail,
+}
+
+impl ErrorClass {
+    /// How many times a request is retried for this reason
+    pub fn max_retries(self, limits: &RetryLimits) -> u32 {
+        match self {
+            ErrorClass::Connection | ErrorClass::Unavailable | ErrorClass::Throttled => limits.max_retries,
+            ErrorClass::ServerError => limits.max_retries.min(5),
+            ErrorClass::AuthExpired => 1,
+            ErrorClass::CapExceeded | ErrorClass::Fatal => 0,
+        }
+    }
+
+    /// What to do after a request failed for this reason `retries` times before.
+    /// Upload URLs come with their own token, a new upload URL is needed when it expires, not a new authorization.
+    pub fn action(self, retries: u32, upload_url_auth: bool, limits: &RetryLimits) -> RetryAction {
+        if retries >= self.max_retries(limits) {
+            return RetryAction::Fail;
+        }
+        match self {
+            ErrorClass::AuthExpired if upload_url_auth => RetryAction::Fail,
+            ErrorClass::AuthExpired => RetryAction::Reauthorize,
+            _ => RetryAction::Backoff,
+        }
+    }
+}
+
+/// Classifies a failed reply by its status and the "code" of its JSON body
+pub fn classify_reply(status: u16, code: &str) -> ErrorClass {
+    match status {
+        403 if cap::is_cap_error(code) => ErrorClass::CapExceeded,
+        401 if code == "expired_auth_token" || code == "bad_auth_token" => ErrorClass::AuthExpired,
+        429 => ErrorClass::Throttled,
+        503 | 408 => ErrorClass::Unavailable,
+        500 => ErrorClass::ServerError,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Classifies a request that got no reply
+pub fn classify_error(err: &reqwest::Error) -> ErrorClass {
+    // Retrying won't change the certificate of a server that we refuse, e.g. because its key isn't pinned
+    if err.is_builder() || err.is_redirect() || endpoint::is_certificate_error(err) {
+        ErrorClass::Fatal
+    } else {
+        ErrorClass::Connection
+    }
+}
+
+/// The wait asked for by a Retry-After header, in seconds. None over `MAX_RETRY_WAIT`, to back off as usual instead.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let wait = Duration::from_secs(value.trim().parse().ok()?);
+    Some(wait).filter(|wait| *wait <= MAX_RETRY_WAIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_b2_errors() {
+        assert_eq!(classify_reply(401, "expired_auth_token"), ErrorClass::AuthExpired);
+        assert_eq!(classify_reply(401, "bad_auth_token"), ErrorClass::AuthExpired);
+        assert_eq!(classify_reply(401, "unauthorized"), ErrorClass::Fatal);
+        assert_eq!(classify_reply(403, "download_cap_exceeded"), ErrorClass::CapExceeded);
+        assert_eq!(classify_reply(403, "access_denied"), ErrorClass::Fatal);
+        assert_eq!(classify_reply(429, "too_many_requests"), ErrorClass::Throttled);
+        assert_eq!(classify_reply(503, "service_unavailable"), ErrorClass::Unavailable);
+        assert_eq!(classify_reply(400, "bad_request"), ErrorClass::Fatal);
+
+        let limits = RetryLimits::default();
+        assert_eq!(
+            ErrorClass::AuthExpired.action(0, false, &limits),
+            RetryAction::Reauthorize
+        );
+        assert_eq!(ErrorClass::AuthExpired.action(1, false, &limits), RetryAction::Fail);
+        assert_eq!(ErrorClass::AuthExpired.action(0, true, &limits), RetryAction::Fail);
+        assert_eq!(ErrorClass::ServerError.action(4, false, &limits), RetryAction::Backoff);
+        assert_eq!(ErrorClass::ServerError.action(5, false, &limits), RetryAction::Fail);
+        assert_eq!(ErrorClass::Unavailable.action(39, false, &limits), RetryAction::Backoff);
+        assert_eq!(ErrorClass::Connection.action(40, false, &limits), RetryAction::Fail);
+        assert_eq!(ErrorClass::CapExceeded.action(0, false, &limits), RetryAction::Fail);
+
+        assert_eq!(parse_retry_after(" 30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("86400"), None);
+    }
+
+    #[test]
+    fn run_budget_is_shared() {
+        let unlimited = RetryBudget::new(&RetryLimits::default());
+        assert!((0..1000).all(|_| unlimited.spend()));
+
+        let budget = RetryBudget::new(&RetryLimits {
+            max_retries: 3,
+            run_budget: Some(2),
+        });
+        assert!(budget.spend());
+        assert!(budget.spend());
+        assert!(!budget.spend());
+    }
+}
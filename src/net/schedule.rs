@@ -0,0 +1,306 @@
+//! Time-of-day bandwidth and concurrency limits, e.g. to leave room for daytime traffic on always-on machines.
+//! The limits of `net::power` apply on top, while on battery or on a metered connection.
+
+use crate::net::power::{PowerLimits, PowerState};
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the active profile is re-evaluated during long runs
+const RE_EVALUATE_INTERVAL: Duration = Duration::from_secs(60);
+/// How much unused bandwidth can accumulate, so a limit still allows short bursts
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Limits that apply between two times of the day, in local time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BandwidthProfile {
+    /// When the profile starts, e.g. "09:00"
+    pub from: String,
+    /// When the profile ends, e.g. "18:00". Profiles can wrap around midnight, and last all day if it's equal to `from`.
+    pub to: String,
+    /// Total transfer speed of the uploads and downloads, unlimited if missing
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Caps the upload, download and delete threads
+    #[serde(default)]
+    pub max_threads: Option<u16>,
+}
+
+/// The limits currently in effect
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub bytes_per_sec: Option<u64>,
+    /// No new transfers start while this is 0
+    pub max_threads: Option<u16>,
+}
+
+impl Limits {
+    /// The stricter of both limits
+    fn min(self, other: Limits) -> Limits {
+        Limits {
+            bytes_per_sec: stricter(self.bytes_per_sec, other.bytes_per_sec),
+            max_threads: stricter(self.max_threads, other.max_threads),
+        }
+    }
+}
+
+/// The smallest limit, missing limits are unlimited
+fn stricter<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Applies the profile matching the current time, with a token bucket for the bandwidth
+pub struct Schedule {
+    /// Start and end in minutes since midnight, with the profile's limits
+    profiles: Vec<(u32, u32, Limits)>,
+    power: PowerLimits,
+    state: Mutex<ScheduleState>,
+}
+
+struct ScheduleState {
+    limits: Limits,
+    evaluated: Option<Instant>,
+    /// Bytes we can still send right away, negative when transfers must wait
+    budget: f64,
+    refilled: Instant,
+}
+
+impl Schedule {
+    pub fn new(profiles: &[BandwidthProfile], power: &PowerLimits) -> Result<Self> {
+        power.validate()?;
+        let profiles = profiles
+            .iter()
+            .map(|profile| {
+                if profile.bytes_per_sec == Some(0) || profile.max_threads == Some(0) {
+                    bail!(
+                        "Bandwidth profile from {} to {} can't have a zero limit",
+                        profile.from,
+                        profile.to
+                    );
+                }
+                let limits = Limits {
+                    bytes_per_sec: profile.bytes_per_sec,
+                    max_threads: profile.max_threads,
+                };
+                Ok((
+                    parse_time_of_day(&profile.from)?,
+                    parse_time_of_day(&profile.to)?,
+                    limits,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            profiles,
+            power: power.clone(),
+            state: Mutex::new(ScheduleState {
+                limits: Limits::default(),
+                evaluated: None,
+                budget: 0.0,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty() && self.power.is_empty()
+    }
+
+    /// The limits in effect right now, the first matching profile wins
+    pub fn limits(&self) -> Limits {
+        if self.is_empty() {
+            return Limits::default();
+        }
+        let mut state = self.state.lock().unwrap();
+        self.re_evaluate(&mut state);
+        state.limits
+    }
+
+    /// Accounts for bytes about to be transferred, and returns how long to wait before sending them
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        if self.is_empty() {
+            return Duration::ZERO;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.re_evaluate(&mut state);
+        let rate = match state.limits.bytes_per_sec {
+            Some(rate) => rate as f64,
+            None => return Duration::ZERO,
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.budget = (state.budget + elapsed * rate).min(rate * MAX_BURST.as_secs_f64());
+        state.refilled = now;
+        state.budget -= bytes as f64;
+        if state.budget >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.budget / rate)
+        }
+    }
+
+    fn re_evaluate(&self, state: &mut ScheduleState) {
+        if let Some(evaluated) = state.evaluated {
+            if evaluated.elapsed() < RE_EVALUATE_INTERVAL {
+                return;
+            }
+        }
+        let limits = self.limits_at(local_minute_of_day(), self.power.detect());
+        if limits.bytes_per_sec != state.limits.bytes_per_sec {
+            state.budget = 0.0;
+            state.refilled = Instant::now();
+        }
+        state.limits = limits;
+        state.evaluated = Some(Instant::now());
+    }
+
+    fn limits_at(&self, minute: u32, power_state: PowerState) -> Limits {
+        let time_limits = self
+            .profiles
+            .iter()
+            .find(|(from, to, _)| {
+                if from < to {
+                    (*from..*to).contains(&minute)
+                } else {
+                    minute >= *from || minute < *to
+                }
+            })
+            .map(|(_, _, limits)| *limits)
+            .unwrap_or_default();
+        self.power.active(power_state).fold(time_limits, |limits, profile| {
+            limits.min(Limits {
+                bytes_per_sec: profile.bytes_per_sec,
+                max_threads: if profile.pause { Some(0) } else { profile.max_threads },
+            })
+        })
+    }
+}
+
+/// Parses "HH:MM" into minutes since midnight
+pub fn parse_time_of_day(time: &str) -> Result<u32> {
+    let invalid = || eyre!("Invalid time of day \"{}\" (use HH:MM, e.g. 09:30)", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// The system's `date` knows the local timezone, if it fails we fall back to UTC
+fn local_minute_of_day() -> u32 {
+    let local = Command::new("date")
+        .arg("+%H:%M")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|time| parse_time_of_day(time.trim()).ok());
+    local.unwrap_or_else(|| {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        (secs % 86400 / 60) as u32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::power::PowerProfile;
+
+    fn profile(from: &str, to: &str, bytes_per_sec: Option<u64>) -> BandwidthProfile {
+        BandwidthProfile {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            bytes_per_sec,
+            max_threads: Some(2),
+        }
+    }
+
+    #[test]
+    fn profiles_match_time_of_day() -> Result<()> {
+        let schedule = Schedule::new(
+            &[profile("09:00", "18:00", Some(1000)), profile("22:00", "02:30", None)],
+            &PowerLimits::default(),
+        )?;
+        assert_eq!(
+            schedule.limits_at(8 * 60 + 59, PowerState::default()),
+            Limits::default()
+        );
+        assert_eq!(
+            schedule.limits_at(9 * 60, PowerState::default()).bytes_per_sec,
+            Some(1000)
+        );
+        assert_eq!(schedule.limits_at(18 * 60, PowerState::default()), Limits::default());
+        assert_eq!(schedule.limits_at(23 * 60, PowerState::default()).max_threads, Some(2));
+        assert_eq!(schedule.limits_at(60, PowerState::default()).max_threads, Some(2));
+        assert_eq!(schedule.limits_at(3 * 60, PowerState::default()), Limits::default());
+
+        assert!(Schedule::new(&[profile("9h", "18:00", None)], &PowerLimits::default()).is_err());
+        assert!(Schedule::new(&[profile("09:00", "24:00", None)], &PowerLimits::default()).is_err());
+        assert!(Schedule::new(&[profile("09:00", "18:00", Some(0))], &PowerLimits::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bandwidth_is_throttled() -> Result<()> {
+        // A profile covering the whole day is always active
+        let schedule = Schedule::new(&[profile("00:00", "00:00", Some(1000))], &PowerLimits::default())?;
+        assert_eq!(schedule.limits().bytes_per_sec, Some(1000));
+        let wait = schedule.reserve(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        let wait = schedule.reserve(1000);
+        assert!(wait > Duration::from_millis(1400) && wait <= Duration::from_millis(1500));
+
+        assert_eq!(
+            Schedule::new(&[], &PowerLimits::default())?.reserve(1 << 30),
+            Duration::ZERO
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn power_limits_apply_on_top() -> Result<()> {
+        let power = PowerLimits {
+            on_battery: Some(PowerProfile {
+                pause: true,
+                ..Default::default()
+            }),
+            on_metered: Some(PowerProfile {
+                bytes_per_sec: Some(500),
+                max_threads: Some(4),
+                pause: false,
+            }),
+        };
+        let schedule = Schedule::new(&[profile("09:00", "18:00", Some(1000))], &power)?;
+        let metered = PowerState {
+            on_battery: false,
+            metered: true,
+        };
+        assert_eq!(schedule.limits_at(10 * 60, metered), Limits {
+            bytes_per_sec: Some(500),
+            max_threads: Some(2),
+        });
+        assert_eq!(schedule.limits_at(20 * 60, metered).max_threads, Some(4));
+        let on_battery = PowerState {
+            on_battery: true,
+            metered: false,
+        };
+        assert_eq!(schedule.limits_at(20 * 60, on_battery).max_threads, Some(0));
+
+        let zero = PowerLimits {
+            on_metered: Some(PowerProfile {
+                max_threads: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(Schedule::new(&[], &zero).is_err());
+        Ok(())
+    }
+}
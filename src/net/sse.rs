@@ -0,0 +1,202 @@
+//! B2 server-side encryption, on top of our client-side encryption for defense in depth
+
+use crate::crypto::{derive_sse_customer_key, Key};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+const ALGORITHM: &str = "AES256";
+
+/// Server-side encryption of the uploaded files, as saved in the config
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SseMode {
+    /// B2 manages the key
+    SseB2,
+    /// We send a key derived from the encryption key with every request, B2 never stores it.
+    /// Every download must send the key too, so files uploaded before enabling it can't be read anymore.
+    SseC,
+}
+
+/// The `serverSideEncryption` field of B2 requests
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SseSettings {
+    pub mode: &'static str,
+    pub algorithm: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_key_md5: Option<String>,
+}
+
+/// The headers and request fields that B2 wants for a `SseMode`
+#[derive(Clone, Debug)]
+pub enum ServerSideEncryption {
+    B2Managed,
+    Customer { key: String, key_md5: String },
+}
+
+impl ServerSideEncryption {
+    pub fn new(mode: SseMode, key: &Key) -> Self {
+        match mode {
+            SseMode::SseB2 => ServerSideEncryption::B2Managed,
+            SseMode::SseC => {
+                let customer_key = derive_sse_customer_key(key);
+                let base64 = base64::engine::general_purpose::STANDARD;
+                ServerSideEncryption::Customer {
+                    key: base64.encode(customer_key),
+                    key_md5: base64.encode(md5(&customer_key)),
+                }
+            }
+        }
+    }
+
+    /// Headers of b2_upload_file
+    pub fn upload_headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ServerSideEncryption::B2Managed => vec![("X-Bz-Server-Side-Encryption", ALGORITHM.to_owned())],
+            ServerSideEncryption::Customer { .. } => self.customer_headers(),
+        }
+    }
+
+    /// Headers of b2_upload_part and of downloads, which only SSE-C needs
+    pub fn customer_headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ServerSideEncryption::B2Managed => Vec::new(),
+            ServerSideEncryption::Customer { key, key_md5 } => vec![
+                ("X-Bz-Server-Side-Encryption-Customer-Algorithm", ALGORITHM.to_owned()),
+                ("X-Bz-Server-Side-Encryption-Customer-Key", key.clone()),
+                ("X-Bz-Server-Side-Encryption-Customer-Key-Md5", key_md5.clone()),
+            ],
+        }
+    }
+
+    /// Headers of S3 PutObject, lowercase since they are signed (see `S3Endpoint::sign`)
+    pub fn s3_upload_headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ServerSideEncryption::B2Managed => vec![("x-amz-server-side-encryption", ALGORITHM.to_owned())],
+            ServerSideEncryption::Customer { .. } => self.s3_customer_headers(),
+        }
+    }
+
+    /// Headers of S3 GetObject, which only SSE-C needs
+    pub fn s3_customer_headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ServerSideEncryption::B2Managed => Vec::new(),
+            ServerSideEncryption::Customer { key, key_md5 } => vec![
+                ("x-amz-server-side-encryption-customer-algorithm", ALGORITHM.to_owned()),
+                ("x-amz-server-side-encryption-customer-key", key.clone()),
+                ("x-amz-server-side-encryption-customer-key-md5", key_md5.clone()),
+            ],
+        }
+    }
+
+    /// The `sourceServerSideEncryption` field of b2_copy_file and b2_copy_part, which only SSE-C needs
+    pub fn copy_source_settings(&self) -> Option<SseSettings> {
+        match self {
+            ServerSideEncryption::B2Managed => None,
+            ServerSideEncryption::Customer { .. } => Some(self.settings()),
+        }
+    }
+
+    /// The `serverSideEncryption` field of b2_start_large_file, and the destination of copies
+    pub fn settings(&self) -> SseSettings {
+        match self {
+            ServerSideEncryption::B2Managed => SseSettings {
+                mode: "SSE-B2",
+                algorithm: ALGORITHM,
+                customer_key: None,
+                customer_key_md5: None,
+            },
+            ServerSideEncryption::Customer { key, key_md5 } => SseSettings {
+                mode: "SSE-C",
+                algorithm: ALGORITHM,
+                customer_key: Some(key.clone()),
+                customer_key_md5: Some(key_md5.clone()),
+            },
+        }
+    }
+}
+
+/// B2 checks the SSE-C key against its MD5, we don't use it for anything else
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(constants[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[(i / 16) * 4 + i % 4]));
+        }
+        for (value, new) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, value) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_key;
+    use data_encoding::HEXLOWER;
+
+    #[test]
+    fn md5_matches_reference() {
+        assert_eq!(HEXLOWER.encode(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            HEXLOWER.encode(&md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(HEXLOWER.encode(&md5(&[b'a'; 64])), "014842d480b571495a4a0363793f7367");
+    }
+
+    #[test]
+    fn customer_key_headers() {
+        let mode: SseMode = serde_json::from_str(r#""sse-c""#).unwrap();
+        let sse = ServerSideEncryption::new(mode, &test_key());
+        let headers = sse.upload_headers();
+        assert_eq!(headers, sse.customer_headers());
+        assert_eq!(headers.len(), 3);
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let key = base64.decode(&headers[1].1).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(headers[2].1, base64.encode(md5(&key)));
+        let settings = serde_json::to_value(sse.settings()).unwrap();
+        assert_eq!(settings["customerKey"], headers[1].1);
+
+        let sse = ServerSideEncryption::new(SseMode::SseB2, &test_key());
+        assert!(sse.customer_headers().is_empty());
+        let settings = serde_json::to_value(sse.settings()).unwrap();
+        assert_eq!(settings, serde_json::json!({"mode": "SSE-B2", "algorithm": "AES256"}));
+    }
+}
@@ -0,0 +1,97 @@
+//! A transfer budget shared by every frozen process running on this machine
+//!
+//! Each running transfer holds a slot, which is a `FileLock` on one of `count` files in a folder shared by every
+//! user, so concurrent invocations never exceed the budget between them. The kernel releases the slots of a
+//! process that exits, however it exits.
+
+use crate::data::file_lock::FileLock;
+use crate::progress;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::thread;
+use tokio::sync::oneshot;
+
+pub struct TransferSlots {
+    dir: PathBuf,
+    count: u16,
+    /// The slot the next wait is on, so that the waits of this process are spread over every slot
+    next_wait: AtomicU16,
+}
+
+/// A slot held for the duration of a transfer, released when dropped
+pub struct TransferSlot {
+    _lock: FileLock,
+}
+
+impl TransferSlots {
+    pub fn new(dir: PathBuf, count: u16) -> Self {
+        Self {
+            dir,
+            count: count.max(1),
+            next_wait: AtomicU16::new(0),
+        }
+    }
+
+    /// Waits until a slot is free and takes it.
+    /// The budget is best effort: if the slots folder can't be used, we return None and don't wait.
+    pub async fn acquire(&self) -> Option<TransferSlot> {
+        for index in 0..self.count {
+            match FileLock::try_lock(&self.dir.join(index.to_string())) {
+                Ok(Some(lock)) => return Some(TransferSlot { _lock: lock }),
+                Ok(None) => {}
+                Err(err) => {
+                    progress::log(format!("Warning: Failed to take a transfer slot: {}", err));
+                    return None;
+                }
+            }
+        }
+
+        // Every slot is taken, the kernel wakes us up when the one we wait on is released.
+        // Not a blocking task of the runtime, which would wait for it on shutdown: a slot taken after we stopped
+        // waiting is dropped with the message, and released.
+        let index = self.next_wait.fetch_add(1, Ordering::Relaxed) % self.count;
+        let path = self.dir.join(index.to_string());
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = sender.send(FileLock::lock(&path));
+        });
+        match receiver.await {
+            Ok(Ok(lock)) => Some(TransferSlot { _lock: lock }),
+            Ok(Err(err)) => {
+                progress::log(format!("Warning: Failed to take a transfer slot: {}", err));
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn slots_are_shared_and_released() {
+        let dir = tempdir().unwrap();
+        let slots = TransferSlots::new(dir.path().to_owned(), 2);
+        let other_process = TransferSlots::new(dir.path().to_owned(), 2);
+
+        let first = slots.acquire().await.unwrap();
+        let _second = other_process.acquire().await.unwrap();
+        let waiting = slots.acquire();
+        tokio::pin!(waiting);
+        assert!(timeout(Duration::from_millis(100), &mut waiting).await.is_err());
+        drop(first);
+        let _third = timeout(Duration::from_secs(10), waiting).await.unwrap().unwrap();
+
+        // What a process that exited left in the slot files doesn't matter
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("0"), "4294967295").unwrap();
+        let slots = TransferSlots::new(dir.path().to_owned(), 1);
+        assert!(slots.acquire().await.is_some());
+    }
+}
@@ -0,0 +1,267 @@
+//! End of run reports for headless machines, where nobody reads the logs
+//!
+//! Reports are sent by email through an SMTP server, over TLS and with credentials if it asks for them.
+//! Only a relay on this machine (e.g. the local MTA) may be reached without TLS.
+
+use crate::data::audit::AuditRecord;
+use crate::data::duration::format_duration;
+use crate::output::format_timestamp;
+use crate::progress;
+use eyre::{bail, eyre, Result, WrapErr};
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// The rest of the errors are only counted, a broken run can have one per file
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Where to email the report of each backup, restore and delete
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EmailSettings {
+    /// The SMTP server, as host or host:port. The port defaults to that of `tls`.
+    pub smtp_server: String,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// Logs into the server if set
+    #[serde(default)]
+    pub credentials: Option<SmtpCredentials>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Only send reports of failed runs
+    #[serde(default)]
+    pub only_failures: bool,
+}
+
+/// How the connection to the SMTP server is encrypted
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgraded to TLS with STARTTLS before anything is sent, on port 587 by default
+    #[default]
+    StartTls,
+    /// TLS from the start, on port 465 by default
+    Tls,
+    /// Plain SMTP on port 25 by default, only to a relay on this machine
+    None,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SmtpCredentials {
+    pub username: String,
+    /// A file holding the password, kept out of the config file
+    pub password_file: PathBuf,
+}
+
+/// Emails the report of a run on the backup of `path`, if the config asks for it.
+/// The run is already over, so failing to send the report is only reported.
+pub async fn send_report(settings: Option<&EmailSettings>, path: &Path, record: &AuditRecord) {
+    let settings = match settings {
+        Some(settings) if !settings.only_failures || is_failure(record) => settings.clone(),
+        _ => return,
+    };
+    let (subject, body) = report(path, record);
+    let sent = tokio::task::spawn_blocking(move || send_email(&settings, &subject, &body)).await;
+    if let Err(err) = sent.map_err(|err| eyre!(err)).and_then(|sent| sent) {
+        progress::log(format!("Failed to email the report: {:#}", err));
+    }
+}
+
+fn is_failure(record: &AuditRecord) -> bool {
+    record.failure.is_some() || record.errors > 0
+}
+
+/// The subject and body of the email
+fn report(path: &Path, record: &AuditRecord) -> (String, String) {
+    let outcome = if is_failure(record) { "failed" } else { "succeeded" };
+    let subject = format!(
+        "Frozen {} of {} on {} {}",
+        record.operation.name(),
+        path.display(),
+        record.host,
+        outcome
+    )
+    // Paths can have line breaks, which would end the header
+    .replace(['\r', '\n'], " ");
+    let mut body = format!(
+        "Started: {} UTC\nDuration: {}\nUser: {}\nTransferred: {}\nDeleted: {}\nErrors: {}\n",
+        format_timestamp(record.started),
+        format_duration(Duration::from_secs(record.duration_secs)),
+        record.user,
+        record.transferred,
+        record.deleted,
+        record.errors
+    );
+    if let Some(exit_code) = record.exit_code {
+        body += &format!("Exit code: {}\n", exit_code);
+    }
+    if let Some(failure) = &record.failure {
+        body += &format!("\nFailure: {}\n", failure);
+    }
+    if !record.error_messages.is_empty() {
+        body += "\nErrors:\n";
+        for msg in record.error_messages.iter().take(MAX_REPORTED_ERRORS) {
+            body += &format!("\t{}\n", msg);
+        }
+        let unlisted = record
+            .errors
+            .saturating_sub(MAX_REPORTED_ERRORS.min(record.error_messages.len()));
+        if unlisted > 0 {
+            body += &format!("\tand {} more\n", unlisted);
+        }
+    }
+    (subject, body)
+}
+
+/// Sends the email, with its Date and Message-ID headers
+fn send_email(settings: &EmailSettings, subject: &str, body: &str) -> Result<()> {
+    if settings.to.is_empty() {
+        bail!("No recipient for the email report");
+    }
+    let mut message = Message::builder()
+        .from(parse_mailbox(&settings.from)?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        // A random one, the Date is added by default
+        .message_id(None);
+    for to in settings.to.iter() {
+        message = message.to(parse_mailbox(to)?);
+    }
+    let message = message.body(body.to_owned())?;
+    smtp_transport(settings)?
+        .send(&message)
+        .wrap_err_with(|| format!("Failed to send the email through {}", settings.smtp_server))?;
+    Ok(())
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .wrap_err_with(|| format!("Invalid email address \"{}\"", address))
+}
+
+fn smtp_transport(settings: &EmailSettings) -> Result<SmtpTransport> {
+    let (host, port) = match settings.smtp_server.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port.parse::<u16>()?)),
+        None => (settings.smtp_server.as_str(), None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let builder = SmtpTransport::builder_dangerous(host).timeout(Some(SMTP_TIMEOUT));
+    let builder = match settings.tls {
+        SmtpTls::StartTls => builder
+            .port(port.unwrap_or(587))
+            .tls(Tls::Required(TlsParameters::new(host.to_owned())?)),
+        SmtpTls::Tls => builder
+            .port(port.unwrap_or(465))
+            .tls(Tls::Wrapper(TlsParameters::new(host.to_owned())?)),
+        // The report and the credentials would cross the network in the clear
+        SmtpTls::None if !is_local(host) => bail!(
+            "Refusing to email the report to {} without TLS, only a relay on this machine may use \"tls\": \"none\"",
+            host
+        ),
+        SmtpTls::None => builder.port(port.unwrap_or(25)).tls(Tls::None),
+    };
+    let builder = match &settings.credentials {
+        Some(credentials) => {
+            let password = fs::read_to_string(&credentials.password_file).wrap_err_with(|| {
+                format!(
+                    "Failed to read the SMTP password from {}",
+                    credentials.password_file.display()
+                )
+            })?;
+            let password = password.trim_end_matches(['\r', '\n']).to_owned();
+            builder.credentials(Credentials::new(credentials.username.clone(), password))
+        }
+        None => builder,
+    };
+    Ok(builder.build())
+}
+
+fn is_local(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|addr| addr.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::audit::AuditOperation;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn sends_report_over_smtp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let settings = EmailSettings {
+            smtp_server: listener.local_addr()?.to_string(),
+            tls: SmtpTls::None,
+            credentials: None,
+            from: "frozen@example.com".to_owned(),
+            to: vec!["admin@example.com".to_owned()],
+            only_failures: false,
+        };
+        let server = thread::spawn(move || -> Result<String> {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            writer.write_all(b"220 ready\r\n")?;
+            let mut transcript = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                transcript += &line;
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-hello\r\n250 SIZE 1000000\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n")?;
+                    return Ok(transcript);
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply)?;
+            }
+        });
+
+        let mut record = AuditRecord::start(AuditOperation::Backup);
+        record.errors = 1;
+        record.error_messages = vec!["Failed to upload a".to_owned()];
+        let (subject, body) = report(Path::new("/data"), &record);
+        assert!(subject.starts_with("Frozen backup of /data on "));
+        assert!(subject.ends_with(" failed"));
+        send_email(&settings, &subject, &(body + ".hidden\n"))?;
+
+        let transcript = server.join().unwrap()?;
+        assert!(transcript.contains("RCPT TO:<admin@example.com>\r\n"));
+        assert!(transcript.contains("\tFailed to upload a\r\n"));
+        assert!(transcript.contains("\r\n..hidden\r\n"));
+        assert!(transcript.contains("\r\nDate: "));
+        assert!(transcript.contains("\r\nMessage-ID: <"));
+
+        // Only a relay on this machine gets the report in the clear
+        let remote = EmailSettings {
+            smtp_server: "smtp.example.com".to_owned(),
+            ..settings
+        };
+        let err = send_email(&remote, &subject, "").unwrap_err();
+        assert!(format!("{:#}", err).contains("without TLS"));
+        assert!(is_local("localhost") && is_local("::1") && !is_local("10.0.0.1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Structured output for commands that print listings
+//! The same rows can be rendered as an aligned table for humans, or as JSON or CSV for scripts.
+
+use crate::data::duration::format_duration;
+use clap::ArgMatches;
+use eyre::{bail, Result};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl OutputFormat {
+    /// Parses an optional `--format` style argument, defaulting to a table
+    pub fn from_arg(args: &ArgMatches, name: &str) -> Result<Self> {
+        match args.get_one::<String>(name).map(String::as_str) {
+            None | Some("table") => Ok(OutputFormat::Table),
+            Some("json") => Ok(OutputFormat::Json),
+            Some("csv") => Ok(OutputFormat::Csv),
+            Some(other) => bail!("Invalid output format \"{}\" (use table, json or csv)", other),
+        }
+    }
+}
+
+/// A single value, with the raw form given to scripts and the human-friendly form shown in tables
+#[derive(Clone, Debug)]
+pub struct Cell {
+    value: Value,
+    text: String,
+}
+
+impl Cell {
+    pub fn text(text: impl Into<String>) -> Self {
+        let text = text.into();
+        Self {
+            value: Value::String(text.clone()),
+            text,
+        }
+    }
+
+    pub fn number(number: u64) -> Self {
+        Self {
+            value: number.into(),
+            text: number.to_string(),
+        }
+    }
+
+    pub fn bool(value: bool) -> Self {
+        Self {
+            value: value.into(),
+            text: if value { "yes" } else { "no" }.to_owned(),
+        }
+    }
+
+    /// A size in bytes
+    pub fn size(size: u64) -> Self {
+        Self {
+            value: size.into(),
+            text: format_size(size),
+        }
+    }
+
+    /// A duration in seconds
+    pub fn duration(secs: u64) -> Self {
+        Self {
+            value: secs.into(),
+            text: format_duration(Duration::from_secs(secs)),
+        }
+    }
+
+    /// A transfer speed in bytes per second
+    pub fn throughput(bytes_per_sec: u64) -> Self {
+        Self {
+            value: bytes_per_sec.into(),
+            text: format_size(bytes_per_sec) + "/s",
+        }
+    }
+
+    /// A Unix timestamp, given to scripts in RFC 3339 format
+    pub fn timestamp(timestamp: u64) -> Self {
+        let formatted = format_timestamp(timestamp);
+        Self {
+            value: Value::String(formatted.replace(' ', "T") + "Z"),
+            text: formatted,
+        }
+    }
+
+    /// A missing or unknown value
+    pub fn none() -> Self {
+        Self {
+            value: Value::Null,
+            text: "-".to_owned(),
+        }
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or_else(Cell::none)
+    }
+}
+
+/// Rows of cells under named columns
+pub struct Listing {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Listing {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Self {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Table => self.render_table(),
+            OutputFormat::Json => self.render_json(),
+            OutputFormat::Csv => self.render_csv(),
+        }
+    }
+
+    fn render_table(&self) -> String {
+        let mut widths: Vec<usize> = self.columns.iter().map(|column| column.chars().count()).collect();
+        for row in self.rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.text.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        let header = self.columns.iter().map(|column| column.to_uppercase());
+        for line in std::iter::once(header.collect::<Vec<_>>()).chain(
+            self.rows
+                .iter()
+                .map(|row| row.iter().map(|cell| cell.text.clone()).collect()),
+        ) {
+            let mut line_text = String::new();
+            for (text, width) in line.iter().zip(widths.iter()) {
+                line_text += &format!("{:<width$}  ", text, width = width);
+            }
+            out += line_text.trim_end();
+            out.push('\n');
+        }
+        out
+    }
+
+    fn render_json(&self) -> String {
+        let rows: Vec<Value> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let object: Map<String, Value> = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, cell)| (column.to_string(), cell.value.clone()))
+                    .collect();
+                Value::Object(object)
+            })
+            .collect();
+        serde_json::to_string_pretty(&rows).unwrap() + "\n"
+    }
+
+    fn render_csv(&self) -> String {
+        let mut out = self.columns.join(",") + "\n";
+        for row in self.rows.iter() {
+            let fields: Vec<String> = row
+                .iter()
+                .map(|cell| match &cell.value {
+                    Value::Null => String::new(),
+                    Value::String(text) => csv_escape(text),
+                    value => value.to_string(),
+                })
+                .collect();
+            out += &fields.join(",");
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn csv_escape(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Formats a Unix timestamp as an UTC date and time, e.g. "2021-03-04 15:06:07"
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+
+    // Converts days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Formats a size in bytes with binary units, e.g. "1.5 GiB"
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Listing {
+        let mut listing = Listing::new(&["path", "files", "size"]);
+        listing.push(vec![Cell::text("/a, b"), Cell::number(3), Cell::size(2048)]);
+        listing.push(vec![Cell::text("/c"), Cell::none(), Cell::from(None::<Cell>)]);
+        listing
+    }
+
+    #[test]
+    fn render_formats() {
+        let listing = sample();
+        assert_eq!(
+            listing.render(OutputFormat::Table),
+            "PATH   FILES  SIZE\n/a, b  3      2.0 KiB\n/c     -      -\n"
+        );
+        assert_eq!(
+            listing.render(OutputFormat::Csv),
+            "path,files,size\n\"/a, b\",3,2048\n/c,,\n"
+        );
+        let json: Value = serde_json::from_str(&listing.render(OutputFormat::Json)).unwrap();
+        assert_eq!(json[0]["size"], 2048);
+        assert!(json[1]["files"].is_null());
+    }
+
+    #[test]
+    fn format_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20");
+        assert_eq!(Cell::timestamp(1_700_000_000).value, "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(format_size(12), "12 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -0,0 +1,106 @@
+//! Rough strength estimate of a new backup password, to warn about weak ones at setup
+
+/// Passwords estimated below this many bits get a warning
+pub const WEAK_PASSWORD_BITS: f64 = 50.0;
+
+/// A few of the most common passwords, attackers try these (and their variants) first
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "123456", "qwerty", "azerty", "letmein", "welcome", "admin", "iloveyou", "monkey", "dragon",
+    "football", "baseball", "sunshine", "princess", "master", "shadow", "superman", "trustno1", "backup", "secret",
+];
+
+/// Keyboard rows and alphabets, runs along them are cheap to guess
+const SEQUENCES: &[&str] = &[
+    "abcdefghijklmnopqrstuvwxyz",
+    "0123456789",
+    "qwertyuiop",
+    "asdfghjkl",
+    "zxcvbnm",
+    "azertyuiop",
+    "qsdfghjklm",
+];
+
+/// Estimates how many bits an attacker has to guess, a lot more pessimistic for predictable passwords.
+///
+/// Each character counts for the size of its character class, except characters that repeat or continue
+/// a sequence of the previous one, which count for very little. Passwords built around a common password get
+/// no credit for that part.
+pub fn estimate_bits(password: &str) -> f64 {
+    let lower = password.to_lowercase();
+    let mut stripped = lower.clone();
+    for common in COMMON_PASSWORDS {
+        stripped = stripped.replace(common, "");
+    }
+    let chars: Vec<char> = stripped.chars().collect();
+    let mut charset = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        charset += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        charset += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        charset += 10;
+    }
+    if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+        charset += 33;
+    }
+    let bits_per_char = (charset.max(1) as f64).log2();
+
+    let mut bits = if stripped.len() < lower.len() { 10.0 } else { 0.0 };
+    for (index, &c) in chars.iter().enumerate() {
+        let predictable = index > 0 && {
+            let previous = chars[index - 1];
+            previous == c
+                || SEQUENCES
+                    .iter()
+                    .any(|sequence| sequence.contains(&format!("{}{}", previous, c)))
+        };
+        bits += if predictable { 1.0 } else { bits_per_char };
+    }
+    bits
+}
+
+/// Why a password is weak, if it is
+pub fn weakness(password: &str) -> Option<String> {
+    if password.is_empty() {
+        return Some("The password is empty".to_owned());
+    }
+    let bits = estimate_bits(password);
+    if bits < WEAK_PASSWORD_BITS {
+        Some(format!(
+            "This password is weak (about {:.0} bits, at least {:.0} are recommended). \
+             A few random words or a longer passphrase would be much harder to guess",
+            bits, WEAK_PASSWORD_BITS
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_passwords_are_flagged() {
+        for weak in [
+            "",
+            "hunter2",
+            "Password123!",
+            "aaaaaaaaaaaaaaaaaaaa",
+            "abcdefghijklmnop1234",
+            "qwertyuiop",
+        ] {
+            assert!(weakness(weak).is_some(), "{} should be weak", weak);
+        }
+        for strong in [
+            "correct horse battery staple",
+            "kT9#vQ2!mZ7$wL4p",
+            "Tr0mbone-Velvet-Quasar",
+        ] {
+            assert!(weakness(strong).is_none(), "{} should be strong", strong);
+        }
+        assert!(estimate_bits("password") < estimate_bits("xkcdvmwq"));
+    }
+}
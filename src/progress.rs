@@ -1,8 +1,16 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
-use std::sync::Arc;
+use crate::data::duration::format_duration;
+use crate::data::file::SkippedFile;
+use crate::net::pause;
+use crate::output::format_size;
+use crate::stats::{self, Stage};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle, WeakProgressBar};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
 mod progress_handler;
 pub use progress_handler::*;
+mod verbosity;
+pub use verbosity::*;
 
 #[derive(Copy, Clone)]
 pub enum ProgressType {
@@ -11,6 +19,11 @@ pub enum ProgressType {
     Upload,
     Download,
     Delete,
+    /// Server-side copies, see `session::SyncSession`
+    Copy,
+    Folders,
+    /// Bytes of the DirDB transfers, see `dirdb::remote`
+    DirDB,
 }
 
 impl ProgressType {
@@ -18,35 +31,194 @@ impl ProgressType {
         match self {
             ProgressType::Diff => "Diff folder [{bar:50}]",
             ProgressType::Cleanup => "Cleanup [{bar:50}] {pos}/{len}",
-            ProgressType::Upload => "Upload file [{bar:50.green}] {pos}/{len}",
-            ProgressType::Download => "Download file [{bar:50.blue}] {pos}/{len}",
-            ProgressType::Delete => "Delete file [{bar:50.red}] {pos}/{len}",
+            ProgressType::Upload => "Upload file [{bar:50.green}] {pos}/{len} {msg}",
+            ProgressType::Download => "Download file [{bar:50.blue}] {pos}/{len} {msg}",
+            ProgressType::Delete => "Delete file [{bar:50.red}] {pos}/{len} {msg}",
+            ProgressType::Copy => "Copy file [{bar:50.green}] {pos}/{len} {msg}",
+            ProgressType::Folders => "Restore folders [{bar:50.cyan}] {pos}/{len}",
+            ProgressType::DirDB => "Transfer DirDB [{bar:50.yellow}] {binary_bytes}/{binary_total_bytes} {msg}",
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            ProgressType::Diff => "Diff",
+            ProgressType::Cleanup => "Cleanup",
+            ProgressType::Upload => "Upload",
+            ProgressType::Download => "Download",
+            ProgressType::Delete => "Delete",
+            ProgressType::Copy => "Copy",
+            ProgressType::Folders => "Restore folders",
+            ProgressType::DirDB => "DirDB",
+        }
+    }
+
+    fn is_transfer(&self) -> bool {
+        matches!(
+            self,
+            ProgressType::Upload | ProgressType::Download | ProgressType::Delete | ProgressType::Copy
+        )
+    }
+
+    /// Whether the bar counts bytes instead of files
+    fn counts_bytes(&self) -> bool {
+        matches!(self, ProgressType::DirDB)
+    }
+
+    /// The stage whose bytes are this bar's throughput, see `stats`
+    fn stage(&self) -> Option<Stage> {
+        match self {
+            ProgressType::Upload => Some(Stage::Upload),
+            ProgressType::Download => Some(Stage::Download),
+            _ => None,
+        }
+    }
+}
+
+/// Where the messages of the library go, see `set_log_handler`
+static LOG_HANDLER: Mutex<Option<fn(&str)>> = Mutex::new(None);
+
+/// Sends the messages of the library that aren't about a bar (e.g. "Starting diff") to `handler`.
+/// Until then they are dropped, the library doesn't write to the terminal itself.
+pub fn set_log_handler(handler: fn(&str)) {
+    *LOG_HANDLER.lock().unwrap() = Some(handler);
+}
+
+/// Shows a message to the user, with the handler given to `set_log_handler`
+pub fn log(msg: impl AsRef<str>) {
+    let handler = *LOG_HANDLER.lock().unwrap();
+    if let Some(handler) = handler {
+        handler(msg.as_ref());
+    }
+}
+
+/// Called each time a bar advances, see `set_advance_handler`. Read on every report, so not behind a lock.
+static ADVANCE_HANDLER: OnceLock<fn()> = OnceLock::new();
+
+/// Calls `handler` each time a bar advances, e.g. to tell a watchdog that the run isn't stuck. Only the first is kept.
+pub fn set_advance_handler(handler: fn()) {
+    let _ = ADVANCE_HANDLER.set(handler);
+}
+
+fn advanced() {
+    if let Some(handler) = ADVANCE_HANDLER.get() {
+        handler();
+    }
+}
+
+/// The bars of every live `Progress`, to mark them as paused (see `net::pause`) and to report the status of the run
+static LIVE_BARS: Mutex<Vec<(ProgressType, WeakProgressBar)>> = Mutex::new(Vec::new());
+
+/// The bars that are still alive and shown
+fn live_bars() -> Vec<(ProgressType, ProgressBar)> {
+    let mut bars = LIVE_BARS.lock().unwrap();
+    bars.retain(|(_, bar)| bar.upgrade().is_some());
+    bars.iter()
+        .filter_map(|(bar_type, bar)| Some((*bar_type, bar.upgrade()?)))
+        .filter(|(_, bar)| bar.length().is_some())
+        .collect()
+}
+
+/// Marks the transfer bars as paused, or clears the mark
+pub(crate) fn show_paused(paused: bool) {
+    for (bar_type, bar) in LIVE_BARS.lock().unwrap().iter() {
+        if let Some(bar) = bar.upgrade().filter(|_| bar_type.is_transfer()) {
+            bar.set_message(if paused { "(paused)" } else { "" });
         }
     }
 }
 
+/// One line per shown progress bar with its count and throughput, e.g. to answer SIGUSR1 during a long backup
+pub fn status_report() -> Vec<String> {
+    let stages = stats::snapshot();
+    let mut lines = Vec::new();
+    for (bar_type, bar) in live_bars() {
+        let (position, length) = (bar.position(), bar.length().unwrap_or(0));
+        let (position, length) = if bar_type.counts_bytes() {
+            (format_size(position), format_size(length))
+        } else {
+            (position.to_string(), length.to_string())
+        };
+        let mut line = format!(
+            "{}: {}/{} in {}",
+            bar_type.name(),
+            position,
+            length,
+            format_duration(Duration::from_secs(bar.elapsed().as_secs()))
+        );
+        let bytes = stages
+            .iter()
+            .find(|stats| Some(stats.stage) == bar_type.stage())
+            .map_or(0, |stats| stats.bytes);
+        if bytes > 0 && !bar.elapsed().is_zero() {
+            let bytes_per_sec = (bytes as f64 / bar.elapsed().as_secs_f64()) as u64;
+            line += &format!(", {} at {}/s", format_size(bytes), format_size(bytes_per_sec));
+        }
+        if bar_type.is_transfer() && pause::is_paused() {
+            line += " (paused)";
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        lines.push("Not transferring anything yet".to_owned());
+    }
+    lines
+}
+
 pub struct Progress {
     multi_progress: Arc<MultiProgress>,
+    warnings: Arc<Mutex<Warnings>>,
     diff_progress: ProgressHandler,
     cleanup_progress: ProgressHandler,
     upload_progress: ProgressHandler,
     download_progress: ProgressHandler,
     delete_progress: ProgressHandler,
+    copy_progress: ProgressHandler,
+    folders_progress: ProgressHandler,
+    dirdb_progress: ProgressHandler,
 }
 
 impl Progress {
-    pub fn new(verbose: bool) -> Self {
-        Self {
+    pub fn new(verbosity: Verbosity) -> Self {
+        let warnings = Arc::new(Mutex::new(Warnings::default()));
+        let bar = |bar_type| Self::create_progress_bar(bar_type, warnings.clone(), verbosity);
+        let progress = Self {
             multi_progress: Arc::new(MultiProgress::with_draw_target(ProgressDrawTarget::stdout())),
-            diff_progress: Self::create_progress_bar(ProgressType::Diff, verbose),
-            cleanup_progress: Self::create_progress_bar(ProgressType::Cleanup, verbose),
-            upload_progress: Self::create_progress_bar(ProgressType::Upload, verbose),
-            download_progress: Self::create_progress_bar(ProgressType::Download, verbose),
-            delete_progress: Self::create_progress_bar(ProgressType::Delete, verbose),
+            diff_progress: bar(ProgressType::Diff),
+            cleanup_progress: bar(ProgressType::Cleanup),
+            upload_progress: bar(ProgressType::Upload),
+            download_progress: bar(ProgressType::Download),
+            delete_progress: bar(ProgressType::Delete),
+            copy_progress: bar(ProgressType::Copy),
+            folders_progress: bar(ProgressType::Folders),
+            dirdb_progress: bar(ProgressType::DirDB),
+            warnings,
+        };
+        LIVE_BARS.lock().unwrap().extend(
+            [
+                (ProgressType::Diff, &progress.diff_progress),
+                (ProgressType::Cleanup, &progress.cleanup_progress),
+                (ProgressType::Upload, &progress.upload_progress),
+                (ProgressType::Download, &progress.download_progress),
+                (ProgressType::Delete, &progress.delete_progress),
+                (ProgressType::Copy, &progress.copy_progress),
+                (ProgressType::Folders, &progress.folders_progress),
+                (ProgressType::DirDB, &progress.dirdb_progress),
+            ]
+            .iter()
+            .map(|(bar_type, handler)| (*bar_type, handler.progress_bar.downgrade())),
+        );
+        if pause::is_paused() {
+            show_paused(true);
         }
+        progress
     }
 
-    fn create_progress_bar(bar_type: ProgressType, verbose: bool) -> ProgressHandler {
+    fn create_progress_bar(
+        bar_type: ProgressType,
+        warnings: Arc<Mutex<Warnings>>,
+        verbosity: Verbosity,
+    ) -> ProgressHandler {
         let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::hidden())
             .with_style(
                 ProgressStyle::default_bar()
@@ -55,7 +227,7 @@ impl Progress {
                     .progress_chars("=> "),
             )
             .with_finish(ProgressFinish::Abandon);
-        ProgressHandler::new(progress_bar, verbose)
+        ProgressHandler::new(progress_bar, warnings, verbosity)
     }
 
     /// Returns a handler to report progress with
@@ -66,6 +238,9 @@ impl Progress {
             ProgressType::Upload => &self.upload_progress,
             ProgressType::Download => &self.download_progress,
             ProgressType::Delete => &self.delete_progress,
+            ProgressType::Copy => &self.copy_progress,
+            ProgressType::Folders => &self.folders_progress,
+            ProgressType::DirDB => &self.dirdb_progress,
         }
     }
 
@@ -83,6 +258,14 @@ impl Progress {
         bar_handler
     }
 
+    /// Displays a bar whose length grows with each transfer it reports, see `ProgressHandler::add_bytes_to_do`
+    pub fn show_bytes_bar(&self, bar_type: ProgressType) -> ProgressHandler {
+        let bar_handler = self.get_progress_handler(bar_type).clone();
+        bar_handler.set_length(0);
+        self.multi_progress.add(bar_handler.progress_bar.clone());
+        bar_handler
+    }
+
     /// Returns the number of progress errors logged since the output started
     pub fn errors_count(&self) -> usize {
         self.diff_progress.errors_count()
@@ -90,15 +273,45 @@ impl Progress {
             + self.upload_progress.errors_count()
             + self.download_progress.errors_count()
             + self.delete_progress.errors_count()
+            + self.copy_progress.errors_count()
+            + self.folders_progress.errors_count()
+    }
+
+    /// Returns the first errors of each bar, see `ProgressHandler::errors`
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = self.diff_progress.errors();
+        errors.extend(self.cleanup_progress.errors());
+        errors.extend(self.upload_progress.errors());
+        errors.extend(self.download_progress.errors());
+        errors.extend(self.delete_progress.errors());
+        errors.extend(self.copy_progress.errors());
+        errors.extend(self.folders_progress.errors());
+        errors
+    }
+
+    /// Returns each warning reported with `ProgressHandler::report_warning`, with how many times it happened
+    pub fn warnings(&self) -> Vec<(String, usize)> {
+        self.warnings.lock().unwrap().counts()
     }
 
-    /// Returns whether all operations have been completed successfully
+    /// Returns the local files that were skipped because they couldn't be read
+    pub fn skipped_files(&self) -> Vec<SkippedFile> {
+        let mut skipped = self.diff_progress.skipped_files();
+        skipped.extend(self.upload_progress.skipped_files());
+        skipped.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        skipped
+    }
+
+    /// Returns whether all operations have been completed successfully.
+    /// The DirDB transfers don't count, a DirDB that fails to download is just diffed without.
     pub fn is_complete(&self) -> bool {
         self.diff_progress.is_complete()
             && self.cleanup_progress.is_complete()
             && self.upload_progress.is_complete()
             && self.download_progress.is_complete()
             && self.delete_progress.is_complete()
+            && self.copy_progress.is_complete()
+            && self.folders_progress.is_complete()
     }
 }
 
@@ -109,5 +322,79 @@ impl Drop for Progress {
         self.upload_progress.finish();
         self.download_progress.finish();
         self.delete_progress.finish();
+        self.copy_progress.finish();
+        self.folders_progress.finish();
+        self.dirdb_progress.finish();
+
+        // Repeated warnings were only printed once, the count says how bad it got
+        let repeated: Vec<_> = self.warnings().into_iter().filter(|(_, count)| *count > 1).collect();
+        if !repeated.is_empty() {
+            log("Repeated warnings:");
+            for (msg, count) in repeated {
+                log(format!("\t{} \u{d7}{}", msg, count));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_shown_bars() {
+        let progress = Progress::new(Verbosity::default());
+        let cleanup = progress.show_progress_bar(ProgressType::Cleanup, 3);
+        cleanup.report_success();
+        assert!(status_report().iter().any(|line| line.starts_with("Cleanup: 1/3 in ")));
+        drop(cleanup);
+        drop(progress);
+        assert!(!status_report().iter().any(|line| line.starts_with("Cleanup: 1/3")));
+    }
+
+    #[test]
+    fn warnings_are_printed_once_and_counted() {
+        let progress = Progress::new(Verbosity::default());
+        let upload = progress.get_progress_handler(ProgressType::Upload);
+        let download = progress.get_progress_handler(ProgressType::Download);
+        for _ in 0..3 {
+            upload.report_warning("503 Service Unavailable");
+        }
+        download.report_warning("Authorization expired, authorizing again");
+        download.report_warning("503 Service Unavailable");
+        assert_eq!(progress.warnings(), vec![
+            ("503 Service Unavailable".to_owned(), 4),
+            ("Authorization expired, authorizing again".to_owned(), 1),
+        ]);
+        assert!(progress.is_complete());
+    }
+
+    #[test]
+    fn logs_go_to_the_handler() {
+        static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        set_log_handler(|msg| LOGGED.lock().unwrap().push(msg.to_owned()));
+        let progress = Progress::new(Verbosity::default());
+        let upload = progress.get_progress_handler(ProgressType::Upload);
+        upload.report_warning("Upload of a slow file is slow");
+        upload.report_warning("Upload of a slow file is slow");
+        drop(progress);
+        assert!(LOGGED
+            .lock()
+            .unwrap()
+            .contains(&"\tUpload of a slow file is slow \u{d7}2".to_owned()));
+    }
+
+    #[test]
+    fn bytes_bar_grows_with_transfers() {
+        let progress = Progress::new(Verbosity::default());
+        let dirdb = progress.show_bytes_bar(ProgressType::DirDB);
+        dirdb.add_bytes_to_do(3000);
+        dirdb.report_bytes(1000);
+        dirdb.add_bytes_to_do(1000);
+        assert!(status_report()
+            .iter()
+            .any(|line| line.starts_with("DirDB: 1000 B/3.9 KiB in ")));
+        dirdb.report_bytes(3000);
+        assert!(dirdb.is_complete());
     }
 }
@@ -1,22 +1,61 @@
+use super::{LogCategory, Verbosity};
+use crate::data::file::SkippedFile;
 use indicatif::ProgressBar;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Only the first errors are kept for the end of run summary, a broken run can report one per file
+const MAX_KEPT_ERRORS: usize = 1000;
+
+/// The warnings of a run, shared by all of its bars. Each warning is printed the first time only,
+/// and counted so that the end of the run can say how often it happened.
+#[derive(Default)]
+pub(super) struct Warnings {
+    /// Each warning with how many times it happened, in the order they first happened
+    seen: Vec<(String, usize)>,
+    /// Where each warning is in `seen`
+    index: HashMap<String, usize>,
+}
+
+impl Warnings {
+    /// Counts a warning, returns whether it's the first time we see it
+    fn record(&mut self, msg: &str) -> bool {
+        if let Some(&pos) = self.index.get(msg) {
+            self.seen[pos].1 += 1;
+            return false;
+        }
+        self.index.insert(msg.to_owned(), self.seen.len());
+        self.seen.push((msg.to_owned(), 1));
+        true
+    }
+
+    pub(super) fn counts(&self) -> Vec<(String, usize)> {
+        self.seen.clone()
+    }
+}
 
 #[derive(Clone)]
 pub struct ProgressHandler {
     pub(super) progress_bar: ProgressBar,
     bar_len: Arc<AtomicUsize>,
     errors_count: Arc<AtomicUsize>,
-    verbose: bool,
+    errors: Arc<Mutex<Vec<String>>>,
+    skipped: Arc<Mutex<Vec<SkippedFile>>>,
+    warnings: Arc<Mutex<Warnings>>,
+    verbosity: Verbosity,
 }
 
 impl ProgressHandler {
-    pub(super) fn new(progress_bar: ProgressBar, verbose: bool) -> Self {
+    pub(super) fn new(progress_bar: ProgressBar, warnings: Arc<Mutex<Warnings>>, verbosity: Verbosity) -> Self {
         Self {
             progress_bar,
             bar_len: Arc::new(AtomicUsize::new(0)),
             errors_count: Arc::new(AtomicUsize::new(0)),
-            verbose,
+            errors: Arc::new(Mutex::new(Vec::new())),
+            skipped: Arc::new(Mutex::new(Vec::new())),
+            warnings,
+            verbosity,
         }
     }
 
@@ -27,11 +66,41 @@ impl ProgressHandler {
 
     pub fn report_success(&self) {
         self.progress_bar.inc(1);
+        super::advanced();
+    }
+
+    /// Grows the length of a bytes bar by the size of a transfer that starts
+    pub fn add_bytes_to_do(&self, bytes: u64) {
+        self.bar_len.fetch_add(bytes as usize, Ordering::AcqRel);
+        self.progress_bar.inc_length(bytes);
+    }
+
+    /// Reports transferred bytes on a bytes bar
+    pub fn report_bytes(&self, bytes: u64) {
+        self.progress_bar.inc(bytes);
+        super::advanced();
     }
 
     pub fn report_error(&self, msg: impl AsRef<str>) {
-        self.errors_count.fetch_add(1, Ordering::AcqRel);
+        if self.errors_count.fetch_add(1, Ordering::AcqRel) < MAX_KEPT_ERRORS {
+            self.errors.lock().unwrap().push(msg.as_ref().to_owned());
+        }
         self.progress_bar.println("Error: ".to_string() + msg.as_ref());
+        // A failed file is still one less to go
+        super::advanced();
+    }
+
+    /// Reports an error for a local file that couldn't be read, and remembers it for the summary
+    pub fn report_skipped(&self, file: SkippedFile) {
+        self.report_error(format!("Skipped {}: {}", file.rel_path.display(), file.reason));
+        self.skipped.lock().unwrap().push(file);
+    }
+
+    /// Prints a warning unless it was already printed during this run, see `Progress::warnings`
+    pub fn report_warning(&self, msg: impl AsRef<str>) {
+        if self.warnings.lock().unwrap().record(msg.as_ref()) {
+            self.progress_bar.println("Warning: ".to_string() + msg.as_ref());
+        }
     }
 
     pub fn println(&self, msg: impl AsRef<str>) {
@@ -43,9 +112,9 @@ impl ProgressHandler {
         self.progress_bar.abandon();
     }
 
-    /// When true, it is okay to println() verbose progress information
-    pub fn verbose(&self) -> bool {
-        self.verbose
+    /// When true, it is okay to println() progress information of this category
+    pub fn logs(&self, category: LogCategory) -> bool {
+        self.verbosity.logs(category)
     }
 
     /// Returns the number of progress errors logged since the output started
@@ -53,6 +122,16 @@ impl ProgressHandler {
         self.errors_count.load(Ordering::Acquire)
     }
 
+    /// Returns the first errors reported with `report_error`
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    /// Returns the files reported with `report_skipped`
+    pub fn skipped_files(&self) -> Vec<SkippedFile> {
+        self.skipped.lock().unwrap().clone()
+    }
+
     /// Returns whether all operations have been completed successfully
     pub fn is_complete(&self) -> bool {
         self.errors_count() == 0 && self.progress_bar.position() == self.bar_len.load(Ordering::Acquire) as u64
@@ -0,0 +1,73 @@
+use eyre::{bail, Result};
+
+/// What can be logged on top of the progress bars
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogCategory {
+    /// Every file uploaded, downloaded or deleted
+    Transfers,
+    /// Every failed request that is retried, and where the B2 servers resolve to
+    Retries,
+    /// How folders are listed during a diff, and why changed files aren't uploaded
+    Diff,
+}
+
+impl LogCategory {
+    /// The categories turned on by each `-v`, in order
+    const BY_LEVEL: [LogCategory; 3] = [LogCategory::Transfers, LogCategory::Retries, LogCategory::Diff];
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "transfers" => Ok(LogCategory::Transfers),
+            "retries" => Ok(LogCategory::Retries),
+            "diff" => Ok(LogCategory::Diff),
+            _ => bail!("Invalid log category \"{}\" (use transfers, retries or diff)", name),
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The categories to log, from `-v`, `-vv`, `-vvv` or `--log`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Verbosity {
+    categories: u8,
+}
+
+impl Verbosity {
+    /// `-v` logs the transfers, `-vv` the retries too, and `-vvv` the diff decisions too
+    pub fn from_level(level: u8) -> Self {
+        Self::only(LogCategory::BY_LEVEL.iter().take(level as usize))
+    }
+
+    /// Logs just these categories, whatever the level
+    pub fn only<'a>(categories: impl IntoIterator<Item = &'a LogCategory>) -> Self {
+        Self {
+            categories: categories.into_iter().fold(0, |bits, category| bits | category.bit()),
+        }
+    }
+
+    pub fn logs(&self, category: LogCategory) -> bool {
+        self.categories & category.bit() != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_add_categories() {
+        assert!(!Verbosity::default().logs(LogCategory::Transfers));
+        assert!(Verbosity::from_level(1).logs(LogCategory::Transfers));
+        assert!(!Verbosity::from_level(1).logs(LogCategory::Retries));
+        assert!(Verbosity::from_level(3).logs(LogCategory::Diff));
+        assert_eq!(Verbosity::from_level(7), Verbosity::from_level(3));
+
+        let diff_only = Verbosity::only(&[LogCategory::from_name("diff").unwrap()]);
+        assert!(diff_only.logs(LogCategory::Diff));
+        assert!(!diff_only.logs(LogCategory::Transfers));
+        assert!(LogCategory::from_name("everything").is_err());
+    }
+}
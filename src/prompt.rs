@@ -1,3 +1,6 @@
+use frozen_core::config::Prompt;
+use frozen_core::crypto::SecretString;
+use frozen_core::password::weakness;
 use std::io::{stdin, stdout, Write};
 
 fn prompt_readline() -> String {
@@ -16,10 +19,33 @@ pub fn prompt(msg: &str) -> String {
     prompt_readline()
 }
 
-pub fn prompt_password(msg: &str) -> String {
+pub fn prompt_password(msg: &str) -> SecretString {
     print!("{}: ", msg);
     stdout().flush().unwrap();
-    rpassword::read_password().unwrap_or_else(|_| prompt_readline())
+    SecretString::from(rpassword::read_password().unwrap_or_else(|_| prompt_readline()))
+}
+
+/// Like `prompt`, but for secrets that are fine to show while they're typed (e.g. a pasted app key)
+pub fn prompt_secret(msg: &str) -> SecretString {
+    SecretString::from(prompt(msg))
+}
+
+/// Asks for a new password twice, warning about weak ones
+pub fn prompt_new_password(msg: &str) -> SecretString {
+    println!("Your backup password can't be recovered or reset. If you forget it, your backups are lost.");
+    loop {
+        let password = prompt_password(msg);
+        if let Some(weakness) = weakness(password.expose()) {
+            println!("{}", weakness);
+            if !prompt_yes_no("Use it anyway?") {
+                continue;
+            }
+        }
+        if prompt_password("Enter the password again") == password {
+            return password;
+        }
+        println!("The passwords don't match, please try again");
+    }
 }
 
 pub fn prompt_yes_no(msg: &str) -> bool {
@@ -37,3 +63,28 @@ pub fn prompt_yes_no(msg: &str) -> bool {
         }
     }
 }
+
+/// Asks on the terminal for what the config needs, see `Config::get_or_create`
+pub struct TerminalPrompt;
+
+impl Prompt for TerminalPrompt {
+    fn text(&self, msg: &str) -> String {
+        prompt(msg)
+    }
+
+    fn password(&self, msg: &str) -> SecretString {
+        prompt_password(msg)
+    }
+
+    fn secret(&self, msg: &str) -> SecretString {
+        prompt_secret(msg)
+    }
+
+    fn new_password(&self, msg: &str) -> SecretString {
+        prompt_new_password(msg)
+    }
+
+    fn yes_no(&self, msg: &str) -> bool {
+        prompt_yes_no(msg)
+    }
+}
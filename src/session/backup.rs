@@ -0,0 +1,419 @@
+use super::AuditedSession;
+use crate::action;
+use crate::config::Config;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::duration::format_duration;
+use crate::data::file::SkippedFile;
+use crate::data::history::{self, BackupRun};
+use crate::data::missing::MissingFiles;
+use crate::data::root::{dir_path_hashes, BackupRoot};
+use crate::dirdb::scan_cache::ScanCache;
+use crate::dirdb::{diff::DiffStrategy, diff::DirDiff, diff::FileDiff, dirstat::DirStat, remote::RemoteDirDB, DirDB};
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{self, LogCategory, Progress, ProgressType};
+use crate::stream::CompressionLevel;
+use eyre::{eyre, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::SpawnExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Options that change how a single backup behaves
+#[derive(Clone, Default)]
+pub struct BackupOptions {
+    /// Keep remote files that have been deleted locally
+    pub keep_existing: bool,
+    /// Only delete remote files once they've been missing locally for this long, see `data::missing`
+    pub delete_after: Option<Duration>,
+    /// Fail before changing anything on the remote if some local files can't be read, instead of skipping them
+    pub strict_scan: bool,
+    /// Only scan and back up this folder of the source (relative to it), the rest of the backup is left as is
+    pub only: Option<PathBuf>,
+    /// Check with the remote that each upload was stored with the right size and SHA1 before counting it as done
+    pub verify: bool,
+    /// How the folders are listed on the remote, only worth changing to debug the diff
+    pub diff_strategy: DiffStrategy,
+    /// Leave out the files modified less than this long ago, which may still be written to, until a later backup
+    pub min_age: Option<Duration>,
+    /// Files left out of the backup as if they didn't exist, relative to the source. Their backed up versions are deleted.
+    pub exclude: Vec<PathBuf>,
+    /// Don't stat the files of folders that didn't change since the last backups, see `dirdb::scan_cache`.
+    /// Off by default, since files modified in place in such folders are missed until the cache expires.
+    pub scan_cache: bool,
+}
+
+/// Backs up a local folder into a backup root
+///
+/// The root must already be locked (see `data::root::open_create_root`), the session does not unlock it.
+pub struct BackupSession {
+    config: Config,
+    backend: Arc<dyn Backend>,
+    root: Arc<BackupRoot>,
+    source: PathBuf,
+    options: BackupOptions,
+}
+
+impl BackupSession {
+    pub fn new(
+        config: &Config,
+        backend: Arc<dyn Backend>,
+        root: Arc<BackupRoot>,
+        source: PathBuf,
+        options: BackupOptions,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            backend,
+            root,
+            source,
+            options,
+        }
+    }
+}
+
+impl AuditedSession for BackupSession {
+    const OPERATION: AuditOperation = AuditOperation::Backup;
+
+    /// Diffs the source folder with the remote and uploads or deletes files as needed.
+    /// Unfinished uploads are left alone, since they may be from a backup still running elsewhere (see `data::gc`).
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self {
+            config,
+            backend,
+            root,
+            source: path,
+            options,
+        } = self;
+
+        let mut run = BackupRun::start();
+        progress::log("Starting diff");
+        let progress = Progress::new(config.verbosity);
+        let diff_progress = progress.show_progress_bar(ProgressType::Diff, 4);
+        let upload_progress = progress.get_progress_handler(ProgressType::Upload);
+        let delete_progress = progress.get_progress_handler(ProgressType::Delete);
+        let dirdb_progress = progress.show_bytes_bar(ProgressType::DirDB);
+
+        let backend = backend.with_progress(diff_progress.clone());
+
+        // Lets us wait for all backup actions to complete
+        let action_futs = FuturesUnordered::new();
+
+        let remote_dirdb_fut = {
+            let backend = backend.clone();
+            let path_hash = root.path_hash.clone();
+            tokio::spawn(async move {
+                RemoteDirDB::fetch_with_progress(backend.as_ref(), &path_hash, Some(dirdb_progress)).await
+            })
+        };
+
+        let mut scan_skipped = Vec::new();
+        let mut scan_cache = if options.scan_cache {
+            Some(ScanCache::load(&Config::get_scan_cache_path().join(&root.path_hash)))
+        } else {
+            None
+        };
+        let local_dirdb = Arc::new(match &options.only {
+            Some(rel_dir) => DirDB::new_from_local_subtree(
+                &path,
+                rel_dir,
+                backend.key(),
+                &options.exclude,
+                &mut scan_skipped,
+                scan_cache.as_mut(),
+            )?,
+            None => DirDB::new_from_local_skipping(
+                &path,
+                backend.key(),
+                &options.exclude,
+                &mut scan_skipped,
+                scan_cache.as_mut(),
+            )?,
+        });
+        if let Some(scan_cache) = scan_cache {
+            let scanned_dir = options.only.as_deref().unwrap_or_else(|| Path::new(""));
+            // The cache only saves time, the backup goes on without it
+            if let Err(err) = scan_cache.save(scanned_dir) {
+                diff_progress.println(format!("Failed to save the scan cache: {:#}", err));
+            }
+        }
+        if options.strict_scan {
+            check_readable(&path, &local_dirdb.root, &mut scan_skipped)?;
+            if !scan_skipped.is_empty() {
+                diff_progress.finish();
+                drop(progress);
+                let mut msg = format!("{} file(s) or folder(s) can't be read:", scan_skipped.len());
+                for skipped in scan_skipped.iter() {
+                    msg += &format!("\n\t{}\t{}", skipped.rel_path.display(), skipped.reason);
+                }
+                return Err(eyre!(msg).wrap_err("Backup refused by --strict-scan, nothing was changed"));
+            }
+        }
+        for skipped in scan_skipped.iter() {
+            diff_progress.report_skipped(skipped.clone());
+        }
+        run.files_count = local_dirdb.root.total_files_count;
+        run.total_size = local_dirdb.root.total_size();
+        diff_progress.report_success();
+
+        let mut remote_dirdb = remote_dirdb_fut.await??;
+        let mut missing = match options.delete_after {
+            Some(delete_after) if !options.keep_existing => {
+                Some(MissingFiles::fetch(backend.as_ref(), &root.path_hash, delete_after).await?)
+            }
+            _ => None,
+        };
+        let rate_limiter = Arc::new(RateLimiter::new(&config, &backend)?);
+        // With --only, the DirDBs we save are the remote one with just the subtree replaced
+        let mut grafting = None;
+        let mut dir_diff = match &options.only {
+            None => {
+                remote_dirdb.load_shards(backend.as_ref(), &local_dirdb.root).await;
+                DirDiff::new(
+                    root.clone(),
+                    rate_limiter.clone(),
+                    local_dirdb.clone(),
+                    &remote_dirdb.dirdb,
+                    options.diff_strategy,
+                )?
+            }
+            Some(rel_dir) => {
+                let (dir_hashes, prefix_path_hash) = dir_path_hashes(rel_dir, backend.key())?;
+                if let Some(missing) = &mut missing {
+                    missing.keep_previous_outside(&(root.path_hash.clone() + &prefix_path_hash));
+                }
+                remote_dirdb.load_shards_of_subtree(backend.as_ref(), &dir_hashes).await;
+                let remote = remote_dirdb
+                    .dirdb
+                    .as_ref()
+                    .ok_or_else(|| eyre!("This backup has no usable DirDB, run a full backup before using --only"))?;
+                let remote_subtree = remote.find(&dir_hashes).cloned().unwrap_or_else(|| DirStat {
+                    dir_name: local_dirdb.root.dir_name.clone(),
+                    dir_name_hash: local_dirdb.root.dir_name_hash,
+                    ..Default::default()
+                });
+                grafting = Some((rel_dir, DirDB {
+                    root: remote.root.clone(),
+                }));
+                DirDiff::new_at(
+                    root.clone(),
+                    rate_limiter.clone(),
+                    local_dirdb.clone(),
+                    &remote_subtree,
+                    prefix_path_hash,
+                    options.diff_strategy,
+                )?
+            }
+        };
+        let path = Arc::new(path);
+        let compression = Arc::new(match config.min_compression_level {
+            Some(min_level) => CompressionLevel::adaptive(min_level, config.compression_level),
+            None => CompressionLevel::fixed(config.compression_level),
+        });
+        diff_progress.report_success();
+        let log_diff = diff_progress.logs(LogCategory::Diff);
+        if log_diff {
+            for listing in dir_diff.listings() {
+                diff_progress.println(listing.to_string());
+            }
+        }
+
+        diff_progress.println("Uploading pessimistic DirDB");
+        match &grafting {
+            Some((rel_dir, remote_full)) => {
+                let subtree = dir_diff.pessimistic_dirdb().root.clone();
+                let pessimistic_dirdb = remote_full.grafted(rel_dir, backend.key(), subtree)?;
+                remote_dirdb.save(backend.as_ref(), &pessimistic_dirdb).await?;
+            }
+            None => {
+                remote_dirdb
+                    .save(backend.as_ref(), dir_diff.pessimistic_dirdb())
+                    .await?;
+            }
+        }
+        diff_progress.report_success();
+
+        diff_progress.println("Starting backup");
+        let mut num_upload_actions = 0;
+        let mut num_delete_actions = 0;
+        let mut too_recent = Vec::new();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        while let Some(item) = dir_diff.next().await {
+            let item = item?;
+
+            match item {
+                FileDiff {
+                    local: Some(lfile),
+                    remote,
+                } => {
+                    if let Some(rfile) = remote {
+                        if rfile.last_modified >= lfile.last_modified {
+                            if log_diff {
+                                diff_progress.println(format!(
+                                    "Not uploading {}, the backup is at least as recent",
+                                    lfile.rel_path.display()
+                                ));
+                            }
+                            continue;
+                        }
+                    }
+                    // Files modified in the future are uploaded, or they'd wait for that long
+                    if let Some(min_age) = options.min_age {
+                        if lfile.last_modified <= now && now - lfile.last_modified < min_age.as_secs() {
+                            too_recent.push(lfile.rel_path);
+                            continue;
+                        }
+                    }
+                    num_upload_actions += 1;
+                    action_futs.spawn(action::upload(
+                        rate_limiter.clone(),
+                        upload_progress.clone(),
+                        compression.clone(),
+                        path.clone(),
+                        options.verify,
+                        lfile,
+                    ))?;
+                }
+                FileDiff {
+                    local: None,
+                    remote: Some(rfile),
+                } => {
+                    // Files we couldn't read locally aren't gone, we must keep their last backup
+                    if options.keep_existing
+                        || scan_skipped
+                            .iter()
+                            .any(|skipped| rfile.rel_path.starts_with(&skipped.rel_path))
+                    {
+                        continue;
+                    }
+                    if let Some(missing) = &mut missing {
+                        if !missing.is_expired(&rfile.full_path_hash, now) {
+                            if log_diff {
+                                diff_progress.println(format!(
+                                    "Not deleting {} yet, it was deleted locally too recently",
+                                    rfile.rel_path.display()
+                                ));
+                            }
+                            continue;
+                        }
+                    }
+                    num_delete_actions += 1;
+                    action_futs.spawn(action::delete(rate_limiter.clone(), delete_progress.clone(), rfile))?;
+                }
+                FileDiff {
+                    local: None,
+                    remote: None,
+                } => unreachable!(),
+            }
+        }
+
+        let delete_progress = progress.show_progress_bar(ProgressType::Delete, num_delete_actions);
+        let upload_progress = progress.show_progress_bar(ProgressType::Upload, num_upload_actions);
+        diff_progress.println(dir_diff.stats().to_string());
+        diff_progress.report_success();
+        diff_progress.finish();
+
+        action_futs.for_each(|()| futures::future::ready(())).await;
+        upload_progress.finish();
+        delete_progress.finish();
+        let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+        let error_messages = progress.errors();
+        let skipped = progress.skipped_files();
+        drop(progress);
+
+        if !skipped.is_empty() {
+            progress::log(format!("Skipped {} unreadable file(s) or folder(s):", skipped.len()));
+            for file in skipped.iter() {
+                progress::log(format!("\t{}\t{}", file.rel_path.display(), file.reason));
+            }
+        }
+
+        if !too_recent.is_empty() {
+            progress::log(format!(
+                "Left out {} file(s) modified less than {} ago, the next backup picks them up:",
+                too_recent.len(),
+                format_duration(options.min_age.unwrap_or_default())
+            ));
+            for rel_path in too_recent.iter() {
+                progress::log(format!("\t{}", rel_path.display()));
+            }
+        }
+
+        if complete {
+            // Folders with files kept by --delete-after must be diffed again next time, which the pessimistic DirDB does.
+            // So must folders with files left out by --min-age.
+            let kept_missing = missing.as_ref().map_or(0, MissingFiles::kept_count);
+            let new_dirdb = if kept_missing > 0 || !too_recent.is_empty() {
+                if kept_missing > 0 {
+                    progress::log(format!(
+                        "Kept {} file(s) deleted locally less than {} ago",
+                        kept_missing,
+                        format_duration(options.delete_after.unwrap_or_default())
+                    ));
+                }
+                dir_diff.pessimistic_dirdb()
+            } else {
+                local_dirdb.as_ref()
+            };
+            progress::log("Uploading new DirDB");
+            match &grafting {
+                Some((rel_dir, remote_full)) => {
+                    let new_dirdb = remote_full.grafted(rel_dir, backend.key(), new_dirdb.root.clone())?;
+                    remote_dirdb.save(backend.as_ref(), &new_dirdb).await?;
+                }
+                None => remote_dirdb.save(backend.as_ref(), new_dirdb).await?,
+            }
+            if let Some(missing) = &missing {
+                missing.save(backend.as_ref(), &root.path_hash).await?;
+            }
+        }
+
+        run.uploaded = num_upload_actions;
+        run.deleted = num_delete_actions;
+        run.errors = err_count;
+        run.finish();
+        audit.transferred = run.uploaded;
+        audit.deleted = run.deleted;
+        audit.errors = run.errors;
+        audit.error_messages = error_messages;
+        // The backup itself is done, failing to log it shouldn't fail it
+        if let Err(err) = history::record_run(backend.as_ref(), &root.path_hash, run).await {
+            progress::log(format!("Failed to save the backup history: {:#}", err));
+        }
+
+        if !complete {
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+        Ok(())
+    }
+}
+
+/// Opens every file of the scanned tree, to find unreadable files before the backup starts
+fn check_readable(base_path: &Path, stat: &DirStat, skipped: &mut Vec<SkippedFile>) -> Result<()> {
+    let direct_files = match &stat.direct_files {
+        Some(direct_files) => direct_files.load()?,
+        None => Default::default(),
+    };
+    for file in direct_files.iter() {
+        let path = base_path.join(&file.rel_path);
+        let is_symlink = fs::symlink_metadata(&path).map(|meta| meta.file_type().is_symlink());
+        let result = match is_symlink {
+            Ok(true) => fs::read_link(&path).map(|_| ()),
+            Ok(false) => fs::File::open(&path).map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            skipped.push(SkippedFile::new(
+                &file.rel_path,
+                format!("Failed to open file: {}", err),
+            ));
+        }
+    }
+    for subfolder in stat.subfolders.iter() {
+        check_readable(base_path, subfolder, skipped)?;
+    }
+    Ok(())
+}
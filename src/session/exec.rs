@@ -0,0 +1,154 @@
+use super::AuditedSession;
+use crate::config::Config;
+use crate::crypto;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::file::FileMeta;
+use crate::data::history::{self, BackupRun};
+use crate::data::root::BackupRoot;
+use crate::dirdb::{remote::RemoteDirDB, DirDB};
+use crate::net::backend::Backend;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress;
+use crate::stream::{CompressionStream, EncryptionStream};
+use eyre::{bail, eyre, Result, WrapErr};
+use futures::FutureExt;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+
+/// Backs up the output of a command (e.g. a database dump) as one file of a backup root
+///
+/// The dump is streamed as it's written, nothing is stored locally. It only becomes the latest version once the
+/// command exits successfully, if the command fails its output is discarded. The root must be locked, and only hold
+/// dumps: a backup of a folder into the same root would delete them.
+pub struct ExecSession {
+    config: Config,
+    backend: Arc<dyn Backend>,
+    root: Arc<BackupRoot>,
+    /// The path of the dump in the backup root
+    rel_path: PathBuf,
+    command: Vec<OsString>,
+}
+
+impl ExecSession {
+    pub fn new(
+        config: &Config,
+        backend: Arc<dyn Backend>,
+        root: Arc<BackupRoot>,
+        rel_path: PathBuf,
+        command: Vec<OsString>,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            backend,
+            root,
+            rel_path,
+            command,
+        }
+    }
+}
+
+impl AuditedSession for ExecSession {
+    const OPERATION: AuditOperation = AuditOperation::Backup;
+
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self {
+            config,
+            backend,
+            root,
+            rel_path,
+            command,
+        } = self;
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| eyre!("No command to back up the output of"))?;
+        let (_, full_path_hash) = root.file_path_hashes(&rel_path, backend.key())?;
+        let rate_limiter = RateLimiter::new(&config, &backend)?;
+        let mut run = BackupRun::start();
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to run {}", program.to_string_lossy()))?;
+        let stdout = child.stdout.take().unwrap();
+        let compressed_stream = CompressionStream::new(stdout, config.compression_level).await;
+        let content_hash = compressed_stream.content_hash();
+        let encrypted_stream = rate_limiter.throttle_upload(Box::new(EncryptionStream::new(
+            Box::new(compressed_stream),
+            backend.key(),
+        )));
+        let meta = FileMeta {
+            rel_path: rel_path.clone(),
+            last_modified: run.started,
+            mode: 0o600,
+            is_symlink: false,
+            birthtime: None,
+            content_hash,
+            original_size: None,
+        };
+        let enc_meta = crypto::encode_meta(backend.key(), &meta);
+
+        let mut command = RunningCommand(Some(child));
+        let mut exit_status = None;
+        // A dump cut short must not replace the last good one, the upload is only finished after a successful exit
+        let confirm = async {
+            let mut child = command.0.take().unwrap();
+            let status = tokio::task::spawn_blocking(move || child.wait()).await??;
+            exit_status = Some(status);
+            if !status.success() {
+                bail!(
+                    "{} failed ({}), its output was discarded",
+                    program.to_string_lossy(),
+                    status
+                );
+            }
+            Ok(())
+        };
+        let uploaded = backend
+            .upload_file_stream_confirmed(&full_path_hash, encrypted_stream, enc_meta, confirm.boxed())
+            .await;
+        audit.exit_code = exit_status.and_then(|status| status.code());
+        match uploaded {
+            Ok(_) => (),
+            Err(err) if matches!(exit_status, Some(status) if !status.success()) => return Err(err),
+            Err(err) => return Err(err.wrap_err(format!("Failed to upload the output as \"{}\"", rel_path.display()))),
+        }
+        progress::log(format!(
+            "{} succeeded, its output is saved as \"{}\"",
+            program.to_string_lossy(),
+            rel_path.display()
+        ));
+
+        // Keeps the counts of list and tree right, the DirDB can't be scanned from a local folder here
+        let files = root.list_remote_files(backend.as_ref()).await?;
+        let dirdb = DirDB::new_from_rel_paths(files.iter().map(|file| file.rel_path.as_path()), backend.key())?;
+        let mut remote_dirdb = RemoteDirDB::fetch(backend.as_ref(), &root.path_hash).await?;
+        remote_dirdb.save(backend.as_ref(), &dirdb).await?;
+
+        run.files_count = files.len() as u64;
+        run.total_size = files.iter().map(|file| file.size).sum();
+        run.uploaded = 1;
+        run.finish();
+        audit.transferred = 1;
+        if let Err(err) = history::record_run(backend.as_ref(), &root.path_hash, run).await {
+            progress::log(format!("Failed to save the backup history: {:#}", err));
+        }
+        Ok(())
+    }
+}
+
+/// Kills the command when dropped before it exited, e.g. if its output fails to upload
+struct RunningCommand(Option<Child>);
+
+impl Drop for RunningCommand {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use super::root_pair::RootPair;
+use super::AuditedSession;
+use crate::action;
+use crate::config::Config;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::root::BackupRoot;
+use crate::dirdb::{remote::RemoteDirDB, DirDB};
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{LogCategory, Progress, ProgressType};
+use eyre::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::SpawnExt;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Moves the files of a backup root into another, with server-side copies, then deletes them from the source
+///
+/// Where both roots have a file, the most recent one is kept. The source's files are only deleted once
+/// every copy succeeded, so an interrupted merge can be run again. Both roots must be locked, and in buckets
+/// of the same account. The source root itself is left in the list of roots for the caller to remove.
+/// The audit record of a run belongs in the trails of both roots: its copies went to the target, its deletions
+/// were in the source.
+pub struct MergeSession {
+    config: Config,
+    roots: RootPair,
+}
+
+impl MergeSession {
+    pub fn new(
+        config: &Config,
+        source_backend: Arc<dyn Backend>,
+        source: Arc<BackupRoot>,
+        target_backend: Arc<dyn Backend>,
+        target: Arc<BackupRoot>,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            roots: RootPair {
+                source_backend,
+                source,
+                target_backend,
+                target,
+            },
+        }
+    }
+}
+
+impl AuditedSession for MergeSession {
+    const OPERATION: AuditOperation = AuditOperation::Merge;
+
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self { config, roots } = self;
+
+        let progress = Progress::new(config.verbosity);
+        let (source_files, target_files) = roots.list_files(&progress).await?;
+
+        let copy_rate_limiter = Arc::new(RateLimiter::new(&config, &roots.target_backend)?);
+        let copy_progress = progress.get_progress_handler(ProgressType::Copy);
+        let copy_futs = FuturesUnordered::new();
+        let newer_files = source_files
+            .iter()
+            .filter(|file| match target_files.get(&file.rel_path) {
+                Some(existing) if existing.last_modified >= file.last_modified => {
+                    if copy_progress.logs(LogCategory::Diff) {
+                        copy_progress.println(format!(
+                            "Not copying {}, the target's is at least as recent",
+                            file.rel_path.display()
+                        ));
+                    }
+                    false
+                }
+                _ => true,
+            });
+        let num_copy_actions = roots.spawn_copies(newer_files, &copy_rate_limiter, copy_progress, &copy_futs)?;
+        let copy_progress = progress.show_progress_bar(ProgressType::Copy, num_copy_actions);
+        copy_futs.for_each(|()| futures::future::ready(())).await;
+        copy_progress.finish();
+        audit.transferred = num_copy_actions;
+        if !progress.is_complete() {
+            let err_count = progress.errors_count();
+            audit.errors = err_count;
+            audit.error_messages = progress.errors();
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+
+        // The merged folders changed on both sides, so the next backup diffs them again
+        let rel_paths: BTreeSet<_> = target_files
+            .keys()
+            .chain(source_files.iter().map(|file| &file.rel_path))
+            .collect();
+        let dirdb = DirDB::new_from_rel_paths(
+            rel_paths.into_iter().map(|path| path.as_path()),
+            roots.target_backend.key(),
+        )?;
+        roots.save_target_dirdb(&dirdb).await?;
+
+        RemoteDirDB::hide(roots.source_backend.as_ref(), &roots.source.path_hash).await?;
+        let delete_rate_limiter = Arc::new(RateLimiter::new(&config, &roots.source_backend)?);
+        let delete_progress = progress.show_progress_bar(ProgressType::Delete, source_files.len());
+        let delete_futs = FuturesUnordered::new();
+        for file in source_files.iter() {
+            delete_futs.spawn(action::delete(
+                delete_rate_limiter.clone(),
+                delete_progress.clone(),
+                file.clone(),
+            ))?;
+        }
+        delete_futs.for_each(|()| futures::future::ready(())).await;
+        delete_progress.finish();
+        let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+        audit.deleted = source_files.len() - err_count.min(source_files.len());
+        audit.errors = err_count;
+        audit.error_messages = progress.errors();
+        drop(progress);
+
+        if !complete {
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+        Ok(())
+    }
+}
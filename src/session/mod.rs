@@ -0,0 +1,37 @@
+use crate::data::audit::{AuditOperation, AuditRecord};
+use eyre::Result;
+use std::future::Future;
+
+mod backup;
+pub use backup::*;
+
+mod restore;
+pub use restore::*;
+
+mod exec;
+pub use exec::*;
+
+mod root_pair;
+
+mod sync;
+pub use sync::*;
+
+mod merge;
+pub use merge::*;
+
+mod tar_restore;
+pub use tar_restore::*;
+
+/// A session whose runs go in the audit trail of their root (see `data::audit`)
+pub trait AuditedSession: Sized {
+    /// What a run is recorded as, unless the caller starts its own record
+    const OPERATION: AuditOperation;
+
+    /// Runs the session, filling in the counts of `audit`
+    fn run_audited(self, audit: &mut AuditRecord) -> impl Future<Output = Result<()>>;
+
+    /// Runs the session without keeping its audit record
+    fn run(self) -> impl Future<Output = Result<()>> {
+        async move { self.run_audited(&mut AuditRecord::start(Self::OPERATION)).await }
+    }
+}
@@ -0,0 +1,470 @@
+use super::AuditedSession;
+use crate::action;
+use crate::config::Config;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::checkpoint::RestoreCheckpoint;
+use crate::data::file::RemoteFile;
+use crate::data::fsync::FsyncQueue;
+use crate::data::paths::{
+    check_dirs_under, check_path_len, check_rel_path, create_dir_all_under, glob_match, path_from_bytes,
+};
+use crate::data::relocation::Relocation;
+use crate::data::root::BackupRoot;
+use crate::dirdb::dirstat::DirStat;
+use crate::dirdb::spill::DirectFiles;
+use crate::dirdb::{
+    diff::{DiffStrategy, DirDiff, FileDiff},
+    remote::RemoteDirDB,
+    DirDB,
+};
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{self, LogCategory, Progress, ProgressHandler, ProgressType};
+use eyre::{bail, Result};
+use fs_set_times::{set_times, SystemTimeSpec};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::SpawnExt;
+use std::collections::{HashMap, HashSet};
+use std::ops::Add;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::spawn_blocking;
+
+/// Options that change how a single restore behaves
+#[derive(Clone, Default)]
+pub struct RestoreOptions {
+    /// Where files are written in the target, instead of their path in the backup
+    pub relocation: Relocation,
+    /// Files matching these globs (or inside a folder that matches) are downloaded before the others,
+    /// see `is_restored_first`
+    pub first: Vec<String>,
+    /// Which local files are replaced by their backed up version
+    pub overwrite: OverwritePolicy,
+}
+
+/// What a restore does with local files that differ from their backed up version
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Only restores missing files
+    Never,
+    /// Replaces files older than their backed up version, newer files are kept
+    #[default]
+    Older,
+    /// Replaces every file that differs, rolling back local changes
+    Always,
+}
+
+impl OverwritePolicy {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "never" => Ok(OverwritePolicy::Never),
+            "older" => Ok(OverwritePolicy::Older),
+            "always" => Ok(OverwritePolicy::Always),
+            _ => bail!("Invalid overwrite policy \"{}\" (use never, older or always)", name),
+        }
+    }
+}
+
+/// Restores a backup root into a local folder
+///
+/// The root must already be locked (see `data::root::open_root`), the session does not unlock it.
+/// Local files that are at least as recent as their backed up version are left alone (see `OverwritePolicy`),
+/// unless the files are relocated, then everything is downloaded since the target doesn't mirror the backup.
+/// Files restored by an interrupted run are recorded in a checkpoint in the target, and not downloaded again.
+/// Once the files are downloaded, a metadata pass creates the empty folders and sets the mtime of restored folders.
+pub struct RestoreSession {
+    config: Config,
+    backend: Arc<dyn Backend>,
+    root: Arc<BackupRoot>,
+    target: PathBuf,
+    options: RestoreOptions,
+}
+
+impl RestoreSession {
+    pub fn new(
+        config: &Config,
+        backend: Arc<dyn Backend>,
+        root: Arc<BackupRoot>,
+        target: PathBuf,
+        options: RestoreOptions,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            backend,
+            root,
+            target,
+            options,
+        }
+    }
+}
+
+impl AuditedSession for RestoreSession {
+    const OPERATION: AuditOperation = AuditOperation::Restore;
+
+    /// Diffs the target folder with the remote and downloads missing or outdated files
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self {
+            config,
+            backend,
+            root,
+            target,
+            options,
+        } = self;
+        let relocation = Arc::new(options.relocation);
+        let checkpoint = Arc::new(RestoreCheckpoint::open(&target, &root.path_hash)?);
+        let fsync = Arc::new(FsyncQueue::new(config.restore_fsync));
+        if !checkpoint.is_empty() {
+            progress::log(format!(
+                "Resuming interrupted restore, {} files were already restored",
+                checkpoint.len()
+            ));
+        }
+
+        progress::log("Starting diff");
+        let progress = Progress::new(config.verbosity);
+        let diff_progress = progress.show_progress_bar(ProgressType::Diff, 3);
+        let download_progress = progress.get_progress_handler(ProgressType::Download);
+
+        let backend = backend.with_progress(diff_progress.clone());
+
+        let target_dirdb = if relocation.is_identity() {
+            Arc::new(DirDB::new_from_local(&target, backend.key())?)
+        } else {
+            // Diff against an empty folder, so every file is downloaded
+            let mut empty = DirDB::new_empty();
+            empty.root.direct_files = Some(DirectFiles::Loaded(Vec::new()));
+            Arc::new(empty)
+        };
+        diff_progress.report_success();
+
+        let dirdb_progress = progress.show_bytes_bar(ProgressType::DirDB);
+        let mut remote_dirdb =
+            RemoteDirDB::fetch_with_progress(backend.as_ref(), &root.path_hash, Some(dirdb_progress.clone())).await?;
+        // Folders with the same content in the target don't need their shards, not even for empty folders
+        remote_dirdb.load_shards(backend.as_ref(), &target_dirdb.root).await;
+        let remote_dirdb = remote_dirdb.dirdb;
+        dirdb_progress.finish();
+        diff_progress.report_success();
+
+        let rate_limiter = Arc::new(RateLimiter::new(&config, &backend)?);
+        let mut dir_diff = DirDiff::new(
+            root.clone(),
+            rate_limiter.clone(),
+            target_dirdb.clone(),
+            &remote_dirdb,
+            DiffStrategy::Auto,
+        )?;
+        let target = Arc::new(target);
+        if diff_progress.logs(LogCategory::Diff) {
+            for listing in dir_diff.listings() {
+                diff_progress.println(listing.to_string());
+            }
+        }
+
+        diff_progress.println("Starting download");
+        // Lets us wait for all backup actions to complete
+        let action_futs = FuturesUnordered::new();
+
+        let mut num_download_actions = 0;
+        // With priorities, only the first files are downloaded during the diff, the rest is queued behind them
+        let mut deferred: Vec<(Arc<PathBuf>, RemoteFile)> = Vec::new();
+        let mut folder_mtimes = FolderMtimes::default();
+        while let Some(item) = dir_diff.next().await {
+            let item = item?;
+
+            match item {
+                FileDiff {
+                    local,
+                    remote: Some(mut rfile),
+                } => {
+                    // Checked before anything uses the path, folder mtimes included, see `action::download`
+                    if let Err(err) = check_rel_path(&rfile.rel_path) {
+                        download_progress.report_error(format!("Refusing to restore a file of the backup: {}", err));
+                        continue;
+                    }
+                    if let Some(lfile) = local {
+                        let kept = match options.overwrite {
+                            OverwritePolicy::Never => true,
+                            OverwritePolicy::Older => lfile.last_modified >= rfile.last_modified,
+                            OverwritePolicy::Always => false,
+                        };
+                        if kept {
+                            folder_mtimes.record(&rfile.rel_path, lfile.last_modified);
+                            continue;
+                        }
+                    }
+                    // Priorities are about paths in the backup, not where they're restored
+                    let first = is_restored_first(&options.first, &rfile.rel_path);
+                    let (base, rest) = match relocation.split(&rfile.rel_path) {
+                        Some(split) => split,
+                        None => {
+                            diff_progress.println(format!(
+                                "Not restoring \"{}\", nothing is left of its path after --strip-prefix",
+                                rfile.rel_path.display()
+                            ));
+                            continue;
+                        }
+                    };
+                    let relocated = base.join(&rest);
+                    folder_mtimes.record(&relocated, rfile.last_modified);
+                    folder_mtimes.mark_restored(&relocated);
+                    if checkpoint.is_done(&rfile.id) && target.join(&relocated).symlink_metadata().is_ok() {
+                        continue;
+                    }
+                    num_download_actions += 1;
+                    // Only the part of the path from the backup is restored inside the target, see `create_dir_all_under`
+                    let file_target = if base.as_os_str().is_empty() {
+                        target.clone()
+                    } else {
+                        Arc::new(target.join(base))
+                    };
+                    rfile.rel_path = rest;
+                    if !first {
+                        deferred.push((file_target, rfile));
+                        continue;
+                    }
+                    action_futs.spawn(action::download(
+                        rate_limiter.clone(),
+                        download_progress.clone(),
+                        file_target,
+                        checkpoint.clone(),
+                        fsync.clone(),
+                        rfile,
+                    ))?;
+                }
+                FileDiff {
+                    local: Some(_),
+                    remote: None,
+                } => (),
+                FileDiff {
+                    local: None,
+                    remote: None,
+                } => unreachable!(),
+            }
+        }
+
+        for (file_target, rfile) in deferred {
+            action_futs.spawn(action::download(
+                rate_limiter.clone(),
+                download_progress.clone(),
+                file_target,
+                checkpoint.clone(),
+                fsync.clone(),
+                rfile,
+            ))?;
+        }
+
+        let download_progress = progress.show_progress_bar(ProgressType::Download, num_download_actions);
+        diff_progress.println(dir_diff.stats().to_string());
+        diff_progress.report_success();
+        diff_progress.finish();
+
+        action_futs.for_each(|()| futures::future::ready(())).await;
+        if let Err(err) = fsync.flush(&checkpoint).await {
+            download_progress.report_error(format!("Failed to sync the restored files: {}", err));
+        }
+        download_progress.finish();
+
+        let empty_folders = remote_dirdb
+            .map(|dirdb| empty_folders(dirdb.root, &relocation))
+            .unwrap_or_default();
+        let folder_mtimes = folder_mtimes.into_restored();
+        let folders_progress =
+            progress.show_progress_bar(ProgressType::Folders, empty_folders.len() + folder_mtimes.len());
+        let threads = config.download_threads as usize;
+        // Creating a folder changes the mtime of its parent, so they're all created before setting any mtime
+        for_each_blocking(empty_folders, threads, &folders_progress, {
+            let target = target.clone();
+            move |(base, rest): (PathBuf, PathBuf)| {
+                let base_path = target.join(base);
+                let dir_path = base_path.join(&rest);
+                check_path_len(&dir_path)
+                    .and_then(|()| create_dir_all_under(&base_path, &rest))
+                    .map_err(|err| format!("Failed to create empty folder \"{}\": {}", dir_path.display(), err))
+            }
+        })
+        .await?;
+        for_each_blocking(folder_mtimes, threads, &folders_progress, {
+            let target = target.clone();
+            move |(rel_path, mtime)| {
+                let dir_path = target.join(&rel_path);
+                let mtime = SystemTime::UNIX_EPOCH.add(Duration::from_secs(mtime));
+                // A folder that became a symlink since its files were restored would have the mtime of its target set.
+                // Folders moved out of the target with `--relocate` were chosen by the user, they aren't checked.
+                let from_backup = rel_path.components().all(|part| matches!(part, Component::Normal(_)));
+                let checked = match from_backup {
+                    true => check_dirs_under(&target, &rel_path).map_err(|err| err.to_string()),
+                    false => Ok(()),
+                };
+                checked
+                    .and_then(|()| {
+                        set_times(&dir_path, None, Some(SystemTimeSpec::Absolute(mtime))).map_err(|err| err.to_string())
+                    })
+                    .map_err(|err| format!("Failed to set mtime of folder \"{}\": {}", dir_path.display(), err))
+            }
+        })
+        .await?;
+        folders_progress.finish();
+        let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+        let error_messages = progress.errors();
+        drop(progress);
+        audit.transferred = num_download_actions;
+        audit.errors = err_count;
+        audit.error_messages = error_messages;
+
+        if !complete {
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+        Arc::try_unwrap(checkpoint)
+            .unwrap_or_else(|_| unreachable!("Downloads are done with the checkpoint"))
+            .remove()
+    }
+}
+
+/// Whether a file of the backup is restored before the others, with `RestoreOptions::first`.
+/// Without any pattern everything is restored in the order of the diff.
+fn is_restored_first(patterns: &[String], rel_path: &Path) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim_matches('/');
+        rel_path
+            .ancestors()
+            .take_while(|path| !path.as_os_str().is_empty())
+            .any(|path| glob_match(pattern, &path.to_string_lossy()))
+    })
+}
+
+/// The newest mtime of the files under each folder, for the folders that files are restored into.
+/// Folder permissions and times aren't in the backup, but writing files changes the folder mtimes,
+/// so restored folders get the mtime of their newest file instead of the time of the restore.
+/// Folders restored outside the target (moved to an absolute path) are left alone.
+#[derive(Default)]
+struct FolderMtimes {
+    newest: HashMap<PathBuf, u64>,
+    restored: HashSet<PathBuf>,
+}
+
+impl FolderMtimes {
+    /// Records the mtime of a file of the backup, restored or already up to date
+    fn record(&mut self, rel_path: &Path, mtime: u64) {
+        for dir in Self::folders_of(rel_path) {
+            let newest = self.newest.entry(dir.to_owned()).or_insert(0);
+            if *newest >= mtime {
+                // The parents are always at least as new as their subfolders
+                break;
+            }
+            *newest = mtime;
+        }
+    }
+
+    /// Records that a file is restored, so its folders will be changed by the restore
+    fn mark_restored(&mut self, rel_path: &Path) {
+        for dir in Self::folders_of(rel_path) {
+            if !self.restored.insert(dir.to_owned()) {
+                break;
+            }
+        }
+    }
+
+    fn folders_of(rel_path: &Path) -> impl Iterator<Item = &Path> {
+        rel_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.is_absolute())
+    }
+
+    /// The mtimes of the folders that files were restored into
+    fn into_restored(self) -> Vec<(PathBuf, u64)> {
+        let Self { newest, restored } = self;
+        newest.into_iter().filter(|(dir, _)| restored.contains(dir)).collect()
+    }
+}
+
+/// Runs `f` on every item, spread over up to `threads` blocking tasks, and reports each result to `progress`
+async fn for_each_blocking<T, F>(items: Vec<T>, threads: usize, progress: &ProgressHandler, f: F) -> Result<()>
+where
+    T: Send + 'static,
+    F: Fn(T) -> std::result::Result<(), String> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let chunk_len = items.len().div_ceil(threads.max(1)).max(1);
+    let mut items = items.into_iter();
+    let mut tasks = Vec::new();
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(chunk_len).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let (f, progress) = (f.clone(), progress.clone());
+        tasks.push(spawn_blocking(move || {
+            for item in chunk {
+                match f(item) {
+                    Ok(()) => progress.report_success(),
+                    Err(err) => progress.report_error(err),
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await?;
+    }
+    Ok(())
+}
+
+/// Lists where the empty folders of the tree are restored, split like `Relocation::split`.
+/// Iterative since the tree can be arbitrarily deep.
+fn empty_folders(root: DirStat, relocation: &Relocation) -> Vec<(PathBuf, PathBuf)> {
+    let mut empty_folders = Vec::new();
+    // Note how the root folder doesn't have a folder name, it's just the relative root "/"
+    let mut stack: Vec<_> = root.subfolders.into_iter().map(|dir| (dir, PathBuf::new())).collect();
+    while let Some((dir, parent_rel_path)) = stack.pop() {
+        let dir_rel_path = match dir.dir_name {
+            Some(dir_name) => parent_rel_path.join(path_from_bytes(&dir_name).unwrap()),
+            None => continue,
+        };
+
+        if let (0, Some(relocated)) = (dir.total_files_count, relocation.split(&dir_rel_path)) {
+            empty_folders.push(relocated);
+        }
+
+        stack.extend(dir.subfolders.into_iter().map(|sub| (sub, dir_rel_path.clone())));
+    }
+    empty_folders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_priorities() {
+        let first = vec!["etc/".to_owned(), "/var/lib/db".to_owned(), "*.key".to_owned()];
+        assert!(is_restored_first(&first, Path::new("etc/fstab")));
+        assert!(is_restored_first(&first, Path::new("var/lib/db/data/1")));
+        assert!(is_restored_first(&first, Path::new("home/me/ssh.key")));
+        assert!(!is_restored_first(&first, Path::new("etcetera/a")));
+        assert!(!is_restored_first(&first, Path::new("var/lib/other")));
+        assert!(is_restored_first(&[], Path::new("anything")));
+    }
+
+    #[test]
+    fn folder_mtimes_of_restored_files() {
+        let mut mtimes = FolderMtimes::default();
+        mtimes.record(Path::new("a/b/old"), 10);
+        mtimes.mark_restored(Path::new("a/b/old"));
+        mtimes.record(Path::new("a/new"), 20);
+        mtimes.record(Path::new("c/up_to_date"), 30);
+        mtimes.record(Path::new("top"), 40);
+        mtimes.mark_restored(Path::new("top"));
+        mtimes.record(Path::new("/elsewhere/moved"), 50);
+        mtimes.mark_restored(Path::new("/elsewhere/moved"));
+
+        let mut restored = mtimes.into_restored();
+        restored.sort();
+        assert_eq!(restored, [(PathBuf::from("a"), 20), (PathBuf::from("a/b"), 10)]);
+    }
+}
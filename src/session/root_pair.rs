@@ -0,0 +1,76 @@
+//! What `SyncSession` and `MergeSession` share: both copy the files of a root into another with server-side copies
+
+use crate::action;
+use crate::data::file::RemoteFile;
+use crate::data::root::BackupRoot;
+use crate::dirdb::{remote::RemoteDirDB, DirDB};
+use crate::net::backend::Backend;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{Progress, ProgressHandler, ProgressType};
+use eyre::Result;
+use futures::future::FutureObj;
+use futures::stream::FuturesUnordered;
+use futures::task::SpawnExt;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The root whose files are copied, and the root they're copied into. Both must be locked.
+pub struct RootPair {
+    pub source_backend: Arc<dyn Backend>,
+    pub source: Arc<BackupRoot>,
+    pub target_backend: Arc<dyn Backend>,
+    pub target: Arc<BackupRoot>,
+}
+
+impl RootPair {
+    /// Lists the files of the source, and those of the target by path.
+    /// The target's DirDB is hidden first, it doesn't match its files until `save_target_dirdb`.
+    pub async fn list_files(&self, progress: &Progress) -> Result<(Vec<RemoteFile>, HashMap<PathBuf, RemoteFile>)> {
+        let diff_progress = progress.show_progress_bar(ProgressType::Diff, 3);
+        RemoteDirDB::hide(self.target_backend.as_ref(), &self.target.path_hash).await?;
+        diff_progress.report_success();
+
+        let source_files = self.source.list_remote_files(self.source_backend.as_ref()).await?;
+        diff_progress.report_success();
+        let target_files = self
+            .target
+            .list_remote_files(self.target_backend.as_ref())
+            .await?
+            .into_iter()
+            .map(|file| (file.rel_path.clone(), file))
+            .collect();
+        diff_progress.report_success();
+        diff_progress.finish();
+        Ok((source_files, target_files))
+    }
+
+    /// Spawns a copy of each file of the source into the target, returns how many
+    pub fn spawn_copies<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a RemoteFile>,
+        rate_limiter: &Arc<RateLimiter>,
+        progress: &ProgressHandler,
+        futs: &FuturesUnordered<FutureObj<'static, ()>>,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for file in files {
+            let (_, filename) = self
+                .target
+                .file_path_hashes(&file.rel_path, self.target_backend.key())?;
+            futs.spawn(action::copy(
+                rate_limiter.clone(),
+                progress.clone(),
+                file.clone(),
+                filename,
+            ))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub async fn save_target_dirdb(&self, dirdb: &DirDB) -> Result<()> {
+        let mut target_dirdb = RemoteDirDB::fetch(self.target_backend.as_ref(), &self.target.path_hash).await?;
+        target_dirdb.save(self.target_backend.as_ref(), dirdb).await
+    }
+}
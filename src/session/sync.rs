@@ -0,0 +1,102 @@
+use super::root_pair::RootPair;
+use super::AuditedSession;
+use crate::action;
+use crate::config::Config;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::root::BackupRoot;
+use crate::dirdb::{remote::RemoteDirDB, DirDB};
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{self, Progress, ProgressType};
+use eyre::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::SpawnExt;
+use std::sync::Arc;
+
+/// Makes a backup root mirror another, with server-side copies so that no file data goes through this machine
+///
+/// Both roots must be locked, and in buckets of the same account. Files of the target that are already the same
+/// as in the source are kept, the rest of the target is deleted.
+pub struct SyncSession {
+    config: Config,
+    roots: RootPair,
+}
+
+impl SyncSession {
+    pub fn new(
+        config: &Config,
+        source_backend: Arc<dyn Backend>,
+        source: Arc<BackupRoot>,
+        target_backend: Arc<dyn Backend>,
+        target: Arc<BackupRoot>,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            roots: RootPair {
+                source_backend,
+                source,
+                target_backend,
+                target,
+            },
+        }
+    }
+}
+
+impl AuditedSession for SyncSession {
+    const OPERATION: AuditOperation = AuditOperation::Sync;
+
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self { config, roots } = self;
+
+        let progress = Progress::new(config.verbosity);
+        let (source_files, mut target_files) = roots.list_files(&progress).await?;
+
+        let rate_limiter = Arc::new(RateLimiter::new(&config, &roots.target_backend)?);
+        let copy_progress = progress.get_progress_handler(ProgressType::Copy);
+        let delete_progress = progress.get_progress_handler(ProgressType::Delete);
+        let action_futs = FuturesUnordered::new();
+        let changed_files = source_files.iter().filter(|file| {
+            !matches!(target_files.remove(&file.rel_path), Some(existing)
+                if existing.last_modified == file.last_modified
+                    && existing.size == file.size
+                    && existing.content_hash == file.content_hash)
+        });
+        let num_copy_actions = roots.spawn_copies(changed_files, &rate_limiter, copy_progress, &action_futs)?;
+        let num_delete_actions = target_files.len();
+        for (_, file) in target_files {
+            action_futs.spawn(action::delete(rate_limiter.clone(), delete_progress.clone(), file))?;
+        }
+
+        let copy_progress = progress.show_progress_bar(ProgressType::Copy, num_copy_actions);
+        let delete_progress = progress.show_progress_bar(ProgressType::Delete, num_delete_actions);
+        action_futs.for_each(|()| futures::future::ready(())).await;
+        copy_progress.finish();
+        delete_progress.finish();
+        let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+        audit.error_messages = progress.errors();
+        drop(progress);
+
+        audit.transferred = num_copy_actions;
+        audit.deleted = num_delete_actions;
+        audit.errors = err_count;
+        if !complete {
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+
+        progress::log("Copying DirDB");
+        let mut source_dirdb = RemoteDirDB::fetch(roots.source_backend.as_ref(), &roots.source.path_hash).await?;
+        source_dirdb
+            .load_shards_of_subtree(roots.source_backend.as_ref(), &[])
+            .await;
+        let dirdb = match source_dirdb.dirdb {
+            Some(dirdb) => dirdb,
+            // Without the source's DirDB, the next backup into the target diffs against its files instead
+            None => DirDB::new_from_rel_paths(
+                source_files.iter().map(|file| file.rel_path.as_path()),
+                roots.target_backend.key(),
+            )?,
+        };
+        roots.save_target_dirdb(&dirdb).await
+    }
+}
@@ -0,0 +1,397 @@
+use super::AuditedSession;
+use crate::config::Config;
+use crate::data::audit::{AuditOperation, AuditRecord};
+use crate::data::file::RemoteFile;
+use crate::data::relocation::Relocation;
+use crate::data::root::BackupRoot;
+use crate::data::tar::{self, EntryKind, TarEntry};
+use crate::net::backend::Backend;
+use crate::net::cap;
+use crate::net::rate_limiter::RateLimiter;
+use crate::progress::{LogCategory, Progress, ProgressHandler, ProgressType};
+use crate::stream::{DecompressedStream, DecryptionStream};
+use bytes::Bytes;
+use eyre::{bail, Result, WrapErr};
+use futures::StreamExt;
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+/// Max chunks waiting for the archive's writer thread, after which the entries wait for the output
+const ARCHIVE_CHUNK_COUNT: usize = 4;
+
+/// Restores a backup root as a tar archive written to an output, e.g. stdout, without touching the local disk
+///
+/// The root must already be locked, the session does not unlock it. Entries are written one at a time in the order
+/// of their paths, since a tar entry can't be interleaved with others, but up to `download_threads` files are
+/// downloaded ahead of their turn. Files whose restored size isn't in their metadata (backed up by older versions)
+/// are decompressed in memory first, since the size comes before the content.
+/// Empty folders and folder mtimes aren't in the archive, `tar -x` creates the folders of the files.
+///
+/// A file that fails to download fails the run, and the archive is left without its end, so that `tar -x` doesn't
+/// take it for a complete one.
+pub struct TarRestoreSession {
+    config: Config,
+    backend: Arc<dyn Backend>,
+    root: Arc<BackupRoot>,
+    output: Box<dyn Write + Send>,
+    relocation: Relocation,
+}
+
+/// The archive, written by a blocking thread so that a slow output never blocks the runtime
+struct Archive {
+    sender: mpsc::Sender<Bytes>,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+/// A file downloaded ahead of its turn in the archive, see `fetch`
+struct Prefetch {
+    /// Dropped without a message if the file isn't downloaded, because a daily cap is reached
+    started: oneshot::Receiver<()>,
+    content: mpsc::Receiver<Result<Bytes>>,
+    task: JoinHandle<()>,
+}
+
+impl TarRestoreSession {
+    pub fn new(
+        config: &Config,
+        backend: Arc<dyn Backend>,
+        root: Arc<BackupRoot>,
+        output: Box<dyn Write + Send>,
+        relocation: Relocation,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            backend,
+            root,
+            output,
+            relocation,
+        }
+    }
+}
+
+impl AuditedSession for TarRestoreSession {
+    const OPERATION: AuditOperation = AuditOperation::Restore;
+
+    async fn run_audited(self, audit: &mut AuditRecord) -> Result<()> {
+        let Self {
+            config,
+            backend,
+            root,
+            output,
+            relocation,
+        } = self;
+
+        let progress = Progress::new(config.verbosity);
+        let diff_progress = progress.show_progress_bar(ProgressType::Diff, 1);
+        let mut files = Vec::new();
+        for file in root.list_remote_files(backend.as_ref()).await? {
+            match archive_path(&relocation, &file.rel_path) {
+                Ok(Some(path)) => files.push((path, file)),
+                Ok(None) => {}
+                Err(err) => diff_progress.report_error(format!("Refusing to archive a file of the backup: {}", err)),
+            }
+        }
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        diff_progress.report_success();
+        diff_progress.finish();
+
+        let download_progress = progress.show_progress_bar(ProgressType::Download, files.len());
+        let backend = backend.with_progress(download_progress.clone());
+        let rate_limiter = Arc::new(RateLimiter::new(&config, &backend)?);
+        let mut archive = Archive::new(output);
+
+        let lookahead = (config.download_threads as usize).max(1);
+        let mut prefetches = VecDeque::with_capacity(lookahead);
+        let mut turn = None;
+        let mut to_fetch = files.iter();
+        let mut written = 0;
+        for (path, file) in files.iter() {
+            while prefetches.len() < lookahead {
+                let (_, next) = match to_fetch.next() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let (next_turn, next_turn_recv) = oneshot::channel();
+                let prefetch = Prefetch::spawn(
+                    rate_limiter.clone(),
+                    download_progress.clone(),
+                    next.clone(),
+                    turn.replace(next_turn_recv),
+                    next_turn,
+                );
+                prefetches.push_back(prefetch);
+            }
+            let mut prefetch = prefetches.pop_front().unwrap();
+            // Nothing new is started once a daily cap is reached, the archive ends with the files done so far
+            if (&mut prefetch.started).await.is_err() {
+                break;
+            }
+            write_entry(&mut archive, &download_progress, path, file, &mut prefetch.content).await?;
+            written += 1;
+        }
+        drop(prefetches);
+
+        let mut end = Vec::new();
+        tar::write_end(&mut end)?;
+        archive
+            .write(end.into())
+            .await
+            .wrap_err("Failed to write the archive")?;
+        archive.finish().await.wrap_err("Failed to write the archive")?;
+
+        download_progress.finish();
+        let (complete, err_count) = (progress.is_complete(), progress.errors_count());
+        audit.transferred = written;
+        audit.errors = err_count;
+        audit.error_messages = progress.errors();
+        drop(progress);
+
+        if !complete {
+            return Err(cap::incomplete_failure(err_count).into());
+        }
+        Ok(())
+    }
+}
+
+/// Where a file goes in the archive, None if nothing is left of its path after stripping its prefix.
+/// Archives only hold relative paths, so absolute relocations lose their root.
+fn archive_path(relocation: &Relocation, rel_path: &Path) -> Result<Option<PathBuf>> {
+    let path = match relocation.apply(rel_path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    // `tar -x` would write the file outside of the folder it extracts to
+    if path.components().any(|comp| comp == Component::ParentDir) {
+        bail!("\"{}\" would be archived as \"{}\"", rel_path.display(), path.display());
+    }
+    Ok(Some(
+        path.components()
+            .filter(|comp| matches!(comp, Component::Normal(_)))
+            .collect(),
+    ))
+}
+
+/// Adds the entry of a file to the archive, as its content arrives.
+/// Failing to download the file is an error, the entry can't be taken back from the archive.
+async fn write_entry(
+    archive: &mut Archive,
+    progress: &ProgressHandler,
+    path: &Path,
+    file: &RemoteFile,
+    content: &mut mpsc::Receiver<Result<Bytes>>,
+) -> Result<()> {
+    let failed = || format!("Failed to restore \"{}\"", file.rel_path.display());
+    let size = match file.original_size {
+        Some(size) if !file.is_symlink => size,
+        _ => {
+            // Symlinks are tiny, and the other files without a known size have to be counted before they're written
+            let mut data = Vec::new();
+            while let Some(chunk) = content.recv().await {
+                data.extend_from_slice(&chunk.wrap_err_with(failed)?);
+            }
+            let mut header = Vec::new();
+            if file.is_symlink {
+                tar::write_header(&mut header, &entry(path, file, 0, EntryKind::Symlink(&data)))?;
+                archive.write(header.into()).await?;
+            } else {
+                let size = data.len() as u64;
+                tar::write_header(&mut header, &entry(path, file, size, EntryKind::File))?;
+                archive.write(header.into()).await?;
+                archive.write(data.into()).await?;
+                archive.write_padding(size).await?;
+            }
+            progress.report_success();
+            return Ok(());
+        }
+    };
+
+    let mut header = Vec::new();
+    tar::write_header(&mut header, &entry(path, file, size, EntryKind::File))?;
+    archive.write(header.into()).await?;
+    let mut remaining = size;
+    let mut overflow = 0;
+    while let Some(chunk) = content.recv().await {
+        let mut chunk = chunk.wrap_err_with(failed)?;
+        if chunk.len() as u64 > remaining {
+            overflow += chunk.len() as u64 - remaining;
+            chunk.truncate(remaining as usize);
+        }
+        remaining -= chunk.len() as u64;
+        if !chunk.is_empty() {
+            archive.write(chunk).await?;
+        }
+    }
+    if remaining != 0 {
+        bail!(
+            "Failed to restore \"{}\": {} bytes shorter than its size",
+            file.rel_path.display(),
+            remaining
+        );
+    }
+    archive.write_padding(size).await?;
+    if overflow != 0 {
+        progress.report_error(format!(
+            "\"{}\" is {} bytes longer than when it was backed up, the rest was cut off",
+            file.rel_path.display(),
+            overflow
+        ));
+    } else {
+        progress.report_success();
+    }
+    Ok(())
+}
+
+/// Downloads a file for its entry, once the file before it took its permit. Permits are taken in the order of the
+/// archive, so the files downloaded ahead never hold the permit that the next entry waits for.
+async fn fetch(
+    rate_limiter: Arc<RateLimiter>,
+    progress: ProgressHandler,
+    file: RemoteFile,
+    turn: Option<oneshot::Receiver<()>>,
+    next_turn: oneshot::Sender<()>,
+    started: oneshot::Sender<()>,
+    content: mpsc::Sender<Result<Bytes>>,
+) {
+    if let Some(turn) = turn {
+        let _ = turn.await;
+    }
+    let _permit_guard = rate_limiter.borrow_download_permit().await;
+    drop(next_turn);
+    if cap::is_reached() {
+        return;
+    }
+    let _ = started.send(());
+    let backend = rate_limiter.backend();
+    if progress.logs(LogCategory::Transfers) {
+        progress.println(format!("Downloading {}", file.rel_path.display()));
+    }
+    let encrypted = match backend.download_file_stream(&file.full_path_hash).await {
+        Ok(encrypted) => encrypted,
+        Err(err) => {
+            let _ = content.send(Err(err.wrap_err("Failed to download it"))).await;
+            return;
+        }
+    };
+    let decrypted_stream = DecryptionStream::new(rate_limiter.throttle_download(encrypted), backend.key());
+    let mut decompressed_stream = DecompressedStream::new(Box::new(decrypted_stream)).await;
+    while let Some(chunk) = decompressed_stream.next().await {
+        let failed = chunk.is_err();
+        if content.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn entry<'a>(path: &'a Path, file: &RemoteFile, size: u64, kind: EntryKind<'a>) -> TarEntry<'a> {
+    TarEntry {
+        path: path.as_os_str().as_bytes(),
+        mode: file.mode,
+        mtime: file.last_modified,
+        size,
+        kind,
+    }
+}
+
+impl Archive {
+    fn new(output: Box<dyn Write + Send>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(ARCHIVE_CHUNK_COUNT);
+        let writer = spawn_blocking(move || {
+            let mut output = BufWriter::new(output);
+            while let Some(chunk) = receiver.blocking_recv() {
+                output.write_all(&chunk)?;
+            }
+            output.flush()
+        });
+        Self { sender, writer }
+    }
+
+    async fn write(&mut self, data: Bytes) -> io::Result<()> {
+        if self.sender.send(data).await.is_ok() {
+            return Ok(());
+        }
+        // The writer thread only stops early when the output fails
+        (&mut self.writer).await??;
+        Err(io::Error::new(ErrorKind::BrokenPipe, "The archive's writer stopped"))
+    }
+
+    async fn write_padding(&mut self, size: u64) -> io::Result<()> {
+        let mut padding = Vec::new();
+        tar::write_padding(&mut padding, size)?;
+        if padding.is_empty() {
+            return Ok(());
+        }
+        self.write(padding.into()).await
+    }
+
+    /// Waits until everything written is in the output
+    async fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        self.writer.await?
+    }
+}
+
+impl Prefetch {
+    fn spawn(
+        rate_limiter: Arc<RateLimiter>,
+        progress: ProgressHandler,
+        file: RemoteFile,
+        turn: Option<oneshot::Receiver<()>>,
+        next_turn: oneshot::Sender<()>,
+    ) -> Self {
+        let (started_send, started) = oneshot::channel();
+        let (content_send, content) = mpsc::channel(crate::stream::CHUNK_BUFFER_COUNT);
+        let task = tokio::spawn(fetch(
+            rate_limiter,
+            progress,
+            file,
+            turn,
+            next_turn,
+            started_send,
+            content_send,
+        ));
+        Self { started, content, task }
+    }
+}
+
+impl Drop for Prefetch {
+    /// A file downloaded ahead of an entry that failed is left unfinished
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_paths_stay_inside_the_archive() -> Result<()> {
+        let relocation = Relocation::default();
+        assert_eq!(
+            archive_path(&relocation, Path::new("dir/./file"))?,
+            Some(PathBuf::from("dir/file"))
+        );
+        assert!(archive_path(&relocation, Path::new("dir/../../.bashrc")).is_err());
+
+        let relocation = Relocation {
+            strip_prefix: 1,
+            moves: vec![(PathBuf::from("moved"), PathBuf::from("/abs/target"))],
+        };
+        assert_eq!(archive_path(&relocation, Path::new("dir"))?, None);
+        assert_eq!(
+            archive_path(&relocation, Path::new("moved/file"))?,
+            Some(PathBuf::from("abs/target/file"))
+        );
+        let relocation = Relocation {
+            strip_prefix: 0,
+            moves: vec![(PathBuf::from("moved"), PathBuf::from("../out"))],
+        };
+        assert!(archive_path(&relocation, Path::new("moved/file")).is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,52 @@
-use eyre::{eyre, Result};
+use eyre::Result;
+use frozen_core::failure::Failure;
+use frozen_core::net::pause;
+use frozen_core::progress;
 use futures::future::{select, Either, FutureExt};
 use std::future::Future;
 use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
 
-/// Runs the future, but interrupts it and returns Err if Ctrl+C is pressed
+/// Runs the future, but interrupts it and returns Err if Ctrl+C is pressed.
+/// Ctrl+Z (SIGTSTP) pauses its transfers instead of stopping the process, until Ctrl+Z is pressed again or SIGCONT.
+/// SIGUSR1 prints the progress so far, for runs whose output goes to a log.
+///
+/// tokio never gives a signal back to its default action once it listens to it, so after the first call Ctrl+Z
+/// doesn't stop the process anymore, even outside of this. Commands must not wait on the terminal after calling it.
 pub async fn interruptible(fut: impl Future<Output = Result<()>>) -> Result<()> {
+    let control_handler = tokio::spawn(handle_control_signals());
     let int_fut = ctrl_c().boxed_local();
     let fut = fut.boxed_local();
-    match select(fut, int_fut).await {
+    let result = match select(fut, int_fut).await {
         Either::Left((fut_result, _int_fut)) => fut_result,
-        Either::Right((Ok(()), _fut)) => Err(eyre!("Interrupted by Ctrl+C")),
+        Either::Right((Ok(()), _fut)) => Err(Failure::Interrupted.into()),
         Either::Right((Err(_), fut)) => fut.await,
+    };
+    control_handler.abort();
+    result
+}
+
+async fn handle_control_signals() -> Result<()> {
+    let mut tstp = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+    let mut cont = signal(SignalKind::from_raw(libc::SIGCONT))?;
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            Some(()) = tstp.recv() => {
+                if pause::is_paused() {
+                    pause::resume();
+                } else {
+                    pause::pause();
+                }
+            }
+            Some(()) = cont.recv() => pause::resume(),
+            Some(()) = usr1.recv() => {
+                eprintln!("Status:");
+                for line in progress::status_report() {
+                    eprintln!("\t{}", line);
+                }
+            }
+            else => return Ok(()),
+        }
     }
 }
@@ -0,0 +1,273 @@
+//! Backs up live systems from a snapshot of the source, so that files changed during the backup stay consistent
+//!
+//! The snapshot is taken by btrfs, ZFS or LVM, or by commands from the config for anything else.
+//! The backup reads from the snapshot, but is still saved under the path of the source.
+
+use crate::progress;
+use eyre::{bail, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+/// The size reserved for the changes made to the source while an LVM snapshot exists
+const LVM_DEFAULT_SIZE: &str = "1G";
+
+/// How snapshots are taken, in the config
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotSettings {
+    /// The size of LVM snapshots, as given to `lvcreate -L` (default 1G)
+    #[serde(default)]
+    pub lvm_size: Option<String>,
+    /// Shell command taking a snapshot of $FROZEN_SOURCE, it prints the path of the snapshot on its last line
+    #[serde(default)]
+    pub create_command: Option<String>,
+    /// Shell command removing the snapshot at $FROZEN_SNAPSHOT of $FROZEN_SOURCE
+    #[serde(default)]
+    pub remove_command: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotKind {
+    Btrfs,
+    Zfs,
+    Lvm,
+    /// The commands of `SnapshotSettings`
+    Command,
+}
+
+impl SnapshotKind {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "btrfs" => Ok(SnapshotKind::Btrfs),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            "lvm" => Ok(SnapshotKind::Lvm),
+            "command" => Ok(SnapshotKind::Command),
+            _ => bail!("Invalid snapshot kind \"{}\" (use btrfs, zfs, lvm or command)", name),
+        }
+    }
+}
+
+/// A snapshot of a source folder, removed when dropped
+pub struct Snapshot {
+    /// Where the source folder is in the snapshot
+    path: PathBuf,
+    /// Removes the snapshot, in order
+    cleanup: Vec<Command>,
+    /// Where LVM snapshots are mounted, removed after they're unmounted
+    mount_dir: Option<TempDir>,
+}
+
+impl Snapshot {
+    pub fn create(kind: SnapshotKind, source: &Path, settings: &SnapshotSettings) -> Result<Self> {
+        let mut snapshot = Snapshot {
+            path: PathBuf::new(),
+            cleanup: Vec::new(),
+            mount_dir: None,
+        };
+        let name = format!(
+            "frozen_{}_{}",
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            std::process::id()
+        );
+        // If a step fails, dropping the snapshot undoes the previous steps
+        match kind {
+            SnapshotKind::Btrfs => snapshot.create_btrfs(source, &name)?,
+            SnapshotKind::Zfs => snapshot.create_zfs(source, &name)?,
+            SnapshotKind::Lvm => snapshot.create_lvm(source, &name, settings)?,
+            SnapshotKind::Command => snapshot.create_with_command(source, settings)?,
+        }
+        if !snapshot.path.is_dir() {
+            bail!(
+                "The snapshot of {} has no folder at {}",
+                source.display(),
+                snapshot.path.display()
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Where to back up from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the snapshot, reporting the steps that failed
+    pub fn remove(mut self) -> Result<()> {
+        self.run_cleanup()
+    }
+
+    fn run_cleanup(&mut self) -> Result<()> {
+        let mut failure = None;
+        for mut command in self.cleanup.drain(..) {
+            if let Err(err) = run(&mut command) {
+                failure.get_or_insert(err);
+            }
+            // Once unmounted, the mount point can go
+            if command.get_program() == "umount" {
+                self.mount_dir.take();
+            }
+        }
+        match failure {
+            Some(err) => Err(err.wrap_err("Failed to remove the snapshot")),
+            None => Ok(()),
+        }
+    }
+
+    /// The source must be a subvolume. The snapshot is created next to it, since it must be on the same filesystem.
+    fn create_btrfs(&mut self, source: &Path, name: &str) -> Result<()> {
+        let parent = source
+            .parent()
+            .ok_or_else(|| eyre!("Can't snapshot the root folder with btrfs, it has no parent to hold the snapshot"))?;
+        let snapshot_path = parent.join(format!(".{}", name));
+        run(Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(source)
+            .arg(&snapshot_path))?;
+        let mut delete = Command::new("btrfs");
+        delete.args(["subvolume", "delete"]).arg(&snapshot_path);
+        self.cleanup.push(delete);
+        self.path = snapshot_path;
+        Ok(())
+    }
+
+    /// Snapshots the dataset holding the source, which is then read through the hidden .zfs folder of the dataset
+    fn create_zfs(&mut self, source: &Path, name: &str) -> Result<()> {
+        let listed = run(Command::new("zfs")
+            .args(["list", "-H", "-o", "name,mountpoint"])
+            .arg(source))?;
+        let (dataset, mountpoint) = listed
+            .lines()
+            .next()
+            .and_then(|line| line.split_once('\t'))
+            .ok_or_else(|| eyre!("Failed to find the ZFS dataset of {}", source.display()))?;
+        let rel_path = source.strip_prefix(mountpoint).wrap_err_with(|| {
+            format!(
+                "{} is not under the mountpoint {} of its dataset",
+                source.display(),
+                mountpoint
+            )
+        })?;
+        let snapshot_name = format!("{}@{}", dataset, name);
+        run(Command::new("zfs").arg("snapshot").arg(&snapshot_name))?;
+        let mut destroy = Command::new("zfs");
+        destroy.arg("destroy").arg(&snapshot_name);
+        self.cleanup.push(destroy);
+        self.path = Path::new(mountpoint).join(".zfs/snapshot").join(name).join(rel_path);
+        Ok(())
+    }
+
+    /// Snapshots the logical volume mounted at the source, and mounts the snapshot read-only in a temporary folder
+    fn create_lvm(&mut self, source: &Path, name: &str, settings: &SnapshotSettings) -> Result<()> {
+        let mounted = run(Command::new("findmnt")
+            .args(["-n", "-o", "SOURCE,TARGET,FSTYPE", "--target"])
+            .arg(source))?;
+        let fields: Vec<&str> = mounted.split_whitespace().collect();
+        let (device, mountpoint, fstype) = match fields[..] {
+            [device, mountpoint, fstype] => (device, mountpoint, fstype),
+            _ => bail!("Failed to find the device mounted at {}", source.display()),
+        };
+        let rel_path = source.strip_prefix(mountpoint)?;
+        let vg_name = run(Command::new("lvs").args(["--noheadings", "-o", "vg_name", device]))?;
+        let snapshot_lv = format!("{}/{}", vg_name.trim(), name);
+
+        let size = settings.lvm_size.as_deref().unwrap_or(LVM_DEFAULT_SIZE);
+        run(Command::new("lvcreate").args(["-s", "-n", name, "-L", size, device]))?;
+        let mut lvremove = Command::new("lvremove");
+        lvremove.args(["-f", &snapshot_lv]);
+        self.cleanup.push(lvremove);
+
+        let mount_dir = tempfile::Builder::new().prefix("frozen-snapshot-").tempdir()?;
+        // XFS refuses to mount a second filesystem with the same UUID
+        let options = if fstype == "xfs" { "ro,nouuid" } else { "ro" };
+        run(Command::new("mount")
+            .args(["-o", options])
+            .arg(format!("/dev/{}", snapshot_lv))
+            .arg(mount_dir.path()))?;
+        let mut umount = Command::new("umount");
+        umount.arg(mount_dir.path());
+        self.cleanup.insert(0, umount);
+        self.path = mount_dir.path().join(rel_path);
+        self.mount_dir = Some(mount_dir);
+        Ok(())
+    }
+
+    fn create_with_command(&mut self, source: &Path, settings: &SnapshotSettings) -> Result<()> {
+        let (create, remove) = match (&settings.create_command, &settings.remove_command) {
+            (Some(create), Some(remove)) => (create, remove),
+            _ => bail!("Snapshots with commands need both create_command and remove_command in the config"),
+        };
+        let output = run(Command::new("sh").args(["-c", create]).env("FROZEN_SOURCE", source))?;
+        let snapshot_path = output
+            .lines()
+            .last()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .ok_or_else(|| eyre!("The snapshot command didn't print the path of the snapshot"))?;
+        let mut remove_command = Command::new("sh");
+        remove_command
+            .args(["-c", remove])
+            .env("FROZEN_SOURCE", source)
+            .env("FROZEN_SNAPSHOT", snapshot_path);
+        self.cleanup.push(remove_command);
+        self.path = PathBuf::from(snapshot_path);
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(err) = self.run_cleanup() {
+            progress::log(format!("{:#}", err));
+        }
+    }
+}
+
+/// Runs a command to completion, returning its stdout
+fn run(command: &mut Command) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .wrap_err_with(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        bail!("{} failed ({})", program, output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn snapshot_commands_run_in_order() -> Result<()> {
+        let dir = tempdir()?;
+        let source = dir.path().join("source");
+        fs::create_dir(&source)?;
+        let settings = SnapshotSettings {
+            lvm_size: None,
+            create_command: Some(
+                "cp -r \"$FROZEN_SOURCE\" \"$FROZEN_SOURCE.snap\" && echo \"$FROZEN_SOURCE.snap\"".to_owned(),
+            ),
+            remove_command: Some("rm -r \"$FROZEN_SNAPSHOT\"".to_owned()),
+        };
+        let snapshot = Snapshot::create(SnapshotKind::Command, &source, &settings)?;
+        assert_eq!(snapshot.path(), dir.path().join("source.snap"));
+        assert!(snapshot.path().is_dir());
+        snapshot.remove()?;
+        assert!(!dir.path().join("source.snap").exists());
+
+        let failing = SnapshotSettings {
+            create_command: Some("exit 1".to_owned()),
+            ..settings
+        };
+        assert!(Snapshot::create(SnapshotKind::Command, &source, &failing).is_err());
+        assert!(SnapshotKind::from_name("ext4").is_err());
+        Ok(())
+    }
+}
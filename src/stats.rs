@@ -0,0 +1,222 @@
+//! Time and bytes spent in each stage of the transfer pipeline, reported by `--stats` at the end of a run
+//!
+//! The counters are shared by the whole process, since the streams and backends updating them are created everywhere.
+//! Times are summed over every thread working on a stage, so they can add up to more than the run itself.
+
+use futures::Stream;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading local files being uploaded
+    Read,
+    Compress,
+    Encrypt,
+    /// SHA1 of the parts of large files
+    Hash,
+    /// Sending file data, including retries
+    Upload,
+    /// Waiting for file data to arrive
+    Download,
+    /// Other API requests (listing, starting uploads, deleting, ...)
+    Request,
+    Decrypt,
+    /// Decompressing restored files, and writing them to disk
+    Decompress,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 9] = [
+        Stage::Read,
+        Stage::Compress,
+        Stage::Encrypt,
+        Stage::Hash,
+        Stage::Upload,
+        Stage::Download,
+        Stage::Request,
+        Stage::Decrypt,
+        Stage::Decompress,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Compress => "compress",
+            Stage::Encrypt => "encrypt",
+            Stage::Hash => "hash",
+            Stage::Upload => "upload",
+            Stage::Download => "download",
+            Stage::Request => "request",
+            Stage::Decrypt => "decrypt",
+            Stage::Decompress => "decompress",
+        }
+    }
+}
+
+struct Counters {
+    calls: AtomicU64,
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+    calls: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    nanos: AtomicU64::new(0),
+};
+static COUNTERS: [Counters; Stage::ALL.len()] = [ZERO; Stage::ALL.len()];
+
+/// The totals of a stage so far
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StageStats {
+    pub stage: Stage,
+    pub calls: u64,
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+impl StageStats {
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        if self.bytes == 0 || self.time.is_zero() {
+            return None;
+        }
+        Some((self.bytes as f64 / self.time.as_secs_f64()) as u64)
+    }
+}
+
+pub fn record(stage: Stage, bytes: u64, time: Duration) {
+    let counters = &COUNTERS[stage as usize];
+    counters.calls.fetch_add(1, Ordering::Relaxed);
+    counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+    counters.nanos.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Runs `f` and records its time under `stage`
+pub fn timed<T>(stage: Stage, bytes: u64, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record(stage, bytes, start.elapsed());
+    result
+}
+
+/// The stages that were used so far, in pipeline order
+pub fn snapshot() -> Vec<StageStats> {
+    Stage::ALL
+        .iter()
+        .map(|&stage| {
+            let counters = &COUNTERS[stage as usize];
+            StageStats {
+                stage,
+                calls: counters.calls.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+                time: Duration::from_nanos(counters.nanos.load(Ordering::Relaxed)),
+            }
+        })
+        .filter(|stats| stats.calls > 0)
+        .collect()
+}
+
+/// Records the reads of a local file under `Stage::Read`, and keeps its own totals
+/// so that the stage consuming it can leave the reads out of its time
+pub struct TimedRead<R> {
+    inner: R,
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+impl<R: Read> TimedRead<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            time: Duration::ZERO,
+        }
+    }
+}
+
+impl<R: Read> Read for TimedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.read(buf);
+        let (bytes, time) = (*result.as_ref().unwrap_or(&0) as u64, start.elapsed());
+        self.bytes += bytes;
+        self.time += time;
+        record(Stage::Read, bytes, time);
+        result
+    }
+}
+
+/// Records how long the consumer of a stream waits for each item under `stage`, with the size of the items
+pub struct TimedStream<S> {
+    inner: S,
+    stage: Stage,
+    waiting_since: Option<Instant>,
+}
+
+impl<S> TimedStream<S> {
+    pub fn new(inner: S, stage: Stage) -> Self {
+        Self {
+            inner,
+            stage,
+            waiting_since: None,
+        }
+    }
+}
+
+impl<S, T, E> Stream for TimedStream<S>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: AsRef<[u8]>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(item) = &poll {
+            self.waiting_since = None;
+            if let Some(Ok(data)) = item {
+                record(self.stage, data.as_ref().len() as u64, waiting_since.elapsed());
+            }
+        }
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_are_accumulated() {
+        // The counters are shared with every other test, so we only look at increases
+        let before = snapshot().into_iter().find(|stats| stats.stage == Stage::Hash);
+        record(Stage::Hash, 100, Duration::from_millis(2));
+        let sum = timed(Stage::Hash, 50, || 2 + 2);
+        assert_eq!(sum, 4);
+
+        let after = snapshot().into_iter().find(|stats| stats.stage == Stage::Hash).unwrap();
+        let (calls, bytes, time) = before.map_or((0, 0, Duration::ZERO), |s| (s.calls, s.bytes, s.time));
+        assert!(after.calls >= calls + 2);
+        assert!(after.bytes >= bytes + 150);
+        assert!(after.time >= time + Duration::from_millis(2));
+        assert!(after.bytes_per_sec().is_some());
+
+        let mut read = TimedRead::new(&b"hello"[..]);
+        let mut buf = Vec::new();
+        read.read_to_end(&mut buf).unwrap();
+        assert_eq!(read.bytes, 5);
+        assert!(snapshot()
+            .iter()
+            .any(|stats| stats.stage == Stage::Read && stats.bytes >= 5));
+    }
+}
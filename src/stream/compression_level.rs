@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+/// Uploads shorter than this say more about latency than about where the bottleneck is
+const MIN_SAMPLE_TIME: Duration = Duration::from_millis(200);
+/// Above this fraction of an upload spent compressing, we're CPU-bound and lower the level
+const CPU_BOUND_RATIO: f64 = 0.6;
+/// Below this fraction, compression mostly waits for the network to drain its output, and we raise the level
+const NETWORK_BOUND_RATIO: f64 = 0.25;
+
+/// The zstd level of the next uploads, which can adapt to whether compression or the network is the bottleneck
+pub struct CompressionLevel {
+    min: i32,
+    max: i32,
+    current: AtomicI32,
+}
+
+impl CompressionLevel {
+    pub fn fixed(level: i32) -> Self {
+        Self::adaptive(level, level)
+    }
+
+    /// Starts at `max`, and moves one level at a time within the range as uploads finish
+    pub fn adaptive(min: i32, max: i32) -> Self {
+        let min = min.min(max);
+        Self {
+            min,
+            max,
+            current: AtomicI32::new(max),
+        }
+    }
+
+    pub fn get(&self) -> i32 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Reports how long an upload took, and how much of that its compression stream was busy compressing.
+    /// When the network is slower, compression spends most of the upload blocked by backpressure.
+    pub fn report(&self, compress_time: Duration, total_time: Duration) {
+        if self.min == self.max || total_time < MIN_SAMPLE_TIME {
+            return;
+        }
+        let ratio = compress_time.as_secs_f64() / total_time.as_secs_f64();
+        let step = if ratio > CPU_BOUND_RATIO {
+            -1
+        } else if ratio < NETWORK_BOUND_RATIO {
+            1
+        } else {
+            return;
+        };
+        let _ = self
+            .current
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |level| {
+                Some((level + step).clamp(self.min, self.max))
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_follows_bottleneck() {
+        let secs = Duration::from_secs;
+        let level = CompressionLevel::adaptive(3, 5);
+        assert_eq!(level.get(), 5);
+        level.report(secs(9), secs(10));
+        level.report(secs(9), secs(10));
+        level.report(secs(9), secs(10));
+        assert_eq!(level.get(), 3);
+        level.report(secs(4), secs(10));
+        level.report(Duration::ZERO, Duration::from_millis(10));
+        assert_eq!(level.get(), 3);
+        level.report(secs(1), secs(10));
+        assert_eq!(level.get(), 4);
+
+        let fixed = CompressionLevel::fixed(18);
+        fixed.report(secs(10), secs(10));
+        assert_eq!(fixed.get(), 18);
+    }
+}
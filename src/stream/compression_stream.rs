@@ -1,17 +1,42 @@
-use crate::stream::{AsyncStreamBox, STREAMS_CHUNK_SIZE};
+use crate::crypto::{ContentHash, RunningContentHash};
+use crate::stats::{self, Stage, TimedRead};
+use crate::stream::{cpu_pool, AsyncStreamBox, STREAMS_CHUNK_SIZE};
 use async_stream::stream;
 use bytes::Bytes;
 use eyre::Result;
 use futures::task::{Context, Poll};
 use futures::{Stream, StreamExt};
-use std::io::Read;
+use std::io::{ErrorKind, Read, Write};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, oneshot};
-use tokio::task::block_in_place;
+use tokio::task::spawn_blocking;
+
+/// How much of the input is read at once, before it's compressed
+const READ_SIZE: usize = 1024 * 1024;
 
 pub struct CompressionStream {
     output: AsyncStreamBox<Bytes>,
     stream_lower_bound: usize,
+    compress_time: CompressTime,
+    content_hash: Arc<Mutex<Option<ContentHash>>>,
+}
+
+/// How long a `CompressionStream` spent compressing, not counting reads or waiting for its output to be consumed.
+/// It can still be read after the stream is moved into the rest of the pipeline.
+#[derive(Clone, Default)]
+pub struct CompressTime(Arc<AtomicU64>);
+
+impl CompressTime {
+    pub fn get(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, time: Duration) {
+        self.0.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
 }
 
 impl CompressionStream {
@@ -19,7 +44,16 @@ impl CompressionStream {
         let (send, mut recv) = mpsc::channel(super::CHUNK_BUFFER_COUNT);
         let (lower_bound_send, lower_bound_recv) = oneshot::channel();
 
-        tokio::task::spawn(Self::process(Box::new(input), compress_level, send, lower_bound_send));
+        let compress_time = CompressTime::default();
+        let content_hash = Arc::new(Mutex::new(None));
+        tokio::task::spawn(Self::process(
+            Box::new(input),
+            compress_level,
+            send,
+            lower_bound_send,
+            compress_time.clone(),
+            content_hash.clone(),
+        ));
         let stream_recv = Box::pin(stream! {
             while let Some(item) = recv.recv().await {
                 yield item;
@@ -28,52 +62,103 @@ impl CompressionStream {
         Self {
             output: stream_recv,
             stream_lower_bound: lower_bound_recv.await.unwrap(),
+            compress_time,
+            content_hash,
         }
     }
 
+    pub fn compress_time(&self) -> CompressTime {
+        self.compress_time.clone()
+    }
+
+    /// Hash of the whole input, once it has all been read.
+    /// That's already the case for inputs that fit in a single chunk, when `new` returns.
+    pub fn content_hash(&self) -> Option<ContentHash> {
+        *self.content_hash.lock().unwrap()
+    }
+
     async fn process(
         input: Box<dyn Read + Send>,
         compress_level: i32,
         sender: mpsc::Sender<Result<Bytes>>,
         lower_bound_send: oneshot::Sender<usize>,
+        compress_time_total: CompressTime,
+        content_hash: Arc<Mutex<Option<ContentHash>>>,
     ) {
-        let mut encoder = zstd::stream::read::Encoder::new(input, compress_level).unwrap();
+        let mut input = TimedRead::new(input);
+        let mut hash = RunningContentHash::default();
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), compress_level).unwrap();
 
         let mut lower_bound_send = Some(lower_bound_send);
         let mut chunks_count = 0;
 
-        let mut pos = 0usize;
-        let mut buf = vec![0u8; STREAMS_CHUNK_SIZE].into_boxed_slice();
         loop {
-            let read_count = match block_in_place(|| encoder.read(&mut buf[pos..])) {
+            // Reads can block for long (pipes, network filesystems), they don't hold one of the CPU threads
+            let (returned_input, read_buf, result) = spawn_blocking(move || {
+                let mut read_buf = vec![0u8; READ_SIZE];
+                let result = read_up_to(&mut input, &mut read_buf);
+                (input, read_buf, result)
+            })
+            .await
+            .unwrap();
+            input = returned_input;
+            let read_count = match result {
                 Err(err) => {
                     let _ = sender.send(Err(err.into())).await;
                     break;
                 }
                 Ok(n) => n,
             };
-
             let at_end = read_count == 0;
-            pos += read_count;
 
-            if pos == STREAMS_CHUNK_SIZE || at_end {
+            let (returned_encoder, returned_hash, result, compress_time) = cpu_pool::run(move || {
+                let data = &read_buf[..read_count];
+                stats::timed(Stage::Hash, read_count as u64, || hash.update(data));
+                let start = Instant::now();
+                let result = if at_end {
+                    encoder.do_finish()
+                } else {
+                    encoder.write_all(data)
+                };
+                (encoder, hash, result, start.elapsed())
+            })
+            .await;
+            encoder = returned_encoder;
+            hash = returned_hash;
+            stats::record(Stage::Compress, read_count as u64, compress_time);
+            compress_time_total.add(compress_time);
+            if let Err(err) = result {
+                let _ = sender.send(Err(err.into())).await;
+                break;
+            }
+
+            if at_end {
+                // Ready before the last chunk goes out, so it's known once the stream ends
+                *content_hash.lock().unwrap() = Some(hash.clone().finish());
+            }
+            let output = encoder.get_mut();
+            let mut chunks = Vec::new();
+            while output.len() >= STREAMS_CHUNK_SIZE {
+                let rest = output.split_off(STREAMS_CHUNK_SIZE);
+                chunks.push(std::mem::replace(output, rest));
+            }
+            if at_end {
+                chunks.push(std::mem::take(output));
+            }
+            for chunk in chunks {
                 chunks_count += 1;
                 if chunks_count == 2 {
                     if let Some(sender) = lower_bound_send.take() {
                         sender.send(chunks_count).unwrap()
                     }
                 }
-                let mut bytes = buf.into_vec();
-                bytes.truncate(pos);
-                if sender.send(Ok(bytes.into())).await.is_err() {
-                    break;
-                }
-                buf = vec![0u8; STREAMS_CHUNK_SIZE].into_boxed_slice();
-                pos = 0;
-                if at_end {
+                if sender.send(Ok(chunk.into())).await.is_err() {
                     break;
                 }
             }
+            if at_end || sender.is_closed() {
+                break;
+            }
         }
 
         if let Some(sender) = lower_bound_send.take() {
@@ -82,6 +167,20 @@ impl CompressionStream {
     }
 }
 
+/// Reads until `buf` is full or the input ends, returns how much was read
+fn read_up_to(input: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match input.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(pos)
+}
+
 impl Stream for CompressionStream {
     type Item = Result<Bytes>;
 
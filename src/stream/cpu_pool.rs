@@ -0,0 +1,30 @@
+//! Runs the CPU-heavy stages of the streams (compression, encryption, hashing) on tokio's blocking threads,
+//! a limited number at once, so that they never hold up the runtime threads driving the network transfers
+
+use std::sync::OnceLock;
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+
+static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Sets how many CPU-heavy stages run at once, the number of CPUs by default.
+/// Only the first call before any stage runs counts, returns whether it did.
+pub fn set_threads(threads: usize) -> bool {
+    PERMITS.set(Semaphore::new(threads.max(1))).is_ok()
+}
+
+fn permits() -> &'static Semaphore {
+    PERMITS.get_or_init(|| Semaphore::new(num_cpus::get()))
+}
+
+/// Runs `work` once a CPU thread is free, panics propagate to the caller
+pub async fn run<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    let _permit = permits().acquire().await.unwrap();
+    match spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(err) => match err.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(err) => panic!("CPU-heavy stage failed: {}", err),
+        },
+    }
+}
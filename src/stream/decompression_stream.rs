@@ -1,13 +1,14 @@
-use crate::stream::{next_stream_bytes, AsyncStreamBox};
+use crate::stats::{self, Stage};
+use crate::stream::{cpu_pool, next_stream_bytes, AsyncStreamBox, STREAMS_CHUNK_SIZE};
 use async_stream::stream;
 use bytes::Bytes;
-use eyre::Result;
+use eyre::{eyre, Result};
 use futures::task::{Context, Poll};
 use futures::{Stream, StreamExt};
 use std::io::Write;
 use std::pin::Pin;
-use tokio::sync::mpsc;
-use tokio::task::block_in_place;
+use tokio::sync::{mpsc, oneshot};
+use zstd::stream::raw::Operation;
 
 /// This "stream" takes a compressed input stream, but writes its output directly to an impl Write
 pub struct DecompressionStream {
@@ -38,15 +39,25 @@ impl DecompressionStream {
         let mut decoder = zstd::stream::write::Decoder::new(output).unwrap();
 
         while let Some(input) = next_stream_bytes(&mut input_stream, &mut sender).await {
-            block_in_place(|| {
-                decoder.write_all(&input).unwrap();
-            });
-            if sender.send(Ok(())).await.is_err() {
+            let (returned_decoder, written) = cpu_pool::run(move || {
+                let written = stats::timed(Stage::Decompress, input.len() as u64, || decoder.write_all(&input));
+                (decoder, written)
+            })
+            .await;
+            decoder = returned_decoder;
+            let result = written.map_err(|err| eyre!("Failed to decompress: {}", err));
+            let failed = result.is_err();
+            if sender.send(result).await.is_err() || failed {
                 return;
             }
         }
 
-        decoder.flush().unwrap();
+        // The output may be waiting on the disk
+        if let Err(err) = cpu_pool::run(move || decoder.flush()).await {
+            let _ = sender
+                .send(Err(eyre!("Failed to write decompressed data: {}", err)))
+                .await;
+        }
     }
 }
 
@@ -57,3 +68,173 @@ impl Stream for DecompressionStream {
         self.output.poll_next_unpin(cx)
     }
 }
+
+/// Unlike `DecompressionStream`, returns the decompressed data as a stream of `STREAMS_CHUNK_SIZE` chunks,
+/// like a `CompressionStream` returns compressed data. For uploading decompressed data again.
+pub struct DecompressedStream {
+    output: AsyncStreamBox<Bytes>,
+    stream_lower_bound: usize,
+}
+
+impl DecompressedStream {
+    pub async fn new(input: Box<dyn Stream<Item = Result<Bytes>> + Send + Sync>) -> Self {
+        let (send, mut recv) = mpsc::channel(super::CHUNK_BUFFER_COUNT);
+        let (lower_bound_send, lower_bound_recv) = oneshot::channel();
+
+        tokio::task::spawn(Self::process(input.into(), send, lower_bound_send));
+        let stream_recv = Box::pin(stream! {
+            while let Some(item) = recv.recv().await {
+                yield item;
+            }
+        });
+        Self {
+            output: stream_recv,
+            stream_lower_bound: lower_bound_recv.await.unwrap(),
+        }
+    }
+
+    async fn process(
+        mut input_stream: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send + Sync>>,
+        sender: mpsc::Sender<Result<Bytes>>,
+        lower_bound_send: oneshot::Sender<usize>,
+    ) {
+        let mut decoder = zstd::stream::raw::Decoder::new().unwrap();
+        let mut lower_bound_send = Some(lower_bound_send);
+        let mut chunks_count = 0;
+
+        let mut input = Bytes::new();
+        // Zero when the decoder is between frames, the input must not end anywhere else
+        let mut frame_remaining = 0;
+        // The decoder may hold more output than fit in the last chunk, even without any input left
+        let mut output_full = false;
+        let mut pos = 0usize;
+        let mut buf = vec![0u8; STREAMS_CHUNK_SIZE].into_boxed_slice();
+        loop {
+            if input.is_empty() && !output_full {
+                input = match input_stream.next().await {
+                    Some(Ok(input)) => input,
+                    Some(Err(err)) => {
+                        let _ = sender.send(Err(err)).await;
+                        break;
+                    }
+                    None if frame_remaining != 0 => {
+                        let _ = sender
+                            .send(Err(eyre!("Failed to decompress: the data is truncated")))
+                            .await;
+                        break;
+                    }
+                    None => {
+                        if pos > 0 {
+                            chunks_count += 1;
+                            let mut bytes = buf.into_vec();
+                            bytes.truncate(pos);
+                            let _ = sender.send(Ok(bytes.into())).await;
+                        }
+                        break;
+                    }
+                };
+            }
+
+            let (returned_decoder, returned_buf, returned_input, result) = cpu_pool::run(move || {
+                let result = stats::timed(Stage::Decompress, input.len() as u64, || {
+                    decoder.run_on_buffers(&input, &mut buf[pos..])
+                });
+                (decoder, buf, input, result)
+            })
+            .await;
+            decoder = returned_decoder;
+            buf = returned_buf;
+            let status = match result {
+                Ok(status) => status,
+                Err(err) => {
+                    let _ = sender.send(Err(eyre!("Failed to decompress: {}", err))).await;
+                    break;
+                }
+            };
+            input = returned_input.slice(status.bytes_read..);
+            frame_remaining = status.remaining;
+            pos += status.bytes_written;
+
+            output_full = pos == STREAMS_CHUNK_SIZE;
+            if output_full {
+                chunks_count += 1;
+                if chunks_count == 2 {
+                    if let Some(sender) = lower_bound_send.take() {
+                        sender.send(chunks_count).unwrap()
+                    }
+                }
+                let bytes = std::mem::replace(&mut buf, vec![0u8; STREAMS_CHUNK_SIZE].into_boxed_slice());
+                if sender.send(Ok(bytes.into_vec().into())).await.is_err() {
+                    break;
+                }
+                pos = 0;
+            }
+        }
+
+        if let Some(sender) = lower_bound_send.take() {
+            sender.send(chunks_count).unwrap();
+        }
+    }
+}
+
+impl Stream for DecompressedStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.output.poll_next_unpin(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.stream_lower_bound, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::CompressionStream;
+    use futures::TryStreamExt;
+    use std::io::Cursor;
+
+    async fn round_trip(data: Vec<u8>) -> Result<(usize, Vec<Bytes>)> {
+        let compressed = CompressionStream::new(Cursor::new(data), 3).await;
+        let decompressed = DecompressedStream::new(Box::new(compressed)).await;
+        let lower_bound = decompressed.size_hint().0;
+        Ok((lower_bound, decompressed.try_collect().await?))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decompresses_into_chunks() -> Result<()> {
+        let (lower_bound, chunks) = round_trip(Vec::new()).await?;
+        assert_eq!((lower_bound, chunks.len()), (0, 0));
+
+        let (lower_bound, chunks) = round_trip(b"hello".to_vec()).await?;
+        assert_eq!(lower_bound, 1);
+        assert_eq!(chunks, vec![Bytes::from_static(b"hello")]);
+
+        // Compresses to much less than a chunk, but decompresses to several
+        let data: Vec<u8> = (0..STREAMS_CHUNK_SIZE * 2 + 10).map(|i| (i / 4096) as u8).collect();
+        let (lower_bound, chunks) = round_trip(data.clone()).await?;
+        assert_eq!(lower_bound, 2);
+        assert_eq!(chunks.iter().map(Bytes::len).collect::<Vec<_>>(), vec![
+            STREAMS_CHUNK_SIZE,
+            STREAMS_CHUNK_SIZE,
+            10
+        ]);
+        assert_eq!(chunks.concat(), data);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn truncated_input_fails() -> Result<()> {
+        let compressed: Vec<Bytes> = CompressionStream::new(Cursor::new(vec![7u8; 100_000]), 3)
+            .await
+            .try_collect()
+            .await?;
+        let compressed = compressed.concat();
+        let truncated = futures::stream::iter(vec![Ok(Bytes::copy_from_slice(&compressed[..compressed.len() / 2]))]);
+        let decompressed = DecompressedStream::new(Box::new(truncated)).await;
+        assert!(decompressed.try_collect::<Vec<Bytes>>().await.is_err());
+        Ok(())
+    }
+}
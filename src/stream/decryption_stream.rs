@@ -1,23 +1,24 @@
 use crate::crypto::{open_secretstream, Key};
-use crate::stream::{next_stream_bytes_chunked, AsyncStreamBox};
+use crate::stats::{self, Stage};
+use crate::stream::encryption_stream::SIZE_TAG_WITH_FINAL;
+use crate::stream::{cpu_pool, next_stream_bytes_chunked, AsyncStreamBox};
 use async_stream::stream;
 use bytes::Bytes;
 use eyre::{eyre, Result};
-use futures::stream::BoxStream;
+use futures::stream::{BoxStream, FusedStream};
 use futures::task::{Context, Poll};
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt};
 use sodiumoxide::crypto::secretstream::{Tag, ABYTES, HEADERBYTES};
 use std::convert::TryInto;
 use std::pin::Pin;
 use tokio::sync::mpsc;
-use tokio::task::block_in_place;
 
 pub struct DecryptionStream {
     output: AsyncStreamBox<Bytes>,
 }
 
 impl DecryptionStream {
-    pub fn new(input: BoxStream<'static, Result<Bytes, reqwest::Error>>, key: &Key) -> Self {
+    pub fn new(input: BoxStream<'static, Result<Bytes>>, key: &Key) -> Self {
         let (send, mut recv) = mpsc::channel(super::CHUNK_BUFFER_COUNT);
 
         tokio::task::spawn(Self::process(input, key.clone(), send));
@@ -29,13 +30,9 @@ impl DecryptionStream {
         Self { output: stream_recv }
     }
 
-    async fn process(
-        input: BoxStream<'static, Result<Bytes, reqwest::Error>>,
-        key: Key,
-        mut sender: mpsc::Sender<Result<Bytes>>,
-    ) {
+    async fn process(input: BoxStream<'static, Result<Bytes>>, key: Key, mut sender: mpsc::Sender<Result<Bytes>>) {
         let mut buf = Vec::new();
-        let mut input = input.map_err(From::from).fuse();
+        let mut input = input.fuse();
 
         let mut secret_stream = match next_stream_bytes_chunked(&mut input, &mut buf, HEADERBYTES, &mut sender).await {
             Some(header) if header.len() == HEADERBYTES => open_secretstream(header.as_ref(), &key),
@@ -50,36 +47,62 @@ impl DecryptionStream {
         };
 
         let encrypted_sizeof = std::mem::size_of::<u64>() + ABYTES;
-        let chunk_size = match next_stream_bytes_chunked(&mut input, &mut buf, encrypted_sizeof, &mut sender).await {
-            Some(encrypted_buf) if encrypted_buf.len() == encrypted_sizeof => {
-                let (buf, tag) = match block_in_place(|| secret_stream.pull(&encrypted_buf, None)) {
-                    Ok(result) => result,
-                    Err(()) => {
-                        let _ = sender
-                            .send(Err(eyre!(
-                                "Decryption failed: could not decrypt the encrypted chunk size",
-                            )))
-                            .await;
-                        return;
-                    }
-                };
-                debug_assert_eq!(tag, Tag::Push);
+        let (chunk_size, has_final) =
+            match next_stream_bytes_chunked(&mut input, &mut buf, encrypted_sizeof, &mut sender).await {
+                Some(encrypted_buf) if encrypted_buf.len() == encrypted_sizeof => {
+                    // Too small to be worth a trip to the CPU pool
+                    let (buf, tag) = match secret_stream.pull(&encrypted_buf, None) {
+                        Ok(result) => result,
+                        Err(()) => {
+                            let _ = sender
+                                .send(Err(eyre!(
+                                    "Decryption failed: could not decrypt the encrypted chunk size",
+                                )))
+                                .await;
+                            return;
+                        }
+                    };
+                    let has_final = match tag {
+                        SIZE_TAG_WITH_FINAL => true,
+                        Tag::Push => false,
+                        _ => {
+                            let _ = sender
+                                .send(Err(eyre!(
+                                    "Couldn't decrypt: unexpected tag on the chunk size. Is the data corrupt?"
+                                )))
+                                .await;
+                            return;
+                        }
+                    };
 
-                let chunk_size_bytes = buf.as_slice().try_into().unwrap();
-                u64::from_le_bytes(chunk_size_bytes) as usize
-            }
-            _ => {
+                    let chunk_size_bytes = buf.as_slice().try_into().unwrap();
+                    (u64::from_le_bytes(chunk_size_bytes) as usize, has_final)
+                }
+                _ => {
+                    let _ = sender
+                        .send(Err(eyre!(
+                            "Couldn't decrypt: failed to read chunk size header. Is the data corrupt?",
+                        )))
+                        .await;
+                    return;
+                }
+            };
+
+        let mut finished = false;
+        while let Some(input) = next_stream_bytes_chunked(&mut input, &mut buf, chunk_size, &mut sender).await {
+            if finished {
                 let _ = sender
-                    .send(Err(eyre!(
-                        "Couldn't decrypt: failed to read chunk size header. Is the data corrupt?",
-                    )))
+                    .send(Err(eyre!("Couldn't decrypt: found more data after the final chunk")))
                     .await;
                 return;
             }
-        };
-
-        while let Some(input) = next_stream_bytes_chunked(&mut input, &mut buf, chunk_size, &mut sender).await {
-            let (decrypted, tag) = match block_in_place(|| secret_stream.pull(&input, None)) {
+            let (returned_stream, pulled) = cpu_pool::run(move || {
+                let pulled = stats::timed(Stage::Decrypt, input.len() as u64, || secret_stream.pull(&input, None));
+                (secret_stream, pulled)
+            })
+            .await;
+            secret_stream = returned_stream;
+            let (decrypted, tag) = match pulled {
                 Ok(result) => result,
                 Err(()) => {
                     let _ = sender
@@ -88,11 +111,28 @@ impl DecryptionStream {
                     return;
                 }
             };
-            debug_assert_eq!(tag, Tag::Message);
+            match tag {
+                Tag::Final if has_final => finished = true,
+                Tag::Message => (),
+                _ => {
+                    let _ = sender
+                        .send(Err(eyre!("Couldn't decrypt: unexpected tag {:?} on a chunk", tag)))
+                        .await;
+                    return;
+                }
+            }
             if sender.send(Ok(Bytes::from(decrypted))).await.is_err() {
                 return;
             }
         }
+        // Without its final chunk, the data was cut short (unless reading it failed, that error was already sent)
+        if has_final && !finished && input.is_terminated() {
+            let _ = sender
+                .send(Err(eyre!(
+                    "Couldn't decrypt: the data is truncated, its final chunk is missing"
+                )))
+                .await;
+        }
     }
 }
 
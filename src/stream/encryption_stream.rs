@@ -1,15 +1,21 @@
 use crate::crypto::{create_secretstream, Key};
-use crate::stream::{next_stream_bytes_chunked, AsyncStreamBox, STREAMS_CHUNK_SIZE};
+use crate::stats::{self, Stage};
+use crate::stream::{cpu_pool, next_stream_bytes_chunked, AsyncStreamBox, STREAMS_CHUNK_SIZE};
 use async_stream::stream;
 use bytes::Bytes;
-use eyre::{eyre, Result};
+use eyre::Result;
+use futures::stream::FusedStream;
 use futures::task::{Context, Poll};
 use futures::{Stream, StreamExt};
 use sodiumoxide::crypto::secretstream::{Header, Push, Stream as SecretStream};
 use sodiumoxide::crypto::secretstream::{Tag, ABYTES};
 use std::pin::Pin;
 use tokio::sync::mpsc;
-use tokio::task::block_in_place;
+
+/// Tag of the encrypted chunk size, for streams that end with a `Tag::Final` chunk.
+/// Streams encrypted before that tag their chunk size with `Tag::Push`, and can't tell when they're truncated.
+/// Rekeying after the chunk size is harmless, both ends do it.
+pub(super) const SIZE_TAG_WITH_FINAL: Tag = Tag::Rekey;
 
 pub struct EncryptionStream {
     output: AsyncStreamBox<Bytes>,
@@ -44,39 +50,58 @@ impl EncryptionStream {
         let mut buf = Vec::new();
         let mut input = input_stream.fuse();
 
+        // An empty input still gets a header and a single empty chunk, so that it decrypts back to nothing
+        let mut data = match next_stream_bytes_chunked(&mut input, &mut buf, STREAMS_CHUNK_SIZE, &mut sender).await {
+            Some(data) => data,
+            None if input.is_terminated() => Bytes::new(),
+            None => return, // The input's error was already sent
+        };
+
         // We concat the header with the first encrypted chunk, it'd be too small just by itself
-        if let Some(data) = next_stream_bytes_chunked(&mut input, &mut buf, STREAMS_CHUNK_SIZE, &mut sender).await {
-            let Header(header_data) = secret_stream_header;
-            let mut first_chunk = header_data.to_vec();
-
-            let encrypted_chunk_size = data.len() + ABYTES;
-            let size_buf = (encrypted_chunk_size as u64).to_le_bytes();
-            debug_assert_eq!(size_buf.len(), std::mem::size_of::<u64>());
-            let encrypted_encrypted_chunk_size =
-                &mut block_in_place(|| secret_stream.push(&size_buf, None, Tag::Push).unwrap());
-            debug_assert_eq!(encrypted_encrypted_chunk_size.len(), size_buf.len() + ABYTES);
-            first_chunk.append(encrypted_encrypted_chunk_size);
-
-            let encrypted = &mut block_in_place(|| secret_stream.push(&data, None, Tag::Message).unwrap());
-            debug_assert_eq!(encrypted.len(), encrypted_chunk_size);
-            drop(data);
-            first_chunk.append(encrypted);
-
-            if sender.send(Ok(Bytes::from(first_chunk))).await.is_err() {
-                return;
-            }
-        } else {
-            let _ = sender.send(Err(eyre!("No input data, failed to encrypt!"))).await;
-            return;
-        }
+        let Header(header_data) = secret_stream_header;
+        let mut first_chunk = header_data.to_vec();
+
+        let encrypted_chunk_size = data.len() + ABYTES;
+        let size_buf = (encrypted_chunk_size as u64).to_le_bytes();
+        debug_assert_eq!(size_buf.len(), std::mem::size_of::<u64>());
+        // Too small to be worth a trip to the CPU pool
+        let encrypted_encrypted_chunk_size = &mut secret_stream.push(&size_buf, None, SIZE_TAG_WITH_FINAL).unwrap();
+        debug_assert_eq!(encrypted_encrypted_chunk_size.len(), size_buf.len() + ABYTES);
+        first_chunk.append(encrypted_encrypted_chunk_size);
+        let mut first_chunk = Some(first_chunk);
 
-        while let Some(input) = next_stream_bytes_chunked(&mut input, &mut buf, STREAMS_CHUNK_SIZE, &mut sender).await {
-            let encrypted = block_in_place(|| secret_stream.push(&input, None, Tag::Message).unwrap());
-            debug_assert_eq!(encrypted.len(), input.len() + ABYTES);
-            drop(input);
-            if sender.send(Ok(Bytes::from(encrypted))).await.is_err() {
+        loop {
+            // The last chunk is tagged Final, so we need to know whether there's another one before pushing this one
+            let next = match next_stream_bytes_chunked(&mut input, &mut buf, STREAMS_CHUNK_SIZE, &mut sender).await {
+                Some(next) => Some(next),
+                None if input.is_terminated() => None,
+                None => return, // The input's error was already sent
+            };
+            let tag = if next.is_some() { Tag::Message } else { Tag::Final };
+            let data_len = data.len();
+            let (returned_stream, mut encrypted) = cpu_pool::run(move || {
+                let encrypted = stats::timed(Stage::Encrypt, data.len() as u64, || {
+                    secret_stream.push(&data, None, tag).unwrap()
+                });
+                (secret_stream, encrypted)
+            })
+            .await;
+            secret_stream = returned_stream;
+            debug_assert_eq!(encrypted.len(), data_len + ABYTES);
+            let output = match first_chunk.take() {
+                Some(mut first_chunk) => {
+                    first_chunk.append(&mut encrypted);
+                    first_chunk
+                }
+                None => encrypted,
+            };
+            if sender.send(Ok(Bytes::from(output))).await.is_err() {
                 return;
             }
+            match next {
+                Some(next) => data = next,
+                None => return,
+            }
         }
     }
 }
@@ -92,3 +117,72 @@ impl Stream for EncryptionStream {
         (self.stream_lower_bound, None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::DecryptionStream;
+    use crate::test_helpers::test_key;
+    use futures::stream;
+    use futures::TryStreamExt;
+
+    async fn round_trip(input: Vec<Bytes>) -> Result<Vec<u8>> {
+        let input = stream::iter(input.into_iter().map(Ok));
+        let encrypted = EncryptionStream::new(Box::new(input), &test_key());
+        let decrypted = DecryptionStream::new(encrypted.boxed(), &test_key());
+        let chunks: Vec<Bytes> = decrypted.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_input_round_trip() -> Result<()> {
+        assert!(round_trip(vec![]).await?.is_empty());
+        assert!(round_trip(vec![Bytes::new()]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_input_round_trip() -> Result<()> {
+        let data = round_trip(vec![Bytes::from_static(b"hello "), Bytes::from_static(b"world")]).await?;
+        assert_eq!(data, b"hello world");
+        Ok(())
+    }
+
+    async fn decrypt(encrypted: Vec<u8>) -> Result<Vec<u8>> {
+        let input = stream::iter(vec![Ok(Bytes::from(encrypted))]);
+        let chunks: Vec<Bytes> = DecryptionStream::new(input.boxed(), &test_key()).try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn truncated_stream_fails() -> Result<()> {
+        let input = stream::iter(vec![Ok(Bytes::from_static(b"hello"))]);
+        let encrypted: Vec<Bytes> = EncryptionStream::new(Box::new(input), &test_key())
+            .try_collect()
+            .await?;
+        let encrypted = encrypted.concat();
+        assert_eq!(decrypt(encrypted.clone()).await?, b"hello");
+
+        // Cut right after the chunk size, every chunk that's left is intact
+        let header_len = sodiumoxide::crypto::secretstream::HEADERBYTES + std::mem::size_of::<u64>() + ABYTES;
+        let err = decrypt(encrypted[..header_len].to_vec()).await.unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+
+        let mut extended = encrypted.clone();
+        extended.extend_from_slice(&encrypted[header_len..]);
+        assert!(decrypt(extended).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_without_final_chunk_still_decrypts() -> Result<()> {
+        // As encrypted before the last chunk was tagged Final
+        let (mut secret_stream, Header(header)) = create_secretstream(&test_key());
+        let mut encrypted = header.to_vec();
+        let size_buf = ((5 + ABYTES) as u64).to_le_bytes();
+        encrypted.append(&mut secret_stream.push(&size_buf, None, Tag::Push).unwrap());
+        encrypted.append(&mut secret_stream.push(b"hello", None, Tag::Message).unwrap());
+        assert_eq!(decrypt(encrypted).await?, b"hello");
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use crate::crypto::sha1_string;
-use crate::stream::AsyncStreamBox;
+use crate::stats::{self, Stage};
+use crate::stream::{cpu_pool, AsyncStreamBox};
 use async_stream::stream;
 use bytes::Bytes;
 use eyre::Result;
@@ -7,7 +8,6 @@ use futures::task::{Context, Poll};
 use futures::{Stream, StreamExt};
 use tokio::macros::support::Pin;
 use tokio::sync::mpsc;
-use tokio::task::block_in_place;
 
 pub struct HashedStream {
     output: AsyncStreamBox<(Bytes, String)>,
@@ -41,7 +41,10 @@ impl HashedStream {
                     break;
                 }
                 Ok(input) => {
-                    let sha1 = block_in_place(|| sha1_string(&input));
+                    let data = input.clone();
+                    let sha1 =
+                        cpu_pool::run(move || stats::timed(Stage::Hash, data.len() as u64, || sha1_string(&data)))
+                            .await;
                     if sender.send(Ok((input, sha1))).await.is_err() {
                         return;
                     }
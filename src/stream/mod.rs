@@ -1,3 +1,7 @@
+pub mod cpu_pool;
+
+mod compression_level;
+pub use compression_level::*;
 mod compression_stream;
 pub use compression_stream::*;
 mod decompression_stream;
@@ -14,6 +18,9 @@ pub use hashed_stream::*;
 mod simple_bytes_stream;
 pub use simple_bytes_stream::*;
 
+mod write_behind;
+pub use write_behind::*;
+
 use bytes::Bytes;
 use eyre::Result;
 use futures::stream::Fuse;
@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+/// Writes are handed to the writer thread in chunks of this size
+const WRITE_BEHIND_CHUNK_SIZE: usize = 1024 * 1024;
+/// Max chunks waiting for the writer thread, after which writes wait for the disk
+const WRITE_BEHIND_CHUNK_COUNT: usize = 4;
+
+/// Writes to an output from a blocking thread, so that whoever produces the data keeps going while the disk catches up
+///
+/// The data is only guaranteed to be handed over after a `flush`.
+/// If the output fails, the next writes fail, and `WriteBehindHandle::finish` returns the error.
+pub struct WriteBehind {
+    buf: Vec<u8>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+/// Waits for the writer thread of a `WriteBehind`
+pub struct WriteBehindHandle {
+    writer: JoinHandle<io::Result<u64>>,
+}
+
+impl WriteBehind {
+    pub fn new(mut output: impl Write + Send + 'static) -> (Self, WriteBehindHandle) {
+        let (sender, receiver) = sync_channel::<Vec<u8>>(WRITE_BEHIND_CHUNK_COUNT);
+        let writer = spawn_blocking(move || {
+            let mut written = 0;
+            for chunk in receiver {
+                output.write_all(&chunk)?;
+                written += chunk.len() as u64;
+            }
+            output.flush()?;
+            Ok(written)
+        });
+        let write_behind = Self {
+            buf: Vec::with_capacity(WRITE_BEHIND_CHUNK_SIZE),
+            sender,
+        };
+        (write_behind, WriteBehindHandle { writer })
+    }
+
+    fn send_buf(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(WRITE_BEHIND_CHUNK_SIZE));
+        self.sender
+            .send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The write-behind thread stopped"))
+    }
+}
+
+impl Write for WriteBehind {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(WRITE_BEHIND_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() == WRITE_BEHIND_CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send_buf()
+    }
+}
+
+impl WriteBehindHandle {
+    /// Waits until everything written and flushed is in the output, once the `WriteBehind` is dropped.
+    /// Returns the number of bytes written.
+    pub async fn finish(self) -> io::Result<u64> {
+        self.writer.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what's written where the test can still see it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn write_behind_writes_everything_in_order() -> io::Result<()> {
+        let output = SharedBuf::default();
+        let (mut write_behind, handle) = WriteBehind::new(output.clone());
+        let data: Vec<u8> = (0..3 * WRITE_BEHIND_CHUNK_SIZE + 123).map(|i| i as u8).collect();
+        write_behind.write_all(&data)?;
+        write_behind.flush()?;
+        drop(write_behind);
+        assert_eq!(handle.finish().await?, data.len() as u64);
+        assert_eq!(*output.0.lock().unwrap(), data);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Integration with systemd: readiness and status notifications, and generating units for scheduled backups
+
+use frozen_core::progress;
+use std::env;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The watchdog that the progress of the run pings, see `SdNotify::start_watchdog`
+static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+
+/// Sends notifications to the service manager (see sd_notify(3))
+#[derive(Clone)]
+pub struct SdNotify {
+    socket: Arc<UnixDatagram>,
+    addr: String,
+    /// The last status, repeated with the watchdog pings
+    status: Arc<Mutex<(String, Instant)>>,
+}
+
+impl SdNotify {
+    /// Returns None if we're not running under a service manager that listens for notifications
+    pub fn from_env() -> Option<Self> {
+        let addr = env::var("NOTIFY_SOCKET").ok()?;
+        let socket = UnixDatagram::unbound().ok()?;
+        Some(Self {
+            socket: Arc::new(socket),
+            addr,
+            status: Arc::new(Mutex::new((String::new(), Instant::now()))),
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn notify(&self, state: &str) {
+        // Notifications are best effort, the backup shouldn't fail because systemd isn't listening
+        let _ = match self.addr.strip_prefix('@') {
+            Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| self.socket.send_to_addr(state.as_bytes(), &addr)),
+            None => self.socket.send_to(state.as_bytes(), &self.addr),
+        };
+    }
+
+    /// Only systemd listens for notifications
+    #[cfg(not(target_os = "linux"))]
+    pub fn notify(&self, _state: &str) {}
+
+    /// A new status is progress too, it pings the watchdog
+    pub fn status(&self, status: &str) {
+        *self.status.lock().unwrap() = (status.to_owned(), Instant::now());
+        match WATCHDOG.get() {
+            Some(watchdog) if watchdog.timeout.is_some() => self.notify(&format!("WATCHDOG=1\nSTATUS={}", status)),
+            _ => self.notify(&format!("STATUS={}", status)),
+        }
+    }
+
+    /// Pings the watchdog when the run makes progress, at most at half its timeout, so that a stuck backup is killed.
+    /// The pings also refresh the time spent in the current status, every minute if the service has no watchdog.
+    pub fn start_watchdog(&self) {
+        let timeout = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .map(Duration::from_micros);
+        if WATCHDOG.set(Watchdog::new(self.clone(), timeout)).is_ok() {
+            progress::set_advance_handler(|| WATCHDOG.get().unwrap().advanced());
+        }
+    }
+}
+
+/// Pings the watchdog of the service when the run advances
+struct Watchdog {
+    notify: SdNotify,
+    timeout: Option<Duration>,
+    interval: Duration,
+    last_ping: Mutex<Instant>,
+}
+
+impl Watchdog {
+    fn new(notify: SdNotify, timeout: Option<Duration>) -> Self {
+        Self {
+            notify,
+            timeout,
+            interval: timeout.map_or(Duration::from_secs(60), |timeout| timeout / 2),
+            last_ping: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Called on every progress report, only pings once per interval
+    fn advanced(&self) {
+        // Another thread that holds it is about to ping
+        let mut last_ping = match self.last_ping.try_lock() {
+            Ok(last_ping) => last_ping,
+            Err(_) => return,
+        };
+        if last_ping.elapsed() < self.interval {
+            return;
+        }
+        *last_ping = Instant::now();
+        let status = {
+            let (status, since) = &*self.notify.status.lock().unwrap();
+            format!("STATUS={} ({} min)", status, since.elapsed().as_secs() / 60)
+        };
+        match self.timeout {
+            Some(_) => self.notify.notify(&format!("WATCHDOG=1\n{}", status)),
+            None => self.notify.notify(&status),
+        }
+    }
+}
+
+/// Escapes a path into a unit name, like `systemd-escape --path`
+pub fn escape_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return "-".to_owned();
+    }
+    let mut escaped = String::new();
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped += "\\x2e",
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' => escaped.push(byte as char),
+            _ => escaped += &format!("\\x{:02x}", byte),
+        }
+    }
+    escaped
+}
+
+/// Quotes an argument of an ExecStart line, escaping what systemd would otherwise expand
+pub fn quote_exec_arg(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_unit_names() {
+        assert_eq!(escape_path(Path::new("/")), "-");
+        assert_eq!(escape_path(Path::new("/home/user/My Files/")), "home-user-My\\x20Files");
+        assert_eq!(escape_path(Path::new("/.hidden/a-b")), "\\x2ehidden-a\\x2db");
+        assert_eq!(quote_exec_arg("/a \"b\" 100%$"), "\"/a \\\"b\\\" 100%%$$\"");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sends_notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let listener = UnixDatagram::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let sd_notify = SdNotify {
+            socket: Arc::new(UnixDatagram::unbound().unwrap()),
+            addr: path.to_string_lossy().into_owned(),
+            status: Arc::new(Mutex::new((String::new(), Instant::now()))),
+        };
+
+        sd_notify.status("Backing up /home");
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STATUS=Backing up /home");
+        assert_eq!(sd_notify.status.lock().unwrap().0, "Backing up /home");
+
+        // The watchdog is only pinged when the run advances, once per interval
+        let watchdog = Watchdog::new(sd_notify, Some(Duration::from_millis(100)));
+        assert!(listener.recv(&mut buf).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        watchdog.advanced();
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1\nSTATUS=Backing up /home (0 min)");
+        watchdog.advanced();
+        assert!(listener.recv(&mut buf).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::crypto::Key;
 use crate::dirdb::dirstat::DirStat;
 use crate::dirdb::filestat::FileStat;
+use crate::dirdb::spill::DirectFiles;
 use std::path::PathBuf;
 
 pub use crate::data::root::test_helpers::test_backup_root;
@@ -14,25 +15,31 @@ pub fn test_key() -> Key {
 pub fn test_dirstat() -> DirStat {
     DirStat {
         total_files_count: 15,
-        direct_files: Some(vec![
+        direct_files: Some(DirectFiles::Loaded(vec![
             FileStat {
                 rel_path: PathBuf::from("a"),
                 last_modified: 0,
                 mode: 0,
+                size: 0,
+                birthtime: None,
             },
             FileStat {
                 rel_path: PathBuf::from("b"),
                 last_modified: 0,
                 mode: 0,
+                size: 0,
+                birthtime: None,
             },
-        ]),
+        ])),
         subfolders: vec![DirStat {
             total_files_count: 5,
-            direct_files: Some(vec![FileStat {
+            direct_files: Some(DirectFiles::Loaded(vec![FileStat {
                 rel_path: PathBuf::from("dir/c"),
                 last_modified: 0,
                 mode: 0,
-            }]),
+                size: 0,
+                birthtime: None,
+            }])),
             subfolders: vec![],
             dir_name: Some("dir".as_bytes().into()),
             dir_name_hash: [5; 8],
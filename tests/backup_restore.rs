@@ -0,0 +1,803 @@
+mod common;
+
+use common::{read_tree, write_file, TestBench};
+use eyre::Result;
+use frozen_core::crypto::content_hash;
+use frozen_core::data::audit::{AuditOperation, AuditRecord};
+use frozen_core::data::manifest::{self, Manifest};
+use frozen_core::data::relocation::Relocation;
+use frozen_core::data::{history, root};
+use frozen_core::dirdb::{remote::RemoteDirDB, DirDB};
+use frozen_core::net::backend::FileListDepth;
+use frozen_core::session::{
+    AuditedSession, BackupOptions, ExecSession, MergeSession, OverwritePolicy, RestoreOptions, SyncSession,
+    TarRestoreSession,
+};
+use fs_set_times::SystemTimeSpec;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::tempdir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backup_modify_backup_restore() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/source");
+
+    write_file(source.path(), "a.txt", b"first", 1_000_000);
+    write_file(source.path(), "dir/b.bin", &[7; 100_000], 1_000_000);
+    write_file(source.path(), "dir/sub/c", b"deleted later", 1_000_000);
+    fs::create_dir_all(source.path().join("empty/nested"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let first_restore = tempdir()?;
+    bench.restore(root_path, first_restore.path()).await?;
+    assert_eq!(read_tree(first_restore.path()), read_tree(source.path()));
+
+    write_file(source.path(), "a.txt", b"second", 2_000_000);
+    write_file(source.path(), "dir/new", b"new file", 2_000_000);
+    fs::remove_file(source.path().join("dir/sub/c"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let second_restore = tempdir()?;
+    bench.restore(root_path, second_restore.path()).await?;
+    assert_eq!(read_tree(second_restore.path()), read_tree(source.path()));
+
+    // Restoring over an outdated copy only replaces what changed
+    bench.restore(root_path, first_restore.path()).await?;
+    let updated = read_tree(first_restore.path());
+    assert_eq!(updated[Path::new("a.txt")].as_deref(), Some(&b"second"[..]));
+    assert_eq!(updated[Path::new("dir/new")].as_deref(), Some(&b"new file"[..]));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn excluded_files_are_left_out() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/exclude");
+
+    write_file(source.path(), ".config/frozen.key", b"secret", 1_000_000);
+    write_file(source.path(), ".config/other", b"other", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    // Excluding a file that's already backed up removes it from the backup
+    let options = BackupOptions {
+        exclude: vec![PathBuf::from(".config/frozen.key")],
+        ..Default::default()
+    };
+    bench.backup(source.path(), root_path, options).await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert!(!tree.contains_key(Path::new(".config/frozen.key")));
+    assert_eq!(tree[Path::new(".config/other")].as_deref(), Some(&b"other"[..]));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_existing_backup() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/keep");
+
+    write_file(source.path(), "kept", b"still here", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    fs::remove_file(source.path().join("kept"))?;
+    write_file(source.path(), "other", b"other", 1_000_000);
+    let options = BackupOptions {
+        keep_existing: true,
+        ..Default::default()
+    };
+    bench.backup(source.path(), root_path, options).await?;
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert_eq!(tree[Path::new("kept")].as_deref(), Some(&b"still here"[..]));
+    assert_eq!(tree[Path::new("other")].as_deref(), Some(&b"other"[..]));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_after_keeps_recently_deleted_files() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/delete-after");
+
+    write_file(source.path(), "dir/deleted", b"deleted", 1_000_000);
+    write_file(source.path(), "dir/other", b"other", 1_000_000);
+    write_file(source.path(), "gone/file", b"gone", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    fs::remove_file(source.path().join("dir/deleted"))?;
+    fs::remove_dir_all(source.path().join("gone"))?;
+
+    // The second backup has nothing new to diff, the kept files must still be found again
+    for _ in 0..2 {
+        let options = BackupOptions {
+            delete_after: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        bench.backup(source.path(), root_path, options).await?;
+        let restored = tempdir()?;
+        bench.restore(root_path, restored.path()).await?;
+        let tree = read_tree(restored.path());
+        assert_eq!(tree[Path::new("dir/deleted")].as_deref(), Some(&b"deleted"[..]));
+        assert_eq!(tree[Path::new("gone/file")].as_deref(), Some(&b"gone"[..]));
+    }
+
+    let options = BackupOptions {
+        delete_after: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    bench.backup(source.path(), root_path, options).await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert!(!tree.contains_key(Path::new("dir/deleted")));
+    assert!(!tree.contains_key(Path::new("gone/file")));
+    assert_eq!(tree[Path::new("dir/other")].as_deref(), Some(&b"other"[..]));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unchanged_backup_uploads_nothing() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/unchanged");
+
+    write_file(source.path(), "a", b"a", 1_000_000);
+    write_file(source.path(), "dir/b", b"b", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    let file_versions = data_versions_count(&bench).await?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    assert_eq!(data_versions_count(&bench).await?, file_versions);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_modified_in_place_are_backed_up() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/in_place");
+
+    write_file(source.path(), "dir/file", b"before", 1_000_000);
+    let dir_mtime = SystemTimeSpec::Absolute(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    fs_set_times::set_times(source.path().join("dir"), None, Some(dir_mtime))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    // Rewriting an existing file leaves its folder's mtime and entries as they were
+    write_file(source.path(), "dir/file", b"after", 2_000_000);
+    let dir_meta = fs::metadata(source.path().join("dir"))?;
+    assert_eq!(dir_meta.modified()?, UNIX_EPOCH + Duration::from_secs(1_000_000));
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(
+        read_tree(restored.path())[Path::new("dir/file")].as_deref(),
+        Some(&b"after"[..])
+    );
+    Ok(())
+}
+
+/// Counts the uploaded versions of backed up files, ignoring the DirDBs that are re-uploaded on every backup
+async fn data_versions_count(bench: &TestBench) -> Result<usize> {
+    let versions = bench.backend.list_remote_file_versions("").await?;
+    Ok(versions
+        .iter()
+        .filter(|version| !version.path.starts_with("dirdb/"))
+        .count())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verified_backup_round_trips() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/verified");
+
+    write_file(source.path(), "empty", b"", 1_000_000);
+    write_file(source.path(), "dir/file", &[7; 100_000], 1_000_000);
+    let options = BackupOptions {
+        verify: true,
+        ..Default::default()
+    };
+    bench.backup(source.path(), root_path, options).await?;
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn empty_files_round_trip() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/empty");
+
+    write_file(source.path(), "empty", b"", 1_000_000);
+    write_file(source.path(), "dir/also_empty", b"", 1_000_000);
+    write_file(source.path(), "dir/not_empty", b"data", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_records_runs() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/history");
+
+    write_file(source.path(), "a", b"12345", 1_000_000);
+    write_file(source.path(), "dir/b", b"678", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    write_file(source.path(), "a", b"changed", 2_000_000);
+    fs::remove_file(source.path().join("dir/b"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let history = history::fetch_history(bench.backend.as_ref(), &roots[0].path_hash).await?;
+    assert_eq!(history.len(), 2);
+    assert_eq!((history[0].files_count, history[0].total_size), (2, 8));
+    assert_eq!((history[0].uploaded, history[0].deleted), (2, 0));
+    assert_eq!((history[1].files_count, history[1].total_size), (1, 7));
+    assert_eq!((history[1].uploaded, history[1].deleted, history[1].errors), (1, 1, 0));
+    assert!(history[0].started <= history[1].started);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_path_hashes_match_uploads() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/paths");
+
+    write_file(source.path(), "top", b"1", 1_000_000);
+    write_file(source.path(), "dir/sub/deep", b"2", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let backend = bench.backend.as_ref();
+    for (rel_path, content) in [("top", b"1"), ("dir/sub/deep", b"2")] {
+        let (dir_hashes, full_path_hash) = roots[0].file_path_hashes(Path::new(rel_path), backend.key())?;
+        assert_eq!(dir_hashes.len(), rel_path.matches('/').count());
+        let files = backend
+            .list_remote_files(&full_path_hash, FileListDepth::Shallow)
+            .await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].rel_path, Path::new(rel_path));
+        assert!(files[0].size > 0);
+        let created = fs::metadata(source.path().join(rel_path))?.created().ok();
+        let created = created.map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert_eq!(files[0].birthtime, created);
+        assert_eq!(files[0].content_hash, Some(content_hash(content)));
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn strict_scan_refuses_unreadable_files() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/strict");
+
+    write_file(source.path(), "readable", b"1", 1_000_000);
+    write_file(source.path(), "secret", b"2", 1_000_000);
+    let secret = source.path().join("secret");
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000))?;
+    if fs::File::open(&secret).is_ok() {
+        // Permissions don't apply to root
+        return Ok(());
+    }
+
+    let options = BackupOptions {
+        strict_scan: true,
+        ..Default::default()
+    };
+    assert!(bench.backup(source.path(), root_path, options).await.is_err());
+    let roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    assert!(history::fetch_history(bench.backend.as_ref(), &roots[0].path_hash)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn symlinks_keep_raw_target_and_mtime() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let target = tempdir()?;
+    let root_path = Path::new("/backups/symlinks");
+
+    write_file(source.path(), "file", b"content", 1_000_000);
+    let link_target = OsStr::from_bytes(b"not-utf8-\xff\xfe");
+    let link = source.path().join("link");
+    std::os::unix::fs::symlink(link_target, &link)?;
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000);
+    fs_set_times::set_symlink_times(&link, None, Some(SystemTimeSpec::Absolute(mtime)))?;
+
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    bench.restore(root_path, target.path()).await?;
+
+    let restored = target.path().join("link");
+    assert_eq!(fs::read_link(&restored)?.as_os_str(), link_target);
+    assert_eq!(fs::symlink_metadata(&restored)?.modified()?, mtime);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deep_empty_folders_are_restored() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let target = tempdir()?;
+    let root_path = Path::new("/backups/deep");
+
+    let deep = "d/".repeat(500);
+    write_file(source.path(), "file", b"content", 1_000_000);
+    fs::create_dir_all(source.path().join(&deep))?;
+
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    bench.restore(root_path, target.path()).await?;
+    assert!(target.path().join(&deep).is_dir());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restored_folders_keep_newest_mtime() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let target = tempdir()?;
+    let root_path = Path::new("/backups/folder_mtimes");
+
+    write_file(source.path(), "dir/old", b"old", 1_000_000);
+    write_file(source.path(), "dir/sub/new", b"new", 2_000_000);
+    fs::create_dir_all(source.path().join("dir/empty"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    bench.restore(root_path, target.path()).await?;
+
+    let mtime = |rel_path: &str| -> Result<u64> {
+        let modified = fs::metadata(target.path().join(rel_path))?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH)?.as_secs())
+    };
+    assert!(target.path().join("dir/empty").is_dir());
+    assert_eq!(mtime("dir")?, 2_000_000);
+    assert_eq!(mtime("dir/sub")?, 2_000_000);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn overwrite_policy_decides_which_files_are_replaced() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let target = tempdir()?;
+    let root_path = Path::new("/backups/overwrite");
+
+    write_file(source.path(), "old", b"backed up", 2_000_000);
+    write_file(source.path(), "new", b"backed up", 2_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let restore = |overwrite| {
+        write_file(target.path(), "old", b"local", 1_000_000);
+        write_file(target.path(), "new", b"local", 3_000_000);
+        let options = RestoreOptions {
+            overwrite,
+            ..Default::default()
+        };
+        bench.restore_with(root_path, target.path(), options)
+    };
+    let contents = || -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok((
+            fs::read(target.path().join("old"))?,
+            fs::read(target.path().join("new"))?,
+        ))
+    };
+    restore(OverwritePolicy::Never).await?;
+    assert_eq!(contents()?, (b"local".to_vec(), b"local".to_vec()));
+    restore(OverwritePolicy::Older).await?;
+    assert_eq!(contents()?, (b"backed up".to_vec(), b"local".to_vec()));
+    restore(OverwritePolicy::Always).await?;
+    assert_eq!(contents()?, (b"backed up".to_vec(), b"backed up".to_vec()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relocated_restore() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let target = tempdir()?;
+    let elsewhere = tempdir()?;
+    let root_path = Path::new("/var/lib/app");
+
+    write_file(source.path(), "top", b"top", 1_000_000);
+    write_file(source.path(), "data/a", b"a", 1_000_000);
+    write_file(source.path(), "data/sub/b", b"b", 1_000_000);
+    write_file(source.path(), "db/c", b"c", 1_000_000);
+    fs::create_dir_all(source.path().join("data/empty"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let options = RestoreOptions {
+        relocation: Relocation {
+            strip_prefix: 1,
+            moves: vec![Relocation::parse_move(
+                &format!("/var/lib/app/db={}", elsewhere.path().display()),
+                root_path,
+            )?],
+        },
+        ..Default::default()
+    };
+    bench.restore_with(root_path, target.path(), options).await?;
+
+    let tree = read_tree(target.path());
+    let paths: Vec<&PathBuf> = tree.keys().collect();
+    assert_eq!(paths, ["a", "empty", "sub", "sub/b"]);
+    assert_eq!(fs::read(elsewhere.path().join("c"))?, b"c");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_backs_up_subtree() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/only");
+
+    write_file(source.path(), "big/a", b"old a", 1_000_000);
+    write_file(source.path(), "big/nested/b", b"old b", 1_000_000);
+    write_file(source.path(), "other/c", b"old c", 1_000_000);
+    write_file(source.path(), "removed", b"removed", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    write_file(source.path(), "big/a", b"new a", 2_000_000);
+    write_file(source.path(), "big/nested/new", b"new", 2_000_000);
+    fs::remove_file(source.path().join("big/nested/b"))?;
+    write_file(source.path(), "other/c", b"new c", 2_000_000);
+    write_file(source.path(), "fresh/deep/d", b"d", 2_000_000);
+    fs::remove_file(source.path().join("removed"))?;
+    for only in ["big", "fresh/deep"] {
+        let options = BackupOptions {
+            only: Some(PathBuf::from(only)),
+            ..Default::default()
+        };
+        bench.backup(source.path(), root_path, options).await?;
+    }
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert_eq!(tree[Path::new("big/a")].as_deref(), Some(&b"new a"[..]));
+    assert_eq!(tree[Path::new("big/nested/new")].as_deref(), Some(&b"new"[..]));
+    assert!(!tree.contains_key(Path::new("big/nested/b")));
+    assert_eq!(tree[Path::new("fresh/deep/d")].as_deref(), Some(&b"d"[..]));
+    // Files outside of the subtree are left as they were
+    assert_eq!(tree[Path::new("other/c")].as_deref(), Some(&b"old c"[..]));
+    assert_eq!(tree[Path::new("removed")].as_deref(), Some(&b"removed"[..]));
+
+    // The next full backup still picks up the changes outside of the subtree
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn imported_manifest_registers_uploaded_files() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/imported");
+
+    write_file(source.path(), "a", b"a", 1_000_000);
+    write_file(source.path(), "dir/nested/b", b"b", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    let roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let files = bench
+        .backend
+        .list_remote_files(&(roots[0].path_hash.clone() + "/"), FileListDepth::Deep)
+        .await?;
+    let manifest = Manifest::new(&roots[0], "bucket", &files);
+    assert_eq!(manifest.files.len(), 2);
+
+    // The uploaded objects are all that's left, as if another tool had uploaded them
+    RemoteDirDB::hide(bench.backend.as_ref(), &roots[0].path_hash).await?;
+    root::save_roots(bench.backend.as_ref(), &[]).await?;
+
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut root = root::open_create_root(&bench.backend, &mut roots, root_path, &|_| false).await?;
+    let mut wrong_manifest = manifest.clone();
+    wrong_manifest.files[0].object_name += "x";
+    assert!(manifest::import(bench.backend.as_ref(), &root, &wrong_manifest)
+        .await
+        .is_err());
+    let mut wrong_manifest = manifest.clone();
+    wrong_manifest.files[1].last_modified += 1;
+    assert!(manifest::import(bench.backend.as_ref(), &root, &wrong_manifest)
+        .await
+        .is_err());
+    assert_eq!(manifest::import(bench.backend.as_ref(), &root, &manifest).await?, 2);
+    root.unlock().await?;
+
+    // The saved DirDB is what a scan of the source finds, so the next backup has no folder to diff
+    let mut remote_dirdb = RemoteDirDB::fetch(bench.backend.as_ref(), &root.path_hash).await?;
+    remote_dirdb.load_shards_of_subtree(bench.backend.as_ref(), &[]).await;
+    let imported = remote_dirdb.dirdb.unwrap();
+    let scanned = DirDB::new_from_local(source.path(), bench.backend.key())?;
+    assert!(imported.root == scanned.root);
+
+    let file_versions = data_versions_count(&bench).await?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+    assert_eq!(data_versions_count(&bench).await?, file_versions);
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn min_age_leaves_recent_files_for_next_backup() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/min_age");
+    let now = UNIX_EPOCH.elapsed()?.as_secs();
+
+    write_file(source.path(), "old", b"old", 1_000_000);
+    write_file(source.path(), "dir/recent", b"recent", now);
+    let options = BackupOptions {
+        min_age: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    bench.backup(source.path(), root_path, options.clone()).await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert_eq!(tree[Path::new("old")].as_deref(), Some(&b"old"[..]));
+    assert!(!tree.contains_key(Path::new("dir/recent")));
+
+    // Nothing changed locally, but the file is old enough now
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    bench.backup(source.path(), root_path, options).await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+/// Backs up the output of a shell script as db.sql in the backup root named `root_path`
+async fn backup_exec(bench: &TestBench, root_path: &Path, script: &str) -> Result<AuditRecord> {
+    let command = ["sh", "-c", script].iter().map(OsString::from).collect();
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut root = root::open_create_root(&bench.backend, &mut roots, root_path, &|_| false).await?;
+    let session = ExecSession::new(
+        &bench.config,
+        bench.backend.clone(),
+        Arc::new(root.clone()),
+        PathBuf::from("db.sql"),
+        command,
+    );
+    let mut audit = AuditRecord::start(AuditOperation::Backup);
+    let result = session.run_audited(&mut audit).await;
+    root.unlock().await?;
+    result.map(|()| audit)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn command_output_is_backed_up() -> Result<()> {
+    let bench = TestBench::new();
+    let root_path = Path::new("/dumps/db");
+
+    assert_eq!(
+        backup_exec(&bench, root_path, "echo first dump").await?.exit_code,
+        Some(0)
+    );
+    // A failed dump doesn't replace the last good one, and is never uploaded as a version
+    let roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let root = roots.iter().find(|root| root.path == root_path).unwrap();
+    let (_, dump_hash) = root.file_path_hashes(Path::new("db.sql"), bench.backend.key())?;
+    let err = backup_exec(&bench, root_path, "echo partial; exit 3")
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("exit status: 3"), "{:#}", err);
+    assert_eq!(bench.backend.list_remote_file_versions(&dump_hash).await?.len(), 1);
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(fs::read(restored.path().join("db.sql"))?, b"first dump\n");
+
+    backup_exec(&bench, root_path, "echo second dump").await?;
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(fs::read(restored.path().join("db.sql"))?, b"second dump\n");
+    Ok(())
+}
+
+/// Syncs the backup root named `source` into the one named `target`, like `frozen sync source target`
+async fn sync(bench: &TestBench, source: &Path, target: &Path) -> Result<()> {
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut source_root = root::open_root(&bench.backend, &mut roots, source, &|_| false).await?;
+    let mut target_root = root::open_create_root(&bench.backend, &mut roots, target, &|_| false).await?;
+    let session = SyncSession::new(
+        &bench.config,
+        bench.backend.clone(),
+        Arc::new(source_root.clone()),
+        bench.backend.clone(),
+        Arc::new(target_root.clone()),
+    );
+    let result = session.run().await;
+    target_root.unlock().await?;
+    source_root.unlock().await?;
+    result
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_mirrors_a_backup() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let other = tempdir()?;
+    let staging = Path::new("/backups/staging");
+    let stable = Path::new("/backups/stable");
+
+    write_file(source.path(), "a", b"first", 1_000_000);
+    write_file(source.path(), "dir/b", &[7; 100_000], 1_000_000);
+    bench.backup(source.path(), staging, BackupOptions::default()).await?;
+    write_file(other.path(), "a", b"older", 500_000);
+    write_file(other.path(), "stale", b"not in staging", 500_000);
+    bench.backup(other.path(), stable, BackupOptions::default()).await?;
+
+    sync(&bench, staging, stable).await?;
+    let restored = tempdir()?;
+    bench.restore(stable, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+
+    // Files already in sync aren't copied again
+    write_file(source.path(), "a", b"second", 2_000_000);
+    bench.backup(source.path(), staging, BackupOptions::default()).await?;
+    let file_versions = data_versions_count(&bench).await?;
+    sync(&bench, staging, stable).await?;
+    assert_eq!(data_versions_count(&bench).await?, file_versions + 1);
+
+    // The DirDB was copied too, backing up the same folder into the mirror finds nothing to do
+    bench.backup(source.path(), stable, BackupOptions::default()).await?;
+    assert_eq!(data_versions_count(&bench).await?, file_versions + 1);
+    let restored = tempdir()?;
+    bench.restore(stable, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn merge_keeps_newest_files_of_both_roots() -> Result<()> {
+    let bench = TestBench::new();
+    let old = tempdir()?;
+    let new = tempdir()?;
+    let old_path = Path::new("/home/me/photos");
+    let new_path = Path::new("/home/me/Photos");
+
+    write_file(old.path(), "2019/a.jpg", b"only in the old root", 1_000_000);
+    write_file(old.path(), "shared.txt", b"old version", 1_000_000);
+    write_file(old.path(), "newer.txt", b"newer in the old root", 3_000_000);
+    bench.backup(old.path(), old_path, BackupOptions::default()).await?;
+    write_file(new.path(), "shared.txt", b"new version", 2_000_000);
+    write_file(new.path(), "newer.txt", b"older in the new root", 2_000_000);
+    bench.backup(new.path(), new_path, BackupOptions::default()).await?;
+
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut source = root::open_root(&bench.backend, &mut roots, old_path, &|_| false).await?;
+    let mut target = root::open_root(&bench.backend, &mut roots, new_path, &|_| false).await?;
+    let session = MergeSession::new(
+        &bench.config,
+        bench.backend.clone(),
+        Arc::new(source.clone()),
+        bench.backend.clone(),
+        Arc::new(target.clone()),
+    );
+    let result = session.run().await;
+    let left_in_source = source.list_remote_files(bench.backend.as_ref()).await;
+    target.unlock().await?;
+    source.unlock().await?;
+    result?;
+    assert!(left_in_source?.is_empty());
+
+    let restored = tempdir()?;
+    bench.restore(new_path, restored.path()).await?;
+    let tree = read_tree(restored.path());
+    assert_eq!(
+        tree[Path::new("2019/a.jpg")].as_deref(),
+        Some(&b"only in the old root"[..])
+    );
+    assert_eq!(tree[Path::new("shared.txt")].as_deref(), Some(&b"new version"[..]));
+    assert_eq!(
+        tree[Path::new("newer.txt")].as_deref(),
+        Some(&b"newer in the old root"[..])
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tar_restore_extracts_like_a_restore() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/tar");
+
+    write_file(source.path(), "a.txt", b"first", 1_000_000);
+    write_file(source.path(), "dir/b.bin", &[7; 100_000], 1_000_000);
+    let long_name = format!("{}/{}", "d".repeat(80), "f".repeat(80));
+    write_file(source.path(), &long_name, b"needs a pax header", 1_000_000);
+    std::os::unix::fs::symlink("a.txt", source.path().join("link"))?;
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let archive = tempfile::NamedTempFile::new()?;
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut root = root::open_root(&bench.backend, &mut roots, root_path, &|_| false).await?;
+    let session = TarRestoreSession::new(
+        &bench.config,
+        bench.backend.clone(),
+        Arc::new(root.clone()),
+        Box::new(archive.reopen()?),
+        Relocation::default(),
+    );
+    let result = session.run().await;
+    root.unlock().await?;
+    result?;
+
+    let extracted = tempdir()?;
+    let status = std::process::Command::new("tar")
+        .arg("-xf")
+        .arg(archive.path())
+        .arg("-C")
+        .arg(extracted.path())
+        .status()?;
+    assert!(status.success());
+    assert_eq!(read_tree(extracted.path()), read_tree(source.path()));
+    assert_eq!(fs::read_link(extracted.path().join("link"))?, Path::new("a.txt"));
+    let mtime = fs::metadata(extracted.path().join("dir/b.bin"))?.modified()?;
+    assert_eq!(mtime, UNIX_EPOCH + Duration::from_secs(1_000_000));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tar_restore_fails_when_a_download_fails() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/tar-failing");
+
+    write_file(source.path(), "a.txt", b"first", 1_000_000);
+    write_file(source.path(), "b.bin", &[7; 100_000], 1_000_000);
+    write_file(source.path(), "c.txt", b"last", 1_000_000);
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    let archive = tempfile::NamedTempFile::new()?;
+    let mut roots = root::fetch_roots(bench.backend.as_ref()).await?;
+    let mut root = root::open_root(&bench.backend, &mut roots, root_path, &|_| false).await?;
+    let files = root.list_remote_files(bench.backend.as_ref()).await?;
+    let failing = files.iter().find(|file| file.rel_path == Path::new("b.bin")).unwrap();
+    bench.memory.fail_downloads_of(&failing.full_path_hash);
+    let session = TarRestoreSession::new(
+        &bench.config,
+        bench.backend.clone(),
+        Arc::new(root.clone()),
+        Box::new(archive.reopen()?),
+        Relocation::default(),
+    );
+    let result = session.run().await;
+    root.unlock().await?;
+    assert!(format!("{:#}", result.unwrap_err()).contains("b.bin"));
+
+    // The entry isn't padded into a complete archive
+    let extracted = tempdir()?;
+    let status = std::process::Command::new("tar")
+        .arg("-xf")
+        .arg(archive.path())
+        .arg("-C")
+        .arg(extracted.path())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    assert!(!status.success());
+    Ok(())
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::{read_tree, write_file, TestBench};
+use eyre::Result;
+use frozen_core::data::checkpoint::CHECKPOINT_FILE_NAME;
+use frozen_core::data::relocation::Relocation;
+use frozen_core::net::chaos::{ChaosBackend, ChaosOptions};
+use frozen_core::session::{BackupOptions, RestoreOptions};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::tempdir;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_backups_resume() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/chaos");
+    for i in 0..20 {
+        write_file(
+            source.path(),
+            &format!("dir{}/file{}", i % 4, i),
+            &[i as u8; 5000],
+            1_000_000,
+        );
+    }
+
+    let options = ChaosOptions::new(0.2, Some(1234))?;
+    let chaos = Arc::new(ChaosBackend::new(bench.backend.clone(), options));
+    let mut failures = 0;
+    while bench
+        .backup_through(chaos.clone(), source.path(), root_path, BackupOptions::default())
+        .await
+        .is_err()
+    {
+        failures += 1;
+        assert!(failures < 50, "Backup never completed under chaos");
+    }
+    assert!(failures > 0, "Chaos didn't disrupt anything");
+
+    let restored = tempdir()?;
+    bench.restore(root_path, restored.path()).await?;
+    assert_eq!(read_tree(restored.path()), read_tree(source.path()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_restores_resume() -> Result<()> {
+    let bench = TestBench::new();
+    let source = tempdir()?;
+    let root_path = Path::new("/backups/chaos-restore");
+    for i in 0..20 {
+        write_file(source.path(), &format!("dir/file{}", i), &[i as u8; 5000], 1_000_000);
+    }
+    bench.backup(source.path(), root_path, BackupOptions::default()).await?;
+
+    // Relocated restores download everything, unless the checkpoint says otherwise
+    let options = RestoreOptions {
+        relocation: Relocation {
+            strip_prefix: 1,
+            moves: Vec::new(),
+        },
+        ..Default::default()
+    };
+    let chaos = Arc::new(ChaosBackend::new(
+        bench.backend.clone(),
+        ChaosOptions::new(0.3, Some(42))?,
+    ));
+    let restored = tempdir()?;
+    let mut inodes = BTreeMap::new();
+    let mut failures = 0;
+    while bench
+        .restore_through(chaos.clone(), root_path, restored.path(), options.clone())
+        .await
+        .is_err()
+    {
+        failures += 1;
+        assert!(failures < 50, "Restore never completed under chaos");
+        assert!(restored.path().join(CHECKPOINT_FILE_NAME).exists());
+        for entry in fs::read_dir(restored.path())? {
+            let entry = entry?;
+            inodes.entry(entry.file_name()).or_insert(entry.metadata()?.ino());
+        }
+    }
+    assert!(failures > 0, "Chaos didn't disrupt anything");
+
+    // Files restored before an interruption are never written again
+    for (name, ino) in inodes.iter() {
+        if name != CHECKPOINT_FILE_NAME {
+            assert_eq!(fs::metadata(restored.path().join(name))?.ino(), *ino);
+        }
+    }
+    assert_eq!(read_tree(restored.path()), read_tree(&source.path().join("dir")));
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Shared harness for the integration tests, which run full backups and restores against an in-memory backend
+#![allow(dead_code)]
+
+use eyre::Result;
+use frozen_core::config::Config;
+use frozen_core::crypto::Key;
+use frozen_core::data::root;
+use frozen_core::net::backend::Backend;
+use frozen_core::net::memory::MemoryBackend;
+use frozen_core::session::{AuditedSession, BackupOptions, BackupSession, RestoreOptions, RestoreSession};
+use fs_set_times::{SetTimes, SystemTimeSpec};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub struct TestBench {
+    pub config: Config,
+    pub memory: MemoryBackend,
+    pub backend: Arc<dyn Backend>,
+}
+
+impl TestBench {
+    pub fn new() -> Self {
+        let memory = MemoryBackend::new(Key([42; 32]));
+        let mut config = Config::default();
+        config.upload_threads = 4;
+        config.download_threads = 4;
+        config.delete_threads = 4;
+        config.compression_level = 3;
+        Self {
+            config,
+            backend: Arc::new(memory.clone()),
+            memory,
+        }
+    }
+
+    /// Backs up `source` into the backup root named `root_path`, like `frozen backup source root_path`
+    pub async fn backup(&self, source: &Path, root_path: &Path, options: BackupOptions) -> Result<()> {
+        self.backup_through(self.backend.clone(), source, root_path, options)
+            .await
+    }
+
+    /// Like `backup`, but the session goes through `backend` (e.g. to inject faults), while locking doesn't
+    pub async fn backup_through(
+        &self,
+        backend: Arc<dyn Backend>,
+        source: &Path,
+        root_path: &Path,
+        options: BackupOptions,
+    ) -> Result<()> {
+        let mut roots = root::fetch_roots(self.backend.as_ref()).await?;
+        let mut root = root::open_create_root(&self.backend, &mut roots, root_path, &|_| false).await?;
+        let session = BackupSession::new(
+            &self.config,
+            backend,
+            Arc::new(root.clone()),
+            source.to_owned(),
+            options,
+        );
+        let result = session.run().await;
+        root.unlock().await?;
+        result
+    }
+
+    /// Restores the backup root named `root_path` into `target`
+    pub async fn restore(&self, root_path: &Path, target: &Path) -> Result<()> {
+        self.restore_with(root_path, target, RestoreOptions::default()).await
+    }
+
+    pub async fn restore_with(&self, root_path: &Path, target: &Path, options: RestoreOptions) -> Result<()> {
+        self.restore_through(self.backend.clone(), root_path, target, options)
+            .await
+    }
+
+    /// Like `restore_with`, but the session goes through `backend`, while locking doesn't
+    pub async fn restore_through(
+        &self,
+        backend: Arc<dyn Backend>,
+        root_path: &Path,
+        target: &Path,
+        options: RestoreOptions,
+    ) -> Result<()> {
+        let mut roots = root::fetch_roots(self.backend.as_ref()).await?;
+        let mut root = root::open_root(&self.backend, &mut roots, root_path, &|_| false).await?;
+        let session = RestoreSession::new(
+            &self.config,
+            backend,
+            Arc::new(root.clone()),
+            target.to_owned(),
+            options,
+        );
+        let result = session.run().await;
+        root.unlock().await?;
+        result
+    }
+}
+
+/// Writes a file (creating its parent folders) with a fixed modification time, in seconds since the epoch
+///
+/// Backups only compare mtimes to the second, so tests that modify files must move the mtime forward explicitly.
+pub fn write_file(dir: &Path, rel_path: &str, content: &[u8], mtime: u64) {
+    let path = dir.join(rel_path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, content).unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(mtime);
+    let file = File::open(&path).unwrap();
+    SetTimes::set_times(&file, None, Some(SystemTimeSpec::Absolute(mtime))).unwrap();
+}
+
+/// Reads every file under `dir`, keyed by relative path. Folders map to `None`.
+pub fn read_tree(dir: &Path) -> BTreeMap<PathBuf, Option<Vec<u8>>> {
+    let mut tree = BTreeMap::new();
+    read_tree_into(dir, dir, &mut tree);
+    tree
+}
+
+fn read_tree_into(base: &Path, dir: &Path, tree: &mut BTreeMap<PathBuf, Option<Vec<u8>>>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let rel_path = path.strip_prefix(base).unwrap().to_owned();
+        if path.is_dir() {
+            tree.insert(rel_path, None);
+            read_tree_into(base, &path, tree);
+        } else {
+            tree.insert(rel_path, Some(fs::read(&path).unwrap()));
+        }
+    }
+}